@@ -1,10 +1,120 @@
-use std::{fs, io, path::Path};
+use std::{fs, io, io::Write, path::Path};
 use std::{convert::TryFrom, fs::File, io::Seek};
 use std::path::PathBuf;
+use xmas_elf::ElfFile;
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{Entry, Type};
+
+// Builds the `symbols` file read by the kernel (see src/symbols.rs):
+// "FSYM", u32 count, count * { u64 addr, u32 size, u32 name offset }, NUL-terminated names.
+fn create_symbol_file(kernel_file: &Path) -> Vec<u8> {
+    let kernel = fs::read(kernel_file).unwrap();
+    let elf = ElfFile::new(&kernel).unwrap();
+
+    let mut symbols: Vec<(u64, u64, String)> = Vec::new();
+    if let Some(symtab) = elf.find_section_by_name(".symtab") {
+        if let Ok(SectionData::SymbolTable64(entries)) = symtab.get_data(&elf) {
+            for entry in entries {
+                if entry.get_type() != Ok(Type::Func) || entry.value() == 0 {
+                    continue;
+                }
+                let name = entry.get_name(&elf).unwrap_or("<unknown>");
+                symbols.push((entry.value(), entry.size(), format!("{:#}", rustc_demangle::demangle(name))));
+            }
+        }
+    }
+    symbols.sort_by_key(|s| s.0);
+    symbols.dedup_by_key(|s| s.0);
+
+    let mut entries = Vec::new();
+    let mut names = Vec::new();
+    for (addr, size, name) in &symbols {
+        entries.extend_from_slice(&addr.to_le_bytes());
+        entries.extend_from_slice(&(*size as u32).to_le_bytes());
+        entries.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+    }
+
+    let mut file = b"FSYM".to_vec();
+    file.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    file.extend_from_slice(&entries);
+    file.extend_from_slice(&names);
+    file
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    None,
+    Gzip,
+    Lz4,
+}
+
+// The loader recognizes compressed files by their magic and decompresses them before use.
+fn compress(data: &[u8], compression: Compression) -> Vec<u8> {
+    match compression {
+        Compression::None => data.to_vec(),
+        Compression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        },
+        Compression::Lz4 => {
+            // the loader sizes its buffer from the content size
+            let info = lz4_flex::frame::FrameInfo::new().content_size(Some(data.len() as u64));
+            let mut encoder = lz4_flex::frame::FrameEncoder::with_frame_info(info, Vec::new());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        },
+    }
+}
+
+// ESP files for one kernel: (kernel name, symbols name, kernel image, symbols)
+fn kernel_files(kernel_file: &Path, names: (&'static str, &'static str), compression: Compression)
+    -> (&'static str, &'static str, Vec<u8>, Vec<u8>) {
+    let symbols = create_symbol_file(kernel_file);
+
+    let kernel_image = fs::read(kernel_file).unwrap();
+    let compressed = compress(&kernel_image, compression);
+    if compression != Compression::None {
+        println!("{} compressed from {} to {} bytes", names.0, kernel_image.len(), compressed.len());
+    }
+    (names.0, names.1, compressed, symbols)
+}
 
-fn create_fat_filesystem(fat_path: &Path, efi_file: &Path, kernel_file: &Path) {
-    // retrieve size of `.efi` file and round it up
-    let efi_size = fs::metadata(&efi_file).unwrap().len();
+fn create_fat_filesystem(fat_path: &Path, efi_file: &Path, kernel_file: &Path, kernel_b_file: Option<&Path>,
+                         old_kernel_file: Option<&Path>, initrd_file: Option<&Path>, compression: Compression) {
+    // with a second kernel both go into the A/B slots, the loader picks one of them
+    let mut kernels = match kernel_b_file {
+        None => vec![kernel_files(kernel_file, ("kernel", "symbols"), compression)],
+        Some(kernel_b_file) => vec![
+            kernel_files(kernel_file, ("kernel.a", "symbols.a"), compression),
+            kernel_files(kernel_b_file, ("kernel.b", "symbols.b"), compression),
+        ],
+    };
+    // offered in the loader's boot menu
+    if let Some(old_kernel_file) = old_kernel_file {
+        kernels.push(kernel_files(old_kernel_file, ("kernel.old", "symbols.old"), compression));
+    }
+
+    // optional files read by the loader: boot configuration and initial ramdisk
+    let mut extra_files: Vec<(&str, Vec<u8>)> = Vec::new();
+    let config_path = kernel_file.with_file_name("ferr_os.cfg");
+    if let Ok(config) = fs::read(&config_path) {
+        println!("using boot configuration {}", config_path.display());
+        extra_files.push(("ferr_os.cfg", config));
+    }
+    if let Some(initrd_file) = initrd_file {
+        let initrd = fs::read(initrd_file).unwrap();
+        let compressed = compress(&initrd, compression);
+        println!("initrd {}: {} bytes, {} bytes stored", initrd_file.display(), initrd.len(), compressed.len());
+        extra_files.push(("initrd", compressed));
+    }
+
+    // retrieve size of all files and round it up
+    let efi_size = fs::metadata(&efi_file).unwrap().len()
+        + kernels.iter().map(|(_, _, kernel, symbols)| (kernel.len() + symbols.len()) as u64).sum::<u64>()
+        + extra_files.iter().map(|(_, data)| data.len() as u64).sum::<u64>();
     // size of a megabyte
     let mb = 1024 * 1024;
     // round it to next megabyte
@@ -33,9 +143,21 @@ fn create_fat_filesystem(fat_path: &Path, efi_file: &Path, kernel_file: &Path) {
     bootx64.truncate().unwrap();
     io::copy(&mut fs::File::open(&efi_file).unwrap(), &mut bootx64).unwrap();
 
-    let mut kernel = root_dir.create_file("kernel").unwrap();
-    kernel.truncate().unwrap();
-    io::copy(&mut fs::File::open(&kernel_file).unwrap(), &mut kernel).unwrap();
+    for (kernel_name, symbols_name, kernel_image, symbols) in &kernels {
+        let mut kernel = root_dir.create_file(kernel_name).unwrap();
+        kernel.truncate().unwrap();
+        kernel.write_all(kernel_image).unwrap();
+
+        let mut symbols_file = root_dir.create_file(symbols_name).unwrap();
+        symbols_file.truncate().unwrap();
+        symbols_file.write_all(symbols).unwrap();
+    }
+
+    for (name, data) in &extra_files {
+        let mut file = root_dir.create_file(name).unwrap();
+        file.truncate().unwrap();
+        file.write_all(data).unwrap();
+    }
 }
 
 fn create_gpt_disk(disk_path: &Path, fat_image: &Path) {
@@ -96,9 +218,28 @@ fn main() {
     let kernel_path = PathBuf::from(args.next()
         .expect("path to `kernel` file must be given as argument"));
 
+    // `--gzip` or `--lz4` store a compressed kernel and initrd, `--ab <kernel>` adds a second
+    // kernel and stores both as A/B images, `--old <kernel>` adds a fallback kernel to the boot
+    // menu, `--initrd <file>` adds an initial ramdisk
+    let mut compression = Compression::None;
+    let mut kernel_b_path = None;
+    let mut old_kernel_path = None;
+    let mut initrd_path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--gzip" => compression = Compression::Gzip,
+            "--lz4" => compression = Compression::Lz4,
+            "--ab" => kernel_b_path = Some(PathBuf::from(args.next().expect("--ab needs the path of the second kernel"))),
+            "--old" => old_kernel_path = Some(PathBuf::from(args.next().expect("--old needs the path of the fallback kernel"))),
+            "--initrd" => initrd_path = Some(PathBuf::from(args.next().expect("--initrd needs the path of the ramdisk"))),
+            _ => panic!("unknown argument {}", arg),
+        }
+    }
+
     let fat_path = efi_path.with_extension("fat");
     let disk_path = fat_path.with_extension("gdt");
 
-    create_fat_filesystem(&fat_path, &efi_path, &kernel_path);
+    create_fat_filesystem(&fat_path, &efi_path, &kernel_path, kernel_b_path.as_deref(), old_kernel_path.as_deref(),
+                          initrd_path.as_deref(), compression);
     create_gpt_disk(&disk_path, &fat_path);
 }
@@ -0,0 +1,38 @@
+#!/usr/bin/env python3
+
+# Extracts screenshots taken with the `screenshot` shell command from a serial log.
+#
+# usage: extract_screenshots.py <serial log> [output dir]
+
+import base64
+import os
+import sys
+
+if len(sys.argv) < 2:
+    print("usage: extract_screenshots.py <serial log> [output dir]")
+    sys.exit(1)
+
+out_dir = sys.argv[2] if len(sys.argv) > 2 else "."
+
+name = None
+size = 0
+chunks = []
+
+with open(sys.argv[1], "r", errors="replace") as f:
+    for line in f:
+        line = line.strip()
+        if line.startswith("screenshot: begin "):
+            _, _, name, size = line.split(" ")
+            size = int(size)
+            chunks = []
+        elif line == "screenshot: end" and name is not None:
+            data = base64.b64decode("".join(chunks))
+            if len(data) != size:
+                print("{}: expected {} bytes, got {}".format(name, size, len(data)))
+            path = os.path.join(out_dir, os.path.basename(name))
+            with open(path, "wb") as out:
+                out.write(data)
+            print("saved " + path)
+            name = None
+        elif name is not None:
+            chunks.append(line)
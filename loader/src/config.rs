@@ -0,0 +1,126 @@
+// Boot configuration file.
+//
+// `ferr_os.cfg` on the ESP holds `key = value` lines, `#` starts a comment:
+//
+//     log_level = debug                # error, warn, info, debug or trace
+//     log_output = serial              # kernel log: serial or framebuffer
+//     resolution = 1280x720            # preferred GOP mode
+//     menu_timeout = 3                 # seconds before the default kernel boots, 0 skips the menu
+//     cmdline = noapic init=/bin/sh    # passed to the kernel as is
+//
+// The file is optional, and so is every key in it. Log level and output reach the kernel as
+// `loglevel=` and `console=` options in front of `cmdline`, so the command line given in the file
+// can still override them.
+//
+// The file is read before the logger is up, since the resolution decides the framebuffer it logs
+// to, so problems with it are collected in `warnings` and reported later.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str::FromStr;
+use log::LevelFilter;
+use shared_lib::cmdline::Cmdline;
+
+pub const CONFIG_FILE_NAME: &str = "ferr_os.cfg";
+const DEFAULT_MENU_TIMEOUT: u64 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogOutput {
+    Serial,
+    Framebuffer,
+}
+
+pub struct BootConfig {
+    pub log_level: LevelFilter,
+    pub log_output: LogOutput,
+    /// Preferred framebuffer width and height
+    pub resolution: Option<(usize, usize)>,
+    /// Seconds the boot menu waits for a choice
+    pub menu_timeout: u64,
+    /// Options from the `cmdline` key
+    pub cmdline: Cmdline,
+    /// Problems found while reading the file
+    pub warnings: Vec<String>,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        BootConfig {
+            log_level: LevelFilter::Info,
+            log_output: LogOutput::Serial,
+            resolution: None,
+            menu_timeout: DEFAULT_MENU_TIMEOUT,
+            cmdline: Cmdline::empty(),
+            warnings: Vec::new(),
+        }
+    }
+}
+
+fn parse_resolution(value: &str) -> Option<(usize, usize)> {
+    let (width, height) = value.split_once('x')?;
+    Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+}
+
+impl BootConfig {
+    /// Parses the configuration file. Lines that can't be parsed end up in `warnings` and are skipped.
+    pub fn parse(text: &str) -> BootConfig {
+        let mut config = BootConfig::default();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                config.warnings.push(format!("line {}: expected `key = value`", number + 1));
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+
+            let valid = match key {
+                "log_level" => LevelFilter::from_str(value).map(|level| config.log_level = level).is_ok(),
+                "log_output" => match value {
+                    "serial" => { config.log_output = LogOutput::Serial; true },
+                    "framebuffer" => { config.log_output = LogOutput::Framebuffer; true },
+                    _ => false,
+                },
+                "resolution" => parse_resolution(value).map(|resolution| config.resolution = Some(resolution)).is_some(),
+                "menu_timeout" => value.parse().map(|seconds| config.menu_timeout = seconds).is_ok(),
+                "cmdline" => config.cmdline.push(value),
+                _ => {
+                    config.warnings.push(format!("line {}: unknown key {}", number + 1, key));
+                    continue;
+                }
+            };
+
+            if !valid {
+                config.warnings.push(format!("line {}: invalid value for {}: {}", number + 1, key, value));
+            }
+        }
+        config
+    }
+
+    /// Configuration to use when the file can't be read
+    pub fn with_warning(warning: String) -> BootConfig {
+        let mut config = BootConfig::default();
+        config.warnings.push(warning);
+        config
+    }
+
+    /// Command line for the kernel: the log options followed by the `cmdline` key
+    pub fn kernel_cmdline(&self) -> Cmdline {
+        let console = match self.log_output {
+            LogOutput::Serial => "serial",
+            LogOutput::Framebuffer => "fb",
+        };
+
+        let mut cmdline = Cmdline::empty();
+        cmdline.push(&format!("loglevel={} console={}", self.log_level.as_str().to_lowercase(), console));
+        if !cmdline.push(self.cmdline.as_str()) {
+            log::warn!("[config] kernel command line too long, dropping {}", self.cmdline.as_str());
+        }
+        cmdline
+    }
+}
@@ -0,0 +1,126 @@
+// Compressed images.
+//
+// `disk_image --gzip` or `--lz4` stores the kernel compressed under the same name. The loader
+// recognizes the format by its magic bytes and decompresses the image into freshly allocated
+// pages before using it. gzip (RFC 1952) is inflated with miniz_oxide and checked against the
+// CRC-32 in its trailer, LZ4 frames are decoded by `shared_lib::lz4` and must record their
+// content size.
+
+use shared_lib::crc::calculate_crc32;
+use shared_lib::lz4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Gzip,
+    Lz4,
+}
+
+/// Format of a compressed image, None for anything else
+pub fn detect(data: &[u8]) -> Option<Format> {
+    if is_gzip(data) {
+        Some(Format::Gzip)
+    } else if lz4::is_lz4_frame(data) {
+        Some(Format::Lz4)
+    } else {
+        None
+    }
+}
+
+/// Size of `data` once decompressed
+pub fn decompressed_size(format: Format, data: &[u8]) -> Result<usize, &'static str> {
+    match format {
+        Format::Gzip => Ok(parse_gzip(data)?.size),
+        Format::Lz4 => lz4::parse_frame_header(data)
+            .map_err(|_| "Bad LZ4 frame header")?
+            .content_size
+            .map(|size| size as usize)
+            .ok_or("LZ4 frame without content size"),
+    }
+}
+
+/// Decompresses `data` into `out`, which must hold `decompressed_size` bytes
+pub fn decompress_into(format: Format, data: &[u8], out: &mut [u8]) -> Result<usize, &'static str> {
+    match format {
+        Format::Gzip => gunzip_into(&parse_gzip(data)?, out),
+        Format::Lz4 => lz4::decompress(data, out).map_err(|e| {
+            log::error!("LZ4 error: {:?}", e);
+            "Corrupted LZ4 data"
+        }),
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const GZIP_HEADER_SIZE: usize = 10;
+const GZIP_TRAILER_SIZE: usize = 8;
+const CM_DEFLATE: u8 = 8;
+
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&GZIP_MAGIC)
+}
+
+struct Gzip<'a> {
+    deflate: &'a [u8],
+    crc32: u32,
+    /// Size of the uncompressed data modulo 2^32
+    size: usize,
+}
+
+fn skip_zero_terminated(data: &[u8], pos: usize) -> Result<usize, &'static str> {
+    let len = data.get(pos..)
+        .and_then(|rest| rest.iter().position(|b| *b == 0))
+        .ok_or("Truncated gzip header")?;
+    Ok(pos + len + 1)
+}
+
+/// Parses the gzip header and trailer around the DEFLATE stream
+fn parse_gzip(data: &[u8]) -> Result<Gzip<'_>, &'static str> {
+    if data.len() < GZIP_HEADER_SIZE + GZIP_TRAILER_SIZE || !is_gzip(data) {
+        return Err("Not a gzip file");
+    }
+    if data[2] != CM_DEFLATE {
+        return Err("Unsupported gzip compression method");
+    }
+
+    let flags = data[3];
+    let mut pos = GZIP_HEADER_SIZE;
+    if flags & FEXTRA != 0 {
+        let extra_len = u16::from_le_bytes([data[pos], data[pos + 1]]) as usize;
+        pos += 2 + extra_len;
+    }
+    if flags & FNAME != 0 {
+        pos = skip_zero_terminated(data, pos)?;
+    }
+    if flags & FCOMMENT != 0 {
+        pos = skip_zero_terminated(data, pos)?;
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+
+    let trailer = data.len() - GZIP_TRAILER_SIZE;
+    if pos > trailer {
+        return Err("Truncated gzip header");
+    }
+
+    Ok(Gzip {
+        deflate: &data[pos..trailer],
+        crc32: u32::from_le_bytes(data[trailer..trailer + 4].try_into().unwrap()),
+        size: u32::from_le_bytes(data[trailer + 4..].try_into().unwrap()) as usize,
+    })
+}
+
+/// Inflates `gzip` into `out`, which must hold at least `gzip.size` bytes
+fn gunzip_into(gzip: &Gzip, out: &mut [u8]) -> Result<usize, &'static str> {
+    let size = miniz_oxide::inflate::decompress_slice_iter_to_slice(out, core::iter::once(gzip.deflate), false, true)
+        .map_err(|_| "Corrupted gzip data")?;
+
+    if size != gzip.size || calculate_crc32(&out[..size]) != gzip.crc32 {
+        return Err("gzip checksum mismatch");
+    }
+    Ok(size)
+}
@@ -0,0 +1,269 @@
+// Exception handlers for the end of the loader.
+//
+// Once boot services are gone the firmware's handlers can't be relied upon, and a fault while
+// building the kernel's page tables used to end in a silent triple fault. `init` installs a GDT
+// with a TSS and an IDT for the 32 CPU exceptions. Every vector enters a small assembly stub that
+// saves the registers, the handler dumps them to COM1 and halts. Double faults switch to their
+// own IST stack, so a loader stack overflow is still reported.
+
+use core::arch::{asm, global_asm};
+use core::mem::size_of;
+use core::ptr::addr_of;
+use shared_lib::serial_println;
+
+const CODE_SELECTOR: u16 = 0x08;
+const DATA_SELECTOR: u16 = 0x10;
+const TSS_SELECTOR: u16 = 0x18;
+
+const EXCEPTION_COUNT: usize = 32;
+const DOUBLE_FAULT_VECTOR: usize = 8;
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 4;
+/// Every stub is aligned to this, so stub N starts at `loader_exception_stubs + N * STUB_ALIGN`
+const STUB_ALIGN: u64 = 16;
+
+const EXCEPTION_NAMES: [&str; EXCEPTION_COUNT] = [
+    "divide error", "debug", "NMI", "breakpoint", "overflow", "bound range exceeded", "invalid opcode",
+    "device not available", "double fault", "coprocessor segment overrun", "invalid TSS", "segment not present",
+    "stack-segment fault", "general protection fault", "page fault", "reserved", "x87 floating-point",
+    "alignment check", "machine check", "SIMD floating-point", "virtualization", "control protection",
+    "reserved", "reserved", "reserved", "reserved", "reserved", "reserved", "hypervisor injection",
+    "VMM communication", "security", "reserved",
+];
+
+// The CPU pushes an error code for 8, 10-14, 17, 21, 29 and 30, the other stubs push a zero in
+// its place so the frame is the same for all vectors.
+global_asm!(
+    r#"
+.macro exception_stub vector, has_error_code
+    .p2align 4
+    .if \has_error_code == 0
+    push 0
+    .endif
+    push \vector
+    jmp loader_exception_common
+.endm
+
+.p2align 4
+.global loader_exception_stubs
+loader_exception_stubs:
+    exception_stub 0, 0
+    exception_stub 1, 0
+    exception_stub 2, 0
+    exception_stub 3, 0
+    exception_stub 4, 0
+    exception_stub 5, 0
+    exception_stub 6, 0
+    exception_stub 7, 0
+    exception_stub 8, 1
+    exception_stub 9, 0
+    exception_stub 10, 1
+    exception_stub 11, 1
+    exception_stub 12, 1
+    exception_stub 13, 1
+    exception_stub 14, 1
+    exception_stub 15, 0
+    exception_stub 16, 0
+    exception_stub 17, 1
+    exception_stub 18, 0
+    exception_stub 19, 0
+    exception_stub 20, 0
+    exception_stub 21, 1
+    exception_stub 22, 0
+    exception_stub 23, 0
+    exception_stub 24, 0
+    exception_stub 25, 0
+    exception_stub 26, 0
+    exception_stub 27, 0
+    exception_stub 28, 0
+    exception_stub 29, 1
+    exception_stub 30, 1
+    exception_stub 31, 0
+
+loader_exception_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    cld
+    call {handler}
+    ud2
+"#,
+    handler = sym exception_handler,
+);
+
+extern "C" {
+    static loader_exception_stubs: u8;
+}
+
+/// Stack contents when the stubs call the handler, lowest address first
+#[repr(C)]
+struct SavedRegisters {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    vector: u64,
+    error_code: u64,
+    // pushed by the CPU
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+#[repr(C, packed(4))]
+struct TaskStateSegment {
+    reserved_1: u32,
+    privilege_stack_table: [u64; 3],
+    reserved_2: u64,
+    interrupt_stack_table: [u64; 7],
+    reserved_3: u64,
+    reserved_4: u16,
+    iomap_base: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct IdtEntry {
+    pointer_low: u16,
+    selector: u16,
+    /// IST index in bits 0-2, then the gate type and the present bit
+    options: u16,
+    pointer_middle: u16,
+    pointer_high: u32,
+    reserved: u32,
+}
+
+impl IdtEntry {
+    const MISSING: IdtEntry = IdtEntry { pointer_low: 0, selector: 0, options: 0, pointer_middle: 0, pointer_high: 0, reserved: 0 };
+
+    fn new(handler: u64, ist_index: u16) -> Self {
+        const PRESENT_INTERRUPT_GATE: u16 = 0x8E00;
+        IdtEntry {
+            pointer_low: handler as u16,
+            selector: CODE_SELECTOR,
+            options: PRESENT_INTERRUPT_GATE | ist_index,
+            pointer_middle: (handler >> 16) as u16,
+            pointer_high: (handler >> 32) as u32,
+            reserved: 0,
+        }
+    }
+}
+
+#[repr(C, packed(2))]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u64,
+}
+
+/// Null, 64-bit code, data and the two halves of the TSS descriptor
+static mut GDT: [u64; 5] = [0, 0x00AF_9A00_0000_FFFF, 0x00CF_9200_0000_FFFF, 0, 0];
+static mut TSS: TaskStateSegment = TaskStateSegment {
+    reserved_1: 0,
+    privilege_stack_table: [0; 3],
+    reserved_2: 0,
+    interrupt_stack_table: [0; 7],
+    reserved_3: 0,
+    reserved_4: 0,
+    iomap_base: size_of::<TaskStateSegment>() as u16,
+};
+static mut IDT: [IdtEntry; EXCEPTION_COUNT] = [IdtEntry::MISSING; EXCEPTION_COUNT];
+static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+/// Replaces the firmware's GDT and IDT.
+///
+/// # Safety
+/// Boot services must have been exited, their timer interrupt has no handler here. Must be
+/// called once.
+pub unsafe fn init() {
+    TSS.interrupt_stack_table[0] = addr_of!(DOUBLE_FAULT_STACK) as u64 + DOUBLE_FAULT_STACK_SIZE as u64;
+
+    let tss = addr_of!(TSS) as u64;
+    let limit = size_of::<TaskStateSegment>() as u64 - 1;
+    // available 64-bit TSS, present
+    GDT[3] = (limit & 0xFFFF) | (tss & 0xFF_FFFF) << 16 | 0x89 << 40 | (limit >> 16 & 0xF) << 48 | (tss >> 24 & 0xFF) << 56;
+    GDT[4] = tss >> 32;
+
+    let gdt_pointer = DescriptorTablePointer { limit: (size_of::<[u64; 5]>() - 1) as u16, base: addr_of!(GDT) as u64 };
+    asm!("lgdt [{}]", in(reg) &gdt_pointer, options(readonly, nostack, preserves_flags));
+
+    // CS can only be reloaded by a far return
+    asm!(
+        "push {selector}",
+        "lea {tmp}, [rip + 2f]",
+        "push {tmp}",
+        "retfq",
+        "2:",
+        selector = in(reg) CODE_SELECTOR as u64,
+        tmp = lateout(reg) _,
+        options(preserves_flags),
+    );
+    asm!(
+        "mov ds, {0:x}",
+        "mov es, {0:x}",
+        "mov ss, {0:x}",
+        in(reg) DATA_SELECTOR,
+        options(nostack, preserves_flags),
+    );
+    asm!("ltr {0:x}", in(reg) TSS_SELECTOR, options(nostack, preserves_flags));
+
+    let stubs = addr_of!(loader_exception_stubs) as u64;
+    for vector in 0..EXCEPTION_COUNT {
+        let ist_index = if vector == DOUBLE_FAULT_VECTOR { 1 } else { 0 };
+        IDT[vector] = IdtEntry::new(stubs + vector as u64 * STUB_ALIGN, ist_index);
+    }
+
+    let idt_pointer = DescriptorTablePointer { limit: (size_of::<[IdtEntry; EXCEPTION_COUNT]>() - 1) as u16, base: addr_of!(IDT) as u64 };
+    asm!("lidt [{}]", in(reg) &idt_pointer, options(readonly, nostack, preserves_flags));
+}
+
+extern "C" fn exception_handler(regs: &SavedRegisters) -> ! {
+    // the fault may have hit while something held the serial port
+    unsafe { shared_lib::serial::SERIAL1.force_unlock() };
+
+    let (cr0, cr2, cr3, cr4): (u64, u64, u64, u64);
+    unsafe {
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+    }
+
+    let name = EXCEPTION_NAMES.get(regs.vector as usize).unwrap_or(&"unknown");
+    serial_println!("[loader] EXCEPTION {} ({}), error code {:#x}", regs.vector, name, regs.error_code);
+    serial_println!("rip {:#018x} cs  {:#06x} rflags {:#x}", regs.rip, regs.cs, regs.rflags);
+    serial_println!("rsp {:#018x} ss  {:#06x}", regs.rsp, regs.ss);
+    serial_println!("rax {:#018x} rbx {:#018x} rcx {:#018x} rdx {:#018x}", regs.rax, regs.rbx, regs.rcx, regs.rdx);
+    serial_println!("rsi {:#018x} rdi {:#018x} rbp {:#018x}", regs.rsi, regs.rdi, regs.rbp);
+    serial_println!("r8  {:#018x} r9  {:#018x} r10 {:#018x} r11 {:#018x}", regs.r8, regs.r9, regs.r10, regs.r11);
+    serial_println!("r12 {:#018x} r13 {:#018x} r14 {:#018x} r15 {:#018x}", regs.r12, regs.r13, regs.r14, regs.r15);
+    serial_println!("cr0 {:#018x} cr2 {:#018x} cr3 {:#018x} cr4 {:#018x}", cr0, cr2, cr3, cr4);
+
+    loop {
+        unsafe { asm!("cli; hlt", options(nomem, nostack)) };
+    }
+}
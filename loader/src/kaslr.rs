@@ -0,0 +1,186 @@
+// Kernel address space layout randomization.
+//
+// The loader moves the physical memory mapping, the kernel stack and, when the kernel is linked
+// as a position independent executable, the kernel image itself by random slides. The slides are
+// seeded from RDRAND when the CPU has it and from the TSC otherwise, which is weak but still
+// differs from boot to boot.
+
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use shared_lib::get_tsc;
+use xmas_elf::ElfFile;
+use xmas_elf::dynamic::Tag;
+use xmas_elf::header;
+use xmas_elf::program::{self, SegmentData};
+
+/// Randomizes the kernel, stack and mapping placement; set to false for a fixed layout
+pub const KASLR: bool = true;
+
+/// Range a randomized region is placed in: `base + n * align` for `n` in `0..slots`
+pub struct Region {
+    pub base: u64,
+    pub align: u64,
+    pub slots: u64,
+}
+
+/// Physical memory mapping: up to 256 GiB above `VIRT_MAPPING_OFFSET` in 1 GiB steps
+pub const PHYS_MAPPING_REGION: Region = Region { base: shared_lib::VIRT_MAPPING_OFFSET, align: 1 << 30, slots: 256 };
+
+/// Kernel stack: 64 GiB in 4 KiB steps, far from the physical memory mapping and the heap
+pub const STACK_REGION: Region = Region { base: 0x100_0000_0000, align: 4096, slots: 1 << 24 };
+
+/// Position independent kernels: 64 GiB in 2 MiB steps
+pub const KERNEL_REGION: Region = Region { base: 0x40_0000_0000, align: 1 << 21, slots: 1 << 15 };
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+/// `Elf64_Rela`: offset, info (symbol and type), addend
+const RELA_ENTRY_SIZE: u64 = 24;
+
+fn has_rdrand() -> bool {
+    let cpuid = __cpuid(1);
+    cpuid.ecx & (1 << 30) != 0
+}
+
+fn rdrand() -> Option<u64> {
+    // the instruction may fail transiently when the entropy source is drained
+    for _ in 0..10 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand {}; setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+pub struct Rng {
+    rdrand: bool,
+    state: u64,
+}
+
+impl Rng {
+    pub fn new() -> Rng {
+        let rdrand = has_rdrand();
+        if !rdrand {
+            log::warn!("[kaslr] RDRAND is not supported, seeding from the TSC");
+        }
+        Rng { rdrand, state: get_tsc() }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        if self.rdrand {
+            if let Some(value) = rdrand() {
+                return value;
+            }
+        }
+
+        // splitmix64, mixed with the TSC again so every call picks up some jitter
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15) ^ get_tsc();
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Random address in `region`, `region.base` when KASLR is off
+    pub fn place(&mut self, region: &Region) -> u64 {
+        if !KASLR {
+            return region.base;
+        }
+        region.base + (self.next_u64() % region.slots) * region.align
+    }
+}
+
+/// Whether the kernel can be loaded at another address than it was linked at
+pub fn is_relocatable(elf_file: &ElfFile) -> bool {
+    matches!(elf_file.header.pt2.type_().as_type(), header::Type::SharedObject)
+}
+
+/// Translates a link-time virtual address of the kernel to its offset in the file
+fn file_offset(elf_file: &ElfFile, virt: u64) -> Option<u64> {
+    elf_file.program_iter()
+        .filter(|header| matches!(header.get_type(), Ok(program::Type::Load)))
+        .find(|header| virt >= header.virtual_addr() && virt + 8 <= header.virtual_addr() + header.file_size())
+        .map(|header| header.offset() + virt - header.virtual_addr())
+}
+
+/// Finds the relocation table through the dynamic segment (`DT_RELA`, `DT_RELASZ`), which is
+/// there even when the section headers are stripped. `.rela.dyn` is used if there is none.
+/// Returns the file offset and size of the table.
+fn rela_table(elf_file: &ElfFile) -> Result<Option<(u64, u64)>, &'static str> {
+    let dynamic = elf_file.program_iter()
+        .find(|header| matches!(header.get_type(), Ok(program::Type::Dynamic)));
+
+    let Some(dynamic) = dynamic else {
+        return Ok(elf_file.find_section_by_name(".rela.dyn")
+            .map(|section| (section.offset(), section.size())));
+    };
+
+    let SegmentData::Dynamic64(entries) = dynamic.get_data(elf_file)? else {
+        return Err("Kernel dynamic segment is not 64-bit");
+    };
+    let (mut table, mut size, mut entry_size) = (None, 0, RELA_ENTRY_SIZE);
+    for entry in entries {
+        match entry.get_tag()? {
+            Tag::Rela => table = Some(entry.get_ptr()?),
+            Tag::RelaSize => size = entry.get_val()?,
+            Tag::RelaEnt => entry_size = entry.get_val()?,
+            Tag::Null => break,
+            _ => {}
+        }
+    }
+    if entry_size != RELA_ENTRY_SIZE {
+        return Err("Unexpected kernel relocation entry size");
+    }
+
+    match table {
+        Some(table) => {
+            let offset = file_offset(elf_file, table).ok_or("Kernel relocation table outside of the loaded segments")?;
+            Ok(Some((offset, size)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Applies the relative relocations of a position independent kernel for a load at `slide`.
+/// The image at `kernel` is patched in place, before it is mapped.
+pub fn relocate(elf_file: &ElfFile, kernel: *mut u8, slide: u64) -> Result<usize, &'static str> {
+    // a page table entry can't move a segment by less than its alignment
+    let max_align = elf_file.program_iter()
+        .filter(|header| matches!(header.get_type(), Ok(program::Type::Load)))
+        .map(|header| header.align())
+        .max()
+        .unwrap_or(1);
+    if slide % max_align.max(1) != 0 {
+        return Err("Kernel slide is not a multiple of the segment alignment");
+    }
+
+    let Some((table, size)) = rela_table(elf_file)? else {
+        return Ok(0);
+    };
+    let entries = elf_file.input.get(table as usize..(table + size) as usize)
+        .ok_or("Kernel relocation table is out of the file")?;
+
+    let mut count = 0;
+    for rela in entries.chunks_exact(RELA_ENTRY_SIZE as usize) {
+        let field = |index: usize| u64::from_le_bytes(rela[index * 8..index * 8 + 8].try_into().unwrap());
+        let (virt, info, addend) = (field(0), field(1), field(2));
+
+        match info as u32 {
+            R_X86_64_NONE => continue,
+            R_X86_64_RELATIVE => {}
+            _ => return Err("Unsupported relocation type in kernel"),
+        }
+        let offset = file_offset(elf_file, virt)
+            .ok_or("Kernel relocation outside of the loaded segments")?;
+        unsafe {
+            (kernel.add(offset as usize) as *mut u64).write_unaligned(slide.wrapping_add(addend));
+        }
+        count += 1;
+    }
+    Ok(count)
+}
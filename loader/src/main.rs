@@ -5,31 +5,45 @@
 extern crate shared_lib;
 extern crate alloc;
 
+mod kaslr;
+mod decompress;
+mod config;
+mod menu;
+mod exceptions;
+
+use alloc::vec::Vec;
 use core::{
     panic::PanicInfo,
     arch::asm,
-    ptr::addr_of,
+    ptr::{addr_of, addr_of_mut},
     slice::{
         from_raw_parts_mut,
         from_raw_parts
     }
 };
 use uefi::prelude::entry;
-use uefi::proto::media::file::File;
-use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams, AllocateType, MemoryType};
+use uefi::proto::media::file::{File, FileInfo};
+use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams, AllocateType, MemoryType, MemoryAttribute, MemoryDescriptor};
 use uefi::proto::media::{
     file::{FileMode, FileAttribute, RegularFile},
     fs::SimpleFileSystem
 };
 use uefi::data_types::CStr16;
-use uefi::proto::console::gop::GraphicsOutput;
+use uefi::proto::console::gop::{GraphicsOutput, Mode, PixelFormat};
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::table::boot::BootServices;
 use xmas_elf::{ElfFile, header, program};
 use shared_lib::addr::{PhysAddr, VirtAddr};
 use shared_lib::logger::FrameBufferInfo;
-use shared_lib::page_table::{PageTable, PageTablesAllocator, map_address, remap_address, align_down, align_down_u64};
-use shared_lib::{BootInfo, logger, VIRT_MAPPING_OFFSET};
+use shared_lib::page_table::{PageTable, PageTableFlags, PageTablesAllocator, map_address, map_huge_2mb, remap_address, align_down, align_down_u64, enable_no_execute, HUGE_PAGE_2MB_SIZE};
+use shared_lib::{BootInfo, logger, phys_mapping_offset, set_phys_mapping_offset};
+use shared_lib::boot_info::{ApTrampoline, EfiRuntimeServices, Initrd, KernelSlide, NextFreeFrame, PhysMappingOffset, Rsdp, StackGuard, Symbols};
+use shared_lib::ab_boot::{self, Slot};
+use shared_lib::nvram;
+use shared_lib::msr;
+use shared_lib::efi::{self, EfiMemoryMap};
 use shared_lib::allocator::ALLOCATOR;
-use shared_lib::frame_allocator::{MemoryRegion, FrameAllocator, MemoryMap, MAX_MEMORY_MAP_SIZE, MEMORY_MAP_PAGES};
+use shared_lib::frame_allocator::{MemoryRegion, FrameAllocator, MemoryMap, MAX_MEMORY_MAP_SIZE};
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -46,10 +60,14 @@ fn panic(info: &PanicInfo) -> ! {
     }
 }
 
+/// Pages of the initial ramdisk. An OS defined type, so they show up as reserved in the memory map
+/// and the kernel's frame allocator leaves them alone.
+const INITRD_MEMORY: MemoryType = MemoryType::custom(0x8000_0000);
+
 fn convert_memory_type(t: MemoryType) -> shared_lib::frame_allocator::MemoryType {
     match t {
         MemoryType::MMIO_PORT_SPACE | MemoryType::MMIO
-        | MemoryType::RESERVED | MemoryType::UNUSABLE => shared_lib::frame_allocator::MemoryType::Reserved,
+        | MemoryType::RESERVED | MemoryType::UNUSABLE | INITRD_MEMORY => shared_lib::frame_allocator::MemoryType::Reserved,
 
         MemoryType::PERSISTENT_MEMORY | MemoryType::CONVENTIONAL
         | MemoryType::LOADER_DATA | MemoryType::LOADER_CODE
@@ -66,7 +84,37 @@ fn convert_memory_type(t: MemoryType) -> shared_lib::frame_allocator::MemoryType
     }
 }
 
-fn init_framebuffer(image: uefi::Handle, system_table: &mut uefi::table::SystemTable<uefi::table::Boot>)
+/// Picks the GOP mode to switch to: the preferred resolution if the firmware offers it with a
+/// 32-bpp RGB or BGR pixel format, otherwise the largest such mode
+fn select_mode(gop: &GraphicsOutput, boot_services: &BootServices, preferred: Option<(usize, usize)>) -> Option<Mode> {
+    let direct = |mode: &Mode| matches!(mode.info().pixel_format(), PixelFormat::Rgb | PixelFormat::Bgr);
+
+    if let Some(preferred) = preferred {
+        let mode = gop.modes(boot_services)
+            .filter(direct)
+            .find(|mode| mode.info().resolution() == preferred);
+        if mode.is_some() {
+            return mode;
+        }
+    }
+
+    gop.modes(boot_services)
+        .filter(direct)
+        .max_by_key(|mode| {
+            let (width, height) = mode.info().resolution();
+            width * height
+        })
+}
+
+/// Video mode saved by the kernel's `config set video_mode`
+fn nvram_video_mode() -> Option<(usize, usize)> {
+    match (nvram::read_u16(nvram::SETTING_VIDEO_WIDTH), nvram::read_u16(nvram::SETTING_VIDEO_HEIGHT)) {
+        (Ok(width), Ok(height)) if width != 0 && height != 0 => Some((width as usize, height as usize)),
+        _ => None,
+    }
+}
+
+fn init_framebuffer(image: uefi::Handle, system_table: &mut uefi::table::SystemTable<uefi::table::Boot>, preferred: Option<(usize, usize)>)
     -> Result<FrameBufferInfo, &'static str> {
     let gop_handle = system_table
         .boot_services()
@@ -86,6 +134,13 @@ fn init_framebuffer(image: uefi::Handle, system_table: &mut uefi::table::SystemT
             .expect("Failed to open GOP protocol")
     };
 
+    // the firmware may leave us in a small mode such as 640x480
+    if let Some(mode) = select_mode(&gop, system_table.boot_services(), preferred) {
+        if mode.info().resolution() != gop.current_mode_info().resolution() {
+            gop.set_mode(&mode).map_err(|_| "Failed to set GOP mode")?;
+        }
+    }
+
     let mode_info = gop.current_mode_info();
 
     Ok(FrameBufferInfo {
@@ -103,10 +158,124 @@ fn init_framebuffer(image: uefi::Handle, system_table: &mut uefi::table::SystemT
     })
 }
 
-fn load_kernel(image: uefi::Handle, system_table: &mut uefi::table::SystemTable<uefi::table::Boot>, kernel_max_size: usize)
-    -> Result<*const u8, &'static str> {
-    let pages_count = 1 + kernel_max_size / 4096;
+/// Kernel images the boot menu offers, in this order
+const KERNEL_FILES: [&str; 4] = ["kernel", "kernel.old", "kernel.a", "kernel.b"];
+
+/// The image the `boot_entry` setting picks, 1 for the first of `KERNEL_FILES`
+fn nvram_boot_entry() -> Option<&'static str> {
+    match nvram::read_u8(nvram::SETTING_BOOT_ENTRY) {
+        Ok(entry) if entry > 0 => KERNEL_FILES.get(entry as usize - 1).copied(),
+        _ => None,
+    }
+}
+
+/// Picks the kernel to boot: the one of the `boot_entry` setting if it is there, else
+/// `kernel.a` or `kernel.b` if there are A/B images, `kernel` otherwise, unless the boot menu is
+/// shown and another image is chosen there. Returns the file name and the A/B slot it belongs to.
+fn select_kernel(image: uefi::Handle, system_table: &mut uefi::table::SystemTable<uefi::table::Boot>, menu_timeout: u64)
+    -> Result<(&'static str, Option<Slot>), &'static str> {
+    let mut present = Vec::new();
+    for name in KERNEL_FILES {
+        if let Some(stamp) = file_stamp(image, system_table, name)? {
+            present.push((name, stamp));
+        }
+    }
+    let stamp = |slot: Slot| present.iter().find(|(name, _)| *name == slot.file_name()).map(|(_, stamp)| *stamp);
+
+    let state = ab_boot::load_state();
+    let ab_choice = ab_boot::choose(state, stamp(Slot::A), stamp(Slot::B));
+    let default = nvram_boot_entry()
+        .filter(|entry| present.iter().any(|(name, _)| name == entry))
+        .unwrap_or_else(|| ab_choice.map_or("kernel", |(slot, _)| slot.file_name()));
+
+    let names: Vec<&'static str> = present.iter().map(|(name, _)| *name).collect();
+    let selected = if names.len() > 1 && menu_timeout > 0 {
+        let default_index = names.iter().position(|name| *name == default).unwrap_or(0);
+        names[menu::run(system_table, &names, default_index, menu_timeout)]
+    } else {
+        default
+    };
+
+    let Some((slot, new_state)) = ab_choice.filter(|(slot, _)| slot.file_name() == selected) else {
+        // picked by hand, the trial state stays as it is
+        if selected != default {
+            log::info!("Boot menu: {} selected instead of {}", selected, default);
+        }
+        return Ok((selected, [Slot::A, Slot::B].into_iter().find(|slot| slot.file_name() == selected)));
+    };
+
+    log::info!("A/B kernels: booting slot {}, last good slot {:?}, trial {:?} started {} times",
+        slot.name(), state.good, new_state.trial, new_state.attempts);
+    if let Some((trial, _)) = new_state.trial.filter(|(trial, _)| *trial != slot) {
+        if new_state.attempts >= ab_boot::MAX_TRIAL_BOOTS {
+            log::warn!("Kernel in slot {} never confirmed its boot, falling back to slot {}", trial.name(), slot.name());
+        }
+    }
+    ab_boot::store_state(new_state);
+    Ok((selected, Some(slot)))
+}
+
+/// Modification time of the file `name` as a number that grows with time, None if it doesn't
+/// exist
+fn file_stamp(image: uefi::Handle, system_table: &mut uefi::table::SystemTable<uefi::table::Boot>, name: &str)
+    -> Result<Option<u32>, &'static str> {
+    let Some(mut file) = open_file(image, system_table, name)? else {
+        return Ok(None);
+    };
+
+    let mut info_buffer = FileInfoBuffer([0; 512]);
+    let time = *file.get_info::<FileInfo>(&mut info_buffer.0)
+        .map_err(|_| "Failed to get file info")?
+        .modification_time();
+
+    // seconds since 1980 with 31 day months: ordered, fits into 32 bits for over a century
+    let months = (time.year() as u32).saturating_sub(1980) * 12 + time.month() as u32 - 1;
+    let days = months * 31 + time.day() as u32 - 1;
+    Ok(Some(((days * 24 + time.hour() as u32) * 60 + time.minute() as u32) * 60 + time.second() as u32))
+}
 
+/// Loads the file `name` into pages of `memory_type`, decompressing it first if it was stored
+/// compressed
+fn load_image(image: uefi::Handle, system_table: &mut uefi::table::SystemTable<uefi::table::Boot>, name: &str, memory_type: MemoryType)
+    -> Result<Option<&'static [u8]>, &'static str> {
+    let Some(file) = load_file(image, system_table, name, memory_type)? else {
+        return Ok(None);
+    };
+
+    let Some(format) = decompress::detect(file) else {
+        return Ok(Some(file));
+    };
+
+    let size = decompress::decompressed_size(format, file)?;
+    log::info!("{} is {:?} compressed: {} bytes, {} bytes uncompressed", name, format, file.len(), size);
+
+    let buffer = allocate_buffer(system_table, size, memory_type)?;
+    let size = decompress::decompress_into(format, file, buffer)?;
+
+    unsafe {
+        system_table.boot_services()
+            .free_pages(file.as_ptr() as u64, file.len().div_ceil(4096))
+            .map_err(|_| "Failed to free compressed file")?;
+    }
+    Ok(Some(&buffer[..size]))
+}
+
+fn allocate_buffer(system_table: &mut uefi::table::SystemTable<uefi::table::Boot>, size: usize, memory_type: MemoryType)
+    -> Result<&'static mut [u8], &'static str> {
+    let ptr = system_table
+        .boot_services()
+        .allocate_pages(AllocateType::AnyPages, memory_type, size.div_ceil(4096).max(1))
+        .map_err(|_| "Failed to allocate pages for file")?;
+    Ok(unsafe { from_raw_parts_mut(ptr as *mut u8, size) })
+}
+
+/// `FileInfo` has to be 8 byte aligned
+#[repr(C, align(8))]
+struct FileInfoBuffer([u8; 512]);
+
+/// Opens the file `name` in the root of the ESP, None if it doesn't exist
+fn open_file(image: uefi::Handle, system_table: &mut uefi::table::SystemTable<uefi::table::Boot>, name: &str)
+    -> Result<Option<RegularFile>, &'static str> {
     let fs_handle = system_table
         .boot_services()
         .get_handle_for_protocol::<SimpleFileSystem>()
@@ -128,28 +297,54 @@ fn load_kernel(image: uefi::Handle, system_table: &mut uefi::table::SystemTable<
     let mut root_fs = fs.open_volume().expect("Failed to open volume");
 
     let mut buff: [u16; 16] = [0; 16];
-    let kernel_name = CStr16::from_str_with_buf("kernel", &mut buff)
-        .expect("Failed to create CStr16");
-    let handle = root_fs.open(kernel_name, FileMode::Read, FileAttribute::READ_ONLY)
-        .expect("Failed to open kernel file");
-
-    let mut file = unsafe { RegularFile::new(handle) };
-
-    let kernel = {
-        let ptr = system_table
-            .boot_services()
-            .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages_count)
-            .expect("Failed to allocate page for kernel");
-        unsafe { from_raw_parts_mut(ptr as *mut u8, kernel_max_size) }
+    let file_name = CStr16::from_str_with_buf(name, &mut buff)
+        .map_err(|_| "Failed to create CStr16")?;
+    let handle = match root_fs.open(file_name, FileMode::Read, FileAttribute::READ_ONLY) {
+        Ok(handle) => handle,
+        Err(_) => return Ok(None)
     };
 
-    file.read(kernel)
-        .expect("Failed to read kernel file");
+    Ok(Some(unsafe { RegularFile::new(handle) }))
+}
 
-    Ok(kernel.as_ptr())
+fn load_file(image: uefi::Handle, system_table: &mut uefi::table::SystemTable<uefi::table::Boot>, name: &str, memory_type: MemoryType)
+    -> Result<Option<&'static [u8]>, &'static str> {
+    let Some(mut file) = open_file(image, system_table, name)? else {
+        return Ok(None);
+    };
+
+    let mut info_buffer = FileInfoBuffer([0; 512]);
+    let file_size = file.get_info::<FileInfo>(&mut info_buffer.0)
+        .map_err(|_| "Failed to get file info")?
+        .file_size() as usize;
+
+    let buffer = allocate_buffer(system_table, file_size, memory_type)?;
+
+    let size = file.read(buffer)
+        .map_err(|_| "Failed to read file")?;
+
+    Ok(Some(&buffer[..size]))
+}
+
+/// Page aligned start and page count of `size` bytes at `addr`
+fn page_range(addr: u64, size: usize) -> (u64, usize) {
+    let start = align_down_u64(addr);
+    (start, (addr + size as u64 - start).div_ceil(4096) as usize)
 }
 
-unsafe fn init_allocator(memory_map: uefi::table::boot::MemoryMap)
+/// Pages of the loader image, its statics (the boot information, the memory map, the exception
+/// tables) are used by the kernel until it has set up its own
+fn loader_image_range(image: uefi::Handle, system_table: &uefi::table::SystemTable<uefi::table::Boot>) -> (u64, usize) {
+    let loaded_image = system_table.boot_services()
+        .open_protocol_exclusive::<LoadedImage>(image)
+        .expect("Failed to open loaded image protocol");
+    let (base, size) = loaded_image.info();
+    page_range(base as u64, size as usize)
+}
+
+/// Builds the memory map for the kernel and an allocator on it. The LOADER_DATA pages in
+/// `in_use` (kernel image, stack, ...) are marked as in use, all other loader memory is free.
+unsafe fn init_allocator(memory_map: uefi::table::boot::MemoryMap, in_use: &[(u64, usize)])
                          -> Result<(FrameAllocator, MemoryMap), &'static str> {
     static mut MMAP: MemoryMap = MemoryMap {
         entries: [ MemoryRegion{ ty: shared_lib::frame_allocator::MemoryType::Reserved, addr: 0, page_count: 0 }; MAX_MEMORY_MAP_SIZE ],
@@ -174,6 +369,11 @@ unsafe fn init_allocator(memory_map: uefi::table::boot::MemoryMap)
     }
     MMAP.next_free_entry_idx = (memory_map.entries().len()) as u64;
 
+    // before the allocator is created: it counts the free frames it takes in map order
+    for &(addr, page_count) in in_use {
+        MMAP.mark_in_use(addr, page_count).map_err(|_| "Memory map is full")?;
+    }
+
     Ok((FrameAllocator::new(addr_of!(MMAP), 0, 0), MMAP.clone()))
 }
 
@@ -183,9 +383,39 @@ struct MappedEntry {
     pub frame: u64
 }
 
-fn map_kernel(elf_file: &ElfFile, kernel: u64, page_table: &mut PageTable, allocator: &mut FrameAllocator) -> Result<(), &'static str> {
+const DATA_PAGE_FLAGS: PageTableFlags = PageTableFlags::WRITABLE.union(PageTableFlags::NO_EXECUTE);
+
+/// Page permissions of an ELF segment: `.text` is read-only and executable, `.rodata` read-only
+/// and `.data`/`.bss` writable, both non-executable.
+fn segment_page_flags(flags: program::Flags) -> PageTableFlags {
+    let mut page_flags = PageTableFlags::empty();
+    if flags.is_write() {
+        page_flags |= PageTableFlags::WRITABLE;
+    }
+    if !flags.is_execute() {
+        page_flags |= PageTableFlags::NO_EXECUTE;
+    }
+    page_flags
+}
+
+/// `PT_TLS` segment of the kernel: the initial image of its `#[thread_local]` statics
+#[derive(Debug, Clone, Copy)]
+struct TlsTemplate {
+    /// Address of the initialized part in the loaded kernel file
+    file_addr: u64,
+    file_size: u64,
+    mem_size: u64,
+    align: u64,
+}
+
+/// Room above the thread pointer for the thread control block, which is only the self pointer
+const TCB_SIZE: u64 = 8;
+
+/// Maps the segments of the kernel. Returns its TLS template, if it has one.
+fn map_kernel(elf_file: &ElfFile, kernel: u64, slide: u64, page_table: &mut PageTable, allocator: &mut FrameAllocator) -> Result<Option<TlsTemplate>, &'static str> {
     let mut mapped_frames: [MappedEntry; 100] = [ MappedEntry{ page: VirtAddr::zero(), frame: 0 }; 100 ];
     let mut mapped_frames_counter = 0;
+    let mut tls = None;
 
     for header in elf_file.program_iter() {
         match header.get_type().unwrap() {
@@ -193,11 +423,13 @@ fn map_kernel(elf_file: &ElfFile, kernel: u64, page_table: &mut PageTable, alloc
                 let phys_start_addr = (kernel as u64) + header.offset();
                 let phys_end_addr = phys_start_addr + header.file_size();
 
-                let virt_start_addr = VirtAddr::new_checked(header.virtual_addr())
+                let virt_start_addr = VirtAddr::new_checked(header.virtual_addr() + slide)
                     .expect("Got bad virtual address from ELF");
 
-                log::debug!("[kernel map] segment: {}, phys_start: {:#x}, phys_end: {:#x}. header file size: {}",
-                    virt_start_addr, phys_start_addr, phys_end_addr, header.file_size());
+                let flags = segment_page_flags(header.flags());
+
+                log::debug!("[kernel map] segment: {}, phys_start: {:#x}, phys_end: {:#x}. header file size: {}. flags: {:?}",
+                    virt_start_addr, phys_start_addr, phys_end_addr, header.file_size(), flags);
 
                 if header.file_size() != 0 {
                     let virt_start_addr_aligned = align_down(virt_start_addr);
@@ -209,7 +441,7 @@ fn map_kernel(elf_file: &ElfFile, kernel: u64, page_table: &mut PageTable, alloc
 
                         log::debug!("[kernel map] Mapping {} to {:#x}", virt, phys);
                         unsafe {
-                            map_address(page_table, virt, phys, allocator)
+                            map_address(page_table, virt, phys, flags, allocator)
                                 .expect("Failed to map kernel");
                         }
                         mapped_frames[mapped_frames_counter] = MappedEntry { page: virt, frame: phys };
@@ -221,7 +453,7 @@ fn map_kernel(elf_file: &ElfFile, kernel: u64, page_table: &mut PageTable, alloc
 
                     log::debug!("[kernel map] Mapping {} to {:#x}", virt_start_addr_aligned, phys_start_addr_aligned);
                     unsafe {
-                        map_address(page_table, virt_start_addr_aligned, phys_start_addr_aligned, allocator)
+                        map_address(page_table, virt_start_addr_aligned, phys_start_addr_aligned, flags, allocator)
                             .expect("Failed to map kernel");
                     }
                     mapped_frames[mapped_frames_counter] = MappedEntry { page: virt_start_addr_aligned, frame: phys_start_addr_aligned };
@@ -243,7 +475,7 @@ fn map_kernel(elf_file: &ElfFile, kernel: u64, page_table: &mut PageTable, alloc
                             for i in 0..mapped_frames_counter {
                                 if mapped_frames[i].frame == frame_to_copy {
                                     log::debug!("[kernel map] Remapping {} to {:#x}", mapped_frames[i].page, frame);
-                                    remap_address(page_table, mapped_frames[i].page, frame, allocator)
+                                    remap_address(page_table, mapped_frames[i].page, frame, flags, allocator)
                                         .expect("Failed to map kernel");
                                 }
                             }
@@ -276,7 +508,7 @@ fn map_kernel(elf_file: &ElfFile, kernel: u64, page_table: &mut PageTable, alloc
                             log::debug!("[kernel map] Mapping {} to {:#x}", virt_ptr, frame);
 
                             unsafe {
-                                map_address(page_table, virt_ptr, frame, allocator)
+                                map_address(page_table, virt_ptr, frame, flags, allocator)
                                     .expect("Failed to map kernel");
                                 core::ptr::write_bytes(
                                     frame as *mut u8,
@@ -288,11 +520,42 @@ fn map_kernel(elf_file: &ElfFile, kernel: u64, page_table: &mut PageTable, alloc
                     }
                 }
             }
-            program::Type::Tls => { unimplemented!("Not implemented TLS section") }
+            program::Type::Tls => {
+                // .tdata is also part of a PT_LOAD segment, so it is already relocated
+                log::debug!("[kernel map] TLS template: {} bytes, {} initialized, align {}",
+                    header.mem_size(), header.file_size(), header.align());
+                tls = Some(TlsTemplate {
+                    file_addr: kernel + header.offset(),
+                    file_size: header.file_size(),
+                    mem_size: header.mem_size(),
+                    align: header.align().max(1),
+                });
+            }
             _ => {}
         }
     }
-    Ok(())
+    Ok(tls)
+}
+
+/// Creates the kernel's TLS block from `template`, with the x86_64 layout: the block ends where
+/// the thread pointer points, at the TCB. Returns the virtual address of the thread pointer, to
+/// be loaded into FS_BASE.
+fn create_tls(template: &TlsTemplate, allocator: &mut FrameAllocator) -> Result<u64, &'static str> {
+    let tls_size = template.mem_size.next_multiple_of(template.align);
+    let frames = (tls_size + TCB_SIZE).div_ceil(4096) as usize;
+    let block = allocator.alloc_contiguous(frames, (template.align as usize).max(4096))
+        .ok_or("Failed to allocate TLS block")?;
+    let thread_pointer = block + tls_size;
+
+    unsafe {
+        core::ptr::copy(template.file_addr as *const u8, block as *mut u8, template.file_size as usize);
+        core::ptr::write_bytes((block + template.file_size) as *mut u8, 0, (tls_size - template.file_size) as usize);
+        // `mov rax, fs:0` must give the thread pointer itself
+        (thread_pointer as *mut u64).write(thread_pointer + phys_mapping_offset());
+    }
+
+    log::info!("Kernel TLS: {} bytes at {:#x}", tls_size, block);
+    Ok(thread_pointer + phys_mapping_offset())
 }
 
 fn map_framebuffer(framebuffer: &FrameBufferInfo, page_table: &mut PageTable, allocator: &mut FrameAllocator) -> Result<(), &'static str> {
@@ -304,7 +567,7 @@ fn map_framebuffer(framebuffer: &FrameBufferInfo, page_table: &mut PageTable, al
     for i in 0..pages_needed_for_fb {
         let ptr = fb_start + i as u64 * 4096;
         unsafe {
-            map_address(page_table, VirtAddr::new_checked(ptr + VIRT_MAPPING_OFFSET).unwrap(), ptr, allocator)
+            map_address(page_table, VirtAddr::new_checked(ptr + phys_mapping_offset()).unwrap(), ptr, DATA_PAGE_FLAGS, allocator)
                 .expect("Failed to map framebuffer");
         }
     }
@@ -312,51 +575,125 @@ fn map_framebuffer(framebuffer: &FrameBufferInfo, page_table: &mut PageTable, al
     Ok(())
 }
 
-fn create_stack(stack_addr: PhysAddr, stack_depth: usize, page_table: &mut PageTable, allocator: &mut FrameAllocator) -> Result<u64, &'static str> {
-    log::info!("Mapping stack");
-    for i in 0..stack_depth {
-        let ptr = stack_addr.0 + i as u64 * 4096;
+/// Maps the `stack_depth` pages above `stack_addr` at `stack_virt` and the pages after it. The
+/// page at `stack_virt` itself stays unmapped as a guard page, so an overflow faults instead of
+/// corrupting the memory below.
+fn create_stack(stack_addr: PhysAddr, stack_virt: u64, stack_depth: usize, page_table: &mut PageTable, allocator: &mut FrameAllocator) -> Result<u64, &'static str> {
+    log::info!("Mapping stack, guard page at {:#x}", stack_virt);
+    for i in 1..=stack_depth {
+        let offset = i as u64 * 4096;
         unsafe {
-            map_address(page_table, VirtAddr::new_checked(ptr).unwrap(), ptr, allocator)
+            map_address(page_table, VirtAddr::new_checked(stack_virt + offset).unwrap(), stack_addr.0 + offset, DATA_PAGE_FLAGS, allocator)
                 .expect("Failed to map stack");
         }
     }
-    Ok(stack_addr.0 + (stack_depth as u64 - 1) * 4096)
+    Ok(stack_virt + (stack_depth as u64 + 1) * 4096)
 }
 
-fn setup_mappings(last_frame_addr: PhysAddr, page_table: &mut PageTable, allocator: &mut FrameAllocator, kernel: *const u8, kernel_size: usize, framebuffer: &FrameBufferInfo) -> VirtAddr {
+/// Maps everything the kernel needs and returns its entry point and the slide it was loaded at
+fn setup_mappings(last_frame_addr: PhysAddr, page_table: &mut PageTable, allocator: &mut FrameAllocator, kernel: *const u8, kernel_size: usize,
+                  framebuffer: &FrameBufferInfo, rng: &mut kaslr::Rng) -> (VirtAddr, u64, Option<u64>) {
     let elf_file = ElfFile::new(unsafe { from_raw_parts(kernel, kernel_size) }).unwrap();
     header::sanity_check(&elf_file).expect("Failed to parse kernel file. Expected ELF");
 
+    let slide = if kaslr::is_relocatable(&elf_file) {
+        let slide = rng.place(&kaslr::KERNEL_REGION);
+        let count = kaslr::relocate(&elf_file, kernel as *mut u8, slide)
+            .expect("Failed to relocate kernel");
+        log::info!("Kernel slide: {:#x}, {} relocations applied", slide, count);
+        slide
+    } else {
+        if kaslr::KASLR {
+            log::info!("Kernel is not position independent, loading it at its link address");
+        }
+        0
+    };
+
     log::info!("Mapping all memory. Last frame: {:#x}", last_frame_addr.0);
 
-    for i in 0..(last_frame_addr.0 / 4096) {
-        let phys =  i * 4096;
-        let virt = VirtAddr::new(phys + VIRT_MAPPING_OFFSET);
+    // 2 MiB pages: a fraction of the page tables and of the time of 4 KiB mappings
+    for i in 0..last_frame_addr.0.div_ceil(HUGE_PAGE_2MB_SIZE) {
+        let phys = i * HUGE_PAGE_2MB_SIZE;
+        let virt = VirtAddr::new(phys + phys_mapping_offset());
 
         unsafe {
-            map_address(page_table, virt, phys, allocator)
+            map_huge_2mb(page_table, virt, phys, DATA_PAGE_FLAGS, allocator, 0)
                 .expect("Failed to map memory");
         }
     }
 
-    map_kernel(&elf_file, kernel as u64, page_table, allocator)
+    let tls = map_kernel(&elf_file, kernel as u64, slide, page_table, allocator)
         .expect("Failed to map kernel");
+    let thread_pointer = tls.map(|template| create_tls(&template, allocator).expect("Failed to create kernel TLS"));
 
     map_framebuffer(&framebuffer, page_table, allocator)
         .expect("Failed to map framebuffer");
 
     unsafe {
         let ctx_switch_ptr = context_switch as *const () as u64;
-        map_address(page_table, align_down(VirtAddr::new_checked(ctx_switch_ptr).unwrap()), align_down_u64(ctx_switch_ptr), allocator)
+        // runs right after the switch to the new page table, so it must stay executable
+        map_address(page_table, align_down(VirtAddr::new_checked(ctx_switch_ptr).unwrap()), align_down_u64(ctx_switch_ptr), PageTableFlags::empty(), allocator)
             .expect("Failed to map context switch function");
     }
 
-    VirtAddr::new_checked(elf_file.header.pt2.entry_point()).unwrap()
+    (VirtAddr::new_checked(elf_file.header.pt2.entry_point() + slide).unwrap(), slide, thread_pointer)
+}
+
+/// Copies the firmware's memory map for the kernel, runtime regions with the virtual address
+/// `map_runtime_regions` gives them
+fn efi_memory_map(memory_map: &uefi::table::boot::MemoryMap) -> EfiMemoryMap {
+    let mut map = EfiMemoryMap::empty();
+    for descriptor in memory_map.entries() {
+        let runtime = descriptor.att.contains(MemoryAttribute::RUNTIME);
+        let copied = map.push(efi::MemoryDescriptor {
+            ty: descriptor.ty.0,
+            phys_start: descriptor.phys_start,
+            virt_start: if runtime { descriptor.phys_start + efi::RUNTIME_MAPPING_OFFSET } else { 0 },
+            page_count: descriptor.page_count,
+            attribute: descriptor.att.bits(),
+        });
+        if !copied {
+            log::warn!("EFI memory map has more than {} entries, the rest is not passed on", MAX_MEMORY_MAP_SIZE);
+            break;
+        }
+    }
+    map
+}
+
+/// Maps the runtime regions for the kernel: code executable, MMIO uncached. The code regions
+/// stay writable, firmware images keep their data in them.
+fn map_runtime_regions(map: &EfiMemoryMap, page_table: &mut PageTable, allocator: &mut FrameAllocator) -> Result<(), &'static str> {
+    for descriptor in map.descriptors().iter().filter(|descriptor| descriptor.is_runtime()) {
+        let flags = match descriptor.ty {
+            efi::RUNTIME_SERVICES_CODE => PageTableFlags::WRITABLE,
+            efi::MEMORY_MAPPED_IO | efi::MEMORY_MAPPED_IO_PORT_SPACE => DATA_PAGE_FLAGS | PageTableFlags::NO_CACHE,
+            _ => DATA_PAGE_FLAGS,
+        };
+        for page in 0..descriptor.page_count {
+            unsafe {
+                map_address(page_table, VirtAddr::new(descriptor.virt_start + page * 4096), descriptor.phys_start + page * 4096, flags, allocator)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Moves the runtime services to the addresses of `map_runtime_regions`. Only possible while the
+/// firmware's identity mapping is active. Returns the new address of the runtime services table.
+fn set_virtual_address_map(system_table: uefi::table::SystemTable<uefi::table::Runtime>, regions: &mut [MemoryDescriptor])
+    -> Result<u64, &'static str> {
+    let system_table_phys = system_table.as_ptr() as u64;
+    let runtime_services_phys = unsafe { system_table.runtime_services() } as *const _ as u64;
+
+    unsafe {
+        system_table.set_virtual_address_map(regions, system_table_phys + efi::RUNTIME_MAPPING_OFFSET)
+            .map_err(|_| "SetVirtualAddressMap failed")?;
+    }
+    Ok(runtime_services_phys + efi::RUNTIME_MAPPING_OFFSET)
 }
 
-fn init_logger(image: uefi::Handle, system_table: &mut uefi::table::SystemTable<uefi::table::Boot>) -> FrameBufferInfo {
-    let framebuffer = init_framebuffer(image, system_table)
+fn init_logger(image: uefi::Handle, system_table: &mut uefi::table::SystemTable<uefi::table::Boot>, resolution: Option<(usize, usize)>) -> FrameBufferInfo {
+    let framebuffer = init_framebuffer(image, system_table, resolution)
         .expect("Failed to init framebuffer");
 
     let logger = logger::LOGGER.get_or_init(move || logger::LockedLogger::new(framebuffer));
@@ -369,20 +706,29 @@ fn map_bootinfo(boot_info: &BootInfo, page_table: &mut PageTable, allocator: &mu
     let boot_info_ptr = boot_info as *const _ as u64;
     log::info!("Mapping boot info. addr: {:#x}", boot_info_ptr);
 
-    unsafe {
-        map_address(page_table, align_down(VirtAddr::new_checked(boot_info_ptr).unwrap()), align_down_u64(boot_info_ptr), allocator)
-            .expect("Failed to map boot info");
-    }
-
-    for i in 0..=MEMORY_MAP_PAGES {
-        let ptr = align_down_u64(boot_info.memory_map.entries.as_ptr() as u64) + i as u64 * 4096;
+    // the entries are stored inline, including the memory maps
+    let boot_info_end = boot_info_ptr + core::mem::size_of::<BootInfo>() as u64;
+    for ptr in (align_down_u64(boot_info_ptr)..boot_info_end).step_by(4096) {
         unsafe {
-            map_address(page_table, VirtAddr::new_checked(ptr).unwrap(), ptr, allocator)
+            map_address(page_table, VirtAddr::new_checked(ptr).unwrap(), ptr, DATA_PAGE_FLAGS, allocator)
                 .expect("Failed to map boot info");
         }
     }
 }
 
+fn load_config(image: uefi::Handle, system_table: &mut uefi::table::SystemTable<uefi::table::Boot>) -> config::BootConfig {
+    match load_file(image, system_table, config::CONFIG_FILE_NAME, MemoryType::LOADER_DATA) {
+        Ok(Some(file)) => match core::str::from_utf8(file) {
+            Ok(text) => config::BootConfig::parse(text),
+            Err(_) => config::BootConfig::with_warning(
+                alloc::format!("{} is not valid UTF-8, using defaults", config::CONFIG_FILE_NAME)),
+        },
+        Ok(None) => config::BootConfig::default(),
+        Err(e) => config::BootConfig::with_warning(
+            alloc::format!("Failed to read {}: {}, using defaults", config::CONFIG_FILE_NAME, e)),
+    }
+}
+
 fn setup_heap(system_table: &mut uefi::table::SystemTable<uefi::table::Boot>) {
     let heap_size_in_pages = 25;
     let heap_addr = PhysAddr(u64::from(system_table
@@ -398,28 +744,98 @@ fn setup_heap(system_table: &mut uefi::table::SystemTable<uefi::table::Boot>) {
 #[entry]
 fn efi_main(image: uefi::Handle, mut system_table: uefi::table::SystemTable<uefi::table::Boot>) -> uefi::Status {
     setup_heap(&mut system_table);
-    let mut framebuffer = init_logger(image, &mut system_table);
+    // read before the logger is set up, the resolution decides which framebuffer it gets
+    let boot_config = load_config(image, &mut system_table);
+    let resolution = boot_config.resolution.or_else(nvram_video_mode);
+    let mut framebuffer = init_logger(image, &mut system_table, resolution);
 
     log::info!("This is a very simple UEFI bootloader");
 
-    let kernel_max_size = 100 * 4096;
-    let kernel = load_kernel(image, &mut system_table, kernel_max_size)
-        .expect("Failed to load kernel");
+    for warning in &boot_config.warnings {
+        log::warn!("[config] {}", warning);
+    }
+    log::set_max_level(boot_config.log_level);
+    log::info!("Framebuffer: {}x{}", framebuffer.width, framebuffer.height);
+    if let Some((width, height)) = resolution {
+        if (framebuffer.width, framebuffer.height) != (width, height) {
+            log::warn!("Resolution {}x{} is not available", width, height);
+        }
+    }
+    let mut cmdline = boot_config.kernel_cmdline();
+
+    let (kernel_file, slot) = select_kernel(image, &mut system_table, boot_config.menu_timeout)
+        .expect("Failed to select kernel");
+    let kernel = load_image(image, &mut system_table, kernel_file, MemoryType::LOADER_DATA)
+        .expect("Failed to load kernel")
+        .expect("Kernel file not found");
+    log::info!("Loaded {}: {} bytes", kernel_file, kernel.len());
+
+    // the kernel confirms the boot of this slot once it is up
+    if let Some(slot) = slot {
+        cmdline.push(&alloc::format!("boot_slot={}", slot.name()));
+    }
+    log::info!("Kernel command line: {}", cmdline.as_str());
+
+    // `kernel.old` comes with `symbols.old` and so on
+    let symbols_file = kernel_file.replacen("kernel", "symbols", 1);
+    let symbols = load_file(image, &mut system_table, &symbols_file, MemoryType::LOADER_DATA)
+        .expect("Failed to load symbols");
+    match symbols {
+        Some(s) => log::info!("Loaded symbols: {} bytes", s.len()),
+        None => log::info!("No symbols file, backtraces won't be symbolized")
+    }
+
+    let initrd = load_image(image, &mut system_table, "initrd", INITRD_MEMORY)
+        .expect("Failed to load initrd");
+    if let Some(initrd) = initrd {
+        log::info!("Loaded initrd: {} bytes at {:#x}", initrd.len(), initrd.as_ptr() as u64);
+    }
 
     let stack_depth = 20;
     let stack_addr = PhysAddr(u64::from(system_table
         .boot_services()
-        .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, stack_depth)
+        .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, stack_depth + 1)
         .unwrap()));
 
+    // application processors start in real mode, SIPI can only point them below 1 MiB
+    let ap_trampoline = system_table
+        .boot_services()
+        .allocate_pages(AllocateType::MaxAddress(0xF_FFFF), MemoryType::LOADER_DATA, 1)
+        .ok()
+        .filter(|&addr| addr != 0);
+    if ap_trampoline.is_none() {
+        log::warn!("No page below 1 MiB for the AP trampoline, the kernel will run on one CPU");
+    }
+
+    // everything the kernel keeps using, marked as in use in its memory map
+    let mut in_use = alloc::vec![
+        page_range(kernel.as_ptr() as u64, kernel.len()),
+        (stack_addr.0, stack_depth + 1),
+        loader_image_range(image, &system_table),
+    ];
+    if let Some(symbols) = symbols {
+        in_use.push(page_range(symbols.as_ptr() as u64, symbols.len()));
+    }
+    if let Some(ap_trampoline) = ap_trampoline {
+        in_use.push((ap_trampoline, 1));
+    }
+
     log::info!("Exiting boot services...");
     let (runtime_system_table, memory_map) = system_table.exit_boot_services(MemoryType::LOADER_DATA);
+    // SAFETY: boot services are gone, nothing relies on the firmware's descriptor tables anymore
+    unsafe { exceptions::init() };
 
     let last_memory_region = memory_map.entries().last().unwrap();
     let last_frame_addr = last_memory_region.phys_start + (last_memory_region.page_count - 1) * 4096;
 
+    let efi_memory_map = efi_memory_map(&memory_map);
+    let mut runtime_regions: Vec<MemoryDescriptor> = memory_map.entries()
+        .filter(|descriptor| descriptor.att.contains(MemoryAttribute::RUNTIME))
+        .map(|descriptor| MemoryDescriptor { virt_start: descriptor.phys_start + efi::RUNTIME_MAPPING_OFFSET, ..*descriptor })
+        .collect();
+
     let (mut allocator, memory_map) = unsafe {
-        init_allocator(memory_map)
+        init_allocator(memory_map, &in_use)
             .expect("Failed to create Allocator")
     };
 
@@ -432,11 +848,20 @@ fn efi_main(image: uefi::Handle, mut system_table: uefi::table::SystemTable<uefi
         &mut *page_table_ptr
     };
 
-    let entry_point = setup_mappings(PhysAddr(u64::from(last_frame_addr)), page_table, &mut allocator, kernel, kernel_max_size, &framebuffer);
+    if !enable_no_execute() {
+        log::warn!("No-execute pages are not supported by the CPU, data pages stay executable");
+    }
+
+    let mut rng = kaslr::Rng::new();
+    set_phys_mapping_offset(rng.place(&kaslr::PHYS_MAPPING_REGION));
+    log::info!("Physical memory mapped at {:#x}", phys_mapping_offset());
+
+    let (entry_point, kernel_slide, thread_pointer) = setup_mappings(PhysAddr(u64::from(last_frame_addr)), page_table, &mut allocator, kernel.as_ptr(), kernel.len(), &framebuffer, &mut rng);
 
-    framebuffer.addr += VIRT_MAPPING_OFFSET;
+    framebuffer.addr += phys_mapping_offset();
 
-    let stack = create_stack(stack_addr, 20, page_table, &mut allocator)
+    let stack_virt = if kaslr::KASLR { rng.place(&kaslr::STACK_REGION) } else { stack_addr.0 };
+    let stack = create_stack(stack_addr, stack_virt, stack_depth, page_table, &mut allocator)
         .expect("Failed to create stack");
 
     let rsdp_addr = {
@@ -454,22 +879,63 @@ fn efi_main(image: uefi::Handle, mut system_table: uefi::table::SystemTable<uefi
         rsdp.map(|entry| entry.address as u64)
     };
 
+    // the system table can't be used from here on, its pointers are virtual afterwards
+    map_runtime_regions(&efi_memory_map, page_table, &mut allocator)
+        .expect("Failed to map runtime services");
+    let efi_runtime_services = set_virtual_address_map(runtime_system_table, &mut runtime_regions)
+        .unwrap_or_else(|e| {
+            log::warn!("{}, runtime services won't be available", e);
+            0
+        });
+
     log::info!("Page table: {:#x}", page_table as *const PageTable as u64);
     log::info!("rsp: {:#x}", stack);
     log::info!("Jumping to kernel entry point at {:#x}", entry_point.0);
-    log::info!("Kernel address: {:#x}", kernel as u64);
+    log::info!("Kernel address: {:#x}", kernel.as_ptr() as u64);
     log::info!("FB addr: {:#x}", framebuffer.addr);
     log::info!("FB info: {:#x}", &framebuffer as *const _ as u64);
     log::info!("RSDP: {:#x}", rsdp_addr.unwrap_or(0));
 
-    let mut boot_info = BootInfo{ fb_info: framebuffer, rsdp_addr: rsdp_addr.unwrap_or(0), memory_map, memory_map_next_free_frame: 0 };
+    // a static, so it is part of the loader image and stays where the kernel finds it
+    static mut BOOT_INFO: BootInfo = BootInfo::new();
+    let boot_info = unsafe { &mut *addr_of_mut!(BOOT_INFO) };
+    boot_info.push(framebuffer).expect("Failed to fill boot info");
+    boot_info.push(cmdline).expect("Failed to fill boot info");
+    boot_info.push(StackGuard(stack_virt)).expect("Failed to fill boot info");
+    boot_info.push(PhysMappingOffset(phys_mapping_offset())).expect("Failed to fill boot info");
+    boot_info.push(KernelSlide(kernel_slide)).expect("Failed to fill boot info");
+    boot_info.push(efi_memory_map).expect("Failed to fill boot info");
+    if let Some(rsdp_addr) = rsdp_addr {
+        boot_info.push(Rsdp(rsdp_addr)).expect("Failed to fill boot info");
+    }
+    if let Some(symbols) = symbols {
+        boot_info.push(Symbols { addr: symbols.as_ptr() as u64, size: symbols.len() as u64 }).expect("Failed to fill boot info");
+    }
+    if let Some(initrd) = initrd {
+        boot_info.push(Initrd { addr: initrd.as_ptr() as u64, size: initrd.len() as u64 }).expect("Failed to fill boot info");
+    }
+    if efi_runtime_services != 0 {
+        boot_info.push(EfiRuntimeServices(efi_runtime_services)).expect("Failed to fill boot info");
+    }
+    if let Some(ap_trampoline) = ap_trampoline {
+        boot_info.push(ApTrampoline(ap_trampoline)).expect("Failed to fill boot info");
+    }
 
-    map_bootinfo(&boot_info, page_table, &mut allocator);
+    map_bootinfo(boot_info, page_table, &mut allocator);
 
-    boot_info.memory_map_next_free_frame = allocator.next;
+    // last, mapping the boot info takes frames for page tables too. The frames the loader took
+    // are in use in the memory map, so the kernel's allocator starts at the first free frame.
+    let mut memory_map = memory_map;
+    memory_map.mark_taken(allocator.next).expect("Memory map is full");
+    boot_info.push(memory_map).expect("Failed to fill boot info");
+    boot_info.push(NextFreeFrame(0)).expect("Failed to fill boot info");
 
     unsafe {
-        context_switch(page_table as *const PageTable as u64, entry_point.0, stack, &boot_info);
+        // nothing in the loader uses FS, the base only matters once the kernel runs
+        if let Some(thread_pointer) = thread_pointer {
+            msr::write(msr::IA32_FS_BASE, thread_pointer);
+        }
+        context_switch(page_table as *const PageTable as u64, entry_point.0, stack, boot_info);
     }
 }
 
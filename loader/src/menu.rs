@@ -0,0 +1,62 @@
+// Boot menu.
+//
+// With more than one kernel on the ESP the loader lists them on the UEFI text console before
+// booting. Up/Down move the selection, Enter boots it, any key stops the countdown, and when it
+// runs out the preselected default boots. Drawn with the firmware's text output, the framebuffer
+// logger only starts writing below it once a kernel is chosen.
+
+use core::fmt::Write;
+use uefi::proto::console::text::{Key, ScanCode};
+use uefi::table::{Boot, SystemTable};
+
+/// Granularity of the countdown while polling for keys
+const POLL_INTERVAL_US: usize = 10_000;
+const POLLS_PER_SEC: u64 = 1_000_000 / POLL_INTERVAL_US as u64;
+
+fn draw(system_table: &mut SystemTable<Boot>, entries: &[&str], selected: usize, seconds_left: Option<u64>) {
+    let out = system_table.stdout();
+    let _ = out.clear();
+    let _ = writeln!(out, "ferr_os boot menu\r\n\r");
+    for (index, entry) in entries.iter().enumerate() {
+        let marker = if index == selected { '>' } else { ' ' };
+        let _ = writeln!(out, " {} {}\r", marker, entry);
+    }
+    let _ = match seconds_left {
+        Some(seconds) => writeln!(out, "\r\nBooting {} in {} s, Up/Down to choose, Enter to boot\r", entries[selected], seconds),
+        None => writeln!(out, "\r\nUp/Down to choose, Enter to boot\r"),
+    };
+}
+
+/// Shows `entries` with `default` preselected and returns the index of the one to boot
+pub fn run(system_table: &mut SystemTable<Boot>, entries: &[&str], default: usize, timeout_secs: u64) -> usize {
+    let mut selected = default;
+    let mut polls_left = Some(timeout_secs * POLLS_PER_SEC);
+    draw(system_table, entries, selected, Some(timeout_secs));
+
+    loop {
+        let key = system_table.stdin().read_key().ok().flatten();
+        let Some(key) = key else {
+            if let Some(polls) = polls_left {
+                if polls == 0 {
+                    return selected;
+                }
+                if polls % POLLS_PER_SEC == 0 {
+                    draw(system_table, entries, selected, Some(polls / POLLS_PER_SEC));
+                }
+                polls_left = Some(polls - 1);
+            }
+            system_table.boot_services().stall(POLL_INTERVAL_US);
+            continue;
+        };
+
+        // the user is choosing, wait for Enter
+        polls_left = None;
+        match key {
+            Key::Special(ScanCode::UP) => selected = selected.checked_sub(1).unwrap_or(entries.len() - 1),
+            Key::Special(ScanCode::DOWN) => selected = (selected + 1) % entries.len(),
+            Key::Printable(c) if char::from(c) == '\r' => return selected,
+            _ => {}
+        }
+        draw(system_table, entries, selected, None);
+    }
+}
@@ -7,22 +7,56 @@ import platform
 
 current_dir = os.path.dirname(os.path.realpath(__file__))
 
+
+def pack_cpio(source_dir, archive_path):
+    """Writes the files of `source_dir` to a newc cpio archive, the format the kernel reads."""
+    def entry(name, data, mode):
+        header = "070701" + "".join("%08X" % field for field in
+            [0, mode, 0, 0, 1, 0, len(data), 0, 0, 0, 0, len(name) + 1, 0])
+        out = header.encode() + name.encode() + b"\0"
+        out += b"\0" * (-len(out) % 4) + data
+        return out + b"\0" * (-len(out) % 4)
+
+    archive = b""
+    for name in sorted(os.listdir(source_dir)):
+        with open(os.path.join(source_dir, name), "rb") as f:
+            archive += entry(name, f.read(), 0o100644)
+    archive += entry("TRAILER!!!", b"", 0)
+
+    with open(archive_path, "wb") as f:
+        f.write(archive)
+
+
+# the integration tests boot with their manifest and fixtures as the ramdisk
+extra_args = []
+if os.path.basename(sys.argv[1]).startswith("integration-"):
+    initrd_path = current_dir + "/target/integration.cpio"
+    pack_cpio(current_dir + "/tests/integration/initrd", initrd_path)
+    extra_args = ["--initrd", initrd_path]
+
 if (platform.system() == "Linux"):
     disk_image_result = subprocess.run(["cargo", "+stable", "run",
     "--package", "disk_image",
     "--target", "x86_64-unknown-linux-gnu",
-    "--", current_dir + "/target/x86_64-unknown-uefi/release/loader.efi", sys.argv[1]])
+    "--", current_dir + "/target/x86_64-unknown-uefi/release/loader.efi", sys.argv[1]] + extra_args)
 else:
     disk_image_result = subprocess.run(["cargo", "+stable", "run",
     "--package", "disk_image",
     "--target", "x86_64-pc-windows-msvc",
-    "--", current_dir + "/target/x86_64-unknown-uefi/release/loader.efi", sys.argv[1]])
+    "--", current_dir + "/target/x86_64-unknown-uefi/release/loader.efi", sys.argv[1]] + extra_args)
 
 if disk_image_result.returncode != 0:
     sys.exit(1)
 
+# files the OS writes to its host share (screenshots, logs) land in target/share
+share_args = []
+if (platform.system() == "Linux"):
+    share_dir = current_dir + "/target/share"
+    os.makedirs(share_dir, exist_ok=True)
+    share_args = ["-virtfs", "local,path=" + share_dir + ",mount_tag=host,security_model=none"]
+
 kernel_result = subprocess.run(["qemu-system-x86_64", "-drive", "format=raw,file=" + current_dir + "/target/x86_64-unknown-uefi/release/loader.gdt",
-"-bios", current_dir + "/build/OVMF_CODE.fd", "-rtc", "base=localtime,clock=host", "-icount", "sleep=on", "-smp", "2", "-device", "isa-debug-exit,iobase=0xf4,iosize=0x04", "-serial", "stdio", "-no-reboot"] + sys.argv[2:])
+"-bios", current_dir + "/build/OVMF_CODE.fd", "-rtc", "base=localtime,clock=host", "-icount", "sleep=on", "-smp", "2", "-device", "isa-debug-exit,iobase=0xf4,iosize=0x04", "-serial", "stdio", "-no-reboot"] + share_args + sys.argv[2:])
 
 if kernel_result.returncode == 33:
     sys.exit(0)
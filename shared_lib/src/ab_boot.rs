@@ -0,0 +1,170 @@
+// A/B kernel images.
+//
+// The ESP may hold two kernels, `kernel.a` and `kernel.b`, each with its own symbols file. The
+// loader boots the newer one, but an image that hasn't booted successfully yet only gets
+// `MAX_TRIAL_BOOTS` attempts: the kernel has to confirm the boot with `mark_successful`, otherwise
+// the loader falls back to the last slot that did. The state is kept in the CMOS NVRAM, which both the loader and the kernel can reach.
+//
+// Images are told apart by a stamp the loader derives from their modification time, so a new
+// image in the trial slot starts a new trial. Updates should go to the slot that isn't marked
+// good; an image replaced in the good slot is trusted right away.
+
+use crate::nvram::{self, USER_BYTES};
+
+pub const MAX_TRIAL_BOOTS: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Slot::A => "kernel.a",
+            Slot::B => "kernel.b",
+        }
+    }
+
+    /// Symbol table matching the kernel in the slot
+    pub fn symbols_file_name(&self) -> &'static str {
+        match self {
+            Slot::A => "symbols.a",
+            Slot::B => "symbols.b",
+        }
+    }
+
+    /// Name used on the kernel command line, `boot_slot=a`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Slot::A => "a",
+            Slot::B => "b",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Slot> {
+        match name {
+            "a" => Some(Slot::A),
+            "b" => Some(Slot::B),
+            _ => None,
+        }
+    }
+
+    fn to_u8(slot: Option<Slot>) -> u8 {
+        match slot {
+            None => 0,
+            Some(Slot::A) => 1,
+            Some(Slot::B) => 2,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Slot> {
+        match value {
+            1 => Some(Slot::A),
+            2 => Some(Slot::B),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BootState {
+    /// Last slot whose kernel confirmed its boot
+    pub good: Option<Slot>,
+    /// Image on trial, with its stamp
+    pub trial: Option<(Slot, u32)>,
+    /// How often the image on trial was started
+    pub attempts: u8,
+}
+
+/// Decides which image to boot. `a` and `b` are the stamps of the images present on the ESP.
+/// Returns the slot and the state to store before starting it.
+pub fn choose(state: BootState, a: Option<u32>, b: Option<u32>) -> Option<(Slot, BootState)> {
+    let (newest, stamp) = match (a, b) {
+        (None, None) => return None,
+        (Some(a), None) => (Slot::A, a),
+        (None, Some(b)) => (Slot::B, b),
+        (Some(a), Some(b)) => if b > a { (Slot::B, b) } else { (Slot::A, a) },
+    };
+
+    if state.good == Some(newest) {
+        return Some((newest, state));
+    }
+
+    let attempts = if state.trial == Some((newest, stamp)) { state.attempts } else { 0 };
+    if attempts >= MAX_TRIAL_BOOTS {
+        let good_present = match state.good {
+            Some(Slot::A) => a.is_some(),
+            Some(Slot::B) => b.is_some(),
+            None => false,
+        };
+        if good_present {
+            return Some((state.good.unwrap(), state));
+        }
+        // no image is known to work, keep trying the newest one
+    }
+
+    Some((newest, BootState { good: state.good, trial: Some((newest, stamp)), attempts: attempts.saturating_add(1) }))
+}
+
+/// Reads the state from the NVRAM, an unreadable state counts as no boot so far
+pub fn load_state() -> BootState {
+    let Ok(data) = nvram::read_user() else {
+        return BootState::default();
+    };
+
+    let stamp = u32::from_le_bytes(data[nvram::SETTING_TRIAL_STAMP..nvram::SETTING_TRIAL_STAMP + 4].try_into().unwrap());
+    BootState {
+        good: Slot::from_u8(data[nvram::SETTING_GOOD_SLOT]),
+        trial: Slot::from_u8(data[nvram::SETTING_TRIAL_SLOT]).map(|slot| (slot, stamp)),
+        attempts: data[nvram::SETTING_TRIAL_ATTEMPTS],
+    }
+}
+
+pub fn store_state(state: BootState) {
+    let mut data = nvram::read_user().unwrap_or([0; USER_BYTES]);
+    data[nvram::SETTING_GOOD_SLOT] = Slot::to_u8(state.good);
+    data[nvram::SETTING_TRIAL_SLOT] = Slot::to_u8(state.trial.map(|(slot, _)| slot));
+    data[nvram::SETTING_TRIAL_ATTEMPTS] = state.attempts;
+    let stamp = state.trial.map_or(0, |(_, stamp)| stamp);
+    data[nvram::SETTING_TRIAL_STAMP..nvram::SETTING_TRIAL_STAMP + 4].copy_from_slice(&stamp.to_le_bytes());
+    nvram::write_user(&data);
+}
+
+/// Called by the kernel once it is up: `slot` becomes the fallback for future trials.
+pub fn mark_successful(slot: Slot) {
+    let state = load_state();
+    let trial = state.trial.filter(|(trial_slot, _)| *trial_slot != slot);
+    store_state(BootState { good: Some(slot), trial, attempts: if trial.is_some() { state.attempts } else { 0 } });
+}
+
+#[test_case]
+fn ab_boot_choose_test() {
+    let first = BootState::default();
+
+    // a new image gets one try
+    let (slot, state) = choose(first, Some(10), None).unwrap();
+    assert_eq!(slot, Slot::A);
+    assert_eq!(state, BootState { good: None, trial: Some((Slot::A, 10)), attempts: 1 });
+
+    // without a good image the newest one is retried
+    assert_eq!(choose(state, Some(10), None).unwrap().0, Slot::A);
+
+    // A confirmed, B installed and never confirmed: fall back to A on the second boot
+    let good_a = BootState { good: Some(Slot::A), trial: None, attempts: 0 };
+    assert_eq!(choose(good_a, Some(10), Some(20)).unwrap().0, Slot::B);
+    let (_, tried_b) = choose(good_a, Some(10), Some(20)).unwrap();
+    let (slot, state) = choose(tried_b, Some(10), Some(20)).unwrap();
+    assert_eq!(slot, Slot::A);
+    assert_eq!(state, tried_b);
+
+    // a newer image in B starts a new trial
+    assert_eq!(choose(tried_b, Some(10), Some(30)).unwrap().0, Slot::B);
+
+    // the good slot boots without a trial
+    let good_b = BootState { good: Some(Slot::B), trial: None, attempts: 0 };
+    assert_eq!(choose(good_b, Some(10), Some(20)), Some((Slot::B, good_b)));
+
+    assert_eq!(choose(first, None, None), None);
+}
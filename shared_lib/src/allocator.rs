@@ -1,18 +1,22 @@
 pub mod fixed_size_block;
+#[cfg(feature = "heap_redzones")]
+pub mod redzone;
+pub mod stats;
 use crate::allocator::fixed_size_block::FixedSizeBlockAllocator;
+use crate::spinlock::{Spinlock, SpinlockGuard};
 
 pub struct Locked<A> {
-    inner: spin::Mutex<A>
+    inner: Spinlock<A>
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner)
+            inner: Spinlock::new(inner)
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> SpinlockGuard<A> {
         self.inner.lock()
     }
 }
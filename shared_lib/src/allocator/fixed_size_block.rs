@@ -1,20 +1,28 @@
 use core::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr};
 use core::ptr::NonNull;
-use crate::allocator::Locked;
+use crate::allocator::{stats, Locked};
+#[cfg(feature = "heap_redzones")]
+use crate::allocator::redzone;
+use crate::memprof;
 
 struct ListNode {
     next: Option<&'static mut ListNode>,
 }
 
 // always powers of 2
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub(crate) const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
 fn list_index(layout: &Layout) -> Option<usize> {
     let required_block_size = layout.size().max(layout.align());
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
+/// Size class of `layout` for the statistics, the last one is the fallback allocator
+fn size_class(layout: &Layout) -> usize {
+    list_index(layout).unwrap_or(BLOCK_SIZES.len())
+}
+
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap
@@ -33,6 +41,11 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
+    /// Grows the heap by `by` bytes at its top. The new range must be mapped or demand-paged.
+    pub unsafe fn extend(&mut self, by: usize) {
+        self.fallback_allocator.extend(by);
+    }
+
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
@@ -43,6 +56,51 @@ impl FixedSizeBlockAllocator {
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(not(feature = "heap_redzones"))]
+        let block_layout = layout;
+        #[cfg(feature = "heap_redzones")]
+        let block_layout = redzone::outer_layout(layout);
+
+        #[cfg(not(feature = "heap_redzones"))]
+        let ptr = self.alloc_impl(block_layout);
+        #[cfg(feature = "heap_redzones")]
+        let ptr = match self.alloc_impl(block_layout) {
+            ptr if ptr.is_null() => ptr,
+            ptr => redzone::arm(ptr, layout, memprof::CallSite::capture()),
+        };
+
+        if ptr.is_null() {
+            stats::record_failure();
+        } else {
+            stats::record_alloc(layout.size(), size_class(&block_layout));
+        }
+        if memprof::is_enabled() && !ptr.is_null() {
+            memprof::HEAP_PROFILE.lock().record_alloc(layout.size(), memprof::CallSite::capture());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if memprof::is_enabled() {
+            memprof::HEAP_PROFILE.lock().record_free(layout.size());
+        }
+        if stats::poison_enabled() {
+            ptr::write_bytes(ptr, stats::POISON_PATTERN, layout.size());
+        }
+        #[cfg(not(feature = "heap_redzones"))]
+        let block_layout = layout;
+        #[cfg(feature = "heap_redzones")]
+        let block_layout = redzone::outer_layout(layout);
+        stats::record_free(layout.size(), size_class(&block_layout));
+
+        #[cfg(feature = "heap_redzones")]
+        let ptr = redzone::disarm(ptr, layout);
+        self.dealloc_impl(ptr, block_layout);
+    }
+}
+
+impl Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc_impl(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
         match list_index(&layout) {
             Some(index) => {
@@ -65,7 +123,7 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn dealloc_impl(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
         match list_index(&layout) {
             Some(index) => {
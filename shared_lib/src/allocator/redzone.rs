@@ -0,0 +1,148 @@
+// Heap redzones, enabled with the `heap_redzones` feature.
+//
+// Every allocation is surrounded by `REDZONE_SIZE` bytes of a known pattern, with a header in
+// front that links all live allocations together:
+//
+//     [padding][Header][front redzone][user data][back redzone]
+//
+// The redzones are verified when the allocation is freed and by `check_all`, which the kernel
+// runs periodically. A damaged redzone means something wrote outside of its buffer; the report
+// names the allocation, its size and where it was allocated. Freed data is overwritten with
+// `FREED_PATTERN`, which makes use-after-free reads stand out.
+
+use core::alloc::Layout;
+use core::mem::{align_of, size_of};
+use core::ptr;
+use crate::memprof::CallSite;
+use crate::serial_println;
+use crate::spinlock::Spinlock;
+
+pub const REDZONE_SIZE: usize = 16;
+pub const REDZONE_PATTERN: u8 = 0xFB;
+pub const FREED_PATTERN: u8 = 0xFD;
+
+#[repr(C)]
+struct Header {
+    next: *mut Header,
+    prev: *mut Header,
+    size: usize,
+    site: CallSite,
+}
+
+const HEADER_SIZE: usize = size_of::<Header>();
+
+struct LiveAllocations {
+    head: *mut Header,
+    count: usize,
+}
+
+// the list is only accessed with the lock held
+unsafe impl Send for LiveAllocations {}
+
+static LIVE: Spinlock<LiveAllocations> = Spinlock::new(LiveAllocations { head: ptr::null_mut(), count: 0 });
+
+/// Bytes from the start of the block to the user data, a multiple of the user alignment
+fn prefix_size(layout: Layout) -> usize {
+    (HEADER_SIZE + REDZONE_SIZE).next_multiple_of(layout.align())
+}
+
+/// Layout of the whole block backing an allocation with `layout`
+pub fn outer_layout(layout: Layout) -> Layout {
+    Layout::from_size_align(prefix_size(layout) + layout.size() + REDZONE_SIZE, layout.align().max(align_of::<Header>()))
+        .expect("allocation too large for redzones")
+}
+
+unsafe fn header_of(user: *mut u8) -> *mut Header {
+    user.sub(REDZONE_SIZE + HEADER_SIZE) as *mut Header
+}
+
+/// Offset of the first damaged redzone byte relative to the user data, negative for the front.
+unsafe fn damaged_offset(user: *const u8, size: usize) -> Option<isize> {
+    for i in 0..REDZONE_SIZE {
+        if user.sub(REDZONE_SIZE - i).read_volatile() != REDZONE_PATTERN {
+            return Some(i as isize - REDZONE_SIZE as isize);
+        }
+    }
+    for i in 0..REDZONE_SIZE {
+        if user.add(size + i).read_volatile() != REDZONE_PATTERN {
+            return Some((size + i) as isize);
+        }
+    }
+    None
+}
+
+unsafe fn report(header: *const Header, offset: isize) {
+    let user = (header as *const u8).add(HEADER_SIZE + REDZONE_SIZE);
+    let side = if offset < 0 { "underflow" } else { "overflow" };
+    serial_println!("[redzone] heap buffer {}: allocation {:p} of {} bytes, damaged at offset {}, allocated at {}",
+        side, user, (*header).size, offset, (*header).site);
+}
+
+/// Writes the header and the redzones into the block at `base` and returns the user pointer.
+pub unsafe fn arm(base: *mut u8, layout: Layout, site: CallSite) -> *mut u8 {
+    let user = base.add(prefix_size(layout));
+    ptr::write_bytes(user.sub(REDZONE_SIZE), REDZONE_PATTERN, REDZONE_SIZE);
+    ptr::write_bytes(user.add(layout.size()), REDZONE_PATTERN, REDZONE_SIZE);
+
+    let header = header_of(user);
+    let mut live = LIVE.lock();
+    header.write(Header { next: live.head, prev: ptr::null_mut(), size: layout.size(), site });
+    if !live.head.is_null() {
+        (*live.head).prev = header;
+    }
+    live.head = header;
+    live.count += 1;
+
+    user
+}
+
+/// Verifies the redzones of the allocation at `user`, unlinks it and returns the block start.
+/// Panics if a redzone was overwritten.
+pub unsafe fn disarm(user: *mut u8, layout: Layout) -> *mut u8 {
+    let header = header_of(user);
+    if let Some(offset) = damaged_offset(user, layout.size()) {
+        report(header, offset);
+        panic!("heap redzone of {:p} ({} bytes) damaged at offset {}", user, layout.size(), offset);
+    }
+
+    {
+        let mut live = LIVE.lock();
+        let Header { next, prev, .. } = header.read();
+        if prev.is_null() {
+            live.head = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        live.count -= 1;
+    }
+
+    ptr::write_bytes(user, FREED_PATTERN, layout.size());
+    user.sub(prefix_size(layout))
+}
+
+/// Verifies the redzones of every live allocation and reports the damaged ones over serial.
+/// Returns the number of damaged allocations.
+pub fn check_all() -> usize {
+    let live = LIVE.lock();
+    let mut damaged = 0;
+    let mut header = live.head;
+    while !header.is_null() {
+        unsafe {
+            let user = (header as *const u8).add(HEADER_SIZE + REDZONE_SIZE);
+            if let Some(offset) = damaged_offset(user, (*header).size) {
+                report(header, offset);
+                damaged += 1;
+            }
+            header = (*header).next;
+        }
+    }
+    damaged
+}
+
+/// Number of allocations currently tracked
+pub fn live_allocations() -> usize {
+    LIVE.lock().count
+}
@@ -0,0 +1,101 @@
+// Always-on heap counters.
+//
+// Unlike `memprof` these are kept from the first allocation on and cost a few relaxed atomic
+// additions per call: bytes allocated and freed, usage and its high-water mark, and the number of
+// live blocks of each size class of the fixed size block allocator. Poisoning, switched on at
+// runtime, fills freed memory with `POISON_PATTERN` so use-after-free reads stand out without
+// rebuilding with `heap_redzones`.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use crate::allocator::fixed_size_block::BLOCK_SIZES;
+
+pub const POISON_PATTERN: u8 = 0xDD;
+
+/// The block sizes, then allocations served by the fallback allocator
+pub const SIZE_CLASSES: usize = BLOCK_SIZES.len() + 1;
+
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+static FREED_BYTES: AtomicU64 = AtomicU64::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static FREES: AtomicU64 = AtomicU64::new(0);
+static PEAK_BYTES: AtomicU64 = AtomicU64::new(0);
+static FAILED_ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static LIVE_BLOCKS: [AtomicUsize; SIZE_CLASSES] = [const { AtomicUsize::new(0) }; SIZE_CLASSES];
+static POISON: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub allocated_bytes: u64,
+    pub freed_bytes: u64,
+    pub allocations: u64,
+    pub frees: u64,
+    pub peak_bytes: u64,
+    pub failed_allocations: u64,
+    /// Live allocations per size class, see `size_class_name`
+    pub live_blocks: [usize; SIZE_CLASSES],
+}
+
+impl HeapStats {
+    pub fn in_use_bytes(&self) -> u64 {
+        self.allocated_bytes.saturating_sub(self.freed_bytes)
+    }
+}
+
+pub fn snapshot() -> HeapStats {
+    HeapStats {
+        allocated_bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+        freed_bytes: FREED_BYTES.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        frees: FREES.load(Ordering::Relaxed),
+        peak_bytes: PEAK_BYTES.load(Ordering::Relaxed),
+        failed_allocations: FAILED_ALLOCATIONS.load(Ordering::Relaxed),
+        live_blocks: core::array::from_fn(|class| LIVE_BLOCKS[class].load(Ordering::Relaxed)),
+    }
+}
+
+/// Largest size served by `class`, None for the fallback allocator
+pub fn size_class_limit(class: usize) -> Option<usize> {
+    BLOCK_SIZES.get(class).copied()
+}
+
+pub fn record_alloc(size: usize, class: usize) {
+    let allocated = ALLOCATED_BYTES.fetch_add(size as u64, Ordering::Relaxed) + size as u64;
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    LIVE_BLOCKS[class].fetch_add(1, Ordering::Relaxed);
+    PEAK_BYTES.fetch_max(allocated.saturating_sub(FREED_BYTES.load(Ordering::Relaxed)), Ordering::Relaxed);
+}
+
+pub fn record_free(size: usize, class: usize) {
+    FREED_BYTES.fetch_add(size as u64, Ordering::Relaxed);
+    FREES.fetch_add(1, Ordering::Relaxed);
+    LIVE_BLOCKS[class].fetch_sub(1, Ordering::Relaxed);
+}
+
+pub fn record_failure() {
+    FAILED_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn poison_enabled() -> bool {
+    POISON.load(Ordering::Relaxed)
+}
+
+pub fn set_poison(enabled: bool) {
+    POISON.store(enabled, Ordering::Relaxed);
+}
+
+#[test_case]
+fn record_test() {
+    let before = snapshot();
+    record_alloc(24, 2);
+    record_alloc(4096, SIZE_CLASSES - 1);
+    record_free(24, 2);
+
+    let after = snapshot();
+    assert_eq!(before.allocated_bytes + 24 + 4096, after.allocated_bytes);
+    assert_eq!(before.freed_bytes + 24, after.freed_bytes);
+    assert_eq!(before.live_blocks[2], after.live_blocks[2]);
+    assert_eq!(before.live_blocks[SIZE_CLASSES - 1] + 1, after.live_blocks[SIZE_CLASSES - 1]);
+    assert!(after.peak_bytes >= before.in_use_bytes() + 24 + 4096);
+    record_free(4096, SIZE_CLASSES - 1);
+}
@@ -1,55 +1,131 @@
-use core::ops::Range;
+use core::ops::{BitAnd, BitOr, Not, Range, Shl, Shr};
 
-pub fn set_bit(num: &mut u64, n: u8, value: bool) {
-    let mask = 1 << n;
-    if value {
-        *num |= mask;
+/// Unsigned integer types the bit helpers below can operate on.
+pub trait BitField: Copy + PartialEq
+    + Shl<u8, Output = Self> + Shr<u8, Output = Self>
+    + BitAnd<Output = Self> + BitOr<Output = Self> + Not<Output = Self>
+{
+    /// Width of the type in bits.
+    const BIT_LENGTH: u8;
+    const ZERO: Self;
+    const ONE: Self;
+}
+
+macro_rules! impl_bit_field {
+    ($($t:ty),*) => {
+        $(
+            impl BitField for $t {
+                const BIT_LENGTH: u8 = <$t>::BITS as u8;
+                const ZERO: Self = 0;
+                const ONE: Self = 1;
+            }
+        )*
+    };
+}
+
+impl_bit_field!(u8, u16, u32, u64);
+
+#[inline]
+fn check_range<T: BitField>(range: &Range<u8>) {
+    assert!(range.start < range.end, "bit range is empty");
+    assert!(range.end <= T::BIT_LENGTH, "bit range out of bounds");
+}
+
+/// Returns a mask with the lowest `width` bits set.
+#[inline]
+fn low_mask<T: BitField>(width: u8) -> T {
+    if width >= T::BIT_LENGTH {
+        !T::ZERO
     } else {
-        *num &= !mask;
+        !(!T::ZERO << width)
     }
 }
 
-pub fn set_bits(num: &mut u64, mask: u64, shift: u8) {
-    let mask = mask << shift;
-
-    *num |= mask;
+pub fn get_bit<T: BitField>(num: T, n: u8) -> bool {
+    assert!(n < T::BIT_LENGTH, "bit index out of bounds");
+    (num >> n) & T::ONE == T::ONE
 }
 
-pub fn get_bits(num: u64, range: Range<u8>) -> u64 {
-    let cut_first_bits = num >> range.start;
-    if range.end != 64 {
-        cut_first_bits & (0xffff_ffff_ffff_ffff >> range.end)
+pub fn set_bit<T: BitField>(num: &mut T, n: u8, value: bool) {
+    assert!(n < T::BIT_LENGTH, "bit index out of bounds");
+    let mask = T::ONE << n;
+    if value {
+        *num = *num | mask;
     } else {
-        cut_first_bits
+        *num = *num & !mask;
     }
 }
 
+/// Replaces the bits of `num` in `range` with `value`.
+///
+/// Panics if the range is empty or out of bounds, or if `value` doesn't fit into the range.
+pub fn set_bits<T: BitField>(num: &mut T, range: Range<u8>, value: T) {
+    check_range::<T>(&range);
+
+    let mask = low_mask::<T>(range.end - range.start);
+    assert!(value & !mask == T::ZERO, "value does not fit into bit range");
+
+    *num = (*num & !(mask << range.start)) | (value << range.start);
+}
+
+/// Returns the bits of `num` in `range`, shifted down to bit 0.
+///
+/// Panics if the range is empty or out of bounds.
+pub fn get_bits<T: BitField>(num: T, range: Range<u8>) -> T {
+    check_range::<T>(&range);
+
+    (num >> range.start) & low_mask::<T>(range.end - range.start)
+}
+
 #[test_case]
 fn set_bit_test() {
-    let mut num = 0b1000_0000;
+    let mut num: u64 = 0b1000_0000;
 
     set_bit(&mut num, 4, true);
     assert_eq!(0b1001_0000, num);
 
     set_bit(&mut num, 7, false);
     assert_eq!(0b0001_0000, num);
+
+    let mut byte: u8 = 0;
+    set_bit(&mut byte, 7, true);
+    assert_eq!(0x80, byte);
+    assert!(get_bit(byte, 7));
+    assert!(!get_bit(byte, 6));
 }
 
 #[test_case]
 fn set_bits_test() {
     let mask = 0b1001_1010;
 
-    let mut num = 1 << 47;
+    let mut num: u64 = 1 << 47;
 
-    set_bits(&mut num, mask, 16);
+    set_bits(&mut num, 16..24, mask);
 
     assert_eq!(0x8000_009a_0000, num);
 }
 
+#[test_case]
+fn set_bits_replaces_field() {
+    let mut num: u32 = 0xffff_ffff;
+    set_bits(&mut num, 8..11, 0);
+    assert_eq!(0xffff_f8ff, num);
+
+    set_bits(&mut num, 8..11, 0b101);
+    assert_eq!(0xffff_fdff, num);
+
+    let mut word: u16 = 0;
+    set_bits(&mut word, 0..16, 0xbeef);
+    assert_eq!(0xbeef, word);
+}
+
 #[test_case]
 fn get_bits_test() {
-    assert_eq!(0b101, get_bits(0b0010_1000, 3..6));
-    assert_eq!(1, get_bits(0x8000_0000_0000_0000, 63..64));
-    assert_eq!(0x3777, get_bits(0x0000_3777_0000_0000, 32..48));
-    assert_eq!(0x22, get_bits(0x0000_0000_0000_0022, 0..6));
-}
\ No newline at end of file
+    assert_eq!(0b101, get_bits(0b0010_1000u64, 3..6));
+    assert_eq!(1, get_bits(0x8000_0000_0000_0000u64, 63..64));
+    assert_eq!(0x3777, get_bits(0x0000_3777_0000_0000u64, 32..48));
+    assert_eq!(0x22, get_bits(0x0000_0000_0000_0022u64, 0..6));
+    assert_eq!(0x2, get_bits(0xffff_ffff_ffff_ff22u64, 0..2));
+    assert_eq!(0xf, get_bits(0xf0u8, 4..8));
+    assert_eq!(0xdead_beef, get_bits(0xdead_beefu32, 0..32));
+}
@@ -0,0 +1,252 @@
+// Boot information passed from the loader to the kernel.
+//
+// A header with a magic number and a version, followed by tagged entries like in Multiboot2 or
+// Limine: each entry starts with its type and payload size and is 8-byte aligned. Readers skip
+// the entries they don't know and the ones whose size doesn't match their definition, so entries
+// can be added without breaking kernels built against an older loader. The version only changes
+// when the header or an existing entry changes meaning.
+//
+// Everything is stored inline with a fixed capacity, the loader maps the whole structure for the
+// kernel and the payloads stay at their addresses (the frame allocator keeps a pointer to the
+// memory map).
+
+use core::mem::{align_of, size_of};
+use crate::cmdline::Cmdline;
+use crate::efi::EfiMemoryMap;
+use crate::frame_allocator::MemoryMap;
+use crate::logger::FrameBufferInfo;
+
+pub const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"FERRBOOT");
+pub const BOOT_INFO_VERSION: u32 = 2;
+
+const ENTRY_HEADER_SIZE: usize = size_of::<EntryHeader>();
+const ENTRY_ALIGN: usize = 8;
+
+/// Room for every entry the loader passes today, with some to spare for new ones
+const TAGS_CAPACITY: usize = size_of::<MemoryMap>() + size_of::<EfiMemoryMap>() + size_of::<Cmdline>()
+    + size_of::<FrameBufferInfo>() + 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TagType {
+    Framebuffer = 1,
+    MemoryMap = 2,
+    NextFreeFrame = 3,
+    Rsdp = 4,
+    Cmdline = 5,
+    Initrd = 6,
+    Symbols = 7,
+    StackGuard = 8,
+    PhysMappingOffset = 9,
+    KernelSlide = 10,
+    EfiMemoryMap = 11,
+    EfiRuntimeServices = 12,
+    ApTrampoline = 13,
+}
+
+/// Payload of a boot information entry
+///
+/// # Safety
+/// The type must be plain data with an alignment of at most 8 bytes: it is copied in and out of
+/// the entry bytes as is.
+pub unsafe trait Tag: Copy {
+    const TYPE: TagType;
+}
+
+/// Number of frames the loader took from the memory map, the kernel's allocator continues after
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct NextFreeFrame(pub usize);
+
+/// Physical address of the ACPI RSDP
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp(pub u64);
+
+/// Initial ramdisk, its pages are reserved in the memory map
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Initrd {
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// Physical address and size of the symbol table
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Symbols {
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// Unmapped page right below the kernel stack
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct StackGuard(pub u64);
+
+/// Virtual address at which all physical memory is mapped
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PhysMappingOffset(pub u64);
+
+/// Difference between the kernel's load address and the address it was linked at
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct KernelSlide(pub u64);
+
+/// Virtual address of the UEFI runtime services table
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct EfiRuntimeServices(pub u64);
+
+/// Physical address of a page below 1 MiB kept for the real-mode startup code of the
+/// application processors
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ApTrampoline(pub u64);
+
+unsafe impl Tag for FrameBufferInfo { const TYPE: TagType = TagType::Framebuffer; }
+unsafe impl Tag for MemoryMap { const TYPE: TagType = TagType::MemoryMap; }
+unsafe impl Tag for NextFreeFrame { const TYPE: TagType = TagType::NextFreeFrame; }
+unsafe impl Tag for Rsdp { const TYPE: TagType = TagType::Rsdp; }
+unsafe impl Tag for Cmdline { const TYPE: TagType = TagType::Cmdline; }
+unsafe impl Tag for Initrd { const TYPE: TagType = TagType::Initrd; }
+unsafe impl Tag for Symbols { const TYPE: TagType = TagType::Symbols; }
+unsafe impl Tag for StackGuard { const TYPE: TagType = TagType::StackGuard; }
+unsafe impl Tag for PhysMappingOffset { const TYPE: TagType = TagType::PhysMappingOffset; }
+unsafe impl Tag for KernelSlide { const TYPE: TagType = TagType::KernelSlide; }
+unsafe impl Tag for EfiMemoryMap { const TYPE: TagType = TagType::EfiMemoryMap; }
+unsafe impl Tag for EfiRuntimeServices { const TYPE: TagType = TagType::EfiRuntimeServices; }
+unsafe impl Tag for ApTrampoline { const TYPE: TagType = TagType::ApTrampoline; }
+
+#[repr(C)]
+struct EntryHeader {
+    ty: u32,
+    /// Payload bytes, without the header and the padding
+    size: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootInfoError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// No room left for the entry
+    Full,
+}
+
+#[repr(C, align(8))]
+pub struct BootInfo {
+    magic: u64,
+    version: u32,
+    /// Bytes of `tags` in use
+    size: u32,
+    tags: [u8; TAGS_CAPACITY],
+}
+
+impl BootInfo {
+    pub const fn new() -> Self {
+        BootInfo { magic: BOOT_INFO_MAGIC, version: BOOT_INFO_VERSION, size: 0, tags: [0; TAGS_CAPACITY] }
+    }
+
+    /// Checks that the loader speaks the same protocol, before any entry is read
+    pub fn validate(&self) -> Result<(), BootInfoError> {
+        if self.magic != BOOT_INFO_MAGIC {
+            return Err(BootInfoError::BadMagic);
+        }
+        if self.version != BOOT_INFO_VERSION {
+            return Err(BootInfoError::UnsupportedVersion(self.version));
+        }
+        Ok(())
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Appends an entry. A kernel reading the structure gets the first entry of each type.
+    pub fn push<T: Tag>(&mut self, value: T) -> Result<(), BootInfoError> {
+        assert!(align_of::<T>() <= ENTRY_ALIGN);
+
+        let start = self.size as usize;
+        let end = start + ENTRY_HEADER_SIZE + size_of::<T>().next_multiple_of(ENTRY_ALIGN);
+        if end > TAGS_CAPACITY {
+            return Err(BootInfoError::Full);
+        }
+
+        unsafe {
+            let header = self.tags.as_mut_ptr().add(start);
+            (header as *mut EntryHeader).write(EntryHeader { ty: T::TYPE as u32, size: size_of::<T>() as u32 });
+            (header.add(ENTRY_HEADER_SIZE) as *mut T).write(value);
+        }
+        self.size = end as u32;
+        Ok(())
+    }
+
+    /// First entry of type `T`, None if there is none or its size doesn't match `T`
+    pub fn get<T: Tag>(&self) -> Option<&T> {
+        let (_, payload) = self.entries()
+            .find(|(ty, _)| *ty == T::TYPE as u32)?;
+        if payload.len() != size_of::<T>() {
+            return None;
+        }
+        // SAFETY: entries start 8-byte aligned and `Tag` types need no more
+        Some(unsafe { &*(payload.as_ptr() as *const T) })
+    }
+
+    /// Type and payload of every entry, including the ones this build doesn't know
+    pub fn entries(&self) -> impl Iterator<Item = (u32, &[u8])> {
+        let used = &self.tags[..self.size as usize];
+        let mut offset = 0;
+        core::iter::from_fn(move || {
+            if offset + ENTRY_HEADER_SIZE > used.len() {
+                return None;
+            }
+            let header = unsafe { &*(used.as_ptr().add(offset) as *const EntryHeader) };
+            let payload = used.get(offset + ENTRY_HEADER_SIZE..offset + ENTRY_HEADER_SIZE + header.size as usize)?;
+            offset += ENTRY_HEADER_SIZE + (header.size as usize).next_multiple_of(ENTRY_ALIGN);
+            Some((header.ty, payload))
+        })
+    }
+}
+
+impl Default for BootInfo {
+    fn default() -> Self {
+        BootInfo::new()
+    }
+}
+
+#[test_case]
+fn boot_info_entries_test() {
+    let mut boot_info = BootInfo::new();
+    assert_eq!(boot_info.validate(), Ok(()));
+    assert!(boot_info.get::<Rsdp>().is_none());
+
+    boot_info.push(Rsdp(0xE0000)).unwrap();
+    boot_info.push(Initrd { addr: 0x100000, size: 5 }).unwrap();
+    boot_info.push(Rsdp(0xF0000)).unwrap();
+
+    assert_eq!(boot_info.get::<Rsdp>().unwrap().0, 0xE0000);
+    assert_eq!(boot_info.get::<Initrd>().unwrap().size, 5);
+    assert!(boot_info.get::<Symbols>().is_none());
+    assert_eq!(boot_info.entries().count(), 3);
+}
+
+#[test_case]
+fn boot_info_skips_unknown_entries_test() {
+    let mut boot_info = BootInfo::new();
+
+    // an entry from a newer loader, and a known type with a payload of another size
+    #[derive(Clone, Copy)]
+    struct Future {
+        _bytes: [u8; 12],
+    }
+    unsafe impl Tag for Future { const TYPE: TagType = TagType::KernelSlide; }
+    boot_info.push(Future { _bytes: [0xAB; 12] }).unwrap();
+    boot_info.push(StackGuard(0x1000)).unwrap();
+
+    assert!(boot_info.get::<KernelSlide>().is_none());
+    assert_eq!(boot_info.get::<StackGuard>().unwrap().0, 0x1000);
+
+    boot_info.magic = 0;
+    assert_eq!(boot_info.validate(), Err(BootInfoError::BadMagic));
+}
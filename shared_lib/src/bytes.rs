@@ -0,0 +1,30 @@
+// Little-endian reads at explicit offsets.
+//
+// On-disk and firmware structures are frequently misaligned in memory. Parsing them from byte
+// slices with these helpers avoids creating references into `repr(packed)` structs.
+// All functions panic if the value doesn't fit into `data`.
+
+pub fn read_u16_le(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+pub fn read_u32_le(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn read_u64_le(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+pub fn read_u128_le(data: &[u8], offset: usize) -> u128 {
+    u128::from_le_bytes(data[offset..offset + 16].try_into().unwrap())
+}
+
+#[test_case]
+fn unaligned_read_test() {
+    let data = [0xAAu8, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, 0x10];
+    assert_eq!(0x0201, read_u16_le(&data, 1));
+    assert_eq!(0x04030201, read_u32_le(&data, 1));
+    assert_eq!(0x0807060504030201, read_u64_le(&data, 1));
+    assert_eq!(0x100F0E0D0C0B0A090807060504030201, read_u128_le(&data, 1));
+}
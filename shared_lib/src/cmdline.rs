@@ -0,0 +1,92 @@
+// Kernel command line passed in `BootInfo`.
+//
+// The loader builds it from the boot configuration file: space separated options, either
+// `key=value` or a bare `flag`. It is stored inline with a fixed capacity so it can be copied
+// into `BootInfo` without allocating memory the kernel would have to map.
+
+use core::fmt;
+
+pub const CMDLINE_CAPACITY: usize = 256;
+
+#[derive(Clone, Copy)]
+pub struct Cmdline {
+    bytes: [u8; CMDLINE_CAPACITY],
+    len: usize,
+}
+
+impl Cmdline {
+    pub const fn empty() -> Self {
+        Cmdline { bytes: [0; CMDLINE_CAPACITY], len: 0 }
+    }
+
+    /// Appends the options in `args`. Returns false and leaves the command line unchanged if they
+    /// don't fit.
+    pub fn push(&mut self, args: &str) -> bool {
+        let args = args.trim();
+        if args.is_empty() {
+            return true;
+        }
+
+        let separator = if self.len == 0 { 0 } else { 1 };
+        if self.len + separator + args.len() > CMDLINE_CAPACITY {
+            return false;
+        }
+
+        if separator != 0 {
+            self.bytes[self.len] = b' ';
+        }
+        let start = self.len + separator;
+        self.bytes[start..start + args.len()].copy_from_slice(args.as_bytes());
+        self.len = start + args.len();
+        true
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ever filled from `&str`s
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+
+    pub fn options(&self) -> impl Iterator<Item = &str> {
+        self.as_str().split_whitespace()
+    }
+
+    /// Value of the last `key=value` option, so later options override earlier ones.
+    /// A bare `key` has an empty value.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.options()
+            .filter_map(|option| match option.split_once('=') {
+                Some((k, value)) if k == key => Some(value),
+                None if option == key => Some(""),
+                _ => None,
+            })
+            .last()
+    }
+
+    pub fn has_flag(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+}
+
+impl fmt::Debug for Cmdline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+#[test_case]
+fn cmdline_options_test() {
+    let mut cmdline = Cmdline::empty();
+    assert!(cmdline.push("loglevel=info console=serial"));
+    assert!(cmdline.push("  noapic loglevel=debug "));
+    assert_eq!(cmdline.as_str(), "loglevel=info console=serial noapic loglevel=debug");
+
+    assert_eq!(cmdline.get("loglevel"), Some("debug"));
+    assert_eq!(cmdline.get("console"), Some("serial"));
+    assert_eq!(cmdline.get("noapic"), Some(""));
+    assert_eq!(cmdline.get("init"), None);
+    assert!(cmdline.has_flag("noapic"));
+
+    let long = [b'x'; CMDLINE_CAPACITY];
+    assert!(!cmdline.push(core::str::from_utf8(&long).unwrap()));
+    assert_eq!(cmdline.get("loglevel"), Some("debug"));
+}
@@ -48,6 +48,32 @@ pub fn calculate_crc32(input: &[u8]) -> u32 {
     !crc
 }
 
+// CRC-16/XMODEM
+// polynomial 0x1021, initial value 0, no reflection
+
+pub fn calculate_crc16_xmodem(input: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+
+    for byte in input {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+#[test_case]
+fn simple_crc16_xmodem_test() {
+    assert_eq!(0x31C3, calculate_crc16_xmodem("123456789".as_bytes()));
+    assert_eq!(0, calculate_crc16_xmodem(&[]));
+}
+
 #[test_case]
 fn simple_crc32_test() {
     assert_eq!(1267612143, calculate_crc32("abcdef".as_bytes()));
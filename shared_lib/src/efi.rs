@@ -0,0 +1,71 @@
+// UEFI memory map and runtime services handed from the loader to the kernel.
+//
+// `MemoryMap` only keeps what the frame allocator needs, so the loader also passes the firmware's
+// descriptors unchanged: type, attributes and, for runtime regions, the virtual address they were
+// moved to by `SetVirtualAddressMap`. The loader maps every region with the `RUNTIME` attribute at
+// its physical address plus `RUNTIME_MAPPING_OFFSET` and switches the firmware to those addresses
+// before jumping to the kernel.
+
+use crate::frame_allocator::MAX_MEMORY_MAP_SIZE;
+
+/// Runtime regions are mapped at their physical address plus this, away from the regions used
+/// by KASLR
+pub const RUNTIME_MAPPING_OFFSET: u64 = 0x300_0000_0000;
+
+/// `EFI_MEMORY_RUNTIME`: the region is used by runtime services
+pub const ATTRIBUTE_RUNTIME: u64 = 1 << 63;
+/// `EFI_MEMORY_WB`: the region supports write-back caching
+pub const ATTRIBUTE_WRITE_BACK: u64 = 1 << 3;
+
+// memory types used by runtime services
+pub const RUNTIME_SERVICES_CODE: u32 = 5;
+pub const RUNTIME_SERVICES_DATA: u32 = 6;
+pub const MEMORY_MAPPED_IO: u32 = 11;
+pub const MEMORY_MAPPED_IO_PORT_SPACE: u32 = 12;
+
+/// `EFI_MEMORY_DESCRIPTOR`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryDescriptor {
+    pub ty: u32,
+    pub phys_start: u64,
+    pub virt_start: u64,
+    pub page_count: u64,
+    pub attribute: u64,
+}
+
+impl MemoryDescriptor {
+    pub const fn empty() -> Self {
+        MemoryDescriptor { ty: 0, phys_start: 0, virt_start: 0, page_count: 0, attribute: 0 }
+    }
+
+    pub fn is_runtime(&self) -> bool {
+        self.attribute & ATTRIBUTE_RUNTIME != 0
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct EfiMemoryMap {
+    pub entries: [MemoryDescriptor; MAX_MEMORY_MAP_SIZE],
+    pub len: usize,
+}
+
+impl EfiMemoryMap {
+    pub const fn empty() -> Self {
+        EfiMemoryMap { entries: [MemoryDescriptor::empty(); MAX_MEMORY_MAP_SIZE], len: 0 }
+    }
+
+    pub fn push(&mut self, descriptor: MemoryDescriptor) -> bool {
+        if self.len == MAX_MEMORY_MAP_SIZE {
+            return false;
+        }
+        self.entries[self.len] = descriptor;
+        self.len += 1;
+        true
+    }
+
+    pub fn descriptors(&self) -> &[MemoryDescriptor] {
+        &self.entries[..self.len]
+    }
+}
@@ -0,0 +1,499 @@
+// ext2 filesystems, read-only.
+//
+// Enough of ext2 to read images made by Linux `mke2fs -t ext2`. The superblock is always at byte
+// 1024. The block groups come after it, each with a descriptor in the table following the
+// superblock; the descriptor gives the block of the group's inode table. Inodes are numbered
+// from 1, root is 2. An inode maps its data with 12 direct block pointers and then a single, a
+// double and a triple indirect block; a zero pointer is a hole and reads as zeros. Directories
+// are files of variable-length entries, an entry with inode 0 is unused.
+//
+// Images with incompatible features this reader doesn't know are refused, like the extents and
+// 64-bit block numbers of ext4 or a journal needing recovery. Symbolic links are listed but not
+// followed.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::bytes::{read_u16_le, read_u32_le};
+use crate::io::{IoError, Read, Seek, SeekFrom};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+/// Inode size of revision 0
+const GOOD_OLD_INODE_SIZE: u16 = 128;
+const GROUP_DESCRIPTOR_SIZE: u64 = 32;
+const DIRECT_BLOCKS: u64 = 12;
+
+/// Directory entries carry a file type
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Group metadata may be packed together, the descriptors still say where
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+/// Regular files use the high 32 bits of the size
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+/// The inode maps its data with an extent tree
+const EXTENTS_FLAG: u32 = 0x0008_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ext2Error {
+    Io(IoError),
+    /// No ext2 superblock
+    NotExt2,
+    /// Incompatible features this reader doesn't know, as in the superblock
+    UnsupportedFeatures(u32),
+    /// An inode mapped with extents
+    Extents,
+    /// The metadata is inconsistent or points outside the filesystem
+    Corrupted,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    /// A symbolic link or a device, which can't be read
+    NotAFile,
+}
+
+impl From<IoError> for Ext2Error {
+    fn from(e: IoError) -> Self {
+        Ext2Error::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    /// Devices, pipes and sockets
+    Other,
+}
+
+impl FileType {
+    fn from_mode(mode: u16) -> FileType {
+        match mode & 0xf000 {
+            0x8000 => FileType::File,
+            0x4000 => FileType::Directory,
+            0xa000 => FileType::Symlink,
+            _ => FileType::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u32,
+    pub file_type: FileType,
+    pub size: u64,
+    /// Seconds since the Unix epoch
+    pub modified: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub inode: u32,
+    pub file_type: FileType,
+    pub size: u64,
+    /// Seconds since the Unix epoch
+    pub modified: u32,
+}
+
+struct Inode {
+    mode: u16,
+    size: u64,
+    modified: u32,
+    flags: u32,
+    blocks: [u32; 15],
+}
+
+impl Inode {
+    fn file_type(&self) -> FileType {
+        FileType::from_mode(self.mode)
+    }
+}
+
+pub struct Ext2<D> {
+    device: D,
+    block_size: u64,
+    blocks_count: u32,
+    inodes_count: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    /// Block of the inode table of every group
+    inode_tables: Vec<u32>,
+    large_files: bool,
+}
+
+impl<D: Read + Seek> Ext2<D> {
+    pub fn mount(mut device: D) -> Result<Ext2<D>, Ext2Error> {
+        let mut superblock = [0u8; SUPERBLOCK_SIZE];
+        device.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+        device.read_exact(&mut superblock).map_err(|e| match e {
+            IoError::UnexpectedEof => Ext2Error::NotExt2,
+            e => Ext2Error::Io(e),
+        })?;
+        if read_u16_le(&superblock, 56) != MAGIC {
+            return Err(Ext2Error::NotExt2);
+        }
+
+        let inodes_count = read_u32_le(&superblock, 0);
+        let blocks_count = read_u32_le(&superblock, 4);
+        let first_data_block = read_u32_le(&superblock, 20);
+        let log_block_size = read_u32_le(&superblock, 24);
+        let blocks_per_group = read_u32_le(&superblock, 32);
+        let inodes_per_group = read_u32_le(&superblock, 40);
+        let revision = read_u32_le(&superblock, 76);
+        let (inode_size, incompat, ro_compat) = match revision {
+            0 => (GOOD_OLD_INODE_SIZE, 0, 0),
+            _ => (read_u16_le(&superblock, 88), read_u32_le(&superblock, 96), read_u32_le(&superblock, 100)),
+        };
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(Ext2Error::UnsupportedFeatures(incompat & !SUPPORTED_INCOMPAT));
+        }
+        let valid = log_block_size <= 6
+            && blocks_per_group > 0
+            && inodes_per_group > 0
+            && inode_size >= GOOD_OLD_INODE_SIZE
+            && inode_size.is_power_of_two()
+            && first_data_block < blocks_count
+            && inodes_count >= ROOT_INODE;
+        if !valid {
+            return Err(Ext2Error::Corrupted);
+        }
+
+        let block_size = 1024u64 << log_block_size;
+        let groups = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+        if inodes_count.div_ceil(inodes_per_group) > groups {
+            return Err(Ext2Error::Corrupted);
+        }
+        let mut descriptors = vec![0u8; (groups as u64 * GROUP_DESCRIPTOR_SIZE) as usize];
+        device.seek(SeekFrom::Start((first_data_block as u64 + 1) * block_size))?;
+        device.read_exact(&mut descriptors)?;
+        let inode_tables: Vec<u32> = descriptors.chunks_exact(GROUP_DESCRIPTOR_SIZE as usize)
+            .map(|descriptor| read_u32_le(descriptor, 8))
+            .collect();
+        let table_blocks = (inodes_per_group as u64 * inode_size as u64).div_ceil(block_size);
+        if inode_tables.iter().any(|&table| table == 0 || table as u64 + table_blocks > blocks_count as u64) {
+            return Err(Ext2Error::Corrupted);
+        }
+
+        let mut fs = Ext2 {
+            device,
+            block_size,
+            blocks_count,
+            inodes_count,
+            inodes_per_group,
+            inode_size: inode_size as u64,
+            inode_tables,
+            large_files: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+        };
+        if fs.inode(ROOT_INODE)?.file_type() != FileType::Directory {
+            return Err(Ext2Error::Corrupted);
+        }
+        Ok(fs)
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn metadata(&mut self, path: &str) -> Result<Metadata, Ext2Error> {
+        let number = self.resolve(path)?;
+        let inode = self.inode(number)?;
+        Ok(Metadata { inode: number, file_type: inode.file_type(), size: inode.size, modified: inode.modified })
+    }
+
+    /// The entries of a directory, without `.` and `..`
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, Ext2Error> {
+        let number = self.resolve(path)?;
+        let inode = self.inode(number)?;
+        if inode.file_type() != FileType::Directory {
+            return Err(Ext2Error::NotADirectory);
+        }
+        let mut entries = Vec::new();
+        for (inode, name) in self.entries(&inode)? {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let child = self.inode(inode)?;
+            entries.push(DirEntry { name, inode, file_type: child.file_type(), size: child.size, modified: child.modified });
+        }
+        Ok(entries)
+    }
+
+    /// Reads from `offset` until the buffer is full or the file ends, returns how many bytes
+    pub fn read(&mut self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, Ext2Error> {
+        let number = self.resolve(path)?;
+        let inode = self.inode(number)?;
+        match inode.file_type() {
+            FileType::File => self.read_at(&inode, offset, buffer),
+            FileType::Directory => Err(Ext2Error::IsADirectory),
+            _ => Err(Ext2Error::NotAFile),
+        }
+    }
+
+    /// The target of a symbolic link
+    pub fn read_link(&mut self, path: &str) -> Result<String, Ext2Error> {
+        let number = self.resolve(path)?;
+        let inode = self.inode(number)?;
+        if inode.file_type() != FileType::Symlink {
+            return Err(Ext2Error::NotFound);
+        }
+        // targets under 60 bytes are stored in place of the block pointers
+        let target = if inode.size < 60 && inode.flags & EXTENTS_FLAG == 0 {
+            inode.blocks.iter().flat_map(|pointer| pointer.to_le_bytes()).take(inode.size as usize).collect()
+        } else {
+            let mut target = vec![0u8; inode.size.min(self.block_size) as usize];
+            let len = self.read_at(&inode, 0, &mut target)?;
+            target.truncate(len);
+            target
+        };
+        String::from_utf8(target).map_err(|_| Ext2Error::Corrupted)
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    fn read_block(&mut self, block: u32, offset: u64, buffer: &mut [u8]) -> Result<(), Ext2Error> {
+        if block >= self.blocks_count {
+            return Err(Ext2Error::Corrupted);
+        }
+        self.device.seek(SeekFrom::Start(block as u64 * self.block_size + offset))?;
+        self.device.read_exact(buffer)?;
+        Ok(())
+    }
+
+    fn inode(&mut self, number: u32) -> Result<Inode, Ext2Error> {
+        if number == 0 || number > self.inodes_count {
+            return Err(Ext2Error::Corrupted);
+        }
+        let index = (number - 1) % self.inodes_per_group;
+        let table = self.inode_tables[((number - 1) / self.inodes_per_group) as usize];
+        let mut record = [0u8; GOOD_OLD_INODE_SIZE as usize];
+        self.read_block(table, index as u64 * self.inode_size, &mut record)?;
+        Ok(self.parse_inode(&record))
+    }
+
+    fn parse_inode(&self, record: &[u8]) -> Inode {
+        let mode = read_u16_le(record, 0);
+        let mut size = read_u32_le(record, 4) as u64;
+        if self.large_files && FileType::from_mode(mode) == FileType::File {
+            size |= (read_u32_le(record, 108) as u64) << 32;
+        }
+        let mut blocks = [0u32; 15];
+        for (i, pointer) in blocks.iter_mut().enumerate() {
+            *pointer = read_u32_le(record, 40 + i * 4);
+        }
+        Inode { mode, size, modified: read_u32_le(record, 16), flags: read_u32_le(record, 32), blocks }
+    }
+
+    /// The physical block of block `index` of the inode's data, 0 for a hole
+    fn map(&mut self, inode: &Inode, index: u64) -> Result<u32, Ext2Error> {
+        if inode.flags & EXTENTS_FLAG != 0 {
+            return Err(Ext2Error::Extents);
+        }
+        if index < DIRECT_BLOCKS {
+            return Ok(inode.blocks[index as usize]);
+        }
+
+        let per_block = self.block_size / 4;
+        let mut index = index - DIRECT_BLOCKS;
+        let mut span = per_block;
+        for (level, &root) in inode.blocks[12..].iter().enumerate() {
+            if index >= span {
+                index -= span;
+                span *= per_block;
+                continue;
+            }
+            // walk down from the indirect block of this level
+            let mut block = root;
+            for depth in (0..=level as u32).rev() {
+                if block == 0 {
+                    return Ok(0);
+                }
+                let slot = index / per_block.pow(depth) % per_block;
+                let mut pointer = [0u8; 4];
+                self.read_block(block, slot * 4, &mut pointer)?;
+                block = u32::from_le_bytes(pointer);
+            }
+            return Ok(block);
+        }
+        Err(Ext2Error::Corrupted)
+    }
+
+    fn read_at(&mut self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, Ext2Error> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = buffer.len().min((inode.size - offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let in_block = position % self.block_size;
+            let chunk = (len - done).min((self.block_size - in_block) as usize);
+            match self.map(inode, position / self.block_size)? {
+                0 => buffer[done..done + chunk].fill(0),
+                block => self.read_block(block, in_block, &mut buffer[done..done + chunk])?,
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    /// The inode and name of the used entries of a directory
+    fn entries(&mut self, dir: &Inode) -> Result<Vec<(u32, String)>, Ext2Error> {
+        if dir.size > self.blocks_count as u64 * self.block_size {
+            return Err(Ext2Error::Corrupted);
+        }
+        let mut data = vec![0u8; dir.size as usize];
+        self.read_at(dir, 0, &mut data)?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let inode = read_u32_le(&data, offset);
+            let record_len = read_u16_le(&data, offset + 4) as usize;
+            let name_len = data[offset + 6] as usize;
+            if record_len < 8 || offset + record_len > data.len() || 8 + name_len > record_len {
+                return Err(Ext2Error::Corrupted);
+            }
+            if inode != 0 {
+                let name = &data[offset + 8..offset + 8 + name_len];
+                entries.push((inode, String::from_utf8_lossy(name).into_owned()));
+            }
+            offset += record_len;
+        }
+        Ok(entries)
+    }
+
+    fn resolve(&mut self, path: &str) -> Result<u32, Ext2Error> {
+        let mut number = ROOT_INODE;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let dir = self.inode(number)?;
+            if dir.file_type() != FileType::Directory {
+                return Err(Ext2Error::NotADirectory);
+            }
+            number = self.entries(&dir)?
+                .into_iter()
+                .find(|(_, entry)| entry == name)
+                .ok_or(Ext2Error::NotFound)?
+                .0;
+        }
+        Ok(number)
+    }
+}
+
+#[cfg(test)]
+use crate::io::Cursor;
+
+/// A 64 KiB image of 1 KiB blocks in a single group: the superblock in block 1, the group
+/// descriptor in 2, 16 inodes from block 5 and the data from block 7. Root holds `hello.txt`
+/// (inode 12) and `sub` (13), which holds `deep` (14); `link` (15) points to `hello.txt`.
+/// `hello.txt` is 14 blocks and 100 bytes, its block 13 is a hole.
+#[cfg(test)]
+fn test_image() -> Vec<u8> {
+    const BLOCK: usize = 1024;
+    let mut image = vec![0u8; 64 * BLOCK];
+    let put16 = |image: &mut [u8], offset: usize, value: u16| image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    let put32 = |image: &mut [u8], offset: usize, value: u32| image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+
+    let sb = BLOCK;
+    put32(&mut image, sb, 16);
+    put32(&mut image, sb + 4, 64);
+    put32(&mut image, sb + 20, 1);
+    put32(&mut image, sb + 32, 8192);
+    put32(&mut image, sb + 40, 16);
+    put16(&mut image, sb + 56, MAGIC);
+    put32(&mut image, sb + 76, 1);
+    put16(&mut image, sb + 88, 128);
+    put32(&mut image, sb + 96, INCOMPAT_FILETYPE);
+    put32(&mut image, 2 * BLOCK + 8, 5);
+
+    let inode = |image: &mut [u8], number: usize, mode: u16, size: u32, blocks: &[u32]| {
+        let offset = 5 * BLOCK + (number - 1) * 128;
+        put16(image, offset, mode);
+        put32(image, offset + 4, size);
+        for (i, &block) in blocks.iter().enumerate() {
+            put32(image, offset + 40 + i * 4, block);
+        }
+    };
+    let dir = |image: &mut [u8], block: usize, entries: &[(u32, &str)]| {
+        let mut offset = block * BLOCK;
+        for (i, &(inode, name)) in entries.iter().enumerate() {
+            let record_len = if i + 1 == entries.len() { block * BLOCK + BLOCK - offset } else { (8 + name.len()).next_multiple_of(4) };
+            put32(image, offset, inode);
+            put16(image, offset + 4, record_len as u16);
+            image[offset + 6] = name.len() as u8;
+            image[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+            offset += record_len;
+        }
+    };
+
+    inode(&mut image, 2, 0x41ed, BLOCK as u32, &[7]);
+    dir(&mut image, 7, &[(2, "."), (2, ".."), (0, "deleted"), (12, "hello.txt"), (13, "sub"), (15, "link")]);
+    // data blocks 8..20, the indirect block 20 maps blocks 12 and 14 to 21 and 22
+    let mut pointers: Vec<u32> = (8..20).collect();
+    pointers.push(20);
+    inode(&mut image, 12, 0x81a4, 14 * BLOCK as u32 + 100, &pointers);
+    put32(&mut image, 20 * BLOCK, 21);
+    put32(&mut image, 20 * BLOCK + 8, 22);
+    for (index, block) in (0..12).map(|i| (i, 8 + i)).chain([(12, 21), (14, 22)]) {
+        for i in 0..BLOCK {
+            image[block * BLOCK + i] = ((index * BLOCK + i) % 251) as u8;
+        }
+    }
+    inode(&mut image, 13, 0x41ed, BLOCK as u32, &[23]);
+    dir(&mut image, 23, &[(13, "."), (2, ".."), (14, "deep")]);
+    inode(&mut image, 14, 0x81a4, 2, &[24]);
+    image[24 * BLOCK..24 * BLOCK + 2].copy_from_slice(b"hi");
+    inode(&mut image, 15, 0xa1ff, 9, &[]);
+    image[5 * BLOCK + 14 * 128 + 40..][..9].copy_from_slice(b"hello.txt");
+    image
+}
+
+#[test_case]
+fn ext2_reads_files_and_directories() {
+    let mut fs = Ext2::mount(Cursor::new(test_image())).unwrap();
+    let names: Vec<(String, FileType)> = fs.read_dir("/").unwrap().into_iter().map(|entry| (entry.name, entry.file_type)).collect();
+    assert_eq!(names, [
+        (String::from("hello.txt"), FileType::File),
+        (String::from("sub"), FileType::Directory),
+        (String::from("link"), FileType::Symlink),
+    ]);
+
+    let size = 14 * 1024 + 100;
+    assert_eq!(fs.metadata("/hello.txt").unwrap().size, size as u64);
+    let mut data = vec![0xffu8; size + 10];
+    assert_eq!(fs.read("/hello.txt", 0, &mut data), Ok(size));
+    for (i, &byte) in data[..size].iter().enumerate() {
+        let expected = if i / 1024 == 13 { 0 } else { (i % 251) as u8 };
+        assert_eq!(byte, expected, "byte {}", i);
+    }
+    let mut tail = [0u8; 8];
+    assert_eq!(fs.read("hello.txt", size as u64 - 4, &mut tail), Ok(4));
+
+    let mut deep = [0u8; 2];
+    assert_eq!(fs.read("/sub/deep", 0, &mut deep), Ok(2));
+    assert_eq!(&deep, b"hi");
+    assert_eq!(fs.read_link("/link").unwrap(), "hello.txt");
+    assert_eq!(fs.read("/link", 0, &mut deep), Err(Ext2Error::NotAFile));
+    assert_eq!(fs.read("/sub", 0, &mut deep), Err(Ext2Error::IsADirectory));
+    assert_eq!(fs.read_dir("/hello.txt/x").err(), Some(Ext2Error::NotADirectory));
+    assert_eq!(fs.metadata("/sub/missing").err(), Some(Ext2Error::NotFound));
+}
+
+#[test_case]
+fn ext2_rejects_unknown_images() {
+    assert!(matches!(Ext2::mount(Cursor::new(vec![0u8; 4096])), Err(Ext2Error::NotExt2)));
+
+    // extents and 64-bit block numbers, as made by mke2fs -t ext4
+    let mut image = test_image();
+    image[1024 + 96..1024 + 100].copy_from_slice(&(INCOMPAT_FILETYPE | 0xc0).to_le_bytes());
+    assert!(matches!(Ext2::mount(Cursor::new(image)), Err(Ext2Error::UnsupportedFeatures(0xc0))));
+
+    let mut image = test_image();
+    image[5 * 1024 + 11 * 128 + 32..][..4].copy_from_slice(&EXTENTS_FLAG.to_le_bytes());
+    let mut fs = Ext2::mount(Cursor::new(image)).unwrap();
+    assert_eq!(fs.read("/hello.txt", 0, &mut [0u8; 16]), Err(Ext2Error::Extents));
+}
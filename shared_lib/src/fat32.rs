@@ -0,0 +1,1071 @@
+// FAT filesystems.
+//
+// FAT32 volumes and the FAT12 and FAT16 ones small partitions get, like the EFI system partition
+// of the boot disk. The BPB in the boot sector gives the layout: reserved sectors, the FATs, on
+// FAT12 and FAT16 a fixed-size root directory, then the data clusters. A file is a chain of
+// clusters linked through the FAT. The first FAT is read into memory by `mount`; changes go to
+// it and to every copy on the device right away.
+//
+// Directories are arrays of 32-byte entries. A long name is stored in the entries right before
+// its 8.3 entry, in pieces of 13 UTF-16 characters, and checksummed against the short name.
+// Names are matched case-insensitively. A new entry gets a long name unless its name is a valid
+// 8.3 name, and then a `NAME~N.EXT` short name unique in its directory. Timestamps aren't kept,
+// new entries are dated 1980-01-01.
+//
+// The API works on paths from the root of the volume and byte offsets, there are no open files.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::bytes::{read_u16_le, read_u32_le};
+use crate::io::{IoError, Read, Seek, SeekFrom, Write};
+
+pub const MAX_NAME_LEN: usize = 255;
+
+const BOOT_SIGNATURE_OFFSET: usize = 510;
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_SIGNATURE: u32 = 0x6141_7272;
+const FS_INFO_FREE_COUNT: u64 = 488;
+
+const DIR_ENTRY_SIZE: usize = 32;
+const DELETED: u8 = 0xe5;
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+/// Flags of the NT reserved byte: the base name or the extension is shown in lower case
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+/// Where the 13 characters of a long name entry are
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    Io(IoError),
+    /// No valid BPB
+    NotFat,
+    /// A cluster chain or a directory is inconsistent
+    Corrupted,
+    NotFound,
+    AlreadyExists,
+    /// Empty, longer than `MAX_NAME_LEN`, `.`, `..` or with characters FAT doesn't allow
+    InvalidName,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    NoSpace,
+    /// Past 4 GiB - 1, the largest size a directory entry holds
+    FileTooLarge,
+    /// The file has the read-only attribute
+    ReadOnly,
+}
+
+impl From<IoError> for FatError {
+    fn from(e: IoError) -> Self {
+        FatError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Smallest value ending a chain
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xff8,
+            FatType::Fat16 => 0xfff8,
+            FatType::Fat32 => 0x0fff_fff8,
+        }
+    }
+
+    fn bits(self) -> u64 {
+        match self {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        }
+    }
+}
+
+/// Byte offsets and sizes from the BPB
+struct Layout {
+    fat_type: FatType,
+    cluster_size: u64,
+    fat_start: u64,
+    fat_size: u64,
+    fats: u64,
+    /// The fixed root directory of FAT12 and FAT16
+    root_start: u64,
+    root_size: u64,
+    /// Of cluster 2
+    data_start: u64,
+    /// Valid clusters are `2..clusters + 2`
+    clusters: u32,
+    root_cluster: u32,
+    fs_info: Option<u64>,
+}
+
+impl Layout {
+    fn parse(sector: &[u8]) -> Result<Layout, FatError> {
+        if sector.len() < 512 || sector[BOOT_SIGNATURE_OFFSET..BOOT_SIGNATURE_OFFSET + 2] != [0x55, 0xaa] {
+            return Err(FatError::NotFat);
+        }
+        let bytes_per_sector = read_u16_le(sector, 11) as u64;
+        let sectors_per_cluster = sector[13] as u64;
+        let reserved_sectors = read_u16_le(sector, 14) as u64;
+        let fats = sector[16] as u64;
+        let root_entries = read_u16_le(sector, 17) as u64;
+        let total_sectors = match read_u16_le(sector, 19) {
+            0 => read_u32_le(sector, 32) as u64,
+            sectors => sectors as u64,
+        };
+        let fat_sectors = match read_u16_le(sector, 22) {
+            0 => read_u32_le(sector, 36) as u64,
+            sectors => sectors as u64,
+        };
+        let valid = matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            && sectors_per_cluster.is_power_of_two()
+            && reserved_sectors > 0
+            && fats > 0
+            && fat_sectors > 0;
+        if !valid {
+            return Err(FatError::NotFat);
+        }
+
+        let root_size = root_entries * DIR_ENTRY_SIZE as u64;
+        let root_start = (reserved_sectors + fats * fat_sectors) * bytes_per_sector;
+        let data_start = root_start + root_size.next_multiple_of(bytes_per_sector);
+        let data_sectors = total_sectors.checked_sub(data_start / bytes_per_sector).ok_or(FatError::NotFat)?;
+        let clusters = data_sectors / sectors_per_cluster;
+        // the count decides the type, not the label
+        let fat_type = match clusters {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let fat_size = fat_sectors * bytes_per_sector;
+        if clusters == 0 || clusters > 0x0fff_fff5 || fat_size * 8 < (clusters + 2) * fat_type.bits() {
+            return Err(FatError::NotFat);
+        }
+        if (fat_type == FatType::Fat32) != (root_entries == 0) {
+            return Err(FatError::NotFat);
+        }
+
+        let (root_cluster, fs_info) = match fat_type {
+            FatType::Fat32 => (read_u32_le(sector, 44), Some(read_u16_le(sector, 48) as u64 * bytes_per_sector)),
+            _ => (0, None),
+        };
+        Ok(Layout {
+            fat_type,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            fat_start: reserved_sectors * bytes_per_sector,
+            fat_size,
+            fats,
+            root_start,
+            root_size,
+            data_start,
+            clusters: clusters as u32,
+            root_cluster,
+            fs_info: fs_info.filter(|&offset| offset != 0 && offset < data_start),
+        })
+    }
+
+    fn total_size(&self) -> u64 {
+        self.data_start + self.clusters as u64 * self.cluster_size
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Dir {
+    /// The root directory of FAT12 and FAT16, outside the clusters
+    FixedRoot,
+    Chain(u32),
+}
+
+/// A used entry of a directory
+#[derive(Clone)]
+struct Entry {
+    name: String,
+    short_name: [u8; 11],
+    attributes: u8,
+    cluster: u32,
+    size: u32,
+    /// Of the 8.3 entry in the directory
+    offset: u64,
+    /// Entries taken, the long name ones before the 8.3 one included
+    slots: u64,
+}
+
+impl Entry {
+    fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    /// The FAT attribute byte: read-only, hidden, system, archive...
+    pub attributes: u8,
+}
+
+impl DirEntry {
+    fn from_entry(entry: &Entry) -> DirEntry {
+        DirEntry { name: entry.name.clone(), is_dir: entry.is_dir(), size: entry.size as u64, attributes: entry.attributes }
+    }
+}
+
+pub struct Fat<D> {
+    device: D,
+    layout: Layout,
+    /// The first FAT
+    fat: Vec<u8>,
+    /// Where the search for a free cluster starts
+    next_free: u32,
+    /// The FAT changed since the FS info sector was written
+    fat_changed: bool,
+}
+
+impl<D: Read + Write + Seek> Fat<D> {
+    pub fn mount(mut device: D) -> Result<Fat<D>, FatError> {
+        let mut sector = [0u8; 512];
+        device.seek(SeekFrom::Start(0))?;
+        device.read_exact(&mut sector)?;
+        let layout = Layout::parse(&sector)?;
+        if layout.total_size() > device.seek(SeekFrom::End(0))? {
+            return Err(FatError::NotFat);
+        }
+
+        let mut fat = vec![0u8; layout.fat_size as usize];
+        device.seek(SeekFrom::Start(layout.fat_start))?;
+        device.read_exact(&mut fat)?;
+        let fs = Fat { device, layout, fat, next_free: 2, fat_changed: false };
+        if fs.layout.fat_type == FatType::Fat32 && !fs.is_cluster(fs.layout.root_cluster) {
+            return Err(FatError::Corrupted);
+        }
+        fs.with_fs_info()
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.layout.fat_type
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.layout.cluster_size
+    }
+
+    pub fn clusters(&self) -> u32 {
+        self.layout.clusters
+    }
+
+    pub fn free_clusters(&self) -> u32 {
+        (2..self.layout.clusters + 2).filter(|&cluster| self.fat_entry(cluster) == 0).count() as u32
+    }
+
+    /// The root directory has no entry, it is returned with an empty name
+    pub fn metadata(&mut self, path: &str) -> Result<DirEntry, FatError> {
+        match self.resolve(path)? {
+            None => Ok(DirEntry { name: String::new(), is_dir: true, size: 0, attributes: ATTR_DIRECTORY }),
+            Some((_, entry)) => Ok(DirEntry::from_entry(&entry)),
+        }
+    }
+
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, FatError> {
+        let dir = self.resolve_dir(path)?;
+        Ok(self.entries(dir)?.iter().map(DirEntry::from_entry).collect())
+    }
+
+    /// Reads from `offset` until the buffer is full or the file ends, returns how many bytes
+    pub fn read(&mut self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, FatError> {
+        let (_, entry) = self.resolve_file(path)?;
+        let size = entry.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buffer.len().min((size - offset) as usize);
+        let chain = self.chain(entry.cluster)?;
+        if (chain.len() as u64) < size.div_ceil(self.layout.cluster_size) {
+            return Err(FatError::Corrupted);
+        }
+        self.transfer(&chain, offset, Transfer::Read(&mut buffer[..len]))?;
+        Ok(len)
+    }
+
+    /// Writes all of `data` at `offset`, growing the file as needed. A gap past the end reads as
+    /// zeros.
+    pub fn write(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, FatError> {
+        let (dir, mut entry) = self.resolve_file(path)?;
+        if entry.attributes & ATTR_READ_ONLY != 0 {
+            return Err(FatError::ReadOnly);
+        }
+        let size = entry.size as u64;
+        let end = offset.checked_add(data.len() as u64).filter(|&end| end <= u32::MAX as u64).ok_or(FatError::FileTooLarge)?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let mut chain = self.chain(entry.cluster)?;
+        let allocated = chain.len() as u64 * self.layout.cluster_size;
+        while (chain.len() as u64) < end.div_ceil(self.layout.cluster_size) {
+            let cluster = self.allocate_cluster(chain.last().copied())?;
+            chain.push(cluster);
+        }
+        // the tail of the last cluster holds whatever was there before
+        if offset > size && size < allocated {
+            let gap = vec![0u8; (offset.min(allocated) - size) as usize];
+            self.transfer(&chain, size, Transfer::Write(&gap))?;
+        }
+        self.transfer(&chain, offset, Transfer::Write(data))?;
+
+        if entry.cluster == 0 || end > size {
+            entry.cluster = chain[0];
+            entry.size = end.max(size) as u32;
+            self.update_entry(dir, &entry)?;
+        }
+        Ok(data.len())
+    }
+
+    /// Creates an empty file
+    pub fn create(&mut self, path: &str) -> Result<(), FatError> {
+        let (dir, name) = self.parent(path)?;
+        self.add_entry(dir, name, ATTR_ARCHIVE, 0)
+    }
+
+    pub fn mkdir(&mut self, path: &str) -> Result<(), FatError> {
+        let (dir, name) = self.parent(path)?;
+        if self.find(dir, name)?.is_some() {
+            return Err(FatError::AlreadyExists);
+        }
+
+        let cluster = self.allocate_cluster(None)?;
+        let parent_cluster = match dir {
+            Dir::Chain(cluster) if cluster != self.layout.root_cluster => cluster,
+            _ => 0,
+        };
+        let mut dots = [0u8; 2 * DIR_ENTRY_SIZE];
+        short_entry(&mut dots[..DIR_ENTRY_SIZE], b".          ", 0, ATTR_DIRECTORY, cluster);
+        short_entry(&mut dots[DIR_ENTRY_SIZE..], b"..         ", 0, ATTR_DIRECTORY, parent_cluster);
+        self.seek(self.cluster_offset(cluster))?;
+        self.device.write_all(&dots)?;
+
+        if let Err(e) = self.add_entry(dir, name, ATTR_DIRECTORY, cluster) {
+            self.free_chain(cluster)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Removes a file and frees its clusters, unless it is read-only
+    pub fn remove(&mut self, path: &str) -> Result<(), FatError> {
+        let (dir, entry) = self.resolve_file(path)?;
+        if entry.attributes & ATTR_READ_ONLY != 0 {
+            return Err(FatError::ReadOnly);
+        }
+        self.remove_entry(dir, &entry)
+    }
+
+    /// Removes an empty directory
+    pub fn rmdir(&mut self, path: &str) -> Result<(), FatError> {
+        let Some((dir, entry)) = self.resolve(path)? else {
+            return Err(FatError::InvalidName);
+        };
+        if !entry.is_dir() {
+            return Err(FatError::NotADirectory);
+        }
+        if !self.entries(Dir::Chain(entry.cluster))?.is_empty() {
+            return Err(FatError::DirectoryNotEmpty);
+        }
+        self.remove_entry(dir, &entry)
+    }
+
+    /// Sets the read-only, hidden and system bits of an entry, the others are kept: the archive
+    /// bit belongs to backup tools
+    pub fn set_attributes(&mut self, path: &str, attributes: u8) -> Result<(), FatError> {
+        let Some((dir, mut entry)) = self.resolve(path)? else {
+            return Err(FatError::InvalidName);
+        };
+        let settable = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM;
+        entry.attributes = entry.attributes & !settable | attributes & settable;
+        self.update_entry(dir, &entry)
+    }
+
+    /// Updates the free count of FAT32 and flushes the device
+    pub fn flush(&mut self) -> Result<(), FatError> {
+        if let (Some(fs_info), true) = (self.layout.fs_info, self.fat_changed) {
+            let free = self.free_clusters();
+            self.seek(fs_info + FS_INFO_FREE_COUNT)?;
+            self.device.write_all(&free.to_le_bytes())?;
+            self.device.write_all(&self.next_free.to_le_bytes())?;
+            self.fat_changed = false;
+        }
+        self.device.flush()?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Keeps the FS info sector only if its signatures are right
+    fn with_fs_info(mut self) -> Result<Self, FatError> {
+        if let Some(offset) = self.layout.fs_info {
+            let mut sector = [0u8; 512];
+            self.seek(offset)?;
+            self.device.read_exact(&mut sector)?;
+            if read_u32_le(&sector, 0) != FS_INFO_LEAD_SIGNATURE || read_u32_le(&sector, 484) != FS_INFO_SIGNATURE {
+                self.layout.fs_info = None;
+            }
+        }
+        Ok(self)
+    }
+
+    fn seek(&mut self, offset: u64) -> Result<(), FatError> {
+        self.device.seek(SeekFrom::Start(offset))?;
+        Ok(())
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (2..self.layout.clusters + 2).contains(&cluster)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.layout.data_start + (cluster - 2) as u64 * self.layout.cluster_size
+    }
+
+    fn fat_entry(&self, cluster: u32) -> u32 {
+        let cluster = cluster as usize;
+        match self.layout.fat_type {
+            FatType::Fat12 => {
+                let pair = read_u16_le(&self.fat, cluster + cluster / 2);
+                (if cluster % 2 == 1 { pair >> 4 } else { pair & 0xfff }) as u32
+            },
+            FatType::Fat16 => read_u16_le(&self.fat, cluster * 2) as u32,
+            FatType::Fat32 => read_u32_le(&self.fat, cluster * 4) & 0x0fff_ffff,
+        }
+    }
+
+    /// Sets the entry of `cluster` in every FAT
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), FatError> {
+        let index = cluster as usize;
+        let (offset, len) = match self.layout.fat_type {
+            FatType::Fat12 => {
+                let offset = index + index / 2;
+                let pair = read_u16_le(&self.fat, offset);
+                let pair = if index % 2 == 1 {
+                    (pair & 0x000f) | ((value as u16) << 4)
+                } else {
+                    (pair & 0xf000) | (value as u16 & 0x0fff)
+                };
+                self.fat[offset..offset + 2].copy_from_slice(&pair.to_le_bytes());
+                (offset, 2)
+            },
+            FatType::Fat16 => {
+                self.fat[index * 2..index * 2 + 2].copy_from_slice(&(value as u16).to_le_bytes());
+                (index * 2, 2)
+            },
+            FatType::Fat32 => {
+                // the top 4 bits are reserved
+                let value = (read_u32_le(&self.fat, index * 4) & 0xf000_0000) | (value & 0x0fff_ffff);
+                self.fat[index * 4..index * 4 + 4].copy_from_slice(&value.to_le_bytes());
+                (index * 4, 4)
+            },
+        };
+
+        for copy in 0..self.layout.fats {
+            self.seek(self.layout.fat_start + copy * self.layout.fat_size + offset as u64)?;
+            let bytes = &self.fat[offset..offset + len];
+            self.device.write_all(bytes)?;
+        }
+        self.fat_changed = true;
+        Ok(())
+    }
+
+    /// The clusters of the chain starting at `first`, none for 0
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FatError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 && cluster < self.layout.fat_type.end_of_chain() {
+            // a free or bad cluster, or a loop
+            if !self.is_cluster(cluster) || chain.len() >= self.layout.clusters as usize {
+                return Err(FatError::Corrupted);
+            }
+            chain.push(cluster);
+            cluster = self.fat_entry(cluster);
+            if cluster == 0 {
+                return Err(FatError::Corrupted);
+            }
+        }
+        Ok(chain)
+    }
+
+    /// A zeroed cluster, linked after `previous`
+    fn allocate_cluster(&mut self, previous: Option<u32>) -> Result<u32, FatError> {
+        let count = self.layout.clusters;
+        let cluster = (0..count)
+            .map(|i| 2 + (self.next_free - 2 + i) % count)
+            .find(|&cluster| self.fat_entry(cluster) == 0)
+            .ok_or(FatError::NoSpace)?;
+
+        self.seek(self.cluster_offset(cluster))?;
+        self.device.write_all(&vec![0u8; self.layout.cluster_size as usize])?;
+        self.set_fat_entry(cluster, 0x0fff_ffff)?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+        self.next_free = if cluster + 1 < count + 2 { cluster + 1 } else { 2 };
+        Ok(cluster)
+    }
+
+    fn free_chain(&mut self, first: u32) -> Result<(), FatError> {
+        for cluster in self.chain(first)? {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Reads or writes `chain` from byte `offset`, which the chain covers
+    fn transfer(&mut self, chain: &[u32], mut offset: u64, mut transfer: Transfer) -> Result<(), FatError> {
+        let cluster_size = self.layout.cluster_size;
+        while transfer.len() > 0 {
+            let cluster = *chain.get((offset / cluster_size) as usize).ok_or(FatError::Corrupted)?;
+            let in_cluster = offset % cluster_size;
+            let len = transfer.len().min((cluster_size - in_cluster) as usize);
+            self.seek(self.cluster_offset(cluster) + in_cluster)?;
+            transfer = match transfer {
+                Transfer::Read(buffer) => {
+                    let (now, rest) = buffer.split_at_mut(len);
+                    self.device.read_exact(now)?;
+                    Transfer::Read(rest)
+                },
+                Transfer::Write(data) => {
+                    let (now, rest) = data.split_at(len);
+                    self.device.write_all(now)?;
+                    Transfer::Write(rest)
+                },
+            };
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    fn root_dir(&self) -> Dir {
+        match self.layout.fat_type {
+            FatType::Fat32 => Dir::Chain(self.layout.root_cluster),
+            _ => Dir::FixedRoot,
+        }
+    }
+
+    /// All entries of a directory, free ones included
+    fn dir_data(&mut self, dir: Dir) -> Result<Vec<u8>, FatError> {
+        match dir {
+            Dir::FixedRoot => {
+                let mut data = vec![0u8; self.layout.root_size as usize];
+                self.seek(self.layout.root_start)?;
+                self.device.read_exact(&mut data)?;
+                Ok(data)
+            },
+            Dir::Chain(first) => {
+                let chain = self.chain(first)?;
+                let mut data = vec![0u8; chain.len() * self.layout.cluster_size as usize];
+                self.transfer(&chain, 0, Transfer::Read(&mut data))?;
+                Ok(data)
+            },
+        }
+    }
+
+    /// Where byte `offset` of a directory is on the device
+    fn dir_position(&self, dir: Dir, offset: u64) -> Result<u64, FatError> {
+        match dir {
+            Dir::FixedRoot => Ok(self.layout.root_start + offset),
+            Dir::Chain(first) => {
+                let cluster = *self.chain(first)?.get((offset / self.layout.cluster_size) as usize).ok_or(FatError::Corrupted)?;
+                Ok(self.cluster_offset(cluster) + offset % self.layout.cluster_size)
+            },
+        }
+    }
+
+    /// The used entries of a directory, without `.` and `..` and the volume label
+    fn entries(&mut self, dir: Dir) -> Result<Vec<Entry>, FatError> {
+        let data = self.dir_data(dir)?;
+        let mut entries = Vec::new();
+        let mut long_name = LongName::default();
+        for (slot, bytes) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+            match bytes[0] {
+                0 => break,
+                DELETED => {
+                    long_name = LongName::default();
+                    continue;
+                },
+                _ => {},
+            }
+            let attributes = bytes[11];
+            if attributes & 0x3f == ATTR_LONG_NAME {
+                long_name.push(bytes, slot);
+                continue;
+            }
+            let short_name: [u8; 11] = bytes[..11].try_into().unwrap();
+            let long = core::mem::take(&mut long_name).finish(checksum(&short_name));
+            if attributes & ATTR_VOLUME_ID != 0 || short_name[0] == b'.' {
+                continue;
+            }
+
+            let (name, first_slot) = long.unwrap_or_else(|| (display_short_name(&short_name, bytes[12]), slot));
+            let cluster = (read_u16_le(bytes, 20) as u32) << 16 | read_u16_le(bytes, 26) as u32;
+            let entry = Entry {
+                name,
+                short_name,
+                attributes,
+                cluster,
+                size: read_u32_le(bytes, 28),
+                offset: (slot * DIR_ENTRY_SIZE) as u64,
+                slots: (slot - first_slot + 1) as u64,
+            };
+            if entry.is_dir() && !self.is_cluster(cluster) {
+                return Err(FatError::Corrupted);
+            }
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    fn find(&mut self, dir: Dir, name: &str) -> Result<Option<Entry>, FatError> {
+        Ok(self.entries(dir)?.into_iter().find(|entry| names_match(&entry.name, name)))
+    }
+
+    /// The directory holding the entry and the entry, None for the root
+    fn resolve(&mut self, path: &str) -> Result<Option<(Dir, Entry)>, FatError> {
+        let mut dir = self.root_dir();
+        let mut found = None;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if let Some((_, entry)) = &found {
+                let entry: &Entry = entry;
+                if !entry.is_dir() {
+                    return Err(FatError::NotADirectory);
+                }
+                dir = Dir::Chain(entry.cluster);
+            }
+            let entry = self.find(dir, name)?.ok_or(FatError::NotFound)?;
+            found = Some((dir, entry));
+        }
+        Ok(found)
+    }
+
+    fn resolve_dir(&mut self, path: &str) -> Result<Dir, FatError> {
+        match self.resolve(path)? {
+            None => Ok(self.root_dir()),
+            Some((_, entry)) if entry.is_dir() => Ok(Dir::Chain(entry.cluster)),
+            Some(_) => Err(FatError::NotADirectory),
+        }
+    }
+
+    fn resolve_file(&mut self, path: &str) -> Result<(Dir, Entry), FatError> {
+        match self.resolve(path)? {
+            None => Err(FatError::IsADirectory),
+            Some((_, entry)) if entry.is_dir() => Err(FatError::IsADirectory),
+            Some(found) => Ok(found),
+        }
+    }
+
+    /// The directory holding the last component of `path` and that component
+    fn parent<'p>(&mut self, path: &'p str) -> Result<(Dir, &'p str), FatError> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if !valid_long_name(name) {
+            return Err(FatError::InvalidName);
+        }
+        Ok((self.resolve_dir(parent)?, name))
+    }
+
+    fn add_entry(&mut self, dir: Dir, name: &str, attributes: u8, cluster: u32) -> Result<(), FatError> {
+        let entries = self.entries(dir)?;
+        if entries.iter().any(|entry| names_match(&entry.name, name)) {
+            return Err(FatError::AlreadyExists);
+        }
+
+        let exact = exact_short_name(name);
+        let (short_name, nt_flags) = match exact {
+            Some(exact) => exact,
+            None => (generate_short_name(name, &entries)?, 0),
+        };
+        let mut slots = Vec::new();
+        if exact.is_none() {
+            let units: Vec<u16> = name.encode_utf16().collect();
+            let pieces = units.len().div_ceil(LFN_CHARS);
+            for piece in (0..pieces).rev() {
+                let mut bytes = [0u8; DIR_ENTRY_SIZE];
+                long_name_entry(&mut bytes, &units, piece, pieces, checksum(&short_name));
+                slots.push(bytes);
+            }
+        }
+        let mut bytes = [0u8; DIR_ENTRY_SIZE];
+        short_entry(&mut bytes, &short_name, nt_flags, attributes, cluster);
+        slots.push(bytes);
+
+        let mut offset = self.free_slots(dir, slots.len())?;
+        for bytes in slots {
+            let position = self.dir_position(dir, offset)?;
+            self.seek(position)?;
+            self.device.write_all(&bytes)?;
+            offset += DIR_ENTRY_SIZE as u64;
+        }
+        Ok(())
+    }
+
+    /// Offset of `count` consecutive free entries, the directory grows if there are none
+    fn free_slots(&mut self, dir: Dir, count: usize) -> Result<u64, FatError> {
+        let data = self.dir_data(dir)?;
+        let mut run = 0;
+        for (slot, bytes) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+            if bytes[0] == 0 || bytes[0] == DELETED {
+                run += 1;
+                if run == count {
+                    return Ok(((slot + 1 - count) * DIR_ENTRY_SIZE) as u64);
+                }
+            } else {
+                run = 0;
+            }
+        }
+
+        let Dir::Chain(first) = dir else {
+            return Err(FatError::NoSpace);
+        };
+        // new clusters are zeroed, the free entries at the end carry on into them
+        let mut last = self.chain(first)?.last().copied();
+        let missing = ((count - run) * DIR_ENTRY_SIZE) as u64;
+        for _ in 0..missing.div_ceil(self.layout.cluster_size) {
+            last = Some(self.allocate_cluster(last)?);
+        }
+        Ok((data.len() - run * DIR_ENTRY_SIZE) as u64)
+    }
+
+    /// Writes the cluster and size of `entry` back
+    fn update_entry(&mut self, dir: Dir, entry: &Entry) -> Result<(), FatError> {
+        let position = self.dir_position(dir, entry.offset)?;
+        let mut bytes = [0u8; DIR_ENTRY_SIZE];
+        self.seek(position)?;
+        self.device.read_exact(&mut bytes)?;
+        bytes[11] = entry.attributes;
+        bytes[20..22].copy_from_slice(&((entry.cluster >> 16) as u16).to_le_bytes());
+        bytes[26..28].copy_from_slice(&(entry.cluster as u16).to_le_bytes());
+        bytes[28..32].copy_from_slice(&entry.size.to_le_bytes());
+        self.seek(position)?;
+        self.device.write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Marks the entry and its long name deleted and frees its clusters
+    fn remove_entry(&mut self, dir: Dir, entry: &Entry) -> Result<(), FatError> {
+        for slot in 0..entry.slots {
+            let position = self.dir_position(dir, entry.offset - slot * DIR_ENTRY_SIZE as u64)?;
+            self.seek(position)?;
+            self.device.write_all(&[DELETED])?;
+        }
+        self.free_chain(entry.cluster)
+    }
+}
+
+enum Transfer<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+impl Transfer<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Transfer::Read(buffer) => buffer.len(),
+            Transfer::Write(data) => data.len(),
+        }
+    }
+}
+
+/// Collects the long name entries before an 8.3 entry, last piece first
+#[derive(Default)]
+struct LongName {
+    units: Vec<u16>,
+    /// Sequence number of the next entry expected, 0 once complete
+    next: u8,
+    checksum: u8,
+    first_slot: usize,
+    valid: bool,
+}
+
+impl LongName {
+    fn push(&mut self, bytes: &[u8], slot: usize) {
+        let sequence = bytes[0] & 0x1f;
+        if bytes[0] & LFN_LAST != 0 {
+            *self = LongName {
+                units: vec![0xffff; sequence as usize * LFN_CHARS],
+                next: sequence,
+                checksum: bytes[13],
+                first_slot: slot,
+                valid: sequence > 0,
+            };
+        }
+        if !self.valid || sequence != self.next || bytes[13] != self.checksum {
+            self.valid = false;
+            return;
+        }
+        let start = (sequence as usize - 1) * LFN_CHARS;
+        for (i, &offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            self.units[start + i] = read_u16_le(bytes, offset);
+        }
+        self.next -= 1;
+    }
+
+    /// The name and the slot of its first entry, if the entries belong to the 8.3 entry
+    fn finish(self, checksum: u8) -> Option<(String, usize)> {
+        if !self.valid || self.next != 0 || self.checksum != checksum {
+            return None;
+        }
+        let end = self.units.iter().position(|&unit| unit == 0 || unit == 0xffff).unwrap_or(self.units.len());
+        let name: String = char::decode_utf16(self.units[..end].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        (!name.is_empty()).then_some((name, self.first_slot))
+    }
+}
+
+fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &byte| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(byte))
+}
+
+fn names_match(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.chars().zip(b.chars()).all(|(a, b)| a.to_lowercase().eq(b.to_lowercase()))
+}
+
+fn valid_long_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.encode_utf16().count() <= MAX_NAME_LEN
+        && name != "."
+        && name != ".."
+        && !name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c))
+}
+
+fn valid_short_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&byte)
+}
+
+/// `NAME.EXT` with the case the NT flags ask for
+fn display_short_name(short_name: &[u8; 11], nt_flags: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        bytes.iter()
+            .map(|&byte| if lower { byte.to_ascii_lowercase() } else { byte })
+            // 0x05 stands for a leading 0xe5, the rest of the code page is left as Latin-1
+            .map(|byte| if byte == 0x05 { 'å' } else { byte as char })
+            .collect::<String>()
+            .trim_end()
+            .into()
+    };
+    let base = part(&short_name[..8], nt_flags & NT_LOWER_BASE != 0);
+    let ext = part(&short_name[8..], nt_flags & NT_LOWER_EXT != 0);
+    if ext.is_empty() { base } else { alloc::format!("{}.{}", base, ext) }
+}
+
+/// The 8.3 entry name and NT flags for a name that needs no long name: at most 8 and 3
+/// characters, each part in a single case
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || (name.contains('.') && ext.is_empty()) {
+        return None;
+    }
+    let case = |part: &str| {
+        let lower = part.bytes().any(|byte| byte.is_ascii_lowercase());
+        let upper = part.bytes().any(|byte| byte.is_ascii_uppercase());
+        let valid = part.bytes().all(|byte| valid_short_char(byte.to_ascii_uppercase()));
+        (valid && !(lower && upper)).then_some(lower)
+    };
+    let (lower_base, lower_ext) = (case(base)?, case(ext)?);
+
+    let mut short_name = [b' '; 11];
+    for (i, byte) in base.bytes().enumerate() {
+        short_name[i] = byte.to_ascii_uppercase();
+    }
+    for (i, byte) in ext.bytes().enumerate() {
+        short_name[8 + i] = byte.to_ascii_uppercase();
+    }
+    let flags = if lower_base { NT_LOWER_BASE } else { 0 } | if lower_ext { NT_LOWER_EXT } else { 0 };
+    Some((short_name, flags))
+}
+
+/// `BASIS~N.EXT`, unique among `entries`
+fn generate_short_name(name: &str, entries: &[Entry]) -> Result<[u8; 11], FatError> {
+    let clean = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| match c.to_ascii_uppercase() {
+                c if c.is_ascii() && valid_short_char(c as u8) => c as u8,
+                _ => b'_',
+            })
+            .collect()
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = trimmed.rsplit_once('.').unwrap_or((trimmed, ""));
+    let (base, ext) = (clean(base), clean(ext));
+
+    for n in 1..1_000_000u32 {
+        let tail = alloc::format!("~{}", n);
+        let keep = base.len().min(8 - tail.len()).max(1);
+        let mut short_name = [b' '; 11];
+        for (i, &byte) in base.iter().take(keep).chain(tail.as_bytes()).enumerate() {
+            short_name[i] = byte;
+        }
+        if base.is_empty() {
+            short_name[0] = b'_';
+        }
+        for (i, &byte) in ext.iter().take(3).enumerate() {
+            short_name[8 + i] = byte;
+        }
+        if !entries.iter().any(|entry| entry.short_name == short_name) {
+            return Ok(short_name);
+        }
+    }
+    Err(FatError::NoSpace)
+}
+
+fn short_entry(bytes: &mut [u8], short_name: &[u8; 11], nt_flags: u8, attributes: u8, cluster: u32) {
+    bytes.fill(0);
+    bytes[..11].copy_from_slice(short_name);
+    bytes[11] = attributes;
+    bytes[12] = nt_flags;
+    // 1980-01-01 00:00 for creation, access and modification
+    for date in [16, 18, 24] {
+        bytes[date..date + 2].copy_from_slice(&0x21u16.to_le_bytes());
+    }
+    bytes[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    bytes[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// Entry for piece `piece` of the UTF-16 name `units`, the name ends with a NUL and 0xffff
+/// padding
+fn long_name_entry(bytes: &mut [u8], units: &[u16], piece: usize, pieces: usize, checksum: u8) {
+    bytes[0] = (piece + 1) as u8 | if piece + 1 == pieces { LFN_LAST } else { 0 };
+    bytes[11] = ATTR_LONG_NAME;
+    bytes[13] = checksum;
+    for (i, &offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+        let index = piece * LFN_CHARS + i;
+        let unit = match index.cmp(&units.len()) {
+            core::cmp::Ordering::Less => units[index],
+            core::cmp::Ordering::Equal => 0,
+            core::cmp::Ordering::Greater => 0xffff,
+        };
+        bytes[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+use crate::io::Cursor;
+
+/// A blank FAT16 volume of 8 MiB: 1 KiB clusters, 2 FATs, 512 root entries
+#[cfg(test)]
+fn test_volume() -> Fat<Cursor<Vec<u8>>> {
+    let sectors = 16384u32;
+    let mut image = vec![0u8; sectors as usize * 512];
+    image[11..13].copy_from_slice(&512u16.to_le_bytes());
+    image[13] = 2;
+    image[14..16].copy_from_slice(&1u16.to_le_bytes());
+    image[16] = 2;
+    image[17..19].copy_from_slice(&512u16.to_le_bytes());
+    image[19..21].copy_from_slice(&(sectors as u16).to_le_bytes());
+    image[22..24].copy_from_slice(&32u16.to_le_bytes());
+    image[510..512].copy_from_slice(&[0x55, 0xaa]);
+    // media descriptor and end of chain in the reserved entries
+    for fat in [512, 512 + 32 * 512] {
+        image[fat..fat + 4].copy_from_slice(&[0xf8, 0xff, 0xff, 0xff]);
+    }
+    Fat::mount(Cursor::new(image)).unwrap()
+}
+
+#[test_case]
+fn fat_files_and_long_names() {
+    let mut fs = test_volume();
+    assert_eq!(fs.fat_type(), FatType::Fat16);
+    let free = fs.free_clusters();
+
+    fs.create("README.TXT").unwrap();
+    fs.create("A long file name.txt").unwrap();
+    fs.mkdir("EFI").unwrap();
+    fs.mkdir("efi/boot").unwrap();
+    let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    fs.write("/EFI/BOOT/bootx64.efi", 0, &data).err().unwrap();
+    fs.create("/EFI/BOOT/bootx64.efi").unwrap();
+    fs.write("/EFI/BOOT/bootx64.efi", 0, &data).unwrap();
+    fs.write("a long file name.TXT", 3, b"xyz").unwrap();
+    assert_eq!(fs.create("readme.txt").err(), Some(FatError::AlreadyExists));
+
+    let names: Vec<String> = fs.read_dir("/").unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, ["README.TXT", "A long file name.txt", "EFI"]);
+    assert_eq!(fs.read_dir("/efi").unwrap()[0].name, "boot");
+    fs.set_attributes("readme.txt", ATTR_HIDDEN | ATTR_DIRECTORY).unwrap();
+    assert_eq!(fs.metadata("README.TXT").unwrap().attributes, ATTR_HIDDEN | ATTR_ARCHIVE);
+    fs.set_attributes("readme.txt", ATTR_READ_ONLY).unwrap();
+    assert_eq!(fs.write("readme.txt", 0, b"x"), Err(FatError::ReadOnly));
+    assert_eq!(fs.remove("readme.txt"), Err(FatError::ReadOnly));
+    fs.set_attributes("readme.txt", 0).unwrap();
+    assert_eq!(fs.metadata("README.TXT").unwrap().attributes, ATTR_ARCHIVE);
+    fs.set_attributes("efi", 0).unwrap();
+    assert!(fs.metadata("EFI").unwrap().is_dir);
+    let mut read_back = vec![0u8; 6000];
+    assert_eq!(fs.read("/efi/boot/BOOTX64.EFI", 0, &mut read_back), Ok(5000));
+    assert_eq!(read_back[..5000], data[..]);
+    let mut gap = [0xffu8; 6];
+    assert_eq!(fs.read("a long file name.txt", 0, &mut gap), Ok(6));
+    assert_eq!(&gap, b"\0\0\0xyz");
+
+    // the long name made a short one
+    let entries = fs.entries(Dir::FixedRoot).unwrap();
+    assert_eq!(&entries[1].short_name, b"ALONGF~1TXT");
+    assert_eq!(entries[1].slots, 3);
+
+    assert_eq!(fs.rmdir("/efi").err(), Some(FatError::DirectoryNotEmpty));
+    fs.remove("/efi/boot/bootx64.efi").unwrap();
+    fs.rmdir("/efi/boot").unwrap();
+    fs.rmdir("/efi").unwrap();
+    fs.remove("A long file name.txt").unwrap();
+    fs.remove("README.TXT").unwrap();
+    assert!(fs.read_dir("/").unwrap().is_empty());
+    assert_eq!(fs.free_clusters(), free);
+}
+
+#[test_case]
+fn fat_directory_grows() {
+    let mut fs = test_volume();
+    fs.mkdir("many").unwrap();
+    // 1 KiB clusters hold 32 entries, `.` and `..` included
+    for i in 0..100 {
+        fs.create(&alloc::format!("many/file number {}", i)).unwrap();
+    }
+    let entries = fs.read_dir("many").unwrap();
+    assert_eq!(entries.len(), 100);
+    assert_eq!(entries[99].name, "file number 99");
+    assert_eq!(fs.metadata("many/FILE NUMBER 42").unwrap().size, 0);
+
+    let fs = Fat::mount(fs.into_inner()).unwrap();
+    assert_eq!(fs.fat_type(), FatType::Fat16);
+}
+
+#[test_case]
+fn fat_rejects_bad_volumes() {
+    assert!(matches!(Fat::mount(Cursor::new(vec![0u8; 4096])), Err(FatError::NotFat)));
+
+    let mut fs = test_volume();
+    fs.create("loop").unwrap();
+    fs.write("loop", 0, &[1u8; 5000]).unwrap();
+    // point the second cluster of the file back at the first
+    let first = fs.entries(Dir::FixedRoot).unwrap()[0].cluster;
+    let second = fs.fat_entry(first);
+    fs.set_fat_entry(second, first).unwrap();
+    let mut buffer = [0u8; 16];
+    assert_eq!(fs.read("loop", 0, &mut buffer), Err(FatError::Corrupted));
+}
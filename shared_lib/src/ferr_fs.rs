@@ -0,0 +1,1006 @@
+// ferr_fs, the native filesystem.
+//
+// Blocks are 4 KiB. Block 0 holds the superblock, followed by the block bitmap, one bit per block
+// of the filesystem with the metadata blocks set. Everything else is data blocks. Files are
+// inodes: a type, a size, block pointers, creation and modification times and `FileFlags`. There
+// are ten direct block pointers, a single and a double indirect block of u32 block numbers.
+// Block 0 is never a data block, so a pointer of 0 is a hole, which reads as zeros.
+//
+// The inode table is itself stored like a file, inode 0, whose inode is kept in the superblock;
+// it grows by a slot whenever no free inode is left. Inode 1 is the root directory. A directory's
+// data is an array of fixed-size entries, a child inode and a name; an entry with inode 0 is free
+// and reused by the next entry added. Paths are resolved from the root, `/logs/boot.txt` and
+// `logs/boot.txt` are the same file.
+//
+// `FerrFs` works on anything `Read + Write + Seek`: a partition through the kernel's block
+// stream, or an image in memory. The bitmap and the inodes are kept in memory and written back
+// by `flush`; file data, directory entries and indirect blocks go to the device right away.
+// Times are seconds since the Unix epoch from the clock given to `with_clock`, 0 without one;
+// writing to a file or a directory's entries updates its modification time.
+// `mount` checks the superblock checksum and layout, and block and inode numbers are checked
+// before they are followed, so a corrupted image fails with an error instead of touching other
+// blocks.
+
+use alloc::collections::BTreeSet;
+use bitflags::bitflags;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::bytes::{read_u32_le, read_u64_le};
+use crate::crc::calculate_crc32;
+use crate::io::{seek_offset, IoError, Read, Seek, SeekFrom, Write};
+
+pub const BLOCK_SIZE: usize = 4096;
+pub const MAX_NAME_LEN: usize = DIR_ENTRY_SIZE - DIR_ENTRY_NAME_OFFSET;
+
+/// "FRFS"
+const MAGIC: u32 = 0x5346_5246;
+const VERSION: u32 = 2;
+const TABLE_INODE_OFFSET: usize = 64;
+const SUPERBLOCK_CHECKSUM_OFFSET: usize = TABLE_INODE_OFFSET + INODE_SIZE;
+
+const INODE_SIZE: usize = 128;
+const INODE_FILE: u8 = 1;
+const INODE_DIRECTORY: u8 = 2;
+/// The inode table, its inode is the one in the superblock
+const TABLE_INODE: u32 = 0;
+const ROOT_INODE: u32 = 1;
+const DIRECT_POINTERS: usize = 10;
+const POINTERS_PER_BLOCK: usize = BLOCK_SIZE / 4;
+const BITS_PER_BLOCK: u64 = BLOCK_SIZE as u64 * 8;
+
+const DIR_ENTRY_SIZE: usize = 64;
+const DIR_ENTRY_NAME_OFFSET: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    Io(IoError),
+    /// No ferr_fs superblock, or one of an unknown version
+    NotFormatted,
+    BadChecksum,
+    /// The metadata is inconsistent or points outside the filesystem
+    Corrupted,
+    /// The device can't hold the metadata and a data block
+    DeviceTooSmall,
+    NotFound,
+    AlreadyExists,
+    /// Empty, longer than `MAX_NAME_LEN`, containing NUL or `.` and `..`
+    InvalidName,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    /// Writing or deleting a file flagged `READ_ONLY`
+    ReadOnly,
+    NoFreeInode,
+    NoSpace,
+    FileTooLarge,
+}
+
+impl From<IoError> for FsError {
+    fn from(e: IoError) -> Self {
+        FsError::Io(e)
+    }
+}
+
+impl From<FsError> for IoError {
+    fn from(e: FsError) -> Self {
+        match e {
+            FsError::Io(e) => e,
+            FsError::NoSpace | FsError::FileTooLarge => IoError::StorageFull,
+            FsError::ReadOnly => IoError::ReadOnly,
+            _ => IoError::InvalidData,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+bitflags! {
+    /// Kept in the inode, `READ_ONLY` is the only one enforced
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct FileFlags: u32 {
+        const READ_ONLY = 1;
+        const HIDDEN = 1 << 1;
+        const SYSTEM = 1 << 2;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Superblock {
+    block_count: u64,
+    free_blocks: u64,
+    bitmap_start: u64,
+    bitmap_blocks: u64,
+    data_start: u64,
+}
+
+impl Superblock {
+    /// Where everything goes on a filesystem of `block_count` blocks, all data blocks free
+    fn layout(block_count: u64) -> Superblock {
+        let bitmap_blocks = block_count.div_ceil(BITS_PER_BLOCK);
+        let data_start = 1 + bitmap_blocks;
+        Superblock {
+            block_count,
+            free_blocks: block_count.saturating_sub(data_start),
+            bitmap_start: 1,
+            bitmap_blocks,
+            data_start,
+        }
+    }
+
+    /// The superblock and the inode of the inode table
+    fn parse(block: &[u8]) -> Result<(Superblock, Inode), FsError> {
+        if read_u32_le(block, 0) != MAGIC || read_u32_le(block, 4) != VERSION {
+            return Err(FsError::NotFormatted);
+        }
+        if calculate_crc32(&block[..SUPERBLOCK_CHECKSUM_OFFSET]) != read_u32_le(block, SUPERBLOCK_CHECKSUM_OFFSET) {
+            return Err(FsError::BadChecksum);
+        }
+        let superblock = Superblock {
+            block_count: read_u64_le(block, 8),
+            free_blocks: read_u64_le(block, 16),
+            bitmap_start: read_u64_le(block, 24),
+            bitmap_blocks: read_u64_le(block, 32),
+            data_start: read_u64_le(block, 40),
+        };
+
+        // only ever written by `format`, everything but the free count follows from the size
+        let expected = Superblock { free_blocks: superblock.free_blocks, ..Superblock::layout(superblock.block_count) };
+        if superblock != expected || superblock.block_count > u32::MAX as u64 || superblock.data_start >= superblock.block_count {
+            return Err(FsError::Corrupted);
+        }
+        let table = Inode::parse(&block[TABLE_INODE_OFFSET..TABLE_INODE_OFFSET + INODE_SIZE])?;
+        Ok((superblock, table))
+    }
+
+    fn to_block(&self, table: &Inode) -> Vec<u8> {
+        let mut block = vec![0u8; BLOCK_SIZE];
+        block[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        block[4..8].copy_from_slice(&VERSION.to_le_bytes());
+        let fields = [self.block_count, self.free_blocks, self.bitmap_start, self.bitmap_blocks, self.data_start];
+        for (field, bytes) in fields.iter().zip(block[8..].chunks_exact_mut(8)) {
+            bytes.copy_from_slice(&field.to_le_bytes());
+        }
+        table.write_to(&mut block[TABLE_INODE_OFFSET..TABLE_INODE_OFFSET + INODE_SIZE]);
+        let checksum = calculate_crc32(&block[..SUPERBLOCK_CHECKSUM_OFFSET]);
+        block[SUPERBLOCK_CHECKSUM_OFFSET..SUPERBLOCK_CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+        block
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Inode {
+    /// None for a free inode
+    file_type: Option<FileType>,
+    size: u64,
+    direct: [u32; DIRECT_POINTERS],
+    indirect: u32,
+    double_indirect: u32,
+    created: u64,
+    modified: u64,
+    flags: FileFlags,
+}
+
+impl Inode {
+    // type: u8, size: u64 at 8, pointers at 16, created: u64 at 64, modified: u64 at 72,
+    // flags: u32 at 80, the rest is reserved
+
+    fn new(file_type: FileType) -> Inode {
+        Inode { file_type: Some(file_type), ..Inode::default() }
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Inode, FsError> {
+        let file_type = match bytes[0] {
+            0 => return Ok(Inode::default()),
+            INODE_FILE => FileType::File,
+            INODE_DIRECTORY => FileType::Directory,
+            _ => return Err(FsError::Corrupted),
+        };
+        Ok(Inode {
+            file_type: Some(file_type),
+            size: read_u64_le(bytes, 8),
+            direct: core::array::from_fn(|i| read_u32_le(bytes, 16 + i * 4)),
+            indirect: read_u32_le(bytes, 56),
+            double_indirect: read_u32_le(bytes, 60),
+            created: read_u64_le(bytes, 64),
+            modified: read_u64_le(bytes, 72),
+            flags: FileFlags::from_bits_retain(read_u32_le(bytes, 80)),
+        })
+    }
+
+    fn write_to(&self, bytes: &mut [u8]) {
+        bytes.fill(0);
+        bytes[0] = match self.file_type {
+            None => return,
+            Some(FileType::File) => INODE_FILE,
+            Some(FileType::Directory) => INODE_DIRECTORY,
+        };
+        bytes[8..16].copy_from_slice(&self.size.to_le_bytes());
+        for (pointer, slot) in self.direct.iter().zip(bytes[16..56].chunks_exact_mut(4)) {
+            slot.copy_from_slice(&pointer.to_le_bytes());
+        }
+        bytes[56..60].copy_from_slice(&self.indirect.to_le_bytes());
+        bytes[60..64].copy_from_slice(&self.double_indirect.to_le_bytes());
+        bytes[64..72].copy_from_slice(&self.created.to_le_bytes());
+        bytes[72..80].copy_from_slice(&self.modified.to_le_bytes());
+        bytes[80..84].copy_from_slice(&self.flags.bits().to_le_bytes());
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME_LEN && name != "." && name != ".." && !name.contains(['/', '\0'])
+}
+
+/// The names along `path`, empty components are skipped
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|name| !name.is_empty())
+}
+
+/// A used directory entry
+struct DirEntry {
+    /// In the directory's data
+    offset: u64,
+    inode: u32,
+    name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    /// Relative to the directory listed
+    pub name: String,
+    pub file_type: FileType,
+    pub size: u64,
+    /// Seconds since the Unix epoch, 0 if unknown
+    pub created: u64,
+    pub modified: u64,
+    pub flags: FileFlags,
+}
+
+pub struct FerrFs<D> {
+    device: D,
+    superblock: Superblock,
+    /// `bitmap_blocks` long
+    bitmap: Vec<u8>,
+    /// Every slot of the inode table, `TABLE_INODE` included
+    inodes: Vec<Inode>,
+    /// Inodes changed since the last flush
+    dirty_inodes: BTreeSet<u32>,
+    /// The bitmap, the superblock or the inode of the table changed since the last flush
+    dirty: bool,
+    /// Seconds since the Unix epoch
+    clock: fn() -> u64,
+}
+
+impl<D: Read + Write + Seek> FerrFs<D> {
+    /// Writes an empty filesystem over the whole device
+    pub fn format(mut device: D) -> Result<FerrFs<D>, FsError> {
+        let block_count = (device.seek(SeekFrom::End(0))? / BLOCK_SIZE as u64).min(u32::MAX as u64);
+        let superblock = Superblock::layout(block_count);
+        if superblock.data_start >= block_count {
+            return Err(FsError::DeviceTooSmall);
+        }
+
+        let mut bitmap = vec![0u8; superblock.bitmap_blocks as usize * BLOCK_SIZE];
+        for block in 0..superblock.data_start as usize {
+            bitmap[block / 8] |= 1 << (block % 8);
+        }
+        let mut fs = FerrFs {
+            device,
+            superblock,
+            bitmap,
+            inodes: vec![Inode::new(FileType::File), Inode::new(FileType::Directory)],
+            dirty_inodes: BTreeSet::from([ROOT_INODE]),
+            dirty: true,
+            clock: no_clock,
+        };
+        fs.flush()?;
+        Ok(fs)
+    }
+
+    pub fn mount(mut device: D) -> Result<FerrFs<D>, FsError> {
+        let mut block = vec![0u8; BLOCK_SIZE];
+        read_blocks(&mut device, 0, &mut block)?;
+        let (superblock, table) = Superblock::parse(&block)?;
+        if superblock.block_count > device.seek(SeekFrom::End(0))? / BLOCK_SIZE as u64 {
+            return Err(FsError::Corrupted);
+        }
+        let table_size = table.size;
+        let valid_table = table.file_type == Some(FileType::File)
+            && table_size % INODE_SIZE as u64 == 0
+            && (2 * INODE_SIZE as u64..=superblock.block_count * BLOCK_SIZE as u64).contains(&table_size);
+        if !valid_table {
+            return Err(FsError::Corrupted);
+        }
+
+        let mut bitmap = vec![0u8; superblock.bitmap_blocks as usize * BLOCK_SIZE];
+        read_blocks(&mut device, superblock.bitmap_start, &mut bitmap)?;
+        let mut fs = FerrFs {
+            device,
+            superblock,
+            bitmap,
+            inodes: vec![table],
+            dirty_inodes: BTreeSet::new(),
+            dirty: false,
+            clock: no_clock,
+        };
+        // the bitmap is authoritative, the free count in the superblock only a copy
+        if (0..fs.superblock.data_start).any(|block| !fs.is_allocated(block)) {
+            return Err(FsError::Corrupted);
+        }
+        let free = (fs.superblock.data_start..fs.superblock.block_count).filter(|&block| !fs.is_allocated(block)).count() as u64;
+        if free != fs.superblock.free_blocks {
+            fs.superblock.free_blocks = free;
+            fs.dirty = true;
+        }
+
+        let mut records = vec![0u8; table_size as usize];
+        fs.read_exact_at(TABLE_INODE, 0, &mut records)?;
+        for record in records.chunks_exact(INODE_SIZE).skip(1) {
+            fs.inodes.push(Inode::parse(record)?);
+        }
+        if fs.inodes[ROOT_INODE as usize].file_type != Some(FileType::Directory) {
+            return Err(FsError::Corrupted);
+        }
+        Ok(fs)
+    }
+
+    /// Where the times of new files and writes come from
+    pub fn with_clock(mut self, clock: fn() -> u64) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.superblock.block_count
+    }
+
+    pub fn free_blocks(&self) -> u64 {
+        self.superblock.free_blocks
+    }
+
+    /// Creates an empty file
+    pub fn create(&mut self, path: &str) -> Result<File<'_, D>, FsError> {
+        let inode = self.add_node(path, FileType::File)?;
+        Ok(File { fs: self, inode, position: 0 })
+    }
+
+    pub fn open(&mut self, path: &str) -> Result<File<'_, D>, FsError> {
+        let inode = self.resolve(path)?;
+        if self.file_type(inode) == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(File { fs: self, inode, position: 0 })
+    }
+
+    pub fn set_flags(&mut self, path: &str, flags: FileFlags) -> Result<(), FsError> {
+        let inode = self.resolve(path)?;
+        self.inodes[inode as usize].flags = flags;
+        self.mark_dirty(inode);
+        Ok(())
+    }
+
+    /// Seconds since the Unix epoch
+    pub fn set_modified(&mut self, path: &str, modified: u64) -> Result<(), FsError> {
+        let inode = self.resolve(path)?;
+        self.inodes[inode as usize].modified = modified;
+        self.mark_dirty(inode);
+        Ok(())
+    }
+
+    /// Removes a file and frees its blocks
+    pub fn delete(&mut self, path: &str) -> Result<(), FsError> {
+        self.remove_node(path, FileType::File)
+    }
+
+    pub fn mkdir(&mut self, path: &str) -> Result<(), FsError> {
+        self.add_node(path, FileType::Directory).map(|_| ())
+    }
+
+    /// Removes an empty directory
+    pub fn rmdir(&mut self, path: &str) -> Result<(), FsError> {
+        self.remove_node(path, FileType::Directory)
+    }
+
+    /// Named by the last component of `path`, empty for the root
+    pub fn metadata(&mut self, path: &str) -> Result<FileInfo, FsError> {
+        let inode = self.resolve(path)?;
+        let name = components(path).last().unwrap_or("");
+        Ok(self.info(inode, String::from(name)))
+    }
+
+    /// The entries of a directory
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<FileInfo>, FsError> {
+        let dir = self.resolve_dir(path)?;
+        let entries = self.entries(dir)?;
+        Ok(entries.into_iter().map(|entry| self.info(entry.inode, entry.name)).collect())
+    }
+
+    /// Everything below a directory, each directory followed by its contents, named by their
+    /// path from it
+    pub fn walk(&mut self, path: &str) -> Result<Vec<FileInfo>, FsError> {
+        let dir = self.resolve_dir(path)?;
+        let mut found = Vec::new();
+        self.walk_dir(dir, "", &mut found, &mut BTreeSet::new())?;
+        Ok(found)
+    }
+
+    /// Writes the changed inodes, the bitmap and the superblock back and flushes the device
+    pub fn flush(&mut self) -> Result<(), FsError> {
+        // the records first, writing them may grow the table
+        for inode in core::mem::take(&mut self.dirty_inodes) {
+            let mut record = [0u8; INODE_SIZE];
+            self.inodes[inode as usize].write_to(&mut record);
+            self.write_all_at(TABLE_INODE, inode as u64 * INODE_SIZE as u64, &record)?;
+        }
+        if self.dirty {
+            write_blocks(&mut self.device, self.superblock.bitmap_start, &self.bitmap)?;
+            let superblock = self.superblock.to_block(&self.inodes[TABLE_INODE as usize]);
+            write_blocks(&mut self.device, 0, &superblock)?;
+            self.dirty = false;
+        }
+        self.device.flush()?;
+        Ok(())
+    }
+
+    /// The device back, changes since the last `flush` are lost
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    fn file_type(&self, inode: u32) -> FileType {
+        self.inodes[inode as usize].file_type.unwrap_or(FileType::File)
+    }
+
+    fn info(&self, inode: u32, name: String) -> FileInfo {
+        let Inode { size, created, modified, flags, .. } = self.inodes[inode as usize];
+        FileInfo { name, file_type: self.file_type(inode), size, created, modified, flags }
+    }
+
+    fn mark_dirty(&mut self, inode: u32) {
+        if inode == TABLE_INODE {
+            self.dirty = true;
+        } else {
+            self.dirty_inodes.insert(inode);
+        }
+    }
+
+    fn resolve(&mut self, path: &str) -> Result<u32, FsError> {
+        let mut inode = ROOT_INODE;
+        for name in components(path) {
+            if self.file_type(inode) != FileType::Directory {
+                return Err(FsError::NotADirectory);
+            }
+            inode = self.lookup(inode, name)?.ok_or(FsError::NotFound)?.inode;
+        }
+        Ok(inode)
+    }
+
+    fn resolve_dir(&mut self, path: &str) -> Result<u32, FsError> {
+        let dir = self.resolve(path)?;
+        if self.file_type(dir) != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(dir)
+    }
+
+    /// The directory holding the last component of `path` and that component
+    fn parent<'p>(&mut self, path: &'p str) -> Result<(u32, &'p str), FsError> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if !valid_name(name) {
+            return Err(FsError::InvalidName);
+        }
+        Ok((self.resolve_dir(parent)?, name))
+    }
+
+    fn add_node(&mut self, path: &str, file_type: FileType) -> Result<u32, FsError> {
+        let (parent, name) = self.parent(path)?;
+        if self.lookup(parent, name)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        let inode = self.allocate_inode(file_type)?;
+        if let Err(e) = self.add_entry(parent, inode, name) {
+            self.inodes[inode as usize] = Inode::default();
+            return Err(e);
+        }
+        Ok(inode)
+    }
+
+    fn remove_node(&mut self, path: &str, file_type: FileType) -> Result<(), FsError> {
+        let (parent, name) = self.parent(path)?;
+        let entry = self.lookup(parent, name)?.ok_or(FsError::NotFound)?;
+        match (self.file_type(entry.inode), file_type) {
+            (FileType::Directory, FileType::File) => return Err(FsError::IsADirectory),
+            (FileType::File, FileType::Directory) => return Err(FsError::NotADirectory),
+            (FileType::Directory, _) if !self.entries(entry.inode)?.is_empty() => return Err(FsError::DirectoryNotEmpty),
+            _ if self.inodes[entry.inode as usize].flags.contains(FileFlags::READ_ONLY) => return Err(FsError::ReadOnly),
+            _ => {},
+        }
+
+        self.write_all_at(parent, entry.offset, &[0u8; DIR_ENTRY_SIZE])?;
+        let Inode { direct, indirect, double_indirect, .. } = core::mem::take(&mut self.inodes[entry.inode as usize]);
+        self.mark_dirty(entry.inode);
+        for block in direct {
+            self.free_tree(block, 0)?;
+        }
+        self.free_tree(indirect, 1)?;
+        self.free_tree(double_indirect, 2)
+    }
+
+    fn allocate_inode(&mut self, file_type: FileType) -> Result<u32, FsError> {
+        let free = self.inodes.iter().skip(ROOT_INODE as usize + 1).position(|inode| inode.file_type.is_none());
+        let inode = match free {
+            Some(slot) => slot + ROOT_INODE as usize + 1,
+            None if self.inodes.len() <= u32::MAX as usize => {
+                self.inodes.push(Inode::default());
+                self.inodes.len() - 1
+            },
+            None => return Err(FsError::NoFreeInode),
+        };
+        let now = (self.clock)();
+        self.inodes[inode] = Inode { created: now, modified: now, ..Inode::new(file_type) };
+        self.mark_dirty(inode as u32);
+        Ok(inode as u32)
+    }
+
+    /// The raw entries of a directory, free ones included
+    fn dir_data(&mut self, dir: u32) -> Result<Vec<u8>, FsError> {
+        let size = self.inodes[dir as usize].size;
+        if !size.is_multiple_of(DIR_ENTRY_SIZE as u64) || size > self.superblock.block_count * BLOCK_SIZE as u64 {
+            return Err(FsError::Corrupted);
+        }
+        let mut data = vec![0u8; size as usize];
+        self.read_exact_at(dir, 0, &mut data)?;
+        Ok(data)
+    }
+
+    fn entries(&mut self, dir: u32) -> Result<Vec<DirEntry>, FsError> {
+        let data = self.dir_data(dir)?;
+        let mut entries = Vec::new();
+        for (i, bytes) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+            let inode = read_u32_le(bytes, 0);
+            if inode == 0 {
+                continue;
+            }
+            let name = bytes[DIR_ENTRY_NAME_OFFSET..].get(..bytes[4] as usize)
+                .and_then(|name| core::str::from_utf8(name).ok())
+                .filter(|name| valid_name(name))
+                .ok_or(FsError::Corrupted)?;
+            let valid_inode = inode > ROOT_INODE && self.inodes.get(inode as usize).is_some_and(|inode| inode.file_type.is_some());
+            if !valid_inode {
+                return Err(FsError::Corrupted);
+            }
+            entries.push(DirEntry { offset: (i * DIR_ENTRY_SIZE) as u64, inode, name: String::from(name) });
+        }
+        Ok(entries)
+    }
+
+    fn lookup(&mut self, dir: u32, name: &str) -> Result<Option<DirEntry>, FsError> {
+        Ok(self.entries(dir)?.into_iter().find(|entry| entry.name == name))
+    }
+
+    /// Puts the entry in the first free slot, or at the end
+    fn add_entry(&mut self, dir: u32, inode: u32, name: &str) -> Result<(), FsError> {
+        let data = self.dir_data(dir)?;
+        let offset = data.chunks_exact(DIR_ENTRY_SIZE)
+            .position(|bytes| read_u32_le(bytes, 0) == 0)
+            .map_or(data.len(), |slot| slot * DIR_ENTRY_SIZE);
+
+        let mut entry = [0u8; DIR_ENTRY_SIZE];
+        entry[0..4].copy_from_slice(&inode.to_le_bytes());
+        entry[4] = name.len() as u8;
+        entry[DIR_ENTRY_NAME_OFFSET..DIR_ENTRY_NAME_OFFSET + name.len()].copy_from_slice(name.as_bytes());
+        self.write_all_at(dir, offset as u64, &entry)
+    }
+
+    fn walk_dir(&mut self, dir: u32, prefix: &str, found: &mut Vec<FileInfo>, visited: &mut BTreeSet<u32>) -> Result<(), FsError> {
+        // a directory reachable twice is a loop on a corrupted image
+        if !visited.insert(dir) {
+            return Err(FsError::Corrupted);
+        }
+        for entry in self.entries(dir)? {
+            let path = format!("{}{}", prefix, entry.name);
+            found.push(self.info(entry.inode, path.clone()));
+            if self.file_type(entry.inode) == FileType::Directory {
+                self.walk_dir(entry.inode, &format!("{}/", path), found, visited)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads from `position` up to the end of its block or of the file, returns how many bytes
+    fn read_at(&mut self, inode: u32, position: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let size = self.inodes[inode as usize].size;
+        if position >= size || buffer.is_empty() {
+            return Ok(0);
+        }
+        let offset = (position % BLOCK_SIZE as u64) as usize;
+        let len = buffer.len().min(BLOCK_SIZE - offset).min((size - position).min(BLOCK_SIZE as u64) as usize);
+
+        match self.map(inode, position / BLOCK_SIZE as u64, false)? {
+            0 => buffer[..len].fill(0),
+            block => {
+                self.device.seek(SeekFrom::Start(block as u64 * BLOCK_SIZE as u64 + offset as u64))?;
+                self.device.read_exact(&mut buffer[..len])?;
+            },
+        }
+        Ok(len)
+    }
+
+    /// Writes at `position` up to the end of its block, returns how many bytes
+    fn write_at(&mut self, inode: u32, position: u64, data: &[u8]) -> Result<usize, FsError> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let offset = (position % BLOCK_SIZE as u64) as usize;
+        let len = data.len().min(BLOCK_SIZE - offset);
+
+        let block = self.map(inode, position / BLOCK_SIZE as u64, true)?;
+        self.device.seek(SeekFrom::Start(block as u64 * BLOCK_SIZE as u64 + offset as u64))?;
+        self.device.write_all(&data[..len])?;
+
+        let end = position + len as u64;
+        if end > self.inodes[inode as usize].size {
+            self.inodes[inode as usize].size = end;
+            self.mark_dirty(inode);
+        }
+        if inode != TABLE_INODE {
+            self.inodes[inode as usize].modified = (self.clock)();
+            self.mark_dirty(inode);
+        }
+        Ok(len)
+    }
+
+    fn read_exact_at(&mut self, inode: u32, mut position: u64, mut buffer: &mut [u8]) -> Result<(), FsError> {
+        while !buffer.is_empty() {
+            match self.read_at(inode, position, buffer)? {
+                0 => return Err(FsError::Io(IoError::UnexpectedEof)),
+                n => {
+                    position += n as u64;
+                    buffer = &mut buffer[n..];
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&mut self, inode: u32, mut position: u64, mut data: &[u8]) -> Result<(), FsError> {
+        while !data.is_empty() {
+            let n = self.write_at(inode, position, data)?;
+            position += n as u64;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    fn is_allocated(&self, block: u64) -> bool {
+        self.bitmap[block as usize / 8] & (1 << (block % 8)) != 0
+    }
+
+    /// A zeroed data block
+    fn allocate_block(&mut self) -> Result<u32, FsError> {
+        let block = (self.superblock.data_start..self.superblock.block_count)
+            .find(|&block| !self.is_allocated(block))
+            .ok_or(FsError::NoSpace)?;
+        write_blocks(&mut self.device, block, &[0u8; BLOCK_SIZE])?;
+        self.bitmap[block as usize / 8] |= 1 << (block % 8);
+        self.superblock.free_blocks -= 1;
+        self.dirty = true;
+        Ok(block as u32)
+    }
+
+    /// `block` if it can hold data, 0 stays a hole
+    fn check_block(&self, block: u32) -> Result<u32, FsError> {
+        let valid = (self.superblock.data_start..self.superblock.block_count).contains(&(block as u64));
+        if block != 0 && !valid {
+            return Err(FsError::Corrupted);
+        }
+        Ok(block)
+    }
+
+    /// `block`, or a new block in place of a hole if `allocate`
+    fn fill_hole(&mut self, block: u32, allocate: bool) -> Result<u32, FsError> {
+        match block {
+            0 if allocate => self.allocate_block(),
+            block => self.check_block(block),
+        }
+    }
+
+    /// The pointer of `inode` selected by `pointer`, filled in if `allocate`
+    fn root<F>(&mut self, inode: u32, pointer: F, allocate: bool) -> Result<u32, FsError>
+    where
+        F: Fn(&mut Inode) -> &mut u32,
+    {
+        let block = *pointer(&mut self.inodes[inode as usize]);
+        let new = self.fill_hole(block, allocate)?;
+        if new != block {
+            *pointer(&mut self.inodes[inode as usize]) = new;
+            self.mark_dirty(inode);
+        }
+        Ok(new)
+    }
+
+    /// Entry `slot` of the pointer block `block`, filled in if `allocate`
+    fn pointer(&mut self, block: u32, slot: usize, allocate: bool) -> Result<u32, FsError> {
+        if block == 0 {
+            return Ok(0);
+        }
+        let offset = block as u64 * BLOCK_SIZE as u64 + slot as u64 * 4;
+        let mut bytes = [0u8; 4];
+        self.device.seek(SeekFrom::Start(offset))?;
+        self.device.read_exact(&mut bytes)?;
+        let pointer = u32::from_le_bytes(bytes);
+        let new = self.fill_hole(pointer, allocate)?;
+        if new != pointer {
+            self.device.seek(SeekFrom::Start(offset))?;
+            self.device.write_all(&new.to_le_bytes())?;
+        }
+        Ok(new)
+    }
+
+    /// The block holding block `index` of the file, 0 for a hole unless `allocate`
+    fn map(&mut self, inode: u32, index: u64, allocate: bool) -> Result<u32, FsError> {
+        let mut index = index as usize;
+        if index < DIRECT_POINTERS {
+            return self.root(inode, |inode| &mut inode.direct[index], allocate);
+        }
+
+        index -= DIRECT_POINTERS;
+        if index < POINTERS_PER_BLOCK {
+            let indirect = self.root(inode, |inode| &mut inode.indirect, allocate)?;
+            return self.pointer(indirect, index, allocate);
+        }
+
+        index -= POINTERS_PER_BLOCK;
+        if index < POINTERS_PER_BLOCK * POINTERS_PER_BLOCK {
+            let double_indirect = self.root(inode, |inode| &mut inode.double_indirect, allocate)?;
+            let indirect = self.pointer(double_indirect, index / POINTERS_PER_BLOCK, allocate)?;
+            return self.pointer(indirect, index % POINTERS_PER_BLOCK, allocate);
+        }
+        Err(FsError::FileTooLarge)
+    }
+
+    /// Frees `block` and, for pointer blocks `depth` levels above the data, the blocks below it
+    fn free_tree(&mut self, block: u32, depth: usize) -> Result<(), FsError> {
+        if self.check_block(block)? == 0 {
+            return Ok(());
+        }
+        if depth > 0 {
+            let mut pointers = vec![0u8; BLOCK_SIZE];
+            read_blocks(&mut self.device, block as u64, &mut pointers)?;
+            for slot in 0..POINTERS_PER_BLOCK {
+                self.free_tree(read_u32_le(&pointers, slot * 4), depth - 1)?;
+            }
+        }
+        if self.is_allocated(block as u64) {
+            self.bitmap[block as usize / 8] &= !(1 << (block % 8));
+            self.superblock.free_blocks += 1;
+            self.dirty = true;
+        }
+        Ok(())
+    }
+}
+
+fn read_blocks<D: Read + Seek>(device: &mut D, block: u64, buffer: &mut [u8]) -> Result<(), IoError> {
+    device.seek(SeekFrom::Start(block * BLOCK_SIZE as u64))?;
+    device.read_exact(buffer)
+}
+
+fn write_blocks<D: Write + Seek>(device: &mut D, block: u64, data: &[u8]) -> Result<(), IoError> {
+    device.seek(SeekFrom::Start(block * BLOCK_SIZE as u64))?;
+    device.write_all(data)
+}
+
+fn no_clock() -> u64 {
+    0
+}
+
+/// An open file. Writing past the end extends it, seeking past the end and writing leaves a
+/// hole. The new size is persisted by `flush`.
+pub struct File<'a, D> {
+    fs: &'a mut FerrFs<D>,
+    inode: u32,
+    position: u64,
+}
+
+impl<D> File<'_, D> {
+    pub fn size(&self) -> u64 {
+        self.fs.inodes[self.inode as usize].size
+    }
+}
+
+impl<D: Read + Write + Seek> Read for File<'_, D> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, IoError> {
+        let len = self.fs.read_at(self.inode, self.position, buffer)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<D: Read + Write + Seek> Write for File<'_, D> {
+    fn write(&mut self, data: &[u8]) -> Result<usize, IoError> {
+        if self.fs.inodes[self.inode as usize].flags.contains(FileFlags::READ_ONLY) {
+            return Err(IoError::ReadOnly);
+        }
+        let len = self.fs.write_at(self.inode, self.position, data)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(self.fs.flush()?)
+    }
+}
+
+impl<D: Read + Write + Seek> Seek for File<'_, D> {
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, IoError> {
+        self.position = match position {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => seek_offset(self.size(), offset)?,
+            SeekFrom::Current(offset) => seek_offset(self.position, offset)?,
+        };
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+use crate::io::Cursor;
+
+#[cfg(test)]
+fn test_image(blocks: usize) -> FerrFs<Cursor<Vec<u8>>> {
+    FerrFs::format(Cursor::new(vec![0u8; blocks * BLOCK_SIZE])).unwrap()
+}
+
+#[test_case]
+fn ferr_fs_files_survive_remount() {
+    let mut fs = test_image(64);
+    let free = fs.free_blocks();
+    // past the direct blocks into the indirect one
+    let data: Vec<u8> = (0..12 * BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+    fs.create("big").unwrap().write_all(&data).unwrap();
+    fs.create("/small").unwrap().write_all(b"hello").unwrap();
+    assert_eq!(fs.create("small").err(), Some(FsError::AlreadyExists));
+    fs.flush().unwrap();
+
+    let mut fs = FerrFs::mount(fs.into_inner()).unwrap();
+    let files: Vec<(String, FileType, u64)> = fs.read_dir("/").unwrap().into_iter().map(|info| (info.name, info.file_type, info.size)).collect();
+    assert_eq!(files, [(String::from("big"), FileType::File, data.len() as u64), (String::from("small"), FileType::File, 5)]);
+    let mut read_back = vec![0u8; data.len()];
+    let mut file = fs.open("big").unwrap();
+    file.read_exact(&mut read_back).unwrap();
+    assert_eq!(read_back, data);
+    assert_eq!(file.read(&mut read_back), Ok(0));
+
+    let mut file = fs.open("small").unwrap();
+    let mut word = [0u8; 3];
+    file.seek(SeekFrom::Start(2)).unwrap();
+    file.read_exact(&mut word).unwrap();
+    assert_eq!(&word, b"llo");
+
+    fs.delete("big").unwrap();
+    fs.delete("small").unwrap();
+    // the root directory and the inode table keep theirs
+    assert_eq!(fs.free_blocks(), free - 1);
+    assert_eq!(fs.open("big").err(), Some(FsError::NotFound));
+}
+
+#[test_case]
+fn ferr_fs_holes_read_as_zeros() {
+    let mut fs = test_image(64);
+    let free = fs.free_blocks();
+    fs.mkdir("dir").unwrap();
+    let mut file = fs.create("dir/sparse").unwrap();
+    file.seek(SeekFrom::Start(3 * BLOCK_SIZE as u64)).unwrap();
+    file.write_all(b"end").unwrap();
+    assert_eq!(file.size(), 3 * BLOCK_SIZE as u64 + 3);
+
+    let mut start = [0xffu8; 16];
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_exact(&mut start).unwrap();
+    assert_eq!(start, [0u8; 16]);
+    // the entry blocks of the root and of the directory, then only the block written to
+    assert_eq!(fs.free_blocks(), free - 3);
+}
+
+#[test_case]
+fn ferr_fs_directories() {
+    let mut fs = test_image(64);
+    fs.mkdir("/logs").unwrap();
+    fs.create("/logs/boot.txt").unwrap().write_all(b"booted").unwrap();
+    fs.mkdir("/logs/old/").unwrap();
+    fs.create("/logs/old/0.txt").unwrap();
+    assert_eq!(fs.mkdir("/logs").err(), Some(FsError::AlreadyExists));
+    assert_eq!(fs.create("/missing/file").err(), Some(FsError::NotFound));
+    assert_eq!(fs.create("/logs/boot.txt/file").err(), Some(FsError::NotADirectory));
+    assert_eq!(fs.open("/logs").err(), Some(FsError::IsADirectory));
+    assert_eq!(fs.delete("/logs").err(), Some(FsError::IsADirectory));
+    assert_eq!(fs.rmdir("/logs").err(), Some(FsError::DirectoryNotEmpty));
+    assert_eq!(fs.rmdir("/").err(), Some(FsError::InvalidName));
+    assert_eq!(fs.mkdir("/logs/..").err(), Some(FsError::InvalidName));
+    fs.flush().unwrap();
+
+    let mut fs = FerrFs::mount(fs.into_inner()).unwrap();
+    let names: Vec<(String, FileType)> = fs.walk("/").unwrap().into_iter().map(|info| (info.name, info.file_type)).collect();
+    assert_eq!(names, [(String::from("logs"), FileType::Directory), (String::from("logs/boot.txt"), FileType::File),
+        (String::from("logs/old"), FileType::Directory), (String::from("logs/old/0.txt"), FileType::File)]);
+    let mut text = [0u8; 6];
+    fs.open("logs/boot.txt").unwrap().read_exact(&mut text).unwrap();
+    assert_eq!(&text, b"booted");
+
+    fs.delete("/logs/old/0.txt").unwrap();
+    fs.rmdir("/logs/old").unwrap();
+    assert_eq!(fs.read_dir("/logs").unwrap().len(), 1);
+    // the freed slot is reused
+    fs.create("/logs/new.txt").unwrap();
+    let logs = fs.resolve("/logs").unwrap();
+    assert_eq!(fs.inodes[logs as usize].size, 2 * DIR_ENTRY_SIZE as u64);
+}
+
+#[cfg(test)]
+static TEST_CLOCK: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
+
+#[test_case]
+fn ferr_fs_times_and_flags() {
+    use core::sync::atomic::Ordering;
+    let mut fs = test_image(64).with_clock(|| TEST_CLOCK.load(Ordering::Relaxed));
+    TEST_CLOCK.store(1000, Ordering::Relaxed);
+    fs.mkdir("etc").unwrap();
+    fs.create("etc/motd").unwrap();
+    TEST_CLOCK.store(2000, Ordering::Relaxed);
+    fs.open("etc/motd").unwrap().write_all(b"hi").unwrap();
+    let motd = fs.metadata("etc/motd").unwrap();
+    assert_eq!((motd.created, motd.modified), (1000, 2000));
+    assert_eq!(fs.metadata("etc").unwrap().modified, 1000);
+    TEST_CLOCK.store(3000, Ordering::Relaxed);
+    fs.create("etc/issue").unwrap();
+    assert_eq!(fs.metadata("etc").unwrap().modified, 3000);
+
+    fs.set_flags("etc/motd", FileFlags::READ_ONLY | FileFlags::SYSTEM).unwrap();
+    fs.set_modified("etc", 42).unwrap();
+    fs.flush().unwrap();
+    let mut fs = FerrFs::mount(fs.into_inner()).unwrap();
+    assert_eq!(fs.metadata("etc/motd").unwrap().flags, FileFlags::READ_ONLY | FileFlags::SYSTEM);
+    assert_eq!(fs.metadata("etc").unwrap().modified, 42);
+    assert_eq!(fs.open("etc/motd").unwrap().write(b"x"), Err(IoError::ReadOnly));
+    assert_eq!(fs.delete("etc/motd").err(), Some(FsError::ReadOnly));
+    fs.set_flags("etc/motd", FileFlags::empty()).unwrap();
+    fs.delete("etc/motd").unwrap();
+}
+
+#[test_case]
+fn ferr_fs_inode_table_grows() {
+    let mut fs = test_image(64);
+    for i in 0..200 {
+        fs.create(&format!("file{}", i)).unwrap();
+    }
+    fs.flush().unwrap();
+    let mut fs = FerrFs::mount(fs.into_inner()).unwrap();
+    assert_eq!(fs.read_dir("/").unwrap().len(), 200);
+    fs.open("file199").unwrap();
+}
+
+#[test_case]
+fn ferr_fs_rejects_bad_images() {
+    assert_eq!(FerrFs::mount(Cursor::new(vec![0u8; 16 * BLOCK_SIZE])).err(), Some(FsError::NotFormatted));
+    assert_eq!(FerrFs::format(Cursor::new(vec![0u8; 2 * BLOCK_SIZE])).err(), Some(FsError::DeviceTooSmall));
+
+    let mut fs = test_image(16);
+    fs.create("x").unwrap().write_all(b"x").unwrap();
+    fs.flush().unwrap();
+    let mut image = fs.into_inner().into_inner();
+    image[8] ^= 1;
+    assert_eq!(FerrFs::mount(Cursor::new(image.clone())).err(), Some(FsError::BadChecksum));
+    image[8] ^= 1;
+
+    // the inode table is block 2, the root directory's entries block 3; point the data of "x",
+    // inode 2, at the bitmap
+    image[2 * BLOCK_SIZE + 2 * INODE_SIZE + 16] = 1;
+    let mut fs = FerrFs::mount(Cursor::new(image)).unwrap();
+    let mut byte = [0u8];
+    assert_eq!(fs.open("x").unwrap().read(&mut byte), Err(IoError::InvalidData));
+}
@@ -1,5 +1,6 @@
-use core::ops::{Deref, DerefMut};
+use core::ops::{Deref, DerefMut, Range};
 use crate::addr::VirtAddr;
+use crate::memprof;
 use crate::page_table::{PageTable, PageTablesAllocator};
 
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -29,10 +30,76 @@ pub struct MemoryMap {
     pub next_free_entry_idx: u64
 }
 
+/// No entry left to split a region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryMapFull;
+
 impl MemoryMap {
     fn next_free_entry_index(&self) -> usize {
         self.next_free_entry_idx as usize
     }
+
+    fn insert(&mut self, index: usize, region: MemoryRegion) -> Result<(), MemoryMapFull> {
+        let len = self.next_free_entry_index();
+        if len == MAX_MEMORY_MAP_SIZE {
+            return Err(MemoryMapFull);
+        }
+        self.entries.copy_within(index..len, index + 1);
+        self.entries[index] = region;
+        self.next_free_entry_idx += 1;
+        Ok(())
+    }
+
+    /// Marks the free frames in the `page_count` pages at `addr` (page aligned) as in use,
+    /// splitting the regions they are part of.
+    pub fn mark_in_use(&mut self, addr: u64, page_count: usize) -> Result<(), MemoryMapFull> {
+        debug_assert!(addr % 4096 == 0);
+        let end = addr + 4096 * page_count as u64;
+
+        let mut index = 0;
+        while index < self.next_free_entry_index() {
+            let region = self.entries[index];
+            let region_end = region.addr + 4096 * region.page_count as u64;
+            if region.ty != MemoryType::Free || region_end <= addr || region.addr >= end {
+                index += 1;
+                continue;
+            }
+
+            // split off the free part in front, the rest is handled in the next iteration
+            if region.addr < addr {
+                let before = ((addr - region.addr) / 4096) as usize;
+                self.entries[index].page_count = before;
+                self.insert(index + 1, MemoryRegion { ty: MemoryType::Free, addr, page_count: region.page_count - before })?;
+                index += 1;
+                continue;
+            }
+
+            if region_end > end {
+                let inside = ((end - region.addr) / 4096) as usize;
+                self.entries[index].page_count = inside;
+                self.insert(index + 1, MemoryRegion { ty: MemoryType::Free, addr: end, page_count: region.page_count - inside })?;
+            }
+            self.entries[index].ty = MemoryType::InUse;
+            index += 1;
+        }
+        Ok(())
+    }
+
+    /// Marks the first `frames` free frames as in use: the ones a `FrameAllocator` on this map
+    /// has taken when its `next` is `frames`.
+    pub fn mark_taken(&mut self, mut frames: usize) -> Result<(), MemoryMapFull> {
+        let mut index = 0;
+        while frames > 0 && index < self.next_free_entry_index() {
+            let region = self.entries[index];
+            if region.ty == MemoryType::Free {
+                let taken = frames.min(region.page_count);
+                self.mark_in_use(region.addr, taken)?;
+                frames -= taken;
+            }
+            index += 1;
+        }
+        Ok(())
+    }
 }
 
 impl Deref for MemoryMap {
@@ -50,41 +117,434 @@ impl DerefMut for MemoryMap {
     }
 }
 
+/// Physical memory zones, for devices which can only address low memory. A zone includes the
+/// zones below it: a DMA32 request can be served from the DMA zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below 16 MiB, ISA DMA
+    Dma,
+    /// Below 4 GiB, devices with 32-bit DMA addresses
+    Dma32,
+    Normal,
+}
+
+impl Zone {
+    pub const ALL: [Zone; 3] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+    /// End of the zone, exclusive
+    pub const fn limit(&self) -> u64 {
+        match self {
+            Zone::Dma => 16 * 1024 * 1024,
+            Zone::Dma32 => 4 * 1024 * 1024 * 1024,
+            Zone::Normal => u64::MAX,
+        }
+    }
+
+    /// The lowest zone containing `addr`
+    pub fn of(addr: u64) -> Zone {
+        Zone::ALL.into_iter().find(|zone| addr < zone.limit()).unwrap_or(Zone::Normal)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Zone::Dma => "DMA",
+            Zone::Dma32 => "DMA32",
+            Zone::Normal => "Normal",
+        }
+    }
+}
+
+/// Largest block of the buddy allocator is 2^MAX_ORDER frames (4 MiB)
+pub const MAX_ORDER: usize = 10;
+
+const NO_BLOCK: u64 = u64::MAX;
+
+/// Hands out the free frames of the memory map in order and reuses deallocated ones.
+///
+/// Deallocated frames form a singly linked list: each one stores the physical address of the
+/// next free frame in its first 8 bytes, accessed through `mapping_offset`.
+///
+/// Once `reserve_dma_zone` is called, the DMA zone is kept for `allocate_frame_in_zone` and
+/// `alloc_contiguous_in_zone`: its untouched and freed frames go to the buddy allocator, and
+/// other allocations only take them when the rest of memory is gone.
+///
+/// Physically contiguous ranges come from a buddy allocator, which takes untouched memory from
+/// the memory map a max-order block at a time. Its free blocks are linked the same way, one list
+/// per order.
 #[repr(align(4096))]
 pub struct FrameAllocator {
     memory_map: *const MemoryMap,
+    /// Number of frames taken from the memory map so far. Passed from the loader to the kernel,
+    /// frames below it are in use.
     pub next: usize,
-    mapping_offset: u64
+    mapping_offset: u64,
+    /// Position of the next untouched frame: memory map entry and frame index inside it
+    region: usize,
+    region_offset: usize,
+    free_list: Option<u64>,
+    free_frames: usize,
+    free_blocks: [u64; MAX_ORDER + 1],
+    /// Frames below this are kept for zone allocations, 0 if the DMA zone isn't reserved
+    dma_floor: u64,
 }
 
+// The memory map is handed over by the loader and never freed, so the allocator can move
+// between owners (e.g. into a static used by the page fault handler).
+unsafe impl Send for FrameAllocator {}
+
 impl FrameAllocator {
-    pub fn new(memory_map: *const MemoryMap, mapping_offset: u64, next_free_frame: usize) -> Self {
-        FrameAllocator {
+    /// An allocator over the free regions of `memory_map`, the first `next_free_frame` of their
+    /// frames are in use.
+    ///
+    /// # Safety
+    /// `memory_map` must point to a valid memory map which stays mapped and unchanged for as long
+    /// as the allocator is used, and its free frames must be reachable at `mapping_offset`.
+    pub unsafe fn new(memory_map: *const MemoryMap, mapping_offset: u64, next_free_frame: usize) -> Self {
+        let mut allocator = FrameAllocator {
             memory_map,
             next: next_free_frame,
-            mapping_offset
+            mapping_offset,
+            region: 0,
+            region_offset: 0,
+            free_list: None,
+            free_frames: 0,
+            free_blocks: [NO_BLOCK; MAX_ORDER + 1],
+            dma_floor: 0,
+        };
+
+        // skip the frames which are already in use
+        let mut skip = next_free_frame;
+        let regions = unsafe { &*memory_map };
+        while allocator.region < regions.len() {
+            let region = &regions[allocator.region];
+            if region.ty == MemoryType::Free {
+                if skip < region.page_count {
+                    allocator.region_offset = skip;
+                    break;
+                }
+                skip -= region.page_count;
+            }
+            allocator.region += 1;
+        }
+        allocator
+    }
+
+    /// Keeps the DMA zone for the allocations which ask for it, see the type documentation
+    pub fn reserve_dma_zone(&mut self) {
+        self.dma_floor = Zone::Dma.limit();
+    }
+
+    fn next_unused_frame(&mut self) -> Option<u64> {
+        let regions = unsafe { &*self.memory_map };
+        while self.region < regions.len() {
+            let region = &regions[self.region];
+            if region.ty == MemoryType::Free && self.region_offset < region.page_count {
+                let frame = region.addr + 4096 * self.region_offset as u64;
+                self.region_offset += 1;
+                self.next += 1;
+                if frame < self.dma_floor {
+                    self.free_range(frame, 1);
+                    continue;
+                }
+                return Some(frame);
+            }
+            self.region += 1;
+            self.region_offset = 0;
         }
+        None
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = u64> + '_ {
-        unsafe {
-            // get usable regions from memory map
-            let regions = (*self.memory_map).iter();
-            let usable_regions = regions.filter(|r| r.ty == MemoryType::Free);
+    /// Whether the memory map has untouched frames below `limit`
+    fn has_unused_frames_below(&self, limit: u64) -> bool {
+        let regions = unsafe { &*self.memory_map };
+        let mut offset = self.region_offset;
+        for region in regions.iter().skip(self.region) {
+            if region.ty == MemoryType::Free && offset < region.page_count {
+                return region.addr + 4096 * (offset as u64) < limit;
+            }
+            offset = 0;
+        }
+        false
+    }
 
-            // map each region to its address range
-            let addr_ranges = usable_regions.map(|r| r.addr..(r.addr + 4096 * r.page_count as u64));
+    fn pop_free_frame(&mut self) -> Option<u64> {
+        let frame = self.free_list?;
+        let next = unsafe { ((frame + self.mapping_offset) as *const u64).read() };
+        self.free_list = if next == 0 { None } else { Some(next) };
+        self.free_frames -= 1;
+        Some(frame)
+    }
 
-            // transform to an iterator of frame start addresses
-            addr_ranges.flat_map(|r| r.step_by(4096))
+    /// Unlinks the first deallocated frame below `limit`
+    fn pop_free_frame_below(&mut self, limit: u64) -> Option<u64> {
+        let mut prev = None;
+        let mut current = self.free_list;
+        while let Some(frame) = current {
+            let next = self.read_link(frame);
+            if frame < limit {
+                match prev {
+                    None => self.free_list = if next == 0 { None } else { Some(next) },
+                    Some(prev) => self.write_link(prev, next),
+                }
+                self.free_frames -= 1;
+                return Some(frame);
+            }
+            prev = Some(frame);
+            current = if next == 0 { None } else { Some(next) };
         }
+        None
     }
 
     pub fn allocate_frame(&mut self) -> Option<u64> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
+        let frame = self.pop_free_frame()
+            .or_else(|| self.next_unused_frame())
+            .or_else(|| self.allocate_block_in_zone(0, Zone::Normal));
+
+        if memprof::is_enabled() && frame.is_some() {
+            memprof::FRAME_PROFILE.lock().record_alloc(4096, memprof::CallSite::capture());
+        }
+        frame
+    }
+
+    /// Returns `frame` to the allocator.
+    ///
+    /// # Safety
+    /// `frame` must have been returned by `allocate_frame` and must not be used afterwards.
+    pub unsafe fn deallocate_frame(&mut self, frame: u64) {
+        debug_assert!(frame % 4096 == 0 && frame != 0);
+
+        if frame < self.dma_floor {
+            self.free_range(frame, 1);
+        } else {
+            ((frame + self.mapping_offset) as *mut u64).write(self.free_list.unwrap_or(0));
+            self.free_list = Some(frame);
+            self.free_frames += 1;
+        }
+
+        if memprof::is_enabled() {
+            memprof::FRAME_PROFILE.lock().record_free(4096);
+        }
+    }
+
+    /// Allocates a frame inside `zone`, e.g. `Zone::Dma32` for a device with 32-bit DMA addresses
+    pub fn allocate_frame_in_zone(&mut self, zone: Zone) -> Option<u64> {
+        if zone == Zone::Normal {
+            return self.allocate_frame();
+        }
+
+        let frame = self.pop_free_frame_below(zone.limit())
+            .or_else(|| self.allocate_block_in_zone(0, zone));
+
+        if memprof::is_enabled() && frame.is_some() {
+            memprof::FRAME_PROFILE.lock().record_alloc(4096, memprof::CallSite::capture());
+        }
         frame
     }
+
+    /// Number of deallocated frames waiting for reuse
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of free frames the memory map has in `zone`, not counting the zones below it
+    pub fn zone_pages(&self, zone: Zone) -> usize {
+        let start = Zone::ALL.iter().take_while(|lower| **lower != zone).map(|lower| lower.limit()).max().unwrap_or(0);
+        let end = zone.limit();
+        let regions = unsafe { &*self.memory_map };
+        regions.iter()
+            .filter(|region| region.ty == MemoryType::Free)
+            .map(|region| {
+                let region_end = region.addr + 4096 * region.page_count as u64;
+                (region_end.min(end).saturating_sub(region.addr.max(start)) / 4096) as usize
+            })
+            .sum()
+    }
+
+    /// Allocates `frames` physically contiguous frames aligned to `align` bytes (a power of two).
+    /// Returns the physical address of the first frame.
+    pub fn alloc_contiguous(&mut self, frames: usize, align: usize) -> Option<u64> {
+        self.alloc_contiguous_in_zone(frames, align, Zone::Normal)
+    }
+
+    /// Same as `alloc_contiguous`, the whole range inside `zone`
+    pub fn alloc_contiguous_in_zone(&mut self, frames: usize, align: usize, zone: Zone) -> Option<u64> {
+        if frames == 0 || !align.is_power_of_two() {
+            return None;
+        }
+
+        let order = order_of(frames).max(order_of(align.div_ceil(4096)));
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let block = self.allocate_block_in_zone(order, zone)?;
+
+        // give back the frames rounding up to the block size added
+        self.free_range(block + 4096 * frames as u64, (1 << order) - frames);
+
+        if memprof::is_enabled() {
+            memprof::FRAME_PROFILE.lock().record_alloc(4096 * frames, memprof::CallSite::capture());
+        }
+        Some(block)
+    }
+
+    /// Returns a range allocated by `alloc_contiguous`.
+    ///
+    /// # Safety
+    /// The range must have been returned by `alloc_contiguous` with the same number of `frames`
+    /// and must not be used afterwards.
+    pub unsafe fn free_contiguous(&mut self, base: u64, frames: usize) {
+        debug_assert!(base % 4096 == 0);
+        self.free_range(base, frames);
+
+        if memprof::is_enabled() {
+            memprof::FRAME_PROFILE.lock().record_free(4096 * frames);
+        }
+    }
+
+    /// Number of free blocks of 2^`order` frames in the buddy allocator
+    pub fn free_block_count(&self, order: usize) -> usize {
+        let mut count = 0;
+        let mut block = self.free_blocks[order];
+        while block != NO_BLOCK {
+            count += 1;
+            block = self.read_link(block);
+        }
+        count
+    }
+
+    fn read_link(&self, block: u64) -> u64 {
+        unsafe { ((block + self.mapping_offset) as *const u64).read() }
+    }
+
+    fn write_link(&mut self, block: u64, next: u64) {
+        unsafe { ((block + self.mapping_offset) as *mut u64).write(next) }
+    }
+
+    fn push_block(&mut self, block: u64, order: usize) {
+        self.write_link(block, self.free_blocks[order]);
+        self.free_blocks[order] = block;
+    }
+
+    /// Unlinks `block` from the free list of `order`, returns false if it isn't there.
+    fn remove_block(&mut self, block: u64, order: usize) -> bool {
+        if self.free_blocks[order] == block {
+            self.free_blocks[order] = self.read_link(block);
+            return true;
+        }
+
+        let mut prev = self.free_blocks[order];
+        while prev != NO_BLOCK {
+            let next = self.read_link(prev);
+            if next == block {
+                let after = self.read_link(block);
+                self.write_link(prev, after);
+                return true;
+            }
+            prev = next;
+        }
+        false
+    }
+
+    /// Takes a free block of `order` lying inside `range`, splitting a larger one if needed: its
+    /// lowest part has to be inside.
+    fn allocate_block_in(&mut self, order: usize, range: Range<u64>) -> Option<u64> {
+        for mut current in order..=MAX_ORDER {
+            let mut block = self.free_blocks[current];
+            while block != NO_BLOCK && !(block >= range.start && block + (4096 << order) <= range.end) {
+                block = self.read_link(block);
+            }
+            if block == NO_BLOCK {
+                continue;
+            }
+
+            self.remove_block(block, current);
+            // the upper halves go back to the lower orders
+            while current > order {
+                current -= 1;
+                self.push_block(block + (4096 << current), current);
+            }
+            return Some(block);
+        }
+        None
+    }
+
+    /// Takes a free block of `order` inside `zone`, moving untouched memory to the buddy
+    /// allocator as long as it can help. Zones other than DMA avoid a reserved DMA zone until
+    /// nothing else is left.
+    fn allocate_block_in_zone(&mut self, order: usize, zone: Zone) -> Option<u64> {
+        let floor = if zone == Zone::Dma { 0 } else { self.dma_floor };
+        let limit = zone.limit();
+
+        loop {
+            if let Some(block) = self.allocate_block_in(order, floor..limit) {
+                return Some(block);
+            }
+            // untouched memory above the zone can't help
+            if (zone != Zone::Normal && !self.has_unused_frames_below(limit)) || !self.refill_blocks() {
+                break;
+            }
+        }
+
+        self.allocate_block_in(order, 0..limit)
+    }
+
+    /// Frees a block, merging it with its buddy as long as the buddy is free as well.
+    fn free_block(&mut self, mut block: u64, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = block ^ (4096 << order);
+            if !self.remove_block(buddy, order) {
+                break;
+            }
+            block = block.min(buddy);
+            order += 1;
+        }
+        self.push_block(block, order);
+    }
+
+    /// Frees an arbitrary range as the largest naturally aligned blocks it consists of.
+    fn free_range(&mut self, mut addr: u64, mut frames: usize) {
+        while frames > 0 {
+            let mut order = ((addr / 4096).trailing_zeros() as usize).min(MAX_ORDER);
+            while (1 << order) > frames {
+                order -= 1;
+            }
+            self.free_block(addr, order);
+            addr += 4096 << order;
+            frames -= 1 << order;
+        }
+    }
+
+    /// Moves untouched memory to the buddy allocator: a max-order block if the current region has
+    /// one left, the rest of the region otherwise. Returns false when the memory map is exhausted.
+    fn refill_blocks(&mut self) -> bool {
+        let regions = unsafe { &*self.memory_map };
+        while self.region < regions.len() {
+            let region = &regions[self.region];
+            if region.ty == MemoryType::Free && self.region_offset < region.page_count {
+                let start = region.addr + 4096 * self.region_offset as u64;
+                let end = region.addr + 4096 * region.page_count as u64;
+
+                let block_size = 4096 << MAX_ORDER;
+                let aligned = start.next_multiple_of(block_size);
+                let taken_end = if aligned + block_size <= end { aligned + block_size } else { end };
+
+                let frames = ((taken_end - start) / 4096) as usize;
+                self.region_offset += frames;
+                self.next += frames;
+                self.free_range(start, frames);
+                return true;
+            }
+            self.region += 1;
+            self.region_offset = 0;
+        }
+        false
+    }
+}
+
+/// Smallest order whose block holds `frames` frames
+fn order_of(frames: usize) -> usize {
+    frames.next_power_of_two().trailing_zeros() as usize
 }
 
 impl PageTablesAllocator for FrameAllocator {
@@ -99,4 +559,152 @@ impl PageTablesAllocator for FrameAllocator {
         page_table[0].clear();
         Ok(&mut page_table[0])
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+const TEST_FRAMES: usize = 8;
+
+/// "Physical" memory for tests: the first `frames` frames of a zeroed static buffer as a single
+/// free region, identity mapped. The buffer is aligned to its size, so the buddy blocks in it are
+/// too. Every call hands out the same buffer and map again.
+#[cfg(test)]
+pub(crate) fn test_memory(frames: usize) -> (&'static mut MemoryMap, FrameAllocator) {
+    #[allow(dead_code)]
+    #[repr(align(32768))]
+    struct Frames([u8; TEST_FRAMES * 4096]);
+    static mut FRAMES: Frames = Frames([0; TEST_FRAMES * 4096]);
+    static mut MAP: MemoryMap = MemoryMap {
+        entries: [MemoryRegion { ty: MemoryType::Reserved, addr: 0, page_count: 0 }; MAX_MEMORY_MAP_SIZE],
+        next_free_entry_idx: 0
+    };
+
+    assert!(frames <= TEST_FRAMES);
+    let base = core::ptr::addr_of_mut!(FRAMES) as u64;
+    let map = unsafe {
+        core::ptr::write_bytes(base as *mut u8, 0, frames * 4096);
+        &mut *core::ptr::addr_of_mut!(MAP)
+    };
+    map.entries[0] = MemoryRegion { ty: MemoryType::Free, addr: base, page_count: frames };
+    map.next_free_entry_idx = 1;
+
+    let allocator = unsafe { FrameAllocator::new(map, 0, 0) };
+    (map, allocator)
+}
+
+#[test_case]
+fn allocate_and_reuse_frames_test() {
+    let (map, _) = test_memory(6);
+    let base = map[0].addr;
+    map.mark_in_use(base + 2 * 4096, 1).unwrap();
+
+    // the first frame was handed out by the loader
+    let mut allocator = unsafe { FrameAllocator::new(map, 0, 1) };
+    assert_eq!(Some(base + 4096), allocator.allocate_frame());
+    assert_eq!(Some(base + 3 * 4096), allocator.allocate_frame());
+    assert_eq!(Some(base + 4 * 4096), allocator.allocate_frame());
+
+    unsafe {
+        allocator.deallocate_frame(base + 4096);
+        allocator.deallocate_frame(base + 3 * 4096);
+    }
+    assert_eq!(2, allocator.free_frames());
+    assert_eq!(Some(base + 3 * 4096), allocator.allocate_frame());
+    assert_eq!(Some(base + 4096), allocator.allocate_frame());
+
+    assert_eq!(Some(base + 5 * 4096), allocator.allocate_frame());
+    assert_eq!(None, allocator.allocate_frame());
+    assert_eq!(5, allocator.next);
+}
+
+#[test_case]
+fn buddy_split_and_merge_test() {
+    // a single naturally aligned 8 frame region
+    let (map, mut allocator) = test_memory(8);
+    let base = map[0].addr;
+    let frame = |i: u64| base + i * 4096;
+
+    // the region becomes one order 3 block, split down to a single frame
+    assert_eq!(Some(frame(0)), allocator.alloc_contiguous(1, 4096));
+    assert_eq!([1, 1, 1, 0], [0, 1, 2, 3].map(|o| allocator.free_block_count(o)));
+
+    assert_eq!(Some(frame(2)), allocator.alloc_contiguous(2, 8192));
+
+    // 3 frames take an order 2 block, the last frame is given back
+    assert_eq!(Some(frame(4)), allocator.alloc_contiguous(3, 4096));
+    assert_eq!([2, 0, 0, 0], [0, 1, 2, 3].map(|o| allocator.free_block_count(o)));
+
+    assert_eq!(None, allocator.alloc_contiguous(2, 4096));
+
+    // freeing everything merges the buddies back into a single block
+    unsafe {
+        allocator.free_contiguous(frame(4), 3);
+        assert_eq!([1, 0, 1, 0], [0, 1, 2, 3].map(|o| allocator.free_block_count(o)));
+        allocator.free_contiguous(frame(2), 2);
+        allocator.free_contiguous(frame(0), 1);
+    }
+    assert_eq!([0, 0, 0, 1], [0, 1, 2, 3].map(|o| allocator.free_block_count(o)));
+
+    // alignment larger than the size
+    assert_eq!(Some(frame(0)), allocator.alloc_contiguous(1, 4096));
+    assert_eq!(Some(frame(4)), allocator.alloc_contiguous(1, 4 * 4096));
+}
+
+#[test_case]
+fn mark_in_use_test() {
+    let mut map = MemoryMap {
+        entries: [MemoryRegion { ty: MemoryType::Reserved, addr: 0, page_count: 0 }; MAX_MEMORY_MAP_SIZE],
+        next_free_entry_idx: 2
+    };
+    map.entries[0] = MemoryRegion { ty: MemoryType::Free, addr: 0x10000, page_count: 8 };
+    map.entries[1] = MemoryRegion { ty: MemoryType::Reserved, addr: 0x18000, page_count: 2 };
+
+    // the middle of a free region, and a range running into a reserved one
+    map.mark_in_use(0x12000, 2).unwrap();
+    map.mark_in_use(0x17000, 2).unwrap();
+    let regions: [(MemoryType, u64, usize); 5] = core::array::from_fn(|i| (map[i].ty, map[i].addr, map[i].page_count));
+    assert_eq!(5, map.len());
+    assert_eq!([
+        (MemoryType::Free, 0x10000, 2),
+        (MemoryType::InUse, 0x12000, 2),
+        (MemoryType::Free, 0x14000, 3),
+        (MemoryType::InUse, 0x17000, 1),
+        (MemoryType::Reserved, 0x18000, 2),
+    ], regions);
+
+    // what an allocator continuing after 3 frames would have handed out
+    map.mark_taken(3).unwrap();
+    assert_eq!(MemoryType::InUse, map[0].ty);
+    assert_eq!((MemoryType::InUse, 0x14000, 1), (map[2].ty, map[2].addr, map[2].page_count));
+    assert_eq!((MemoryType::Free, 0x15000, 2), (map[3].ty, map[3].addr, map[3].page_count));
+
+    let allocator = unsafe { FrameAllocator::new(&map, 0, 0) };
+    assert_eq!(0, allocator.next);
+}
+
+#[test_case]
+fn dma_zone_test() {
+    // two frames on each side of the DMA zone limit, "mapped" to the buffer
+    let dma_limit = Zone::Dma.limit();
+    let (map, _) = test_memory(4);
+    let offset = map[0].addr - (dma_limit - 2 * 4096);
+    map.entries[0].addr = dma_limit - 2 * 4096;
+
+    let mut allocator = unsafe { FrameAllocator::new(map, offset, 0) };
+    allocator.reserve_dma_zone();
+    assert_eq!([2, 2, 0], Zone::ALL.map(|zone| allocator.zone_pages(zone)));
+    assert_eq!(Zone::Dma32, Zone::of(dma_limit));
+
+    // the DMA zone is skipped by other allocations
+    assert_eq!(Some(dma_limit), allocator.allocate_frame());
+    assert_eq!(Some(dma_limit - 2 * 4096), allocator.allocate_frame_in_zone(Zone::Dma));
+    assert_eq!(Some(dma_limit + 4096), allocator.allocate_frame_in_zone(Zone::Dma32));
+
+    // and only used once nothing else is left
+    assert_eq!(Some(dma_limit - 4096), allocator.allocate_frame());
+    assert_eq!(None, allocator.allocate_frame_in_zone(Zone::Dma));
+
+    // freed DMA frames are kept for the zone
+    unsafe { allocator.deallocate_frame(dma_limit - 4096) };
+    assert_eq!(0, allocator.free_frames());
+    assert_eq!(Some(dma_limit - 4096), allocator.alloc_contiguous_in_zone(1, 4096, Zone::Dma));
+}
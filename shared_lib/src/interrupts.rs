@@ -1,19 +1,24 @@
 use core::arch::asm;
-use crate::bits::get_bits;
+use crate::bits::get_bit;
 
+/// Returns whether the interrupt flag is set (i.e. interrupts are enabled)
 #[inline]
-pub fn without_interrupts<F, R>(f: F) -> R
-    where
-        F: FnOnce() -> R,
-{
+pub fn are_enabled() -> bool {
     let rflags: u64;
 
     unsafe {
         asm!("pushfq; pop {}", out(reg) rflags, options(nomem, preserves_flags));
     }
 
-    // true if the interrupt flag is set (i.e. interrupts are enabled)
-    let saved_intpt_flag = get_bits(rflags, 9..10) == 1;
+    get_bit(rflags, 9)
+}
+
+#[inline]
+pub fn without_interrupts<F, R>(f: F) -> R
+    where
+        F: FnOnce() -> R,
+{
+    let saved_intpt_flag = are_enabled();
 
     // if interrupts are enabled, disable them for now
     if saved_intpt_flag {
@@ -0,0 +1,173 @@
+// Byte stream traits.
+//
+// A small subset of `std::io` for code shared by the loader and the kernel: filesystems read
+// and write their partition through `Read + Write + Seek`, whatever is behind it. `Cursor`
+// provides the traits over memory, for images loaded as a whole and for tests.
+
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoError {
+    /// The device failed the transfer
+    Device,
+    /// The stream ended before the buffer was filled
+    UnexpectedEof,
+    /// Nothing could be written, the stream is full
+    WriteZero,
+    /// Seeking before the start of the stream
+    InvalidSeek,
+    /// The data behind the stream is inconsistent, like a corrupted filesystem
+    InvalidData,
+    /// No space is left for the data
+    StorageFull,
+    /// The file can't be written
+    ReadOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+pub trait Read {
+    /// Reads up to `buffer.len()` bytes, returns how many. Zero means the end of the stream.
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, IoError>;
+
+    fn read_exact(&mut self, mut buffer: &mut [u8]) -> Result<(), IoError> {
+        while !buffer.is_empty() {
+            match self.read(buffer)? {
+                0 => return Err(IoError::UnexpectedEof),
+                n => buffer = &mut buffer[n..],
+            }
+        }
+        Ok(())
+    }
+}
+
+pub trait Write {
+    /// Writes up to `data.len()` bytes, returns how many
+    fn write(&mut self, data: &[u8]) -> Result<usize, IoError>;
+
+    /// Makes the written data durable
+    fn flush(&mut self) -> Result<(), IoError>;
+
+    fn write_all(&mut self, mut data: &[u8]) -> Result<(), IoError> {
+        while !data.is_empty() {
+            match self.write(data)? {
+                0 => return Err(IoError::WriteZero),
+                n => data = &data[n..],
+            }
+        }
+        Ok(())
+    }
+}
+
+pub trait Seek {
+    /// Returns the new position from the start
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, IoError>;
+
+    fn stream_position(&mut self) -> Result<u64, IoError> {
+        self.seek(SeekFrom::Current(0))
+    }
+}
+
+/// `position` moved by `offset`, for `Seek` implementations
+pub fn seek_offset(position: u64, offset: i64) -> Result<u64, IoError> {
+    position.checked_add_signed(offset).ok_or(IoError::InvalidSeek)
+}
+
+/// A stream over memory. Writes past the end of a `Vec` grow it, a slice has a fixed size.
+pub struct Cursor<T> {
+    inner: T,
+    position: u64,
+}
+
+impl<T> Cursor<T> {
+    pub fn new(inner: T) -> Self {
+        Cursor { inner, position: 0 }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl<T: AsRef<[u8]>> Read for Cursor<T> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, IoError> {
+        let data = self.inner.as_ref();
+        let start = (self.position as usize).min(data.len());
+        let len = buffer.len().min(data.len() - start);
+        buffer[..len].copy_from_slice(&data[start..start + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<T: AsRef<[u8]>> Seek for Cursor<T> {
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, IoError> {
+        self.position = match position {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => seek_offset(self.inner.as_ref().len() as u64, offset)?,
+            SeekFrom::Current(offset) => seek_offset(self.position, offset)?,
+        };
+        Ok(self.position)
+    }
+}
+
+impl Write for Cursor<Vec<u8>> {
+    fn write(&mut self, data: &[u8]) -> Result<usize, IoError> {
+        let start = self.position as usize;
+        let end = start + data.len();
+        if end > self.inner.len() {
+            self.inner.resize(end, 0);
+        }
+        self.inner[start..end].copy_from_slice(data);
+        self.position = end as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+
+impl Write for Cursor<&mut [u8]> {
+    fn write(&mut self, data: &[u8]) -> Result<usize, IoError> {
+        let start = (self.position as usize).min(self.inner.len());
+        let len = data.len().min(self.inner.len() - start);
+        self.inner[start..start + len].copy_from_slice(&data[..len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+
+#[test_case]
+fn cursor_test() {
+    let mut cursor = Cursor::new(Vec::new());
+    cursor.write_all(b"hello world").unwrap();
+    assert_eq!(cursor.seek(SeekFrom::End(-5)), Ok(6));
+    let mut word = [0u8; 5];
+    cursor.read_exact(&mut word).unwrap();
+    assert_eq!(&word, b"world");
+    assert_eq!(cursor.read(&mut word), Ok(0));
+    assert_eq!(cursor.seek(SeekFrom::Current(-12)), Err(IoError::InvalidSeek));
+
+    let mut buffer = [0u8; 4];
+    let mut fixed = Cursor::new(&mut buffer[..]);
+    assert_eq!(fixed.write_all(b"hello"), Err(IoError::WriteZero));
+    assert_eq!(&buffer, b"hell");
+}
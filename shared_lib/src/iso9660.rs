@@ -0,0 +1,508 @@
+// ISO 9660 filesystems, read-only.
+//
+// The volume descriptors start at logical sector 16 of 2048 bytes; the primary one gives the
+// logical block size, the path table and the record of the root directory. Directories are
+// extents of records that don't cross sector boundaries: a record starts with its length, and
+// a zero length pads the rest of the sector. Numbers are stored in both byte orders, only the
+// little-endian half is read. Names are `NAME.EXT;1` in upper case, shown without the version
+// and matched case-insensitively.
+//
+// Rock Ridge names replace them when the `SP` entry of the System Use Sharing Protocol is in
+// the first record of the root, and are matched exactly. Their `NM` entries may continue in a
+// `CE` continuation area. The path table lists every directory with its parent by their ISO
+// names, so directories are looked up there unless the volume has Rock Ridge names. A file
+// bigger than 4 GiB is split into several records with the same name, which are joined.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::bytes::{read_u16_le, read_u32_le};
+use crate::io::{IoError, Read, Seek, SeekFrom};
+
+const SECTOR_SIZE: u64 = 2048;
+const FIRST_DESCRIPTOR: u64 = 16;
+const STANDARD_ID: &[u8; 5] = b"CD001";
+const PRIMARY: u8 = 1;
+const TERMINATOR: u8 = 255;
+/// Descriptors read before giving up on a terminator
+const MAX_DESCRIPTORS: u64 = 64;
+const ROOT_RECORD_OFFSET: usize = 156;
+
+const FLAG_DIRECTORY: u8 = 0x02;
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+/// `NM` flags: the name goes on in the next `NM` entry
+const NM_CONTINUE: u8 = 0x01;
+/// `NM` names for `.` and `..`
+const NM_CURRENT_OR_PARENT: u8 = 0x06;
+/// Continuation areas followed for a single record
+const MAX_CONTINUATIONS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsoError {
+    Io(IoError),
+    /// No primary volume descriptor
+    NotIso,
+    /// A record or the path table is inconsistent
+    Corrupted,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+}
+
+impl From<IoError> for IsoError {
+    fn from(e: IoError) -> Self {
+        IsoError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+}
+
+/// A file or directory, from its directory records
+#[derive(Clone)]
+struct Entry {
+    name: String,
+    is_dir: bool,
+    /// First logical block and length of each part
+    extents: Vec<(u32, u32)>,
+}
+
+impl Entry {
+    fn size(&self) -> u64 {
+        self.extents.iter().map(|&(_, len)| len as u64).sum()
+    }
+
+    fn to_dir_entry(&self) -> DirEntry {
+        DirEntry { name: self.name.clone(), is_dir: self.is_dir, size: self.size() }
+    }
+}
+
+/// A directory of the path table, `parent` is an index in the table
+struct PathTableEntry {
+    name: String,
+    extent: u32,
+    parent: usize,
+}
+
+pub struct Iso9660<D> {
+    device: D,
+    block_size: u64,
+    volume_blocks: u32,
+    volume_id: String,
+    root: Entry,
+    path_table: Vec<PathTableEntry>,
+    /// Bytes to skip at the start of the System Use area of every record, from `SP`
+    rock_ridge: Option<usize>,
+}
+
+impl<D: Read + Seek> Iso9660<D> {
+    pub fn mount(mut device: D) -> Result<Iso9660<D>, IsoError> {
+        let mut descriptor = vec![0u8; SECTOR_SIZE as usize];
+        let mut primary = None;
+        for sector in FIRST_DESCRIPTOR..FIRST_DESCRIPTOR + MAX_DESCRIPTORS {
+            device.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+            device.read_exact(&mut descriptor).map_err(|e| match e {
+                IoError::UnexpectedEof => IsoError::NotIso,
+                e => IsoError::Io(e),
+            })?;
+            if &descriptor[1..6] != STANDARD_ID {
+                return Err(IsoError::NotIso);
+            }
+            match descriptor[0] {
+                PRIMARY if primary.is_none() => primary = Some(descriptor.clone()),
+                TERMINATOR => break,
+                _ => {},
+            }
+        }
+        let descriptor = primary.ok_or(IsoError::NotIso)?;
+
+        let block_size = read_u16_le(&descriptor, 128) as u64;
+        if !matches!(block_size, 512 | 1024 | 2048) {
+            return Err(IsoError::Corrupted);
+        }
+        let volume_id = String::from_utf8_lossy(&descriptor[40..72]).trim_end().into();
+        let root_record = &descriptor[ROOT_RECORD_OFFSET..ROOT_RECORD_OFFSET + 34];
+        let mut fs = Iso9660 {
+            device,
+            block_size,
+            volume_blocks: read_u32_le(&descriptor, 80),
+            volume_id,
+            root: Entry {
+                name: String::new(),
+                is_dir: true,
+                extents: vec![(read_u32_le(root_record, 2), read_u32_le(root_record, 10))],
+            },
+            path_table: Vec::new(),
+            rock_ridge: None,
+        };
+        fs.check_extent(fs.root.extents[0])?;
+        fs.rock_ridge = fs.find_sp()?;
+        fs.path_table = fs.read_path_table(read_u32_le(&descriptor, 140), read_u32_le(&descriptor, 132))?;
+        Ok(fs)
+    }
+
+    pub fn volume_id(&self) -> &str {
+        &self.volume_id
+    }
+
+    pub fn has_rock_ridge(&self) -> bool {
+        self.rock_ridge.is_some()
+    }
+
+    /// The root has an empty name
+    pub fn metadata(&mut self, path: &str) -> Result<DirEntry, IsoError> {
+        Ok(self.resolve(path)?.to_dir_entry())
+    }
+
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, IsoError> {
+        let dir = self.resolve(path)?;
+        if !dir.is_dir {
+            return Err(IsoError::NotADirectory);
+        }
+        Ok(self.entries(&dir)?.iter().map(Entry::to_dir_entry).collect())
+    }
+
+    /// Reads from `offset` until the buffer is full or the file ends, returns how many bytes
+    pub fn read(&mut self, path: &str, mut offset: u64, buffer: &mut [u8]) -> Result<usize, IsoError> {
+        let file = self.resolve(path)?;
+        if file.is_dir {
+            return Err(IsoError::IsADirectory);
+        }
+        let mut done = 0;
+        for &(block, len) in &file.extents {
+            let len = len as u64;
+            if offset >= len {
+                offset -= len;
+                continue;
+            }
+            let chunk = (buffer.len() - done).min((len - offset) as usize);
+            self.device.seek(SeekFrom::Start(block as u64 * self.block_size + offset))?;
+            self.device.read_exact(&mut buffer[done..done + chunk])?;
+            done += chunk;
+            offset = 0;
+            if done == buffer.len() {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    fn check_extent(&self, (block, len): (u32, u32)) -> Result<(), IsoError> {
+        let end = block as u64 * self.block_size + len as u64;
+        if end > self.volume_blocks as u64 * self.block_size {
+            return Err(IsoError::Corrupted);
+        }
+        Ok(())
+    }
+
+    fn read_extent(&mut self, (block, len): (u32, u32)) -> Result<Vec<u8>, IsoError> {
+        self.check_extent((block, len))?;
+        let mut data = vec![0u8; len as usize];
+        self.device.seek(SeekFrom::Start(block as u64 * self.block_size))?;
+        self.device.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// The skip length of the `SP` entry in the first record of the root, if there is one
+    fn find_sp(&mut self) -> Result<Option<usize>, IsoError> {
+        let first = self.root.extents[0];
+        let sector = self.read_extent((first.0, first.1.min(SECTOR_SIZE as u32)))?;
+        let Some(record) = records(&sector).next() else {
+            return Err(IsoError::Corrupted);
+        };
+        let system_use = &record[system_use_offset(record)..];
+        let found = system_use.len() >= 7 && &system_use[..2] == b"SP" && system_use[4..6] == [0xbe, 0xef];
+        Ok(found.then_some(system_use.get(6).copied().unwrap_or(0) as usize))
+    }
+
+    fn read_path_table(&mut self, block: u32, len: u32) -> Result<Vec<PathTableEntry>, IsoError> {
+        let data = self.read_extent((block, len))?;
+        let mut table = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let name_len = data[offset] as usize;
+            let name = data.get(offset + 8..offset + 8 + name_len).ok_or(IsoError::Corrupted)?;
+            let parent = read_u16_le(&data, offset + 6) as usize;
+            // parents come first, the root is its own parent
+            if name_len == 0 || parent == 0 || parent > table.len().max(1) {
+                return Err(IsoError::Corrupted);
+            }
+            table.push(PathTableEntry {
+                name: String::from_utf8_lossy(name).into_owned(),
+                extent: read_u32_le(&data, offset + 2),
+                parent: parent - 1,
+            });
+            offset += 8 + name_len.next_multiple_of(2);
+        }
+        if table.first().map(|root| root.extent) != Some(self.root.extents[0].0) {
+            return Err(IsoError::Corrupted);
+        }
+        Ok(table)
+    }
+
+    /// The entries of a directory, without `.` and `..`
+    fn entries(&mut self, dir: &Entry) -> Result<Vec<Entry>, IsoError> {
+        let data = self.read_extent(dir.extents[0])?;
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut continued = false;
+        for record in data.chunks(SECTOR_SIZE as usize).flat_map(records) {
+            let id_len = record[32] as usize;
+            let id = &record[33..33 + id_len];
+            let extent = (read_u32_le(record, 2), read_u32_le(record, 10));
+            let flags = record[25];
+            let is_dir = flags & FLAG_DIRECTORY != 0;
+            self.check_extent(extent)?;
+
+            if continued {
+                // the next part of the previous file
+                entries.last_mut().unwrap().extents.push(extent);
+            } else if id != [0] && id != [1] {
+                let name = match self.rock_ridge_name(record)? {
+                    Some(name) => name,
+                    None => iso_name(id, is_dir),
+                };
+                entries.push(Entry { name, is_dir, extents: vec![extent] });
+            }
+            continued = flags & FLAG_MULTI_EXTENT != 0 && !is_dir;
+        }
+        Ok(entries)
+    }
+
+    /// The `NM` name of a record, following continuation areas
+    fn rock_ridge_name(&mut self, record: &[u8]) -> Result<Option<String>, IsoError> {
+        let Some(skip) = self.rock_ridge else {
+            return Ok(None);
+        };
+        let mut area = record.get(system_use_offset(record) + skip..).unwrap_or(&[]).to_vec();
+        let mut name: Option<Vec<u8>> = None;
+        let mut complete = false;
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut continuation = None;
+            let mut offset = 0;
+            while offset + 4 <= area.len() {
+                let len = area[offset + 2] as usize;
+                if len < 4 || offset + len > area.len() {
+                    break;
+                }
+                let entry = &area[offset..offset + len];
+                match &entry[..2] {
+                    b"NM" if len >= 5 && entry[4] & NM_CURRENT_OR_PARENT == 0 && !complete => {
+                        name.get_or_insert_with(Vec::new).extend_from_slice(&entry[5..]);
+                        complete = entry[4] & NM_CONTINUE == 0;
+                    },
+                    b"CE" if len >= 28 => {
+                        continuation = Some((read_u32_le(entry, 4), read_u32_le(entry, 12), read_u32_le(entry, 20)));
+                    },
+                    b"ST" => break,
+                    _ => {},
+                }
+                offset += len;
+            }
+            let Some((block, area_offset, len)) = continuation.filter(|_| !complete) else {
+                break;
+            };
+            let end = area_offset.checked_add(len).ok_or(IsoError::Corrupted)?;
+            let sector = self.read_extent((block, end))?;
+            area = sector[area_offset as usize..].to_vec();
+        }
+        Ok(name.filter(|name| !name.is_empty()).map(|name| String::from_utf8_lossy(&name).into_owned()))
+    }
+
+    fn resolve(&mut self, path: &str) -> Result<Entry, IsoError> {
+        let components: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+        let Some((last, parents)) = components.split_last() else {
+            return Ok(self.root.clone());
+        };
+        let mut dir = match self.rock_ridge {
+            Some(_) => self.root.clone(),
+            None => self.path_table_dir(parents)?,
+        };
+        let walk = if self.rock_ridge.is_some() { parents } else { &[] };
+        for name in walk.iter().chain([last]) {
+            if !dir.is_dir {
+                return Err(IsoError::NotADirectory);
+            }
+            let rock_ridge = self.rock_ridge.is_some();
+            dir = self.entries(&dir)?
+                .into_iter()
+                .find(|entry| if rock_ridge { entry.name == *name } else { entry.name.eq_ignore_ascii_case(name) })
+                .ok_or(IsoError::NotFound)?;
+        }
+        Ok(dir)
+    }
+
+    /// The directory `path` through the path table
+    fn path_table_dir(&mut self, path: &[&str]) -> Result<Entry, IsoError> {
+        let mut index = 0;
+        for name in path {
+            index = self.path_table.iter()
+                .skip(1)
+                .position(|entry| entry.parent == index && entry.name.eq_ignore_ascii_case(name))
+                .map(|position| position + 1)
+                .ok_or(IsoError::NotFound)?;
+        }
+        let extent = self.path_table[index].extent;
+        // the length is in the `.` record of the directory
+        let sector = self.read_extent((extent, SECTOR_SIZE as u32))?;
+        let record = records(&sector).next().ok_or(IsoError::Corrupted)?;
+        Ok(Entry { name: String::new(), is_dir: true, extents: vec![(extent, read_u32_le(record, 10))] })
+    }
+}
+
+/// The records of a directory sector
+fn records(sector: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut offset = 0;
+    core::iter::from_fn(move || {
+        let len = *sector.get(offset)? as usize;
+        let record = sector.get(offset..offset + len)?;
+        if len < 34 || 33 + record[32] as usize > len {
+            return None;
+        }
+        offset += len;
+        Some(record)
+    })
+}
+
+/// Where the System Use area of a record starts, after the name and its padding
+fn system_use_offset(record: &[u8]) -> usize {
+    let id_len = record[32] as usize;
+    33 + id_len + (id_len + 1) % 2
+}
+
+/// `NAME.EXT;1` as `NAME.EXT`, and `NAME.;1` as `NAME`
+fn iso_name(id: &[u8], is_dir: bool) -> String {
+    let mut name = String::from_utf8_lossy(id).into_owned();
+    if !is_dir {
+        if let Some(version) = name.rfind(';') {
+            name.truncate(version);
+        }
+        if name.ends_with('.') {
+            name.pop();
+        }
+    }
+    name
+}
+
+#[cfg(test)]
+use crate::io::Cursor;
+
+/// A directory record
+#[cfg(test)]
+fn test_record(id: &[u8], extent: u32, len: u32, flags: u8, system_use: &[u8]) -> Vec<u8> {
+    let mut record = vec![0u8; 33 + id.len() + (id.len() + 1) % 2];
+    record[2..6].copy_from_slice(&extent.to_le_bytes());
+    record[10..14].copy_from_slice(&len.to_le_bytes());
+    record[25] = flags;
+    record[32] = id.len() as u8;
+    record[33..33 + id.len()].copy_from_slice(id);
+    record.extend_from_slice(system_use);
+    if record.len() % 2 == 1 {
+        record.push(0);
+    }
+    record[0] = record.len() as u8;
+    record
+}
+
+/// A volume of 27 sectors: the root directory in sector 20 holds `HELLO.TXT` (sector 22), `BIG`
+/// in two extents (23 and 24) and `BOOT` (21), which holds `LOADER.EFI` (25). With Rock Ridge,
+/// `HELLO.TXT` is named `hello-world.txt` and `BOOT` `boot`, half of it in a continuation area
+/// in sector 26.
+#[cfg(test)]
+fn test_image(rock_ridge: bool) -> Vec<u8> {
+    let sector = SECTOR_SIZE as usize;
+    let mut image = vec![0u8; 27 * sector];
+    let nm = |name: &[u8], flags: u8| [&[b'N', b'M', 5 + name.len() as u8, 1, flags][..], name].concat();
+    let (sp, hello_nm, boot_nm) = match rock_ridge {
+        true => {
+            let mut ce = vec![b'C', b'E', 28, 1];
+            for value in [26u32, 0, 0, 0, 7, 0] {
+                ce.extend_from_slice(&value.to_le_bytes());
+            }
+            (vec![b'S', b'P', 7, 1, 0xbe, 0xef, 0], nm(b"hello-world.txt", 0), [nm(b"bo", NM_CONTINUE), ce].concat())
+        },
+        false => (Vec::new(), Vec::new(), Vec::new()),
+    };
+    image[26 * sector..][..7].copy_from_slice(&nm(b"ot", 0));
+
+    let mut root = [
+        test_record(&[0], 20, 2048, FLAG_DIRECTORY, &sp),
+        test_record(&[1], 20, 2048, FLAG_DIRECTORY, &[]),
+        test_record(b"HELLO.TXT;1", 22, 13, 0, &hello_nm),
+        test_record(b"BIG.;1", 23, 2048, FLAG_MULTI_EXTENT, &[]),
+        test_record(b"BIG.;1", 24, 10, 0, &[]),
+        test_record(b"BOOT", 21, 2048, FLAG_DIRECTORY, &boot_nm),
+    ].concat();
+    // the records don't cross into the next sector, pad what is left
+    root.resize(sector, 0);
+    image[20 * sector..21 * sector].copy_from_slice(&root);
+    let boot = [
+        test_record(&[0], 21, 2048, FLAG_DIRECTORY, &[]),
+        test_record(&[1], 20, 2048, FLAG_DIRECTORY, &[]),
+        test_record(b"LOADER.EFI;1", 25, 4, 0, &[]),
+    ].concat();
+    image[21 * sector..][..boot.len()].copy_from_slice(&boot);
+    image[22 * sector..][..13].copy_from_slice(b"Hello, world!");
+    for (i, byte) in image[23 * sector..23 * sector + 2048].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    image[24 * sector..][..10].copy_from_slice(b"0123456789");
+    image[25 * sector..][..4].copy_from_slice(b"MZ\x90\0");
+
+    let path_table = [&[1, 0][..], &20u32.to_le_bytes(), &[1, 0, 0, 0], &[4, 0], &21u32.to_le_bytes(), &[1, 0], b"BOOT"].concat();
+    image[18 * sector..][..path_table.len()].copy_from_slice(&path_table);
+
+    let pvd = &mut image[16 * sector..17 * sector];
+    pvd[0] = PRIMARY;
+    pvd[1..6].copy_from_slice(STANDARD_ID);
+    pvd[40..72].copy_from_slice(b"FERR_CD                         ");
+    pvd[80..84].copy_from_slice(&27u32.to_le_bytes());
+    pvd[128..130].copy_from_slice(&2048u16.to_le_bytes());
+    pvd[132..136].copy_from_slice(&(path_table.len() as u32).to_le_bytes());
+    pvd[140..144].copy_from_slice(&18u32.to_le_bytes());
+    pvd[ROOT_RECORD_OFFSET..ROOT_RECORD_OFFSET + 34].copy_from_slice(&test_record(&[0], 20, 2048, FLAG_DIRECTORY, &[]));
+    image[17 * sector] = TERMINATOR;
+    image[17 * sector + 1..17 * sector + 6].copy_from_slice(STANDARD_ID);
+    image
+}
+
+#[test_case]
+fn iso9660_reads_iso_names() {
+    let mut fs = Iso9660::mount(Cursor::new(test_image(false))).unwrap();
+    assert_eq!(fs.volume_id(), "FERR_CD");
+    assert!(!fs.has_rock_ridge());
+    let names: Vec<(String, u64)> = fs.read_dir("/").unwrap().into_iter().map(|entry| (entry.name, entry.size)).collect();
+    assert_eq!(names, [(String::from("HELLO.TXT"), 13), (String::from("BIG"), 2058), (String::from("BOOT"), 2048)]);
+
+    let mut buffer = [0u8; 32];
+    assert_eq!(fs.read("/hello.txt", 7, &mut buffer), Ok(6));
+    assert_eq!(&buffer[..6], b"world!");
+    // across the two extents
+    assert_eq!(fs.read("/BIG", 2040, &mut buffer), Ok(18));
+    assert_eq!(&buffer[..18], b"\xf8\xf9\xfa\xfb\xfc\xfd\xfe\xff0123456789");
+    assert_eq!(fs.read("/boot/loader.efi", 0, &mut buffer), Ok(4));
+    assert_eq!(fs.metadata("/boot").unwrap().is_dir, true);
+    assert_eq!(fs.read("/boot", 0, &mut buffer), Err(IsoError::IsADirectory));
+    assert_eq!(fs.read_dir("/missing/x").err(), Some(IsoError::NotFound));
+}
+
+#[test_case]
+fn iso9660_reads_rock_ridge_names() {
+    let mut fs = Iso9660::mount(Cursor::new(test_image(true))).unwrap();
+    assert!(fs.has_rock_ridge());
+    let names: Vec<String> = fs.read_dir("/").unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, ["hello-world.txt", "BIG", "boot"]);
+    let mut buffer = [0u8; 13];
+    assert_eq!(fs.read("/hello-world.txt", 0, &mut buffer), Ok(13));
+    assert_eq!(fs.read("/HELLO-WORLD.TXT", 0, &mut buffer), Err(IsoError::NotFound));
+    assert_eq!(fs.read("/boot/LOADER.EFI", 0, &mut buffer), Ok(4));
+
+    assert!(matches!(Iso9660::mount(Cursor::new(vec![0u8; 40 * 2048])), Err(IsoError::NotIso)));
+}
@@ -20,29 +20,46 @@ pub mod frame_allocator;
 pub mod allocator;
 pub mod serial_logger;
 pub mod crc;
+pub mod volatile;
+pub mod stack_trace;
+pub mod memprof;
+pub mod spinlock;
+pub mod bytes;
+pub mod time_page;
+pub mod cmdline;
+pub mod lz4;
+pub mod nvram;
+pub mod ab_boot;
+pub mod efi;
+pub mod msr;
+pub mod boot_info;
+pub mod io;
+pub mod ferr_fs;
+pub mod fat32;
+pub mod ext2;
+pub mod iso9660;
 
 use core::arch::asm;
 use core::panic::PanicInfo;
-use crate::frame_allocator::MemoryMap;
-use crate::logger::FrameBufferInfo;
-
-pub struct BootInfo {
-    pub fb_info: FrameBufferInfo,
-    pub rsdp_addr: u64,
-    pub memory_map: MemoryMap,
-    pub memory_map_next_free_frame: usize
-}
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub use crate::boot_info::BootInfo;
 
+/// Base of the physical memory mapping. With KASLR the loader moves it up by a random slide,
+/// use `phys_mapping_offset` for the actual value.
 pub const VIRT_MAPPING_OFFSET: u64 = 0x180_0000_0000;
 
+static PHYS_MAPPING_OFFSET: AtomicU64 = AtomicU64::new(VIRT_MAPPING_OFFSET);
+
+/// Virtual address at which all physical memory is mapped
 #[inline]
-pub unsafe fn read_u32_ptr(ptr: *mut u32, offset: u32) -> u32 {
-    core::ptr::read_volatile(ptr.byte_offset(offset as isize))
+pub fn phys_mapping_offset() -> u64 {
+    PHYS_MAPPING_OFFSET.load(Ordering::Relaxed)
 }
 
-#[inline]
-pub unsafe fn write_u32_ptr(ptr: *mut u32, offset: u32, value: u32) {
-    core::ptr::write_volatile(ptr.byte_offset(offset as isize), value);
+/// Set once on entry from the boot information, before anything uses the mapping
+pub fn set_phys_mapping_offset(offset: u64) {
+    PHYS_MAPPING_OFFSET.store(offset, Ordering::Relaxed);
 }
 
 #[inline]
@@ -114,6 +131,12 @@ macro_rules! entry_point {
             // validate the signature of the program entry point
             let f: fn(&'static BootInfo) -> ! = $path;
 
+            if let Err(e) = boot_info.validate() {
+                panic!("Unsupported boot information: {:?}", e);
+            }
+            if let Some(offset) = boot_info.get::<$crate::boot_info::PhysMappingOffset>() {
+                $crate::set_phys_mapping_offset(offset.0);
+            }
             f(boot_info)
         }
     };
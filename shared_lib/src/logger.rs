@@ -4,12 +4,12 @@ use alloc::vec::Vec;
 use core::fmt;
 use core::slice::from_raw_parts_mut;
 use core::ptr::read_volatile;
-use spinning_top::{RawSpinlock, Spinlock};
+use crate::spinlock::{Spinlock, SpinlockGuard};
 use conquer_once::spin::OnceCell;
 use core::fmt::{Arguments, Write};
 use font8x8::UnicodeFonts;
-use spinning_top::lock_api::MutexGuard;
 use crate::interrupts;
+use crate::time_page::UptimePrefix;
 
 #[derive(Clone, Copy)]
 pub enum PixelFormat {
@@ -37,23 +37,86 @@ pub struct Logger {
 
     char_buffer: VecDeque<Vec<char>>,
     char_buffer_width: usize,
-    char_buffer_height: usize
+    char_buffer_height: usize,
+
+    // In deferred mode characters only go to `char_buffer`, pixels are written by `flush`
+    deferred: bool,
+    dirty_rows: Vec<bool>,
+    redraw_all: bool,
+    pending_lines: usize
 }
 
+/// In batching mode the log is drawn after this many lines even if nobody flushes it
+pub const FLUSH_LINES: usize = 16;
+
 impl Logger {
     pub fn new(fb_info: FrameBufferInfo) -> Self {
         let fb_slice = unsafe { from_raw_parts_mut(fb_info.addr as *mut u8, fb_info.size) };
         fb_slice.fill(0);
 
         let w = (fb_info.width - 1) / 8;
-        let h = (fb_info.height - 1) / 8;
+        // the bottom row is the status bar
+        let h = (fb_info.height - 1) / 8 - 1;
 
         let mut char_buffer = VecDeque::with_capacity(h);
         for _ in 0..w {
             char_buffer.push_back(vec!['\0'; w]);
         }
 
-        Logger{fb_info, fb: &mut *fb_slice, x_pos: 0, y_pos: 0, char_buffer, char_buffer_width: w, char_buffer_height: h }
+        Logger{fb_info, fb: &mut *fb_slice, x_pos: 0, y_pos: 0, char_buffer, char_buffer_width: w, char_buffer_height: h,
+            deferred: false, dirty_rows: vec![false; h], redraw_all: false, pending_lines: 0 }
+    }
+
+    /// Enables or disables deferred drawing. Disabling it draws everything pending.
+    pub fn set_deferred(&mut self, deferred: bool) {
+        if !deferred {
+            self.flush();
+        }
+        self.deferred = deferred;
+    }
+
+    /// Number of lines written since the last flush
+    pub fn pending_lines(&self) -> usize {
+        self.pending_lines
+    }
+
+    /// Draws the rows changed since the last flush, or the whole screen if it scrolled.
+    pub fn flush(&mut self) {
+        if self.redraw_all {
+            self.draw_char_buffer();
+        } else {
+            for y in 0..self.char_buffer_height {
+                if self.dirty_rows[y] {
+                    self.draw_row(y);
+                }
+            }
+        }
+
+        self.dirty_rows.fill(false);
+        self.redraw_all = false;
+        self.pending_lines = 0;
+    }
+
+    fn draw_row(&mut self, y: usize) {
+        for x in 0..self.char_buffer_width {
+            let rendered = font8x8::BASIC_FONTS
+                .get(self.char_buffer[y][x])
+                .unwrap();
+
+            self.write_8x8(rendered, 1 + x * 8, 1 + y * 8);
+        }
+    }
+
+    /// Draws `text` into the status bar below the log, padded or cut to the screen width.
+    pub fn draw_status(&mut self, text: &str) {
+        let mut chars = text.chars();
+        for x in 0..self.char_buffer_width {
+            let rendered = chars.next()
+                .and_then(|c| font8x8::BASIC_FONTS.get(c))
+                .unwrap_or([0; 8]);
+
+            self.write_8x8(rendered, 1 + x * 8, 1 + self.char_buffer_height * 8);
+        }
     }
 
     pub fn draw_char_buffer(&mut self) {
@@ -87,13 +150,18 @@ impl Logger {
     fn newline(&mut self) {
         self.y_pos += 1;
         self.carriage_return();
+        self.pending_lines += 1;
 
         if self.y_pos >= self.char_buffer_height {
             self.char_buffer.pop_front();
             self.char_buffer.push_back(vec!['\0'; self.char_buffer_width]);
             self.y_pos = self.char_buffer_height - 1;
             self.x_pos = 0;
-            self.draw_char_buffer();
+            if self.deferred {
+                self.redraw_all = true;
+            } else {
+                self.draw_char_buffer();
+            }
         }
     }
 
@@ -117,6 +185,9 @@ impl Logger {
     pub fn height(&self) -> usize {
         self.fb_info.height
     }
+    pub fn fb_info(&self) -> FrameBufferInfo {
+        self.fb_info
+    }
 
     pub fn write_8x8(&mut self, rendered: [u8; 8], x_pos: usize, y_pos: usize) {
         for (y, byte) in rendered.iter().enumerate() {
@@ -138,7 +209,9 @@ impl Logger {
 
                 self.char_buffer[self.y_pos][self.x_pos] = c;
 
-                if c != '\0' {
+                if self.deferred {
+                    self.dirty_rows[self.y_pos] = true;
+                } else if c != '\0' {
                     let rendered = font8x8::BASIC_FONTS
                         .get(c);
                     if rendered.is_none() {
@@ -176,7 +249,7 @@ impl LockedLogger {
         LockedLogger(Spinlock::new(Logger::new(fb_info)))
     }
 
-    pub fn lock(&self) -> MutexGuard<'_, RawSpinlock, Logger> {
+    pub fn lock(&self) -> SpinlockGuard<'_, Logger> {
         self.0.lock()
     }
 
@@ -186,6 +259,28 @@ impl LockedLogger {
         });
     }
 
+    /// Batches drawing: the log is drawn on `flush` or every `FLUSH_LINES` lines, so bursts of
+    /// messages don't stall on framebuffer writes. Disabling it draws everything pending.
+    pub fn set_batching(&self, enabled: bool) {
+        interrupts::without_interrupts(|| {
+            self.0.lock().set_deferred(enabled);
+        });
+    }
+
+    /// Replaces the status bar text, see `Logger::draw_status`.
+    pub fn draw_status(&self, text: &str) {
+        interrupts::without_interrupts(|| {
+            self.0.lock().draw_status(text);
+        });
+    }
+
+    /// Draws pending output, should be called about once per frame in batching mode.
+    pub fn flush(&self) {
+        interrupts::without_interrupts(|| {
+            self.0.lock().flush();
+        });
+    }
+
     /// Force-unlocks the logger to prevent a deadlock.
     ///
     /// This method is not memory safe and should be only used when absolutely necessary.
@@ -202,11 +297,16 @@ impl log::Log for LockedLogger {
     fn log(&self, record: &log::Record) {
         interrupts::without_interrupts(|| {
             let mut logger = self.0.lock();
-            writeln!(logger, "{}:    {}", record.level(), record.args()).unwrap();
+            writeln!(logger, "{}{}:    {}", UptimePrefix, record.level(), record.args()).unwrap();
+            if logger.pending_lines() >= FLUSH_LINES {
+                logger.flush();
+            }
         });
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        LockedLogger::flush(self);
+    }
 }
 
 #[macro_export]
@@ -0,0 +1,234 @@
+// LZ4 decompression.
+//
+// Decodes the LZ4 frame format (magic 0x184D2204) written by `lz4 -c` or `lz4_flex`'s
+// `FrameEncoder` into a caller provided buffer, without allocating. Blocks may be linked, since
+// the whole output stays in one buffer the matches can reach back into earlier blocks. The
+// optional xxHash32 checksums are skipped, not verified.
+
+use crate::bytes::{read_u32_le, read_u64_le};
+
+const FRAME_MAGIC: u32 = 0x184D2204;
+const SKIPPABLE_MAGIC_MASK: u32 = 0xFFFFFFF0;
+const SKIPPABLE_MAGIC: u32 = 0x184D2A50;
+
+const FLG_VERSION_MASK: u8 = 0b1100_0000;
+const FLG_VERSION: u8 = 0b0100_0000;
+const FLG_BLOCK_CHECKSUM: u8 = 1 << 4;
+const FLG_CONTENT_SIZE: u8 = 1 << 3;
+const FLG_CONTENT_CHECKSUM: u8 = 1 << 2;
+const FLG_DICT_ID: u8 = 1 << 0;
+
+const BLOCK_UNCOMPRESSED: u32 = 1 << 31;
+const MIN_MATCH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lz4Error {
+    BadMagic,
+    UnsupportedVersion,
+    /// Frames with a dictionary id need the dictionary, which we don't have
+    DictionaryRequired,
+    Truncated,
+    /// A match points before the start of the output
+    BadOffset,
+    OutputTooSmall,
+}
+
+pub struct FrameHeader {
+    flags: u8,
+    /// Size of the decompressed data, if the encoder recorded it
+    pub content_size: Option<u64>,
+    size: usize,
+}
+
+pub fn is_lz4_frame(data: &[u8]) -> bool {
+    data.len() >= 4 && read_u32_le(data, 0) == FRAME_MAGIC
+}
+
+pub fn parse_frame_header(data: &[u8]) -> Result<FrameHeader, Lz4Error> {
+    if !is_lz4_frame(data) {
+        return Err(Lz4Error::BadMagic);
+    }
+    // magic, FLG, BD
+    let flags = *data.get(4).ok_or(Lz4Error::Truncated)?;
+    if flags & FLG_VERSION_MASK != FLG_VERSION {
+        return Err(Lz4Error::UnsupportedVersion);
+    }
+    if flags & FLG_DICT_ID != 0 {
+        return Err(Lz4Error::DictionaryRequired);
+    }
+
+    let mut size = 6;
+    let mut content_size = None;
+    if flags & FLG_CONTENT_SIZE != 0 {
+        if data.len() < size + 8 {
+            return Err(Lz4Error::Truncated);
+        }
+        content_size = Some(read_u64_le(data, size));
+        size += 8;
+    }
+    // header checksum
+    size += 1;
+
+    if data.len() < size {
+        return Err(Lz4Error::Truncated);
+    }
+    Ok(FrameHeader { flags, content_size, size })
+}
+
+fn read_length(input: &[u8], pos: &mut usize, mut length: usize) -> Result<usize, Lz4Error> {
+    loop {
+        let byte = *input.get(*pos).ok_or(Lz4Error::Truncated)?;
+        *pos += 1;
+        length += byte as usize;
+        if byte != 255 {
+            return Ok(length);
+        }
+    }
+}
+
+/// Decodes one compressed block to `output[out_pos..]`. Matches may refer to anything before
+/// `out_pos`. Returns the new end of the output.
+pub fn decompress_block(input: &[u8], output: &mut [u8], mut out_pos: usize) -> Result<usize, Lz4Error> {
+    let mut pos = 0;
+    while pos < input.len() {
+        let token = input[pos];
+        pos += 1;
+
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals = read_length(input, &mut pos, literals)?;
+        }
+        let literal_bytes = input.get(pos..pos + literals).ok_or(Lz4Error::Truncated)?;
+        output.get_mut(out_pos..out_pos + literals)
+            .ok_or(Lz4Error::OutputTooSmall)?
+            .copy_from_slice(literal_bytes);
+        pos += literals;
+        out_pos += literals;
+
+        // the last sequence has no match
+        if pos == input.len() {
+            break;
+        }
+
+        let offset = input.get(pos..pos + 2).ok_or(Lz4Error::Truncated)?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        pos += 2;
+        if offset == 0 || offset > out_pos {
+            return Err(Lz4Error::BadOffset);
+        }
+
+        let mut match_length = (token & 0xF) as usize;
+        if match_length == 15 {
+            match_length = read_length(input, &mut pos, match_length)?;
+        }
+        match_length += MIN_MATCH;
+
+        if out_pos + match_length > output.len() {
+            return Err(Lz4Error::OutputTooSmall);
+        }
+        // matches may overlap their own output, e.g. offset 1 repeats the last byte
+        let start = out_pos - offset;
+        for i in 0..match_length {
+            output[out_pos + i] = output[start + i];
+        }
+        out_pos += match_length;
+    }
+    Ok(out_pos)
+}
+
+/// Decompresses all frames in `input` into `output` and returns the decompressed size.
+/// Skippable frames are ignored.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<usize, Lz4Error> {
+    let mut pos = 0;
+    let mut out_pos = 0;
+
+    while pos < input.len() {
+        let data = &input[pos..];
+        if data.len() >= 8 && read_u32_le(data, 0) & SKIPPABLE_MAGIC_MASK == SKIPPABLE_MAGIC {
+            pos += 8 + read_u32_le(data, 4) as usize;
+            continue;
+        }
+
+        let header = parse_frame_header(data)?;
+        pos += header.size;
+
+        loop {
+            if input.len() < pos + 4 {
+                return Err(Lz4Error::Truncated);
+            }
+            let block_size = read_u32_le(input, pos);
+            pos += 4;
+            if block_size == 0 {
+                break;
+            }
+
+            let len = (block_size & !BLOCK_UNCOMPRESSED) as usize;
+            let block = input.get(pos..pos + len).ok_or(Lz4Error::Truncated)?;
+            if block_size & BLOCK_UNCOMPRESSED != 0 {
+                output.get_mut(out_pos..out_pos + len)
+                    .ok_or(Lz4Error::OutputTooSmall)?
+                    .copy_from_slice(block);
+                out_pos += len;
+            } else {
+                out_pos = decompress_block(block, output, out_pos)?;
+            }
+            pos += len;
+
+            if header.flags & FLG_BLOCK_CHECKSUM != 0 {
+                pos += 4;
+            }
+        }
+
+        if header.flags & FLG_CONTENT_CHECKSUM != 0 {
+            pos += 4;
+        }
+    }
+
+    if pos > input.len() {
+        return Err(Lz4Error::Truncated);
+    }
+    Ok(out_pos)
+}
+
+#[test_case]
+fn lz4_block_test() {
+    // "abcabcabcabc" + "xyz": literals "abc", match offset 3 length 9, last literals "xyz"
+    let block = [0x35, b'a', b'b', b'c', 0x03, 0x00, 0x30, b'x', b'y', b'z'];
+    let mut output = [0u8; 15];
+    assert_eq!(decompress_block(&block, &mut output, 0), Ok(15));
+    assert_eq!(&output, b"abcabcabcabcxyz");
+
+    let mut small = [0u8; 8];
+    assert_eq!(decompress_block(&block, &mut small, 0), Err(Lz4Error::OutputTooSmall));
+
+    let bad_offset = [0x10, b'a', 0x02, 0x00];
+    assert_eq!(decompress_block(&bad_offset, &mut output, 0), Err(Lz4Error::BadOffset));
+}
+
+#[test_case]
+fn lz4_frame_test() {
+    let mut frame = [0u8; 35];
+    // magic, FLG: version 01, independent blocks, content size; BD: 64 KiB blocks
+    frame[0..4].copy_from_slice(&FRAME_MAGIC.to_le_bytes());
+    frame[4] = 0b0110_1000;
+    frame[5] = 0x40;
+    frame[6..14].copy_from_slice(&17u64.to_le_bytes());
+    frame[14] = 0; // header checksum, not verified
+    // compressed block: 16 x 'a' and a final 'b'
+    let block = [0x1B, b'a', 0x01, 0x00, 0x10, b'b'];
+    frame[15..19].copy_from_slice(&(block.len() as u32).to_le_bytes());
+    frame[19..25].copy_from_slice(&block);
+    // uncompressed block, empty
+    frame[25..29].copy_from_slice(&BLOCK_UNCOMPRESSED.to_le_bytes());
+    // end mark
+    frame[29..33].copy_from_slice(&0u32.to_le_bytes());
+
+    let header = parse_frame_header(&frame).unwrap();
+    assert_eq!(header.content_size, Some(17));
+
+    let mut output = [0u8; 17];
+    assert_eq!(decompress(&frame[..33], &mut output), Ok(17));
+    assert_eq!(&output, b"aaaaaaaaaaaaaaaab");
+
+    assert_eq!(decompress(&frame[..20], &mut output), Err(Lz4Error::Truncated));
+}
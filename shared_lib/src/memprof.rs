@@ -0,0 +1,183 @@
+// Opt-in allocation profiler for the kernel heap and the frame allocator.
+//
+// Profiling is switched on at runtime (see `enable`), until then the hooks in the allocators
+// cost a single atomic load. The profiler never allocates: all statistics live in fixed-size
+// static tables.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::stack_trace::collect_return_addresses;
+
+/// Number of return addresses stored per call site.
+pub const CALL_SITE_DEPTH: usize = 4;
+/// Number of distinct call sites tracked per allocator.
+const MAX_CALL_SITES: usize = 32;
+/// Power-of-two size buckets: <=8, <=16, ..., <=64k, bigger.
+const SIZE_BUCKETS: usize = 15;
+/// Frames belonging to the profiler and the allocator itself, not interesting for the report.
+const SKIP_FRAMES: usize = 2;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub static HEAP_PROFILE: spin::Mutex<AllocProfile> = spin::Mutex::new(AllocProfile::new("heap"));
+pub static FRAME_PROFILE: spin::Mutex<AllocProfile> = spin::Mutex::new(AllocProfile::new("frames"));
+
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Starts recording. Statistics collected before are kept.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+pub fn reset() {
+    HEAP_PROFILE.lock().reset();
+    FRAME_PROFILE.lock().reset();
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct CallSite(pub [u64; CALL_SITE_DEPTH]);
+
+impl CallSite {
+    const EMPTY: CallSite = CallSite([0; CALL_SITE_DEPTH]);
+
+    /// Captures the call chain of the allocation currently in progress.
+    #[inline(always)]
+    pub fn capture() -> CallSite {
+        let mut site = CallSite::EMPTY;
+        collect_return_addresses(SKIP_FRAMES, &mut site.0);
+        site
+    }
+}
+
+impl fmt::Display for CallSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, addr) in self.0.iter().take_while(|a| **a != 0).enumerate() {
+            if i != 0 {
+                write!(f, " <- ")?;
+            }
+            write!(f, "{:#x}", addr)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CallSiteStats {
+    site: CallSite,
+    count: u64,
+    bytes: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct AllocProfile {
+    name: &'static str,
+    histogram: [u64; SIZE_BUCKETS],
+    allocations: u64,
+    deallocations: u64,
+    current_bytes: u64,
+    peak_bytes: u64,
+    call_sites: [CallSiteStats; MAX_CALL_SITES],
+}
+
+fn size_bucket(size: usize) -> usize {
+    let mut bucket = 0;
+    let mut limit = 8;
+    while size > limit && bucket < SIZE_BUCKETS - 1 {
+        limit <<= 1;
+        bucket += 1;
+    }
+    bucket
+}
+
+impl AllocProfile {
+    const fn new(name: &'static str) -> Self {
+        AllocProfile {
+            name,
+            histogram: [0; SIZE_BUCKETS],
+            allocations: 0,
+            deallocations: 0,
+            current_bytes: 0,
+            peak_bytes: 0,
+            call_sites: [CallSiteStats { site: CallSite::EMPTY, count: 0, bytes: 0 }; MAX_CALL_SITES],
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = AllocProfile::new(self.name);
+    }
+
+    pub fn record_alloc(&mut self, size: usize, site: CallSite) {
+        self.histogram[size_bucket(size)] += 1;
+        self.allocations += 1;
+        self.current_bytes += size as u64;
+        self.peak_bytes = self.peak_bytes.max(self.current_bytes);
+
+        if let Some(stats) = self.call_sites.iter_mut().find(|s| s.site == site) {
+            stats.count += 1;
+            stats.bytes += size as u64;
+            return;
+        }
+
+        // table is full: evict the least frequent call site
+        let victim = self.call_sites
+            .iter_mut()
+            .min_by_key(|s| s.count)
+            .unwrap();
+        *victim = CallSiteStats { site, count: 1, bytes: size as u64 };
+    }
+
+    pub fn record_free(&mut self, size: usize) {
+        self.deallocations += 1;
+        self.current_bytes = self.current_bytes.saturating_sub(size as u64);
+    }
+
+    pub fn dump(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(out, "[memprof] {}: allocs: {}, frees: {}, in use: {} B, peak: {} B",
+                 self.name, self.allocations, self.deallocations, self.current_bytes, self.peak_bytes)?;
+
+        let mut limit = 8;
+        for (i, count) in self.histogram.iter().enumerate() {
+            if *count != 0 {
+                if i == SIZE_BUCKETS - 1 {
+                    writeln!(out, "  > {} B: {}", limit >> 1, count)?;
+                } else {
+                    writeln!(out, "  <= {} B: {}", limit, count)?;
+                }
+            }
+            limit <<= 1;
+        }
+
+        let mut sites = self.call_sites;
+        sites.sort_unstable_by(|a, b| b.count.cmp(&a.count));
+        for stats in sites.iter().filter(|s| s.count != 0).take(8) {
+            writeln!(out, "  {}x {} B at {}", stats.count, stats.bytes, stats.site)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    // copy the profiles out first: writing the report may allocate
+    let heap = *HEAP_PROFILE.lock();
+    let frames = *FRAME_PROFILE.lock();
+
+    writeln!(out, "[memprof] profiling is {}", if is_enabled() { "on" } else { "off" })?;
+    heap.dump(out)?;
+    frames.dump(out)
+}
+
+#[test_case]
+fn size_bucket_test() {
+    assert_eq!(0, size_bucket(1));
+    assert_eq!(0, size_bucket(8));
+    assert_eq!(1, size_bucket(9));
+    assert_eq!(9, size_bucket(4096));
+    assert_eq!(SIZE_BUCKETS - 1, size_bucket(1 << 20));
+}
@@ -0,0 +1,43 @@
+// Model specific registers.
+//
+// Reading or writing an MSR the CPU doesn't implement raises #GP, callers check CPUID first.
+
+use core::arch::asm;
+
+pub const IA32_EFER: u32 = 0xC000_0080;
+pub const IA32_FS_BASE: u32 = 0xC000_0100;
+pub const IA32_GS_BASE: u32 = 0xC000_0101;
+/// Swapped with IA32_GS_BASE by `swapgs`
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+pub const IA32_APIC_BASE: u32 = 0x1B;
+/// Performance counter 0, a write sets the low 32 bits and sign-extends them
+pub const IA32_PMC0: u32 = 0xC1;
+pub const IA32_MCG_CAP: u32 = 0x179;
+pub const IA32_MCG_STATUS: u32 = 0x17A;
+pub const IA32_PERFEVTSEL0: u32 = 0x186;
+pub const IA32_THERM_INTERRUPT: u32 = 0x19B;
+pub const IA32_THERM_STATUS: u32 = 0x19C;
+pub const MSR_TEMPERATURE_TARGET: u32 = 0x1A2;
+pub const IA32_PACKAGE_THERM_STATUS: u32 = 0x1B1;
+/// Architectural performance monitoring version 2 and later
+pub const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+pub const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+/// Status register of machine check bank 0, bank `n` is at `IA32_MC0_STATUS + 4 * n`
+pub const IA32_MC0_STATUS: u32 = 0x401;
+pub const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// # Safety
+/// The MSR must exist on this CPU.
+#[inline]
+pub unsafe fn read(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    (high as u64) << 32 | low as u64
+}
+
+/// # Safety
+/// The MSR must exist on this CPU and `value` must not break any memory safety guarantees.
+#[inline]
+pub unsafe fn write(msr: u32, value: u64) {
+    asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack, preserves_flags));
+}
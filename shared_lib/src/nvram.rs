@@ -0,0 +1,143 @@
+// CMOS NVRAM.
+//
+// The 128 bytes of battery-backed CMOS RAM are accessed through an index port and a data port.
+// Besides the RTC registers read by the kernel's `chrono`, the kernel and the loader keep a small
+// block of their own settings at the end of the bank, where neither the standard layout nor QEMU
+// put anything:
+//
+//     0x60        magic
+//     0x61        layout version
+//     0x62..0x7E  USER_BYTES bytes of settings
+//     0x7E..0x80  big endian sum of the bytes above, like the standard CMOS checksum
+//
+// The block is rewritten as a whole, so a reset in the middle of a write leaves a bad checksum
+// rather than a mix of old and new settings. On machines without a battery the block simply
+// reads back as uninitialized after every boot.
+
+use crate::interrupts::without_interrupts;
+use crate::serial::{inb, outb};
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+/// Bit 7 of the index port masks NMIs, keep them enabled
+const NMI_DISABLE: u8 = 0x80;
+
+const BLOCK_START: u8 = 0x60;
+const MAGIC: u8 = 0xFE;
+const VERSION: u8 = 1;
+const DATA_START: u8 = BLOCK_START + 2;
+const CHECKSUM: u8 = 0x7E;
+
+pub const USER_BYTES: usize = (CHECKSUM - DATA_START) as usize;
+
+/// Offsets of the settings in the user bytes
+pub const SETTING_VIDEO_WIDTH: usize = 0;
+pub const SETTING_VIDEO_HEIGHT: usize = 2;
+/// Kernel the loader preselects, 1 for the first of its list; 0 leaves the choice to it
+pub const SETTING_BOOT_ENTRY: usize = 4;
+pub const SETTING_PANIC_POLICY: usize = 5;
+/// A/B kernel state, see `ab_boot`
+pub const SETTING_GOOD_SLOT: usize = 6;
+pub const SETTING_TRIAL_SLOT: usize = 7;
+pub const SETTING_TRIAL_ATTEMPTS: usize = 8;
+pub const SETTING_TRIAL_STAMP: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvramError {
+    /// The block was never written, or by another layout version
+    NotInitialized,
+    BadChecksum,
+    OutOfRange,
+}
+
+/// Reads the CMOS register `reg`
+pub fn read_register(reg: u8) -> u8 {
+    // the index and the access must not be split by an interrupt handler touching the CMOS
+    without_interrupts(|| unsafe {
+        outb(INDEX_PORT, reg & !NMI_DISABLE);
+        inb(DATA_PORT)
+    })
+}
+
+/// Writes the CMOS register `reg`. Writing the RTC or the standard configuration registers can
+/// confuse the firmware, the kernel only writes its own block.
+fn write_register(reg: u8, value: u8) {
+    without_interrupts(|| unsafe {
+        outb(INDEX_PORT, reg & !NMI_DISABLE);
+        outb(DATA_PORT, value);
+    })
+}
+
+fn checksum(header: [u8; 2], data: &[u8; USER_BYTES]) -> u16 {
+    header.iter().chain(data.iter()).fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16))
+}
+
+/// Reads and verifies the settings block
+pub fn read_user() -> Result<[u8; USER_BYTES], NvramError> {
+    let header = [read_register(BLOCK_START), read_register(BLOCK_START + 1)];
+    if header != [MAGIC, VERSION] {
+        return Err(NvramError::NotInitialized);
+    }
+
+    let mut data = [0u8; USER_BYTES];
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = read_register(DATA_START + i as u8);
+    }
+
+    let stored = u16::from_be_bytes([read_register(CHECKSUM), read_register(CHECKSUM + 1)]);
+    if stored != checksum(header, &data) {
+        return Err(NvramError::BadChecksum);
+    }
+    Ok(data)
+}
+
+/// Writes the whole settings block with a new checksum
+pub fn write_user(data: &[u8; USER_BYTES]) {
+    let header = [MAGIC, VERSION];
+    write_register(BLOCK_START, header[0]);
+    write_register(BLOCK_START + 1, header[1]);
+    for (i, byte) in data.iter().enumerate() {
+        write_register(DATA_START + i as u8, *byte);
+    }
+
+    let [high, low] = checksum(header, data).to_be_bytes();
+    write_register(CHECKSUM, high);
+    write_register(CHECKSUM + 1, low);
+}
+
+/// Reads `buffer.len()` user bytes starting at `offset`
+pub fn read(offset: usize, buffer: &mut [u8]) -> Result<(), NvramError> {
+    let data = read_user()?;
+    let bytes = data.get(offset..offset + buffer.len()).ok_or(NvramError::OutOfRange)?;
+    buffer.copy_from_slice(bytes);
+    Ok(())
+}
+
+/// Replaces the user bytes at `offset` with `bytes`. A block that isn't valid yet starts out as
+/// zeroes.
+pub fn write(offset: usize, bytes: &[u8]) -> Result<(), NvramError> {
+    let mut data = read_user().unwrap_or([0; USER_BYTES]);
+    data.get_mut(offset..offset + bytes.len())
+        .ok_or(NvramError::OutOfRange)?
+        .copy_from_slice(bytes);
+    write_user(&data);
+    Ok(())
+}
+
+pub fn read_u8(offset: usize) -> Result<u8, NvramError> {
+    let mut buffer = [0u8; 1];
+    read(offset, &mut buffer)?;
+    Ok(buffer[0])
+}
+
+pub fn read_u16(offset: usize) -> Result<u16, NvramError> {
+    let mut buffer = [0u8; 2];
+    read(offset, &mut buffer)?;
+    Ok(u16::from_le_bytes(buffer))
+}
+
+pub fn read_u32(offset: usize) -> Result<u32, NvramError> {
+    let mut buffer = [0u8; 4];
+    read(offset, &mut buffer)?;
+    Ok(u32::from_le_bytes(buffer))
+}
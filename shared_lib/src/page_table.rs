@@ -1,9 +1,14 @@
 use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::ops::IndexMut;
 use bitflags::bitflags;
 use crate::addr::VirtAddr;
+use crate::msr;
 
 pub const PAGE_SIZE: u64 = 4096;
+pub const HUGE_PAGE_2MB_SIZE: u64 = 2 * 1024 * 1024;
+pub const HUGE_PAGE_1GB_SIZE: u64 = 1024 * 1024 * 1024;
 
 #[derive(Clone, Copy)]
 #[repr(transparent)]
@@ -33,6 +38,12 @@ impl PageTableEntry {
         self.flags().contains(PageTableFlags::PRESENT)
     }
 
+    /// Whether a P3 or P2 entry maps a 1 GiB or 2 MiB page instead of pointing to a table.
+    #[inline]
+    pub const fn is_huge(&self) -> bool {
+        self.flags().contains(PageTableFlags::HUGE_PAGE)
+    }
+
     /// Returns the physical address mapped by this entry, might be zero.
     #[inline]
     pub fn addr(&self) -> u64 {
@@ -105,6 +116,11 @@ bitflags! {
     }
 }
 
+impl PageTableFlags {
+    /// Marks a read-only leaf entry whose frame is shared copy-on-write, see `map_cow`.
+    pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+}
+
 pub const ENTRY_COUNT: u16 = 512;
 
 #[repr(align(4096))]
@@ -144,19 +160,59 @@ impl core::ops::IndexMut<u16> for PageTable {
     }
 }
 
-unsafe fn create_next_table<'a>(page_table_entry: &'a mut PageTableEntry, page_tables_allocator: &'a mut impl PageTablesAllocator, offset: u64)
+/// Permissions are enforced on the leaf entries, tables are writable and executable. User pages
+/// need `USER_ACCESSIBLE` on every level though, so it is added to the tables on the way.
+unsafe fn create_next_table<'a>(page_table_entry: &'a mut PageTableEntry, page_tables_allocator: &'a mut impl PageTablesAllocator, flags: PageTableFlags, offset: u64)
                                 -> Result::<&'a mut PageTable, &'static str> {
-    if page_table_entry.flags().contains(PageTableFlags::PRESENT) {
+    let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | (flags & PageTableFlags::USER_ACCESSIBLE);
+
+    if page_table_entry.is_huge() {
+        Err("address is mapped by a huge page")
+    }
+    else if page_table_entry.flags().contains(PageTableFlags::PRESENT) {
+        if !page_table_entry.flags().contains(table_flags) {
+            page_table_entry.set_addr(page_table_entry.addr(), page_table_entry.flags() | table_flags);
+        }
         let next_page_table = unsafe { &mut *((page_table_entry.addr() + offset) as *mut PageTable) };
         Ok(next_page_table)
     }
     else {
         let new_table = page_tables_allocator.allocate_page_table()?;
-        page_table_entry.set_addr(new_table as *const _ as u64 - offset, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        page_table_entry.set_addr(new_table as *const _ as u64 - offset, table_flags);
         Ok(new_table)
     }
 }
 
+static NO_EXECUTE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables the no-execute bit (EFER.NXE) if the CPU supports it. Without it `NO_EXECUTE` is
+/// dropped from new mappings, since the bit is reserved and would fault. Must be called by each
+/// binary that maps pages, before mapping them.
+pub fn enable_no_execute() -> bool {
+    const EFER_NXE: u64 = 1 << 11;
+
+    // CPUID 0x80000001, EDX bit 20
+    let supported = __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 20) != 0;
+    if supported {
+        unsafe {
+            msr::write(msr::IA32_EFER, msr::read(msr::IA32_EFER) | EFER_NXE);
+        }
+    }
+
+    NO_EXECUTE_ENABLED.store(supported, Ordering::Relaxed);
+    supported
+}
+
+/// Leaf entry flags for a mapping with `flags`
+fn leaf_flags(flags: PageTableFlags) -> PageTableFlags {
+    let flags = flags | PageTableFlags::PRESENT;
+    if NO_EXECUTE_ENABLED.load(Ordering::Relaxed) {
+        flags
+    } else {
+        flags - PageTableFlags::NO_EXECUTE
+    }
+}
+
 pub trait PageTablesAllocator {
     fn allocate_page_table(&mut self) -> Result::<&mut PageTable, &'static str>;
 }
@@ -166,7 +222,7 @@ enum MappingMode {
     Remapping
 }
 
-unsafe fn map_address_impl(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, page_tables_allocator: &mut impl PageTablesAllocator, mapping_mode: MappingMode, offset: u64)
+unsafe fn map_address_impl(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, flags: PageTableFlags, page_tables_allocator: &mut impl PageTablesAllocator, mapping_mode: MappingMode, offset: u64)
                            -> core::result::Result<(), &'static str> {
     if virt.0 % 4096 != 0 {
         return Err("Virtual address must be aligned!");
@@ -176,23 +232,32 @@ unsafe fn map_address_impl(l4_page_table: &mut PageTable, virt: VirtAddr, phys:
         return Err("Physical address must be aligned!");
     }
 
-    log::trace!("Mapping {} -> {:#x}", virt, phys);
+    log::trace!("Mapping {} -> {:#x} {:?}", virt, phys, flags);
 
+    let flags = leaf_flags(flags);
     let l3_page_table_entry = {
-        let l3_table = create_next_table(&mut l4_page_table[virt.p4_index()], page_tables_allocator, offset)?;
+        let l3_table = create_next_table(&mut l4_page_table[virt.p4_index()], page_tables_allocator, flags, offset)?;
         l3_table.index_mut(virt.p3_index()) as *mut PageTableEntry
     };
 
     log::trace!("[mapper] got l3_page_table");
 
+    if (*l3_page_table_entry).is_huge() {
+        return huge_page_covers(&*l3_page_table_entry, HUGE_PAGE_1GB_SIZE, virt, phys);
+    }
+
     let l2_page_table_entry = {
-        let l2_table = create_next_table(&mut *l3_page_table_entry, page_tables_allocator, offset)?;
+        let l2_table = create_next_table(&mut *l3_page_table_entry, page_tables_allocator, flags, offset)?;
         l2_table.index_mut(virt.p2_index()) as *mut PageTableEntry
     };
 
     log::trace!("[mapper] got l2_page_table");
 
-    let l1_table = create_next_table(&mut *l2_page_table_entry, page_tables_allocator, offset)?;
+    if (*l2_page_table_entry).is_huge() {
+        return huge_page_covers(&*l2_page_table_entry, HUGE_PAGE_2MB_SIZE, virt, phys);
+    }
+
+    let l1_table = create_next_table(&mut *l2_page_table_entry, page_tables_allocator, flags, offset)?;
 
     log::trace!("[mapper] got l1_page_table");
 
@@ -201,38 +266,275 @@ unsafe fn map_address_impl(l4_page_table: &mut PageTable, virt: VirtAddr, phys:
     log::trace!("[mapper] got l1_entry {:#x}", l1_entry as *const _ as u64);
     return if l1_entry.flags().contains(PageTableFlags::PRESENT) {
         if l1_entry.addr() == phys {
-            log::info!("[mapper] addr {} already mapped to the same physical address. doing nothing.", virt);
+            // e.g. two ELF segments sharing a page: the page gets the permissions of both
+            let old_flags = l1_entry.flags();
+            let merged = (old_flags | flags) - ((old_flags ^ flags) & PageTableFlags::NO_EXECUTE);
+            if merged != old_flags {
+                l1_entry.set_addr(phys, merged);
+                invalidate_page(virt);
+            }
+            log::debug!("[mapper] addr {} already mapped to the same physical address. flags: {:?}", virt, merged);
             return Ok(());
         }
 
         match mapping_mode {
             MappingMode::CheckFrameIsFree => Err("this virtual address already mapped to another frame"),
             MappingMode::Remapping => {
-                l1_entry.set_addr(phys, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
-                asm!("invlpg [{}]", in(reg) phys, options(nostack, preserves_flags));
+                l1_entry.set_addr(phys, flags);
+                invalidate_page(virt);
                 Ok(())
             }
         }
     } else {
-        l1_entry.set_addr(phys, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
-        asm!("invlpg [{}]", in(reg) phys, options(nostack, preserves_flags));
+        l1_entry.set_addr(phys, flags);
+        invalidate_page(virt);
+        Ok(())
+    }
+}
+
+/// Drops the TLB entry of the page containing `virt` on the current CPU.
+#[inline]
+pub fn invalidate_page(virt: VirtAddr) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) virt.0, options(nostack, preserves_flags));
+    }
+}
+
+/// Drops all non-global TLB entries on the current CPU by reloading CR3.
+#[inline]
+pub fn flush_tlb() {
+    unsafe {
+        asm!("mov {0}, cr3", "mov cr3, {0}", out(reg) _, options(nostack, preserves_flags));
+    }
+}
+
+/// Returns the present L1 entry mapping `virt`, None if it isn't mapped, or an error if it is
+/// mapped by a huge page.
+unsafe fn find_l1_entry<'a>(l4_page_table: &'a mut PageTable, virt: VirtAddr, offset: u64) -> Result<Option<&'a mut PageTableEntry>, &'static str> {
+    let mut table = l4_page_table;
+    for index in [virt.p4_index(), virt.p3_index(), virt.p2_index()] {
+        let entry = &mut table[index];
+        if !entry.is_present() {
+            return Ok(None);
+        }
+        if entry.is_huge() {
+            return Err("address is mapped by a huge page");
+        }
+        table = &mut *((entry.addr() + offset) as *mut PageTable);
+    }
+
+    let l1_entry = &mut table[virt.p1_index()];
+    Ok(if l1_entry.is_present() { Some(l1_entry) } else { None })
+}
+
+unsafe fn unmap_address_impl(l4_page_table: &mut PageTable, virt: VirtAddr, offset: u64) -> Result<u64, &'static str> {
+    if virt.0 % 4096 != 0 {
+        return Err("Virtual address must be aligned!");
+    }
+
+    let l1_entry = find_l1_entry(l4_page_table, virt, offset)?
+        .ok_or("this virtual address is not mapped")?;
+
+    log::trace!("Unmapping {} -> {:#x}", virt, l1_entry.addr());
+
+    let frame = l1_entry.addr();
+    *l1_entry = PageTableEntry::new();
+    invalidate_page(virt);
+    Ok(frame)
+}
+
+unsafe fn unmap_range_impl(l4_page_table: &mut PageTable, start: VirtAddr, pages: usize, offset: u64, mut unmapped: impl FnMut(VirtAddr, u64))
+                           -> Result<usize, &'static str> {
+    if start.0 % 4096 != 0 {
+        return Err("Virtual address must be aligned!");
+    }
+
+    let mut count = 0;
+    for i in 0..pages as u64 {
+        let virt = start.offset(i * PAGE_SIZE)?;
+        let Some(l1_entry) = find_l1_entry(l4_page_table, virt, offset)? else {
+            continue;
+        };
+
+        let frame = l1_entry.addr();
+        *l1_entry = PageTableEntry::new();
+        count += 1;
+        unmapped(virt, frame);
+    }
+
+    // one flush is cheaper than invalidating a long range page by page
+    if count > 32 {
+        flush_tlb();
+    } else {
+        for i in 0..pages as u64 {
+            invalidate_page(VirtAddr::new(start.0 + i * PAGE_SIZE));
+        }
+    }
+    Ok(count)
+}
+
+/// A 4 KiB mapping inside an existing huge page is fine if the huge page already maps `virt`
+/// to `phys`, e.g. MMIO inside the physical memory window.
+fn huge_page_covers(entry: &PageTableEntry, size: u64, virt: VirtAddr, phys: u64) -> Result<(), &'static str> {
+    if entry.addr() & !(size - 1) == phys & !(size - 1) && virt.0 & (size - 1) == phys & (size - 1) {
         Ok(())
+    } else {
+        Err("this virtual address already mapped by a huge page to another frame")
     }
 }
 
-pub unsafe fn map_address(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, page_tables_allocator: &mut impl PageTablesAllocator)
+/// Maps the huge page at `virt` in the table at `level` (3 for 1 GiB pages, 2 for 2 MiB pages).
+unsafe fn map_huge_impl(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, flags: PageTableFlags, page_tables_allocator: &mut impl PageTablesAllocator, level: u8, offset: u64)
+                        -> core::result::Result<(), &'static str> {
+    let size = if level == 3 { HUGE_PAGE_1GB_SIZE } else { HUGE_PAGE_2MB_SIZE };
+    if virt.0 % size != 0 || phys % size != 0 {
+        return Err("Huge page addresses must be aligned to the page size!");
+    }
+
+    log::trace!("Mapping huge page {} -> {:#x} {:?}", virt, phys, flags);
+
+    let flags = leaf_flags(flags);
+    let l3_page_table_entry = {
+        let l3_table = create_next_table(&mut l4_page_table[virt.p4_index()], page_tables_allocator, flags, offset)?;
+        l3_table.index_mut(virt.p3_index()) as *mut PageTableEntry
+    };
+
+    let entry = if level == 3 {
+        &mut *l3_page_table_entry
+    } else {
+        let l2_table = create_next_table(&mut *l3_page_table_entry, page_tables_allocator, flags, offset)?;
+        &mut l2_table[virt.p2_index()]
+    };
+
+    if entry.is_present() {
+        return if entry.is_huge() && entry.addr() == phys {
+            Ok(())
+        } else {
+            Err("this virtual address already mapped")
+        };
+    }
+
+    entry.set_addr(phys, flags | PageTableFlags::HUGE_PAGE);
+    invalidate_page(virt);
+    Ok(())
+}
+
+/// Maps a 2 MiB page. Page tables are accessed through `offset`.
+pub unsafe fn map_huge_2mb(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, flags: PageTableFlags, page_tables_allocator: &mut impl PageTablesAllocator, offset: u64)
+                           -> core::result::Result<(), &'static str> {
+    map_huge_impl(l4_page_table, virt, phys, flags, page_tables_allocator, 2, offset)
+}
+
+/// Maps a 1 GiB page, the CPU must support them (CPUID 0x80000001, EDX bit 26).
+/// Page tables are accessed through `offset`.
+pub unsafe fn map_huge_1gb(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, flags: PageTableFlags, page_tables_allocator: &mut impl PageTablesAllocator, offset: u64)
+                           -> core::result::Result<(), &'static str> {
+    map_huge_impl(l4_page_table, virt, phys, flags, page_tables_allocator, 3, offset)
+}
+
+/// Maps the 4 KiB page `virt` to `phys` with `flags`, `PRESENT` is implied. Mapping a page
+/// again to the same frame merges the permissions.
+pub unsafe fn map_address(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, flags: PageTableFlags, page_tables_allocator: &mut impl PageTablesAllocator)
                           -> core::result::Result<(), &'static str> {
-    map_address_impl(l4_page_table, virt, phys, page_tables_allocator, MappingMode::CheckFrameIsFree, 0)
+    map_address_impl(l4_page_table, virt, phys, flags, page_tables_allocator, MappingMode::CheckFrameIsFree, 0)
 }
 
-pub unsafe fn remap_address(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, page_tables_allocator: &mut impl PageTablesAllocator)
+pub unsafe fn remap_address(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, flags: PageTableFlags, page_tables_allocator: &mut impl PageTablesAllocator)
                             -> core::result::Result<(), &'static str> {
-    map_address_impl(l4_page_table, virt, phys, page_tables_allocator, MappingMode::Remapping, 0)
+    map_address_impl(l4_page_table, virt, phys, flags, page_tables_allocator, MappingMode::Remapping, 0)
 }
 
-pub unsafe fn map_address_with_offset(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, page_tables_allocator: &mut impl PageTablesAllocator, offset: u64)
+pub unsafe fn map_address_with_offset(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, flags: PageTableFlags, page_tables_allocator: &mut impl PageTablesAllocator, offset: u64)
                           -> core::result::Result<(), &'static str> {
-    map_address_impl(l4_page_table, virt, phys, page_tables_allocator, MappingMode::CheckFrameIsFree, offset)
+    map_address_impl(l4_page_table, virt, phys, flags, page_tables_allocator, MappingMode::CheckFrameIsFree, offset)
+}
+
+/// Removes the 4 KiB mapping of `virt` and flushes it from the TLB of the current CPU.
+/// Returns the frame that backed it, the caller decides whether to free it.
+pub unsafe fn unmap_address(l4_page_table: &mut PageTable, virt: VirtAddr) -> core::result::Result<u64, &'static str> {
+    unmap_address_impl(l4_page_table, virt, 0)
+}
+
+/// Same as `unmap_address`, page tables are accessed through `offset`.
+pub unsafe fn unmap_address_with_offset(l4_page_table: &mut PageTable, virt: VirtAddr, offset: u64) -> core::result::Result<u64, &'static str> {
+    unmap_address_impl(l4_page_table, virt, offset)
+}
+
+/// Removes the mappings of `pages` pages from `start`, skipping pages that are not mapped.
+/// `unmapped` is called with each page and the frame that backed it. Returns the number of
+/// unmapped pages; huge pages in the range are an error.
+pub unsafe fn unmap_range(l4_page_table: &mut PageTable, start: VirtAddr, pages: usize, unmapped: impl FnMut(VirtAddr, u64))
+                          -> core::result::Result<usize, &'static str> {
+    unmap_range_impl(l4_page_table, start, pages, 0, unmapped)
+}
+
+/// Same as `unmap_range`, page tables are accessed through `offset`.
+pub unsafe fn unmap_range_with_offset(l4_page_table: &mut PageTable, start: VirtAddr, pages: usize, offset: u64, unmapped: impl FnMut(VirtAddr, u64))
+                                      -> core::result::Result<usize, &'static str> {
+    unmap_range_impl(l4_page_table, start, pages, offset, unmapped)
+}
+
+/// Maps `virt` to `phys` copy-on-write: read-only with `COPY_ON_WRITE` set, `WRITABLE` in
+/// `flags` is what the page gets back once `break_cow` gives it a private frame. An existing
+/// mapping of `virt` to the same frame is turned into a COW one. Page tables are accessed
+/// through `offset`.
+pub unsafe fn map_cow(l4_page_table: &mut PageTable, virt: VirtAddr, phys: u64, flags: PageTableFlags, page_tables_allocator: &mut impl PageTablesAllocator, offset: u64)
+                      -> core::result::Result<(), &'static str> {
+    let cow_flags = (flags - PageTableFlags::WRITABLE) | PageTableFlags::COPY_ON_WRITE;
+
+    if let Some(l1_entry) = find_l1_entry(l4_page_table, virt, offset)? {
+        if l1_entry.addr() != phys {
+            return Err("this virtual address already mapped to another frame");
+        }
+        l1_entry.set_addr(phys, leaf_flags(cow_flags));
+        invalidate_page(virt);
+        return Ok(());
+    }
+
+    map_address_impl(l4_page_table, virt, phys, cow_flags, page_tables_allocator, MappingMode::CheckFrameIsFree, offset)
+}
+
+/// The shared frame of the COW page `virt`, None if `virt` isn't mapped copy-on-write.
+pub unsafe fn cow_frame(l4_page_table: &mut PageTable, virt: VirtAddr, offset: u64) -> core::result::Result<Option<u64>, &'static str> {
+    let l1_entry = find_l1_entry(l4_page_table, VirtAddr::new(align_down_u64(virt.0)), offset)?;
+    Ok(l1_entry.filter(|entry| entry.flags().contains(PageTableFlags::COPY_ON_WRITE)).map(|entry| entry.addr()))
+}
+
+/// Makes the COW page `virt` writable again, backed by `frame`. A `frame` other than the shared
+/// one gets a copy of its contents first; passing the shared frame keeps it, for the last user.
+/// Both frames are accessed through `offset`.
+pub unsafe fn break_cow(l4_page_table: &mut PageTable, virt: VirtAddr, frame: u64, offset: u64) -> core::result::Result<(), &'static str> {
+    let virt = VirtAddr::new(align_down_u64(virt.0));
+    let l1_entry = find_l1_entry(l4_page_table, virt, offset)?
+        .filter(|entry| entry.flags().contains(PageTableFlags::COPY_ON_WRITE))
+        .ok_or("this virtual address is not mapped copy-on-write")?;
+
+    let shared = l1_entry.addr();
+    if frame != shared {
+        core::ptr::copy_nonoverlapping((shared + offset) as *const u8, (frame + offset) as *mut u8, PAGE_SIZE as usize);
+    }
+
+    let flags = (l1_entry.flags() - PageTableFlags::COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    l1_entry.set_addr(frame, flags);
+    invalidate_page(virt);
+    Ok(())
+}
+
+/// Clears the dirty bit of the page `virt`, returns whether the page was written to since the
+/// last call. False if `virt` isn't mapped. Page tables are accessed through `offset`.
+pub unsafe fn take_dirty(l4_page_table: &mut PageTable, virt: VirtAddr, offset: u64) -> core::result::Result<bool, &'static str> {
+    let virt = VirtAddr::new(align_down_u64(virt.0));
+    let Some(l1_entry) = find_l1_entry(l4_page_table, virt, offset)? else {
+        return Ok(false);
+    };
+
+    let flags = l1_entry.flags();
+    if !flags.contains(PageTableFlags::DIRTY) {
+        return Ok(false);
+    }
+    l1_entry.set_addr(l1_entry.addr(), flags - PageTableFlags::DIRTY);
+    invalidate_page(virt);
+    Ok(true)
 }
 
 pub unsafe fn get_physical_address(l4_page_table: &PageTable, virt: VirtAddr) -> Option<u64> {
@@ -246,12 +548,19 @@ pub unsafe fn get_physical_address(l4_page_table: &PageTable, virt: VirtAddr) ->
     if !l3_entry.flags().contains(PageTableFlags::PRESENT) {
         return None;
     }
+    if l3_entry.is_huge() {
+        // the frame of the 4 KiB page inside the huge page
+        return Some((l3_entry.addr() & !(HUGE_PAGE_1GB_SIZE - 1)) + (virt.0 & (HUGE_PAGE_1GB_SIZE - 1) & !(PAGE_SIZE - 1)));
+    }
 
     let l2_table = & *(l3_entry.addr() as *const PageTable);
     let l2_entry = l2_table[virt.p2_index()];
     if !l2_entry.flags().contains(PageTableFlags::PRESENT) {
         return None;
     }
+    if l2_entry.is_huge() {
+        return Some((l2_entry.addr() & !(HUGE_PAGE_2MB_SIZE - 1)) + (virt.0 & (HUGE_PAGE_2MB_SIZE - 1) & !(PAGE_SIZE - 1)));
+    }
 
     let l1_table = & *(l2_entry.addr() as *const PageTable);
     let l1_entry = l1_table[virt.p1_index()];
@@ -268,4 +577,84 @@ pub fn align_down(val: VirtAddr) -> VirtAddr {
 
 pub fn align_down_u64(val: u64) -> u64 {
     return val & 0xffff_ffff_ffff_f000;
-}
\ No newline at end of file
+}
+#[test_case]
+fn map_and_unmap_test() {
+    // frames for the L3, L2 and L1 tables
+    let (_, mut allocator) = crate::frame_allocator::test_memory(3);
+
+    let mut l4 = PageTable::new();
+    let virt = VirtAddr::new(0x5555_0000_0000);
+    let page = |i: u64| VirtAddr::new(virt.0 + i * PAGE_SIZE);
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    unsafe {
+        map_address(&mut l4, page(0), 0x1000_0000, flags, &mut allocator).unwrap();
+        map_address(&mut l4, page(2), 0x1000_2000, flags, &mut allocator).unwrap();
+        assert_eq!(Some(0x1000_2000), get_physical_address(&l4, page(2)));
+
+        assert_eq!(Ok(0x1000_2000), unmap_address(&mut l4, page(2)));
+        assert_eq!(None, get_physical_address(&l4, page(2)));
+        assert!(unmap_address(&mut l4, page(2)).is_err());
+
+        map_address(&mut l4, page(3), 0x1000_3000, flags, &mut allocator).unwrap();
+        let mut frames = 0;
+        assert_eq!(Ok(2), unmap_range(&mut l4, virt, 4, |_, frame| frames += frame));
+        assert_eq!(0x1000_0000 + 0x1000_3000, frames);
+        assert_eq!(None, get_physical_address(&l4, page(0)));
+    }
+}
+
+#[test_case]
+fn copy_on_write_test() {
+    // three frames for the tables, a shared frame and the private copy
+    let (_, mut allocator) = crate::frame_allocator::test_memory(5);
+    let (shared, private) = (allocator.allocate_frame().unwrap(), allocator.allocate_frame().unwrap());
+
+    let mut l4 = PageTable::new();
+    let virt = VirtAddr::new(0x5556_0000_0000);
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    unsafe {
+        *(shared as *mut u64) = 0xC0FFEE;
+
+        map_cow(&mut l4, virt, shared, flags, &mut allocator, 0).unwrap();
+        assert_eq!(Ok(Some(shared)), cow_frame(&mut l4, VirtAddr::new(virt.0 + 8), 0));
+        let entry = find_l1_entry(&mut l4, virt, 0).unwrap().unwrap();
+        assert!(!entry.flags().contains(PageTableFlags::WRITABLE));
+        assert!(map_cow(&mut l4, virt, private, flags, &mut allocator, 0).is_err());
+
+        break_cow(&mut l4, virt, private, 0).unwrap();
+        assert_eq!(0xC0FFEE, *(private as *const u64));
+        assert_eq!(Some(private), get_physical_address(&l4, virt));
+        assert_eq!(Ok(None), cow_frame(&mut l4, virt, 0));
+        let entry = find_l1_entry(&mut l4, virt, 0).unwrap().unwrap();
+        assert!(entry.flags().contains(PageTableFlags::WRITABLE));
+        assert!(break_cow(&mut l4, virt, private, 0).is_err());
+    }
+}
+
+#[test_case]
+fn take_dirty_test() {
+    // the tables below the PML4
+    let (_, mut allocator) = crate::frame_allocator::test_memory(3);
+
+    let mut l4 = PageTable::new();
+    let virt = VirtAddr::new(0x5557_0000_0000);
+    let frame = 0x1000_0000;
+
+    unsafe {
+        assert_eq!(Ok(false), take_dirty(&mut l4, virt, 0));
+        map_address(&mut l4, virt, frame, PageTableFlags::WRITABLE, &mut allocator).unwrap();
+        assert_eq!(Ok(false), take_dirty(&mut l4, virt, 0));
+
+        // the CPU sets the dirty bit, fake it
+        let entry = find_l1_entry(&mut l4, virt, 0).unwrap().unwrap();
+        entry.set_addr(frame, entry.flags() | PageTableFlags::DIRTY);
+        assert_eq!(Ok(true), take_dirty(&mut l4, VirtAddr::new(virt.0 + 8), 0));
+        assert_eq!(Ok(false), take_dirty(&mut l4, virt, 0));
+        let entry = find_l1_entry(&mut l4, virt, 0).unwrap().unwrap();
+        assert!(entry.flags().contains(PageTableFlags::WRITABLE));
+        assert_eq!(Some(frame), get_physical_address(&l4, virt));
+    }
+}
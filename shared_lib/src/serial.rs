@@ -69,6 +69,15 @@ impl SerialPort {
         }
     }
 
+    /// Returns a received byte, if there is one.
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.line_sts().contains(LineStsFlags::INPUT_FULL) {
+            unsafe { Some(inb(self.0)) }
+        } else {
+            None
+        }
+    }
+
     fn line_sts(&mut self) -> LineStsFlags {
         unsafe { LineStsFlags::from_bits_truncate(inb(self.0 + 5)) }
     }
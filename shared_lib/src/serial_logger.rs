@@ -1,10 +1,10 @@
 use core::fmt;
 use core::fmt::{Arguments, Write};
 use conquer_once::spin::OnceCell;
-use spinning_top::{RawSpinlock, Spinlock};
-use spinning_top::lock_api::MutexGuard;
+use crate::spinlock::{Spinlock, SpinlockGuard};
 use crate::interrupts;
 use crate::serial::SerialPort;
+use crate::time_page::UptimePrefix;
 
 pub struct SerialLogger {
     port: SerialPort
@@ -42,7 +42,7 @@ impl LockedSerialLogger {
         LockedSerialLogger(Spinlock::new(SerialLogger::new()))
     }
 
-    pub fn lock(&self) -> MutexGuard<'_, RawSpinlock, SerialLogger> {
+    pub fn lock(&self) -> SpinlockGuard<'_, SerialLogger> {
         self.0.lock()
     }
 
@@ -68,7 +68,7 @@ impl log::Log for LockedSerialLogger {
     fn log(&self, record: &log::Record) {
         interrupts::without_interrupts(|| {
             let mut logger = self.0.lock();
-            writeln!(logger, "{}:    {}", record.level(), record.args()).unwrap();
+            writeln!(logger, "{}{}:    {}", UptimePrefix, record.level(), record.args()).unwrap();
         });
     }
 
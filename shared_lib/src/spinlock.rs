@@ -0,0 +1,183 @@
+// Kernel spinlock type.
+//
+// With the `lock_debug` feature every `Spinlock` becomes a `DebugRawSpinlock`, which tracks the
+// lock owner, panics on re-entrant acquisition, warns when a lock that was taken with interrupts
+// disabled (i.e. one shared with interrupt handlers) is taken with interrupts enabled, and
+// reports locks held for suspiciously long.
+//
+// Every lock counts itself in `HELD` while it is held. The scheduler doesn't preempt a thread
+// while the count isn't zero: a thread switched out holding a lock would leave the next one
+// spinning on it, forever if that one spins with interrupts disabled. The count covers all CPUs,
+// a lock held on another one only delays the switch.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spinning_top::lock_api::{self, RawMutex};
+
+#[cfg(not(feature = "lock_debug"))]
+pub type RawKernelSpinlock = Counted<spinning_top::RawSpinlock>;
+#[cfg(feature = "lock_debug")]
+pub type RawKernelSpinlock = Counted<debug::DebugRawSpinlock>;
+
+pub type Spinlock<T> = lock_api::Mutex<RawKernelSpinlock, T>;
+pub type SpinlockGuard<'a, T> = lock_api::MutexGuard<'a, RawKernelSpinlock, T>;
+pub type MappedSpinlockGuard<'a, T> = lock_api::MappedMutexGuard<'a, RawKernelSpinlock, T>;
+
+/// Spinlocks held right now, on any CPU
+static HELD: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether any spinlock is held, preemption waits until none is
+pub fn any_held() -> bool {
+    HELD.load(Ordering::Relaxed) != 0
+}
+
+/// Raw lock `R` counted in `HELD`. Inlined, so the call site `lock_debug` records stays the
+/// caller's.
+pub struct Counted<R>(R);
+
+unsafe impl<R: RawMutex> RawMutex for Counted<R> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Counted(R::INIT);
+
+    type GuardMarker = R::GuardMarker;
+
+    #[inline(always)]
+    fn lock(&self) {
+        self.0.lock();
+        HELD.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    fn try_lock(&self) -> bool {
+        let locked = self.0.try_lock();
+        if locked {
+            HELD.fetch_add(1, Ordering::Relaxed);
+        }
+        locked
+    }
+
+    #[inline(always)]
+    unsafe fn unlock(&self) {
+        HELD.fetch_sub(1, Ordering::Relaxed);
+        self.0.unlock();
+    }
+
+    fn is_locked(&self) -> bool {
+        self.0.is_locked()
+    }
+}
+
+#[cfg(feature = "lock_debug")]
+pub mod debug {
+    use core::arch::x86_64::__cpuid;
+    use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+    use spinning_top::lock_api::{GuardSend, RawMutex};
+    use crate::get_tsc;
+    use crate::interrupts::are_enabled;
+    use crate::serial_println;
+    use crate::stack_trace::collect_return_addresses;
+
+    /// Locks held longer than this number of TSC ticks are reported.
+    const HOLD_TIME_OUTLIER: u64 = 50_000_000;
+
+    const NO_OWNER: u32 = u32::MAX;
+
+    fn current_cpu() -> u32 {
+        // initial APIC id of the executing processor
+        unsafe { __cpuid(1).ebx >> 24 }
+    }
+
+    fn caller() -> u64 {
+        let mut site = [0u64; 1];
+        collect_return_addresses(1, &mut site);
+        site[0]
+    }
+
+    pub struct DebugRawSpinlock {
+        locked: AtomicBool,
+        owner_cpu: AtomicU32,
+        owner_site: AtomicU64,
+        acquired_at: AtomicU64,
+        irq_shared: AtomicBool,
+        irq_warned: AtomicBool,
+    }
+
+    impl DebugRawSpinlock {
+        fn try_acquire(&self) -> bool {
+            self.locked
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        }
+
+        fn set_owner(&self, site: u64) {
+            self.owner_cpu.store(current_cpu(), Ordering::Relaxed);
+            self.owner_site.store(site, Ordering::Relaxed);
+            self.acquired_at.store(get_tsc(), Ordering::Relaxed);
+        }
+
+        fn check_irq_safety(&self, site: u64) {
+            if !are_enabled() {
+                self.irq_shared.store(true, Ordering::Relaxed);
+            } else if self.irq_shared.load(Ordering::Relaxed)
+                && !self.irq_warned.swap(true, Ordering::Relaxed) {
+                serial_println!("[lock_debug] lock {:p} is shared with interrupt handlers, but taken with interrupts enabled at {:#x}",
+                    self, site);
+            }
+        }
+    }
+
+    unsafe impl RawMutex for DebugRawSpinlock {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const INIT: Self = DebugRawSpinlock {
+            locked: AtomicBool::new(false),
+            owner_cpu: AtomicU32::new(NO_OWNER),
+            owner_site: AtomicU64::new(0),
+            acquired_at: AtomicU64::new(0),
+            irq_shared: AtomicBool::new(false),
+            irq_warned: AtomicBool::new(false),
+        };
+
+        type GuardMarker = GuardSend;
+
+        fn lock(&self) {
+            let site = caller();
+            self.check_irq_safety(site);
+
+            while !self.try_acquire() {
+                if self.owner_cpu.load(Ordering::Relaxed) == current_cpu() {
+                    panic!("[lock_debug] re-entrant acquisition of lock {:p} at {:#x}, already held since {:#x}",
+                        self, site, self.owner_site.load(Ordering::Relaxed));
+                }
+                core::hint::spin_loop();
+            }
+            self.set_owner(site);
+        }
+
+        fn try_lock(&self) -> bool {
+            let site = caller();
+            self.check_irq_safety(site);
+
+            if self.try_acquire() {
+                self.set_owner(site);
+                true
+            } else {
+                false
+            }
+        }
+
+        unsafe fn unlock(&self) {
+            let held_for = get_tsc().wrapping_sub(self.acquired_at.load(Ordering::Relaxed));
+            let site = self.owner_site.load(Ordering::Relaxed);
+
+            self.owner_cpu.store(NO_OWNER, Ordering::Relaxed);
+            self.locked.store(false, Ordering::Release);
+
+            if held_for > HOLD_TIME_OUTLIER {
+                serial_println!("[lock_debug] lock {:p} taken at {:#x} was held for {} TSC ticks", self, site, held_for);
+            }
+        }
+
+        fn is_locked(&self) -> bool {
+            self.locked.load(Ordering::Relaxed)
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use core::arch::asm;
+
+/// Maximum distance between two consecutive stack frames that we still trust.
+/// Anything bigger means the frame pointer chain is broken.
+const MAX_FRAME_SIZE: u64 = 64 * 1024;
+
+/// Returns the frame pointer (`rbp`) of the calling function.
+///
+/// Only meaningful when the code is compiled with frame pointers.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+/// Walks the frame pointer chain starting at `rbp` and calls `f` with the return address of
+/// every frame. Walking stops when `f` returns `false` or when the chain looks broken.
+///
+/// The entry point of the kernel has a zero return address, so the walk ends there.
+pub unsafe fn walk_stack<F: FnMut(u64) -> bool>(mut rbp: u64, mut f: F) {
+    while rbp != 0 && rbp % 8 == 0 {
+        let return_address = *((rbp + 8) as *const u64);
+        if return_address == 0 || !f(return_address) {
+            break;
+        }
+
+        let next = *(rbp as *const u64);
+        if next <= rbp || next - rbp > MAX_FRAME_SIZE {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+/// Fills `out` with return addresses of the calling function's callers, skipping the
+/// `skip` innermost frames. Returns the number of collected addresses.
+#[inline(always)]
+pub fn collect_return_addresses(skip: usize, out: &mut [u64]) -> usize {
+    let mut skipped = 0;
+    let mut count = 0;
+    unsafe {
+        walk_stack(frame_pointer(), |addr| {
+            if skipped < skip {
+                skipped += 1;
+                return true;
+            }
+            if count == out.len() {
+                return false;
+            }
+            out[count] = addr;
+            count += 1;
+            true
+        });
+    }
+    count
+}
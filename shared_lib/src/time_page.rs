@@ -0,0 +1,110 @@
+// Kernel time-of-day page.
+//
+// A page-aligned snapshot of (timer ticks, uptime, wall time) published by the timer interrupt and
+// read without locks by the loggers, the console status bar and, eventually, user space through a
+// read-only mapping. Consistency is kept with a sequence counter: the writer makes it odd while
+// updating, readers retry if it was odd or changed while they were reading.
+
+use core::hint::spin_loop;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+/// A reader gives up after this many torn reads, e.g. an NMI that interrupted the writer
+const MAX_READ_RETRIES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSnapshot {
+    /// Timer interrupts since boot
+    pub ticks: u64,
+    /// Milliseconds since the timer was started
+    pub uptime_ms: u64,
+    /// Seconds since the Unix epoch, 0 if the wall clock isn't known yet
+    pub wall_secs: u64,
+}
+
+#[repr(C, align(4096))]
+pub struct TimePage {
+    seq: AtomicU64,
+    ticks: AtomicU64,
+    uptime_ms: AtomicU64,
+    wall_secs: AtomicU64,
+}
+
+pub static TIME_PAGE: TimePage = TimePage::new();
+
+impl TimePage {
+    const fn new() -> Self {
+        TimePage {
+            seq: AtomicU64::new(0),
+            ticks: AtomicU64::new(0),
+            uptime_ms: AtomicU64::new(0),
+            wall_secs: AtomicU64::new(0),
+        }
+    }
+
+    /// Publishes a new snapshot. There must be a single writer, the timer interrupt handler.
+    pub fn publish(&self, snapshot: TimeSnapshot) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        self.ticks.store(snapshot.ticks, Ordering::Relaxed);
+        self.uptime_ms.store(snapshot.uptime_ms, Ordering::Relaxed);
+        self.wall_secs.store(snapshot.wall_secs, Ordering::Relaxed);
+
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Returns the latest snapshot, or None if nothing was published yet or the writer was
+    /// interrupted in the middle of an update by the caller.
+    pub fn snapshot(&self) -> Option<TimeSnapshot> {
+        for _ in 0..MAX_READ_RETRIES {
+            let start = self.seq.load(Ordering::Acquire);
+            if start == 0 {
+                return None;
+            }
+            if start & 1 != 0 {
+                spin_loop();
+                continue;
+            }
+
+            let snapshot = TimeSnapshot {
+                ticks: self.ticks.load(Ordering::Relaxed),
+                uptime_ms: self.uptime_ms.load(Ordering::Relaxed),
+                wall_secs: self.wall_secs.load(Ordering::Relaxed),
+            };
+
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == start {
+                return Some(snapshot);
+            }
+        }
+        None
+    }
+}
+
+/// Log line prefix with the uptime, e.g. `[   12.345] `. Empty before the timer is started.
+pub struct UptimePrefix;
+
+impl core::fmt::Display for UptimePrefix {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match TIME_PAGE.snapshot() {
+            Some(time) => write!(f, "[{:>5}.{:03}] ", time.uptime_ms / 1000, time.uptime_ms % 1000),
+            None => Ok(()),
+        }
+    }
+}
+
+#[test_case]
+fn time_page_publish_and_read_test() {
+    let page = TimePage::new();
+    assert_eq!(page.snapshot(), None);
+
+    let time = TimeSnapshot { ticks: 250, uptime_ms: 1000, wall_secs: 1_700_000_000 };
+    page.publish(time);
+    assert_eq!(page.snapshot(), Some(time));
+    assert_eq!(page.seq.load(Ordering::Relaxed), 2);
+
+    // a writer that never finished
+    page.seq.store(3, Ordering::Relaxed);
+    assert_eq!(page.snapshot(), None);
+}
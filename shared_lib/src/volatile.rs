@@ -0,0 +1,169 @@
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ptr::{read_volatile, write_volatile};
+use crate::addr::VirtAddr;
+
+/// A memory location which is always accessed with volatile reads and writes.
+#[repr(transparent)]
+pub struct VolatileCell<T: Copy> {
+    value: UnsafeCell<T>,
+}
+
+impl<T: Copy> VolatileCell<T> {
+    pub const fn new(value: T) -> Self {
+        VolatileCell { value: UnsafeCell::new(value) }
+    }
+
+    #[inline]
+    pub fn get(&self) -> T {
+        unsafe { read_volatile(self.value.get()) }
+    }
+
+    #[inline]
+    pub fn set(&self, value: T) {
+        unsafe { write_volatile(self.value.get(), value) }
+    }
+
+    /// Read-modify-write of the cell. Not atomic.
+    #[inline]
+    pub fn update<F: FnOnce(T) -> T>(&self, f: F) {
+        self.set(f(self.get()));
+    }
+}
+
+/// A register of type `T` inside the register block `B`.
+///
+/// Registers are created by the [`register_block!`] macro, which checks offset alignment and
+/// block bounds at compile time.
+pub struct Register<B, T> {
+    offset: usize,
+    phantom: PhantomData<(B, T)>,
+}
+
+impl<B, T> Register<B, T> {
+    #[doc(hidden)]
+    pub const fn new(offset: usize) -> Self {
+        Register { offset, phantom: PhantomData }
+    }
+
+    #[inline]
+    pub const fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<B, T> Clone for Register<B, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<B, T> Copy for Register<B, T> {}
+
+/// A register block type declared by [`register_block!`].
+pub trait RegisterBlock {
+    /// Size of the register block in bytes.
+    const SIZE: usize;
+}
+
+/// A mapped instance of the register block `B`.
+pub struct Mmio<B> {
+    base: VirtAddr,
+    phantom: PhantomData<B>,
+}
+
+impl<B> Mmio<B> {
+    /// Creates an accessor for the register block mapped at `base`.
+    ///
+    /// # Safety
+    /// The caller must guarantee that the whole block is mapped at `base` for as long as
+    /// the accessor (or a copy of it) is used.
+    #[inline]
+    pub const unsafe fn new(base: VirtAddr) -> Self {
+        Mmio { base, phantom: PhantomData }
+    }
+
+    #[inline]
+    pub const fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    #[inline]
+    pub fn cell<T: Copy>(&self, reg: Register<B, T>) -> &VolatileCell<T> {
+        // SAFETY: `new` guarantees the block is mapped and `register_block!` guarantees the
+        // register is aligned and inside of the block.
+        unsafe { &*((self.base.0 as usize + reg.offset) as *const VolatileCell<T>) }
+    }
+
+    #[inline]
+    pub fn read<T: Copy>(&self, reg: Register<B, T>) -> T {
+        self.cell(reg).get()
+    }
+
+    #[inline]
+    pub fn write<T: Copy>(&self, reg: Register<B, T>, value: T) {
+        self.cell(reg).set(value)
+    }
+
+    #[inline]
+    pub fn update<T: Copy, F: FnOnce(T) -> T>(&self, reg: Register<B, T>, f: F) {
+        self.cell(reg).update(f)
+    }
+}
+
+impl<B> Clone for Mmio<B> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<B> Copy for Mmio<B> {}
+
+/// Declares a register block: a marker type with a known size and a set of typed registers
+/// at fixed byte offsets inside of it.
+///
+/// ```ignore
+/// register_block! {
+///     pub struct IoApicRegs[0x20] {
+///         IOREGSEL: u32 = 0x00,
+///         IOWIN: u32 = 0x10,
+///     }
+/// }
+///
+/// let io_apic = unsafe { Mmio::<IoApicRegs>::new(addr) };
+/// io_apic.write(IoApicRegs::IOREGSEL, 0x1);
+/// ```
+#[macro_export]
+macro_rules! register_block {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident[$size:expr] {
+            $($(#[$reg_attr:meta])* $reg:ident: $ty:ty = $offset:expr),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis struct $name;
+
+        #[allow(dead_code)]
+        impl $name {
+            /// Size of the register block in bytes.
+            pub const SIZE: usize = $size;
+
+            $(
+                $(#[$reg_attr])*
+                pub const $reg: $crate::volatile::Register<$name, $ty> = $crate::volatile::Register::new($offset);
+            )*
+        }
+
+        impl $crate::volatile::RegisterBlock for $name {
+            const SIZE: usize = $size;
+        }
+
+        $(
+            const _: () = {
+                assert!($offset % core::mem::align_of::<$ty>() == 0, "misaligned register");
+                assert!($offset + core::mem::size_of::<$ty>() <= $size, "register is out of the block bounds");
+            };
+        )*
+    };
+}
@@ -1,11 +1,26 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
 use shared_lib::addr::VirtAddr;
 use shared_lib::allocator::ALLOCATOR;
-use shared_lib::page_table::{map_address_with_offset, PageTable};
-use shared_lib::VIRT_MAPPING_OFFSET;
-use shared_lib::frame_allocator::FrameAllocator;
+use shared_lib::page_table::{align_down_u64, map_address_with_offset, PageTable, PageTableFlags};
+use shared_lib::spinlock::Spinlock;
+use shared_lib::phys_mapping_offset;
+use shared_lib::frame_allocator::{FrameAllocator, Zone};
+use crate::memory::active_level_4_table;
+use crate::vm;
 
 pub const HEAP_START: usize = 0x_7777_7777_0000;
-pub const HEAP_SIZE: usize = 300 * 1024; // 300 KiB
+pub const HEAP_SIZE: usize = 300 * 1024; // 300 KiB, mapped eagerly
+/// Default ceiling for the heap once it is allowed to grow
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
+
+const HEAP_PAGE_FLAGS: PageTableFlags = PageTableFlags::WRITABLE.union(PageTableFlags::NO_EXECUTE);
+
+/// Frames for the heap growth are taken from here after `enable_heap_growth`
+pub static FRAME_ALLOCATOR: Spinlock<Option<FrameAllocator>> = Spinlock::new(None);
+
+/// End of the virtual range the allocator may hand out, pages inside it are mapped on first access
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_START + HEAP_SIZE);
+static HEAP_MAPPED: AtomicUsize = AtomicUsize::new(0);
 
 pub fn init_heap(page_table: &mut PageTable, frame_allocator: &mut FrameAllocator) -> Result<(), &'static str> {
     let mut heap = VirtAddr::new(HEAP_START as u64);
@@ -17,16 +32,117 @@ pub fn init_heap(page_table: &mut PageTable, frame_allocator: &mut FrameAllocato
             .expect("Failed to allocate frame");
 
         unsafe {
-            map_address_with_offset(page_table, heap, frame, frame_allocator, VIRT_MAPPING_OFFSET)
+            map_address_with_offset(page_table, heap, frame, HEAP_PAGE_FLAGS, frame_allocator, phys_mapping_offset())
                 .expect("Failed to map new frame");
         }
 
         heap = heap.offset(4096).unwrap();
     }
+    HEAP_MAPPED.store(HEAP_SIZE, Ordering::Relaxed);
 
     unsafe {
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
     }
 
+    vm::reserve(VirtAddr::new(HEAP_START as u64), HEAP_SIZE as u64, HEAP_PAGE_FLAGS, "heap")
+        .map_err(|_| "the heap overlaps another VMA")?;
+
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Hands the frame allocator over to the page fault handler and lets the heap grow up to
+/// `max_size` bytes. Must be called after the IDT is loaded.
+pub fn enable_heap_growth(frame_allocator: FrameAllocator, max_size: usize) {
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    let max_size = max_size.next_multiple_of(4096);
+    if max_size <= HEAP_SIZE {
+        return;
+    }
+
+    if let Err(e) = vm::resize(VirtAddr::new(HEAP_START as u64), max_size as u64) {
+        log::warn!("The heap can't grow to {} KiB: {:?}", max_size / 1024, e);
+        return;
+    }
+
+    HEAP_LIMIT.store(HEAP_START + max_size, Ordering::Relaxed);
+    unsafe {
+        // the free block for the new range is written at the current top, which is the first fault
+        ALLOCATOR.lock().extend(max_size - HEAP_SIZE);
+    }
+}
+
+/// Maps a frame for a not-present fault inside the heap range. Returns false if `addr` is not
+/// a heap address or no frame could be mapped; the fault is fatal then.
+pub fn handle_heap_fault(addr: u64) -> bool {
+    if addr < HEAP_START as u64 || addr >= HEAP_LIMIT.load(Ordering::Relaxed) as u64 {
+        return false;
+    }
+
+    // the fault may hit while the frame allocator is held, e.g. by a buggy caller: don't deadlock
+    let Some(mut frame_allocator) = FRAME_ALLOCATOR.try_lock() else {
+        return false;
+    };
+    let Some(frame_allocator) = frame_allocator.as_mut() else {
+        return false;
+    };
+    let Some(frame) = frame_allocator.allocate_frame() else {
+        return false;
+    };
+
+    unsafe {
+        let page = VirtAddr::new(align_down_u64(addr));
+        if map_address_with_offset(active_level_4_table(), page, frame, HEAP_PAGE_FLAGS, frame_allocator, phys_mapping_offset()).is_err() {
+            return false;
+        }
+    }
+
+    HEAP_MAPPED.fetch_add(4096, Ordering::Relaxed);
+    true
+}
+
+/// Bytes of the heap backed by physical frames
+pub fn heap_mapped_size() -> usize {
+    HEAP_MAPPED.load(Ordering::Relaxed)
+}
+
+/// Current ceiling of the heap
+pub fn heap_max_size() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed) - HEAP_START
+}
+
+/// Allocates physically contiguous frames for DMA, see `FrameAllocator::alloc_contiguous`.
+/// Only available after `enable_heap_growth`.
+pub fn alloc_contiguous(frames: usize, align: usize) -> Option<u64> {
+    FRAME_ALLOCATOR.lock().as_mut()?.alloc_contiguous(frames, align)
+}
+
+/// Same as `alloc_contiguous`, for devices which can only address `zone`, e.g. `Zone::Dma32`
+/// for 32-bit DMA addresses
+pub fn alloc_contiguous_in_zone(frames: usize, align: usize, zone: Zone) -> Option<u64> {
+    FRAME_ALLOCATOR.lock().as_mut()?.alloc_contiguous_in_zone(frames, align, zone)
+}
+
+/// # Safety
+/// See `FrameAllocator::free_contiguous`.
+pub unsafe fn free_contiguous(base: u64, frames: usize) {
+    if let Some(frame_allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+        frame_allocator.free_contiguous(base, frames);
+    }
+}
+
+/// Verifies the heap redzones every `period_ms`, see `shared_lib::allocator::redzone`.
+#[cfg(feature = "heap_redzones")]
+pub async fn redzone_check_loop(period_ms: u64) {
+    use shared_lib::allocator::redzone;
+
+    loop {
+        crate::task::timer::sleep_for(period_ms).await;
+
+        let damaged = redzone::check_all();
+        if damaged != 0 {
+            log::error!("[redzone] {} of {} heap allocations have damaged redzones, see serial output",
+                damaged, redzone::live_allocations());
+        }
+    }
+}
@@ -1,41 +1,158 @@
 #![allow(dead_code)]
 use core::arch::asm;
 use shared_lib::addr::VirtAddr;
-use crate::port::Port;
+use crate::port::{Port, PortWriteOnly};
 use crate::interrupts;
-use shared_lib::{get_tsc, read_u32_ptr, write_u32_ptr};
-use shared_lib::bits::{set_bit, set_bits};
+use shared_lib::get_tsc;
+use shared_lib::register_block;
+use shared_lib::volatile::{Mmio, Register};
 use crate::interrupts::InterruptIndex;
 use crate::xsdt::ApicAddresses;
 use crate::task::timer;
 use crate::chrono::read_rtc;
+use crate::watchdog;
+use crate::cmdline;
+use crate::ioapic;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use shared_lib::msr;
+
+register_block! {
+    /// Local APIC register map
+    pub struct LocalApicRegs[0x400] {
+        APICID: u32     = 0x20,
+        APICVER: u32    = 0x30,
+        TASKPRIOR: u32  = 0x80,
+        EOI: u32        = 0x0B0,
+        LDR: u32        = 0x0D0,
+        DFR: u32        = 0x0E0,
+        SPURIOUS: u32   = 0x0F0,
+        ESR: u32        = 0x280,
+        ICRL: u32       = 0x300,
+        ICRH: u32       = 0x310,
+        LVT_TMR: u32    = 0x320,
+        LVT_THERMAL: u32 = 0x330,
+        LVT_PERF: u32   = 0x340,
+        LVT_LINT0: u32  = 0x350,
+        LVT_LINT1: u32  = 0x360,
+        LVT_ERR: u32    = 0x370,
+        TMRINITCNT: u32 = 0x380,
+        TMRCURRCNT: u32 = 0x390,
+        TMRDIV: u32     = 0x3E0,
+    }
+}
 
-pub const APIC_APICID: u32     = 0x20;
-pub const APIC_APICVER: u32    = 0x30;
-pub const APIC_TASKPRIOR: u32  = 0x80;
-pub const APIC_EOI: u32        = 0x0B0;
-pub const APIC_LDR: u32        = 0x0D0;
-pub const APIC_DFR: u32        = 0x0E0;
-pub const APIC_SPURIOUS: u32   = 0x0F0;
-pub const APIC_ESR: u32        = 0x280;
-pub const APIC_ICRL: u32       = 0x300;
-pub const APIC_ICRH: u32       = 0x310;
-pub const APIC_LVT_TMR: u32    = 0x320;
-pub const APIC_LVT_PERF: u32   = 0x340;
-pub const APIC_LVT_LINT0: u32  = 0x350;
-pub const APIC_LVT_LINT1: u32  = 0x360;
-pub const APIC_LVT_ERR: u32    = 0x370;
-pub const APIC_TMRINITCNT: u32 = 0x380;
-pub const APIC_TMRCURRCNT: u32 = 0x390;
-pub const APIC_TMRDIV: u32     = 0x3E0;
-pub const APIC_LAST: u32       = 0x38F;
 pub const APIC_DISABLE: u32    = 0x10000;
 pub const APIC_SW_ENABLE: u32  = 0x100;
 pub const APIC_CPUFOCUS: u32   = 0x200;
 pub const APIC_NMI: u32        = 4<<8;
+pub const APIC_EXTINT: u32     = 7<<8;
 pub const TMR_PERIODIC: u32	= 0x20000;
+pub const TMR_TSC_DEADLINE: u32 = 0x40000;
 pub const TMR_BASEDIV: u32	= 1 << 20;
 
+/// Entries of the local vector table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lvt {
+    Timer,
+    /// Only on CPUs with `max_lvt() >= 5`
+    Thermal,
+    PerformanceCounter,
+    Lint0,
+    Lint1,
+    Error,
+}
+
+impl Lvt {
+    fn register(self) -> Register<LocalApicRegs, u32> {
+        match self {
+            Lvt::Timer => LocalApicRegs::LVT_TMR,
+            Lvt::Thermal => LocalApicRegs::LVT_THERMAL,
+            Lvt::PerformanceCounter => LocalApicRegs::LVT_PERF,
+            Lvt::Lint0 => LocalApicRegs::LVT_LINT0,
+            Lvt::Lint1 => LocalApicRegs::LVT_LINT1,
+            Lvt::Error => LocalApicRegs::LVT_ERR,
+        }
+    }
+}
+
+/// Value of an LVT entry. Not every entry has every field: the trigger mode and polarity only
+/// apply to LINT0/LINT1, the timer mode only to the timer, which is always fixed delivery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LvtEntry(u32);
+
+impl LvtEntry {
+    pub const fn masked() -> LvtEntry {
+        LvtEntry(APIC_DISABLE)
+    }
+
+    pub const fn fixed(vector: u8) -> LvtEntry {
+        LvtEntry(vector as u32)
+    }
+
+    pub const fn nmi() -> LvtEntry {
+        LvtEntry(APIC_NMI)
+    }
+
+    /// Interrupt with the vector supplied by the 8259 PIC
+    pub const fn ext_int() -> LvtEntry {
+        LvtEntry(APIC_EXTINT)
+    }
+
+    pub const fn periodic(self) -> LvtEntry {
+        LvtEntry(self.0 | TMR_PERIODIC)
+    }
+
+    /// The timer fires when the TSC reaches IA32_TSC_DEADLINE
+    pub const fn tsc_deadline(self) -> LvtEntry {
+        LvtEntry(self.0 | TMR_TSC_DEADLINE)
+    }
+
+    pub const fn level_triggered(self) -> LvtEntry {
+        LvtEntry(self.0 | 1 << 15)
+    }
+
+    pub const fn active_low(self) -> LvtEntry {
+        LvtEntry(self.0 | 1 << 13)
+    }
+
+    pub const fn with_mask(self, masked: bool) -> LvtEntry {
+        if masked { LvtEntry(self.0 | APIC_DISABLE) } else { LvtEntry(self.0 & !APIC_DISABLE) }
+    }
+
+    pub const fn is_masked(self) -> bool {
+        self.0 & APIC_DISABLE != 0
+    }
+
+    pub const fn vector(self) -> u8 {
+        self.0 as u8
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+pub fn write_lvt(local_apic: &Mmio<LocalApicRegs>, lvt: Lvt, entry: LvtEntry) {
+    local_apic.write(lvt.register(), entry.bits());
+}
+
+pub fn read_lvt(local_apic: &Mmio<LocalApicRegs>, lvt: Lvt) -> LvtEntry {
+    LvtEntry(local_apic.read(lvt.register()))
+}
+
+/// Bits of the error status register
+const ESR_ERRORS: [(u32, &str); 8] = [
+    (1 << 0, "send checksum error"),
+    (1 << 1, "receive checksum error"),
+    (1 << 2, "send accept error"),
+    (1 << 3, "receive accept error"),
+    (1 << 4, "redirectable IPI"),
+    (1 << 5, "send illegal vector"),
+    (1 << 6, "received illegal vector"),
+    (1 << 7, "illegal register address"),
+];
+
 pub struct Apic {
     apic_base: VirtAddr
 }
@@ -47,32 +164,66 @@ impl Apic {
 
     pub unsafe fn initialize(&mut self, addr: VirtAddr) {
         self.apic_base = addr;
+        let regs = self.regs();
 
-        self.apic_write(APIC_DFR, 0xFFFF_FFFF);
-        let mut ldr = self.apic_read(APIC_LDR) & 0x00FFFFFF;
+        regs.write(LocalApicRegs::DFR, 0xFFFF_FFFF);
+        let mut ldr = regs.read(LocalApicRegs::LDR) & 0x00FFFFFF;
 
         ldr |= 0b0000_0001;
-        self.apic_write(APIC_LDR, ldr);
+        regs.write(LocalApicRegs::LDR, ldr);
+
+        write_lvt(&regs, Lvt::Timer, LvtEntry::masked());
+        write_lvt(&regs, Lvt::PerformanceCounter, LvtEntry::nmi());
+        write_lvt(&regs, Lvt::Lint0, LvtEntry::masked());
+        write_lvt(&regs, Lvt::Lint1, LvtEntry::masked());
+        if self.max_lvt() >= 5 {
+            write_lvt(&regs, Lvt::Thermal, LvtEntry::masked());
+        }
+        write_lvt(&regs, Lvt::Error, LvtEntry::fixed(InterruptIndex::ApicError as u8));
+        // the ESR is only updated by a write, this also drops errors from before
+        regs.write(LocalApicRegs::ESR, 0);
+        regs.write(LocalApicRegs::TASKPRIOR, 0);
+    }
+
+    /// Index of the last LVT entry: 4 without the thermal sensor entry, 5 with it
+    pub fn max_lvt(&self) -> u32 {
+        self.regs().read(LocalApicRegs::APICVER) >> 16 & 0xFF
+    }
+
+    pub fn set_lvt(&self, lvt: Lvt, entry: LvtEntry) {
+        write_lvt(&self.regs(), lvt, entry);
+    }
 
-        self.apic_write(APIC_LVT_TMR, APIC_DISABLE);
-        self.apic_write(APIC_LVT_PERF, APIC_NMI);
-        self.apic_write(APIC_LVT_LINT0, APIC_DISABLE);
-        self.apic_write(APIC_LVT_LINT1, APIC_DISABLE);
-        self.apic_write(APIC_TASKPRIOR, 0);
+    pub fn lvt(&self, lvt: Lvt) -> LvtEntry {
+        read_lvt(&self.regs(), lvt)
     }
 
-    unsafe fn apic_read(&self, offset: u32) -> u32 {
-        let apic_base = self.apic_base.0 as *mut u32;
-        core::ptr::read_volatile(apic_base.offset((offset / 4) as isize))
+    /// Reads and clears the error status register
+    pub fn take_errors(&self) -> u32 {
+        let regs = self.regs();
+        regs.write(LocalApicRegs::ESR, 0);
+        regs.read(LocalApicRegs::ESR)
     }
 
-    unsafe fn apic_write(&self, offset: u32, value: u32) {
-        let apic_base = self.apic_base.0 as *mut u32;
-        core::ptr::write_volatile(apic_base.byte_offset(offset as isize), value);
+    fn regs(&self) -> Mmio<LocalApicRegs> {
+        // SAFETY: apic_base is set once in `initialize` to the mapped local APIC page
+        unsafe { Mmio::new(self.apic_base) }
     }
 
     pub unsafe fn notify_end_of_interrupt(&mut self) {
-        self.apic_write(APIC_EOI, 0);
+        self.regs().write(LocalApicRegs::EOI, 0);
+    }
+}
+
+/// Logs the errors of the error status register, called by the APIC error interrupt handler
+pub fn report_errors(esr: u32) {
+    for (bit, name) in ESR_ERRORS {
+        if esr & bit != 0 {
+            log::warn!("[apic] error: {}", name);
+        }
+    }
+    if esr & !ESR_ERRORS.iter().fold(0, |all, (bit, _)| all | bit) != 0 {
+        log::warn!("[apic] error status {:#x}", esr);
     }
 }
 
@@ -104,7 +255,7 @@ fn is_tsc_constant() -> bool {
 pub fn pit_calibrate_tsc(latch: u32, ms: u64, loop_min: u16) -> u64 {
     unsafe {
         // Set the Gate high, disable speaker
-        let mut pit_channel2_gate = Port::new(0x61);
+        let mut pit_channel2_gate = Port::<u8>::new(0x61);
         {
             let v = (pit_channel2_gate.read() & 0xfd) | 0x1;
             pit_channel2_gate.write(v);
@@ -115,10 +266,10 @@ pub fn pit_calibrate_tsc(latch: u32, ms: u64, loop_min: u16) -> u64 {
          * count mode), binary count. Set the latch register to 50ms
          * (LSB then MSB) to begin countdown.
          */
-        let mut pit_channel2_command = Port::new(0x43);
+        let mut pit_channel2_command = PortWriteOnly::<u8>::new(0x43);
         pit_channel2_command.write(0xb0);
 
-        let mut pit_channel2_data = Port::new(0x42);
+        let mut pit_channel2_data = PortWriteOnly::<u8>::new(0x42);
         pit_channel2_data.write((latch & 0xff) as u8);
         pit_channel2_data.write((latch >> 8) as u8);
 
@@ -168,12 +319,12 @@ pub fn tsc_read_apic_ref(local_apic: VirtAddr) -> (u64, u32) {
     let tsc_default_threshold = 0x20000;
     let mut t1: u64;
     let mut t2: u64;
-    let apic_base = local_apic.0 as *mut u32;
+    let apic_regs = unsafe { Mmio::<LocalApicRegs>::new(local_apic) };
 
     let mut apic_tmr: u32 = 0;
     for _ in 0..max_retries {
         t1 = get_tsc();
-        apic_tmr = unsafe { read_u32_ptr(apic_base, APIC_TMRCURRCNT) };
+        apic_tmr = apic_regs.read(LocalApicRegs::TMRCURRCNT);
         t2 = get_tsc();
 
         if t2 - t1 < tsc_default_threshold {
@@ -201,8 +352,8 @@ pub fn calc_apic_timer_ref(deltatsc: u64, pm1: u64, mut pm2: u64) -> u64 {
     deltatsc / tmp
 }
 
-// returns lowest CPU frequency
-pub fn pit_hpet_ptimer_calibrate_cpu(local_apic: VirtAddr) -> u64 {
+// returns lowest CPU frequency, None when neither the PIT nor the APIC timer gave a value
+pub fn pit_hpet_ptimer_calibrate_cpu(local_apic: VirtAddr) -> Option<u64> {
     // The clock frequency of the i8253/i8254 PIT
     let pit_tick_rate: u64 = 1193182;
 
@@ -279,7 +430,7 @@ pub fn pit_hpet_ptimer_calibrate_cpu(local_apic: VirtAddr) -> u64 {
 
         if delta >= 90 && delta <= 110 {
             log::info!("PIT calibration matches APIC timer. {} loops", i + 1);
-            return tsc_ref_min;
+            return Some(tsc_ref_min);
         }
         /*
          * Check whether PIT failed more than once. This
@@ -299,18 +450,19 @@ pub fn pit_hpet_ptimer_calibrate_cpu(local_apic: VirtAddr) -> u64 {
         log::warn!("Unable to calibrate against PIT");
 
         if tsc_ref_min == 0x_FFFF_FFFF_FFFF_FFFF {
-            panic!("Failed to calibrate TSC against PIT and APIC");
+            log::warn!("Unable to calibrate against APIC timer");
+            return None;
         }
-        return tsc_ref_min;
+        return Some(tsc_ref_min);
     }
 
     log::info!("Using PIT calibration value");
-    return tsc_pit_min;
+    return Some(tsc_pit_min);
 }
 
 pub fn disable_pic() {
-    let mut p1 = Port::new(0x21);
-    let mut p2 = Port::new(0xA1);
+    let mut p1 = PortWriteOnly::<u8>::new(0x21);
+    let mut p2 = PortWriteOnly::<u8>::new(0xA1);
 
     unsafe {
         p1.write(0xff);
@@ -318,16 +470,6 @@ pub fn disable_pic() {
     }
 }
 
-unsafe fn read_io_apic(io_apic: *mut u32, register: u32) -> u32 {
-    write_u32_ptr(io_apic, 0, register & 0xff);
-    read_u32_ptr(io_apic, 0x10)
-}
-
-unsafe fn write_io_apic(io_apic: *mut u32, register: u32, value: u32) {
-    write_u32_ptr(io_apic, 0, register & 0xff);
-    write_u32_ptr(io_apic, 0x10, value);
-}
-
 pub fn initialize_apic(apic_addrs: ApicAddresses) {
     unsafe { interrupts::APIC.lock().initialize(apic_addrs.local_apic_addr); };
 
@@ -343,36 +485,158 @@ pub fn initialize_apic(apic_addrs: ApicAddresses) {
 
     log::info!("APIC enabled");
 
-    let apic_base = apic_addrs.local_apic_addr.0 as *mut u32;
+    let local_apic = unsafe { Mmio::<LocalApicRegs>::new(apic_addrs.local_apic_addr) };
 
-    let mut date_time = read_rtc();
-    log::info!("CMOS datetime: {:?}", date_time);
+    local_apic.update(LocalApicRegs::SPURIOUS, |v| v | APIC_SW_ENABLE);
+
+    let tsc_deadline = tsc_deadline_supported()
+        && is_tsc_constant()
+        && start_tsc_deadline_timer(&local_apic, apic_addrs.local_apic_addr);
+    if !tsc_deadline {
+        start_periodic_timer(&local_apic);
+    }
+
+    let perf_watchdog = watchdog::start_perf_counter(apic_addrs.local_apic_addr);
+    if cmdline::io_apic_enabled() {
+        ioapic::init(apic_addrs.io_apic_addr, apic_addrs.io_apic_gsi_base, apic_addrs.overrides);
+        setup_io_apic(&local_apic, !perf_watchdog);
+    } else {
+        log::info!("noapic: keyboard through the legacy PIC");
+        if !perf_watchdog {
+            log::info!("no NMI watchdog");
+        }
+        route_keyboard_through_pic(&local_apic);
+    }
+
+    // enable hardware interrupts
+    unsafe {
+        asm!("sti", options(nomem, nostack));
+    }
+}
+
+/// CPUID.1:ECX
+const CPUID_TSC_DEADLINE: u32 = 1 << 24;
+
+/// TSC cycles per tick in TSC-deadline mode, 0 with the periodic timer
+static TSC_DEADLINE_PERIOD: AtomicU64 = AtomicU64::new(0);
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+/// CPUID leaf with the TSC to core crystal clock ratio
+const CPUID_TSC_LEAF: u32 = 0x15;
+/// CPUID leaf with the processor base frequency in MHz
+const CPUID_FREQUENCY_LEAF: u32 = 0x16;
+
+fn tsc_deadline_supported() -> bool {
+    __cpuid(1).ecx & CPUID_TSC_DEADLINE != 0
+}
+
+/// TSC frequency in Hz as enumerated by CPUID, from the crystal clock ratio of leaf 0x15 or
+/// else the base frequency of leaf 0x16. Either may be missing or zero, mostly under hypervisors.
+fn cpuid_tsc_hz() -> Option<u64> {
+    let max_leaf = __cpuid(0).eax;
+
+    if max_leaf >= CPUID_TSC_LEAF {
+        let leaf = __cpuid(CPUID_TSC_LEAF);
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            return Some(leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64);
+        }
+    }
+
+    if max_leaf >= CPUID_FREQUENCY_LEAF {
+        let base_mhz = __cpuid(CPUID_FREQUENCY_LEAF).eax & 0xFFFF;
+        if base_mhz != 0 {
+            return Some(base_mhz as u64 * 1_000_000);
+        }
+    }
+
+    None
+}
+
+/// Calibrates the TSC against the PIT, a few hundred milliseconds, or takes its frequency from
+/// CPUID when that fails, and fires the timer at TSC deadlines `TIMER_FREQUENCY` times per
+/// second. Returns false without touching the timer if the TSC frequency is unknown.
+fn start_tsc_deadline_timer(local_apic: &Mmio<LocalApicRegs>, local_apic_addr: VirtAddr) -> bool {
+    let tsc_hz = match pit_hpet_ptimer_calibrate_cpu(local_apic_addr) {
+        Some(khz) => khz * 1000,
+        None => match cpuid_tsc_hz() {
+            Some(hz) => {
+                log::warn!("TSC calibration failed, using the CPUID frequency");
+                hz
+            }
+            None => {
+                log::warn!("TSC frequency unknown, falling back to the periodic timer");
+                return false;
+            }
+        },
+    };
+    log::info!("TSC frequency: {} MHz, timer in TSC-deadline mode", tsc_hz / 1_000_000);
+    timer::set_tsc_frequency(tsc_hz);
+
+    let period = tsc_hz / timer::TIMER_FREQUENCY as u64;
+    TSC_DEADLINE_PERIOD.store(period, Ordering::Relaxed);
+    write_lvt(local_apic, Lvt::Timer, LvtEntry::fixed(InterruptIndex::Timer as u8).tsc_deadline());
+
+    let deadline = get_tsc() + period;
+    NEXT_DEADLINE.store(deadline, Ordering::Relaxed);
+    unsafe {
+        // the LVT write is not serializing, the mode must be set before the deadline
+        asm!("mfence", options(nostack, preserves_flags));
+        msr::write(msr::IA32_TSC_DEADLINE, deadline);
+    }
+    true
+}
+
+/// Arms the next tick in TSC-deadline mode, called by the timer interrupt handler. Deadlines
+/// follow each other by exactly one period, so the ticks don't drift with the handler latency.
+pub fn rearm_timer() {
+    let period = TSC_DEADLINE_PERIOD.load(Ordering::Relaxed);
+    if period == 0 {
+        return;
+    }
 
+    let now = get_tsc();
+    let mut deadline = NEXT_DEADLINE.load(Ordering::Relaxed) + period;
+    if deadline <= now {
+        // missed while idle, `timer::account_idle` counts these ticks
+        deadline = now + period;
+    }
+    NEXT_DEADLINE.store(deadline, Ordering::Relaxed);
     unsafe {
-        write_u32_ptr(apic_base, APIC_TMRDIV, 0x03);
-        write_u32_ptr(apic_base, APIC_SPURIOUS, read_u32_ptr(apic_base, APIC_SPURIOUS) | APIC_SW_ENABLE);
+        msr::write(msr::IA32_TSC_DEADLINE, deadline);
     }
+}
+
+/// Calibrates the periodic APIC timer against the RTC, which takes about four seconds
+fn start_periodic_timer(local_apic: &Mmio<LocalApicRegs>) {
+    let mut date_time = read_rtc();
+    log::info!("CMOS datetime: {:?}", date_time);
+
+    local_apic.write(LocalApicRegs::TMRDIV, 0x03);
 
     let mut full_second_passing = false;
     let mut first_measure = 0;
     let mut second_measure = 0;
     let mut third_measure = 0;
+    let mut tsc_start = 0;
+    let mut tsc_end = 0;
 
     loop {
         let new_date_time = read_rtc();
         if date_time != new_date_time {
-            let ticks_in_1s = 0xFFFFFFFF - unsafe {
-                write_u32_ptr(apic_base, APIC_LVT_TMR, APIC_DISABLE);
-                read_u32_ptr(apic_base, APIC_TMRCURRCNT)
+            let ticks_in_1s = 0xFFFFFFFF - {
+                write_lvt(local_apic, Lvt::Timer, LvtEntry::masked());
+                local_apic.read(LocalApicRegs::TMRCURRCNT)
             };
             if !full_second_passing {
                 full_second_passing = true;
+                tsc_start = get_tsc();
             } else if first_measure == 0 {
                 first_measure = ticks_in_1s;
             } else if second_measure == 0 {
                 second_measure = ticks_in_1s;
             } else if third_measure == 0 {
                 third_measure = ticks_in_1s;
+                tsc_end = get_tsc();
             } else {
                 break;
             }
@@ -380,11 +644,9 @@ pub fn initialize_apic(apic_addrs: ApicAddresses) {
             log::info!("New datetime: {:?}. Ticks elapsed: {}", new_date_time, ticks_in_1s);
             date_time = new_date_time;
 
-            unsafe {
-                // one-shot mode
-                write_u32_ptr(apic_base, APIC_LVT_TMR, InterruptIndex::Timer as u32);
-                write_u32_ptr(apic_base, APIC_TMRINITCNT, 0xFFFFFFFF);
-            }
+            // one-shot mode
+            write_lvt(local_apic, Lvt::Timer, LvtEntry::fixed(InterruptIndex::Timer as u8));
+            local_apic.write(LocalApicRegs::TMRINITCNT, 0xFFFFFFFF);
         }
     }
 
@@ -394,35 +656,128 @@ pub fn initialize_apic(apic_addrs: ApicAddresses) {
     log::info!("CPU bus freq: {} Mhz", ((bus_freq / 1000) as f64) / 1000.0);
 
     let timer_frequency = timer::TIMER_FREQUENCY; // x interrupts per sec
-    let timer_value = avg_ticks / timer_frequency as u64; // x interrupts per sec
+    let timer_value = avg_ticks / timer_frequency as u64; // APIC timer counts per interrupt
+    if timer_value == 0 || timer_value > u32::MAX as u64 {
+        panic!("APIC timer can't run at {} Hz with {} counts per second", timer_frequency, avg_ticks);
+    }
+
+    log::info!("Ok. let's enable APIC with proper value. timer init value: {}, timer_frequency per sec: {}, {} ms per tick",
+        timer_value, timer_frequency, timer::MS_PER_TICK);
+
+    local_apic.write(LocalApicRegs::TMRINITCNT, timer_value as u32);
+    write_lvt(local_apic, Lvt::Timer, LvtEntry::fixed(InterruptIndex::Timer as u8).periodic());
+
+    // the RTC edges 1 and 4 are three seconds apart
+    if is_tsc_constant() {
+        let tsc_hz = (tsc_end - tsc_start) / 3;
+        log::info!("TSC frequency: {} MHz", tsc_hz / 1_000_000);
+        timer::set_tsc_frequency(tsc_hz);
+    } else {
+        log::info!("TSC is not invariant, ticks missed while idle won't be counted");
+    }
+}
+
+/// The keyboard is routed through the 8259 PIC instead of the IO-APIC (`noapic`)
+static LEGACY_PIC: AtomicBool = AtomicBool::new(false);
+
+pub fn legacy_pic() -> bool {
+    LEGACY_PIC.load(Ordering::Relaxed)
+}
+
+/// Acknowledges an interrupt delivered by the 8259 PIC, instead of the local APIC EOI
+pub fn notify_pic_end_of_interrupt() {
+    unsafe { PortWriteOnly::<u8>::new(0x20).write(0x20) };
+}
 
-    log::info!("Ok. let's enable APIC with proper value. timer init value: {}, timer_frequency per sec: {}", timer_value, timer_frequency);
+/// Virtual wire mode: the PIC passes the keyboard IRQ to LINT0 of the local APIC, which delivers
+/// it as an external interrupt with the vector the PIC supplies.
+fn route_keyboard_through_pic(local_apic: &Mmio<LocalApicRegs>) {
+    let mut master_command = PortWriteOnly::<u8>::new(0x20);
+    let mut master_data = PortWriteOnly::<u8>::new(0x21);
+    let mut slave_command = PortWriteOnly::<u8>::new(0xA0);
+    let mut slave_data = PortWriteOnly::<u8>::new(0xA1);
 
     unsafe {
-        write_u32_ptr(apic_base, APIC_TMRINITCNT, timer_value as u32);
-        write_u32_ptr(apic_base, APIC_LVT_TMR, InterruptIndex::Timer as u32 | TMR_PERIODIC);
+        // ICW1: edge triggered, cascaded, ICW4 follows
+        master_command.write(0x11);
+        slave_command.write(0x11);
+        // ICW2: vector offsets, IRQ 1 becomes the keyboard vector
+        master_data.write(interrupts::PIC_1_OFFSET);
+        slave_data.write(interrupts::PIC_1_OFFSET + 8);
+        // ICW3: slave on IRQ 2
+        master_data.write(4);
+        slave_data.write(2);
+        // ICW4: 8086 mode
+        master_data.write(1);
+        slave_data.write(1);
+        // mask everything but the keyboard
+        master_data.write(!(1 << 1));
+        slave_data.write(0xFF);
+    }
 
-        let local_apic_id = read_u32_ptr(apic_base, APIC_APICID);
+    write_lvt(local_apic, Lvt::Lint0, LvtEntry::ext_int());
+    LEGACY_PIC.store(true, Ordering::Relaxed);
+}
 
-        let io_apic_base = apic_addrs.io_apic_addr.0 as *mut u32;
+/// Routes the keyboard and, if `pit_nmi`, the PIT as NMI for the lockup watchdog to this CPU
+fn setup_io_apic(local_apic: &Mmio<LocalApicRegs>, pit_nmi: bool) {
+    let local_apic_id = (local_apic.read(LocalApicRegs::APICID) >> 24) as u8;
 
-        let version = read_io_apic(io_apic_base, 0x1);
+    if let Err(e) = ioapic::route_legacy_irq(ioapic::IRQ_KEYBOARD, InterruptIndex::Keyboard as u8, local_apic_id) {
+        log::warn!("Failed to route the keyboard IRQ: {}", e);
+    }
 
-        log::info!("IOAPIC[0]: version: {}, address: {:#x}", version as u8, apic_addrs.io_apic_addr.0);
-        let mut low_reg = read_io_apic(io_apic_base, 0x12) as u64;
+    if !pit_nmi {
+        return;
+    }
+    // the PIT is usually overridden to GSI 2
+    let (gsi, polarity, trigger) = ioapic::legacy_irq(ioapic::IRQ_PIT);
+    match ioapic::route_nmi(gsi, polarity, trigger, local_apic_id) {
+        Ok(()) => watchdog::start_pit(),
+        Err(e) => log::warn!("Failed to route the PIT as NMI: {}", e),
+    }
+}
 
-        set_bits(&mut low_reg, InterruptIndex::Keyboard as u64, 0);
+/// IA32_APIC_BASE: the local APIC is enabled
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+const ICR_INIT: u32 = 5 << 8;
+const ICR_STARTUP: u32 = 6 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_LEVEL_TRIGGERED: u32 = 1 << 15;
+
+/// Sends an IPI with the low ICR bits `command` to the local APIC with ID `apic_id`, returns once
+/// it has been accepted
+fn send_ipi(local_apic: &Mmio<LocalApicRegs>, apic_id: u8, command: u32) {
+    local_apic.write(LocalApicRegs::ICRH, u32::from(apic_id) << 24);
+    local_apic.write(LocalApicRegs::ICRL, command);
+    while local_apic.read(LocalApicRegs::ICRL) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
 
-        set_bits(&mut low_reg, 0, 8); // Fixed delivery mode
-        set_bit(&mut low_reg, 11, false); // Physical destination
-        set_bit(&mut low_reg, 13, false); // Pin polarity - active high
-        set_bit(&mut low_reg, 15, false); // Trigger mode - edge
-        set_bit(&mut low_reg, 16, false); // unmask interrupt
+/// Resets the CPU with the local APIC `apic_id`, it then waits for a startup IPI
+pub fn send_init_ipi(local_apic: &Mmio<LocalApicRegs>, apic_id: u8) {
+    send_ipi(local_apic, apic_id, ICR_INIT | ICR_LEVEL_ASSERT | ICR_LEVEL_TRIGGERED);
+    // the deassert is only needed by old CPUs and ignored by the others
+    send_ipi(local_apic, apic_id, ICR_INIT | ICR_LEVEL_TRIGGERED);
+}
 
-        write_io_apic(io_apic_base, 0x12, low_reg as u32);
-        write_io_apic(io_apic_base, 0x13, local_apic_id);
+/// Starts the CPU with the local APIC `apic_id`, waiting after an INIT IPI, in real mode at
+/// `page` * 4096
+pub fn send_startup_ipi(local_apic: &Mmio<LocalApicRegs>, apic_id: u8, page: u8) {
+    send_ipi(local_apic, apic_id, ICR_STARTUP | u32::from(page));
+}
 
-        // enable hardware interrupts
-        asm!("sti", options(nomem, nostack));
+/// Enables the local APIC of an application processor. INIT left all its LVT entries masked, so
+/// it only takes IPIs and reports its errors.
+pub fn init_ap(local_apic: &Mmio<LocalApicRegs>) {
+    unsafe {
+        msr::write(msr::IA32_APIC_BASE, msr::read(msr::IA32_APIC_BASE) | APIC_GLOBAL_ENABLE);
     }
+    write_lvt(local_apic, Lvt::Error, LvtEntry::fixed(InterruptIndex::ApicError as u8));
+    local_apic.write(LocalApicRegs::ESR, 0);
+    local_apic.write(LocalApicRegs::TASKPRIOR, 0);
+    local_apic.update(LocalApicRegs::SPURIOUS, |v| v | APIC_SW_ENABLE);
 }
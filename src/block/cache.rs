@@ -0,0 +1,325 @@
+// Block cache.
+//
+// Sectors read from or written to a disk stay in memory, keyed by disk and LBA, up to
+// `CAPACITY_SECTORS`. Writes only go to the cache and mark the sectors dirty: the flusher task
+// writes them back every `WRITEBACK_INTERVAL_MS`, `sync` right away, both in runs of adjacent
+// sectors. When the cache is full the least recently used clean sectors are evicted; if only
+// dirty ones are left it syncs first.
+//
+// I/O through a `Disk` directly bypasses the cache and may see stale data.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use shared_lib::spinlock::Spinlock;
+use crate::ide::{AtaError, SECTOR_SIZE};
+use crate::task::timer::interval;
+use super::Disk;
+
+/// 2 MiB
+const CAPACITY_SECTORS: usize = 4096;
+const WRITEBACK_INTERVAL_MS: u64 = 5000;
+/// Longest run of dirty sectors written back with one request
+const MAX_WRITEBACK_SECTORS: usize = 256;
+
+/// Disk id and LBA
+type Key = (u64, u64);
+
+struct Entry {
+    data: Box<[u8; SECTOR_SIZE]>,
+    dirty: bool,
+    /// Bumped by every write, a writeback only cleans the version it wrote
+    version: u64,
+    /// Key of the entry in `Cache::lru`
+    stamp: u64,
+}
+
+struct Cache {
+    entries: BTreeMap<Key, Entry>,
+    /// Least recently used first
+    lru: BTreeMap<u64, Key>,
+    next_stamp: u64,
+    /// Disks with sectors in the cache, for the writeback
+    disks: BTreeMap<u64, Arc<Disk>>,
+    dirty: usize,
+}
+
+/// Adjacent dirty sectors of a disk and the versions of them being written
+struct Run {
+    disk: Arc<Disk>,
+    lba: u64,
+    data: Vec<u8>,
+    versions: Vec<u64>,
+}
+
+impl Cache {
+    const fn new() -> Self {
+        Cache { entries: BTreeMap::new(), lru: BTreeMap::new(), next_stamp: 0, disks: BTreeMap::new(), dirty: 0 }
+    }
+
+    fn stamp(&mut self, key: Key) -> u64 {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        self.lru.insert(stamp, key);
+        stamp
+    }
+
+    /// Marks the entry as the most recently used one
+    fn touch(&mut self, key: Key) {
+        let Some(old) = self.entries.get(&key).map(|entry| entry.stamp) else {
+            return;
+        };
+        self.lru.remove(&old);
+        let stamp = self.stamp(key);
+        self.entries.get_mut(&key).unwrap().stamp = stamp;
+    }
+
+    /// Copies the cached sector into `sector`, false on a miss
+    fn lookup(&mut self, key: Key, sector: &mut [u8]) -> bool {
+        let Some(entry) = self.entries.get(&key) else {
+            return false;
+        };
+        sector.copy_from_slice(&entry.data[..]);
+        self.touch(key);
+        true
+    }
+
+    /// Caches `sector` as read from the disk. If the sector was written meanwhile the cache is
+    /// newer than the disk and `sector` gets the cached data instead.
+    fn fill(&mut self, key: Key, sector: &mut [u8]) {
+        if !self.lookup(key, sector) {
+            let stamp = self.stamp(key);
+            let data = Box::new(sector.try_into().unwrap());
+            self.entries.insert(key, Entry { data, dirty: false, version: 0, stamp });
+        }
+    }
+
+    fn store(&mut self, key: Key, sector: &[u8]) {
+        self.touch(key);
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.data.copy_from_slice(sector);
+            entry.version += 1;
+            if !entry.dirty {
+                entry.dirty = true;
+                self.dirty += 1;
+            }
+            return;
+        }
+
+        let stamp = self.stamp(key);
+        let data = Box::new(sector.try_into().unwrap());
+        self.entries.insert(key, Entry { data, dirty: true, version: 0, stamp });
+        self.dirty += 1;
+    }
+
+    /// The dirty sectors in runs, ordered by disk and LBA
+    fn dirty_runs(&self) -> Vec<Run> {
+        let mut runs: Vec<Run> = Vec::new();
+        for (&(disk_id, lba), entry) in self.entries.iter().filter(|(_, entry)| entry.dirty) {
+            match runs.last_mut() {
+                Some(run) if run.disk.id() == disk_id
+                    && run.lba + run.versions.len() as u64 == lba
+                    && run.versions.len() < MAX_WRITEBACK_SECTORS => {
+                    run.data.extend_from_slice(&entry.data[..]);
+                    run.versions.push(entry.version);
+                },
+                _ => runs.push(Run {
+                    disk: self.disks[&disk_id].clone(),
+                    lba,
+                    data: entry.data.to_vec(),
+                    versions: alloc::vec![entry.version],
+                }),
+            }
+        }
+        runs
+    }
+
+    /// Marks the sectors of a run clean unless they were written again meanwhile
+    fn written_back(&mut self, run: &Run) {
+        for (i, &version) in run.versions.iter().enumerate() {
+            if let Some(entry) = self.entries.get_mut(&(run.disk.id(), run.lba + i as u64)) {
+                if entry.dirty && entry.version == version {
+                    entry.dirty = false;
+                    self.dirty -= 1;
+                }
+            }
+        }
+    }
+
+    /// Evicts least recently used clean sectors down to the capacity, false if the dirty ones
+    /// alone are over it
+    fn evict_clean(&mut self) -> bool {
+        let excess = self.entries.len().saturating_sub(CAPACITY_SECTORS);
+        if excess == 0 {
+            return true;
+        }
+
+        let victims: Vec<(u64, Key)> = self.lru.iter()
+            .filter(|(_, key)| !self.entries[key].dirty)
+            .take(excess)
+            .map(|(&stamp, &key)| (stamp, key))
+            .collect();
+        for (stamp, key) in &victims {
+            self.lru.remove(stamp);
+            self.entries.remove(key);
+        }
+        EVICTED.fetch_add(victims.len() as u64, Ordering::Relaxed);
+        self.entries.len() <= CAPACITY_SECTORS
+    }
+}
+
+static CACHE: Spinlock<Cache> = Spinlock::new(Cache::new());
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+static WRITTEN_BACK: AtomicU64 = AtomicU64::new(0);
+static EVICTED: AtomicU64 = AtomicU64::new(0);
+
+/// Reads `buffer.len() / SECTOR_SIZE` sectors starting at `lba`, from the cache where possible
+pub async fn read(disk: &Arc<Disk>, lba: u64, buffer: &mut [u8]) -> Result<(), AtaError> {
+    if buffer.len() % SECTOR_SIZE != 0 {
+        return Err(AtaError::InvalidBufferSize);
+    }
+
+    let id = disk.id();
+    let mut missing = Vec::new();
+    {
+        let mut cache = CACHE.lock();
+        cache.disks.entry(id).or_insert_with(|| disk.clone());
+        for (i, sector) in buffer.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            if !cache.lookup((id, lba + i as u64), sector) {
+                missing.push(i);
+            }
+        }
+    }
+    HITS.fetch_add((buffer.len() / SECTOR_SIZE - missing.len()) as u64, Ordering::Relaxed);
+    MISSES.fetch_add(missing.len() as u64, Ordering::Relaxed);
+
+    // one request per run of missing sectors
+    let mut i = 0;
+    while i < missing.len() {
+        let start = missing[i];
+        let mut end = start + 1;
+        i += 1;
+        while i < missing.len() && missing[i] == end {
+            end += 1;
+            i += 1;
+        }
+
+        let run = &mut buffer[start * SECTOR_SIZE..end * SECTOR_SIZE];
+        disk.read(lba + start as u64, run).await?;
+        let mut cache = CACHE.lock();
+        for (j, sector) in run.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            cache.fill((id, lba + (start + j) as u64), sector);
+        }
+    }
+
+    shrink().await;
+    Ok(())
+}
+
+/// Writes `data` to the cache, the disk gets it with the next writeback
+pub async fn write(disk: &Arc<Disk>, lba: u64, data: &[u8]) -> Result<(), AtaError> {
+    if data.len() % SECTOR_SIZE != 0 {
+        return Err(AtaError::InvalidBufferSize);
+    }
+    // the writeback is too late to report it
+    if lba + (data.len() / SECTOR_SIZE) as u64 > disk.size() {
+        return Err(AtaError::OutOfRange);
+    }
+
+    let id = disk.id();
+    {
+        let mut cache = CACHE.lock();
+        cache.disks.entry(id).or_insert_with(|| disk.clone());
+        for (i, sector) in data.chunks_exact(SECTOR_SIZE).enumerate() {
+            cache.store((id, lba + i as u64), sector);
+        }
+    }
+
+    shrink().await;
+    Ok(())
+}
+
+/// Drops the sectors of a disk which is gone, the dirty ones too: there is nowhere to write them
+pub fn forget(disk: &Disk) {
+    let id = disk.id();
+    let lost = {
+        let mut cache = CACHE.lock();
+        let keys: Vec<Key> = cache.entries.range((id, 0)..=(id, u64::MAX)).map(|(&key, _)| key).collect();
+        let mut lost = 0;
+        for key in keys {
+            let Some(entry) = cache.entries.remove(&key) else { continue };
+            cache.lru.remove(&entry.stamp);
+            if entry.dirty {
+                cache.dirty -= 1;
+                lost += 1;
+            }
+        }
+        cache.disks.remove(&id);
+        lost
+    };
+    if lost > 0 {
+        log::warn!("[cache] {} dirty sectors of {} lost", lost, disk.name());
+    }
+}
+
+/// `sync` as a SysRq sync hook, runs on a kernel thread
+pub fn sync_hook() -> Result<(), &'static str> {
+    crate::thread::block_on(sync()).map_err(|_| "a write-back failed")
+}
+
+/// Writes all dirty sectors back. Sectors which failed stay dirty and are retried by the next
+/// sync, the last error is returned.
+pub async fn sync() -> Result<(), AtaError> {
+    let runs = CACHE.lock().dirty_runs();
+    let mut result = Ok(());
+    for run in runs {
+        match run.disk.write(run.lba, &run.data).await {
+            Ok(()) => {
+                CACHE.lock().written_back(&run);
+                WRITTEN_BACK.fetch_add(run.versions.len() as u64, Ordering::Relaxed);
+            },
+            Err(e) => {
+                log::warn!("[cache] writeback of {} sectors at {} LBA {} failed: {:?}", run.versions.len(), run.disk.name(), run.lba, e);
+                result = Err(e);
+            },
+        }
+    }
+    result
+}
+
+/// Evicts down to the capacity, writing dirty sectors back if needed
+async fn shrink() {
+    if CACHE.lock().evict_clean() {
+        return;
+    }
+    // failures are logged by `sync`, the cache stays over its capacity until the disk works again
+    let _ = sync().await;
+    CACHE.lock().evict_clean();
+}
+
+/// Writes dirty sectors back periodically
+pub async fn flusher() {
+    let mut writeback = interval(WRITEBACK_INTERVAL_MS);
+    loop {
+        writeback.tick().await;
+        if CACHE.lock().dirty != 0 {
+            let _ = sync().await;
+        }
+    }
+}
+
+/// Part of the `iostat` output
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    let (entries, dirty) = {
+        let cache = CACHE.lock();
+        (cache.entries.len(), cache.dirty)
+    };
+    writeln!(out, "cache: {} of {} sectors, {} dirty, {} hits, {} misses, {} written back, {} evicted",
+        entries, CAPACITY_SECTORS, dirty, HITS.load(Ordering::Relaxed), MISSES.load(Ordering::Relaxed),
+        WRITTEN_BACK.load(Ordering::Relaxed), EVICTED.load(Ordering::Relaxed))
+}
@@ -0,0 +1,440 @@
+// Block layer between filesystems and disk drivers.
+//
+// Every disk gets a request queue when it is registered. `Disk::read` and `Disk::write` queue a
+// request and wait for it; the first waiting task that finds the disk idle dispatches everything
+// pending, including the requests of other tasks, and wakes their owners as they complete.
+// Requests are dispatched in elevator order (C-SCAN): ascending LBA from where the previous batch
+// ended, then wrapping around to the lowest. Neighbouring requests in the same direction are
+// merged into a single driver call. A submitter yields once before dispatching so requests the
+// other tasks queue in the same executor round can join the batch. The dispatching task awaits
+// the driver, so other tasks run meanwhile and queue more; if it is dropped the next waiter takes
+// over.
+//
+// Every disk keeps statistics for `iostat`. Filesystems go through the block cache in `cache`
+// rather than the queue directly, synchronous ones through a `stream::BlockStream`.
+
+pub mod cache;
+pub mod partition;
+pub mod stream;
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::task::Poll;
+use futures_util::task::AtomicWaker;
+use shared_lib::get_tsc;
+use shared_lib::spinlock::Spinlock;
+use crate::ide::{AtaError, BlockDevice, SECTOR_SIZE};
+use crate::pci::PciAddress;
+use crate::sysinfo::{self, Category};
+use crate::task::timer;
+use crate::task::yield_now;
+
+/// Largest transfer merged requests are combined into
+const MAX_MERGE_SECTORS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Read,
+    Write,
+}
+
+struct Request {
+    op: Op,
+    lba: u64,
+    sectors: usize,
+    /// The data to write, or the data read once the request completed
+    data: Spinlock<Vec<u8>>,
+    result: Spinlock<Option<Result<(), AtaError>>>,
+    waker: AtomicWaker,
+    /// TSC when it was queued
+    submitted: u64,
+}
+
+impl Request {
+    fn end(&self) -> u64 {
+        self.lba + self.sectors as u64
+    }
+}
+
+struct Queue {
+    /// In submission order, sorted when a batch is taken
+    pending: Vec<Arc<Request>>,
+    /// A task is running the driver
+    busy: bool,
+    /// LBA after the last request dispatched, where the elevator continues
+    position: u64,
+}
+
+impl Queue {
+    /// The pending requests in the order the elevator visits them
+    fn take_batch(&mut self) -> Vec<Arc<Request>> {
+        let mut batch = core::mem::take(&mut self.pending);
+        batch.sort_by_key(|request| request.lba);
+        let wrap = batch.iter().position(|request| request.lba >= self.position).unwrap_or(batch.len());
+        batch.rotate_left(wrap);
+        if let Some(last) = batch.last() {
+            self.position = last.end();
+        }
+        batch
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    sectors_read: AtomicU64,
+    sectors_written: AtomicU64,
+    /// Requests which went to the driver as part of another one's transfer
+    merged: AtomicU64,
+    /// Calls into the driver
+    dispatches: AtomicU64,
+    errors: AtomicU64,
+    /// Queue to completion, summed over all requests
+    latency_cycles: AtomicU64,
+    max_latency_cycles: AtomicU64,
+}
+
+/// Statistics of a disk since it was registered
+#[derive(Debug, Clone, Copy)]
+pub struct DiskStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub sectors_read: u64,
+    pub sectors_written: u64,
+    pub merged: u64,
+    pub dispatches: u64,
+    pub errors: u64,
+    pub latency_cycles: u64,
+    pub max_latency_cycles: u64,
+    /// TSC cycles since the disk was registered
+    pub uptime_cycles: u64,
+}
+
+impl DiskStats {
+    /// Requests completed per second, None without a calibrated TSC
+    pub fn iops(&self) -> Option<u64> {
+        let hz = timer::tsc_frequency()?;
+        Some((self.reads + self.writes) * hz / self.uptime_cycles.max(1))
+    }
+
+    pub fn average_latency_cycles(&self) -> u64 {
+        self.latency_cycles / (self.reads + self.writes).max(1)
+    }
+}
+
+pub struct Disk {
+    /// Unique, also among the disks which aren't registered
+    id: u64,
+    name: String,
+    device: Box<dyn BlockDevice>,
+    queue: Spinlock<Queue>,
+    stats: Stats,
+    created: u64,
+}
+
+impl Disk {
+    /// A disk with its own queue which isn't registered, e.g. for an image in memory
+    pub fn new(name: String, device: Box<dyn BlockDevice>) -> Disk {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Disk {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            name,
+            device,
+            queue: Spinlock::new(Queue { pending: Vec::new(), busy: false, position: 0 }),
+            stats: Stats::default(),
+            created: get_tsc(),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The driver, bypassing the queue
+    pub fn device(&self) -> &dyn BlockDevice {
+        &*self.device
+    }
+
+    /// In sectors
+    pub fn size(&self) -> u64 {
+        self.device.size()
+    }
+
+    /// Reads `buffer.len() / SECTOR_SIZE` sectors starting at `lba`
+    pub async fn read(&self, lba: u64, buffer: &mut [u8]) -> Result<(), AtaError> {
+        let request = self.submit(Op::Read, lba, vec![0; buffer.len()])?;
+        self.wait(&request).await?;
+        buffer.copy_from_slice(&request.data.lock());
+        Ok(())
+    }
+
+    pub async fn write(&self, lba: u64, data: &[u8]) -> Result<(), AtaError> {
+        let request = self.submit(Op::Write, lba, data.to_vec())?;
+        self.wait(&request).await
+    }
+
+    pub fn stats(&self) -> DiskStats {
+        let stats = &self.stats;
+        DiskStats {
+            reads: stats.reads.load(Ordering::Relaxed),
+            writes: stats.writes.load(Ordering::Relaxed),
+            sectors_read: stats.sectors_read.load(Ordering::Relaxed),
+            sectors_written: stats.sectors_written.load(Ordering::Relaxed),
+            merged: stats.merged.load(Ordering::Relaxed),
+            dispatches: stats.dispatches.load(Ordering::Relaxed),
+            errors: stats.errors.load(Ordering::Relaxed),
+            latency_cycles: stats.latency_cycles.load(Ordering::Relaxed),
+            max_latency_cycles: stats.max_latency_cycles.load(Ordering::Relaxed),
+            uptime_cycles: get_tsc().wrapping_sub(self.created),
+        }
+    }
+
+    fn submit(&self, op: Op, lba: u64, data: Vec<u8>) -> Result<Arc<Request>, AtaError> {
+        // checked here so a bad request can't fail the ones it would be merged with
+        if data.len() % SECTOR_SIZE != 0 {
+            return Err(AtaError::InvalidBufferSize);
+        }
+        let sectors = data.len() / SECTOR_SIZE;
+        if lba + sectors as u64 > self.size() {
+            return Err(AtaError::OutOfRange);
+        }
+
+        let request = Arc::new(Request {
+            op,
+            lba,
+            sectors,
+            data: Spinlock::new(data),
+            result: Spinlock::new(None),
+            waker: AtomicWaker::new(),
+            submitted: get_tsc(),
+        });
+        self.queue.lock().pending.push(request.clone());
+        Ok(request)
+    }
+
+    async fn wait(&self, request: &Request) -> Result<(), AtaError> {
+        yield_now().await;
+        loop {
+            if self.start_dispatch() {
+                self.dispatch().await;
+            }
+
+            // None: the dispatching task stopped before it got to the request
+            let result = poll_fn(|cx| {
+                request.waker.register(cx.waker());
+                if let Some(result) = request.result.lock().take() {
+                    return Poll::Ready(Some(result));
+                }
+                if self.queue.lock().busy {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(None)
+                }
+            }).await;
+            if let Some(result) = result {
+                return result;
+            }
+        }
+    }
+
+    /// Whether the calling task is to dispatch, false if another task does already
+    fn start_dispatch(&self) -> bool {
+        let mut queue = self.queue.lock();
+        !core::mem::replace(&mut queue.busy, true)
+    }
+
+    /// Runs the pending requests until the queue is empty
+    async fn dispatch(&self) {
+        let mut dispatch = Dispatch { disk: self, batch: Vec::new() };
+        loop {
+            dispatch.batch = {
+                let mut queue = self.queue.lock();
+                if queue.pending.is_empty() {
+                    break;
+                }
+                queue.take_batch()
+            };
+
+            let batch = dispatch.batch.clone();
+            let mut start = 0;
+            while start < batch.len() {
+                let mut end = start + 1;
+                let mut sectors = batch[start].sectors;
+                while end < batch.len()
+                    && batch[end].op == batch[start].op
+                    && batch[end].lba == batch[end - 1].end()
+                    && sectors + batch[end].sectors <= MAX_MERGE_SECTORS
+                {
+                    sectors += batch[end].sectors;
+                    end += 1;
+                }
+                self.execute(&batch[start..end]).await;
+                start = end;
+            }
+        }
+    }
+
+    /// Transfers the adjacent requests of `run` with one driver call
+    async fn execute(&self, run: &[Arc<Request>]) {
+        if let [request] = run {
+            return self.execute_one(request).await;
+        }
+
+        let first = &run[0];
+        self.stats.dispatches.fetch_add(1, Ordering::Relaxed);
+        let mut buffer = Vec::with_capacity(run.iter().map(|request| request.sectors * SECTOR_SIZE).sum());
+        for request in run {
+            match request.op {
+                Op::Write => buffer.extend_from_slice(&request.data.lock()),
+                Op::Read => buffer.resize(buffer.len() + request.sectors * SECTOR_SIZE, 0),
+            }
+        }
+        if self.transfer(first.op, first.lba, &mut buffer).await.is_err() {
+            // find out which of them failed
+            for request in run {
+                self.execute_one(request).await;
+            }
+            return;
+        }
+
+        self.stats.merged.fetch_add(run.len() as u64 - 1, Ordering::Relaxed);
+        let mut offset = 0;
+        for request in run {
+            let len = request.sectors * SECTOR_SIZE;
+            if first.op == Op::Read {
+                request.data.lock().copy_from_slice(&buffer[offset..offset + len]);
+            }
+            offset += len;
+            self.complete(request, Ok(()));
+        }
+    }
+
+    async fn execute_one(&self, request: &Request) {
+        self.stats.dispatches.fetch_add(1, Ordering::Relaxed);
+        // not locked across the transfer
+        let mut data = core::mem::take(&mut *request.data.lock());
+        let result = self.transfer(request.op, request.lba, &mut data).await;
+        *request.data.lock() = data;
+        self.complete(request, result);
+    }
+
+    async fn transfer(&self, op: Op, lba: u64, buffer: &mut [u8]) -> Result<(), AtaError> {
+        match op {
+            Op::Read => self.device.read(lba, buffer).await,
+            Op::Write => self.device.write(lba, buffer).await,
+        }
+    }
+
+    fn complete(&self, request: &Request, result: Result<(), AtaError>) {
+        let stats = &self.stats;
+        let latency = get_tsc().wrapping_sub(request.submitted);
+        match (request.op, &result) {
+            (_, Err(e)) => {
+                log::warn!("[block] {}: {:?} of {} sectors at LBA {} failed: {:?}", self.name, request.op, request.sectors, request.lba, e);
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+            },
+            (Op::Read, Ok(())) => {
+                stats.reads.fetch_add(1, Ordering::Relaxed);
+                stats.sectors_read.fetch_add(request.sectors as u64, Ordering::Relaxed);
+            },
+            (Op::Write, Ok(())) => {
+                stats.writes.fetch_add(1, Ordering::Relaxed);
+                stats.sectors_written.fetch_add(request.sectors as u64, Ordering::Relaxed);
+            },
+        }
+        stats.latency_cycles.fetch_add(latency, Ordering::Relaxed);
+        stats.max_latency_cycles.fetch_max(latency, Ordering::Relaxed);
+
+        *request.result.lock() = Some(result);
+        request.waker.wake();
+    }
+}
+
+/// The batch of the dispatching task. Clears `busy` when the task is done, or dropped in the
+/// middle of a transfer: the requests it didn't complete go back to the queue and their owners
+/// are woken to take over.
+struct Dispatch<'a> {
+    disk: &'a Disk,
+    batch: Vec<Arc<Request>>,
+}
+
+impl Drop for Dispatch<'_> {
+    fn drop(&mut self) {
+        let mut queue = self.disk.queue.lock();
+        let unfinished = self.batch.drain(..).filter(|request| request.result.lock().is_none());
+        queue.pending.extend(unfinished);
+        queue.busy = false;
+        for request in &queue.pending {
+            request.waker.wake();
+        }
+    }
+}
+
+static DISKS: Spinlock<Vec<Arc<Disk>>> = Spinlock::new(Vec::new());
+/// Names the disks disk0, disk1, ... in the order they were found
+static NEXT_DISK: AtomicUsize = AtomicUsize::new(0);
+
+/// Gives `device` a request queue and adds it to the disks
+pub fn register(device: Box<dyn BlockDevice>) -> Arc<Disk> {
+    let name = format!("disk{}", NEXT_DISK.fetch_add(1, Ordering::Relaxed));
+    let disk = Arc::new(Disk::new(name, device));
+    DISKS.lock().push(disk.clone());
+    disk
+}
+
+/// Takes the disks of the removed PCI function at `address` out of the disks, with their
+/// partitions and cached sectors, and returns them. Their requests fail from now on.
+pub fn unregister_pci(address: PciAddress) -> Vec<Arc<Disk>> {
+    let removed: Vec<Arc<Disk>> = {
+        let mut disks = DISKS.lock();
+        let (removed, kept) = core::mem::take(&mut *disks).into_iter()
+            .partition(|disk| disk.device.pci_address() == Some(address));
+        *disks = kept;
+        removed
+    };
+
+    for disk in &removed {
+        partition::unregister(disk);
+        cache::forget(disk);
+        sysinfo::remove(Category::Block, &disk.name);
+    }
+    removed
+}
+
+pub fn disks() -> Vec<Arc<Disk>> {
+    DISKS.lock().clone()
+}
+
+pub fn find(name: &str) -> Option<Arc<Disk>> {
+    DISKS.lock().iter().find(|disk| disk.name == name).cloned()
+}
+
+/// The `iostat` table
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    let tsc_khz = timer::tsc_frequency().map(|hz| hz / 1000).filter(|&khz| khz != 0);
+    writeln!(out, "disk        reads   writes    kB read  kB written  merged  calls  errors   IOPS  avg us  max us")?;
+    for disk in disks() {
+        let stats = disk.stats();
+        write!(out, "{:<8} {:>8} {:>8} {:>10} {:>11} {:>7} {:>6} {:>7}", disk.name, stats.reads, stats.writes,
+            stats.sectors_read * SECTOR_SIZE as u64 / 1024, stats.sectors_written * SECTOR_SIZE as u64 / 1024,
+            stats.merged, stats.dispatches, stats.errors)?;
+        match (stats.iops(), tsc_khz) {
+            (Some(iops), Some(khz)) => writeln!(out, " {:>6} {:>7} {:>7}", iops,
+                stats.average_latency_cycles() * 1000 / khz, stats.max_latency_cycles * 1000 / khz)?,
+            _ => writeln!(out, " {:>6} {:>7} {:>7}", "?", "?", "?")?,
+        }
+    }
+    cache::dump(out)
+}
@@ -0,0 +1,209 @@
+// Partitions as block devices.
+//
+// A `Partition` is a range of sectors of a disk, as listed by its partition table. Its LBAs are
+// relative to the start of the range and requests past its end are rejected, so a filesystem
+// can be mounted on it like on a whole disk. The I/O goes through the queue of the disk.
+//
+// `scan` finds out whether a disk has a GPT or an MBR partition table.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use futures_util::future::BoxFuture;
+use shared_lib::spinlock::Spinlock;
+use crate::gpt::{self, GptError};
+use crate::ide::{AtaError, BlockDevice, SECTOR_SIZE};
+use crate::mbr::{self, MbrError};
+use crate::smart::Health;
+use super::{cache, Disk};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionType {
+    Gpt(u128),
+    Mbr(u8),
+}
+
+impl fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PartitionType::Gpt(guid) => f.write_str(&gpt::guid_to_str(guid)),
+            PartitionType::Mbr(partition_type) => write!(f, "{:#04x} ({})", partition_type, mbr::type_name(partition_type)),
+        }
+    }
+}
+
+pub struct Partition {
+    disk: Arc<Disk>,
+    /// 1-based, the position in the partition table
+    index: usize,
+    start: u64,
+    sectors: u64,
+    partition_type: PartitionType,
+    /// Zero on MBR disks
+    unique_guid: u128,
+    name: String,
+}
+
+impl Partition {
+    /// Sectors `start..start + sectors` of `disk`
+    pub fn new(disk: Arc<Disk>, index: usize, start: u64, sectors: u64) -> Result<Partition, AtaError> {
+        if sectors == 0 || start.checked_add(sectors).map_or(true, |end| end > disk.size()) {
+            return Err(AtaError::OutOfRange);
+        }
+        let partition_type = PartitionType::Mbr(0);
+        Ok(Partition { disk, index, start, sectors, partition_type, unique_guid: 0, name: String::new() })
+    }
+
+    pub fn with_type(mut self, partition_type: PartitionType) -> Self {
+        self.partition_type = partition_type;
+        self
+    }
+
+    pub fn with_unique_guid(mut self, unique_guid: u128) -> Self {
+        self.unique_guid = unique_guid;
+        self
+    }
+
+    pub fn with_name(mut self, name: String) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub fn disk(&self) -> &Arc<Disk> {
+        &self.disk
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// e.g. `disk0p1`, unlike `name` unique
+    pub fn id(&self) -> String {
+        format!("{}p{}", self.disk.name(), self.index)
+    }
+
+    /// First LBA on the disk
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Last LBA on the disk, inclusive like in the partition table
+    pub fn end(&self) -> u64 {
+        self.start + self.sectors - 1
+    }
+
+    pub fn partition_type(&self) -> PartitionType {
+        self.partition_type
+    }
+
+    pub fn unique_guid(&self) -> u128 {
+        self.unique_guid
+    }
+
+    /// Empty if the partition table has no names
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The LBA on the disk
+    fn translate(&self, lba: u64, len: usize) -> Result<u64, AtaError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(AtaError::InvalidBufferSize);
+        }
+        match lba.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.sectors => Ok(self.start + lba),
+            _ => Err(AtaError::OutOfRange),
+        }
+    }
+}
+
+impl BlockDevice for Partition {
+    fn read<'a>(&'a self, lba: u64, buffer: &'a mut [u8]) -> BoxFuture<'a, Result<(), AtaError>> {
+        Box::pin(async move {
+            let lba = self.translate(lba, buffer.len())?;
+            self.disk.read(lba, buffer).await
+        })
+    }
+
+    fn write<'a>(&'a self, lba: u64, data: &'a [u8]) -> BoxFuture<'a, Result<(), AtaError>> {
+        Box::pin(async move {
+            let lba = self.translate(lba, data.len())?;
+            self.disk.write(lba, data).await
+        })
+    }
+
+    fn size(&self) -> u64 {
+        self.sectors
+    }
+
+    fn model(&self) -> [u8; 41] {
+        self.disk.device().model()
+    }
+
+    fn location(&self) -> String {
+        format!("{} partition {}", self.disk.name(), self.index)
+    }
+
+    /// Of the whole disk
+    fn health(&self) -> BoxFuture<'_, Result<Health, AtaError>> {
+        self.disk.device().health()
+    }
+}
+
+static PARTITIONS: Spinlock<Vec<Arc<Partition>>> = Spinlock::new(Vec::new());
+
+/// Makes the partitions of a registered disk available to `find`
+pub fn register(partitions: Vec<Partition>) {
+    PARTITIONS.lock().extend(partitions.into_iter().map(Arc::new));
+}
+
+/// Replaces the registered partitions of `disk` with those in its partition table now, returns
+/// how many there are
+pub async fn rescan(disk: &Arc<Disk>) -> Result<usize, ScanError> {
+    let partitions = scan(disk).await?;
+    let count = partitions.len();
+    let mut registered = PARTITIONS.lock();
+    registered.retain(|partition| partition.disk.id() != disk.id());
+    registered.extend(partitions.into_iter().map(Arc::new));
+    Ok(count)
+}
+
+/// Drops the registered partitions of `disk`
+pub fn unregister(disk: &Disk) {
+    PARTITIONS.lock().retain(|partition| partition.disk.id() != disk.id());
+}
+
+pub fn partitions() -> Vec<Arc<Partition>> {
+    PARTITIONS.lock().clone()
+}
+
+/// By `Partition::id`
+pub fn find(id: &str) -> Option<Arc<Partition>> {
+    PARTITIONS.lock().iter().find(|partition| partition.id() == id).cloned()
+}
+
+#[derive(Debug)]
+pub enum ScanError {
+    NoPartitionTable,
+    Read(AtaError),
+    Gpt(GptError),
+    Mbr(MbrError),
+}
+
+/// The partitions of `disk`, from its GPT if LBA 0 is a protective MBR, otherwise from its MBR
+pub async fn scan(disk: &Arc<Disk>) -> Result<Vec<Partition>, ScanError> {
+    let mut sector = [0u8; SECTOR_SIZE];
+    cache::read(disk, 0, &mut sector).await.map_err(ScanError::Read)?;
+    if !mbr::has_boot_signature(&sector) {
+        return Err(ScanError::NoPartitionTable);
+    }
+
+    if mbr::is_protective(&sector) {
+        gpt::parse_gpt(disk).await.map_err(ScanError::Gpt)
+    } else {
+        mbr::parse_mbr(disk).await.map_err(ScanError::Mbr)
+    }
+}
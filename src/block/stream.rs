@@ -0,0 +1,117 @@
+// Partitions as byte streams.
+//
+// Filesystems shared with the loader, like ferr_fs, are synchronous and work on
+// `shared_lib::io` streams. A `BlockStream` provides them over a partition or a whole disk: it
+// keeps a byte position and turns reads and writes into sector I/O through the block cache,
+// reading the sectors around unaligned writes first. The async cache calls are driven by
+// `thread::block_on`, so a stream is used from a kernel thread, e.g. under `spawn_blocking`.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use shared_lib::io::{seek_offset, IoError, Read, Seek, SeekFrom, Write};
+use crate::ide::{AtaError, BlockDevice, SECTOR_SIZE};
+use crate::thread;
+use super::cache;
+use super::partition::Partition;
+use super::Disk;
+
+/// Largest transfer of a single `read` or `write` call
+const MAX_TRANSFER_SECTORS: usize = 64;
+
+pub struct BlockStream {
+    disk: Arc<Disk>,
+    /// First LBA on the disk
+    start: u64,
+    sectors: u64,
+    position: u64,
+}
+
+impl BlockStream {
+    pub fn new(partition: &Partition) -> BlockStream {
+        BlockStream {
+            disk: partition.disk().clone(),
+            start: partition.start(),
+            sectors: partition.size(),
+            position: 0,
+        }
+    }
+
+    pub fn whole_disk(disk: Arc<Disk>) -> BlockStream {
+        let sectors = disk.size();
+        BlockStream { disk, start: 0, sectors, position: 0 }
+    }
+
+    fn len(&self) -> u64 {
+        self.sectors * SECTOR_SIZE as u64
+    }
+
+    /// The sectors covering up to `len` bytes from the position, clipped to the end and the
+    /// largest transfer: the first LBA, the offset of the position in it and the bytes covered
+    fn span(&self, len: usize) -> (u64, usize, usize) {
+        let lba = self.position / SECTOR_SIZE as u64;
+        let offset = (self.position % SECTOR_SIZE as u64) as usize;
+        let available = self.len().saturating_sub(self.position);
+        let len = (len as u64).min(available).min((MAX_TRANSFER_SECTORS * SECTOR_SIZE - offset) as u64) as usize;
+        (self.start + lba, offset, len)
+    }
+}
+
+fn device_error(e: AtaError) -> IoError {
+    log::warn!("[block] stream I/O failed: {:?}", e);
+    IoError::Device
+}
+
+impl Read for BlockStream {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, IoError> {
+        let (lba, offset, len) = self.span(buffer.len());
+        if len == 0 {
+            return Ok(0);
+        }
+        if offset == 0 && len % SECTOR_SIZE == 0 {
+            thread::block_on(cache::read(&self.disk, lba, &mut buffer[..len])).map_err(device_error)?;
+        } else {
+            let mut sectors = vec![0u8; (offset + len).next_multiple_of(SECTOR_SIZE)];
+            thread::block_on(cache::read(&self.disk, lba, &mut sectors)).map_err(device_error)?;
+            buffer[..len].copy_from_slice(&sectors[offset..offset + len]);
+        }
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for BlockStream {
+    fn write(&mut self, data: &[u8]) -> Result<usize, IoError> {
+        let (lba, offset, len) = self.span(data.len());
+        if len == 0 {
+            return if data.is_empty() { Ok(0) } else { Err(IoError::WriteZero) };
+        }
+        if offset == 0 && len % SECTOR_SIZE == 0 {
+            thread::block_on(cache::write(&self.disk, lba, &data[..len])).map_err(device_error)?;
+        } else {
+            let mut sectors = vec![0u8; (offset + len).next_multiple_of(SECTOR_SIZE)];
+            thread::block_on(async {
+                cache::read(&self.disk, lba, &mut sectors).await?;
+                sectors[offset..offset + len].copy_from_slice(&data[..len]);
+                cache::write(&self.disk, lba, &sectors).await
+            }).map_err(device_error)?;
+        }
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    /// Syncs the whole cache, the writeback isn't per disk
+    fn flush(&mut self) -> Result<(), IoError> {
+        thread::block_on(cache::sync()).map_err(device_error)
+    }
+}
+
+impl Seek for BlockStream {
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, IoError> {
+        self.position = match position {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => seek_offset(self.len(), offset)?,
+            SeekFrom::Current(offset) => seek_offset(self.position, offset)?,
+        };
+        Ok(self.position)
+    }
+}
@@ -1,5 +1,5 @@
 use chrono::{DateTime, TimeZone};
-use crate::port::Port;
+use shared_lib::nvram::read_register;
 
 pub fn read_rtc() -> DateTime<chrono::Utc> {
     let mut century: u8;
@@ -11,24 +11,10 @@ pub fn read_rtc() -> DateTime<chrono::Utc> {
     let mut second: u8;
 
     let update_in_progress = || -> bool {
-        let mut cmos_control_port = Port::new(0x70);
-        let mut cmos_data_port = Port::new(0x71);
-
-        unsafe {
-            cmos_control_port.write(0x0A);
-            (cmos_data_port.read() & 0x80) != 0
-        }
+        (read_register(0x0A) & 0x80) != 0
     };
 
-    let get_rtc_register = |reg: u8| -> u8 {
-        let mut cmos_control_port = Port::new(0x70);
-        let mut cmos_data_port = Port::new(0x71);
-
-        unsafe {
-            cmos_control_port.write(reg);
-            cmos_data_port.read()
-        }
-    };
+    let get_rtc_register = read_register;
 
     while update_in_progress() {};
 
@@ -0,0 +1,124 @@
+// Kernel command line options.
+//
+// The loader passes the command line in `BootInfo` (see `shared_lib::cmdline`), `init` keeps it
+// for the whole run and the functions below give the known options their types:
+//
+//   console=serial|fb      where the log goes, serial by default
+//   loglevel=<level>       off, error, warn, info, debug or trace
+//   init=<path>            program to start once the kernel is up
+//   noapic                 leave the IO-APIC alone, the keyboard goes through the legacy PIC
+//   nosmp                  only run on the bootstrap processor, don't start the others
+//   boot_slot=a|b          A/B kernel slot the loader started, added by the loader
+//
+// Options are read during `preinit`, before most of the kernel is up, so an invalid value falls
+// back to the default and `report` logs it once the logger exists.
+
+use alloc::string::{String, ToString};
+use core::fmt;
+use conquer_once::spin::OnceCell;
+use log::LevelFilter;
+use shared_lib::ab_boot::Slot;
+use shared_lib::cmdline::Cmdline;
+
+const KNOWN_OPTIONS: [&str; 6] = ["console", "loglevel", "init", "noapic", "nosmp", "boot_slot"];
+
+static CMDLINE: OnceCell<Cmdline> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    Serial,
+    Framebuffer,
+}
+
+/// Keeps the command line passed by the loader
+pub fn init(cmdline: Cmdline) {
+    CMDLINE.init_once(move || cmdline);
+}
+
+fn cmdline() -> Option<&'static Cmdline> {
+    CMDLINE.get()
+}
+
+pub fn as_str() -> &'static str {
+    cmdline().map_or("", |cmdline| cmdline.as_str())
+}
+
+/// Value of the last `key=value` option, empty for a bare `key`
+pub fn get(key: &str) -> Option<&'static str> {
+    cmdline()?.get(key)
+}
+
+pub fn has_flag(key: &str) -> bool {
+    get(key).is_some()
+}
+
+fn parse_console(value: &str) -> Option<Console> {
+    match value {
+        "serial" => Some(Console::Serial),
+        "fb" => Some(Console::Framebuffer),
+        _ => None,
+    }
+}
+
+pub fn console() -> Console {
+    get("console").and_then(parse_console).unwrap_or(Console::Serial)
+}
+
+/// Log level from `loglevel`, None if not given or invalid
+pub fn log_level() -> Option<LevelFilter> {
+    get("loglevel")?.parse().ok()
+}
+
+pub fn init_path() -> Option<&'static str> {
+    get("init").filter(|path| !path.is_empty())
+}
+
+/// False with `noapic`: interrupts are not routed through the IO-APIC
+pub fn io_apic_enabled() -> bool {
+    !has_flag("noapic")
+}
+
+/// False with `nosmp`: the application processors are not started
+pub fn smp_enabled() -> bool {
+    !has_flag("nosmp")
+}
+
+pub fn boot_slot() -> Option<Slot> {
+    get("boot_slot").and_then(Slot::from_name)
+}
+
+/// Logs the command line and the options which are unknown or have an invalid value
+pub fn report() {
+    log::info!("Command line: {}", as_str());
+
+    let Some(cmdline) = cmdline() else {
+        return;
+    };
+    for option in cmdline.options() {
+        let key = option.split_once('=').map_or(option, |(key, _)| key);
+        if !KNOWN_OPTIONS.contains(&key) {
+            log::warn!("[cmdline] unknown option {}", option);
+        }
+    }
+
+    if get("console").is_some_and(|value| parse_console(value).is_none()) {
+        log::warn!("[cmdline] invalid console, using serial");
+    }
+    if get("loglevel").is_some() && log_level().is_none() {
+        log::warn!("[cmdline] invalid loglevel, using debug");
+    }
+    if get("boot_slot").is_some() && boot_slot().is_none() {
+        log::warn!("[cmdline] invalid boot_slot");
+    }
+}
+
+/// Output of the `cmdline` shell command
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    writeln!(out, "{}", as_str())?;
+    writeln!(out, "console: {:?}", console())?;
+    writeln!(out, "loglevel: {}", log_level().map_or(String::from("default"), |level| level.to_string()))?;
+    writeln!(out, "init: {}", init_path().unwrap_or("none"))?;
+    writeln!(out, "io-apic: {}", if io_apic_enabled() { "enabled" } else { "disabled (noapic)" })?;
+    writeln!(out, "smp: {}", if smp_enabled() { "enabled" } else { "disabled (nosmp)" })?;
+    writeln!(out, "boot slot: {}", boot_slot().map_or("none", |slot| slot.name()))
+}
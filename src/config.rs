@@ -0,0 +1,144 @@
+// Runtime kernel settings.
+//
+// Settings are plain atomics so they can be read from any context, including the panic and
+// exception handlers. They are changed by key with `set`, e.g. from the `config` shell command.
+// Settings that should survive a reboot are also written to the CMOS NVRAM and restored by
+// `load_persistent`. The video mode and the boot entry only take effect on the next boot.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use shared_lib::nvram::{self, NvramError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PanicPolicy {
+    /// Halt the CPU after printing diagnostics
+    Halt = 0,
+    /// Reset the machine after `panic_reboot_delay` seconds
+    Reboot = 1,
+    /// Enter the synchronous debug shell on COM1
+    DebugShell = 2,
+}
+
+impl PanicPolicy {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => PanicPolicy::Reboot,
+            2 => PanicPolicy::DebugShell,
+            _ => PanicPolicy::Halt,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            PanicPolicy::Halt => "halt",
+            PanicPolicy::Reboot => "reboot",
+            PanicPolicy::DebugShell => "debug",
+        }
+    }
+}
+
+static PANIC_POLICY: AtomicU8 = AtomicU8::new(PanicPolicy::Halt as u8);
+static PANIC_REBOOT_DELAY_SECS: AtomicU64 = AtomicU64::new(5);
+static THERMAL_WARN_CELSIUS: AtomicU64 = AtomicU64::new(90);
+static POLL_BUDGET_MS: AtomicU64 = AtomicU64::new(10);
+
+#[derive(Debug)]
+pub enum ConfigError {
+    UnknownKey,
+    InvalidValue,
+    Nvram(NvramError),
+}
+
+pub fn panic_policy() -> PanicPolicy {
+    PanicPolicy::from_u8(PANIC_POLICY.load(Ordering::Relaxed))
+}
+
+pub fn set_panic_policy(policy: PanicPolicy) {
+    PANIC_POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+pub fn panic_reboot_delay_secs() -> u64 {
+    PANIC_REBOOT_DELAY_SECS.load(Ordering::Relaxed)
+}
+
+/// CPU temperature that triggers a warning
+pub fn thermal_warn_celsius() -> u32 {
+    THERMAL_WARN_CELSIUS.load(Ordering::Relaxed) as u32
+}
+
+/// Longest a single poll of a task may take before the executor warns about it, 0 disables
+/// the warning
+pub fn poll_budget_ms() -> u64 {
+    POLL_BUDGET_MS.load(Ordering::Relaxed)
+}
+
+/// Sets the setting `key` from its textual `value`.
+pub fn set(key: &str, value: &str) -> Result<(), ConfigError> {
+    match key {
+        "panic" => {
+            let policy = match value {
+                "halt" => PanicPolicy::Halt,
+                "reboot" => PanicPolicy::Reboot,
+                "debug" => PanicPolicy::DebugShell,
+                _ => return Err(ConfigError::InvalidValue),
+            };
+            set_panic_policy(policy);
+            nvram::write(nvram::SETTING_PANIC_POLICY, &[policy as u8]).map_err(ConfigError::Nvram)?;
+        },
+        "panic_reboot_delay" => {
+            let secs = value.parse().map_err(|_| ConfigError::InvalidValue)?;
+            PANIC_REBOOT_DELAY_SECS.store(secs, Ordering::Relaxed);
+        },
+        "thermal_warn" => {
+            let celsius: u8 = value.parse().map_err(|_| ConfigError::InvalidValue)?;
+            THERMAL_WARN_CELSIUS.store(celsius as u64, Ordering::Relaxed);
+        },
+        "poll_budget" => {
+            let ms = value.parse().map_err(|_| ConfigError::InvalidValue)?;
+            POLL_BUDGET_MS.store(ms, Ordering::Relaxed);
+        },
+        "video_mode" => {
+            // 0x0 lets the loader keep the firmware's mode
+            let (width, height) = match value {
+                "auto" => (0, 0),
+                _ => {
+                    let (width, height) = value.split_once('x').ok_or(ConfigError::InvalidValue)?;
+                    (width.parse::<u16>().map_err(|_| ConfigError::InvalidValue)?,
+                     height.parse::<u16>().map_err(|_| ConfigError::InvalidValue)?)
+                }
+            };
+            nvram::write(nvram::SETTING_VIDEO_WIDTH, &width.to_le_bytes()).map_err(ConfigError::Nvram)?;
+            nvram::write(nvram::SETTING_VIDEO_HEIGHT, &height.to_le_bytes()).map_err(ConfigError::Nvram)?;
+        },
+        "boot_entry" => {
+            let entry: u8 = value.parse().map_err(|_| ConfigError::InvalidValue)?;
+            nvram::write(nvram::SETTING_BOOT_ENTRY, &[entry]).map_err(ConfigError::Nvram)?;
+        },
+        _ => return Err(ConfigError::UnknownKey),
+    }
+    Ok(())
+}
+
+/// Restores the settings saved in the NVRAM by an earlier boot
+pub fn load_persistent() {
+    match nvram::read_u8(nvram::SETTING_PANIC_POLICY) {
+        Ok(policy) => set_panic_policy(PanicPolicy::from_u8(policy)),
+        Err(NvramError::NotInitialized) => {},
+        Err(e) => log::warn!("[config] NVRAM settings not restored: {:?}", e),
+    }
+}
+
+/// Writes all settings as `key = value` lines.
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    writeln!(out, "panic = {}", panic_policy().name())?;
+    writeln!(out, "panic_reboot_delay = {}", panic_reboot_delay_secs())?;
+    writeln!(out, "thermal_warn = {}", thermal_warn_celsius())?;
+    writeln!(out, "poll_budget = {}", poll_budget_ms())?;
+
+    match (nvram::read_u16(nvram::SETTING_VIDEO_WIDTH), nvram::read_u16(nvram::SETTING_VIDEO_HEIGHT)) {
+        (Ok(width), Ok(height)) if width != 0 && height != 0 => writeln!(out, "video_mode = {}x{}", width, height)?,
+        _ => writeln!(out, "video_mode = auto")?,
+    }
+    writeln!(out, "boot_entry = {}", nvram::read_u8(nvram::SETTING_BOOT_ENTRY).unwrap_or(0))
+}
@@ -0,0 +1,149 @@
+// Copy-on-write pages.
+//
+// A frame can be mapped at several pages read-only with `PageTableFlags::COPY_ON_WRITE`, the
+// first write to one of them faults and `handle_fault` gives that page a private copy. This is
+// the groundwork for fork() and for handing the initrd to readers without copying it up front.
+//
+// `SHARED_FRAMES` counts the references to each frame which has more than one, a frame missing
+// from it has a single owner. `share` counts both pages, `map_shared` counts the frame's owner
+// outside of the COW mappings, so the frame itself is never written through them. A write by the
+// last reference keeps the frame instead of copying it. The fault path never allocates on the
+// heap: entries are added when sharing and only decremented or swap-removed there.
+//
+// Without CR0.WP the CPU ignores read-only pages in ring 0, a kernel write would go straight to
+// the shared frame: `enable_write_protect` runs on every CPU before anything is shared.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use shared_lib::addr::VirtAddr;
+use shared_lib::page_table::{align_down_u64, break_cow, cow_frame, map_cow, unmap_address_with_offset, PageTableFlags};
+use shared_lib::phys_mapping_offset;
+use shared_lib::spinlock::Spinlock;
+use crate::allocator::FRAME_ALLOCATOR;
+use crate::memory::{active_level_4_table, translate_addr};
+
+const CR0_WP: u64 = 1 << 16;
+
+/// Frames with more than one reference and their reference count
+static SHARED_FRAMES: Spinlock<Vec<(u64, usize)>> = Spinlock::new(Vec::new());
+
+/// Makes ring 0 writes honor read-only pages on this CPU
+pub fn enable_write_protect() {
+    // SAFETY: the kernel maps nothing read-only that it writes to, except the COW pages which
+    // rely on this
+    unsafe {
+        let mut cr0: u64;
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+        cr0 |= CR0_WP;
+        asm!("mov cr0, {}", in(reg) cr0, options(nostack, preserves_flags));
+    }
+}
+
+fn add_reference(shared_frames: &mut Vec<(u64, usize)>, frame: u64, initial: usize) {
+    match shared_frames.iter_mut().find(|(shared, _)| *shared == frame) {
+        Some((_, count)) => *count += 1,
+        None => shared_frames.push((frame, initial)),
+    }
+}
+
+/// Drops one reference to `frame`, returns the references left
+fn drop_reference(shared_frames: &mut Vec<(u64, usize)>, frame: u64) -> usize {
+    let Some(index) = shared_frames.iter().position(|(shared, _)| *shared == frame) else {
+        return 0;
+    };
+
+    shared_frames[index].1 -= 1;
+    let left = shared_frames[index].1;
+    if left == 1 {
+        shared_frames.swap_remove(index);
+    }
+    left
+}
+
+/// Maps `virt` to `frame` copy-on-write, `frame` stays owned by its current user, e.g. the
+/// initrd. `flags` are the permissions of the page once written to. Only available after
+/// `enable_heap_growth`.
+pub fn map_shared(virt: VirtAddr, frame: u64, flags: PageTableFlags) -> Result<(), &'static str> {
+    let mut shared_frames = SHARED_FRAMES.lock();
+    // growing the heap needs the frame allocator, which is held below
+    shared_frames.reserve(1);
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().ok_or("frame allocator is not available")?;
+
+    unsafe {
+        map_cow(active_level_4_table(), virt, frame, flags, frame_allocator, phys_mapping_offset())?;
+    }
+    add_reference(&mut shared_frames, frame, 2);
+    Ok(())
+}
+
+/// Maps `dst` to the frame of the mapped page `src`, both pages become copy-on-write. `flags`
+/// are the permissions of the pages once written to.
+pub fn share(src: VirtAddr, dst: VirtAddr, flags: PageTableFlags) -> Result<(), &'static str> {
+    let mut shared_frames = SHARED_FRAMES.lock();
+    // growing the heap needs the frame allocator, which is held below
+    shared_frames.reserve(1);
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().ok_or("frame allocator is not available")?;
+
+    unsafe {
+        let l4_table = active_level_4_table();
+        let frame = translate_addr(src).map(align_down_u64).ok_or("the source page is not mapped")?;
+        map_cow(l4_table, src, frame, flags, frame_allocator, phys_mapping_offset())?;
+        map_cow(l4_table, dst, frame, flags, frame_allocator, phys_mapping_offset())?;
+        add_reference(&mut shared_frames, frame, 2);
+    }
+    Ok(())
+}
+
+/// Unmaps the COW page `virt`, its frame is freed if this was the last reference.
+pub fn unmap(virt: VirtAddr) -> Result<(), &'static str> {
+    let mut shared_frames = SHARED_FRAMES.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().ok_or("frame allocator is not available")?;
+
+    unsafe {
+        let l4_table = active_level_4_table();
+        let frame = cow_frame(l4_table, virt, phys_mapping_offset())?.ok_or("this virtual address is not mapped copy-on-write")?;
+        unmap_address_with_offset(l4_table, virt, phys_mapping_offset())?;
+        if drop_reference(&mut shared_frames, frame) == 0 {
+            frame_allocator.deallocate_frame(frame);
+        }
+    }
+    Ok(())
+}
+
+/// Resolves a write protection fault at `addr` if it hit a COW page. Returns false if it did
+/// not, or no frame could be allocated for the copy; the fault is fatal then.
+pub fn handle_fault(addr: u64) -> bool {
+    // the fault may hit while one of the locks is held: don't deadlock
+    let Some(mut shared_frames) = SHARED_FRAMES.try_lock() else {
+        return false;
+    };
+    let virt = VirtAddr::new(addr);
+
+    unsafe {
+        let l4_table = active_level_4_table();
+        let Ok(Some(shared)) = cow_frame(l4_table, virt, phys_mapping_offset()) else {
+            return false;
+        };
+
+        if !shared_frames.iter().any(|(frame, _)| *frame == shared) {
+            // last reference, the page keeps the frame
+            return break_cow(l4_table, virt, shared, phys_mapping_offset()).is_ok();
+        }
+
+        let Some(mut frame_allocator) = FRAME_ALLOCATOR.try_lock() else {
+            return false;
+        };
+        let Some(private) = frame_allocator.as_mut().and_then(|allocator| allocator.allocate_frame()) else {
+            return false;
+        };
+        if break_cow(l4_table, virt, private, phys_mapping_offset()).is_err() {
+            frame_allocator.as_mut().unwrap().deallocate_frame(private);
+            return false;
+        }
+        drop_reference(&mut shared_frames, shared);
+    }
+    true
+}
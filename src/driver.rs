@@ -0,0 +1,184 @@
+// Driver registry and init ordering.
+//
+// Subsystems register an async init function under a name, together with the names of the
+// drivers they need, e.g. a filesystem mount depends on the block devices it mounts. `init_all`
+// runs the init functions one after another in dependency order: a driver starts once all of its
+// dependencies are ready and is skipped when one of them failed, was never registered or is part
+// of a cycle. Long-running tasks that need a subsystem wait for it with `ready`.
+//
+// The platform setup in `preinit` (GDT, IDT, APIC) is not a driver: the executor runs on the
+// timer interrupt, so it has to be in place before any init function can be polled.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
+use core::task::{Poll, Waker};
+use shared_lib::spinlock::Spinlock;
+use crate::task::timer;
+
+pub type InitResult = Result<(), &'static str>;
+
+type InitFuture = Pin<Box<dyn Future<Output = InitResult>>>;
+type InitFn = Box<dyn FnOnce() -> InitFuture + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriverState {
+    Registered,
+    Initializing,
+    Ready,
+    Failed(&'static str),
+    /// A dependency failed, is missing or is part of a dependency cycle
+    Skipped,
+}
+
+#[derive(Debug)]
+pub enum DriverError {
+    AlreadyRegistered,
+}
+
+struct Driver {
+    name: &'static str,
+    depends_on: &'static [&'static str],
+    init: Option<InitFn>,
+    state: DriverState,
+}
+
+static DRIVERS: Spinlock<Vec<Driver>> = Spinlock::new(Vec::new());
+static WAITERS: Spinlock<Vec<Waker>> = Spinlock::new(Vec::new());
+
+/// Registers `init` to run once all drivers in `depends_on` are ready.
+pub fn register<F>(name: &'static str, depends_on: &'static [&'static str], init: fn() -> F) -> Result<(), DriverError>
+    where F: Future<Output = InitResult> + 'static
+{
+    let mut drivers = DRIVERS.lock();
+    if drivers.iter().any(|driver| driver.name == name) {
+        return Err(DriverError::AlreadyRegistered);
+    }
+
+    drivers.push(Driver {
+        name,
+        depends_on,
+        init: Some(Box::new(move || Box::pin(init()) as InitFuture)),
+        state: DriverState::Registered,
+    });
+    Ok(())
+}
+
+fn state_of(drivers: &[Driver], name: &str) -> Option<DriverState> {
+    drivers.iter().find(|driver| driver.name == name).map(|driver| driver.state)
+}
+
+fn wake_waiters() {
+    for waker in WAITERS.lock().drain(..) {
+        waker.wake();
+    }
+}
+
+/// Takes the init function of the first registered driver whose dependencies are all ready and
+/// marks it as initializing. Drivers that can never start because of a dependency are skipped.
+fn next_runnable() -> Option<(&'static str, InitFn)> {
+    let mut drivers = DRIVERS.lock();
+    loop {
+        let mut skipped_any = false;
+        for i in 0..drivers.len() {
+            if drivers[i].state != DriverState::Registered {
+                continue;
+            }
+
+            let mut all_ready = true;
+            for dependency in drivers[i].depends_on {
+                match state_of(&drivers, dependency) {
+                    Some(DriverState::Ready) => {},
+                    Some(DriverState::Registered) | Some(DriverState::Initializing) => all_ready = false,
+                    state => {
+                        let reason = match state {
+                            None => "is not registered",
+                            Some(DriverState::Failed(_)) => "failed",
+                            _ => "was skipped",
+                        };
+                        log::warn!("[driver] skipping {}: dependency {} {}", drivers[i].name, dependency, reason);
+                        drivers[i].state = DriverState::Skipped;
+                        skipped_any = true;
+                        all_ready = false;
+                        break;
+                    }
+                }
+            }
+
+            if all_ready {
+                drivers[i].state = DriverState::Initializing;
+                return Some((drivers[i].name, drivers[i].init.take().unwrap()));
+            }
+        }
+
+        // a skipped driver may block others in turn
+        if !skipped_any {
+            return None;
+        }
+    }
+}
+
+fn set_state(name: &'static str, state: DriverState) {
+    if let Some(driver) = DRIVERS.lock().iter_mut().find(|driver| driver.name == name) {
+        driver.state = state;
+    }
+    wake_waiters();
+}
+
+/// Initializes all registered drivers in dependency order.
+pub async fn init_all() {
+    while let Some((name, init)) = next_runnable() {
+        log::info!("[driver] initializing {}", name);
+        let start = timer::ticks();
+
+        let state = match init().await {
+            Ok(()) => {
+                log::info!("[driver] {} ready after {} ticks", name, timer::ticks() - start);
+                DriverState::Ready
+            },
+            Err(e) => {
+                log::error!("[driver] {} failed: {}", name, e);
+                DriverState::Failed(e)
+            },
+        };
+        set_state(name, state);
+    }
+
+    // whatever is left waits on itself through its dependencies
+    for driver in DRIVERS.lock().iter_mut().filter(|driver| driver.state == DriverState::Registered) {
+        log::error!("[driver] skipping {}: dependency cycle", driver.name);
+        driver.state = DriverState::Skipped;
+    }
+    wake_waiters();
+}
+
+/// Resolves once the driver `name` is ready, or with its state if it failed or was skipped.
+/// Waits for drivers that aren't registered yet.
+pub async fn ready(name: &'static str) -> Result<(), DriverState> {
+    poll_fn(|cx| {
+        let drivers = DRIVERS.lock();
+        match state_of(&drivers, name) {
+            Some(DriverState::Ready) => Poll::Ready(Ok(())),
+            Some(state @ (DriverState::Failed(_) | DriverState::Skipped)) => Poll::Ready(Err(state)),
+            _ => {
+                // registered with the driver list locked, so a state change can't slip in between
+                WAITERS.lock().push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }).await
+}
+
+/// Writes one line per driver with its state and dependencies.
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    for driver in DRIVERS.lock().iter() {
+        write!(out, "{}: {:?}", driver.name, driver.state)?;
+        if !driver.depends_on.is_empty() {
+            write!(out, ", depends on {}", driver.depends_on.join(", "))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,136 @@
+// UEFI runtime services.
+//
+// The loader moves the runtime services to virtual addresses with `SetVirtualAddressMap` before
+// jumping to the kernel and passes the address of the runtime services table in `BootInfo`. The
+// firmware code runs on the kernel's stack and page tables, with interrupts disabled: it isn't
+// reentrant.
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use shared_lib::interrupts::without_interrupts;
+use shared_lib::spinlock::Spinlock;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+/// `EFI_GLOBAL_VARIABLE`: `BootOrder`, `Timeout`, `PlatformLang` and so on
+pub const GLOBAL_VARIABLE: Guid = Guid {
+    data1: 0x8BE4DF61,
+    data2: 0x93CA,
+    data3: 0x11D2,
+    data4: [0xAA, 0x0D, 0x00, 0xE0, 0x98, 0x03, 0x2B, 0x8C],
+};
+
+const EFI_SUCCESS: usize = 0;
+const ERROR_BIT: usize = 1 << 63;
+const EFI_BUFFER_TOO_SMALL: usize = ERROR_BIT | 5;
+const EFI_NOT_FOUND: usize = ERROR_BIT | 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EfiError {
+    /// The loader couldn't set up the runtime services
+    Unavailable,
+    NotFound,
+    /// The variable needs a buffer of this size
+    BufferTooSmall(usize),
+    Status(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ResetType {
+    Cold = 0,
+    Warm = 1,
+    Shutdown = 2,
+}
+
+#[repr(C)]
+struct TableHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    reserved: u32,
+}
+
+/// `EFI_RUNTIME_SERVICES`, up to the calls we use
+#[repr(C)]
+struct RuntimeServices {
+    header: TableHeader,
+    get_time: usize,
+    set_time: usize,
+    get_wakeup_time: usize,
+    set_wakeup_time: usize,
+    set_virtual_address_map: usize,
+    convert_pointer: usize,
+    get_variable: unsafe extern "efiapi" fn(name: *const u16, vendor: *const Guid, attributes: *mut u32,
+                                            data_size: *mut usize, data: *mut u8) -> usize,
+    get_next_variable_name: usize,
+    set_variable: usize,
+    get_next_high_monotonic_count: usize,
+    reset_system: unsafe extern "efiapi" fn(reset_type: ResetType, status: usize, data_size: usize, data: *const u8) -> !,
+}
+
+const RUNTIME_SERVICES_SIGNATURE: u64 = 0x56524553544e5552; // "RUNTSERV"
+
+static RUNTIME_SERVICES: OnceCell<&'static RuntimeServices> = OnceCell::uninit();
+/// Runtime services must not be entered twice at the same time
+static EFI_LOCK: Spinlock<()> = Spinlock::new(());
+
+/// Takes the runtime services table passed by the loader, 0 means there is none
+pub fn init(runtime_services: u64) {
+    if runtime_services == 0 {
+        log::info!("[efi] runtime services not available");
+        return;
+    }
+
+    let table = unsafe { &*(runtime_services as *const RuntimeServices) };
+    if table.header.signature != RUNTIME_SERVICES_SIGNATURE {
+        log::warn!("[efi] bad runtime services signature {:#x}", table.header.signature);
+        return;
+    }
+
+    log::info!("[efi] runtime services revision {}.{}", table.header.revision >> 16, table.header.revision & 0xFFFF);
+    RUNTIME_SERVICES.init_once(|| table);
+}
+
+pub fn is_available() -> bool {
+    RUNTIME_SERVICES.get().is_some()
+}
+
+/// Reads the variable `name` into `buffer`. Returns its size and attributes.
+pub fn get_variable(name: &str, vendor: &Guid, buffer: &mut [u8]) -> Result<(usize, u32), EfiError> {
+    let services = RUNTIME_SERVICES.get().ok_or(EfiError::Unavailable)?;
+
+    let name: Vec<u16> = name.encode_utf16().chain(core::iter::once(0)).collect();
+    let mut attributes = 0;
+    let mut size = buffer.len();
+
+    let status = without_interrupts(|| {
+        let _guard = EFI_LOCK.lock();
+        unsafe { (services.get_variable)(name.as_ptr(), vendor, &mut attributes, &mut size, buffer.as_mut_ptr()) }
+    });
+
+    match status {
+        EFI_SUCCESS => Ok((size, attributes)),
+        EFI_NOT_FOUND => Err(EfiError::NotFound),
+        EFI_BUFFER_TOO_SMALL => Err(EfiError::BufferTooSmall(size)),
+        status => Err(EfiError::Status(status)),
+    }
+}
+
+/// Resets or powers off the machine through the firmware. Returns only if the runtime services
+/// are unavailable.
+pub fn reset(reset_type: ResetType) {
+    let Some(services) = RUNTIME_SERVICES.get() else {
+        return;
+    };
+    log::info!("[efi] {:?} reset", reset_type);
+    without_interrupts(|| unsafe { (services.reset_system)(reset_type, EFI_SUCCESS, 0, core::ptr::null()) })
+}
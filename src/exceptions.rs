@@ -0,0 +1,350 @@
+// Exception entry with a full register snapshot.
+//
+// `x86-interrupt` handlers only see the interrupt stack frame, so the exceptions which end in a
+// report and page faults enter through the stubs below instead: each pushes a dummy error code
+// if the CPU doesn't push one and the vector, `exception_common` pushes every general purpose
+// register and calls `dispatch` with the resulting `ExceptionFrame`. When `dispatch` returns, the
+// registers are restored from the frame and the interrupted code resumes, which is how resolved
+// page faults return. Everything else is fatal: `fatal` logs the registers and panics, the
+// frame pointer chain of the interrupted code continues above the stub, so the panic backtrace
+// shows its callers.
+//
+// The vectors handed out by `interrupts::register_irq` enter the same way, through the
+// `irq_stubs` table, and are passed on to `interrupts::dispatch_irq`.
+//
+// Breakpoint, debug, NMI and double fault keep their `x86-interrupt` handlers in `interrupts`.
+
+use core::arch::{asm, global_asm};
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use shared_lib::addr::VirtAddr;
+use shared_lib::msr;
+use crate::gdt;
+use crate::idt::{InterruptDescriptorTable, InterruptStackFrameValue, PageFaultErrorCode};
+use crate::interrupts;
+use crate::symbols::Resolved;
+
+const PAGE_FAULT: u64 = 14;
+const MACHINE_CHECK: u64 = 18;
+const SIMD_FLOATING_POINT: u64 = 19;
+
+/// CPUID.1:EDX, machine check architecture
+const CPUID_MCA: u32 = 1 << 14;
+
+/// General purpose registers of the interrupted code, in the order `exception_common` pushes them
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct GeneralRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+impl fmt::Display for GeneralRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "RAX {:#018x} RBX {:#018x} RCX {:#018x}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "RDX {:#018x} RSI {:#018x} RDI {:#018x}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "RBP {:#018x} R8  {:#018x} R9  {:#018x}", self.rbp, self.r8, self.r9)?;
+        writeln!(f, "R10 {:#018x} R11 {:#018x} R12 {:#018x}", self.r10, self.r11, self.r12)?;
+        write!(f, "R13 {:#018x} R14 {:#018x} R15 {:#018x}", self.r13, self.r14, self.r15)
+    }
+}
+
+/// Stack of an exception entered through a stub, lowest address first
+#[derive(Debug)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub registers: GeneralRegisters,
+    pub vector: u64,
+    /// 0 for the exceptions which don't push one
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+impl ExceptionFrame {
+    pub fn instruction_pointer(&self) -> u64 {
+        self.stack_frame.instruction_pointer.0
+    }
+}
+
+// The CPU aligns RSP to 16 bytes before pushing its 5 words, with the error code, the vector and
+// the 15 registers the frame is 22 words: RSP is still aligned for the call.
+global_asm!(
+    ".global exception_common",
+    "exception_common:",
+    "    push rax",
+    "    push rbx",
+    "    push rcx",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push rbp",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    push r11",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov rdi, rsp",
+    "    cld",
+    "    call {dispatch}",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rbp",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rbx",
+    "    pop rax",
+    // vector and error code
+    "    add rsp, 16",
+    "    iretq",
+    dispatch = sym dispatch,
+);
+
+macro_rules! exception_stub {
+    ($stub:ident, $vector:literal) => {
+        global_asm!(concat!(
+            ".global ", stringify!($stub), "\n",
+            stringify!($stub), ":\n",
+            "    push 0\n",
+            "    push ", stringify!($vector), "\n",
+            "    jmp exception_common\n",
+        ));
+        extern "C" { fn $stub(); }
+    };
+    ($stub:ident, $vector:literal, error_code) => {
+        global_asm!(concat!(
+            ".global ", stringify!($stub), "\n",
+            stringify!($stub), ":\n",
+            "    push ", stringify!($vector), "\n",
+            "    jmp exception_common\n",
+        ));
+        extern "C" { fn $stub(); }
+    };
+}
+
+exception_stub!(exception_divide_error, 0);
+exception_stub!(exception_overflow, 4);
+exception_stub!(exception_bound_range_exceeded, 5);
+exception_stub!(exception_invalid_opcode, 6);
+exception_stub!(exception_device_not_available, 7);
+exception_stub!(exception_invalid_tss, 10, error_code);
+exception_stub!(exception_segment_not_present, 11, error_code);
+exception_stub!(exception_stack_segment_fault, 12, error_code);
+exception_stub!(exception_general_protection_fault, 13, error_code);
+exception_stub!(exception_page_fault, 14, error_code);
+exception_stub!(exception_x87_floating_point, 16);
+exception_stub!(exception_alignment_check, 17, error_code);
+exception_stub!(exception_machine_check, 18);
+exception_stub!(exception_simd_floating_point, 19);
+exception_stub!(exception_virtualization, 20);
+exception_stub!(exception_control_protection, 21, error_code);
+exception_stub!(exception_hv_injection, 28);
+exception_stub!(exception_vmm_communication, 29, error_code);
+exception_stub!(exception_security_exception, 30, error_code);
+
+/// Size of each stub in `irq_stubs`, see `irq_stub`
+const IRQ_STUB_SIZE: u64 = 16;
+
+// One stub per dynamic vector, each padded to `IRQ_STUB_SIZE`
+global_asm!(
+    ".global irq_stubs",
+    ".balign 16",
+    "irq_stubs:",
+    ".set irq_vector, {first}",
+    ".rept {count}",
+    "    .balign 16",
+    "    push 0",
+    "    push irq_vector",
+    "    jmp exception_common",
+    "    .set irq_vector, irq_vector + 1",
+    ".endr",
+    first = const interrupts::FIRST_DYNAMIC_VECTOR,
+    count = const interrupts::DYNAMIC_VECTORS,
+);
+
+extern "C" { fn irq_stubs(); }
+
+/// Entry point of the dynamic vector `vector`
+pub fn irq_stub(vector: u8) -> VirtAddr {
+    let index = u64::from(vector - interrupts::FIRST_DYNAMIC_VECTOR);
+    VirtAddr::new(stub_addr(irq_stubs).0 + index * IRQ_STUB_SIZE)
+}
+
+fn stub_addr(stub: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(stub as u64)
+}
+
+/// Points the IDT entries of the exceptions handled here at their stubs
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_addr(stub_addr(exception_divide_error));
+        idt.overflow.set_handler_addr(stub_addr(exception_overflow));
+        idt.bound_range_exceeded.set_handler_addr(stub_addr(exception_bound_range_exceeded));
+        idt.invalid_opcode.set_handler_addr(stub_addr(exception_invalid_opcode));
+        idt.device_not_available.set_handler_addr(stub_addr(exception_device_not_available));
+        idt.invalid_tss.set_handler_addr(stub_addr(exception_invalid_tss));
+        idt.segment_not_present.set_handler_addr(stub_addr(exception_segment_not_present));
+        idt.stack_segment_fault.set_handler_addr(stub_addr(exception_stack_segment_fault));
+        idt.general_protection_fault.set_handler_addr(stub_addr(exception_general_protection_fault));
+        idt.page_fault.set_handler_addr(stub_addr(exception_page_fault))
+            .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        idt.x87_floating_point.set_handler_addr(stub_addr(exception_x87_floating_point));
+        idt.alignment_check.set_handler_addr(stub_addr(exception_alignment_check));
+        idt.machine_check.set_handler_addr(stub_addr(exception_machine_check))
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point.set_handler_addr(stub_addr(exception_simd_floating_point));
+        idt.virtualization.set_handler_addr(stub_addr(exception_virtualization));
+        idt.cp_protection_exception.set_handler_addr(stub_addr(exception_control_protection));
+        idt.hv_injection_exception.set_handler_addr(stub_addr(exception_hv_injection));
+        idt.vmm_communication_exception.set_handler_addr(stub_addr(exception_vmm_communication));
+        idt.security_exception.set_handler_addr(stub_addr(exception_security_exception));
+    }
+}
+
+pub fn exception_name(vector: u64) -> &'static str {
+    match vector {
+        0 => "DIVIDE ERROR",
+        1 => "DEBUG",
+        2 => "NON-MASKABLE INTERRUPT",
+        3 => "BREAKPOINT",
+        4 => "OVERFLOW",
+        5 => "BOUND RANGE EXCEEDED",
+        6 => "INVALID OPCODE",
+        7 => "DEVICE NOT AVAILABLE",
+        8 => "DOUBLE FAULT",
+        10 => "INVALID TSS",
+        11 => "SEGMENT NOT PRESENT",
+        12 => "STACK SEGMENT FAULT",
+        13 => "GENERAL PROTECTION FAULT",
+        14 => "PAGE FAULT",
+        16 => "X87 FLOATING POINT",
+        17 => "ALIGNMENT CHECK",
+        18 => "MACHINE CHECK",
+        19 => "SIMD FLOATING POINT",
+        20 => "VIRTUALIZATION",
+        21 => "CONTROL PROTECTION",
+        28 => "HYPERVISOR INJECTION",
+        29 => "VMM COMMUNICATION",
+        30 => "SECURITY EXCEPTION",
+        _ => "RESERVED",
+    }
+}
+
+extern "C" fn dispatch(frame: &ExceptionFrame) {
+    if frame.vector >= u64::from(interrupts::FIRST_DYNAMIC_VECTOR) {
+        interrupts::dispatch_irq(frame.vector as u8);
+        return;
+    }
+    if frame.vector == PAGE_FAULT && interrupts::handle_page_fault(frame) {
+        return;
+    }
+    fatal(frame);
+}
+
+fn read_cr2() -> u64 {
+    let cr2: u64;
+    unsafe {
+        asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+    }
+    cr2
+}
+
+fn log_control_registers() {
+    let (cr0, cr3, cr4): (u64, u64, u64);
+    unsafe {
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+    }
+    log::error!("CR0 {:#010x} CR2 {:#018x} CR3 {:#018x} CR4 {:#010x}", cr0, read_cr2(), cr3, cr4);
+}
+
+/// Decodes the error code of the exceptions which report a segment selector
+fn log_selector_error_code(error_code: u64) {
+    let table = match (error_code >> 1) & 0b11 {
+        0 => "GDT",
+        2 => "LDT",
+        _ => "IDT",
+    };
+    log::error!("Selector: {} index {}{}", table, (error_code >> 3) & 0x1FFF,
+        if error_code & 1 != 0 { ", external event" } else { "" });
+}
+
+fn log_machine_check_banks() {
+    if __cpuid(1).edx & CPUID_MCA == 0 {
+        return;
+    }
+
+    unsafe {
+        let banks = msr::read(msr::IA32_MCG_CAP) & 0xFF;
+        log::error!("MCG_STATUS: {:#x}", msr::read(msr::IA32_MCG_STATUS));
+        for bank in 0..banks as u32 {
+            let status = msr::read(msr::IA32_MC0_STATUS + 4 * bank);
+            // VAL: the bank logged an error
+            if status & (1 << 63) != 0 {
+                log::error!("MC{}_STATUS: {:#018x}", bank, status);
+            }
+        }
+    }
+}
+
+/// Logs the exception and the registers of the interrupted code, then panics
+fn fatal(frame: &ExceptionFrame) -> ! {
+    interrupts::unlock_loggers();
+
+    let name = exception_name(frame.vector);
+    log::error!("EXCEPTION: {} (vector {})", name, frame.vector);
+    match frame.vector {
+        // invalid TSS, segment not present, stack segment fault, general protection fault
+        10..=13 if frame.error_code != 0 => {
+            log::error!("Error code: {:#x}", frame.error_code);
+            log_selector_error_code(frame.error_code);
+        },
+        PAGE_FAULT => {
+            log::error!("Accessed address: {:#x}", read_cr2());
+            log::error!("Error code: {:?}", PageFaultErrorCode::from_bits_truncate(frame.error_code));
+        },
+        MACHINE_CHECK => log_machine_check_banks(),
+        SIMD_FLOATING_POINT => {
+            let mut mxcsr: u32 = 0;
+            unsafe {
+                asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack, preserves_flags));
+            }
+            log::error!("MXCSR: {:#x}", mxcsr);
+        },
+        17 | 21 | 29 | 30 => log::error!("Error code: {:#x}", frame.error_code),
+        _ => {}
+    }
+
+    let stack_frame = &frame.stack_frame;
+    log::error!("RIP {:#018x} CS {:#06x} RFLAGS {:#010x}", stack_frame.instruction_pointer.0, stack_frame.code_segment, stack_frame.cpu_flags);
+    log::error!("RSP {:#018x} SS {:#06x}", stack_frame.stack_pointer.0, stack_frame.stack_segment);
+    log::error!("{}", frame.registers);
+    log_control_registers();
+    log::error!("Faulting instruction: {}", Resolved(frame.instruction_pointer()));
+
+    panic!("EXCEPTION: {} at {}", name, Resolved(frame.instruction_pointer()));
+}
@@ -0,0 +1,235 @@
+// File descriptors.
+//
+// Open files over the path-based calls of the VFS: `FdTable::open` resolves a path once, checks
+// it against the `OpenFlags`, and hands out a descriptor that `read`, `write` and `seek` use
+// with a position of its own. Each process gets a table; until there are processes, each shell
+// session has one. Descriptors 0, 1 and 2 are the standard streams: the input has nothing to
+// read yet, the output and the error go to the kernel log. A file gets the lowest free number,
+// like on Unix.
+//
+// The table only locks to look a descriptor up, the VFS calls happen outside of it and can
+// block: like the VFS, everything but `dump` must run on a kernel thread.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use shared_lib::io::{seek_offset, SeekFrom};
+use shared_lib::spinlock::Spinlock;
+use crate::vfs::{self, FileFlags, FileType, VfsError};
+
+/// Descriptors a table holds at most, the standard streams included
+pub const MAX_FILES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileError {
+    Vfs(VfsError),
+    /// No such descriptor open
+    BadDescriptor,
+    /// Neither `READ` nor `WRITE`
+    InvalidFlags,
+    NotReadable,
+    NotWritable,
+    /// Before the start of the file
+    InvalidSeek,
+    TooManyFiles,
+}
+
+impl From<VfsError> for FileError {
+    fn from(e: VfsError) -> Self {
+        FileError::Vfs(e)
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct OpenFlags: u32 {
+        const READ = 1;
+        const WRITE = 1 << 1;
+        /// Create the file if it doesn't exist
+        const CREATE = 1 << 2;
+        /// Every write goes to the end of the file
+        const APPEND = 1 << 3;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Fd(pub usize);
+
+pub const STDIN: Fd = Fd(0);
+pub const STDOUT: Fd = Fd(1);
+pub const STDERR: Fd = Fd(2);
+
+impl fmt::Display for Fd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+struct OpenFile {
+    path: String,
+    flags: OpenFlags,
+    position: AtomicU64,
+}
+
+#[derive(Clone)]
+enum Descriptor {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(Arc<OpenFile>),
+}
+
+pub struct FdTable {
+    descriptors: Spinlock<Vec<Option<Descriptor>>>,
+}
+
+impl FdTable {
+    /// A table with the standard streams open
+    pub fn new() -> Self {
+        let descriptors = alloc::vec![Some(Descriptor::Stdin), Some(Descriptor::Stdout), Some(Descriptor::Stderr)];
+        FdTable { descriptors: Spinlock::new(descriptors) }
+    }
+
+    fn get(&self, fd: Fd) -> Result<Descriptor, FileError> {
+        self.descriptors.lock().get(fd.0).cloned().flatten().ok_or(FileError::BadDescriptor)
+    }
+
+    pub fn open(&self, path: &str, flags: OpenFlags) -> Result<Fd, FileError> {
+        if !flags.intersects(OpenFlags::READ | OpenFlags::WRITE) {
+            return Err(FileError::InvalidFlags);
+        }
+
+        let path = vfs::normalize(path)?;
+        match vfs::metadata(&path) {
+            Ok(metadata) if metadata.file_type == FileType::Directory => return Err(VfsError::IsADirectory.into()),
+            Ok(metadata) if flags.contains(OpenFlags::WRITE) && metadata.flags.contains(FileFlags::READ_ONLY) => {
+                return Err(VfsError::ReadOnly.into());
+            },
+            Ok(_) => {},
+            Err(VfsError::NotFound) if flags.contains(OpenFlags::CREATE) => vfs::create(&path)?,
+            Err(e) => return Err(e.into()),
+        }
+
+        let file = Descriptor::File(Arc::new(OpenFile { path, flags, position: AtomicU64::new(0) }));
+        let mut descriptors = self.descriptors.lock();
+        match descriptors.iter().position(Option::is_none) {
+            Some(fd) => {
+                descriptors[fd] = Some(file);
+                Ok(Fd(fd))
+            },
+            None if descriptors.len() < MAX_FILES => {
+                descriptors.push(Some(file));
+                Ok(Fd(descriptors.len() - 1))
+            },
+            None => Err(FileError::TooManyFiles),
+        }
+    }
+
+    /// Reads from the position of `fd` and moves it, returns how many bytes. Zero at the end.
+    pub fn read(&self, fd: Fd, buffer: &mut [u8]) -> Result<usize, FileError> {
+        let file = match self.get(fd)? {
+            Descriptor::Stdin => return Ok(0),
+            Descriptor::Stdout | Descriptor::Stderr => return Err(FileError::NotReadable),
+            Descriptor::File(file) => file,
+        };
+        if !file.flags.contains(OpenFlags::READ) {
+            return Err(FileError::NotReadable);
+        }
+
+        let position = file.position.load(Ordering::Relaxed);
+        let len = vfs::read(&file.path, position, buffer)?;
+        file.position.store(position + len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+
+    /// Writes all of `data` at the position of `fd`, or at the end with `APPEND`, and moves it
+    pub fn write(&self, fd: Fd, data: &[u8]) -> Result<usize, FileError> {
+        let file = match self.get(fd)? {
+            Descriptor::Stdin => return Err(FileError::NotWritable),
+            Descriptor::Stdout => {
+                log::info!("{}", String::from_utf8_lossy(data).trim_end());
+                return Ok(data.len());
+            },
+            Descriptor::Stderr => {
+                log::warn!("{}", String::from_utf8_lossy(data).trim_end());
+                return Ok(data.len());
+            },
+            Descriptor::File(file) => file,
+        };
+        if !file.flags.contains(OpenFlags::WRITE) {
+            return Err(FileError::NotWritable);
+        }
+
+        let position = if file.flags.contains(OpenFlags::APPEND) {
+            vfs::metadata(&file.path)?.size
+        } else {
+            file.position.load(Ordering::Relaxed)
+        };
+        let len = vfs::write(&file.path, position, data)?;
+        file.position.store(position + len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+
+    /// Moves the position of `fd`, returns the new one. It may go past the end of the file, a
+    /// write there leaves a hole.
+    pub fn seek(&self, fd: Fd, to: SeekFrom) -> Result<u64, FileError> {
+        let Descriptor::File(file) = self.get(fd)? else {
+            return Err(FileError::InvalidSeek);
+        };
+
+        let position = match to {
+            SeekFrom::Start(offset) => Ok(offset),
+            SeekFrom::Current(offset) => seek_offset(file.position.load(Ordering::Relaxed), offset),
+            SeekFrom::End(offset) => seek_offset(vfs::metadata(&file.path)?.size, offset),
+        };
+        let position = position.map_err(|_| FileError::InvalidSeek)?;
+        file.position.store(position, Ordering::Relaxed);
+        Ok(position)
+    }
+
+    /// Frees the descriptor, the standard streams can be closed too
+    pub fn close(&self, fd: Fd) -> Result<(), FileError> {
+        let mut descriptors = self.descriptors.lock();
+        descriptors.get_mut(fd.0).and_then(Option::take).ok_or(FileError::BadDescriptor)?;
+        while descriptors.len() > 3 && descriptors.last().is_some_and(Option::is_none) {
+            descriptors.pop();
+        }
+        Ok(())
+    }
+
+    /// Output of the `fds` shell command, doesn't block
+    pub fn dump(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        for (fd, descriptor) in self.descriptors.lock().iter().enumerate() {
+            match descriptor {
+                None => {},
+                Some(Descriptor::Stdin) => writeln!(out, "{:>3} stdin", fd)?,
+                Some(Descriptor::Stdout) => writeln!(out, "{:>3} stdout", fd)?,
+                Some(Descriptor::Stderr) => writeln!(out, "{:>3} stderr", fd)?,
+                Some(Descriptor::File(file)) => {
+                    let mode = |flag, c| if file.flags.contains(flag) { c } else { '-' };
+                    writeln!(
+                        out,
+                        "{:>3} {}{}{}{} {:>10} {}",
+                        fd,
+                        mode(OpenFlags::READ, 'r'),
+                        mode(OpenFlags::WRITE, 'w'),
+                        mode(OpenFlags::CREATE, 'c'),
+                        mode(OpenFlags::APPEND, 'a'),
+                        file.position.load(Ordering::Relaxed),
+                        file.path,
+                    )?
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        FdTable::new()
+    }
+}
@@ -1,8 +1,29 @@
+// GDT and TSS, one pair per CPU.
+//
+// The bootstrap processor starts with static tables (`init`), so it works before the frame
+// allocator and the heap are set up. Their IST entries all point at one small static stack,
+// enough for the faults of early boot, which are fatal anyway. Every CPU, the bootstrap
+// processor included once `preinit` runs, then gets its own GDT and TSS on the heap and its own
+// stack for each entry of `IST_STACKS` (`alloc_cpu`, allocated by the bootstrap processor, then
+// `load_cpu` on the CPU itself). The stacks are mapped in a VMA each, with their lowest page
+// left unmapped: an overflow faults instead of running into the next stack.
+//
+// With separate stacks an NMI or machine check arriving in the middle of a page fault doesn't
+// overwrite the page fault's frame. A vector can't nest with itself on its IST stack though:
+// a page fault inside the page fault handler starts over at the top of the same stack.
+
+use alloc::boxed::Box;
 use core::arch::asm;
 use bitflags::bitflags;
 use lazy_static::lazy_static;
 use shared_lib::bits::{get_bits, set_bits};
 use shared_lib::addr::VirtAddr;
+use shared_lib::frame_allocator::FrameAllocator;
+use shared_lib::page_table::{map_address_with_offset, unmap_range_with_offset, PageTableFlags};
+use shared_lib::phys_mapping_offset;
+use shared_lib::spinlock::Spinlock;
+use crate::memory::active_level_4_table;
+use crate::vm;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed(4))]
@@ -41,18 +62,43 @@ impl TaskStateSegment {
 }
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Page faults get their own stack, so a kernel stack overflow can still be reported
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+/// A machine check can interrupt any code, including the entry of another handler
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+/// NMIs too, e.g. the watchdog's while another handler runs
+pub const NMI_IST_INDEX: u16 = 3;
+
+pub struct IstStack {
+    pub index: u16,
+    pub pages: usize,
+}
+
+/// The stack each CPU gets for each IST entry in use
+pub const IST_STACKS: [IstStack; 4] = [
+    IstStack { index: DOUBLE_FAULT_IST_INDEX, pages: 5 },
+    IstStack { index: PAGE_FAULT_IST_INDEX, pages: 5 },
+    IstStack { index: MACHINE_CHECK_IST_INDEX, pages: 2 },
+    IstStack { index: NMI_IST_INDEX, pages: 2 },
+];
+
+/// Shared by the IST entries of the static TSS
+const BOOT_IST_STACK_SIZE: usize = 4096 * 5;
+const STACK_FLAGS: PageTableFlags = PageTableFlags::WRITABLE.union(PageTableFlags::NO_EXECUTE);
+
+pub const MAX_CPUS: usize = 64;
+/// Index of the bootstrap processor in the per-CPU tables
+pub const BSP: usize = 0;
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        static mut BOOT_IST_STACK: [u8; BOOT_IST_STACK_SIZE] = [0; BOOT_IST_STACK_SIZE];
 
-            let stack_start = VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(STACK) });
-            let stack_end = VirtAddr::new(stack_start.0 + STACK_SIZE as u64);
-            stack_end
-        };
+        let mut tss = TaskStateSegment::new();
+        let stack_start = VirtAddr::from_ptr(core::ptr::addr_of!(BOOT_IST_STACK));
+        for stack in IST_STACKS.iter() {
+            tss.interrupt_stack_table[usize::from(stack.index)] = VirtAddr::new(stack_start.0 + BOOT_IST_STACK_SIZE as u64);
+        }
         tss
     };
 }
@@ -305,59 +351,138 @@ impl Descriptor {
         let mut low = Flags::PRESENT.bits();
 
         // base
-        set_bits(&mut low, get_bits(ptr, 0..24), 16);
-        set_bits(&mut low, get_bits(ptr, 24..32), 56);
+        set_bits(&mut low, 16..40, get_bits(ptr, 0..24));
+        set_bits(&mut low, 56..64, get_bits(ptr, 24..32));
 
         // limit (the `-1` in needed since the bound is inclusive)
-        set_bits(&mut low, (size_of::<TaskStateSegment>() - 1) as u64, 0);
+        set_bits(&mut low, 0..16, (size_of::<TaskStateSegment>() - 1) as u64);
 
         // type (0b1001 = available 64-bit tss)
-        set_bits(&mut low, 0b1001, 40);
+        set_bits(&mut low, 40..44, 0b1001);
 
         let mut high = 0;
-        set_bits(&mut high, get_bits(ptr, 32..64), 0);
+        set_bits(&mut high, 0..32, get_bits(ptr, 32..64));
 
         Descriptor::SystemSegment(low, high)
     }
 }
 
-struct GdtAndSelectors {
+pub struct GdtAndSelectors {
     pub gdt: GlobalDescriptorTable,
+    pub tss: &'static TaskStateSegment,
     pub code_selector: SegmentSelector,
     pub tss_selector: SegmentSelector,
     pub data_selector: SegmentSelector
 }
 
-lazy_static! {
-    static ref GDT: GdtAndSelectors = {
+impl GdtAndSelectors {
+    fn new(tss: &'static TaskStateSegment) -> GdtAndSelectors {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
         let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-        GdtAndSelectors { gdt: gdt, code_selector: code_selector, tss_selector: tss_selector, data_selector: data_selector }
-    };
+        GdtAndSelectors { gdt, tss, code_selector, tss_selector, data_selector }
+    }
+
+    /// Loads the GDT, reloads the segment registers and the task register on this CPU
+    fn load(&'static self) {
+        self.gdt.load();
+
+        unsafe {
+            asm!(
+                "push {sel}",
+                "lea {tmp}, [1f + rip]",
+                "push {tmp}",
+                "retfq",
+                "1:",
+                sel = in(reg) self.code_selector.0 as u64,
+                tmp = lateout(reg) _,
+                options(preserves_flags),
+            );
+
+            asm!("mov ds, {0:x}", in(reg) self.data_selector.0, options(nostack, preserves_flags));
+            asm!("mov es, {0:x}", in(reg) self.data_selector.0, options(nostack, preserves_flags));
+            asm!("mov ss, {0:x}", in(reg) self.data_selector.0, options(nostack, preserves_flags));
+
+            asm!("ltr {0:x}", in(reg) self.tss_selector.0, options(nostack, preserves_flags));
+        }
+    }
+}
+
+lazy_static! {
+    static ref GDT: GdtAndSelectors = GdtAndSelectors::new(&TSS);
 }
 
+/// Tables of every CPU that went through `init` or `alloc_cpu`, indexed by CPU number
+static CPU_TABLES: Spinlock<[Option<&'static GdtAndSelectors>; MAX_CPUS]> = Spinlock::new([None; MAX_CPUS]);
+
+/// Loads the static GDT and TSS on the bootstrap processor
 pub fn init() {
-    GDT.gdt.load();
-
-    unsafe {
-        asm!(
-            "push {sel}",
-            "lea {tmp}, [1f + rip]",
-            "push {tmp}",
-            "retfq",
-            "1:",
-            sel = in(reg) GDT.code_selector.0 as u64,
-            tmp = lateout(reg) _,
-            options(preserves_flags),
-        );
-
-        asm!("mov ds, {0:x}", in(reg) GDT.data_selector.0, options(nostack, preserves_flags));
-        asm!("mov es, {0:x}", in(reg) GDT.data_selector.0, options(nostack, preserves_flags));
-        asm!("mov ss, {0:x}", in(reg) GDT.data_selector.0, options(nostack, preserves_flags));
-
-        asm!("ltr {0:x}", in(reg) GDT.tss_selector.0, options(nostack, preserves_flags));
+    GDT.load();
+    CPU_TABLES.lock()[BSP] = Some(&GDT);
+}
+
+/// Maps a stack of `pages` below an unmapped guard page in a VMA called `name`, returns its top
+pub fn allocate_stack(pages: usize, name: &'static str, frame_allocator: &mut FrameAllocator) -> Result<VirtAddr, &'static str> {
+    let region = vm::allocate_region((pages + 1) * 4096, STACK_FLAGS, name)
+        .map_err(|_| "no virtual memory for a stack")?;
+
+    for page in 1..=pages as u64 {
+        let frame = frame_allocator.allocate_frame().ok_or("Failed to allocate a stack")?;
+        unsafe {
+            map_address_with_offset(active_level_4_table(), VirtAddr::new(region.0 + page * 4096), frame,
+                STACK_FLAGS, frame_allocator, phys_mapping_offset())?;
+        }
     }
+    Ok(VirtAddr::new(region.0 + (pages as u64 + 1) * 4096))
+}
+
+/// Unmaps a stack `allocate_stack` returned with the same `pages` and gives its frames back
+///
+/// # Safety
+/// Nothing may run on the stack anymore.
+pub unsafe fn free_stack(top: VirtAddr, pages: usize, frame_allocator: &mut FrameAllocator) -> Result<(), &'static str> {
+    let region = VirtAddr::new(top.0 - (pages as u64 + 1) * 4096);
+    unmap_range_with_offset(active_level_4_table(), VirtAddr::new(region.0 + 4096), pages, phys_mapping_offset(),
+        |_, frame| frame_allocator.deallocate_frame(frame))?;
+    vm::free_region(region).map_err(|_| "stack is not a VMA")?;
+    Ok(())
+}
+
+/// Sets up a GDT and TSS for the CPU `cpu`, with IST stacks taken from `frame_allocator`.
+/// `load_cpu` loads them on that CPU.
+pub fn alloc_cpu(cpu: usize, frame_allocator: &mut FrameAllocator) -> Result<(), &'static str> {
+    if cpu >= MAX_CPUS {
+        return Err("CPU number out of range");
+    }
+
+    let mut tss = TaskStateSegment::new();
+    for stack in IST_STACKS.iter() {
+        tss.interrupt_stack_table[usize::from(stack.index)] = allocate_stack(stack.pages, "ist stack", frame_allocator)?;
+    }
+
+    // kept until the CPU goes away, which it never does
+    let tss = Box::leak(Box::new(tss));
+    let tables = Box::leak(Box::new(GdtAndSelectors::new(tss)));
+    CPU_TABLES.lock()[cpu] = Some(tables);
+    Ok(())
+}
+
+/// Loads the tables `alloc_cpu` set up for `cpu`, on that CPU
+pub fn load_cpu(cpu: usize) -> Result<(), &'static str> {
+    cpu_tables(cpu).ok_or("CPU tables not allocated")?.load();
+    Ok(())
+}
+
+/// `alloc_cpu` and `load_cpu` at once, for the bootstrap processor during `preinit`. An
+/// application processor can't allocate while it is brought up, the bootstrap processor does
+/// it before starting it.
+pub fn init_cpu(cpu: usize, frame_allocator: &mut FrameAllocator) -> Result<(), &'static str> {
+    alloc_cpu(cpu, frame_allocator)?;
+    load_cpu(cpu)
+}
 
+/// GDT and TSS of the CPU `cpu`, None if it wasn't initialized
+pub fn cpu_tables(cpu: usize) -> Option<&'static GdtAndSelectors> {
+    CPU_TABLES.lock().get(cpu).copied().flatten()
 }
\ No newline at end of file
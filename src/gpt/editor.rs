@@ -0,0 +1,230 @@
+// Creating and changing partition tables.
+//
+// A `GptEditor` holds a partition table in memory: a blank one from `create` or the one on the
+// disk from `open`. `write` puts it on the disk with both checksums recalculated, the backup
+// array and header at the end of the disk first, then the primary ones and the protective MBR,
+// so a write cut short leaves one valid copy. The writes go through the block cache and are
+// synced before `write` returns.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use shared_lib::crc::calculate_crc32;
+use shared_lib::get_tsc;
+use crate::block::{cache, Disk};
+use crate::ide::SECTOR_SIZE;
+use crate::mbr::{MbrPartitionEntry, PARTITION_TABLE_OFFSET, TYPE_GPT_PROTECTIVE};
+use super::{check_protective_mbr, read_table, GptError, PartitionEntry, PartitionTableHeader, MIN_HEADER_SIZE};
+
+const GPT_REVISION: u32 = 0x0001_0000;
+/// Entries of a new table, the minimum the spec allows
+const ENTRIES_NUM: u32 = 128;
+const ENTRY_SIZE: u32 = 128;
+/// Alignment of the first usable LBA of a new table, 1 MiB like other partitioning tools
+const FIRST_USABLE_ALIGNMENT: u64 = 2048;
+
+pub struct GptEditor {
+    disk: Arc<Disk>,
+    disk_guid: u128,
+    first_usable_block: u64,
+    last_usable_block: u64,
+    starting_lba_of_array: u64,
+    entry_size: u32,
+    /// All slots of the array, unused ones included
+    entries: Vec<PartitionEntry>,
+}
+
+impl GptEditor {
+    /// An empty table for `disk`, nothing is written before `write`
+    pub fn create(disk: Arc<Disk>) -> Result<GptEditor, GptError> {
+        let array_sectors = array_sectors(ENTRIES_NUM, ENTRY_SIZE);
+        let first_usable_block = if disk.size() > 4 * FIRST_USABLE_ALIGNMENT {
+            FIRST_USABLE_ALIGNMENT
+        } else {
+            2 + array_sectors
+        };
+        // the backup array and header
+        let last_usable_block = disk.size().checked_sub(2 + array_sectors)
+            .filter(|&last| last >= first_usable_block)
+            .ok_or(GptError::DiskTooSmall)?;
+
+        Ok(GptEditor {
+            disk,
+            disk_guid: new_guid(),
+            first_usable_block,
+            last_usable_block,
+            starting_lba_of_array: 2,
+            entry_size: ENTRY_SIZE,
+            entries: (0..ENTRIES_NUM).map(|_| PartitionEntry::new(0, 0, 0, 0, "")).collect(),
+        })
+    }
+
+    /// The table on `disk`
+    pub async fn open(disk: Arc<Disk>) -> Result<GptEditor, GptError> {
+        let (header, array) = read_table(&disk).await?;
+        // checks the array against the header
+        let _ = super::parse_partition_entries(&header, &array)?;
+
+        // the backup array goes right before the backup header
+        let array_sectors = array_sectors(header.entries_num, header.entry_size);
+        if header.last_usable_block + array_sectors + 1 >= disk.size() {
+            return Err(GptError::InvalidPartitionTableHeader);
+        }
+
+        let entries = array[..header.entries_array_size()]
+            .chunks_exact(header.entry_size as usize)
+            .map(PartitionEntry::parse)
+            .collect();
+        Ok(GptEditor {
+            disk,
+            disk_guid: header.disk_guid,
+            first_usable_block: header.first_usable_block,
+            last_usable_block: header.last_usable_block,
+            starting_lba_of_array: header.starting_lba_of_array,
+            entry_size: header.entry_size,
+            entries,
+        })
+    }
+
+    pub fn disk(&self) -> &Arc<Disk> {
+        &self.disk
+    }
+
+    /// First and last LBA partitions may use
+    pub fn usable(&self) -> (u64, u64) {
+        (self.first_usable_block, self.last_usable_block)
+    }
+
+    /// The used entries with their 1-based index
+    pub fn partitions(&self) -> impl Iterator<Item = (usize, &PartitionEntry)> {
+        self.entries.iter().enumerate()
+            .filter(|(_, entry)| entry.is_used())
+            .map(|(i, entry)| (i + 1, entry))
+    }
+
+    /// Adds a partition over LBAs `first..=last` in the first free entry and returns its index
+    pub fn add(&mut self, type_guid: u128, first: u64, last: u64, name: &str) -> Result<usize, GptError> {
+        if type_guid == 0 {
+            return Err(GptError::InvalidRange);
+        }
+        self.check_range(first, last, None)?;
+        let slot = self.entries.iter().position(|entry| !entry.is_used()).ok_or(GptError::NoFreeEntry)?;
+        self.entries[slot] = PartitionEntry::new(type_guid, new_guid(), first, last, name);
+        Ok(slot + 1)
+    }
+
+    pub fn delete(&mut self, index: usize) -> Result<(), GptError> {
+        let slot = self.slot(index)?;
+        self.entries[slot] = PartitionEntry::new(0, 0, 0, 0, "");
+        Ok(())
+    }
+
+    /// Moves the last LBA of partition `index`, its data stays where it is
+    pub fn resize(&mut self, index: usize, last: u64) -> Result<(), GptError> {
+        let slot = self.slot(index)?;
+        self.check_range(self.entries[slot].starting_lba, last, Some(slot))?;
+        self.entries[slot].ending_lba = last;
+        Ok(())
+    }
+
+    /// Writes the protective MBR, both headers and both arrays
+    pub async fn write(&self) -> Result<(), GptError> {
+        let last_lba = self.disk.size() - 1;
+        let mut array = vec![0u8; self.entries.len() * self.entry_size as usize];
+        for (entry, bytes) in self.entries.iter().zip(array.chunks_exact_mut(self.entry_size as usize)) {
+            entry.write_to(bytes);
+        }
+        let array_checksum = calculate_crc32(&array);
+        array.resize(array.len().next_multiple_of(SECTOR_SIZE), 0);
+        let backup_array_lba = last_lba - (array.len() / SECTOR_SIZE) as u64;
+
+        let header = |this_header_lba, alternate_header_lba, starting_lba_of_array| PartitionTableHeader {
+            gpt_revision: GPT_REVISION,
+            header_size: MIN_HEADER_SIZE as u32,
+            header_checksum: 0,
+            this_header_lba,
+            alternate_header_lba,
+            first_usable_block: self.first_usable_block,
+            last_usable_block: self.last_usable_block,
+            disk_guid: self.disk_guid,
+            starting_lba_of_array,
+            entries_num: self.entries.len() as u32,
+            entry_size: self.entry_size,
+            array_checksum,
+        };
+
+        let disk = &self.disk;
+        cache::write(disk, backup_array_lba, &array).await.map_err(GptError::Io)?;
+        cache::write(disk, last_lba, &header(last_lba, 1, backup_array_lba).to_bytes()).await.map_err(GptError::Io)?;
+        cache::sync().await.map_err(GptError::Io)?;
+
+        cache::write(disk, self.starting_lba_of_array, &array).await.map_err(GptError::Io)?;
+        cache::write(disk, 1, &header(1, last_lba, self.starting_lba_of_array).to_bytes()).await.map_err(GptError::Io)?;
+        cache::write(disk, 0, &self.protective_mbr().await?).await.map_err(GptError::Io)?;
+        cache::sync().await.map_err(GptError::Io)
+    }
+
+    /// LBA 0 with the partition table replaced, the boot code is kept
+    async fn protective_mbr(&self) -> Result<[u8; SECTOR_SIZE], GptError> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        cache::read(&self.disk, 0, &mut sector).await.map_err(GptError::Io)?;
+        if check_protective_mbr(&sector).is_ok() {
+            return Ok(sector);
+        }
+
+        sector[PARTITION_TABLE_OFFSET..PARTITION_TABLE_OFFSET + 4 * MbrPartitionEntry::SIZE].fill(0);
+        let entry = &mut sector[PARTITION_TABLE_OFFSET..PARTITION_TABLE_OFFSET + MbrPartitionEntry::SIZE];
+        entry[1..4].copy_from_slice(&[0x0, 0x2, 0x0]);
+        entry[4] = TYPE_GPT_PROTECTIVE;
+        entry[5..8].copy_from_slice(&[0xff, 0xff, 0xff]);
+        entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+        let sectors = (self.disk.size() - 1).min(u32::MAX as u64) as u32;
+        entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+        sector[510..512].copy_from_slice(&[0x55, 0xaa]);
+        Ok(sector)
+    }
+
+    fn slot(&self, index: usize) -> Result<usize, GptError> {
+        match index.checked_sub(1) {
+            Some(slot) if self.entries.get(slot).is_some_and(PartitionEntry::is_used) => Ok(slot),
+            _ => Err(GptError::NoSuchPartition),
+        }
+    }
+
+    /// Checks that `first..=last` is usable and free, the entry in `ignore` aside
+    fn check_range(&self, first: u64, last: u64, ignore: Option<usize>) -> Result<(), GptError> {
+        if first > last || first < self.first_usable_block || last > self.last_usable_block {
+            return Err(GptError::InvalidRange);
+        }
+        let overlaps = self.entries.iter().enumerate()
+            .filter(|&(slot, entry)| entry.is_used() && Some(slot) != ignore)
+            .any(|(_, entry)| first <= entry.ending_lba && entry.starting_lba <= last);
+        if overlaps {
+            return Err(GptError::InvalidRange);
+        }
+        Ok(())
+    }
+}
+
+fn array_sectors(entries_num: u32, entry_size: u32) -> u64 {
+    (entries_num as u64 * entry_size as u64).div_ceil(SECTOR_SIZE as u64)
+}
+
+/// A version 4 GUID. There is no entropy source, the TSC and a counter through splitmix64 keep
+/// the GUIDs of one boot apart.
+fn new_guid() -> u128 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut state = get_tsc() ^ COUNTER.fetch_add(1, Ordering::Relaxed).rotate_left(32);
+    let mut next = || {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    let mut bytes = ((next() as u128) << 64 | next() as u128).to_le_bytes();
+    bytes[7] = (bytes[7] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    u128::from_le_bytes(bytes)
+}
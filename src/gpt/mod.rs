@@ -0,0 +1,317 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use shared_lib::bytes::{read_u32_le, read_u64_le, read_u128_le};
+use shared_lib::crc::{calculate_crc32, calculate_crc32_partial};
+use crate::block::{cache, Disk};
+use crate::block::partition::{Partition, PartitionType};
+use crate::ide::{AtaError, SECTOR_SIZE};
+use crate::mbr::{has_boot_signature, MbrPartitionEntry, PARTITION_TABLE_OFFSET, TYPE_GPT_PROTECTIVE};
+
+// All structures are parsed from byte slices at the offsets given by the UEFI spec,
+// never by casting sector buffers to packed structs.
+
+mod editor;
+
+pub use editor::GptEditor;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const MIN_HEADER_SIZE: usize = 92;
+const HEADER_CHECKSUM_OFFSET: usize = 16;
+const PARTITION_NAME_OFFSET: usize = 56;
+const PARTITION_NAME_LEN: usize = 36; // UTF-16 code units
+
+pub struct PartitionTableHeader {
+    pub gpt_revision: u32,
+    pub header_size: u32,
+    pub header_checksum: u32,
+    pub this_header_lba: u64,
+    pub alternate_header_lba: u64,
+    pub first_usable_block: u64,
+    pub last_usable_block: u64,
+    pub disk_guid: u128,
+    pub starting_lba_of_array: u64,
+    pub entries_num: u32,
+    pub entry_size: u32,
+    pub array_checksum: u32,
+}
+
+pub struct PartitionEntry {
+    pub partition_type_guid: u128, // zero is unused entry
+    pub unique_partition_guid: u128,
+    pub starting_lba: u64,
+    pub ending_lba: u64,
+    pub attributes: u64,
+    name: [u16; PARTITION_NAME_LEN],
+}
+
+#[derive(Debug)]
+pub enum GptError {
+    InvalidProtectiveMBR,
+    InvalidPartitionTableHeader,
+    InvalidTableHeaderChecksum,
+    InvalidMyLbaHeader,
+    InvalidEntriesArrayChecksum,
+    Io(AtaError),
+    DiskTooSmall,
+    /// Outside of the usable LBAs, or overlapping another partition
+    InvalidRange,
+    NoFreeEntry,
+    NoSuchPartition,
+}
+
+pub fn guid_to_str(guid: u128) -> String {
+    let slice = guid.to_le_bytes();
+    format!("{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            slice[3], slice[2], slice[1], slice[0],
+            slice[5], slice[4],
+            slice[7], slice[6],
+            slice[8], slice[9],
+            slice[10], slice[11], slice[12], slice[13], slice[14], slice[15])
+}
+
+/// Byte order of `guid.to_le_bytes()` in the string form: the first three fields are little endian
+const GUID_STR_ORDER: [usize; 16] = [3, 2, 1, 0, 5, 4, 7, 6, 8, 9, 10, 11, 12, 13, 14, 15];
+
+/// Parses the `guid_to_str` form, in either case
+pub fn parse_guid(s: &str) -> Option<u128> {
+    let s = s.as_bytes();
+    if s.len() != 36 || [8, 13, 18, 23].iter().any(|&i| s[i] != b'-') {
+        return None;
+    }
+    let hex: Vec<u8> = s.iter().copied().filter(|&c| c != b'-').collect();
+    if hex.len() != 32 {
+        return None;
+    }
+    let nibble = |c: u8| (c as char).to_digit(16);
+    let mut bytes = [0u8; 16];
+    for (i, pair) in hex.chunks_exact(2).enumerate() {
+        bytes[GUID_STR_ORDER[i]] = (nibble(pair[0])? << 4 | nibble(pair[1])?) as u8;
+    }
+    Some(u128::from_le_bytes(bytes))
+}
+
+/// Type GUIDs by the short names `gpt add` takes
+pub fn type_guid(name: &str) -> Option<u128> {
+    match name {
+        "efi" => parse_guid("C12A7328-F81F-11D2-BA4B-00A0C93EC93B"),
+        "linux" => parse_guid("0FC63DAF-8483-4772-8E79-3D69D8477DE4"),
+        "basic" => parse_guid("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7"),
+        _ => parse_guid(name),
+    }
+}
+
+/// Checks that LBA 0 holds a protective MBR with a single GPT partition.
+pub fn check_protective_mbr(sector: &[u8]) -> Result<(), GptError> {
+    if !has_boot_signature(sector) {
+        return Err(GptError::InvalidProtectiveMBR);
+    }
+
+    let first_partition_mbr = MbrPartitionEntry::parse(&sector[PARTITION_TABLE_OFFSET..PARTITION_TABLE_OFFSET + MbrPartitionEntry::SIZE]);
+
+    if first_partition_mbr.bootable != 0x0
+        || first_partition_mbr.starting_chs != [0x0, 0x2, 0x0]
+        || first_partition_mbr.partition_type != TYPE_GPT_PROTECTIVE
+        || first_partition_mbr.starting_lba != 0x1 {
+        return Err(GptError::InvalidProtectiveMBR)
+    }
+    Ok(())
+}
+
+impl PartitionTableHeader {
+    /// Parses and validates the primary header from LBA 1.
+    pub fn parse(sector: &[u8]) -> Result<Self, GptError> {
+        // we expect 'EFI PART'
+        if sector.len() < MIN_HEADER_SIZE || &sector[0..8] != GPT_SIGNATURE {
+            return Err(GptError::InvalidPartitionTableHeader);
+        }
+
+        let header_size = read_u32_le(sector, 12);
+        if (header_size as usize) < MIN_HEADER_SIZE || header_size as usize > sector.len() {
+            return Err(GptError::InvalidPartitionTableHeader);
+        }
+
+        let header = PartitionTableHeader {
+            gpt_revision: read_u32_le(sector, 8),
+            header_size,
+            header_checksum: read_u32_le(sector, HEADER_CHECKSUM_OFFSET),
+            this_header_lba: read_u64_le(sector, 24),
+            alternate_header_lba: read_u64_le(sector, 32),
+            first_usable_block: read_u64_le(sector, 40),
+            last_usable_block: read_u64_le(sector, 48),
+            disk_guid: read_u128_le(sector, 56),
+            starting_lba_of_array: read_u64_le(sector, 72),
+            entries_num: read_u32_le(sector, 80),
+            entry_size: read_u32_le(sector, 84),
+            array_checksum: read_u32_le(sector, 88),
+        };
+
+        // the checksum is calculated with the checksum field zeroed
+        let mut crc = calculate_crc32_partial(&sector[..HEADER_CHECKSUM_OFFSET], 0xFFFFFFFF);
+        crc = calculate_crc32_partial(&[0; 4], crc);
+        crc = calculate_crc32_partial(&sector[HEADER_CHECKSUM_OFFSET + 4..header_size as usize], crc);
+        if !crc != header.header_checksum {
+            return Err(GptError::InvalidTableHeaderChecksum);
+        }
+
+        if header.this_header_lba != 1 {
+            return Err(GptError::InvalidMyLbaHeader);
+        }
+
+        if (header.entry_size as usize) < PARTITION_NAME_OFFSET + PARTITION_NAME_LEN * 2 {
+            return Err(GptError::InvalidPartitionTableHeader);
+        }
+
+        Ok(header)
+    }
+
+    pub fn entries_array_size(&self) -> usize {
+        self.entries_num as usize * self.entry_size as usize
+    }
+
+    /// The header as stored on the disk, `header_checksum` is calculated rather than taken
+    pub fn to_bytes(&self) -> [u8; SECTOR_SIZE] {
+        let mut sector = [0u8; SECTOR_SIZE];
+        sector[0..8].copy_from_slice(GPT_SIGNATURE);
+        sector[8..12].copy_from_slice(&self.gpt_revision.to_le_bytes());
+        sector[12..16].copy_from_slice(&self.header_size.to_le_bytes());
+        sector[24..32].copy_from_slice(&self.this_header_lba.to_le_bytes());
+        sector[32..40].copy_from_slice(&self.alternate_header_lba.to_le_bytes());
+        sector[40..48].copy_from_slice(&self.first_usable_block.to_le_bytes());
+        sector[48..56].copy_from_slice(&self.last_usable_block.to_le_bytes());
+        sector[56..72].copy_from_slice(&self.disk_guid.to_le_bytes());
+        sector[72..80].copy_from_slice(&self.starting_lba_of_array.to_le_bytes());
+        sector[80..84].copy_from_slice(&self.entries_num.to_le_bytes());
+        sector[84..88].copy_from_slice(&self.entry_size.to_le_bytes());
+        sector[88..92].copy_from_slice(&self.array_checksum.to_le_bytes());
+
+        let checksum = calculate_crc32(&sector[..self.header_size as usize]);
+        sector[HEADER_CHECKSUM_OFFSET..HEADER_CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+        sector
+    }
+}
+
+impl PartitionEntry {
+    pub fn parse(bytes: &[u8]) -> Self {
+        let mut name = [0u16; PARTITION_NAME_LEN];
+        for (i, c) in name.iter_mut().enumerate() {
+            *c = u16::from_le_bytes([bytes[PARTITION_NAME_OFFSET + i * 2], bytes[PARTITION_NAME_OFFSET + i * 2 + 1]]);
+        }
+
+        PartitionEntry {
+            partition_type_guid: read_u128_le(bytes, 0),
+            unique_partition_guid: read_u128_le(bytes, 16),
+            starting_lba: read_u64_le(bytes, 32),
+            ending_lba: read_u64_le(bytes, 40),
+            attributes: read_u64_le(bytes, 48),
+            name,
+        }
+    }
+
+    /// An entry named `name`, cut to 36 UTF-16 code units
+    pub fn new(partition_type_guid: u128, unique_partition_guid: u128, starting_lba: u64, ending_lba: u64, name: &str) -> Self {
+        let mut encoded = [0u16; PARTITION_NAME_LEN];
+        for (c, unit) in encoded.iter_mut().zip(name.encode_utf16()) {
+            *c = unit;
+        }
+        PartitionEntry { partition_type_guid, unique_partition_guid, starting_lba, ending_lba, attributes: 0, name: encoded }
+    }
+
+    /// Writes the entry to the start of `bytes`, the rest of an entry bigger than 128 bytes is
+    /// left as it is
+    pub fn write_to(&self, bytes: &mut [u8]) {
+        bytes[0..16].copy_from_slice(&self.partition_type_guid.to_le_bytes());
+        bytes[16..32].copy_from_slice(&self.unique_partition_guid.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.starting_lba.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.ending_lba.to_le_bytes());
+        bytes[48..56].copy_from_slice(&self.attributes.to_le_bytes());
+        for (i, c) in self.name.iter().enumerate() {
+            bytes[PARTITION_NAME_OFFSET + i * 2..PARTITION_NAME_OFFSET + i * 2 + 2].copy_from_slice(&c.to_le_bytes());
+        }
+    }
+
+    pub fn is_used(&self) -> bool {
+        self.partition_type_guid != 0
+    }
+
+    /// Partition name, stored as UTF-16LE
+    pub fn name(&self) -> String {
+        let len = self.name.iter().position(|c| *c == 0).unwrap_or(PARTITION_NAME_LEN);
+        char::decode_utf16(self.name[..len].iter().cloned())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+}
+
+/// Validates the partition entry array against the header and returns the used entries.
+pub fn parse_partition_entries<'a>(header: &PartitionTableHeader, array: &'a [u8])
+    -> Result<impl Iterator<Item = (usize, PartitionEntry)> + 'a, GptError> {
+    let array_size = header.entries_array_size();
+    if array.len() < array_size || calculate_crc32(&array[..array_size]) != header.array_checksum {
+        return Err(GptError::InvalidEntriesArrayChecksum);
+    }
+
+    Ok(array[..array_size]
+        .chunks_exact(header.entry_size as usize)
+        .map(PartitionEntry::parse)
+        .enumerate()
+        .filter(|(_, entry)| entry.partition_type_guid != 0))
+}
+
+/// The primary header of `disk` and its partition entry array, checked against the header in
+/// `parse_partition_entries`
+async fn read_table(disk: &Arc<Disk>) -> Result<(PartitionTableHeader, Vec<u8>), GptError> {
+    let mut sector = [0u8; SECTOR_SIZE];
+    cache::read(disk, 0x0, &mut sector).await.map_err(GptError::Io)?;
+    check_protective_mbr(&sector)?;
+
+    cache::read(disk, 0x1, &mut sector).await.map_err(GptError::Io)?;
+    let header = PartitionTableHeader::parse(&sector)?;
+
+    let mut array = vec![0u8; header.entries_array_size().next_multiple_of(SECTOR_SIZE)];
+    cache::read(disk, header.starting_lba_of_array, &mut array).await.map_err(GptError::Io)?;
+    Ok((header, array))
+}
+
+/// Logs the partitions of `disk` and returns them, entries outside of the usable LBAs are skipped
+pub async fn parse_gpt(disk: &Arc<Disk>) -> Result<Vec<Partition>, GptError> {
+    log::info!("[gpt] Parsing GPT for {}kb block device {} at {}", (disk.size() * 512) / 1024, disk.name(), disk.device().location());
+
+    let (header, array) = read_table(disk).await?;
+
+    log::info!("[gpt] GPT info: gpt revision: {:#x}, header size: {}, guid: {}, total entries: {}, size of entry: {}, usable LBAs {} - {}",
+    header.gpt_revision,
+    header.header_size,
+    guid_to_str(header.disk_guid),
+    header.entries_num,
+    header.entry_size,
+    header.first_usable_block,
+    header.last_usable_block);
+
+    let entries_per_sector = (SECTOR_SIZE / header.entry_size as usize).max(1);
+    let mut partitions = Vec::new();
+    for (idx, entry) in parse_partition_entries(&header, &array)? {
+        log::info!("[gpt] entry at LBA {}:{} - type: {}, id: {} [{}-{}] {} {}", idx / entries_per_sector + header.starting_lba_of_array as usize,
+            idx % entries_per_sector, guid_to_str(entry.partition_type_guid), guid_to_str(entry.unique_partition_guid), entry.starting_lba, entry.ending_lba,
+            entry.attributes, entry.name());
+
+        if entry.starting_lba < header.first_usable_block || entry.ending_lba > header.last_usable_block
+            || entry.starting_lba > entry.ending_lba {
+            log::warn!("[gpt] entry {} is outside of the usable LBAs, skipped", idx);
+            continue;
+        }
+        let sectors = entry.ending_lba - entry.starting_lba + 1;
+        match Partition::new(disk.clone(), idx + 1, entry.starting_lba, sectors) {
+            Ok(partition) => partitions.push(partition
+                .with_type(PartitionType::Gpt(entry.partition_type_guid))
+                .with_unique_guid(entry.unique_partition_guid)
+                .with_name(entry.name())),
+            Err(e) => log::warn!("[gpt] entry {} doesn't fit on the disk: {:?}", idx, e),
+        }
+    }
+
+    log::info!("[gpt] Parsing ok");
+    return Ok(partitions)
+}
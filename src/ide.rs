@@ -1,14 +1,136 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::Poll;
+use futures_util::future::BoxFuture;
+use futures_util::task::AtomicWaker;
+use shared_lib::bits::get_bits;
+use shared_lib::frame_allocator::Zone;
+use shared_lib::phys_mapping_offset;
+use crate::allocator::{alloc_contiguous_in_zone, free_contiguous};
+use crate::interrupts::{self, Vector};
+use crate::ioapic;
+use crate::pci::{self, pci_config_read_dword, PciAddress, PciDevice, PciMatch, ProbeResult, Probed};
+use crate::percpu;
 use crate::port;
 use crate::port::Port;
+use crate::smart::Health;
+use crate::task::sync::Mutex;
 use crate::task::timer::sleep_for;
+use crate::task::yield_now;
 
-#[derive(Clone, Copy)]
 struct IDEChannelRegister {
     io_base: u16,
     ctrl: u16,
-    bm_ide: u16, // Bus Master IDE
-    no_interrupt: u8
+    bm_ide: u16, // Bus Master IDE, 0 if the controller can't do DMA
+    no_interrupt: AtomicU8,
+    legacy: bool, // claimed the ISA compatibility ports
+    enabled: bool,
+    /// Held for a command on the channel, keeps the DMA buffers once they are allocated
+    transfer: Mutex<Option<DmaArea>>,
+}
+
+/// Bus Master IDE registers, offsets from `bm_ide`
+const BM_COMMAND: u16 = 0x0;
+const BM_STATUS: u16 = 0x2;
+const BM_PRDT: u16 = 0x4;
+
+const BM_COMMAND_START: u8 = 1 << 0;
+/// The controller writes to memory: a read from the drive
+const BM_COMMAND_READ: u8 = 1 << 3;
+const BM_STATUS_ACTIVE: u8 = 1 << 0;
+const BM_STATUS_ERROR: u8 = 1 << 1;
+const BM_STATUS_INTERRUPT: u8 = 1 << 2;
+
+/// Last entry of a Physical Region Descriptor table
+const PRD_END_OF_TABLE: u16 = 1 << 15;
+
+/// Sectors of one DMA command: the bounce buffer is a single 64 KiB PRD entry, which must not
+/// cross a 64 KiB boundary
+const MAX_DMA_SECTORS: usize = 128;
+const DMA_BUFFER_FRAMES: usize = MAX_DMA_SECTORS * SECTOR_SIZE / 4096;
+
+/// PRD table and bounce buffer of a channel, below 4 GiB as the Bus Master registers are 32 bit
+struct DmaArea {
+    prdt: u64,
+    buffer: u64,
+}
+
+impl DmaArea {
+    fn new() -> Option<DmaArea> {
+        let prdt = alloc_contiguous_in_zone(1, 4096, Zone::Dma32)?;
+        let Some(buffer) = alloc_contiguous_in_zone(DMA_BUFFER_FRAMES, 64 * 1024, Zone::Dma32) else {
+            // SAFETY: allocated above and not handed out
+            unsafe { free_contiguous(prdt, 1) };
+            return None;
+        };
+        Some(DmaArea { prdt, buffer })
+    }
+
+    fn buffer(&self, len: usize) -> &'static mut [u8] {
+        // SAFETY: the buffer is owned by the channel, whose `transfer` lock the caller holds
+        unsafe { core::slice::from_raw_parts_mut((self.buffer + phys_mapping_offset()) as *mut u8, len) }
+    }
+
+    /// Points the single PRD entry at the first `len` bytes of the buffer
+    fn set_length(&self, len: usize) {
+        let entry = (self.prdt + phys_mapping_offset()) as *mut u32;
+        // a byte count of 0 means 64 KiB
+        let count = (len % (64 * 1024)) as u32;
+        unsafe {
+            core::ptr::write_volatile(entry, self.buffer as u32);
+            core::ptr::write_volatile(entry.add(1), count | (u32::from(PRD_END_OF_TABLE) << 16));
+        }
+    }
+}
+
+impl Drop for DmaArea {
+    fn drop(&mut self) {
+        // SAFETY: the controller is gone, nothing transfers to them anymore
+        unsafe {
+            free_contiguous(self.prdt, 1);
+            free_contiguous(self.buffer, DMA_BUFFER_FRAMES);
+        }
+    }
+}
+
+/// Interrupt state of the compatibility channels, whose IRQ 14 and 15 are routed while a
+/// controller claims them: the vector, 0 if not routed, the task waiting for the drive and
+/// whether it raised its interrupt since the last command was issued
+static CHANNEL_WAKERS: [AtomicWaker; 2] = [AtomicWaker::new(), AtomicWaker::new()];
+static CHANNEL_IRQ_VECTOR: [AtomicU8; 2] = [AtomicU8::new(0), AtomicU8::new(0)];
+static CHANNEL_INTERRUPTED: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+/// One PCI IDE controller. Drives keep it alive through an `Arc`.
+pub struct IdeController {
+    address: PciAddress,
+    channels: [IDEChannelRegister; 2]
+}
+
+/// Compatibility mode channels claimed by a controller, bit per `ATAChannel`.
+/// Only one controller can decode the legacy ports.
+static LEGACY_CHANNELS: AtomicU8 = AtomicU8::new(0);
+
+impl IdeController {
+    fn channel(&self, channel: ATAChannel) -> &IDEChannelRegister {
+        &self.channels[channel as usize]
+    }
+}
+
+impl Drop for IdeController {
+    fn drop(&mut self) {
+        for channel in [ATAChannel::Primary, ATAChannel::Secondary] {
+            if self.channel(channel).legacy {
+                release_channel_irq(channel);
+                LEGACY_CHANNELS.fetch_and(!(1 << channel as u8), Ordering::Relaxed);
+            }
+        }
+        log::info!("[ide] controller {:?} released", self.address);
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -17,8 +139,7 @@ pub enum ATAChannel {
     Secondary = 0x1
 }
 
-#[allow(dead_code)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum IDEInterfaceType {
     Ata = 0x00,
     Atapi = 0x01
@@ -31,33 +152,68 @@ pub enum DriveType {
     Slave = 0x1
 }
 
-#[derive(Debug, Clone, Copy)]
 #[allow(dead_code)]
 pub struct IDEDevice {
+    controller: Arc<IdeController>,
     pub channel: ATAChannel,
     pub drive: DriveType,        // Master or Slave
     interface_type: IDEInterfaceType,      // 0: ATA, 1:ATAPI.
     signature: u16,   // Drive Signature
     capabilities: u16, // Features.
     command_sets: u32, // Command Sets Supported.
-    pub size: u32,        // Size in Sectors.
+    pub size: u32,        // Size in Sectors, of the medium for ATAPI. 0 without one.
     pub model: [u8; 41],   // Model in string.
     enabled_48bit: bool // 48 bit addressing supported
 }
 
+pub const SECTOR_SIZE: usize = 512;
+
+/// Logical block of a CD-ROM, what READ(10) addresses. An ATAPI drive is still a disk of
+/// `SECTOR_SIZE` sectors, the blocks around a request are read and cut down.
+const ATAPI_BLOCK_SIZE: usize = 2048;
+/// Blocks per READ(10) command, yielding between them like `PIO_CHUNK_SECTORS`
+const ATAPI_CHUNK_BLOCKS: usize = 8;
+
+/// LBA mid and high after a reset or an aborted IDENTIFY, for parallel and serial ATAPI drives
+const ATAPI_SIGNATURES: [(u8, u8); 2] = [(0x14, 0xEB), (0x69, 0x96)];
+
+/// SCSI commands sent in a PACKET
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+
+/// Sectors per PIO command. Without an interrupt the drive is polled and the task only yields
+/// between the commands, so a large transfer doesn't hold up the keyboard and timer tasks.
+const PIO_CHUNK_SECTORS: usize = 8;
+
+/// A disk driver. `read` and `write` are async functions, boxed so disks can be handled as
+/// `dyn BlockDevice`.
 #[allow(dead_code)]
-pub trait BlockDevice {
-    fn read(&self, lba: u32, num: u8) -> Result<Vec<[u16; 256]>, AtaError>;
+pub trait BlockDevice: Send + Sync {
+    /// Reads `buffer.len() / SECTOR_SIZE` sectors starting at `lba` directly into `buffer`.
+    /// The length of `buffer` must be a multiple of `SECTOR_SIZE`.
+    fn read<'a>(&'a self, lba: u64, buffer: &'a mut [u8]) -> BoxFuture<'a, Result<(), AtaError>>;
 
-    fn write(&self, lba: u32, data: Vec<[u16; 256]>) -> Result<(), AtaError>;
+    /// Writes `data` to the sectors starting at `lba`.
+    /// The length of `data` must be a multiple of `SECTOR_SIZE`.
+    fn write<'a>(&'a self, lba: u64, data: &'a [u8]) -> BoxFuture<'a, Result<(), AtaError>>;
 
-    fn size(&self) -> u32;
+    /// In sectors
+    fn size(&self) -> u64;
 
     fn model(&self) -> [u8; 41];
 
-    fn channel(&self) -> ATAChannel;
+    /// Where the drive is attached, e.g. "Primary Master" or "virtio 00:04.0"
+    fn location(&self) -> String;
 
-    fn drive_type(&self) -> DriveType;
+    /// The PCI function the drive belongs to, its disk goes away with it
+    fn pci_address(&self) -> Option<PciAddress> {
+        None
+    }
+
+    /// SMART attributes and status, for drives that have them
+    fn health(&self) -> BoxFuture<'_, Result<Health, AtaError>> {
+        Box::pin(core::future::ready(Err(AtaError::NotSupported)))
+    }
 }
 
 #[repr(usize)]
@@ -114,8 +270,16 @@ enum AtaCommand {
     Packet            = 0xA0,
     IdentifyPacket   = 0xA1,
     Identify          = 0xEC,
+    Smart             = 0xB0,
 }
 
+/// SMART subcommands, in the features register
+const SMART_READ_DATA: u8 = 0xD0;
+const SMART_ENABLE_OPERATIONS: u8 = 0xD8;
+const SMART_RETURN_STATUS: u8 = 0xDA;
+/// LBA mid and high of every SMART command, and of a RETURN STATUS that found no problem
+const SMART_SIGNATURE: (u8, u8) = (0x4F, 0xC2);
+
 #[repr(u8)]
 #[allow(dead_code)]
 enum AtaStatus {
@@ -131,7 +295,7 @@ enum AtaStatus {
 
 #[repr(u8)]
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum AtaError {
     NoError = 0,
     DeviceFault = 19,
@@ -144,82 +308,89 @@ pub enum AtaError {
     ReadsNothing = 23,
     WriteProtected = 8,
 
+    /// Data from the drive failed its checksum
+    BadChecksum = 250,
+    /// The drive doesn't have the feature
+    NotSupported = 251,
+    /// No memory below 4 GiB for the DMA buffers
+    NoDmaMemory = 252,
+    /// Another command is in flight on the channel
+    ChannelBusy = 253,
+    InvalidBufferSize = 254,
     OutOfRange = 255,
 }
 
-static mut CHANNELS: [IDEChannelRegister; 2] = [IDEChannelRegister{ io_base: 0, ctrl: 0, bm_ide: 0, no_interrupt: 0 }; 2];
-
-unsafe fn ide_write(channel: ATAChannel, reg: AtaRegister, data: u8) {
+unsafe fn ide_write(channel: &IDEChannelRegister, reg: AtaRegister, data: u8) {
     if (reg as u8) > 0x07 && (reg as u8) < 0x0C {
-        ide_write(channel, AtaRegister::ControlAndAltStatus, 0x80 | CHANNELS[channel as usize].no_interrupt);
+        ide_write(channel, AtaRegister::ControlAndAltStatus, 0x80 | channel.no_interrupt.load(Ordering::Relaxed));
     }
 
     if (reg as u8) < 0x08 {
-        port::write(CHANNELS[channel as usize].io_base + reg as u16 - 0x00, data);
+        port::write(channel.io_base + reg as u16 - 0x00, data);
     }
     else if (reg as u8) < 0x0C {
-        port::write(CHANNELS[channel as usize].io_base + reg as u16 - 0x06, data);
+        port::write(channel.io_base + reg as u16 - 0x06, data);
     }
     else if (reg as u8) < 0x0E {
-        port::write(CHANNELS[channel as usize].ctrl + reg as u16 - 0x0A, data);
+        port::write(channel.ctrl + reg as u16 - 0x0A, data);
     }
     else if (reg as u8) < 0x16 {
-        port::write(CHANNELS[channel as usize].bm_ide + reg as u16 - 0x0E, data);
+        port::write(channel.bm_ide + reg as u16 - 0x0E, data);
     }
 
     if (reg as u8) > 0x07 && (reg as u8) < 0x0C {
-        ide_write(channel, AtaRegister::ControlAndAltStatus, CHANNELS[channel as usize].no_interrupt);
+        ide_write(channel, AtaRegister::ControlAndAltStatus, channel.no_interrupt.load(Ordering::Relaxed));
     }
 }
 
-unsafe fn ide_read(channel: ATAChannel, reg: AtaRegister) -> u8 {
+unsafe fn ide_read(channel: &IDEChannelRegister, reg: AtaRegister) -> u8 {
     let mut result: u8 = 0;
     if (reg as u8) > 0x07 && (reg as u8) < 0x0C {
-        ide_write(channel, AtaRegister::ControlAndAltStatus, 0x80 | CHANNELS[channel as usize].no_interrupt);
+        ide_write(channel, AtaRegister::ControlAndAltStatus, 0x80 | channel.no_interrupt.load(Ordering::Relaxed));
     }
 
     if (reg as u8) < 0x08 {
-        result = port::read(CHANNELS[channel as usize].io_base + reg as u16 - 0x00);
+        result = port::read(channel.io_base + reg as u16 - 0x00);
     } else if (reg as u8) < 0x0C {
-        result = port::read(CHANNELS[channel as usize].io_base + reg as u16 - 0x06);
+        result = port::read(channel.io_base + reg as u16 - 0x06);
     }
     else if (reg as u8) < 0x0E {
-        result = port::read(CHANNELS[channel as usize].ctrl + reg as u16 - 0x0A);
+        result = port::read(channel.ctrl + reg as u16 - 0x0A);
     }
     else if (reg as u8) < 0x16 {
-        result = port::read(CHANNELS[channel as usize].bm_ide + reg as u16 - 0x0E);
+        result = port::read(channel.bm_ide + reg as u16 - 0x0E);
     }
 
     if (reg as u8) > 0x07 && (reg as u8) < 0x0C {
-        ide_write(channel, AtaRegister::ControlAndAltStatus, CHANNELS[channel as usize].no_interrupt);
+        ide_write(channel, AtaRegister::ControlAndAltStatus, channel.no_interrupt.load(Ordering::Relaxed));
     }
     result
 }
 
-unsafe fn ide_read_buffer(channel: ATAChannel, reg: AtaRegister, words: u16, buffer: &mut [u16; 1024]) {
+unsafe fn ide_read_buffer(channel: &IDEChannelRegister, reg: AtaRegister, words: u16, buffer: &mut [u16; 1024]) {
     if (reg as u8) > 0x07 && (reg as u8) < 0x0C {
-        ide_write(channel, AtaRegister::ControlAndAltStatus, 0x80 | CHANNELS[channel as usize].no_interrupt);
+        ide_write(channel, AtaRegister::ControlAndAltStatus, 0x80 | channel.no_interrupt.load(Ordering::Relaxed));
     }
 
-    let mut port: Option<Port> = if (reg as u8) < 0x08 {
-        Some(Port::new(CHANNELS[channel as usize].io_base + reg as u16 - 0x00))
+    let mut port: Option<Port<u16>> = if (reg as u8) < 0x08 {
+        Some(Port::new(channel.io_base + reg as u16 - 0x00))
     } else if (reg as u8) < 0x0C {
-        Some(Port::new(CHANNELS[channel as usize].io_base + reg as u16 - 0x06))
+        Some(Port::new(channel.io_base + reg as u16 - 0x06))
     } else if (reg as u8) < 0x0E {
-        Some(Port::new(CHANNELS[channel as usize].ctrl + reg as u16 - 0x0A))
+        Some(Port::new(channel.ctrl + reg as u16 - 0x0A))
     } else if (reg as u8) < 0x0E {
-        Some(Port::new(CHANNELS[channel as usize].bm_ide + reg as u16 - 0x0E))
+        Some(Port::new(channel.bm_ide + reg as u16 - 0x0E))
     } else {
         None
     };
 
     for i in 0..words as usize {
-        let res_u16 = port.as_mut().unwrap().read_u16();
+        let res_u16 = port.as_mut().unwrap().read();
         buffer[i] = res_u16;
     }
 
     if (reg as u8) > 0x07 && (reg as u8) < 0x0C {
-        ide_write(channel, AtaRegister::ControlAndAltStatus, CHANNELS[channel as usize].no_interrupt);
+        ide_write(channel, AtaRegister::ControlAndAltStatus, channel.no_interrupt.load(Ordering::Relaxed));
     }
 }
 
@@ -235,56 +406,183 @@ fn get_u32_from_buffer(buffer: [u16; 1024], offset: IdentifyBufferOffset) -> u32
     construct_u32(buffer[offset as usize .. offset as usize + 2].try_into().unwrap())
 }
 
-pub(crate) async fn ide_initialize(_prog_if: u8) -> Vec<impl BlockDevice> {
-    log::info!("IDE initializing");
-    // IDE compatibility mode constants
-    let bar0: u32 = 0x1F0;
-    let bar1: u32 = 0x3F6;
-    let bar2: u32 = 0x170;
-    let bar3: u32 = 0x376;
-    // don't use DMA now
-    let bar4: u32 = 0x0;
+/// Sets up the channels of the controller at `address`. A channel in native mode (its bit in
+/// `prog_if` is set) uses the ports from the BARs, otherwise the ISA compatibility ports.
+fn create_controller(address: PciAddress, prog_if: u8) -> IdeController {
+    let bar = |index: u8| unsafe {
+        pci_config_read_dword(address, 0x10 + 4 * index as u16)
+    };
+    // Bus Master IDE, an I/O BAR with the registers of both channels
+    let bar4 = bar(4);
+    let bus_master = bar4 & 1 != 0 && bar4 & 0xFFFC != 0;
+    if bus_master {
+        pci::enable_command_bits(address, pci::PCI_COMMAND_IO_SPACE | pci::PCI_COMMAND_BUS_MASTER);
+    }
 
-    unsafe {
-        CHANNELS[ATAChannel::Primary as usize].io_base = (bar0 & 0xFFFFFFFC) as u16;
-        CHANNELS[ATAChannel::Primary as usize].ctrl = (bar1 & 0xFFFFFFFC) as u16;
+    let channels = [ATAChannel::Primary, ATAChannel::Secondary].map(|channel| {
+        let native = prog_if & (1 << (2 * channel as u8)) != 0;
+        let (io_base, ctrl) = if native {
+            (bar(2 * channel as u8), bar(2 * channel as u8 + 1))
+        } else {
+            // IDE compatibility mode constants
+            match channel {
+                ATAChannel::Primary => (0x1F0, 0x3F6),
+                ATAChannel::Secondary => (0x170, 0x376)
+            }
+        };
+
+        let enabled = if native {
+            io_base & 0xFFFFFFFC != 0
+        } else {
+            LEGACY_CHANNELS.fetch_or(1 << channel as u8, Ordering::Relaxed) & (1 << channel as u8) == 0
+        };
+        if !enabled {
+            log::warn!("[ide] {:?} channel of {:?} is not usable: {}", channel, address,
+                if native { "no I/O ports assigned" } else { "compatibility ports taken by another controller" });
+        }
+
+        let bm_ide = if bus_master { (bar4 & 0xFFFC) as u16 + 8 * channel as u16 } else { 0 };
+        if !native && enabled {
+            route_channel_irq(channel);
+        }
+
+        IDEChannelRegister {
+            io_base: (io_base & 0xFFFFFFFC) as u16,
+            ctrl: (ctrl & 0xFFFFFFFC) as u16,
+            bm_ide,
+            no_interrupt: AtomicU8::new(0),
+            legacy: !native && enabled,
+            enabled,
+            transfer: Mutex::new(None),
+        }
+    });
+
+    IdeController { address, channels }
+}
+
+/// ISA IRQ and handler of the compatibility channel `channel`
+fn channel_irq_handler(channel: ATAChannel) -> (u8, interrupts::IrqHandler) {
+    match channel {
+        ATAChannel::Primary => (ioapic::IRQ_PRIMARY_ATA, primary_channel_irq),
+        ATAChannel::Secondary => (ioapic::IRQ_SECONDARY_ATA, secondary_channel_irq),
+    }
+}
+
+/// Routes the ISA IRQ of the compatibility channel `channel` to its handler, once. Native
+/// channels use a PCI interrupt pin, which can't be routed without the ACPI tables describing
+/// it; their commands are polled.
+fn route_channel_irq(channel: ATAChannel) {
+    if CHANNEL_IRQ_VECTOR[channel as usize].load(Ordering::Acquire) != 0 {
+        return;
+    }
+    let (irq, handler) = channel_irq_handler(channel);
+    let routed = interrupts::register_irq(None, handler)
+        .and_then(|Vector(vector)| ioapic::route_legacy_irq(irq, vector, percpu::current().apic_id)
+            .map(|gsi| (vector, gsi))
+            .inspect_err(|_| { let _ = interrupts::unregister_irq(Vector(vector), handler); }));
+    match routed {
+        Ok((vector, gsi)) => {
+            CHANNEL_IRQ_VECTOR[channel as usize].store(vector, Ordering::Release);
+            log::info!("[ide] {:?} channel interrupts on GSI {}", channel, gsi);
+        },
+        Err(e) => log::warn!("[ide] {:?} channel interrupts unavailable, polling: {}", channel, e),
+    }
+}
+
+/// Masks the IRQ of the compatibility channel `channel` and drops its handler, when the
+/// controller which claimed the channel goes away
+fn release_channel_irq(channel: ATAChannel) {
+    let vector = CHANNEL_IRQ_VECTOR[channel as usize].swap(0, Ordering::AcqRel);
+    if vector == 0 {
+        return;
+    }
+    let (irq, handler) = channel_irq_handler(channel);
+    let (gsi, _, _) = ioapic::legacy_irq(irq);
+    if let Err(e) = ioapic::mask(gsi).and_then(|()| interrupts::unregister_irq(Vector(vector), handler)) {
+        log::warn!("[ide] {:?} channel interrupts not released: {}", channel, e);
+    }
+}
+
+fn primary_channel_irq() -> bool {
+    channel_irq(ATAChannel::Primary)
+}
+
+fn secondary_channel_irq() -> bool {
+    channel_irq(ATAChannel::Secondary)
+}
+
+/// Acknowledges the drive's interrupt and wakes the waiting task, which checks the status
+fn channel_irq(channel: ATAChannel) -> bool {
+    let status_port = match channel {
+        ATAChannel::Primary => 0x1F7,
+        ATAChannel::Secondary => 0x177,
+    };
+    // reading the status register deasserts INTRQ
+    unsafe { port::read(status_port) };
+    CHANNEL_INTERRUPTED[channel as usize].store(true, Ordering::Release);
+    CHANNEL_WAKERS[channel as usize].wake();
+    true
+}
 
-        CHANNELS[ATAChannel::Secondary as usize].io_base = (bar2 & 0xFFFFFFFC)  as u16;
-        CHANNELS[ATAChannel::Secondary as usize].ctrl = (bar3 & 0xFFFFFFFC) as u16;
+/// IDE controllers in any mode, `create_controller` tells the channels apart
+pub const PCI_MATCHES: &[PciMatch] = &[PciMatch::Class(0x1, 0x1, None)];
 
-        CHANNELS[ATAChannel::Primary as usize].bm_ide = ((bar4 & 0xFFFFFFFC) + 0) as u16; // Bus Master IDE
-        CHANNELS[ATAChannel::Secondary as usize].bm_ide = ((bar4 & 0xFFFFFFFC) + 8) as u16; // Bus Master IDE
+/// Sets up the controller and hands on the drives found on its channels
+pub async fn probe(device: PciDevice) -> ProbeResult {
+    let drives = ide_initialize(device.address(), device.id().prog_if).await;
+    Ok(drives.into_iter().map(|drive| Probed::Drive(Box::new(drive))).collect())
+}
+
+/// The drives of the controller at `address` left with their disks. The controller, its
+/// compatibility channels and their interrupts are released with the last drive, once whatever
+/// still has one open lets go of it.
+pub fn remove(address: PciAddress) {
+    log::info!("[ide] controller {:?} removed", address);
+}
 
-        // Disable IRQs
-        ide_write(ATAChannel::Primary, AtaRegister::ControlAndAltStatus, 2);
-        ide_write(ATAChannel::Secondary, AtaRegister::ControlAndAltStatus, 2);
+async fn ide_initialize(address: PciAddress, prog_if: u8) -> Vec<IDEDevice> {
+    log::info!("IDE initializing {:?}, prog_if: {:#x}", address, prog_if);
+    let controller = Arc::new(create_controller(address, prog_if));
+
+    for channel in [ATAChannel::Primary, ATAChannel::Secondary] {
+        if controller.channel(channel).enabled {
+            // Disable IRQs
+            unsafe {
+                ide_write(controller.channel(channel), AtaRegister::ControlAndAltStatus, 2);
+            }
+        }
     }
 
     let mut drives = Vec::new();
 
     for channel in [ATAChannel::Primary, ATAChannel::Secondary] {
+        let regs = controller.channel(channel);
+        if !regs.enabled {
+            continue;
+        }
+
         for drive in [DriveType::Master, DriveType::Slave] {
             log::info!("Checking {:?} {:?}", channel, drive);
 
             let mut err: u8 = 0;
-            let interface_type = IDEInterfaceType::Ata;
+            let mut interface_type = IDEInterfaceType::Ata;
             let mut status: u8;
 
             unsafe {
-                ide_write(channel, AtaRegister::HddEvSel, 0xA0 | ((drive as u8) << 4));
+                ide_write(regs, AtaRegister::HddEvSel, 0xA0 | ((drive as u8) << 4));
             }
             sleep_for(1).await;
 
             unsafe {
-                ide_write(channel, AtaRegister::CommandAndStatus, AtaCommand::Identify as u8)
+                ide_write(regs, AtaRegister::CommandAndStatus, AtaCommand::Identify as u8)
             }
             sleep_for(1).await;
 
             unsafe {
-                if ide_read(channel, AtaRegister::CommandAndStatus) == 0 { continue; } // No Device
+                if ide_read(regs, AtaRegister::CommandAndStatus) == 0 { continue; } // No Device
 
                 loop {
-                    status = ide_read(channel, AtaRegister::CommandAndStatus);
+                    status = ide_read(regs, AtaRegister::CommandAndStatus);
                     if (status & AtaStatus::Error as u8) != 0 {
                         err = 1;
                         break; // Device is not ATA
@@ -295,15 +593,23 @@ pub(crate) async fn ide_initialize(_prog_if: u8) -> Vec<impl BlockDevice> {
                 }
 
                 if err != 0 {
-                    // It's a place to probe for ATAPI Devices, but I don't want it
-                    continue;
+                    // ATAPI drives abort IDENTIFY and leave their signature
+                    let signature = (ide_read(regs, AtaRegister::Lba1), ide_read(regs, AtaRegister::Lba2));
+                    if !ATAPI_SIGNATURES.contains(&signature) {
+                        continue; // Unknown Type (may not be a device)
+                    }
+                    interface_type = IDEInterfaceType::Atapi;
+                    ide_write(regs, AtaRegister::CommandAndStatus, AtaCommand::IdentifyPacket as u8);
                 }
             }
+            if interface_type == IDEInterfaceType::Atapi {
+                sleep_for(1).await;
+            }
 
             let mut ide_buf: [u16; 1024] = [0; 1024];
 
             unsafe {
-                ide_read_buffer(channel, AtaRegister::Data, 256, &mut ide_buf);
+                ide_read_buffer(regs, AtaRegister::Data, 256, &mut ide_buf);
             }
 
             let command_sets = get_u32_from_buffer(ide_buf, IdentifyBufferOffset::Commandsets);
@@ -311,7 +617,11 @@ pub(crate) async fn ide_initialize(_prog_if: u8) -> Vec<impl BlockDevice> {
             let mut model: [u8; 41] = [0; 41];
             let enabled_48bit: bool;
 
-            if command_sets & (1 << 26) != 0 {
+            if interface_type == IDEInterfaceType::Atapi {
+                // READ CAPACITY below, the medium can be changed
+                enabled_48bit = false;
+                size = 0;
+            } else if command_sets & (1 << 26) != 0 {
                 // Device uses 48-Bit Addressing:
                 enabled_48bit = true;
                 size = get_u32_from_buffer(ide_buf, IdentifyBufferOffset::MaxLbaExt);
@@ -331,7 +641,8 @@ pub(crate) async fn ide_initialize(_prog_if: u8) -> Vec<impl BlockDevice> {
             }
             model[40] = 0;
 
-            drives.push(IDEDevice {
+            let mut device = IDEDevice {
+                controller: controller.clone(),
                 channel,
                 drive,
                 interface_type,
@@ -341,17 +652,25 @@ pub(crate) async fn ide_initialize(_prog_if: u8) -> Vec<impl BlockDevice> {
                 size,
                 model,
                 enabled_48bit,
-            });
+            };
+            if interface_type == IDEInterfaceType::Atapi {
+                match device.read_capacity().await {
+                    Ok(size) => device.size = size,
+                    Err(e) => log::warn!("[ide] no medium in the ATAPI drive on {:?} {:?}: {:?}", channel, drive, e),
+                }
+            }
+            drives.push(device);
         }
     }
 
     for drive in &drives {
-        log::info!("Found ATA Drive {} kB - '{}'. 48-bit addressing: {}", (drive.size * 512) / 1024, core::str::from_utf8(&drive.model).unwrap(), drive.enabled_48bit);
+        let kind = match drive.interface_type { IDEInterfaceType::Ata => "ATA", IDEInterfaceType::Atapi => "ATAPI" };
+        log::info!("Found {} Drive {} kB - '{}'. 48-bit addressing: {}", kind, (drive.size * 512) / 1024, core::str::from_utf8(&drive.model).unwrap(), drive.enabled_48bit);
     }
     drives
 }
 
-unsafe fn ide_polling(channel: ATAChannel, advanced_check: bool) -> AtaError {
+unsafe fn ide_polling(channel: &IDEChannelRegister, advanced_check: bool) -> AtaError {
     // Delay 400 nanosecond for BSY to be set:
     for _ in 0..4 {
         // Reading the Alternate Status port wastes 100ns; loop four times.
@@ -399,9 +718,16 @@ enum LbaMode {
 }
 
 impl IDEDevice {
-    unsafe fn io_prepare(&self, lba: u32, numsects: u8, dma: bool, is_write: bool) -> LbaMode {
-        CHANNELS[self.channel as usize].no_interrupt = 0x02;
-        ide_write(self.channel, AtaRegister::ControlAndAltStatus, CHANNELS[self.channel as usize].no_interrupt);
+    fn regs(&self) -> &IDEChannelRegister {
+        self.controller.channel(self.channel)
+    }
+
+    /// Selects the drive and issues the command, `interrupts` lets the drive raise its IRQ when
+    /// it is done
+    unsafe fn io_prepare(&self, lba: u32, numsects: u8, dma: bool, is_write: bool, interrupts: bool) -> LbaMode {
+        let no_interrupt = if interrupts { 0x00 } else { 0x02 };
+        self.regs().no_interrupt.store(no_interrupt, Ordering::Relaxed);
+        ide_write(self.regs(), AtaRegister::ControlAndAltStatus, no_interrupt);
 
         let lba_mode;
         let mut lba_io = [0u8; 6];
@@ -409,10 +735,10 @@ impl IDEDevice {
         if lba >= 0x10000000 { // with this lba drive must support LBA48
             // LBA48
             lba_mode = LbaMode::Lba48;
-            lba_io[0] = ((lba & 0x000000FF) >> 0) as u8;
-            lba_io[1] = ((lba & 0x0000FF00) >> 8) as u8;
-            lba_io[2] = ((lba & 0x00FF0000) >> 16) as u8;
-            lba_io[3] = ((lba & 0xFF000000) >> 24) as u8;
+            lba_io[0] = get_bits(lba, 0..8) as u8;
+            lba_io[1] = get_bits(lba, 8..16) as u8;
+            lba_io[2] = get_bits(lba, 16..24) as u8;
+            lba_io[3] = get_bits(lba, 24..32) as u8;
             lba_io[4] = 0; // These Registers are not used here.
             lba_io[5] = 0; // These Registers are not used here.
             head = 0;      // Lower 4-bits of HDDEVSEL are not used here.
@@ -420,13 +746,13 @@ impl IDEDevice {
         } else if (self.capabilities & 0x200) != 0 {
             // LBA28
             lba_mode = LbaMode::Lba28;
-            lba_io[0] = ((lba & 0x000000FF) >> 0) as u8;
-            lba_io[1] = ((lba & 0x0000FF00) >> 8) as u8;
-            lba_io[2] = ((lba & 0x00FF0000) >> 16) as u8;
+            lba_io[0] = get_bits(lba, 0..8) as u8;
+            lba_io[1] = get_bits(lba, 8..16) as u8;
+            lba_io[2] = get_bits(lba, 16..24) as u8;
             lba_io[3] = 0; // These Registers are not used here.
             lba_io[4] = 0; // These Registers are not used here.
             lba_io[5] = 0; // These Registers are not used here.
-            head = ((lba & 0xF000000) >> 24) as u8;
+            head = get_bits(lba, 24..28) as u8;
         } else {
             // CHS
             //lba_mode = LbaMode::Chs;
@@ -434,27 +760,27 @@ impl IDEDevice {
         }
 
         // wait if busy
-        while (ide_read(self.channel, AtaRegister::CommandAndStatus) & AtaStatus::Busy as u8) != 0 {}
+        while (ide_read(self.regs(), AtaRegister::CommandAndStatus) & AtaStatus::Busy as u8) != 0 {}
 
         let slavebit: u8 = match self.drive { DriveType::Master => 0b0000, DriveType::Slave => 0b10000 };
         match lba_mode {
             LbaMode::Chs => unimplemented!(),
             LbaMode::Lba28 => {
-                ide_write(self.channel, AtaRegister::HddEvSel, 0xE0 | slavebit | head);
+                ide_write(self.regs(), AtaRegister::HddEvSel, 0xE0 | slavebit | head);
             }
             LbaMode::Lba48 => {
-                ide_write(self.channel, AtaRegister::HddEvSel, 0xE0 | slavebit | head);
+                ide_write(self.regs(), AtaRegister::HddEvSel, 0xE0 | slavebit | head);
 
-                ide_write(self.channel, AtaRegister::SecCount1,   0);
-                ide_write(self.channel, AtaRegister::Lba3,   lba_io[3]);
-                ide_write(self.channel, AtaRegister::Lba4,   lba_io[4]);
-                ide_write(self.channel, AtaRegister::Lba5,   lba_io[5]);
+                ide_write(self.regs(), AtaRegister::SecCount1,   0);
+                ide_write(self.regs(), AtaRegister::Lba3,   lba_io[3]);
+                ide_write(self.regs(), AtaRegister::Lba4,   lba_io[4]);
+                ide_write(self.regs(), AtaRegister::Lba5,   lba_io[5]);
             }
         }
-        ide_write(self.channel, AtaRegister::SecCount0,   numsects);
-        ide_write(self.channel, AtaRegister::Lba0,   lba_io[0]);
-        ide_write(self.channel, AtaRegister::Lba1,   lba_io[1]);
-        ide_write(self.channel, AtaRegister::Lba2,   lba_io[2]);
+        ide_write(self.regs(), AtaRegister::SecCount0,   numsects);
+        ide_write(self.regs(), AtaRegister::Lba0,   lba_io[0]);
+        ide_write(self.regs(), AtaRegister::Lba1,   lba_io[1]);
+        ide_write(self.regs(), AtaRegister::Lba2,   lba_io[2]);
 
         let command = match lba_mode {
             LbaMode::Chs => unimplemented!(),
@@ -476,103 +802,404 @@ impl IDEDevice {
             }
         };
 
-        ide_write(self.channel, AtaRegister::CommandAndStatus, command as u8);
+        CHANNEL_INTERRUPTED[self.channel as usize].store(false, Ordering::Release);
+        ide_write(self.regs(), AtaRegister::CommandAndStatus, command as u8);
 
         lba_mode
     }
 
-    unsafe fn write_impl(&self, lba: u32, data: Vec<[u16; 256]>) -> Result<(), AtaError> {
-        // DMA is not implemented for now
-        let dma = false;
+    /// Whether commands on the drive's channel can complete with an interrupt
+    fn irq(&self) -> bool {
+        self.regs().legacy && CHANNEL_IRQ_VECTOR[self.channel as usize].load(Ordering::Acquire) != 0
+    }
 
-        let lba_mode = self.io_prepare(lba, data.len() as u8, dma, true);
+    /// Waits for the interrupt of the command in flight, then checks the status like
+    /// `ide_polling`. The task sleeps meanwhile.
+    async fn wait_irq(&self) -> Result<(), AtaError> {
+        let channel = self.channel as usize;
+        core::future::poll_fn(|cx| {
+            CHANNEL_WAKERS[channel].register(cx.waker());
+            if CHANNEL_INTERRUPTED[channel].swap(false, Ordering::AcqRel) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }).await;
+        match unsafe { ide_polling(self.regs(), true) } {
+            AtaError::NoError => Ok(()),
+            err => Err(err),
+        }
+    }
 
-        if dma {
-            unimplemented!();
-        } else {
-            let mut port = Port::new(CHANNELS[self.channel as usize].io_base);
+    /// Keeps the interrupt setting of the last command
+    async fn flush_cache(&self, lba_mode: LbaMode, irq: bool) -> Result<(), AtaError> {
+        let command = match lba_mode {
+            LbaMode::Lba48 => AtaCommand::CacheFlushExt,
+            LbaMode::Chs | LbaMode::Lba28 => AtaCommand::CacheFlush,
+        };
+        CHANNEL_INTERRUPTED[self.channel as usize].store(false, Ordering::Release);
+        unsafe { ide_write(self.regs(), AtaRegister::CommandAndStatus, command as u8) };
+        if irq {
+            return self.wait_irq().await;
+        }
+        match unsafe { ide_polling(self.regs(), false) } {
+            AtaError::NoError => Ok(()),
+            err @ _ => Err(err)
+        }
+    }
 
-            for sector in data {
-                ide_polling(self.channel, false);
-                for word in sector {
-                    port.write_u16(word);
-                }
-            }
+    /// One PIO write command. The drive asks for the first sector by setting DRQ, for the others
+    /// and at the end with an interrupt if it may raise one.
+    async fn write_impl(&self, lba: u32, data: &[u8]) -> Result<(), AtaError> {
+        let irq = self.irq();
+        let lba_mode = unsafe { self.io_prepare(lba, (data.len() / SECTOR_SIZE) as u8, false, true, irq) };
+
+        let mut port = Port::<u16>::new(self.regs().io_base);
 
-            match lba_mode {
-                LbaMode::Lba48 => ide_write(self.channel, AtaRegister::CommandAndStatus, AtaCommand::CacheFlushExt as u8),
-                LbaMode::Chs | LbaMode::Lba28 => ide_write(self.channel, AtaRegister::CommandAndStatus, AtaCommand::CacheFlush as u8)
+        for (i, sector) in data.chunks_exact(SECTOR_SIZE).enumerate() {
+            if irq && i != 0 {
+                self.wait_irq().await?;
+            } else {
+                unsafe { ide_polling(self.regs(), false) };
             }
-            match ide_polling(self.channel, false) {
-                AtaError::NoError => Ok(()),
-                err @ _ => Err(err)
+            unsafe { port.write_from(sector) };
+        }
+        if irq {
+            self.wait_irq().await?;
+        }
+
+        self.flush_cache(lba_mode, irq).await
+    }
+
+    /// One PIO read command, the drive interrupts when each sector is ready
+    async fn read_impl(&self, lba: u32, buffer: &mut [u8]) -> Result<(), AtaError> {
+        let irq = self.irq();
+        unsafe { self.io_prepare(lba, (buffer.len() / SECTOR_SIZE) as u8, false, false, irq) };
+
+        let mut port = Port::<u16>::new(self.regs().io_base);
+
+        for sector in buffer.chunks_exact_mut(SECTOR_SIZE) {
+            if irq {
+                self.wait_irq().await?;
+            } else {
+                let err = unsafe { ide_polling(self.regs(), true) };
+                if !matches!(err, AtaError::NoError) {
+                    return Err(err);
+                }
             }
+            unsafe { port.read_into(sector) };
         }
+
+        Ok(())
     }
-    unsafe fn read_impl(&self, lba: u32, numsects: u8) -> Result<Vec<[u16; 256]>, AtaError> {
-        // DMA is not implemented for now
-        let dma = false;
 
-        self.io_prepare(lba, numsects, dma, false);
+    /// Whether the controller and the drive can do DMA. ATAPI commands are always PIO.
+    pub fn dma_capable(&self) -> bool {
+        // IDENTIFY word 49 bit 8: DMA supported
+        self.interface_type == IDEInterfaceType::Ata && self.regs().bm_ide != 0 && self.capabilities & 0x100 != 0
+    }
 
-        if dma {
-            unimplemented!();
-        } else {
-            let mut port = Port::new(CHANNELS[self.channel as usize].io_base);
-
-            let mut buffer = [0u16; 256];
-            let mut result = Vec::new();
-            result.reserve(numsects as usize);
-
-            for _ in 0..numsects {
-                let err = ide_polling(self.channel, true);
-                match err {
-                    AtaError::NoError => {
-                        for i in 0..256 {
-                            let word = port.read_u16();
-                            buffer[i] = word;
-                        }
-                        result.push(buffer.clone());
-                    },
-                    _ => { return Err(err); }
+    /// Sends the SCSI command `packet` with the PACKET command, PIO, and reads the data the drive
+    /// returns into `buffer`, which it must fill. The drive hands it over in pieces of at most
+    /// `ATAPI_BLOCK_SIZE` bytes, each announced with DRQ and an interrupt if it may raise one.
+    async fn packet(&self, packet: [u8; 12], buffer: &mut [u8]) -> Result<(), AtaError> {
+        let regs = self.regs();
+        let irq = self.irq();
+        let byte_count = buffer.len().min(ATAPI_BLOCK_SIZE);
+
+        unsafe {
+            let no_interrupt = if irq { 0x00 } else { 0x02 };
+            regs.no_interrupt.store(no_interrupt, Ordering::Relaxed);
+            ide_write(regs, AtaRegister::ControlAndAltStatus, no_interrupt);
+            while (ide_read(regs, AtaRegister::CommandAndStatus) & AtaStatus::Busy as u8) != 0 {}
+
+            let slavebit: u8 = match self.drive { DriveType::Master => 0b0000, DriveType::Slave => 0b10000 };
+            ide_write(regs, AtaRegister::HddEvSel, 0xA0 | slavebit);
+            ide_write(regs, AtaRegister::ErrorAndFeatures, 0); // PIO
+            ide_write(regs, AtaRegister::Lba1, byte_count as u8);
+            ide_write(regs, AtaRegister::Lba2, (byte_count >> 8) as u8);
+            ide_write(regs, AtaRegister::CommandAndStatus, AtaCommand::Packet as u8);
+
+            // the drive asks for the packet with DRQ, without an interrupt
+            match ide_polling(regs, true) {
+                AtaError::NoError => {},
+                err => return Err(err),
+            }
+            CHANNEL_INTERRUPTED[self.channel as usize].store(false, Ordering::Release);
+            Port::<u16>::new(regs.io_base).write_from(&packet);
+        }
+
+        let mut port = Port::<u16>::new(regs.io_base);
+        let mut offset = 0;
+        loop {
+            if irq {
+                self.wait_irq().await?;
+            } else {
+                let err = unsafe { ide_polling(regs, true) };
+                if !matches!(err, AtaError::NoError) {
+                    return Err(err);
                 }
             }
 
-            return Ok(result);
+            // without DRQ the command is done
+            if unsafe { ide_read(regs, AtaRegister::CommandAndStatus) } & AtaStatus::DataRequestReady as u8 == 0 {
+                break;
+            }
+            let count = unsafe {
+                ide_read(regs, AtaRegister::Lba1) as usize | (ide_read(regs, AtaRegister::Lba2) as usize) << 8
+            };
+            let Some(piece) = buffer.get_mut(offset..offset + count) else {
+                return Err(AtaError::InvalidBufferSize);
+            };
+            unsafe { port.read_into(piece) };
+            offset += count;
+        }
+
+        if offset != buffer.len() {
+            return Err(AtaError::ReadsNothing);
         }
+        Ok(())
     }
-}
 
-impl BlockDevice for IDEDevice {
-    fn read(&self, lba: u32, num: u8) -> Result<Vec<[u16; 256]>, AtaError> {
-        if lba + num as u32 > self.size {
-            return Err(AtaError::OutOfRange);
+    /// Size of the medium in an ATAPI drive, in `SECTOR_SIZE` sectors
+    async fn read_capacity(&self) -> Result<u32, AtaError> {
+        let _transfer = self.regs().transfer.lock().await;
+        let mut packet = [0u8; 12];
+        packet[0] = SCSI_READ_CAPACITY_10;
+
+        let mut data = [0u8; 8];
+        // the first command after a reset or a medium change reports it with a unit attention
+        if self.packet(packet, &mut data).await.is_err() {
+            self.packet(packet, &mut data).await?;
         }
 
-        unsafe { self.read_impl(lba, num) }
+        let last_block = u32::from_be_bytes(data[0..4].try_into().unwrap());
+        let block_size = u32::from_be_bytes(data[4..8].try_into().unwrap());
+        if block_size as usize != ATAPI_BLOCK_SIZE {
+            log::warn!("[ide] ATAPI block size {} is not supported", block_size);
+            return Err(AtaError::NotSupported);
+        }
+        Ok((last_block + 1) * (ATAPI_BLOCK_SIZE / SECTOR_SIZE) as u32)
+    }
+
+    /// Reads the sectors from `lba` with READ(10), through a buffer of the whole blocks which
+    /// hold them
+    async fn read_atapi(&self, lba: u32, buffer: &mut [u8]) -> Result<(), AtaError> {
+        let sectors_per_block = (ATAPI_BLOCK_SIZE / SECTOR_SIZE) as u32;
+        let first_block = lba / sectors_per_block;
+        let skip = (lba % sectors_per_block) as usize * SECTOR_SIZE;
+        let blocks = (skip + buffer.len()).div_ceil(ATAPI_BLOCK_SIZE);
+
+        let mut data = vec![0u8; blocks * ATAPI_BLOCK_SIZE];
+        for (i, chunk) in data.chunks_mut(ATAPI_CHUNK_BLOCKS * ATAPI_BLOCK_SIZE).enumerate() {
+            let block = first_block + (i * ATAPI_CHUNK_BLOCKS) as u32;
+            let count = (chunk.len() / ATAPI_BLOCK_SIZE) as u16;
+            let mut packet = [0u8; 12];
+            packet[0] = SCSI_READ_10;
+            packet[2..6].copy_from_slice(&block.to_be_bytes());
+            packet[7..9].copy_from_slice(&count.to_be_bytes());
+            self.packet(packet, chunk).await?;
+            yield_now().await;
+        }
+        buffer.copy_from_slice(&data[skip..skip + buffer.len()]);
+        Ok(())
+    }
+
+    async fn read_pio(&self, lba: u32, buffer: &mut [u8]) -> Result<(), AtaError> {
+        for (i, chunk) in buffer.chunks_mut(PIO_CHUNK_SECTORS * SECTOR_SIZE).enumerate() {
+            self.read_impl(lba + (i * PIO_CHUNK_SECTORS) as u32, chunk).await?;
+            yield_now().await;
+        }
+        Ok(())
     }
 
-    fn write(&self, lba: u32, data: Vec<[u16; 256]>) -> Result<(), AtaError> {
-        if lba + data.len() as u32 > self.size {
+    async fn write_pio(&self, lba: u32, data: &[u8]) -> Result<(), AtaError> {
+        for (i, chunk) in data.chunks(PIO_CHUNK_SECTORS * SECTOR_SIZE).enumerate() {
+            self.write_impl(lba + (i * PIO_CHUNK_SECTORS) as u32, chunk).await?;
+            yield_now().await;
+        }
+        Ok(())
+    }
+
+    /// The task waits for the completion interrupt instead of copying every word
+    async fn read_dma(&self, transfer: &mut Option<DmaArea>, lba: u32, buffer: &mut [u8]) -> Result<(), AtaError> {
+        let area = dma_area(transfer)?;
+        for (i, chunk) in buffer.chunks_mut(MAX_DMA_SECTORS * SECTOR_SIZE).enumerate() {
+            self.dma_transfer(area, lba + (i * MAX_DMA_SECTORS) as u32, chunk.len(), false).await?;
+            chunk.copy_from_slice(area.buffer(chunk.len()));
+        }
+        Ok(())
+    }
+
+    async fn write_dma(&self, transfer: &mut Option<DmaArea>, lba: u32, data: &[u8]) -> Result<(), AtaError> {
+        let area = dma_area(transfer)?;
+        for (i, chunk) in data.chunks(MAX_DMA_SECTORS * SECTOR_SIZE).enumerate() {
+            area.buffer(chunk.len()).copy_from_slice(chunk);
+            let lba_mode = self.dma_transfer(area, lba + (i * MAX_DMA_SECTORS) as u32, chunk.len(), true).await?;
+            self.flush_cache(lba_mode, self.irq()).await?;
+        }
+        Ok(())
+    }
+
+    /// Runs one DMA command of `len` bytes between the drive and the bounce buffer
+    async fn dma_transfer(&self, area: &DmaArea, lba: u32, len: usize, is_write: bool) -> Result<LbaMode, AtaError> {
+        let regs = self.regs();
+        let bm = regs.bm_ide;
+        let irq = self.irq();
+        let direction = if is_write { 0 } else { BM_COMMAND_READ };
+
+        area.set_length(len);
+        let lba_mode = unsafe {
+            port::write(bm + BM_COMMAND, 0);
+            Port::<u32>::new(bm + BM_PRDT).write(area.prdt as u32);
+            // the error and interrupt bits are cleared by writing 1
+            port::write(bm + BM_STATUS, BM_STATUS_ERROR | BM_STATUS_INTERRUPT);
+            let lba_mode = self.io_prepare(lba, (len / SECTOR_SIZE) as u8, true, is_write, irq);
+            port::write(bm + BM_COMMAND, direction | BM_COMMAND_START);
+            lba_mode
+        };
+
+        let status = core::future::poll_fn(|cx| {
+            if irq {
+                CHANNEL_WAKERS[self.channel as usize].register(cx.waker());
+            }
+            let status = unsafe { port::read(bm + BM_STATUS) };
+            if status & (BM_STATUS_INTERRUPT | BM_STATUS_ERROR) != 0 || status & BM_STATUS_ACTIVE == 0 {
+                return Poll::Ready(status);
+            }
+            if !irq {
+                // no interrupt to wait for, checked again after the other tasks
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        }).await;
+
+        unsafe {
+            port::write(bm + BM_COMMAND, 0);
+            port::write(bm + BM_STATUS, BM_STATUS_ERROR | BM_STATUS_INTERRUPT);
+            match ide_polling(regs, true) {
+                AtaError::NoError if status & BM_STATUS_ERROR != 0 => Err(AtaError::DeviceFault),
+                AtaError::NoError => Ok(lba_mode),
+                err => Err(err),
+            }
+        }
+    }
+
+    /// Issues a SMART subcommand with interrupts disabled
+    unsafe fn smart_command(&self, feature: u8) {
+        let regs = self.regs();
+        regs.no_interrupt.store(0x02, Ordering::Relaxed);
+        ide_write(regs, AtaRegister::ControlAndAltStatus, 0x02);
+        while (ide_read(regs, AtaRegister::CommandAndStatus) & AtaStatus::Busy as u8) != 0 {}
+
+        let slavebit: u8 = match self.drive { DriveType::Master => 0b0000, DriveType::Slave => 0b10000 };
+        ide_write(regs, AtaRegister::HddEvSel, 0xA0 | slavebit);
+        ide_write(regs, AtaRegister::ErrorAndFeatures, feature);
+        ide_write(regs, AtaRegister::SecCount0, 0);
+        ide_write(regs, AtaRegister::Lba0, 0);
+        ide_write(regs, AtaRegister::Lba1, SMART_SIGNATURE.0);
+        ide_write(regs, AtaRegister::Lba2, SMART_SIGNATURE.1);
+        ide_write(regs, AtaRegister::CommandAndStatus, AtaCommand::Smart as u8);
+    }
+
+    async fn smart_health(&self) -> Result<Health, AtaError> {
+        // IDENTIFY word 82 bit 0: SMART feature set supported
+        if self.interface_type == IDEInterfaceType::Atapi || self.command_sets & 1 == 0 {
+            return Err(AtaError::NotSupported);
+        }
+
+        let _transfer = self.regs().transfer.lock().await;
+        let regs = self.regs();
+        let mut data = [0u8; SECTOR_SIZE];
+        let passed = unsafe {
+            // off by default on some drives
+            self.smart_command(SMART_ENABLE_OPERATIONS);
+            ide_polling(regs, false);
+
+            self.smart_command(SMART_READ_DATA);
+            match ide_polling(regs, true) {
+                AtaError::NoError => Port::<u16>::new(regs.io_base).read_into(&mut data),
+                err => return Err(err),
+            }
+
+            self.smart_command(SMART_RETURN_STATUS);
+            match ide_polling(regs, true) {
+                AtaError::NoError => {},
+                err => return Err(err),
+            }
+            (ide_read(regs, AtaRegister::Lba1), ide_read(regs, AtaRegister::Lba2)) == SMART_SIGNATURE
+        };
+        Health::parse(&data, passed)
+    }
+
+    /// The LBA of a valid request, which fits the 28 or 48 bits of the drive
+    fn check_request(&self, lba: u64, len: usize) -> Result<u32, AtaError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(AtaError::InvalidBufferSize);
+        }
+        if lba + (len / SECTOR_SIZE) as u64 > self.size as u64 {
             return Err(AtaError::OutOfRange);
         }
+        Ok(lba as u32)
+    }
+}
 
-        unsafe { self.write_impl(lba, data) }
+/// DMA buffers of the channel whose `transfer` lock is held, allocated on first use
+fn dma_area(transfer: &mut Option<DmaArea>) -> Result<&DmaArea, AtaError> {
+    if transfer.is_none() {
+        *transfer = Some(DmaArea::new().ok_or(AtaError::NoDmaMemory)?);
     }
+    Ok(transfer.as_ref().unwrap())
+}
 
-    fn size(&self) -> u32 {
-        self.size
+impl BlockDevice for IDEDevice {
+    /// With DMA if the drive supports it, PIO otherwise, and READ(10) on ATAPI drives
+    fn read<'a>(&'a self, lba: u64, buffer: &'a mut [u8]) -> BoxFuture<'a, Result<(), AtaError>> {
+        Box::pin(async move {
+            let lba = self.check_request(lba, buffer.len())?;
+            let mut transfer = self.regs().transfer.lock().await;
+            if self.interface_type == IDEInterfaceType::Atapi {
+                self.read_atapi(lba, buffer).await
+            } else if self.dma_capable() {
+                self.read_dma(&mut transfer, lba, buffer).await
+            } else {
+                self.read_pio(lba, buffer).await
+            }
+        })
+    }
+
+    fn write<'a>(&'a self, lba: u64, data: &'a [u8]) -> BoxFuture<'a, Result<(), AtaError>> {
+        Box::pin(async move {
+            let lba = self.check_request(lba, data.len())?;
+            if self.interface_type == IDEInterfaceType::Atapi {
+                return Err(AtaError::WriteProtected);
+            }
+            let mut transfer = self.regs().transfer.lock().await;
+            if self.dma_capable() {
+                self.write_dma(&mut transfer, lba, data).await
+            } else {
+                self.write_pio(lba, data).await
+            }
+        })
+    }
+
+    fn size(&self) -> u64 {
+        self.size as u64
     }
 
     fn model(&self) -> [u8; 41] {
         self.model
     }
 
-    fn channel(&self) -> ATAChannel {
-        self.channel
+    fn location(&self) -> String {
+        format!("{:?} {:?}", self.channel, self.drive)
+    }
+
+    fn pci_address(&self) -> Option<PciAddress> {
+        Some(self.controller.address)
     }
 
-    fn drive_type(&self) -> DriveType {
-        self.drive
+    fn health(&self) -> BoxFuture<'_, Result<Health, AtaError>> {
+        Box::pin(self.smart_health())
     }
 }
\ No newline at end of file
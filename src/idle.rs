@@ -0,0 +1,143 @@
+// CPU idle states.
+//
+// When the executor runs out of work it calls `enter`. On CPUs with MONITOR/MWAIT the C-states
+// reported by CPUID leaf 5 are used, the deeper the longer the CPU is expected to stay idle:
+// with no sleeping task due for a while nothing but the next interrupt will wake us anyway.
+// Without ARAT the local APIC timer stops below C1, so those CPUs never go deeper than C1.
+// Without MWAIT every idle period is a plain HLT. Entries and time spent (in TSC cycles) are
+// counted per state, `idle` in the shell shows them.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+use conquer_once::spin::OnceCell;
+use shared_lib::get_tsc;
+use crate::task::timer;
+
+const CPUID_ECX_MONITOR: u32 = 1 << 3;
+const CPUID_MWAIT_LEAF: u32 = 5;
+const CPUID_POWER_LEAF: u32 = 6;
+/// Leaf 6 EAX: the APIC timer keeps running in every C-state
+const CPUID_EAX_ARAT: u32 = 1 << 2;
+/// Leaf 5 EDX holds the number of sub-states of C0..C7, four bits each
+const MWAIT_SUBSTATE_BITS: u32 = 4;
+const MAX_STATES: usize = 8;
+
+const C_STATE_NAMES: [&str; MAX_STATES] = ["C0", "C1", "C2", "C3", "C4", "C5", "C6", "C7"];
+
+struct IdleState {
+    name: &'static str,
+    /// MWAIT hint, None for HLT
+    hint: Option<u32>,
+    /// Idle time in timer ticks that makes the state worth entering
+    min_idle_ticks: u64,
+}
+
+static STATES: OnceCell<Vec<IdleState>> = OnceCell::uninit();
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static ENTRIES: [AtomicU64; MAX_STATES] = [ZERO; MAX_STATES];
+static RESIDENCY: [AtomicU64; MAX_STATES] = [ZERO; MAX_STATES];
+
+/// Cache line armed by MONITOR, a write to it wakes the CPU like an interrupt does
+static MONITOR_LINE: AtomicU64 = AtomicU64::new(0);
+
+fn hlt_state() -> IdleState {
+    IdleState { name: "HLT", hint: None, min_idle_ticks: 0 }
+}
+
+fn detect_states() -> Vec<IdleState> {
+    let mut states = Vec::new();
+
+    let (max_leaf, features) = (__cpuid(0).eax, __cpuid(1).ecx);
+    if features & CPUID_ECX_MONITOR == 0 || max_leaf < CPUID_MWAIT_LEAF {
+        states.push(hlt_state());
+        return states;
+    }
+
+    let arat = max_leaf >= CPUID_POWER_LEAF && __cpuid(CPUID_POWER_LEAF).eax & CPUID_EAX_ARAT != 0;
+    let deepest = if arat { MAX_STATES as u32 - 1 } else { 1 };
+
+    let substates = __cpuid(CPUID_MWAIT_LEAF).edx;
+    for c_state in 1..=deepest {
+        if (substates >> (c_state * MWAIT_SUBSTATE_BITS)) & 0xF == 0 {
+            continue;
+        }
+        states.push(IdleState {
+            name: C_STATE_NAMES[c_state as usize],
+            // EAX[7:4] is the C-state minus one, sub-state 0
+            hint: Some((c_state - 1) << 4),
+            min_idle_ticks: states.len() as u64,
+        });
+    }
+
+    if states.is_empty() {
+        states.push(hlt_state());
+    }
+    states
+}
+
+pub fn init() {
+    let states = STATES.get_or_init(detect_states);
+    let names: Vec<&str> = states.iter().map(|state| state.name).collect();
+    log::info!("[idle] states: {}", names.join(", "));
+}
+
+/// Deepest state worth entering for the predicted idle time
+fn select(states: &[IdleState], predicted_ticks: Option<u64>) -> usize {
+    let predicted = predicted_ticks.unwrap_or(u64::MAX);
+    states.iter().rposition(|state| state.min_idle_ticks <= predicted).unwrap_or(0)
+}
+
+/// Idles until the next interrupt. Must be called with interrupts disabled, so a wakeup between
+/// checking for work and going idle isn't lost; interrupts are enabled on return.
+/// `predicted_ticks` is the time until the next timer is due, None if there is none.
+pub fn enter(predicted_ticks: Option<u64>) {
+    let Some(states) = STATES.get() else {
+        // SAFETY: enabling interrupts and halting, the STI shadow covers HLT
+        unsafe { asm!("sti; hlt", options(nomem, nostack)) };
+        return;
+    };
+
+    let index = select(states, predicted_ticks);
+    let start = get_tsc();
+    match states[index].hint {
+        // SAFETY: MONITOR only arms the address, the STI shadow covers MWAIT so an interrupt
+        // arriving in between still breaks it
+        Some(hint) => unsafe {
+            asm!("monitor", in("rax") addr_of!(MONITOR_LINE), in("ecx") 0, in("edx") 0, options(nostack, readonly));
+            asm!("sti; mwait", in("eax") hint, in("ecx") 0, options(nomem, nostack));
+        },
+        None => unsafe { asm!("sti; hlt", options(nomem, nostack)) },
+    }
+
+    ENTRIES[index].fetch_add(1, Ordering::Relaxed);
+    RESIDENCY[index].fetch_add(get_tsc().wrapping_sub(start), Ordering::Relaxed);
+
+    if states[index].hint.is_some() {
+        timer::account_idle();
+    }
+}
+
+/// Entries and residency of every state
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    let Some(states) = STATES.get() else {
+        return writeln!(out, "idle states not initialized");
+    };
+
+    let total: u64 = RESIDENCY.iter().map(|cycles| cycles.load(Ordering::Relaxed)).sum();
+    for (index, state) in states.iter().enumerate() {
+        let residency = RESIDENCY[index].load(Ordering::Relaxed);
+        write!(out, "{}", state.name)?;
+        if let Some(hint) = state.hint {
+            write!(out, " (hint {:#04x})", hint)?;
+        }
+        writeln!(out, ": {} entries, {} TSC cycles, {}%",
+            ENTRIES[index].load(Ordering::Relaxed), residency, (residency * 100).checked_div(total).unwrap_or(0))?;
+    }
+    Ok(())
+}
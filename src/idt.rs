@@ -5,7 +5,7 @@ use core::ops::{Index, IndexMut};
 use bitflags::bitflags;
 
 use shared_lib::addr::VirtAddr;
-use shared_lib::bits::get_bits;
+use shared_lib::bits::set_bits;
 
 #[repr(transparent)]
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -32,9 +32,7 @@ impl EntryOptions {
     pub unsafe fn set_stack_index(&mut self, index: u16) -> &mut Self {
         // The hardware IST index starts at 1, but our software IST index
         // starts at 0. Therefore we need to add 1 here.
-        let mask = index + 1;
-
-        self.0 |= get_bits(mask as u64, 0..3) as u16;
+        set_bits(&mut self.0, 0..3, index + 1);
 
         self
     }
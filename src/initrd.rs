@@ -0,0 +1,78 @@
+// Initial ramdisk.
+//
+// The loader reads the optional `initrd` file from the ESP, decompressing it like the kernel, and
+// passes its location in `BootInfo`. The pages are reserved in the memory map, so the image stays
+// valid for as long as the kernel runs and can back a filesystem before any disk driver is up.
+//
+// The image is used as is, or read as a `newc` cpio archive (`find . | cpio -o -H newc`) when
+// files are looked up by name.
+
+use conquer_once::spin::OnceCell;
+
+static INITRD: OnceCell<&'static [u8]> = OnceCell::uninit();
+
+/// Records the ramdisk passed by the loader. `addr` is a physical address, 0 means there is none.
+pub fn init(addr: u64, size: u64) {
+    if addr == 0 {
+        return;
+    }
+
+    let data = unsafe {
+        core::slice::from_raw_parts((addr + shared_lib::phys_mapping_offset()) as *const u8, size as usize)
+    };
+    log::info!("[initrd] {} bytes at {:#x}", size, addr);
+    INITRD.init_once(move || data);
+}
+
+/// Contents of the initial ramdisk, None if the loader didn't find one
+pub fn data() -> Option<&'static [u8]> {
+    INITRD.get().copied()
+}
+
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+/// Parses the 8 hex digit field `index` of a newc header
+fn cpio_field(header: &[u8], index: usize) -> Option<usize> {
+    let start = CPIO_MAGIC.len() + index * 8;
+    let digits = core::str::from_utf8(header.get(start..start + 8)?).ok()?;
+    usize::from_str_radix(digits, 16).ok()
+}
+
+/// Name and contents of the files in a newc cpio `archive`, stops at the trailer or the first
+/// malformed entry
+pub fn cpio_entries(archive: &[u8]) -> impl Iterator<Item = (&str, &[u8])> {
+    const FILE_SIZE_FIELD: usize = 6;
+    const NAME_SIZE_FIELD: usize = 11;
+
+    let mut offset = 0;
+    core::iter::from_fn(move || {
+        let header = archive.get(offset..offset + CPIO_HEADER_SIZE)?;
+        if !header.starts_with(CPIO_MAGIC) {
+            return None;
+        }
+        let file_size = cpio_field(header, FILE_SIZE_FIELD)?;
+        let name_size = cpio_field(header, NAME_SIZE_FIELD)?;
+
+        // the name ends with a NUL, name and data are padded to 4 bytes
+        let name_start = offset + CPIO_HEADER_SIZE;
+        let name = archive.get(name_start..name_start + name_size.checked_sub(1)?)?;
+        let name = core::str::from_utf8(name).ok()?;
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let data = archive.get(data_start..data_start + file_size)?;
+        offset = (data_start + file_size).next_multiple_of(4);
+
+        if name == CPIO_TRAILER {
+            return None;
+        }
+        Some((name.trim_start_matches("./"), data))
+    })
+}
+
+/// Contents of the file `name` in the ramdisk, if it is a cpio archive holding one
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    cpio_entries(data()?)
+        .find(|(entry, _)| *entry == name)
+        .map(|(_, data)| data)
+}
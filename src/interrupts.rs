@@ -1,12 +1,19 @@
+use alloc::vec::Vec;
 use core::arch::asm;
-use core::ptr;
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::exceptions::{self, ExceptionFrame};
 use crate::idt::{InterruptStackFrame, InterruptDescriptorTable, PageFaultErrorCode};
 use lazy_static::lazy_static;
-use crate::gdt;
-use spin;
+use crate::{gdt, percpu};
+use shared_lib::get_tsc;
+use shared_lib::interrupts::without_interrupts;
+use shared_lib::spinlock::Spinlock;
 use shared_lib::serial_logger::SERIAL_LOGGER;
-use crate::port::Port;
+use crate::port::PortReadOnly;
 use crate::apic::Apic;
+use crate::symbols::Resolved;
 
 pub const PIC_1_OFFSET: u8 = 32;
 
@@ -15,7 +22,9 @@ pub const PIC_1_OFFSET: u8 = 32;
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
-    Spurious = 39
+    Spurious = 39,
+    Thermal = 0xFD,
+    ApicError = 0xFE,
 }
 
 impl InterruptIndex {
@@ -28,28 +37,241 @@ impl InterruptIndex {
     }
 }
 
-pub static APIC: spin::Mutex<Apic> =
-    spin::Mutex::new(Apic::new());
+/// Vectors `register_irq` hands out, above the fixed ones of `InterruptIndex`. The 16 vectors
+/// above them are kept for the kernel, e.g. for IPIs.
+pub const FIRST_DYNAMIC_VECTOR: u8 = 48;
+pub const DYNAMIC_VECTORS: usize = 192;
+/// Handlers sharing one vector
+const MAX_SHARED_HANDLERS: usize = 4;
+
+/// Handler of a dynamic vector, called with interrupts disabled. Returns whether its device
+/// raised the interrupt, which tells the handlers of a shared vector apart.
+pub type IrqHandler = fn() -> bool;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vector(pub u8);
+
+/// Interrupts taken on each vector and the longest time a handler ran, in TSC cycles
+static IRQ_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+static IRQ_MAX_CYCLES: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+/// Spurious interrupts of the PIC and the local APIC, and dynamic vectors no handler claimed
+static SPURIOUS_IRQS: AtomicU64 = AtomicU64::new(0);
+
+/// APIC spurious interrupt vector, the reset value of the SPURIOUS register
+const APIC_SPURIOUS_VECTOR: u8 = 0xFF;
+
+static IRQ_HANDLERS: Spinlock<[[Option<IrqHandler>; MAX_SHARED_HANDLERS]; DYNAMIC_VECTORS]> =
+    Spinlock::new([[None; MAX_SHARED_HANDLERS]; DYNAMIC_VECTORS]);
+
+pub static APIC: Spinlock<Apic> =
+    Spinlock::new(Apic::new());
+
+/// Unmapped page below the boot stack, 0 if unknown
+static KERNEL_STACK_GUARD: AtomicU64 = AtomicU64::new(0);
+
+/// Lets the page fault handler recognize accesses to `guard_page` as a kernel stack overflow.
+pub fn set_kernel_stack_guard(guard_page: u64) {
+    KERNEL_STACK_GUARD.store(guard_page, Ordering::Relaxed);
+}
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        // faults and aborts, with a full register dump
+        exceptions::install(&mut idt);
+        idt.debug.set_handler_fn(debug_handler);
+        unsafe {
+            idt.non_maskable_interrupt.set_handler_fn(nmi_handler).set_stack_index(gdt::NMI_IST_INDEX);
+        }
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
         idt[InterruptIndex::Spurious.as_usize()].set_handler_fn(spurious_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt[InterruptIndex::Thermal.as_usize()].set_handler_fn(thermal_interrupt_handler);
+        idt[InterruptIndex::ApicError.as_usize()].set_handler_fn(apic_error_handler);
+        idt[usize::from(APIC_SPURIOUS_VECTOR)].set_handler_fn(spurious_handler);
+        for vector in 0..DYNAMIC_VECTORS {
+            let vector = FIRST_DYNAMIC_VECTOR + vector as u8;
+            unsafe {
+                idt[usize::from(vector)].set_handler_addr(exceptions::irq_stub(vector));
+            }
+        }
 
         idt
     };
 }
 
+/// CPUID.1:EDX, machine check exception
+const CPUID_MCE: u32 = 1 << 7;
+const CR4_MCE: u64 = 1 << 6;
+
 pub fn init_idt() {
     IDT.load();
+
+    // without CR4.MCE a machine check shuts the CPU down instead of reaching the handler
+    if __cpuid(1).edx & CPUID_MCE != 0 {
+        unsafe {
+            asm!("mov {tmp}, cr4", "or {tmp}, {mce}", "mov cr4, {tmp}",
+                tmp = out(reg) _, mce = in(reg) CR4_MCE, options(nomem, nostack, preserves_flags));
+        }
+    }
+}
+
+/// Installs `handler` on a dynamic vector and returns the vector, which the caller then programs
+/// into its device or the IO-APIC. With `vector_hint` the handler gets that vector, shared with
+/// the handlers already on it, otherwise the first free one.
+pub fn register_irq(vector_hint: Option<Vector>, handler: IrqHandler) -> Result<Vector, &'static str> {
+    without_interrupts(|| {
+        let mut irq_handlers = IRQ_HANDLERS.lock();
+
+        let index = match vector_hint {
+            Some(Vector(vector)) => usize::from(vector.checked_sub(FIRST_DYNAMIC_VECTOR).ok_or("not a dynamic vector")?),
+            None => irq_handlers.iter().position(|handlers| handlers[0].is_none()).ok_or("no free interrupt vector")?,
+        };
+        let slot = irq_handlers.get_mut(index).ok_or("not a dynamic vector")?
+            .iter_mut().find(|slot| slot.is_none()).ok_or("too many handlers share this vector")?;
+        *slot = Some(handler);
+
+        Ok(Vector(FIRST_DYNAMIC_VECTOR + index as u8))
+    })
+}
+
+/// Removes `handler` from `vector`, which is free again once its last handler is gone. The caller
+/// masks or reroutes whatever raised it first.
+pub fn unregister_irq(Vector(vector): Vector, handler: IrqHandler) -> Result<(), &'static str> {
+    let index = usize::from(vector.checked_sub(FIRST_DYNAMIC_VECTOR).ok_or("not a dynamic vector")?);
+    without_interrupts(|| {
+        let mut irq_handlers = IRQ_HANDLERS.lock();
+        let handlers = irq_handlers.get_mut(index).ok_or("not a dynamic vector")?;
+        let position = handlers.iter()
+            .position(|slot| slot.is_some_and(|registered| core::ptr::fn_addr_eq(registered, handler)))
+            .ok_or("handler is not registered")?;
+
+        // the others move up, a vector with an empty first slot counts as free
+        handlers[position..].rotate_left(1);
+        handlers[MAX_SHARED_HANDLERS - 1] = None;
+        Ok(())
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VectorStats {
+    pub vector: u8,
+    pub count: u64,
+    /// Longest time a handler of the vector ran, in TSC cycles
+    pub max_cycles: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct IrqStats {
+    /// The vectors which were taken at least once
+    pub vectors: Vec<VectorStats>,
+    pub spurious: u64,
+}
+
+/// Counts an interrupt on `vector` whose handler started at the TSC value `start`
+fn account_irq(vector: u8, start: u64) {
+    let index = usize::from(vector);
+    IRQ_COUNTS[index].fetch_add(1, Ordering::Relaxed);
+    percpu::current().stats.interrupts.fetch_add(1, Ordering::Relaxed);
+    IRQ_MAX_CYCLES[index].fetch_max(get_tsc().wrapping_sub(start), Ordering::Relaxed);
+}
+
+pub fn stats() -> IrqStats {
+    let vectors = (0..=u8::MAX)
+        .map(|vector| VectorStats {
+            vector,
+            count: IRQ_COUNTS[usize::from(vector)].load(Ordering::Relaxed),
+            max_cycles: IRQ_MAX_CYCLES[usize::from(vector)].load(Ordering::Relaxed),
+        })
+        .filter(|stats| stats.count > 0)
+        .collect();
+    IrqStats { vectors, spurious: SPURIOUS_IRQS.load(Ordering::Relaxed) }
+}
+
+fn vector_name(vector: u8) -> &'static str {
+    match vector {
+        0..=31 => exceptions::exception_name(u64::from(vector)),
+        v if v == InterruptIndex::Timer.as_u8() => "timer",
+        v if v == InterruptIndex::Keyboard.as_u8() => "keyboard",
+        v if v == InterruptIndex::Spurious.as_u8() || v == APIC_SPURIOUS_VECTOR => "spurious",
+        v if v == InterruptIndex::Thermal.as_u8() => "thermal",
+        v if v == InterruptIndex::ApicError.as_u8() => "apic error",
+        v if v >= FIRST_DYNAMIC_VECTOR && usize::from(v - FIRST_DYNAMIC_VECTOR) < DYNAMIC_VECTORS => "dynamic",
+        _ => "unknown",
+    }
+}
+
+/// Output of the `irqstat` shell command
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    let stats = stats();
+    let uptime_secs = crate::task::timer::now().map_or(0, |now| now.uptime_ms / 1000);
+    let tsc_mhz = crate::task::timer::tsc_frequency().map(|hz| hz / 1_000_000).filter(|mhz| *mhz > 0);
+
+    writeln!(out, "vector name             count      /s   max latency")?;
+    for vector in &stats.vectors {
+        write!(out, "{:>6} {:<12} {:>10} {:>7} ", vector.vector, vector_name(vector.vector), vector.count,
+            vector.count / uptime_secs.max(1))?;
+        match tsc_mhz {
+            Some(mhz) => writeln!(out, "{:>10} us", vector.max_cycles / mhz)?,
+            None => writeln!(out, "{:>10} cycles", vector.max_cycles)?,
+        }
+    }
+    writeln!(out, "spurious: {}", stats.spurious)?;
+    crate::softirq::dump(out)?;
+    percpu::dump(out)
+}
+
+/// Calls the handlers of the dynamic vector `vector`, entered from `exceptions`
+pub(crate) fn dispatch_irq(vector: u8) {
+    crate::trace_irq_enter!(vector);
+    let start = get_tsc();
+    // copied out so the handlers may register others
+    let handlers = IRQ_HANDLERS.lock()[usize::from(vector - FIRST_DYNAMIC_VECTOR)];
+    let mut handled = false;
+    for handler in handlers.iter().flatten() {
+        handled |= handler();
+    }
+    if !handled {
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+        log::trace!("[interrupts] unclaimed interrupt on vector {}", vector);
+    }
+
+    unsafe {
+        APIC.lock()
+            .notify_end_of_interrupt();
+    }
+    account_irq(vector, start);
+    crate::trace_irq_exit!(vector);
+}
+
+/// Lets a fault report get through even if it interrupted a log call
+pub(crate) fn unlock_loggers() {
+    unsafe {
+        if shared_lib::logger::LOGGER.is_initialized() {
+            shared_lib::logger::LOGGER
+                .get()
+                .map(|l| l.force_unlock())
+                .unwrap()
+        } else if SERIAL_LOGGER.is_initialized() {
+            SERIAL_LOGGER.get().map(|l| l.force_unlock()).unwrap()
+        }
+    }
+}
+
+extern "x86-interrupt" fn debug_handler(
+    stack_frame: InterruptStackFrame)
+{
+    let dr6: u64;
+    unsafe {
+        asm!("mov {}, dr6", out(reg) dr6, options(nomem, nostack, preserves_flags));
+        // the status bits are sticky
+        asm!("mov dr6, {}", in(reg) 0xFFFF_0FF0u64, options(nomem, nostack, preserves_flags));
+    }
+    log::info!("EXCEPTION: DEBUG, DR6 {:#x}\n{:#?}", dr6, stack_frame);
 }
 
 extern "x86-interrupt" fn breakpoint_handler(
@@ -58,83 +280,116 @@ extern "x86-interrupt" fn breakpoint_handler(
     log::info!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame, _error_code: u64) -> !
+extern "x86-interrupt" fn nmi_handler(
+    stack_frame: InterruptStackFrame)
 {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    let start = get_tsc();
+    crate::watchdog::nmi_tick(&stack_frame);
+    account_irq(2, start);
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(
-    stack_frame: InterruptStackFrame, error_code: u64)
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
-    log::info!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}. Error code: {}", stack_frame, error_code);
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
+    stack_frame: InterruptStackFrame)
 {
+    crate::trace_irq_enter!(InterruptIndex::Timer.as_u8());
+    let start = get_tsc();
     crate::task::timer::raise_timer();
+    percpu::current().stats.timer_ticks.fetch_add(1, Ordering::Relaxed);
+    crate::apic::rearm_timer();
+    crate::watchdog::timer_tick(&stack_frame);
 
     unsafe {
         APIC.lock()
             .notify_end_of_interrupt();
     }
+    account_irq(InterruptIndex::Timer.as_u8(), start);
+    crate::trace_irq_exit!(InterruptIndex::Timer.as_u8());
+    // last: the handler may only return once this thread is scheduled again
+    crate::thread::tick();
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
+    stack_frame: InterruptStackFrame)
 {
-    let mut port = Port::new(0x60);
+    crate::trace_irq_enter!(InterruptIndex::Keyboard.as_u8());
+    let start = get_tsc();
+    let mut port = PortReadOnly::<u8>::new(0x60);
     let scancode = unsafe { port.read() };
-    crate::task::keyboard::add_scancode(scancode);
-
-    unsafe {
-        APIC.lock()
-            .notify_end_of_interrupt();
+    if !crate::sysrq::handle_scancode(scancode, &stack_frame) {
+        crate::softirq::raise(crate::task::keyboard::scancode_bottom_half, scancode as u64);
     }
-}
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    unsafe {
-        if shared_lib::logger::LOGGER.is_initialized() {
-            shared_lib::logger::LOGGER
-                .get()
-                .map(|l| l.force_unlock())
-                .unwrap()
-        } else if SERIAL_LOGGER.is_initialized() {
-            SERIAL_LOGGER.get().map(|l| l.force_unlock()).unwrap()
+    if crate::apic::legacy_pic() {
+        crate::apic::notify_pic_end_of_interrupt();
+    } else {
+        unsafe {
+            APIC.lock()
+                .notify_end_of_interrupt();
         }
     }
+    account_irq(InterruptIndex::Keyboard.as_u8(), start);
+    crate::trace_irq_exit!(InterruptIndex::Keyboard.as_u8());
+}
 
-    log::info!("EXCEPTION: PAGE FAULT");
-
+/// Resolves the page fault of `frame`, false if it is fatal. Runs on the page fault IST stack.
+pub(crate) fn handle_page_fault(frame: &ExceptionFrame) -> bool {
     let cr2: u64;
     unsafe {
         asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
     }
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+
+    // first touch of a heap page which isn't backed yet
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) && crate::allocator::handle_heap_fault(cr2) {
+        return true;
+    }
+
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE) && crate::cow::handle_fault(cr2) {
+        return true;
+    }
 
-    log::info!("Accessed Address: {:#x}", cr2);
-    log::info!("Error Code: {:?}", error_code);
-    log::info!("{:#?}", stack_frame);
+    let guard_page = KERNEL_STACK_GUARD.load(Ordering::Relaxed);
+    if guard_page != 0 && (guard_page..guard_page + 4096).contains(&cr2) {
+        // the handler runs on its own stack, the panic path has room to print the backtrace
+        panic!("kernel stack overflow: RSP {:#x}, accessed address {:#x}, instruction {}",
+            frame.stack_frame.stack_pointer.0, cr2, Resolved(frame.instruction_pointer()));
+    }
+    false
+}
 
-    log::info!("Reading stack from address {:#x}", stack_frame.value.stack_pointer.0);
+extern "x86-interrupt" fn apic_error_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    let start = get_tsc();
+    let mut apic = APIC.lock();
+    crate::apic::report_errors(apic.take_errors());
     unsafe {
-        for i in 0..40 {
-            log::info!("{}: {:#x}", i, ptr::read_volatile((stack_frame.value.stack_pointer.0 + 8 * i) as *const u64));
-        }
+        apic.notify_end_of_interrupt();
     }
+    account_irq(InterruptIndex::ApicError.as_u8(), start);
+}
 
-    loop {
-        unsafe {
-            asm!("hlt", options(nomem, nostack, preserves_flags));
-        }
+extern "x86-interrupt" fn thermal_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    let start = get_tsc();
+    crate::thermal::handle_interrupt();
+    unsafe {
+        APIC.lock()
+            .notify_end_of_interrupt();
     }
+    account_irq(InterruptIndex::Thermal.as_u8(), start);
 }
 
+/// Spurious interrupts of the PIC (IRQ 7) and the local APIC, neither gets an EOI
 extern "x86-interrupt" fn spurious_handler(
     _stack_frame: InterruptStackFrame)
 {
+    SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
 }
@@ -0,0 +1,196 @@
+// IO-APIC redirection table.
+//
+// Each input of the IO-APIC is a global system interrupt (GSI) with a 64-bit redirection entry:
+// the vector, delivery mode, polarity, trigger mode, mask and destination APIC ID. `init` masks
+// every entry, drivers then route their interrupt with `route_irq` or, for the ISA IRQs,
+// `route_legacy_irq`, which applies the MADT Interrupt Source Overrides: the firmware may wire
+// an ISA IRQ to another GSI (the PIT to GSI 2 on most machines) or with another polarity and
+// trigger mode than the ISA default, active high and edge triggered.
+
+use alloc::vec::Vec;
+use shared_lib::addr::VirtAddr;
+use shared_lib::bits::{get_bits, set_bit, set_bits};
+use shared_lib::register_block;
+use shared_lib::spinlock::Spinlock;
+use shared_lib::volatile::Mmio;
+use crate::xsdt::InterruptSourceOverride;
+
+register_block! {
+    /// IOAPIC indirect register access window
+    pub struct IoApicRegs[0x20] {
+        IOREGSEL: u32 = 0x00,
+        IOWIN: u32    = 0x10,
+    }
+}
+
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+const DELIVERY_FIXED: u32 = 0b000;
+const DELIVERY_NMI: u32 = 0b100;
+const MASKED: u8 = 16;
+
+/// Legacy IRQs with a fixed meaning on PCs
+pub const IRQ_PIT: u8 = 0;
+pub const IRQ_KEYBOARD: u8 = 1;
+pub const IRQ_COM1: u8 = 4;
+pub const IRQ_RTC: u8 = 8;
+pub const IRQ_PRIMARY_ATA: u8 = 14;
+pub const IRQ_SECONDARY_ATA: u8 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+struct IoApic {
+    base: VirtAddr,
+    /// GSI of the first redirection entry
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn regs(&self) -> Mmio<IoApicRegs> {
+        // SAFETY: base is the mapped register page passed to `init`
+        unsafe { Mmio::new(self.base) }
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        let regs = self.regs();
+        regs.write(IoApicRegs::IOREGSEL, register & 0xff);
+        regs.read(IoApicRegs::IOWIN)
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        let regs = self.regs();
+        regs.write(IoApicRegs::IOREGSEL, register & 0xff);
+        regs.write(IoApicRegs::IOWIN, value);
+    }
+
+    /// Register of the low half of the redirection entry of `gsi`
+    fn entry_register(&self, gsi: u32) -> Result<u32, &'static str> {
+        match gsi.checked_sub(self.gsi_base) {
+            Some(index) if index < self.entries => Ok(IOREDTBL + 2 * index),
+            _ => Err("GSI not handled by the IO-APIC"),
+        }
+    }
+}
+
+static IO_APIC: Spinlock<Option<IoApic>> = Spinlock::new(None);
+static OVERRIDES: Spinlock<Vec<InterruptSourceOverride>> = Spinlock::new(Vec::new());
+
+/// Takes over the IO-APIC mapped at `base` and masks all of its inputs
+pub fn init(base: VirtAddr, gsi_base: u32, overrides: Vec<InterruptSourceOverride>) {
+    let mut io_apic = IoApic { base, gsi_base, entries: 0 };
+    let version = io_apic.read(IOAPICVER);
+    io_apic.entries = get_bits(version, 16..24) + 1;
+    log::info!("IOAPIC[0]: version: {}, address: {:#x}, GSIs {}-{}",
+        version as u8, base.0, gsi_base, gsi_base + io_apic.entries - 1);
+
+    for index in 0..io_apic.entries {
+        io_apic.write(IOREDTBL + 2 * index, 1 << MASKED);
+    }
+
+    *IO_APIC.lock() = Some(io_apic);
+    *OVERRIDES.lock() = overrides;
+}
+
+fn set_entry(gsi: u32, low: u32, dest: u8) -> Result<(), &'static str> {
+    let io_apic = IO_APIC.lock();
+    let io_apic = io_apic.as_ref().ok_or("IO-APIC is not initialized")?;
+    let register = io_apic.entry_register(gsi)?;
+
+    // masked while the halves don't match
+    io_apic.write(register, 1 << MASKED);
+    io_apic.write(register + 1, u32::from(dest) << 24);
+    io_apic.write(register, low);
+    Ok(())
+}
+
+fn entry(delivery: u32, vector: u8, polarity: Polarity, trigger: Trigger) -> u32 {
+    let mut low = 0;
+    set_bits(&mut low, 0..8, u32::from(vector));
+    set_bits(&mut low, 8..11, delivery);
+    set_bit(&mut low, 11, false); // Physical destination
+    set_bit(&mut low, 13, polarity == Polarity::ActiveLow);
+    set_bit(&mut low, 15, trigger == Trigger::Level);
+    low
+}
+
+/// Delivers `gsi` as `vector` to the local APIC with ID `dest`, unmasked
+pub fn route_irq(gsi: u32, vector: u8, polarity: Polarity, trigger: Trigger, dest: u8) -> Result<(), &'static str> {
+    set_entry(gsi, entry(DELIVERY_FIXED, vector, polarity, trigger), dest)
+}
+
+/// Delivers `gsi` as NMI to the local APIC with ID `dest`, unmasked
+pub fn route_nmi(gsi: u32, polarity: Polarity, trigger: Trigger, dest: u8) -> Result<(), &'static str> {
+    set_entry(gsi, entry(DELIVERY_NMI, 0, polarity, trigger), dest)
+}
+
+/// GSI, polarity and trigger mode of the ISA IRQ `irq`, after the Interrupt Source Overrides
+pub fn legacy_irq(irq: u8) -> (u32, Polarity, Trigger) {
+    let overrides = OVERRIDES.lock();
+    let Some(interrupt_override) = overrides.iter().find(|o| o.bus_source == 0 && o.irq_source == irq) else {
+        return (u32::from(irq), Polarity::ActiveHigh, Trigger::Edge);
+    };
+
+    // MPS INTI flags, 0 conforms to the bus: ISA is active high, edge triggered
+    let flags = interrupt_override.flags;
+    let polarity = if flags & 0b11 == 0b11 { Polarity::ActiveLow } else { Polarity::ActiveHigh };
+    let trigger = if (flags >> 2) & 0b11 == 0b11 { Trigger::Level } else { Trigger::Edge };
+    (interrupt_override.global_system_interrupt, polarity, trigger)
+}
+
+/// Routes the ISA IRQ `irq` as `vector` to `dest`, returns the GSI it is wired to
+pub fn route_legacy_irq(irq: u8, vector: u8, dest: u8) -> Result<u32, &'static str> {
+    let (gsi, polarity, trigger) = legacy_irq(irq);
+    route_irq(gsi, vector, polarity, trigger, dest)?;
+    Ok(gsi)
+}
+
+/// Routes the PCI INTx input the firmware assigned to ISA IRQ `line` (the Interrupt Line register
+/// of the function) as `vector` to `dest`, returns its GSI. PCI interrupts are level triggered and
+/// active low unless an Interrupt Source Override says otherwise; they may be shared.
+pub fn route_pci_irq(line: u8, vector: u8, dest: u8) -> Result<u32, &'static str> {
+    let (gsi, polarity, trigger) = {
+        let overrides = OVERRIDES.lock();
+        match overrides.iter().find(|o| o.bus_source == 0 && o.irq_source == line) {
+            Some(interrupt_override) => {
+                let flags = interrupt_override.flags;
+                let polarity = if flags & 0b11 == 0b01 { Polarity::ActiveHigh } else { Polarity::ActiveLow };
+                let trigger = if (flags >> 2) & 0b11 == 0b01 { Trigger::Edge } else { Trigger::Level };
+                (interrupt_override.global_system_interrupt, polarity, trigger)
+            },
+            None => (u32::from(line), Polarity::ActiveLow, Trigger::Level),
+        }
+    };
+    route_irq(gsi, vector, polarity, trigger, dest)?;
+    Ok(gsi)
+}
+
+fn set_masked(gsi: u32, masked: bool) -> Result<(), &'static str> {
+    let io_apic = IO_APIC.lock();
+    let io_apic = io_apic.as_ref().ok_or("IO-APIC is not initialized")?;
+    let register = io_apic.entry_register(gsi)?;
+
+    let mut low = io_apic.read(register);
+    set_bit(&mut low, MASKED, masked);
+    io_apic.write(register, low);
+    Ok(())
+}
+
+pub fn mask(gsi: u32) -> Result<(), &'static str> {
+    set_masked(gsi, true)
+}
+
+pub fn unmask(gsi: u32) -> Result<(), &'static str> {
+    set_masked(gsi, false)
+}
@@ -8,12 +8,13 @@ use core::panic::PanicInfo;
 use shared_lib::frame_allocator::FrameAllocator;
 use shared_lib::serial_println;
 use crate::apic::{disable_pic, initialize_apic};
-use crate::gpt::parse_gpt;
-use crate::pci::PciDevice::{Drive, Generic};
+use crate::pci::Probed::{Drive, Generic};
 use crate::xsdt::read_xsdt;
+use crate::sysinfo::{Category, Node};
 
 pub mod idt;
-mod interrupts;
+pub mod interrupts;
+pub mod exceptions;
 pub mod gdt;
 pub mod port;
 pub mod memory;
@@ -21,11 +22,40 @@ pub mod task;
 pub mod allocator;
 pub mod shell;
 mod apic;
-mod xsdt;
-mod pci;
-mod ide;
+pub mod ioapic;
+pub mod xsdt;
+pub mod pci;
+pub mod ide;
+pub mod block;
 pub mod chrono;
-mod gpt;
+pub mod gpt;
+pub mod mbr;
+pub mod smart;
+pub mod trace;
+pub mod symbols;
+pub mod watchdog;
+pub mod screenshot;
+pub mod xmodem;
+pub mod config;
+pub mod panic;
+pub mod virtio;
+pub mod driver;
+pub mod idle;
+pub mod initrd;
+pub mod sysrq;
+pub mod efi;
+pub mod thermal;
+pub mod sysinfo;
+pub mod cmdline;
+pub mod cow;
+pub mod vm;
+pub mod meminfo;
+pub mod smp;
+pub mod percpu;
+pub mod thread;
+pub mod softirq;
+pub mod vfs;
+pub mod fs;
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
@@ -40,29 +70,180 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
 
 pub fn preinit(allocator: &mut FrameAllocator, rsdp_addr: u64) {
     gdt::init();
+    percpu::init_bsp();
+    softirq::init();
     interrupts::init_idt();
-    let apic_addrs= read_xsdt(allocator, rsdp_addr);
+    cow::enable_write_protect();
+    gdt::init_cpu(gdt::BSP, allocator).expect("Failed to allocate the IST stacks");
+    let mut apic_addrs= read_xsdt(allocator, rsdp_addr);
+    let local_apic = apic_addrs.local_apic_addr;
+    let processors = core::mem::take(&mut apic_addrs.processors);
     disable_pic();
     initialize_apic(apic_addrs);
+    idle::init();
+    thermal::init();
+    smp::init(allocator, local_apic, &processors);
+    task::timer::set_wall_clock(chrono::read_rtc().timestamp() as u64);
+    config::load_persistent();
 }
 
-pub async fn init() {
+/// Registers the built-in drivers and initializes them in dependency order. `boot_slot` is the
+/// A/B kernel slot the loader started.
+pub async fn init(boot_slot: Option<shared_lib::ab_boot::Slot>) {
+    // the filesystems first, their write-back goes through the cache
+    sysrq::register_sync_hook("vfs", vfs::sync_hook);
+    sysrq::register_sync_hook("block cache", block::cache::sync_hook);
+
+    driver::register("tmpfs", &[], init_tmpfs).expect("Failed to register tmpfs");
+    driver::register("pci", &[], init_pci_devices).expect("Failed to register PCI driver");
+    // on a disk found behind PCI
+    driver::register("esp", &["pci"], init_esp).expect("Failed to register the EFI system partition");
+
+    driver::init_all().await;
+
+    if let Some(init) = cmdline::init_path() {
+        log::warn!("init={} requested, but there is no userspace to run it in, staying in the shell", init);
+    }
+
+    // everything is up, an A/B kernel on trial is good from now on
+    if let Some(slot) = boot_slot {
+        shared_lib::ab_boot::mark_successful(slot);
+        log::info!("Boot of kernel slot {} confirmed", slot.name());
+    }
+}
+
+async fn init_tmpfs() -> driver::InitResult {
+    vfs::mount_tmpfs("/tmp").map_err(|e| {
+        log::warn!("[vfs] Failed to mount /tmp: {:?}", e);
+        "failed to mount /tmp"
+    })
+}
+
+async fn init_pci_devices() -> driver::InitResult {
+    register_pci_drivers();
     let pci_devices = pci::init_pci().await;
+    // the ESP of the boot disk is mounted by its own driver
+    handle_pci_devices(pci_devices).await;
+    Ok(())
+}
+
+/// Mounts the first EFI system partition found at boot on /boot
+async fn init_esp() -> driver::InitResult {
+    let Some(esp) = block::partition::partitions().into_iter().find(|partition| is_esp(partition)) else {
+        log::info!("[vfs] No EFI system partition, nothing on /boot");
+        return Ok(());
+    };
+    mount_esp(esp.id()).await
+}
+
+/// The drivers `pci::init_pci` binds to the functions it finds. Virtio comes first: its
+/// functions are matched by id, the others by class.
+fn register_pci_drivers() {
+    pci::register_driver("virtio-blk", virtio::blk::PCI_MATCHES, virtio::blk::probe, virtio::blk::remove)
+        .expect("Failed to register the virtio-blk driver");
+    pci::register_driver("virtio-9p", virtio::ninep::PCI_MATCHES, virtio::ninep::probe, virtio::ninep::remove)
+        .expect("Failed to register the virtio-9p driver");
+    pci::register_driver("ide", ide::PCI_MATCHES, ide::probe, ide::remove).expect("Failed to register the IDE driver");
+}
+
+/// Handles devices found by `pci rescan`, e.g. after QEMU `device_add`
+pub async fn pci_hotplug() {
+    if let Err(state) = driver::ready("pci").await {
+        log::warn!("[pci] No hotplug, PCI is {:?}", state);
+        return;
+    }
+
+    loop {
+        pci::rescan_requested().await;
+        log::info!("[pci] Rescanning");
+        let pci_devices = pci::rescan().await;
+        for esp in handle_pci_devices(pci_devices).await {
+            // logged, /boot stays as it is
+            let _ = mount_esp(esp).await;
+        }
+    }
+}
+
+fn is_esp(partition: &block::partition::Partition) -> bool {
+    match partition.partition_type() {
+        block::partition::PartitionType::Gpt(guid) => Some(guid) == gpt::type_guid("efi"),
+        block::partition::PartitionType::Mbr(partition_type) => partition_type == 0xef,
+    }
+}
+
+/// Mounts the EFI system partition `id` on /boot on a kernel thread, if nothing is mounted there
+/// yet
+async fn mount_esp(id: alloc::string::String) -> driver::InitResult {
+    let mounted = task::join::spawn_blocking(move || {
+        let Some(partition) = block::partition::find(&id) else {
+            return Ok(());
+        };
+        match vfs::mount_partition(&partition, "/boot", Some("fat")) {
+            Ok(()) => Ok(()),
+            Err(vfs::VfsError::Busy) => {
+                log::info!("[vfs] /boot is taken, {} not mounted", id);
+                Ok(())
+            },
+            Err(e) => {
+                log::warn!("[vfs] Failed to mount the EFI system partition {}: {:?}", id, e);
+                Err("failed to mount the EFI system partition")
+            },
+        }
+    });
+    match mounted {
+        Ok(handle) => handle.await.unwrap_or(Err("EFI system partition mount cancelled")),
+        Err(e) => {
+            log::warn!("[vfs] No thread to mount the EFI system partition: {}", e);
+            Err(e)
+        },
+    }
+}
 
+/// Registers the drives among `pci_devices` and their partitions. Returns the EFI system
+/// partitions found.
+async fn handle_pci_devices(pci_devices: alloc::vec::Vec<pci::Probed>) -> alloc::vec::Vec<alloc::string::String> {
+    let mut esps = alloc::vec::Vec::new();
     for pci_device in pci_devices {
         match pci_device {
             Drive(drive) => {
-                log::info!("[pci] Found {:?} drive on {:?} channel. Size: {} kB. Model: {}",
-                    drive.drive_type(),
-                    drive.channel(),
+                let model = alloc::string::String::from(core::str::from_utf8(&drive.model())
+                    .expect("drive model string is not utf-8")
+                    .trim_end_matches(['\0', ' ']));
+                log::info!("[pci] Found drive at {}. Size: {} kB. Model: {}",
+                    drive.location(),
                     (drive.size() * 512) / 1024,
-                    core::str::from_utf8(&drive.model()).expect("IDE drive model string is not utf-8"));
+                    model);
+
+                let location = drive.location();
+                let disk = block::register(drive);
+                let mut node = Node::new(disk.name())
+                    .with("location", location)
+                    .with("size_kib", (disk.size() * 512) / 1024)
+                    .with("model", model);
 
-                parse_gpt(drive).expect("Failed to parse GPT");
+                // a blank virtio image has no partition table
+                let partitions = block::partition::scan(&disk).await.unwrap_or_else(|e| {
+                    log::warn!("[block] No partitions on {}: {:?}", disk.name(), e);
+                    alloc::vec::Vec::new()
+                });
+                for partition in &partitions {
+                    let mut child = Node::new(partition.id())
+                        .with("name", partition.name())
+                        .with("type", partition.partition_type())
+                        .with("lba", alloc::format!("{}-{}", partition.start(), partition.end()));
+                    if partition.unique_guid() != 0 {
+                        child = child.with("guid", gpt::guid_to_str(partition.unique_guid()));
+                    }
+                    node = node.with_child(child);
+                }
+                esps.extend(partitions.iter().filter(|partition| is_esp(partition)).map(|partition| partition.id()));
+                block::partition::register(partitions);
+                sysinfo::set(Category::Block, node);
             },
             Generic(device) => {
-                log::info!("[pci] device: {:?}", device);
+                log::info!("[pci] {} {:04x}:{:04x} has no driver", device.address(), device.id().vendor_id, device.id().device_id);
             }
         }
     }
+    esps
 }
\ No newline at end of file
@@ -4,8 +4,13 @@
 extern crate alloc;
 extern crate shared_lib;
 
-use shared_lib::{BootInfo, serial_logger, VIRT_MAPPING_OFFSET};
+use shared_lib::{BootInfo, serial_logger};
+use shared_lib::boot_info::{ApTrampoline, EfiRuntimeServices, Initrd, KernelSlide, NextFreeFrame, Rsdp, StackGuard, Symbols};
+use shared_lib::cmdline::Cmdline;
+use shared_lib::frame_allocator::MemoryMap;
+use shared_lib::logger::FrameBufferInfo;
 use shared_lib::entry_point;
+use ferr_os::cmdline::Console;
 use ferr_os::memory::active_level_4_table;
 
 use core::panic::PanicInfo;
@@ -15,54 +20,48 @@ use core::sync::atomic::{ AtomicU64, Ordering };
 use ferr_os::allocator::init_heap;
 use ferr_os::shell::Shell;
 use ferr_os::task::executor::Executor;
-use ferr_os::task::{keyboard, Task, timer::{timer_loop, sleep_for}};
-use ferr_os::port::Port;
+use ferr_os::task::{console, keyboard, Priority, Task, timer::{interval, sleep_for}};
+use ferr_os::port::PortWriteOnly;
 use ferr_os::chrono::read_rtc;
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    unsafe {
-        logger::LOGGER
-            .get()
-            .map(|l| l.force_unlock());
-
-        serial_logger::SERIAL_LOGGER
-            .get()
-            .map(|l| l.force_unlock());
-    };
-
-    log::error!("{}", info);
-
-    loop {
-        unsafe {
-            asm!("hlt", options(nomem, nostack, preserves_flags));
-        }
-    }
+    ferr_os::panic::handle_panic(info)
 }
 
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static shared_lib::BootInfo) -> ! {
     shared_lib::serial_println!("Hello from kernel!");
-    let fb_info = boot_info.fb_info;
-    let memory_map = &boot_info.memory_map;
+    let fb_info = *boot_info.get::<FrameBufferInfo>().expect("No framebuffer in boot information");
+    let memory_map = boot_info.get::<MemoryMap>().expect("No memory map in boot information");
+    let next_free_frame = boot_info.get::<NextFreeFrame>().expect("No next free frame in boot information");
+    ferr_os::cmdline::init(boot_info.get::<Cmdline>().copied().unwrap_or(Cmdline::empty()));
+
+    ferr_os::interrupts::set_kernel_stack_guard(boot_info.get::<StackGuard>().map_or(0, |guard| guard.0));
+
+    // NXE is already on if the loader enabled it, this only lets the kernel's mappings use it
+    shared_lib::page_table::enable_no_execute();
 
     shared_lib::serial_println!("Creating allocator");
     let l4_table = unsafe {
         active_level_4_table()
     };
 
-    let mut allocator = shared_lib::frame_allocator::FrameAllocator::new(memory_map, VIRT_MAPPING_OFFSET, boot_info.memory_map_next_free_frame);
+    let mut allocator = unsafe {
+        shared_lib::frame_allocator::FrameAllocator::new(memory_map, shared_lib::phys_mapping_offset(), next_free_frame.0)
+    };
+    // what is left of the low 16 MiB after the loader is kept for ISA DMA
+    allocator.reserve_dma_zone();
 
     shared_lib::serial_println!("Creating heap");
     init_heap(l4_table, &mut allocator)
         .expect("Failed to init heap");
+    ferr_os::memory::register_memory_map(memory_map);
 
     shared_lib::serial_println!("Creating logger");
 
-    let logger_is_serial = true;
-
-    if logger_is_serial {
+    if ferr_os::cmdline::console() == Console::Serial {
         let logger = serial_logger::SERIAL_LOGGER.get_or_init(move || serial_logger::LockedSerialLogger::new());
         log::set_logger(logger).unwrap();
     } else {
@@ -70,34 +69,67 @@ fn kernel_main(boot_info: &'static shared_lib::BootInfo) -> ! {
         log::set_logger(logger).unwrap();
     }
 
-    log::set_max_level(log::LevelFilter::Debug);
+    log::set_max_level(ferr_os::cmdline::log_level().unwrap_or(log::LevelFilter::Debug));
 
     log::info!("Hello from kernel!");
+    ferr_os::cmdline::report();
+    log::info!("Boot information version {}", boot_info.version());
 
-    ferr_os::preinit(&mut allocator, boot_info.rsdp_addr);
+    let symbols = boot_info.get::<Symbols>().copied().unwrap_or(Symbols { addr: 0, size: 0 });
+    let kernel_slide = boot_info.get::<KernelSlide>().map_or(0, |slide| slide.0);
+    if let Err(e) = ferr_os::symbols::init(symbols.addr, symbols.size, kernel_slide) {
+        log::warn!("Failed to load symbols: {:?}", e);
+    }
+    let initrd = boot_info.get::<Initrd>().copied().unwrap_or(Initrd { addr: 0, size: 0 });
+    ferr_os::initrd::init(initrd.addr, initrd.size);
+    ferr_os::efi::init(boot_info.get::<EfiRuntimeServices>().map_or(0, |services| services.0));
+    ferr_os::smp::set_trampoline(boot_info.get::<ApTrampoline>().map(|trampoline| trampoline.0));
+
+    ferr_os::preinit(&mut allocator, boot_info.get::<Rsdp>().map_or(0, |rsdp| rsdp.0));
 
     log::info!("Preinit done");
 
+    ferr_os::allocator::enable_heap_growth(allocator, ferr_os::allocator::HEAP_MAX_SIZE);
+
+    // the executor keeps running on the boot stack, as the `main` thread
+    if let Err(e) = ferr_os::thread::init() {
+        log::warn!("Kernel threads unavailable: {}", e);
+    }
+
     let mut executor: Executor = Executor::new();
 
-    executor.spawn(Task::new(timer_loop()));
+    executor.spawn(Task::new(console::console_flush_loop()));
+
+    executor.spawn(Task::new(ferr_os::block::cache::flusher()));
+
+    executor.spawn(Task::new(ferr_os::sysrq::sysrq_loop()));
+
+    executor.spawn(Task::with_priority(ferr_os::thermal::thermal_monitor_loop(), Priority::Idle));
+
+    #[cfg(feature = "heap_redzones")]
+    executor.spawn(Task::with_priority(ferr_os::allocator::redzone_check_loop(1000), Priority::Idle));
 
     let shell = Shell::new(fb_info);
-    executor.spawn(Task::new(keyboard::print_keypresses(shell)));
+    executor.spawn(Task::with_priority(keyboard::print_keypresses(shell), Priority::High));
 
     executor.spawn(Task::new(print_every_sec_task()));
 
     executor.spawn(Task::new(init_task()));
 
-    executor.spawn(Task::new(ferr_os::init()));
+    executor.spawn(Task::new(ferr_os::init(ferr_os::cmdline::boot_slot())));
+
+    executor.spawn(Task::with_priority(ferr_os::pci_hotplug(), Priority::Idle));
 
     executor.run();
 
     // TODO: ACPI shutdown
     log::info!("exited");
 
-    let mut shutdown_port = Port::new(0xB004);
-    unsafe { shutdown_port.write_u16(0x2000); };
+    // returns if the firmware can't do it, QEMU's port below can
+    ferr_os::efi::reset(ferr_os::efi::ResetType::Shutdown);
+
+    let mut shutdown_port = PortWriteOnly::<u16>::new(0xB004);
+    unsafe { shutdown_port.write(0x2000); };
 
     loop {
         unsafe {
@@ -107,8 +139,9 @@ fn kernel_main(boot_info: &'static shared_lib::BootInfo) -> ! {
 }
 
 pub async fn print_every_sec_task() {
+    let mut every_sec = interval(1000);
     loop {
-        sleep_for(1000).await;
+        every_sec.tick().await;
 
         static COUNTER: AtomicU64 = AtomicU64::new(1);
         log::info!("1 sec timer tick. {}. DateTime: {:?}", COUNTER.fetch_add(1, Ordering::Relaxed), read_rtc());
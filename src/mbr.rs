@@ -0,0 +1,162 @@
+// MBR partition tables.
+//
+// LBA 0 holds four primary entries. One of them may be an extended partition: a chain of
+// extended boot records (EBRs), each describing one logical partition relative to the EBR itself
+// and linking to the next EBR relative to the start of the extended partition. Logical
+// partitions are numbered from 5, like Linux does.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use shared_lib::bytes::read_u32_le;
+use crate::block::{cache, Disk};
+use crate::block::partition::{Partition, PartitionType};
+use crate::ide::{AtaError, SECTOR_SIZE};
+
+pub const PARTITION_TABLE_OFFSET: usize = 446;
+const BOOT_SIGNATURE_OFFSET: usize = 510;
+const PRIMARY_ENTRIES: usize = 4;
+const FIRST_LOGICAL_INDEX: usize = 5;
+/// Longest EBR chain followed
+const MAX_LOGICAL_PARTITIONS: usize = 64;
+
+pub const TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+pub struct MbrPartitionEntry {
+    pub bootable: u8,
+    pub starting_chs: [u8; 3],
+    pub partition_type: u8,
+    pub ending_chs: [u8; 3],
+    pub starting_lba: u32,
+    pub sectors: u32,
+}
+
+impl MbrPartitionEntry {
+    pub const SIZE: usize = 16;
+
+    pub fn parse(bytes: &[u8]) -> Self {
+        MbrPartitionEntry {
+            bootable: bytes[0],
+            starting_chs: [bytes[1], bytes[2], bytes[3]],
+            partition_type: bytes[4],
+            ending_chs: [bytes[5], bytes[6], bytes[7]],
+            starting_lba: read_u32_le(bytes, 8),
+            sectors: read_u32_le(bytes, 12),
+        }
+    }
+
+    pub fn is_used(&self) -> bool {
+        self.partition_type != 0 && self.sectors != 0
+    }
+
+    pub fn is_extended(&self) -> bool {
+        matches!(self.partition_type, 0x05 | 0x0f | 0x85)
+    }
+}
+
+#[derive(Debug)]
+pub enum MbrError {
+    InvalidSignature,
+    InvalidExtendedPartition,
+    Read(AtaError),
+}
+
+pub fn has_boot_signature(sector: &[u8]) -> bool {
+    sector.len() >= SECTOR_SIZE && sector[BOOT_SIGNATURE_OFFSET..BOOT_SIGNATURE_OFFSET + 2] == [0x55, 0xaa]
+}
+
+/// The four entries of an MBR or EBR
+pub fn parse_entries(sector: &[u8]) -> Result<[MbrPartitionEntry; PRIMARY_ENTRIES], MbrError> {
+    if !has_boot_signature(sector) {
+        return Err(MbrError::InvalidSignature);
+    }
+    Ok(core::array::from_fn(|i| {
+        let offset = PARTITION_TABLE_OFFSET + i * MbrPartitionEntry::SIZE;
+        MbrPartitionEntry::parse(&sector[offset..offset + MbrPartitionEntry::SIZE])
+    }))
+}
+
+/// Whether LBA 0 is the protective MBR of a GPT disk
+pub fn is_protective(sector: &[u8]) -> bool {
+    parse_entries(sector).is_ok_and(|entries| entries.iter().any(|entry| entry.partition_type == TYPE_GPT_PROTECTIVE))
+}
+
+pub fn type_name(partition_type: u8) -> &'static str {
+    match partition_type {
+        0x01 => "FAT12",
+        0x04 | 0x06 | 0x0e => "FAT16",
+        0x05 | 0x0f | 0x85 => "extended",
+        0x07 => "NTFS/exFAT",
+        0x0b | 0x0c => "FAT32",
+        0x82 => "Linux swap",
+        0x83 => "Linux",
+        0x96 => "ISO9660",
+        TYPE_GPT_PROTECTIVE => "GPT protective",
+        0xef => "EFI system",
+        _ => "unknown",
+    }
+}
+
+/// Logs the primary and logical partitions of `disk` and returns them
+pub async fn parse_mbr(disk: &Arc<Disk>) -> Result<Vec<Partition>, MbrError> {
+    log::info!("[mbr] Parsing MBR for {}kb block device {}", (disk.size() * 512) / 1024, disk.name());
+
+    let mut sector = [0u8; SECTOR_SIZE];
+    cache::read(disk, 0, &mut sector).await.map_err(MbrError::Read)?;
+
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for (i, entry) in parse_entries(&sector)?.iter().enumerate().filter(|(_, entry)| entry.is_used()) {
+        log_entry(i + 1, entry.starting_lba as u64, entry);
+        if !entry.is_extended() {
+            add_partition(&mut partitions, disk, i + 1, entry.starting_lba as u64, entry);
+        } else if extended.is_none() {
+            extended = Some(entry.starting_lba as u64);
+        } else {
+            log::warn!("[mbr] entry {} is a second extended partition, skipped", i + 1);
+        }
+    }
+
+    if let Some(start) = extended {
+        parse_logical_partitions(disk, start, &mut partitions).await?;
+    }
+    Ok(partitions)
+}
+
+/// Follows the EBR chain of the extended partition at `extended_start`
+async fn parse_logical_partitions(disk: &Arc<Disk>, extended_start: u64, partitions: &mut Vec<Partition>) -> Result<(), MbrError> {
+    let mut sector = [0u8; SECTOR_SIZE];
+    let mut ebr = extended_start;
+    for index in FIRST_LOGICAL_INDEX..FIRST_LOGICAL_INDEX + MAX_LOGICAL_PARTITIONS {
+        cache::read(disk, ebr, &mut sector).await.map_err(MbrError::Read)?;
+        let [logical, next, ..] = parse_entries(&sector).map_err(|_| MbrError::InvalidExtendedPartition)?;
+        if logical.is_used() {
+            log_entry(index, ebr + logical.starting_lba as u64, &logical);
+            add_partition(partitions, disk, index, ebr + logical.starting_lba as u64, &logical);
+        }
+
+        if !next.is_used() {
+            return Ok(());
+        }
+        // EBRs only ever point forward, anything else is a loop
+        let next_ebr = extended_start + next.starting_lba as u64;
+        if next_ebr <= ebr {
+            return Err(MbrError::InvalidExtendedPartition);
+        }
+        ebr = next_ebr;
+    }
+
+    log::warn!("[mbr] more than {} logical partitions, the rest is skipped", MAX_LOGICAL_PARTITIONS);
+    Ok(())
+}
+
+fn log_entry(index: usize, start: u64, entry: &MbrPartitionEntry) {
+    log::info!("[mbr] partition {} - type: {:#04x} ({}) [{}+{}]{}", index, entry.partition_type, type_name(entry.partition_type),
+        start, entry.sectors, if entry.bootable == 0x80 { " bootable" } else { "" });
+}
+
+fn add_partition(partitions: &mut Vec<Partition>, disk: &Arc<Disk>, index: usize, start: u64, entry: &MbrPartitionEntry) {
+    match Partition::new(disk.clone(), index, start, entry.sectors as u64) {
+        Ok(partition) => partitions.push(partition.with_type(PartitionType::Mbr(entry.partition_type))),
+        Err(e) => log::warn!("[mbr] partition {} doesn't fit on the disk: {:?}", index, e),
+    }
+}
@@ -0,0 +1,68 @@
+// Memory usage of the kernel: the heap counters kept by `shared_lib::allocator::stats`, how much
+// of the heap is backed by frames and how many freed frames wait for reuse. `meminfo` in the
+// shell prints it and switches poison-on-free.
+
+use core::fmt;
+use shared_lib::allocator::stats::{self, HeapStats, SIZE_CLASSES};
+use shared_lib::frame_allocator::Zone;
+use crate::allocator;
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemInfo {
+    pub heap: HeapStats,
+    /// Bytes of the heap backed by frames
+    pub heap_mapped: usize,
+    pub heap_limit: usize,
+    /// Frames on the free list of the frame allocator. None before `enable_heap_growth`, or if
+    /// the frame allocator is busy.
+    pub free_frames: Option<usize>,
+    /// Free frames of the memory map in each zone of `Zone::ALL`, same availability as above
+    pub zone_pages: Option<[usize; 3]>,
+}
+
+pub fn get() -> MemInfo {
+    let frame_allocator = allocator::FRAME_ALLOCATOR.try_lock();
+    let frame_allocator = frame_allocator.as_ref().and_then(|frame_allocator| frame_allocator.as_ref());
+
+    MemInfo {
+        heap: stats::snapshot(),
+        heap_mapped: allocator::heap_mapped_size(),
+        heap_limit: allocator::heap_max_size(),
+        free_frames: frame_allocator.map(|frame_allocator| frame_allocator.free_frames()),
+        zone_pages: frame_allocator.map(|frame_allocator| Zone::ALL.map(|zone| frame_allocator.zone_pages(zone))),
+    }
+}
+
+/// Fills freed heap memory with `POISON_PATTERN` from now on
+pub fn set_poison(enabled: bool) {
+    stats::set_poison(enabled);
+}
+
+/// Output of the `meminfo` shell command
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    let info = get();
+    let heap = &info.heap;
+
+    writeln!(out, "heap: {} KiB in use, peak {} KiB, {} KiB mapped, limit {} KiB",
+        heap.in_use_bytes() / 1024, heap.peak_bytes / 1024, info.heap_mapped / 1024, info.heap_limit / 1024)?;
+    writeln!(out, "  {} allocations ({} KiB), {} frees ({} KiB), {} failed",
+        heap.allocations, heap.allocated_bytes / 1024, heap.frees, heap.freed_bytes / 1024, heap.failed_allocations)?;
+    for class in 0..SIZE_CLASSES {
+        match stats::size_class_limit(class) {
+            Some(limit) => writeln!(out, "  <= {} B: {} live", limit, heap.live_blocks[class])?,
+            None => writeln!(out, "  larger: {} live", heap.live_blocks[class])?,
+        }
+    }
+    writeln!(out, "poison on free: {}", if stats::poison_enabled() { "on" } else { "off" })?;
+
+    match info.free_frames {
+        Some(frames) => writeln!(out, "frames: {} freed, waiting for reuse ({} KiB)", frames, frames * 4)?,
+        None => writeln!(out, "frames: unknown")?,
+    }
+    if let Some(zone_pages) = info.zone_pages {
+        for (zone, pages) in Zone::ALL.iter().zip(zone_pages) {
+            writeln!(out, "  zone {}: {} KiB in the memory map", zone.name(), pages * 4)?;
+        }
+    }
+    Ok(())
+}
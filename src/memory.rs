@@ -1,7 +1,17 @@
+use alloc::format;
+use alloc::vec::Vec;
 use core::arch::asm;
-use shared_lib::addr::VirtAddr;
-use shared_lib::page_table::PageTable;
-use shared_lib::VIRT_MAPPING_OFFSET;
+use core::fmt;
+use core::ops::Range;
+use shared_lib::addr::{PhysAddr, VirtAddr};
+use shared_lib::page_table::{align_down_u64, map_address_with_offset, unmap_range_with_offset, PageTable, PageTableFlags, HUGE_PAGE_1GB_SIZE, HUGE_PAGE_2MB_SIZE, PAGE_SIZE};
+use shared_lib::frame_allocator::{FrameAllocator, MemoryMap, MemoryRegion};
+use shared_lib::phys_mapping_offset;
+use shared_lib::spinlock::Spinlock;
+use shared_lib::volatile::{Mmio, RegisterBlock};
+use crate::allocator::FRAME_ALLOCATOR;
+use crate::sysinfo::{self, Category, Node};
+use crate::vm;
 
 pub unsafe fn active_level_4_table() -> &'static mut PageTable
 {
@@ -13,7 +23,7 @@ pub unsafe fn active_level_4_table() -> &'static mut PageTable
 
     let level_4_table_frame = value & 0x_000f_ffff_ffff_f000;
 
-    let virt = VIRT_MAPPING_OFFSET + level_4_table_frame;
+    let virt = phys_mapping_offset() + level_4_table_frame;
     let page_table_ptr = virt as *mut PageTable;
 
     &mut *page_table_ptr // unsafe
@@ -34,18 +44,243 @@ fn translate_addr_inner(addr: VirtAddr) -> Option<u64> {
     }
     let mut frame = value & 0x_000f_ffff_ffff_f000;
 
-    for &index in &table_indexes {
-        let virt = frame + VIRT_MAPPING_OFFSET;
+    // sizes of the pages mapped by P3 and P2 entries with the huge page flag
+    let huge_page_sizes = [0, HUGE_PAGE_1GB_SIZE, HUGE_PAGE_2MB_SIZE, 0];
+
+    for (level, &index) in table_indexes.iter().enumerate() {
+        let virt = frame + phys_mapping_offset();
         let table_ptr= virt as *const PageTable;
         let table = unsafe { &*table_ptr };
 
         let entry = table[index];
-        if entry.is_present() {
-            frame = entry.addr();
-        } else {
+        if !entry.is_present() {
             return None;
         }
+
+        frame = entry.addr();
+        let huge_page_size = huge_page_sizes[level];
+        if huge_page_size != 0 && entry.is_huge() {
+            return Some((frame & !(huge_page_size - 1)) + (addr.0 & (huge_page_size - 1)));
+        }
     }
 
     Some(frame + u64::from(addr.get_page_offset()))
 }
+
+/// A run of pages mapped to contiguous physical memory with the same permissions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub virt: u64,
+    pub phys: u64,
+    pub size: u64,
+    /// Leaf flags with the permissions of the upper levels applied, without the bits the CPU
+    /// sets on access
+    pub flags: PageTableFlags,
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |flag: PageTableFlags, name: &'static str| if self.flags.contains(flag) { name } else { "-" };
+        write!(f, "{:#018x}-{:#018x} -> {:#014x} {:>9} KiB r{}{} {}{}{}{}{}",
+            self.virt, self.virt + (self.size - 1), self.phys, self.size / 1024,
+            flag(PageTableFlags::WRITABLE, "w"),
+            if self.flags.contains(PageTableFlags::NO_EXECUTE) { "-" } else { "x" },
+            flag(PageTableFlags::USER_ACCESSIBLE, "u"),
+            flag(PageTableFlags::GLOBAL, "g"),
+            flag(PageTableFlags::NO_CACHE, "c"),
+            flag(PageTableFlags::WRITE_THROUGH, "t"),
+            if self.flags.contains(PageTableFlags::COPY_ON_WRITE) { " cow" } else { "" })
+    }
+}
+
+/// Present mappings of the active page tables overlapping `range`, adjacent pages merged
+pub fn mappings(range: Range<u64>) -> Vec<Mapping> {
+    let mut mappings: Vec<Mapping> = Vec::new();
+    let l4_table = unsafe { active_level_4_table() };
+
+    walk_table(l4_table, 4, 0, PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE, &range, &mut |mapping| {
+        match mappings.last_mut() {
+            Some(last) if last.virt + last.size == mapping.virt && last.phys + last.size == mapping.phys && last.flags == mapping.flags => {
+                last.size += mapping.size;
+            }
+            _ => mappings.push(mapping),
+        }
+    });
+    mappings
+}
+
+/// Calls `found` for each present leaf entry below `table`, which maps from `base` (before the
+/// sign extension). `inherited` are the permissions allowed by the upper levels.
+fn walk_table(table: &PageTable, level: u32, base: u64, inherited: PageTableFlags, range: &Range<u64>, found: &mut impl FnMut(Mapping)) {
+    let entry_size = PAGE_SIZE << (9 * (level - 1));
+    let permissions = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+    for index in 0..512u16 {
+        let raw = base + index as u64 * entry_size;
+        let virt = VirtAddr::new(raw).0;
+        if virt.saturating_add(entry_size) <= range.start || virt >= range.end {
+            continue;
+        }
+
+        let entry = table[index];
+        if !entry.is_present() {
+            continue;
+        }
+
+        // writable and user accessible only if every level allows it, not executable if any level forbids it
+        let flags = entry.flags();
+        let allowed = (inherited & flags & permissions) | ((inherited | flags) & PageTableFlags::NO_EXECUTE);
+        if level == 1 || entry.is_huge() {
+            let leaf = flags - permissions - PageTableFlags::NO_EXECUTE - PageTableFlags::ACCESSED - PageTableFlags::DIRTY - PageTableFlags::HUGE_PAGE;
+            found(Mapping { virt, phys: entry.addr(), size: entry_size, flags: leaf | allowed });
+        } else {
+            let next = unsafe { &*((entry.addr() + phys_mapping_offset()) as *const PageTable) };
+            walk_table(next, level - 1, raw, allowed, range, found);
+        }
+    }
+}
+
+/// Output of the `mappings` shell command
+pub fn dump_mappings(out: &mut impl fmt::Write, range: Range<u64>) -> fmt::Result {
+    for mapping in mappings(range) {
+        writeln!(out, "{}", mapping)?;
+    }
+    Ok(())
+}
+
+const MMIO_FLAGS: PageTableFlags = PageTableFlags::WRITABLE.union(PageTableFlags::NO_EXECUTE).union(PageTableFlags::NO_CACHE);
+
+/// A device memory range handed out by `map_mmio`
+#[derive(Debug, Clone, Copy)]
+struct MmioReservation {
+    phys: u64,
+    len: u64,
+    /// Start of the VMA the range is mapped in, the first page of the range
+    window: VirtAddr,
+}
+
+impl MmioReservation {
+    fn overlaps(&self, phys: u64, len: u64) -> bool {
+        self.phys < phys + len && phys < self.phys + self.len
+    }
+
+    fn pages(&self) -> u64 {
+        (self.phys + self.len - align_down_u64(self.phys)).div_ceil(PAGE_SIZE)
+    }
+
+    fn addr(&self) -> VirtAddr {
+        VirtAddr::new(self.window.0 + self.phys % PAGE_SIZE)
+    }
+}
+
+static MMIO_RESERVATIONS: Spinlock<Vec<MmioReservation>> = Spinlock::new(Vec::new());
+
+/// Maps `len` bytes of device memory at `phys` uncached into a VMA of its own and returns
+/// their virtual address. Only available after `enable_heap_growth`, see `map_mmio_with` before.
+///
+/// The range is reserved until `unmap_mmio`: a range overlapping another reservation is an
+/// error, two drivers driving the same registers is a bug. Mapping exactly the same range again
+/// returns the same window, e.g. when a device is probed again.
+pub fn map_mmio(phys: PhysAddr, len: usize) -> Result<VirtAddr, &'static str> {
+    map_mmio_impl(None, phys, len)
+}
+
+/// Same as `map_mmio`, with the frame allocator of the boot before `enable_heap_growth`
+pub fn map_mmio_with(frame_allocator: &mut FrameAllocator, phys: PhysAddr, len: usize) -> Result<VirtAddr, &'static str> {
+    map_mmio_impl(Some(frame_allocator), phys, len)
+}
+
+/// `map_mmio` for the register block `B`, the reservation covers `B::SIZE` bytes
+pub fn map_registers<B: RegisterBlock>(phys: PhysAddr) -> Result<Mmio<B>, &'static str> {
+    let base = map_mmio(phys, B::SIZE)?;
+    // SAFETY: the whole block was just mapped, it stays mapped until `unmap_mmio`
+    Ok(unsafe { Mmio::new(base) })
+}
+
+/// Unmaps the range reserved at `phys` and frees its VMA. Accessors of the range must not be
+/// used anymore.
+pub fn unmap_mmio(phys: PhysAddr) -> Result<(), &'static str> {
+    let mut reservations = MMIO_RESERVATIONS.lock();
+    let index = reservations.iter().position(|reservation| reservation.phys == phys.0).ok_or("no MMIO reservation at this address")?;
+    let reservation = reservations.swap_remove(index);
+
+    unsafe {
+        unmap_range_with_offset(active_level_4_table(), reservation.window, reservation.pages() as usize, phys_mapping_offset(), |_, _| {})?;
+    }
+    vm::free_region(reservation.window).map_err(|_| "the MMIO window has no VMA")?;
+    Ok(())
+}
+
+fn map_mmio_impl(boot_allocator: Option<&mut FrameAllocator>, phys: PhysAddr, len: usize) -> Result<VirtAddr, &'static str> {
+    let len = (len as u64).max(1);
+    let mut reservations = MMIO_RESERVATIONS.lock();
+
+    if let Some(existing) = reservations.iter().find(|existing| existing.overlaps(phys.0, len)) {
+        if existing.phys == phys.0 && existing.len == len {
+            return Ok(existing.addr());
+        }
+        log::warn!("[mmio] {:#x}+{:#x} overlaps the reservation {:#x}+{:#x}", phys.0, len, existing.phys, existing.len);
+        return Err("the MMIO range overlaps another reservation");
+    }
+
+    // growing the heap needs the frame allocator, which is held while mapping
+    reservations.reserve(1);
+    let mut reservation = MmioReservation { phys: phys.0, len, window: VirtAddr::zero() };
+    reservation.window = vm::allocate_region((reservation.pages() * PAGE_SIZE) as usize, MMIO_FLAGS, "mmio")
+        .map_err(|_| "no virtual address space left for the MMIO window")?;
+
+    let mapped = match boot_allocator {
+        Some(frame_allocator) => map_window(frame_allocator, &reservation),
+        None => match FRAME_ALLOCATOR.lock().as_mut() {
+            Some(frame_allocator) => map_window(frame_allocator, &reservation),
+            None => Err("frame allocator is not available"),
+        },
+    };
+    if let Err(e) = mapped {
+        unsafe {
+            let _ = unmap_range_with_offset(active_level_4_table(), reservation.window, reservation.pages() as usize, phys_mapping_offset(), |_, _| {});
+        }
+        let _ = vm::free_region(reservation.window);
+        return Err(e);
+    }
+
+    reservations.push(reservation);
+    Ok(reservation.addr())
+}
+
+fn map_window(frame_allocator: &mut FrameAllocator, reservation: &MmioReservation) -> Result<(), &'static str> {
+    let first_page = align_down_u64(reservation.phys);
+    for i in 0..reservation.pages() {
+        unsafe {
+            map_address_with_offset(active_level_4_table(), VirtAddr::new(reservation.window.0 + i * PAGE_SIZE), first_page + i * PAGE_SIZE,
+                MMIO_FLAGS, frame_allocator, phys_mapping_offset())?;
+        }
+    }
+    Ok(())
+}
+
+/// Adds the regions of the memory map to the system information, adjacent regions of the same
+/// type merged, and reserves the VMA of the physical memory window the loader mapped over them
+pub fn register_memory_map(memory_map: &MemoryMap) {
+    let mut regions: Vec<MemoryRegion> = Vec::new();
+    for region in memory_map.iter() {
+        match regions.last_mut() {
+            Some(last) if last.ty == region.ty && last.addr + last.page_count as u64 * PAGE_SIZE == region.addr => {
+                last.page_count += region.page_count;
+            }
+            _ => regions.push(*region),
+        }
+    }
+
+    for (index, region) in regions.iter().enumerate() {
+        sysinfo::set(Category::Memory, Node::new(format!("region{}", index))
+            .with("type", format!("{:?}", region.ty))
+            .with("start", format!("{:#x}", region.addr))
+            .with("size_kib", region.page_count as u64 * PAGE_SIZE / 1024));
+    }
+
+    let window = regions.iter().map(|region| region.addr + region.page_count as u64 * PAGE_SIZE).max().unwrap_or(0);
+    if let Err(e) = vm::reserve(VirtAddr::new(phys_mapping_offset()), window.next_multiple_of(HUGE_PAGE_2MB_SIZE), PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE, "phys window") {
+        log::warn!("Failed to reserve the physical memory window: {:?}", e);
+    }
+}
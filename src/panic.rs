@@ -0,0 +1,215 @@
+// Kernel panic handling.
+//
+// After the diagnostics are printed, `config::panic_policy` decides what happens next: halt,
+// reset the machine after a delay, or enter a debug shell on COM1. Everything here runs with
+// interrupts disabled and must not depend on the executor, timers or the heap, since the panic
+// may come from any of them.
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use shared_lib::addr::VirtAddr;
+use shared_lib::serial::SERIAL1;
+use shared_lib::{logger, serial_logger, serial_print, serial_println};
+use crate::config::{self, PanicPolicy};
+use crate::memory::translate_addr;
+use crate::port::{Port, PortWriteOnly};
+use crate::{symbols, watchdog};
+
+const MAX_COMMAND_LEN: usize = 80;
+const MAX_DUMP_LEN: u64 = 4096;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+pub fn handle_panic(info: &PanicInfo) -> ! {
+    unsafe { asm!("cli", options(nomem, nostack)); }
+    watchdog::disarm();
+
+    unsafe {
+        logger::LOGGER
+            .get()
+            .map(|l| l.force_unlock());
+
+        serial_logger::SERIAL_LOGGER
+            .get()
+            .map(|l| l.force_unlock());
+
+        SERIAL1.force_unlock();
+    };
+
+    // draw pending and further output right away
+    logger::LOGGER
+        .get()
+        .map(|l| l.set_batching(false));
+
+    log::error!("{}", info);
+    log_registers();
+    symbols::print_backtrace();
+
+    // a panic inside the debug shell or the reboot path: don't loop
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        halt();
+    }
+
+    match config::panic_policy() {
+        PanicPolicy::Halt => halt(),
+        PanicPolicy::Reboot => {
+            let delay = config::panic_reboot_delay_secs();
+            log::error!("Rebooting in {} s", delay);
+            wait_seconds(delay);
+            reboot();
+        },
+        PanicPolicy::DebugShell => debug_shell(),
+    }
+}
+
+/// Stack and control registers at the panic, a fault has logged the full set already
+fn log_registers() {
+    let (rsp, rbp, cr2, cr3): (u64, u64, u64, u64);
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    }
+    log::error!("RSP {:#018x} RBP {:#018x} CR2 {:#018x} CR3 {:#018x}", rsp, rbp, cr2, cr3);
+}
+
+pub fn halt() -> ! {
+    loop {
+        unsafe {
+            asm!("hlt", options(nomem, nostack, preserves_flags));
+        }
+    }
+}
+
+pub fn reboot() -> ! {
+    unsafe {
+        // PCI reset control register
+        PortWriteOnly::<u8>::new(0xCF9).write(0x06);
+
+        // 8042 keyboard controller pulses the reset line
+        let mut kbc_status = Port::<u8>::new(0x64);
+        while kbc_status.read() & 0x02 != 0 {}
+        kbc_status.write(0xFE);
+
+        // triple fault: no IDT to handle the breakpoint
+        let null_idt = [0u64; 2];
+        asm!("lidt [{}]", "int3", in(reg) null_idt.as_ptr(), options(nostack));
+    }
+    halt();
+}
+
+/// Busy-waits using the RTC seconds counter, which works with interrupts disabled.
+fn wait_seconds(seconds: u64) {
+    let rtc_second = || unsafe {
+        PortWriteOnly::<u8>::new(0x70).write(0x00);
+        Port::<u8>::new(0x71).read()
+    };
+
+    let mut last = rtc_second();
+    let mut elapsed = 0;
+    while elapsed < seconds {
+        let now = rtc_second();
+        if now != last {
+            last = now;
+            elapsed += 1;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn receive() -> u8 {
+    loop {
+        if let Some(byte) = SERIAL1.lock().try_receive() {
+            return byte;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn read_line(buffer: &mut [u8; MAX_COMMAND_LEN]) -> &str {
+    let mut len = 0;
+    loop {
+        match receive() {
+            b'\r' | b'\n' => {
+                serial_println!();
+                break;
+            },
+            // backspace or DEL
+            0x08 | 0x7F => if len > 0 {
+                len -= 1;
+                serial_print!("\x08 \x08");
+            },
+            byte if byte.is_ascii() && !byte.is_ascii_control() && len < MAX_COMMAND_LEN => {
+                buffer[len] = byte;
+                len += 1;
+                serial_print!("{}", byte as char);
+            },
+            _ => {}
+        }
+    }
+    core::str::from_utf8(&buffer[..len]).unwrap_or("")
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+/// Hex dump of `len` bytes at `addr`, stopping at the first unmapped page.
+fn dump_memory(addr: u64, len: u64) {
+    let len = len.min(MAX_DUMP_LEN);
+    let mut line = addr & !0xF;
+    while line < addr + len {
+        if line % 4096 == 0 || line == addr & !0xF {
+            let mapped = VirtAddr::new_checked(line).ok()
+                .and_then(|page| unsafe { translate_addr(page) })
+                .is_some();
+            if !mapped {
+                serial_println!("{:#018x}: not mapped", line);
+                return;
+            }
+        }
+
+        serial_print!("{:#018x}:", line);
+        for i in 0..16 {
+            let byte = unsafe { core::ptr::read_volatile((line + i) as *const u8) };
+            serial_print!(" {:02x}", byte);
+        }
+        serial_println!();
+        line += 16;
+    }
+}
+
+fn debug_shell() -> ! {
+    serial_println!("[panic] debug shell, type 'help' for commands");
+
+    let mut buffer = [0u8; MAX_COMMAND_LEN];
+    loop {
+        serial_print!("panic> ");
+        let mut args = read_line(&mut buffer).split_whitespace();
+
+        match args.next() {
+            Some("help") => {
+                serial_println!("- bt");
+                serial_println!("- halt");
+                serial_println!("- read <hex addr> [hex len]");
+                serial_println!("- reboot");
+                serial_println!("- sym <hex addr>");
+            },
+            Some("bt") => symbols::print_backtrace(),
+            Some("read") => match args.next().and_then(parse_hex) {
+                Some(addr) => dump_memory(addr, args.next().and_then(parse_hex).unwrap_or(0x40)),
+                None => serial_println!("usage: read <hex addr> [hex len]"),
+            },
+            Some("sym") => match args.next().and_then(parse_hex) {
+                Some(addr) => serial_println!("{}", symbols::Resolved(addr)),
+                None => serial_println!("usage: sym <hex addr>"),
+            },
+            Some("reboot") => reboot(),
+            Some("halt") => halt(),
+            Some(_) => serial_println!("unknown command"),
+            None => {}
+        }
+    }
+}
@@ -1,23 +1,288 @@
 use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::collections::BTreeMap;
 use alloc::vec;
 use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+use futures_util::task::AtomicWaker;
+use shared_lib::addr::{PhysAddr, VirtAddr};
+use shared_lib::frame_allocator::FrameAllocator;
+use shared_lib::spinlock::Spinlock;
+use crate::block;
+use crate::driver::DriverError;
 use crate::ide::BlockDevice;
-use crate::pci::PciDevice::Drive;
-use crate::port::Port;
-
-unsafe fn pci_config_read_word(bus: u8, device: u8, func: u8, offset: u8) -> u16 {
-    let address: u32 =
-        (bus as u32) << 16
-        | (device as u32) << 11
-        | (func as u32) << 8
+use crate::memory::map_mmio_with;
+use crate::port::{Port, PortWriteOnly};
+use crate::sysinfo::{self, Category, Node};
+use crate::xsdt::McfgEntry;
+
+/// ECAM window of the buses `start_bus..=end_bus` of a segment
+struct EcamWindow {
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    /// Where the configuration space of `start_bus` is mapped
+    base: VirtAddr,
+}
+
+/// Set from the MCFG at boot. Until then, and on machines without one, configuration space is
+/// reached through the ports.
+static ECAM: OnceCell<Vec<EcamWindow>> = OnceCell::uninit();
+
+/// Maps the configuration space windows the MCFG lists, they replace the ports from then on
+pub(crate) fn init_ecam(allocator: &mut FrameAllocator, entries: &[McfgEntry]) {
+    let windows = entries.iter()
+        .filter_map(|entry| {
+            if entry.end_bus < entry.start_bus {
+                log::warn!("[pci] Skipping the ECAM of segment {:04x}, its buses {:02x}-{:02x} are out of order", entry.segment, entry.start_bus, entry.end_bus);
+                return None;
+            }
+            let start = entry.base + ((entry.start_bus as u64) << 20);
+            let len = entry.end_bus as usize - entry.start_bus as usize + 1;
+            match map_mmio_with(allocator, PhysAddr(start), len << 20) {
+                Ok(base) => {
+                    log::info!("[pci] ECAM of segment {:04x}, buses {:02x}-{:02x} at {:#x}", entry.segment, entry.start_bus, entry.end_bus, start);
+                    Some(EcamWindow { segment: entry.segment, start_bus: entry.start_bus, end_bus: entry.end_bus, base })
+                },
+                Err(e) => {
+                    log::warn!("[pci] Failed to map the ECAM of segment {:04x}: {}", entry.segment, e);
+                    None
+                },
+            }
+        })
+        .collect();
+    ECAM.init_once(move || windows);
+}
+
+/// The dword at `offset` in the ECAM window of the function, None if no window covers it
+fn ecam_register(address: PciAddress, offset: u16) -> Option<*mut u32> {
+    if offset > 0xFFF {
+        return None;
+    }
+    let window = ECAM.get()?.iter()
+        .find(|window| window.segment == address.segment && (window.start_bus..=window.end_bus).contains(&address.bus))?;
+    let offset = ((address.bus - window.start_bus) as u64) << 20
+        | (address.device as u64) << 15
+        | (address.function as u64) << 12
+        | (offset & 0xFFC) as u64;
+    Some((window.base.0 + offset) as *mut u32)
+}
+
+/// What to write to 0xCF8 for the dword at `offset`, None if the ports can't reach it: they only
+/// know segment 0 and the first 256 bytes
+fn port_address(address: PciAddress, offset: u16) -> Option<u32> {
+    if address.segment != 0 || offset > 0xFF {
+        return None;
+    }
+    Some((address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
         | (offset as u32 & 0xFC)
-        | 0x80000000u32;
+        | 0x80000000u32)
+}
+
+/// Reads the dword at `offset` of the configuration space of the function. Registers that
+/// can't be reached read as all ones, like those of a missing function.
+pub(crate) unsafe fn pci_config_read_dword(address: PciAddress, offset: u16) -> u32 {
+    if let Some(register) = ecam_register(address, offset) {
+        return core::ptr::read_volatile(register);
+    }
+    let Some(port_address) = port_address(address, offset) else {
+        return 0xFFFF_FFFF;
+    };
+
+    let mut config_address_port = PortWriteOnly::<u32>::new(0xCF8);
+    config_address_port.write(port_address);
+
+    let mut config_data_port = Port::<u32>::new(0xCFC);
+    config_data_port.read()
+}
+
+unsafe fn pci_config_read_word(address: PciAddress, offset: u16) -> u16 {
+    ((pci_config_read_dword(address, offset) >> ((offset & 2) * 8)) & 0xFFFF) as u16
+}
+
+/// Writes the dword at `offset`, writes to registers that can't be reached are dropped
+pub(crate) unsafe fn pci_config_write_dword(address: PciAddress, offset: u16, value: u32) {
+    if let Some(register) = ecam_register(address, offset) {
+        core::ptr::write_volatile(register, value);
+        return;
+    }
+    let Some(port_address) = port_address(address, offset) else {
+        return;
+    };
+
+    let mut config_address_port = PortWriteOnly::<u32>::new(0xCF8);
+    config_address_port.write(port_address);
+
+    let mut config_data_port = Port::<u32>::new(0xCFC);
+    config_data_port.write(value);
+}
+
+/// Command register bits
+pub(crate) const PCI_COMMAND_IO_SPACE: u16 = 1 << 0;
+pub(crate) const PCI_COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub(crate) const PCI_COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+/// Sets `bits` in the command register, e.g. to let the function decode its BARs and do DMA.
+pub(crate) fn enable_command_bits(address: PciAddress, bits: u16) {
+    unsafe {
+        // the upper half is the status register, its bits are cleared by writing 1
+        let command = pci_config_read_word(address, 0x4);
+        pci_config_write_dword(address, 0x4, (command | bits) as u32);
+    }
+}
+
+/// What a BAR decodes, once sized
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    /// `base..base + size` of the physical address space
+    Memory { base: u64, size: u64, prefetchable: bool, is_64bit: bool },
+    /// Ports `base..base + size`
+    Io { base: u32, size: u32 },
+}
+
+impl Resource {
+    pub fn base(&self) -> u64 {
+        match *self {
+            Resource::Memory { base, .. } => base,
+            Resource::Io { base, .. } => base as u64,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match *self {
+            Resource::Memory { size, .. } => size,
+            Resource::Io { size, .. } => size as u64,
+        }
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Resource::Memory { base, size, prefetchable, is_64bit } => {
+                write!(f, "mem {:#x}+{:#x}", base, size)?;
+                if is_64bit {
+                    f.write_str(" 64-bit")?;
+                }
+                if prefetchable {
+                    f.write_str(" prefetchable")?;
+                }
+                Ok(())
+            },
+            Resource::Io { base, size } => write!(f, "io {:#x}+{:#x}", base, size),
+        }
+    }
+}
+
+/// Reads the BAR at `offset` and what it reads back once all ones are written to it, the bits
+/// the function decodes. The original value is restored.
+unsafe fn probe_bar(address: PciAddress, offset: u16) -> (u32, u32) {
+    let original = pci_config_read_dword(address, offset);
+    pci_config_write_dword(address, offset, 0xFFFF_FFFF);
+    let mask = pci_config_read_dword(address, offset);
+    pci_config_write_dword(address, offset, original);
+    (original, mask)
+}
+
+/// Decodes the BAR `index`, returns it and the number of BAR slots it takes: 2 for a 64-bit
+/// memory BAR, 1 otherwise. Decoding must be off in the command register.
+unsafe fn decode_bar(address: PciAddress, index: u8, count: u8) -> (Option<Resource>, u8) {
+    let offset = 0x10 + 4 * index as u16;
+    let (original, mask) = probe_bar(address, offset);
+
+    if original & 1 != 0 {
+        let mut size_mask = mask & !0x3;
+        if size_mask == 0 {
+            return (None, 1);
+        }
+        // only 16 bits of port address decoded
+        if size_mask & 0xFFFF_0000 == 0 {
+            size_mask |= 0xFFFF_0000;
+        }
+        return (Some(Resource::Io { base: original & !0x3, size: (!size_mask).wrapping_add(1) }), 1);
+    }
+
+    let is_64bit = (original >> 1) & 0x3 == 0x2 && index + 1 < count;
+    let prefetchable = original & (1 << 3) != 0;
+    let (mut base, mut size_mask) = ((original & !0xF) as u64, (mask & !0xF) as u64);
+    if is_64bit {
+        let (high, high_mask) = probe_bar(address, offset + 4);
+        base |= (high as u64) << 32;
+        size_mask |= (high_mask as u64) << 32;
+    } else {
+        size_mask |= 0xFFFF_FFFF_0000_0000;
+    }
+
+    let slots = if is_64bit { 2 } else { 1 };
+    if size_mask == 0xFFFF_FFFF_0000_0000 || size_mask == 0 {
+        return (None, slots);
+    }
+    (Some(Resource::Memory { base, size: (!size_mask).wrapping_add(1), prefetchable, is_64bit }), slots)
+}
+
+/// Decodes the `count` BARs of the function, with I/O and memory decoding turned off meanwhile
+/// so the all-ones probe doesn't claim addresses. The upper half of a 64-bit BAR is None.
+fn decode_bars(address: PciAddress, count: u8) -> [Option<Resource>; 6] {
+    let mut bars = [None; 6];
+    unsafe {
+        let command = pci_config_read_word(address, 0x4);
+        pci_config_write_dword(address, 0x4, (command & !(PCI_COMMAND_IO_SPACE | PCI_COMMAND_MEMORY_SPACE)) as u32);
+
+        let mut index = 0;
+        while index < count {
+            let (resource, slots) = decode_bar(address, index, count);
+            bars[index as usize] = resource;
+            index += slots;
+        }
 
-    let mut config_address_port = Port::new(0xCF8);
-    config_address_port.write_u32(address);
+        pci_config_write_dword(address, 0x4, command as u32);
+    }
+    bars
+}
+
+/// Offsets of the entries of the capability list as (capability id, offset) pairs.
+pub(crate) fn capabilities(address: PciAddress) -> Vec<(u8, u8)> {
+    let mut capabilities = Vec::new();
+    unsafe {
+        let status = pci_config_read_word(address, 0x6);
+        if status & (1 << 4) == 0 {
+            return capabilities;
+        }
+
+        let mut offset = (pci_config_read_dword(address, 0x34) & 0xFC) as u8;
+        // the list lives in the 192 bytes after the header, a longer walk means a loop
+        while offset != 0 && capabilities.len() < 48 {
+            let [id, next] = pci_config_read_word(address, offset as u16).to_le_bytes();
+            capabilities.push((id, offset));
+            offset = next & 0xFC;
+        }
+    }
+    capabilities
+}
 
-    let mut config_data_port = Port::new(0xCFC);
-    ((config_data_port.read_u32() >> ((offset & 2) * 8)) & 0xFFFF) as u16
+/// The extended capability list of a PCI Express function after the first 256 bytes, as
+/// (capability id, offset) pairs. Empty without ECAM, the ports can't reach it.
+pub(crate) fn extended_capabilities(address: PciAddress) -> Vec<(u16, u16)> {
+    let mut capabilities = Vec::new();
+    let mut offset = 0x100;
+    // 4 bytes at least per entry in the 3840 bytes
+    while offset >= 0x100 && capabilities.len() < 960 {
+        let header = unsafe { pci_config_read_dword(address, offset) };
+        if header == 0 || header == 0xFFFF_FFFF {
+            break;
+        }
+        capabilities.push((header as u16, offset));
+        offset = (header >> 20) as u16 & 0xFFC;
+    }
+    capabilities
 }
 
 fn get_device_type(class_code: u8, subclass: u8, prog_if: u8) -> &'static str {
@@ -61,91 +326,473 @@ fn get_device_type(class_code: u8, subclass: u8, prog_if: u8) -> &'static str {
     ""
 }
 
-#[allow(dead_code)]
-#[derive(Debug)]
-pub struct GenericPciDevice {
-    bus: u8,
-    device: u8,
-    function: u8,
+/// A function with its configuration space decoded, as handed to drivers
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    address: PciAddress,
+    id: PciFunctionId,
+    revision: u8,
+    bars: [Option<Resource>; 6],
+    interrupt_line: u8,
+    interrupt_pin: u8,
+    capabilities: Vec<(u8, u8)>,
+}
+
+impl PciDevice {
+    /// A function known only by its ids, without BARs, interrupt or capabilities
+    pub fn from_id(address: PciAddress, id: PciFunctionId) -> PciDevice {
+        PciDevice { address, id, revision: 0, bars: [None; 6], interrupt_line: 0, interrupt_pin: 0, capabilities: Vec::new() }
+    }
+
+    /// Reads the header of the function and sizes its BARs: general functions have six, PCI
+    /// bridges two, CardBus bridges none.
+    pub fn read(address: PciAddress, id: PciFunctionId) -> PciDevice {
+            let bar_count = match id.header_type & 0x7F {
+            0x0 => 6,
+            0x1 => 2,
+            _ => 0,
+        };
+        let (revision, [interrupt_line, interrupt_pin]) = unsafe {
+            let [_prog_if, revision] = pci_config_read_word(address, 0x8).to_be_bytes();
+            (revision, pci_config_read_word(address, 0x3C).to_le_bytes())
+        };
+
+        PciDevice {
+            address,
+            id,
+            revision,
+            bars: decode_bars(address, bar_count),
+            interrupt_line,
+            interrupt_pin,
+            capabilities: capabilities(address),
+        }
+    }
+
+    pub fn address(&self) -> PciAddress {
+        self.address
+    }
+
+    pub fn id(&self) -> &PciFunctionId {
+        &self.id
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    /// What BAR `index` decodes, None if it is unused, the upper half of a 64-bit BAR, or out of
+    /// range
+    pub fn bar(&self, index: usize) -> Option<Resource> {
+        self.bars.get(index).copied().flatten()
+    }
+
+    /// The legacy PIC line the firmware routed the function to, 0xFF if none
+    pub fn interrupt_line(&self) -> u8 {
+        self.interrupt_line
+    }
+
+    /// 1 to 4 for INTA# to INTD#, 0 if the function doesn't use one
+    pub fn interrupt_pin(&self) -> u8 {
+        self.interrupt_pin
+    }
+
+    /// (capability id, offset) pairs, in list order
+    pub fn capabilities(&self) -> &[(u8, u8)] {
+        &self.capabilities
+    }
+
+    /// Config space offset of the first capability with `id`
+    pub fn capability(&self, id: u8) -> Option<u8> {
+        self.capabilities.iter().find(|(capability, _)| *capability == id).map(|(_, offset)| *offset)
+    }
 
-    class_code: u8,
-    subclass: u8,
-    prog_if: u8,
-    vendor_id: u16
+    /// Sets `bits` in the command register, see `enable_command_bits`
+    pub fn enable(&self, bits: u16) {
+        enable_command_bits(self.address, bits);
+    }
 }
 
-pub enum PciDevice {
+/// What probing a function gave
+pub enum Probed {
     Drive(Box<dyn BlockDevice>),
-    Generic(GenericPciDevice)
+    /// No driver took the function
+    Generic(PciDevice)
 }
 
-async unsafe fn check_function(bus: u8, device: u8, func: u8, vendor_id: u16) -> Vec<PciDevice> {
-    let [_bist, header_type] = pci_config_read_word(bus, device, func, 0xE).to_be_bytes();
-    let [class_code, subclass] = pci_config_read_word(bus, device, func, 0xA).to_be_bytes();
-    let [prog_if, _revision_id] = pci_config_read_word(bus, device, func, 0x8).to_be_bytes();
+/// The devices a driver made of a function it took, e.g. the drives of a disk controller. A
+/// driver that sets up its device on its own, like the 9p share, returns none. An error hands
+/// the function on to the next matching driver.
+pub type ProbeResult = Result<Vec<Probed>, &'static str>;
 
-    let device_type_str = get_device_type(class_code, subclass, prog_if);
+type ProbeFuture = Pin<Box<dyn Future<Output = ProbeResult>>>;
+type ProbeFn = Box<dyn Fn(PciDevice) -> ProbeFuture + Send>;
 
-    let mut prefix = "";
-    if func != 0 {
-        prefix = "|--- ";
+/// Which functions a driver is offered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciMatch {
+    /// Class code, subclass and programming interface, any interface if None
+    Class(u8, u8, Option<u8>),
+    /// Vendor and device id
+    Id(u16, u16),
+}
+
+impl PciMatch {
+    pub fn matches(&self, id: &PciFunctionId) -> bool {
+        match *self {
+            PciMatch::Class(class_code, subclass, prog_if) => {
+                id.class_code == class_code && id.subclass == subclass && prog_if.map_or(true, |prog_if| id.prog_if == prog_if)
+            },
+            PciMatch::Id(vendor_id, device_id) => id.vendor_id == vendor_id && id.device_id == device_id,
+        }
     }
+}
 
-    if device_type_str == "" {
-        log::info!("[pci] {}device #{} - vendor: {:#x}, header_type: {:#x}, class: {:#x}, subclass: {:#x}, func: {}", prefix, device, vendor_id, header_type, class_code, subclass, func);
-    } else {
-        log::info!("[pci] {}device #{} - vendor: {:#x}, header_type: {:#x}, func: {}, device_type: {}", prefix, device, vendor_id, header_type, func, device_type_str);
+struct PciDriver {
+    name: &'static str,
+    matches: &'static [PciMatch],
+    probe: ProbeFn,
+    /// Called when a function bound to the driver is gone, after its disks were unregistered
+    remove: fn(PciAddress),
+}
+
+impl PciDriver {
+    fn takes(&self, id: &PciFunctionId) -> bool {
+        self.matches.iter().any(|pattern| pattern.matches(id))
+    }
+}
+
+static DRIVERS: Spinlock<Vec<PciDriver>> = Spinlock::new(Vec::new());
+/// Driver each bound function is bound to
+static BOUND: Spinlock<BTreeMap<PciAddress, &'static str>> = Spinlock::new(BTreeMap::new());
+
+/// Registers a driver offered the functions `matches` describes. Drivers registered first are
+/// offered a function first. Only functions found afterwards are probed, at boot register
+/// before `init_pci`. `remove` tears down what `probe` set up for a function which went away.
+pub fn register_driver<F>(name: &'static str, matches: &'static [PciMatch], probe: fn(PciDevice) -> F,
+                          remove: fn(PciAddress)) -> Result<(), DriverError>
+    where F: Future<Output = ProbeResult> + 'static
+{
+    let mut drivers = DRIVERS.lock();
+    if drivers.iter().any(|driver| driver.name == name) {
+        return Err(DriverError::AlreadyRegistered);
+    }
+
+    drivers.push(PciDriver { name, matches, probe: Box::new(move |device| Box::pin(probe(device)) as ProbeFuture), remove });
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    /// PCI segment group, only the MCFG reaches others than 0
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.segment != 0 {
+            write!(f, "{:04x}:", self.segment)?;
+        }
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// What identifies a function in a slot. A different id at the same address is a different
+/// device, e.g. after QEMU `device_del` + `device_add`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciFunctionId {
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class_code: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: u8,
+}
+
+/// Functions which have been probed, keyed by address
+static REGISTRY: Spinlock<BTreeMap<PciAddress, PciFunctionId>> = Spinlock::new(BTreeMap::new());
+
+static RESCAN_REQUESTED: AtomicBool = AtomicBool::new(false);
+static RESCAN_WAKER: AtomicWaker = AtomicWaker::new();
+
+unsafe fn read_function_id(address: PciAddress, vendor_id: u16) -> PciFunctionId {
+    let [_bist, header_type] = pci_config_read_word(address, 0xE).to_be_bytes();
+    let [class_code, subclass] = pci_config_read_word(address, 0xA).to_be_bytes();
+    let [prog_if, _revision_id] = pci_config_read_word(address, 0x8).to_be_bytes();
+
+    PciFunctionId {
+        vendor_id,
+        device_id: pci_config_read_word(address, 0x2),
+        class_code,
+        subclass,
+        prog_if,
+        header_type,
     }
+}
+
+unsafe fn check_function(present: &mut BTreeMap<PciAddress, PciFunctionId>, address: PciAddress, vendor_id: u16) {
+    let id = read_function_id(address, vendor_id);
+    present.insert(address, id);
 
-    if class_code == 0x1 && subclass == 0x1 {
-        let drives = crate::ide::ide_initialize(prog_if).await;
-        return drives.into_iter().map(|a|Drive(Box::new(a))).collect();
+    // PCI-to-PCI bridge: enumerate the bus behind it
+    if id.class_code == 0x6 && id.subclass == 0x4 {
+        let [secondary_bus, _primary_bus] = pci_config_read_word(address, 0x18).to_be_bytes();
+        if secondary_bus > address.bus {
+            check_bus(present, address.segment, secondary_bus);
+        }
     }
-    vec![PciDevice::Generic(GenericPciDevice{ bus, device, function: func, class_code, subclass, prog_if, vendor_id })]
 }
 
-async unsafe fn check_device(bus: u8, device: u8) -> Vec<PciDevice> {
-    let vendor_id = pci_config_read_word(bus, device, 0, 0);
+unsafe fn check_device(present: &mut BTreeMap<PciAddress, PciFunctionId>, segment: u16, bus: u8, device: u8) {
+    let address = PciAddress { segment, bus, device, function: 0 };
+    let vendor_id = pci_config_read_word(address, 0);
 
     // device doesn't exist
     if vendor_id == 0xFFFF {
-        return Vec::new();
+        return;
     }
-    let [_bist, header_type] = pci_config_read_word(bus, device, 0, 0xE).to_be_bytes();
+    check_function(present, address, vendor_id);
 
+    let [_bist, header_type] = pci_config_read_word(address, 0xE).to_be_bytes();
     if header_type & 0x80 != 0 {
         // it's a multifunction device!
-        let [class_code, subclass] = pci_config_read_word(bus, device, 0, 0xA).to_be_bytes();
-        log::info!("[pci] multifunction device #{} - vendor: {:#x}, header_type: {:#x}, device_type: {}", device, vendor_id, header_type, get_device_type(class_code, subclass, 0));
-
-        let mut vec = Vec::new();
-        for func in 1..8 {
-            let vendor_id = pci_config_read_word(bus, device, func, 0);
+        for function in 1..8 {
+            let address = PciAddress { function, ..address };
+            let vendor_id = pci_config_read_word(address, 0);
             if vendor_id != 0xFFFF {
-                vec.append(&mut check_function(bus, device, func, vendor_id).await);
+                check_function(present, address, vendor_id);
             }
         }
-        return vec;
     }
+}
 
-    check_function(bus, device, 0, vendor_id).await
+unsafe fn check_bus(present: &mut BTreeMap<PciAddress, PciFunctionId>, segment: u16, bus: u8) {
+    for device in 0..32 {
+        check_device(present, segment, bus, device);
+    }
 }
 
-pub async fn init_pci() -> Vec<PciDevice> {
-    let mut vec = Vec::new();
-    unsafe {
-        let [_bist, header_type] = pci_config_read_word(0, 0, 0, 0xE).to_be_bytes();
+/// Reads the configuration space of every function currently present: those of each segment
+/// the MCFG lists, or of segment 0 without one.
+fn enumerate() -> BTreeMap<PciAddress, PciFunctionId> {
+    let roots: Vec<(u16, u8)> = match ECAM.get() {
+        Some(windows) if !windows.is_empty() => windows.iter().map(|window| (window.segment, window.start_bus)).collect(),
+        _ => vec![(0, 0)],
+    };
 
-        if header_type & 0x80 == 0 {
-            // Single PCI host controller
-            // checking bus #0
-            for device in 0..32 {
-                vec.append(&mut check_device(0, device).await);
+    let mut present = BTreeMap::new();
+    for (segment, bus) in roots {
+        let host = PciAddress { segment, bus, device: 0, function: 0 };
+        unsafe {
+            if pci_config_read_word(host, 0) == 0xFFFF {
+                continue;
+            }
+            let [_bist, header_type] = pci_config_read_word(host, 0xE).to_be_bytes();
+
+            if header_type & 0x80 == 0 {
+                // Single PCI host controller
+                check_bus(&mut present, segment, bus);
+            } else {
+                // Multiple PCI host controllers, function n is the one for bus n
+                for function in 0..8 {
+                    let Some(bus) = bus.checked_add(function) else {
+                        break;
+                    };
+                    if pci_config_read_word(PciAddress { function, ..host }, 0) != 0xFFFF {
+                        check_bus(&mut present, segment, bus);
+                    }
+                }
             }
-        } else {
-            // Multiple PCI host controllers
-            unimplemented!();
         }
     }
-    vec
-}
\ No newline at end of file
+    present
+}
+
+/// Logs a newly found function.
+fn log_function(address: PciAddress, id: &PciFunctionId) {
+    let PciAddress { bus, device, function, .. } = address;
+    let device_type_str = get_device_type(id.class_code, id.subclass, id.prog_if);
+
+    let mut prefix = "";
+    if function != 0 {
+        prefix = "|--- ";
+    }
+
+    if device_type_str == "" {
+        log::info!("[pci] {}device {}:{} - vendor: {:#x}, device: {:#x}, header_type: {:#x}, class: {:#x}, subclass: {:#x}, func: {}", prefix, bus, device, id.vendor_id, id.device_id, id.header_type, id.class_code, id.subclass, function);
+    } else {
+        log::info!("[pci] {}device {}:{} - vendor: {:#x}, device: {:#x}, header_type: {:#x}, func: {}, device_type: {}", prefix, bus, device, id.vendor_id, id.device_id, id.header_type, function, device_type_str);
+    }
+}
+
+/// Offers the function to the drivers matching it in registration order, until one takes it.
+/// Returns that driver and what its probe made of the function.
+pub async fn bind(pci_device: PciDevice) -> (Option<&'static str>, Vec<Probed>) {
+    let address = pci_device.address();
+    let mut next = 0;
+    loop {
+        let (name, probe) = {
+            let drivers = DRIVERS.lock();
+            let Some((index, driver)) = drivers.iter().enumerate().skip(next).find(|(_, driver)| driver.takes(pci_device.id())) else {
+                break;
+            };
+            next = index + 1;
+            (driver.name, (driver.probe)(pci_device.clone()))
+        };
+
+        match probe.await {
+            Ok(probed) => {
+                log::info!("[pci] {} bound to {}", address, name);
+                BOUND.lock().insert(address, name);
+                return (Some(name), probed);
+            },
+            Err(e) => log::warn!("[pci] {} rejected {}: {}", name, address, e),
+        }
+    }
+    (None, vec![Probed::Generic(pci_device)])
+}
+
+/// Adds a function and the driver bound to it to the system information, network controllers
+/// to their own category too
+fn register_function(pci_device: &PciDevice, driver: Option<&'static str>) {
+    let (address, id) = (pci_device.address(), *pci_device.id());
+    let class = match get_device_type(id.class_code, id.subclass, id.prog_if) {
+        "" => format!("{:#04x}:{:#04x}", id.class_code, id.subclass),
+        name => String::from(name),
+    };
+    let virtio_type = crate::virtio::device_type(&id);
+
+    let mut node = Node::new(address.to_string())
+        .with("vendor", format!("{:#06x}", id.vendor_id))
+        .with("device", format!("{:#06x}", id.device_id))
+        .with("class", &class)
+        .with("revision", pci_device.revision())
+        .with("driver", driver.unwrap_or("none"));
+    if let Some(virtio_type) = virtio_type {
+        node = node.with("virtio", format!("{:?}", virtio_type));
+    }
+    const BAR_NAMES: [&str; 6] = ["bar0", "bar1", "bar2", "bar3", "bar4", "bar5"];
+    for (index, name) in BAR_NAMES.into_iter().enumerate() {
+        if let Some(resource) = pci_device.bar(index) {
+            node = node.with(name, resource);
+        }
+    }
+    if pci_device.interrupt_pin() != 0 {
+        let pin = (b'A' + pci_device.interrupt_pin() - 1) as char;
+        node = node.with("irq", format!("INT{}# line {}", pin, pci_device.interrupt_line()));
+    }
+    let extended: Vec<String> = extended_capabilities(address).iter().map(|(id, offset)| format!("{:#06x}@{:#x}", id, offset)).collect();
+    if !extended.is_empty() {
+        node = node.with("ext_caps", extended.join(" "));
+    }
+    sysinfo::set(Category::Pci, node);
+
+    if id.class_code == 0x2 {
+        let kind = match virtio_type {
+            Some(_) => String::from("virtio-net"),
+            None => class,
+        };
+        sysinfo::set(Category::Network, Node::new(address.to_string()).with("type", kind).with("driver", driver.unwrap_or("none")));
+    }
+}
+
+fn remove_function(address: PciAddress, id: PciFunctionId) {
+    log::info!("[pci] device {} removed - vendor: {:#x}, device: {:#x}, device_type: {}",
+        address, id.vendor_id, id.device_id, get_device_type(id.class_code, id.subclass, id.prog_if));
+
+    let Some(driver) = BOUND.lock().remove(&address) else {
+        return;
+    };
+    for disk in block::unregister_pci(address) {
+        log::warn!("[pci] {} of {} is gone", disk.name(), address);
+    }
+    let remove = DRIVERS.lock().iter().find(|registered| registered.name == driver).map(|registered| registered.remove);
+    if let Some(remove) = remove {
+        remove(address);
+    }
+    log::info!("[pci] {} unbound from {}", address, driver);
+}
+
+/// Re-enumerates all buses, tears down functions that disappeared and probes new ones.
+/// Returns the devices created for the new functions.
+pub async fn rescan() -> Vec<Probed> {
+    let present = enumerate();
+
+    let (added, removed) = {
+        let mut registry = REGISTRY.lock();
+
+        let removed: Vec<_> = registry
+            .iter()
+            .filter(|(address, id)| present.get(address) != Some(id))
+            .map(|(address, id)| (*address, *id))
+            .collect();
+        let added: Vec<_> = present
+            .iter()
+            .filter(|(address, id)| registry.get(address) != Some(id))
+            .map(|(address, id)| (*address, *id))
+            .collect();
+
+        *registry = present;
+        (added, removed)
+    };
+
+    for (address, id) in removed {
+        remove_function(address, id);
+        sysinfo::remove(Category::Pci, &address.to_string());
+        sysinfo::remove(Category::Network, &address.to_string());
+    }
+
+    let mut devices = Vec::new();
+    for (address, id) in added {
+        log_function(address, &id);
+        let pci_device = PciDevice::read(address, id);
+        let (driver, mut probed) = bind(pci_device.clone()).await;
+        register_function(&pci_device, driver);
+        devices.append(&mut probed);
+    }
+    devices
+}
+
+/// Probes every function, with the drivers registered by then
+pub async fn init_pci() -> Vec<Probed> {
+    rescan().await
+}
+
+/// Name of the driver the function at `address` is bound to
+pub fn bound_driver(address: PciAddress) -> Option<&'static str> {
+    BOUND.lock().get(&address).copied()
+}
+
+/// Functions found by the last scan
+pub fn registered_functions() -> Vec<(PciAddress, PciFunctionId)> {
+    REGISTRY.lock().iter().map(|(address, id)| (*address, *id)).collect()
+}
+
+pub fn request_rescan() {
+    RESCAN_REQUESTED.store(true, Ordering::Relaxed);
+    RESCAN_WAKER.wake();
+}
+
+/// Resolves once `request_rescan` has been called.
+pub async fn rescan_requested() {
+    poll_fn(|cx| {
+        if RESCAN_REQUESTED.swap(false, Ordering::Relaxed) {
+            return Poll::Ready(());
+        }
+
+        RESCAN_WAKER.register(cx.waker());
+
+        if RESCAN_REQUESTED.swap(false, Ordering::Relaxed) {
+            RESCAN_WAKER.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }).await
+}
+
+pub fn device_type_name(id: &PciFunctionId) -> &'static str {
+    get_device_type(id.class_code, id.subclass, id.prog_if)
+}
@@ -0,0 +1,155 @@
+// Per-CPU data.
+//
+// Each CPU has a `PerCpu` block, GS_BASE holds its address and the block starts with a pointer
+// to itself, so `current` is a single `gs`-relative load. The kernel never leaves ring 0, so its
+// GS_BASE is always the active one; IA32_KERNEL_GS_BASE is set to the same block, which keeps a
+// `swapgs` on a future user mode entry or exit harmless until there is a user GS_BASE to swap.
+//
+// The bootstrap processor uses a static block, set up right after its GDT so interrupt handlers
+// can use it from the start. The blocks of the application processors are allocated by the
+// bootstrap processor before starting them (`alloc_cpu`) and installed by the CPU itself
+// (`load_cpu`), like their GDT and TSS.
+//
+// The system time stays global: only the bootstrap processor's timer advances `timer::ticks`,
+// the per-CPU counters are statistics.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use conquer_once::spin::OnceCell;
+use shared_lib::msr;
+use shared_lib::spinlock::Spinlock;
+use crate::gdt::{BSP, MAX_CPUS};
+use crate::task::executor::RunQueues;
+
+/// `current_task` while no task is being polled
+pub const NO_TASK: u64 = u64::MAX;
+
+#[derive(Debug)]
+pub struct CpuStats {
+    /// Interrupts handled, exceptions excluded
+    pub interrupts: AtomicU64,
+    pub timer_ticks: AtomicU64,
+    /// Task polls by the executor
+    pub polls: AtomicU64,
+}
+
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this block, read by `current`: must stay the first field
+    this: *const PerCpu,
+    pub cpu: usize,
+    pub apic_id: u8,
+    /// Task being polled on this CPU, `NO_TASK` while none, and the timer tick its poll started at
+    pub current_task: AtomicU64,
+    pub poll_started: AtomicU64,
+    /// Ready queues of the executor running on this CPU
+    run_queue: OnceCell<Arc<RunQueues>>,
+    pub stats: CpuStats,
+}
+
+// `this` is only written before the block is shared
+unsafe impl Sync for PerCpu {}
+unsafe impl Send for PerCpu {}
+
+impl PerCpu {
+    const fn new(this: *const PerCpu, cpu: usize, apic_id: u8) -> PerCpu {
+        PerCpu {
+            this,
+            cpu,
+            apic_id,
+            current_task: AtomicU64::new(NO_TASK),
+            poll_started: AtomicU64::new(0),
+            run_queue: OnceCell::uninit(),
+            stats: CpuStats {
+                interrupts: AtomicU64::new(0),
+                timer_ticks: AtomicU64::new(0),
+                polls: AtomicU64::new(0),
+            },
+        }
+    }
+
+    /// Registers the ready queue of the executor on this CPU, there is one executor per CPU
+    pub fn set_run_queue(&self, queue: Arc<RunQueues>) -> Result<(), &'static str> {
+        self.run_queue.try_init_once(|| queue).map_err(|_| "CPU already has an executor")
+    }
+
+    pub fn run_queue(&self) -> Option<&Arc<RunQueues>> {
+        self.run_queue.get()
+    }
+}
+
+/// The APIC ID is filled in by `init_bsp`
+static mut BSP_BLOCK: PerCpu = PerCpu::new(core::ptr::null(), BSP, 0);
+
+/// Blocks of every CPU that went through `init_bsp` or `alloc_cpu`, indexed by CPU number
+static BLOCKS: Spinlock<[Option<&'static PerCpu>; MAX_CPUS]> = Spinlock::new([None; MAX_CPUS]);
+
+fn install(block: &'static PerCpu) {
+    let addr = block as *const PerCpu as u64;
+    unsafe {
+        msr::write(msr::IA32_GS_BASE, addr);
+        msr::write(msr::IA32_KERNEL_GS_BASE, addr);
+    }
+}
+
+/// Installs the static block of the bootstrap processor
+pub fn init_bsp() {
+    let block = unsafe {
+        let block = &mut *core::ptr::addr_of_mut!(BSP_BLOCK);
+        block.this = block;
+        block.apic_id = (__cpuid(1).ebx >> 24) as u8;
+        &*block
+    };
+    install(block);
+    BLOCKS.lock()[BSP] = Some(block);
+}
+
+/// Allocates the block of the application processor `cpu`, `load_cpu` installs it on that CPU
+pub fn alloc_cpu(cpu: usize, apic_id: u8) -> Result<(), &'static str> {
+    if cpu >= MAX_CPUS {
+        return Err("CPU number out of range");
+    }
+
+    // kept until the CPU goes away, which it never does
+    let block = Box::leak(Box::new(PerCpu::new(core::ptr::null(), cpu, apic_id)));
+    block.this = block;
+    BLOCKS.lock()[cpu] = Some(block);
+    Ok(())
+}
+
+/// Installs the block `alloc_cpu` allocated for `cpu`, on that CPU
+pub fn load_cpu(cpu: usize) -> Result<(), &'static str> {
+    let block = cpu_block(cpu).ok_or("per-CPU block not allocated")?;
+    install(block);
+    Ok(())
+}
+
+/// Block of the CPU we run on. Only valid after `init_bsp` or `load_cpu` on this CPU.
+#[inline]
+pub fn current() -> &'static PerCpu {
+    let this: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) this, options(nostack, readonly, preserves_flags));
+        &*this
+    }
+}
+
+/// Block of the CPU `cpu`, None if it wasn't set up
+pub fn cpu_block(cpu: usize) -> Option<&'static PerCpu> {
+    BLOCKS.lock().get(cpu).copied().flatten()
+}
+
+/// Per-CPU part of the `irqstat` output
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    for block in BLOCKS.lock().iter().flatten() {
+        let stats = &block.stats;
+        writeln!(out, "cpu{} (APIC ID {}): {} interrupts, {} timer ticks, {} polls", block.cpu, block.apic_id,
+            stats.interrupts.load(Ordering::Relaxed), stats.timer_ticks.load(Ordering::Relaxed),
+            stats.polls.load(Ordering::Relaxed))?;
+    }
+    Ok(())
+}
@@ -1,82 +1,193 @@
+// x86 I/O ports.
+//
+// A port is typed by its access width (`u8`, `u16` or `u32`) and by direction: `Port` can be
+// read and written, `PortReadOnly` and `PortWriteOnly` only one way, so e.g. a write to a status
+// register or a byte access to a dword register doesn't compile.
+
 use core::arch::asm;
+use core::fmt;
 use core::marker::PhantomData;
 
-pub struct Port {
-    port: u16,
-    phantom: PhantomData<u8>,
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+}
+
+/// A value that can be read from a port with `in`
+pub trait PortRead: sealed::Sealed {
+    /// # Safety
+    /// Reading a port may have side effects on the device.
+    unsafe fn read_from_port(port: u16) -> Self;
 }
 
-impl Port {
+/// A value that can be written to a port with `out`
+pub trait PortWrite: sealed::Sealed {
+    /// # Safety
+    /// Writing a port may have side effects on the device.
+    unsafe fn write_to_port(port: u16, value: Self);
+}
+
+impl PortRead for u8 {
     #[inline]
-    pub const fn new(port: u16) -> Port {
-        Port {
-            port,
-            phantom: PhantomData,
+    unsafe fn read_from_port(port: u16) -> u8 {
+        let value: u8;
+        unsafe {
+            asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
         }
+        value
     }
+}
 
+impl PortRead for u16 {
     #[inline]
-    pub unsafe fn write(&mut self, value: u8) {
+    unsafe fn read_from_port(port: u16) -> u16 {
+        let value: u16;
         unsafe {
-            asm!("out dx, al", in("dx") self.port, in("al") value, options(nomem, nostack, preserves_flags));
+            asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack, preserves_flags));
         }
+        value
     }
+}
 
+impl PortRead for u32 {
     #[inline]
-    pub unsafe fn write_u16(&mut self, value: u16) {
+    unsafe fn read_from_port(port: u16) -> u32 {
+        let value: u32;
         unsafe {
-            asm!("out dx, ax", in("dx") self.port, in("ax") value, options(nomem, nostack, preserves_flags));
+            asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack, preserves_flags));
         }
+        value
     }
+}
 
+impl PortWrite for u8 {
     #[inline]
-    pub unsafe fn write_u32(&mut self, value: u32) {
+    unsafe fn write_to_port(port: u16, value: u8) {
         unsafe {
-            asm!("out dx, eax", in("dx") self.port, in("eax") value, options(nomem, nostack, preserves_flags));
+            asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
         }
     }
+}
 
+impl PortWrite for u16 {
     #[inline]
-    pub unsafe fn read(&mut self) -> u8 {
-        let value: u8;
+    unsafe fn write_to_port(port: u16, value: u16) {
         unsafe {
-            asm!("in al, dx", out("al") value, in("dx") self.port, options(nomem, nostack, preserves_flags));
+            asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack, preserves_flags));
         }
-        value
     }
+}
 
+impl PortWrite for u32 {
     #[inline]
-    pub unsafe fn read_u16(&mut self) -> u16 {
-        let value: u16;
+    unsafe fn write_to_port(port: u16, value: u32) {
         unsafe {
-            asm!("in ax, dx", out("ax") value, in("dx") self.port, options(nomem, nostack, preserves_flags));
+            asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
         }
-        value
     }
+}
+
+macro_rules! port_type {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        pub struct $name<T> {
+            port: u16,
+            phantom: PhantomData<T>,
+        }
+
+        impl<T> $name<T> {
+            #[inline]
+            pub const fn new(port: u16) -> $name<T> {
+                $name {
+                    port,
+                    phantom: PhantomData,
+                }
+            }
+
+            #[inline]
+            pub const fn number(&self) -> u16 {
+                self.port
+            }
+        }
+
+        impl<T> fmt::Debug for $name<T> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}<{}>({:#x})", stringify!($name), core::any::type_name::<T>(), self.port)
+            }
+        }
+    };
+}
+
+port_type!(
+    /// Read-write port of width `T`
+    Port
+);
+port_type!(
+    /// Port of width `T` that is only read, e.g. a status register
+    PortReadOnly
+);
+port_type!(
+    /// Port of width `T` that is only written, e.g. a command register
+    PortWriteOnly
+);
+
+impl<T: PortRead> Port<T> {
+    #[inline]
+    pub unsafe fn read(&mut self) -> T {
+        T::read_from_port(self.port)
+    }
+}
 
+impl<T: PortWrite> Port<T> {
     #[inline]
-    pub unsafe fn read_u32(&mut self) -> u32 {
-        let value: u32;
+    pub unsafe fn write(&mut self, value: T) {
+        T::write_to_port(self.port, value)
+    }
+}
+
+impl<T: PortRead> PortReadOnly<T> {
+    #[inline]
+    pub unsafe fn read(&mut self) -> T {
+        T::read_from_port(self.port)
+    }
+}
+
+impl<T: PortWrite> PortWriteOnly<T> {
+    #[inline]
+    pub unsafe fn write(&mut self, value: T) {
+        T::write_to_port(self.port, value)
+    }
+}
+
+impl Port<u16> {
+    /// Reads `buffer.len() / 2` words straight into `buffer` (`rep insw`), no alignment required.
+    #[inline]
+    pub unsafe fn read_into(&mut self, buffer: &mut [u8]) {
         unsafe {
-            asm!("in eax, dx", out("eax") value, in("dx") self.port, options(nomem, nostack, preserves_flags));
+            asm!("rep insw", in("dx") self.port, inout("rdi") buffer.as_mut_ptr() => _, inout("rcx") buffer.len() / 2 => _,
+                options(nostack, preserves_flags));
+        }
+    }
+
+    /// Writes `buffer.len() / 2` words from `buffer` (`rep outsw`), no alignment required.
+    #[inline]
+    pub unsafe fn write_from(&mut self, buffer: &[u8]) {
+        unsafe {
+            asm!("rep outsw", in("dx") self.port, inout("rsi") buffer.as_ptr() => _, inout("rcx") buffer.len() / 2 => _,
+                options(nostack, preserves_flags, readonly));
         }
-        value
     }
 }
 
 #[inline]
 pub unsafe fn write(port: u16, value: u8) {
-    unsafe {
-        asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
-    }
+    u8::write_to_port(port, value)
 }
 
 #[inline]
 pub unsafe fn read(port: u16) -> u8 {
-    let value: u8;
-    unsafe {
-        asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
-    }
-    value
+    u8::read_from_port(port)
 }
-
@@ -0,0 +1,172 @@
+// Framebuffer screenshots.
+//
+// The BMP is written through the VFS when a filesystem is mounted at its path, e.g. the tmpfs on
+// /tmp. Otherwise it goes to the host share (virtio-9p) if there is one, or is streamed over
+// serial as base64 lines framed by `screenshot: begin <name> <size>` / `screenshot: end`, and
+// `extract_screenshots.py` turns a serial log back into files.
+//
+// A full-screen BMP is several megabytes, far more than the kernel heap, so the image is encoded
+// row by row and never held in memory.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ptr::read_volatile;
+use shared_lib::logger::{FrameBufferInfo, PixelFormat};
+use shared_lib::serial_println;
+use crate::vfs::{self, VfsError};
+use crate::virtio::ninep::{HostFile, NinePError};
+
+const BMP_HEADER_SIZE: usize = 14;
+const DIB_HEADER_SIZE: usize = 40;
+/// Input bytes per base64 line, gives 76 characters
+const BASE64_LINE_BYTES: usize = 57;
+
+fn row_size(fb_info: &FrameBufferInfo) -> usize {
+    (fb_info.width * 3 + 3) & !3
+}
+
+/// Size of the encoded 24-bit BMP.
+pub fn bmp_size(fb_info: &FrameBufferInfo) -> usize {
+    BMP_HEADER_SIZE + DIB_HEADER_SIZE + row_size(fb_info) * fb_info.height
+}
+
+/// Encodes the visible part of the framebuffer as a 24-bit BMP, passing it to `sink` in pieces.
+pub fn write_bmp<F: FnMut(&[u8])>(fb_info: &FrameBufferInfo, mut sink: F) -> Result<(), &'static str> {
+    let (r, g, b) = match fb_info.pixel_format {
+        PixelFormat::Rgb => (0, 1, 2),
+        PixelFormat::Bgr => (2, 1, 0),
+        _ => return Err("unsupported pixel format")
+    };
+
+    let row_size = row_size(fb_info);
+    let image_size = row_size * fb_info.height;
+
+    let mut header = Vec::with_capacity(BMP_HEADER_SIZE + DIB_HEADER_SIZE);
+
+    // file header
+    header.extend_from_slice(b"BM");
+    header.extend_from_slice(&(bmp_size(fb_info) as u32).to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&((BMP_HEADER_SIZE + DIB_HEADER_SIZE) as u32).to_le_bytes());
+
+    // BITMAPINFOHEADER
+    header.extend_from_slice(&(DIB_HEADER_SIZE as u32).to_le_bytes());
+    header.extend_from_slice(&(fb_info.width as i32).to_le_bytes());
+    header.extend_from_slice(&(fb_info.height as i32).to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes()); // planes
+    header.extend_from_slice(&24u16.to_le_bytes()); // bits per pixel
+    header.extend_from_slice(&0u32.to_le_bytes()); // no compression
+    header.extend_from_slice(&(image_size as u32).to_le_bytes());
+    header.extend_from_slice(&2835u32.to_le_bytes()); // 72 DPI
+    header.extend_from_slice(&2835u32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    sink(&header);
+
+    // rows are stored bottom-up, pixels as BGR
+    let fb = fb_info.addr as *const u8;
+    let mut row = Vec::with_capacity(row_size);
+    for y in (0..fb_info.height).rev() {
+        row.clear();
+        for x in 0..fb_info.width {
+            let pixel = unsafe { fb.add((y * fb_info.stride + x) * 4) };
+            unsafe {
+                row.extend_from_slice(&[read_volatile(pixel.add(b)), read_volatile(pixel.add(g)), read_volatile(pixel.add(r))]);
+            }
+        }
+        row.resize(row_size, 0);
+        sink(&row);
+    }
+
+    Ok(())
+}
+
+fn base64_line(data: &[u8]) -> [u8; BASE64_LINE_BYTES / 3 * 4] {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut line = [b' '; BASE64_LINE_BYTES / 3 * 4];
+    for (chunk, out) in data.chunks(3).zip(line.chunks_exact_mut(4)) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+
+        out[0] = ALPHABET[(n >> 18) as usize & 63];
+        out[1] = ALPHABET[(n >> 12) as usize & 63];
+        out[2] = if chunk.len() > 1 { ALPHABET[(n >> 6) as usize & 63] } else { b'=' };
+        out[3] = if chunk.len() > 2 { ALPHABET[n as usize & 63] } else { b'=' };
+    }
+    line
+}
+
+fn print_base64_line(data: &[u8]) {
+    let line = base64_line(data);
+    // base64 alphabet is ASCII
+    serial_println!("{}", core::str::from_utf8(&line).unwrap().trim_end());
+}
+
+/// Streams a BMP of the framebuffer over serial. Returns the size of the image.
+pub fn export_to_serial(name: &str, fb_info: &FrameBufferInfo) -> Result<usize, &'static str> {
+    let size = bmp_size(fb_info);
+    let mut pending = [0u8; BASE64_LINE_BYTES];
+    let mut pending_len = 0;
+
+    serial_println!("screenshot: begin {} {}", name, size);
+    write_bmp(fb_info, |mut data| {
+        while !data.is_empty() {
+            let n = (BASE64_LINE_BYTES - pending_len).min(data.len());
+            pending[pending_len..pending_len + n].copy_from_slice(&data[..n]);
+            pending_len += n;
+            data = &data[n..];
+
+            if pending_len == BASE64_LINE_BYTES {
+                print_base64_line(&pending);
+                pending_len = 0;
+            }
+        }
+    })?;
+    if pending_len != 0 {
+        print_base64_line(&pending[..pending_len]);
+    }
+    serial_println!("screenshot: end");
+
+    Ok(size)
+}
+
+/// Writes a BMP of the framebuffer to `path` through the VFS, replacing the file if there is one.
+/// Blocks, see `vfs`. Returns the size of the image.
+pub fn export_to_vfs(path: &str, fb_info: &FrameBufferInfo) -> Result<usize, String> {
+    let vfs_error = |e: VfsError| format!("{}: {:?}", path, e);
+
+    match vfs::remove(path) {
+        Ok(()) | Err(VfsError::NotFound) => {},
+        Err(e) => return Err(vfs_error(e)),
+    }
+    vfs::create(path).map_err(vfs_error)?;
+
+    let mut offset = 0;
+    let mut result = Ok(());
+    write_bmp(fb_info, |data| {
+        if result.is_ok() {
+            result = vfs::write(path, offset, data).map(|_| ());
+            offset += data.len() as u64;
+        }
+    })?;
+    result.map_err(vfs_error)?;
+    Ok(bmp_size(fb_info))
+}
+
+/// Writes a BMP of the framebuffer to `path` on the host share. Returns the size of the image.
+pub fn export_to_share(path: &str, fb_info: &FrameBufferInfo) -> Result<usize, String> {
+    let share_error = |e: NinePError| format!("host share: {:?}", e);
+
+    let mut file = HostFile::create(path).map_err(share_error)?;
+    let mut result = Ok(());
+    write_bmp(fb_info, |data| {
+        if result.is_ok() {
+            result = file.write_all(data);
+        }
+    })?;
+    result.map_err(share_error)?;
+    Ok(bmp_size(fb_info))
+}
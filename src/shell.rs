@@ -1,19 +1,50 @@
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::fmt::Write;
 use core::sync::atomic::Ordering::Relaxed;
+use shared_lib::io::SeekFrom;
 use shared_lib::logger::{FrameBufferInfo, Logger};
-use crate::task::executor::STOP;
+use shared_lib::memprof;
+use crate::task::executor::{self, STOP};
+use crate::task::join;
+use crate::allocator;
+use crate::block::{self, cache};
+use crate::cmdline;
+use crate::config;
+use crate::driver;
+use crate::efi;
+use crate::fs::{self, FdTable, OpenFlags};
+use crate::gpt::{self, GptEditor, GptError};
+use crate::idle;
+use crate::interrupts;
+use crate::meminfo;
+use crate::memory;
+use crate::pci;
+use crate::trace;
+use crate::virtio::ninep;
+use crate::symbols;
+use crate::smart;
+use crate::sysinfo;
+use crate::thermal;
+use crate::thread;
+use crate::vfs::{self, VfsError};
+use crate::vm;
+use crate::screenshot;
+use crate::xmodem;
 
 pub struct Shell {
     logger: Logger,
     input_buffer: Vec<char>,
+    /// The descriptors of the session, used by the blocking commands
+    files: Arc<FdTable>,
 }
 
 impl Shell {
     pub fn new(fb_info: FrameBufferInfo) -> Self {
         let mut logger = Logger::new(fb_info);
         logger.write_str("# ").unwrap();
-        Shell{ logger, input_buffer: Vec::new() }
+        Shell{ logger, input_buffer: Vec::new(), files: Arc::new(FdTable::new()) }
     }
 
     pub fn char_input(&mut self, c: char) {
@@ -23,17 +54,596 @@ impl Shell {
             return;
         }
 
-        if self.input_buffer == ['s', 'h', 'u', 't', 'd', 'o', 'w', 'n'] {
-            self.logger.write_str("\nshutting down...\n").unwrap();
-            STOP.store(true, Relaxed);
-            return;
-        } else if self.input_buffer == [ 'h', 'e', 'l', 'p' ] {
-            self.logger.write_str("This is Rust OS! Commands list:\n").unwrap();
-            self.logger.write_str("- help\n").unwrap();
-            self.logger.write_str("- shutdown\n").unwrap();
+        let command: String = self.input_buffer.iter().collect();
+        let mut args = command.split_whitespace();
+
+        match args.next() {
+            Some("shutdown") => {
+                self.logger.write_str("\nshutting down...\n").unwrap();
+                // the executor stops once the cached writes are on the disks
+                join::spawn(async {
+                    let _ = cache::sync().await;
+                    STOP.store(true, Relaxed);
+                });
+                return;
+            },
+            Some("help") => {
+                self.logger.write_str("This is Rust OS! Commands list:\n").unwrap();
+                self.logger.write_str("- cat <path>\n").unwrap();
+                self.logger.write_str("- chattr <+|-><r|h|s> <path>\n").unwrap();
+                self.logger.write_str("- close <fd>\n").unwrap();
+                self.logger.write_str("- cmdline\n").unwrap();
+                self.logger.write_str("- config [<key> <value>]\n").unwrap();
+                self.logger.write_str("- drivers\n").unwrap();
+                self.logger.write_str("- efivar <name>\n").unwrap();
+                self.logger.write_str("- fds\n").unwrap();
+                self.logger.write_str("- gpt <disk> [init|add <type> <first> <last> [name]|del <n>|resize <n> <last>]\n").unwrap();
+                self.logger.write_str("- health [disk]\n").unwrap();
+                self.logger.write_str("- help\n").unwrap();
+                self.logger.write_str("- hostfs [path]\n").unwrap();
+                self.logger.write_str("- idle\n").unwrap();
+                self.logger.write_str("- iostat\n").unwrap();
+                self.logger.write_str("- irqstat\n").unwrap();
+                self.logger.write_str("- ls [path]\n").unwrap();
+                self.logger.write_str("- mappings [start [end]]\n").unwrap();
+                self.logger.write_str("- meminfo [poison on|off]\n").unwrap();
+                self.logger.write_str("- memprof [on|off|reset]\n").unwrap();
+                self.logger.write_str("- mount [tmpfs <path>|<partition|disk> <path> [ferr_fs|fat|ext2|iso9660]]\n").unwrap();
+                self.logger.write_str("- open <path> [r][w][c][a]\n").unwrap();
+                self.logger.write_str("- pci [rescan]\n").unwrap();
+                self.logger.write_str("- ps\n").unwrap();
+                self.logger.write_str("- read <fd> [len]\n").unwrap();
+                self.logger.write_str("- rx <path>\n").unwrap();
+                self.logger.write_str("- screenshot [name]\n").unwrap();
+                self.logger.write_str("- seek <fd> <offset|+offset|-offset|end>\n").unwrap();
+                self.logger.write_str("- sensors\n").unwrap();
+                self.logger.write_str("- shutdown\n").unwrap();
+                self.logger.write_str("- sym <addr>\n").unwrap();
+                self.logger.write_str("- sync\n").unwrap();
+                self.logger.write_str("- sysinfo [path]\n").unwrap();
+                self.logger.write_str("- threads\n").unwrap();
+                self.logger.write_str("- touch <path>\n").unwrap();
+                self.logger.write_str("- trace [on|off|dump|save <disk> <lba>]\n").unwrap();
+                self.logger.write_str("- umount <path>\n").unwrap();
+                self.logger.write_str("- vm\n").unwrap();
+                self.logger.write_str("- write <fd> <text>\n").unwrap();
+            },
+            Some("mappings") => self.mappings(args.next(), args.next()),
+            Some("meminfo") => self.meminfo(args.next(), args.next()),
+            Some("memprof") => self.memprof(args.next()),
+            Some("trace") => self.trace(&args.collect::<Vec<_>>()),
+            Some("pci") => self.pci(args.next()),
+            Some("config") => self.config(args.next(), args.next()),
+            Some("cmdline") => cmdline::dump(&mut self.logger).unwrap(),
+            Some("drivers") => driver::dump(&mut self.logger).unwrap(),
+            Some("idle") => idle::dump(&mut self.logger).unwrap(),
+            Some("iostat") => block::dump(&mut self.logger).unwrap(),
+            Some("irqstat") => interrupts::dump(&mut self.logger).unwrap(),
+            Some("threads") => thread::dump(&mut self.logger).unwrap(),
+            Some("ps") => executor::dump(&mut self.logger).unwrap(),
+            Some("sensors") => thermal::dump(&mut self.logger).unwrap(),
+            Some("sync") => self.blocking(|| {
+                // mapped files and filesystem metadata first, they land in the cache
+                if let Err(e) = vfs::sync_all() {
+                    log::warn!("[vfs] sync failed: {:?}", e);
+                }
+                match thread::block_on(cache::sync()) {
+                    Ok(()) => log::info!("[cache] synced"),
+                    Err(e) => log::warn!("[cache] sync failed: {:?}", e),
+                }
+            }),
+            Some("sysinfo") => sysinfo::dump(&mut self.logger, args.next()).unwrap(),
+            Some("vm") => vm::dump(&mut self.logger).unwrap(),
+            Some("sym") => self.sym(args.next()),
+            Some("efivar") => self.efivar(args.next()),
+            Some("rx") => self.rx(args.next()),
+            Some("hostfs") => self.hostfs(args.next()),
+            Some("gpt") => self.gpt(&args.collect::<Vec<_>>()),
+            Some("health") => self.health(args.next()),
+            Some("mount") => self.mount(&args.collect::<Vec<_>>()),
+            Some("umount") => self.umount(args.next()),
+            Some("ls") => self.ls(args.next().unwrap_or("/")),
+            Some("cat") => self.cat(args.next()),
+            Some("chattr") => self.chattr(args.next(), args.next()),
+            Some("touch") => self.touch(args.next()),
+            Some("open") => self.open(args.next(), args.next()),
+            Some("read") => self.read(args.next(), args.next()),
+            Some("write") => self.write(args.next(), command.split_whitespace().skip(2).collect::<Vec<_>>().join(" ")),
+            Some("seek") => self.seek(args.next(), args.next()),
+            Some("close") => self.close(args.next()),
+            Some("fds") => self.files.dump(&mut self.logger).unwrap(),
+            Some("screenshot") => self.screenshot(args.next().unwrap_or("/tmp/screen.bmp")),
+            _ => {}
         }
 
         self.input_buffer.clear();
         self.logger.write_str("# ").unwrap();
     }
-}
\ No newline at end of file
+
+    fn mappings(&mut self, start: Option<&str>, end: Option<&str>) {
+        let parse = |arg: Option<&str>, default: u64| match arg {
+            Some(arg) => u64::from_str_radix(arg.trim_start_matches("0x"), 16).ok(),
+            None => Some(default),
+        };
+        match (parse(start, 0), parse(end, u64::MAX)) {
+            (Some(start), Some(end)) => memory::dump_mappings(&mut self.logger, start..end).unwrap(),
+            _ => self.logger.write_str("usage: mappings [hex start [hex end]]\n").unwrap(),
+        }
+    }
+
+    fn meminfo(&mut self, arg: Option<&str>, value: Option<&str>) {
+        match (arg, value) {
+            (None, _) => meminfo::dump(&mut self.logger).unwrap(),
+            (Some("poison"), Some("on")) => meminfo::set_poison(true),
+            (Some("poison"), Some("off")) => meminfo::set_poison(false),
+            _ => self.logger.write_str("usage: meminfo [poison on|off]\n").unwrap(),
+        }
+    }
+
+    fn memprof(&mut self, arg: Option<&str>) {
+        match arg {
+            Some("on") => memprof::enable(),
+            Some("off") => memprof::disable(),
+            Some("reset") => memprof::reset(),
+            None => {
+                memprof::dump(&mut self.logger).unwrap();
+                writeln!(self.logger, "heap: {} kB mapped, limit {} kB", allocator::heap_mapped_size() / 1024, allocator::heap_max_size() / 1024).unwrap();
+            },
+            Some(_) => self.logger.write_str("usage: memprof [on|off|reset]\n").unwrap(),
+        }
+    }
+
+    fn trace(&mut self, args: &[&str]) {
+        match *args {
+            ["on"] => trace::enable(),
+            ["off"] => trace::disable(),
+            ["dump"] => {
+                trace::export_to_serial();
+                self.logger.write_str("trace written to serial\n").unwrap();
+            },
+            ["save", name, lba] => {
+                let (Some(disk), Ok(lba)) = (block::find(name), lba.parse::<u64>()) else {
+                    writeln!(self.logger, "trace: no disk {} or bad LBA {}", name, lba).unwrap();
+                    return;
+                };
+                join::spawn(async move {
+                    match trace::export_to_disk(&disk, lba).await {
+                        Ok(()) => log::info!("[trace] written to {} at LBA {}", disk.name(), lba),
+                        Err(e) => log::warn!("[trace] {}: {:?}", disk.name(), e),
+                    }
+                });
+                self.logger.write_str("see the log for the result\n").unwrap();
+            },
+            _ => self.logger.write_str("usage: trace [on|off|dump|save <disk> <lba>]\n").unwrap(),
+        }
+    }
+
+    fn config(&mut self, key: Option<&str>, value: Option<&str>) {
+        match (key, value) {
+            (None, _) => config::dump(&mut self.logger).unwrap(),
+            (Some(key), Some(value)) => if let Err(e) = config::set(key, value) {
+                writeln!(self.logger, "config: {:?}", e).unwrap();
+            },
+            (Some(_), None) => self.logger.write_str("usage: config [<key> <value>]\n").unwrap(),
+        }
+    }
+
+    fn pci(&mut self, arg: Option<&str>) {
+        match arg {
+            Some("rescan") => {
+                pci::request_rescan();
+                self.logger.write_str("rescan requested, see the log for changes\n").unwrap();
+            },
+            None => {
+                for (address, id) in pci::registered_functions() {
+                    writeln!(self.logger, "{} {:04x}:{:04x} {:<10} {}", address, id.vendor_id, id.device_id,
+                        pci::bound_driver(address).unwrap_or("-"), pci::device_type_name(&id)).unwrap();
+                }
+            },
+            Some(_) => self.logger.write_str("usage: pci [rescan]\n").unwrap(),
+        }
+    }
+
+    fn sym(&mut self, arg: Option<&str>) {
+        let addr = arg.and_then(|a| u64::from_str_radix(a.trim_start_matches("0x"), 16).ok());
+        match addr {
+            Some(addr) => writeln!(self.logger, "{}", symbols::Resolved(addr)).unwrap(),
+            None => self.logger.write_str("usage: sym <hex addr>\n").unwrap(),
+        }
+    }
+
+    fn efivar(&mut self, name: Option<&str>) {
+        let Some(name) = name else {
+            self.logger.write_str("usage: efivar <name>\n").unwrap();
+            return;
+        };
+
+        let mut buffer = [0u8; 256];
+        match efi::get_variable(name, &efi::GLOBAL_VARIABLE, &mut buffer) {
+            Ok((size, attributes)) => {
+                writeln!(self.logger, "{}: {} bytes, attributes {:#x}", name, size, attributes).unwrap();
+                for byte in &buffer[..size] {
+                    write!(self.logger, "{:02x} ", byte).unwrap();
+                }
+                writeln!(self.logger).unwrap();
+            },
+            Err(e) => writeln!(self.logger, "{}: {:?}", name, e).unwrap(),
+        }
+    }
+
+    fn rx(&mut self, path: Option<&str>) {
+        let Some(path) = path else {
+            self.logger.write_str("usage: rx <path>\n").unwrap();
+            return;
+        };
+
+        self.logger.write_str("waiting for XMODEM transfer on COM1...\n").unwrap();
+        let data = match xmodem::receive_file_quietly() {
+            Ok(data) => data,
+            Err(e) => {
+                writeln!(self.logger, "transfer failed: {:?}", e).unwrap();
+                return;
+            },
+        };
+        writeln!(self.logger, "received {} bytes", data.len()).unwrap();
+
+        let path = String::from(path);
+        self.blocking(move || match xmodem::store(&path, &data) {
+            Ok(()) => log::info!("[rx] {} written ({} bytes)", path, data.len()),
+            Err(e) => log::warn!("[rx] {}: {:?}", path, e),
+        });
+    }
+
+    fn screenshot(&mut self, name: &str) {
+        if vfs::is_mounted(name) {
+            let (path, fb_info) = (String::from(name), self.logger.fb_info());
+            self.blocking(move || match screenshot::export_to_vfs(&path, &fb_info) {
+                Ok(size) => log::info!("[screenshot] {} written ({} bytes)", path, size),
+                Err(e) => log::warn!("[screenshot] failed: {}", e),
+            });
+            return;
+        }
+
+        if ninep::is_mounted() {
+            let path = name.trim_start_matches('/');
+            match screenshot::export_to_share(path, &self.logger.fb_info()) {
+                Ok(size) => writeln!(self.logger, "{} written to the host share ({} bytes)", path, size).unwrap(),
+                Err(e) => writeln!(self.logger, "screenshot failed: {}", e).unwrap(),
+            }
+            return;
+        }
+
+        match screenshot::export_to_serial(name, &self.logger.fb_info()) {
+            Ok(size) => writeln!(self.logger, "{} written to serial ({} bytes)", name, size).unwrap(),
+            Err(e) => writeln!(self.logger, "screenshot failed: {}", e).unwrap(),
+        }
+    }
+
+    fn gpt(&mut self, args: &[&str]) {
+        let number = |i: usize| args.get(i).and_then(|arg| arg.parse::<u64>().ok());
+        let command = match (args.get(1).copied(), args.len()) {
+            (None, 1) => Some(GptCommand::List),
+            (Some("init"), 2) => Some(GptCommand::Init),
+            (Some("add"), 5 | 6) => match (gpt::type_guid(args[2]), number(3), number(4)) {
+                (Some(type_guid), Some(first), Some(last)) => {
+                    Some(GptCommand::Add(type_guid, first, last, String::from(args.get(5).copied().unwrap_or(""))))
+                },
+                _ => None,
+            },
+            (Some("del"), 3) => number(2).map(|index| GptCommand::Delete(index as usize)),
+            (Some("resize"), 4) => number(2).zip(number(3)).map(|(index, last)| GptCommand::Resize(index as usize, last)),
+            _ => None,
+        };
+        let Some(command) = command else {
+            self.logger.write_str("usage: gpt <disk> [init|add <type> <first> <last> [name]|del <n>|resize <n> <last>]\n").unwrap();
+            self.logger.write_str("  type is efi, linux, basic or a GUID\n").unwrap();
+            return;
+        };
+        let Some(disk) = block::find(args[0]) else {
+            writeln!(self.logger, "gpt: no disk {}", args[0]).unwrap();
+            return;
+        };
+
+        join::spawn(async move {
+            let name = String::from(disk.name());
+            match gpt_command(disk, command).await {
+                Ok(()) => {},
+                Err(e) => log::warn!("[gpt] {}: {:?}", name, e),
+            }
+        });
+        self.logger.write_str("see the log for the result\n").unwrap();
+    }
+
+    fn health(&mut self, name: Option<&str>) {
+        let disks = match name {
+            None => block::disks(),
+            Some(name) => match block::find(name) {
+                Some(disk) => alloc::vec![disk],
+                None => {
+                    writeln!(self.logger, "health: no disk {}", name).unwrap();
+                    return;
+                },
+            },
+        };
+        let detailed = name.is_some();
+
+        join::spawn(async move {
+            for disk in disks {
+                match disk.device().health().await {
+                    Ok(health) if detailed => log::info!("[smart] {}:\n{}", disk.name(), health),
+                    Ok(health) => log::info!("[smart] {}: {}", disk.name(), smart::Summary(&health)),
+                    Err(e) => log::info!("[smart] {}: {:?}", disk.name(), e),
+                }
+            }
+        });
+        self.logger.write_str("see the log for the result\n").unwrap();
+    }
+
+    /// Runs a VFS call on a kernel thread, the filesystems block
+    fn blocking(&mut self, f: impl FnOnce() + Send + 'static) {
+        match join::spawn_blocking(f) {
+            Ok(_) => self.logger.write_str("see the log for the result\n").unwrap(),
+            Err(e) => writeln!(self.logger, "no thread to run it: {}", e).unwrap(),
+        }
+    }
+
+    fn mount(&mut self, args: &[&str]) {
+        match *args {
+            [] => {
+                for mount in vfs::mounts() {
+                    writeln!(self.logger, "{}", mount).unwrap();
+                }
+            },
+            ["tmpfs", path] => {
+                if let Err(e) = vfs::mount_tmpfs(path) {
+                    writeln!(self.logger, "mount: {:?}", e).unwrap();
+                }
+            },
+            [id, path] | [id, path, _] => {
+                // a partition, or a whole disk like an ISO image
+                let (partition, disk) = (block::partition::find(id), block::find(id));
+                if partition.is_none() && disk.is_none() {
+                    writeln!(self.logger, "mount: no partition or disk {}", id).unwrap();
+                    return;
+                }
+                let (id, path) = (String::from(id), String::from(path));
+                let kind = args.get(2).map(|&kind| String::from(kind));
+                self.blocking(move || {
+                    let result = match (partition, disk) {
+                        (Some(partition), _) => vfs::mount_partition(&partition, &path, kind.as_deref()),
+                        (None, Some(disk)) => vfs::mount_disk(&disk, &path, kind.as_deref()),
+                        (None, None) => unreachable!(),
+                    };
+                    if let Err(e) = result {
+                        log::warn!("[vfs] mount of {} on {} failed: {:?}", id, path, e);
+                    }
+                });
+            },
+            _ => self.logger.write_str("usage: mount [tmpfs <path>|<partition|disk> <path> [ferr_fs|fat|ext2|iso9660]]\n").unwrap(),
+        }
+    }
+
+    fn umount(&mut self, path: Option<&str>) {
+        let Some(path) = path.map(String::from) else {
+            self.logger.write_str("usage: umount <path>\n").unwrap();
+            return;
+        };
+        self.blocking(move || {
+            if let Err(e) = vfs::unmount(&path) {
+                log::warn!("[vfs] umount {}: {:?}", path, e);
+            }
+        });
+    }
+
+    fn ls(&mut self, path: &str) {
+        let path = String::from(path);
+        self.blocking(move || {
+            let mut listing = String::new();
+            match vfs::dump(&mut listing, &path) {
+                Ok(()) => log::info!("[vfs] {}:\n{}", path, listing.trim_end()),
+                Err(e) => log::warn!("[vfs] ls {}: {:?}", path, e),
+            }
+        });
+    }
+
+    fn cat(&mut self, path: Option<&str>) {
+        let Some(path) = path.map(String::from) else {
+            self.logger.write_str("usage: cat <path>\n").unwrap();
+            return;
+        };
+        self.blocking(move || {
+            match vfs::read_to_end(&path) {
+                Ok(data) => log::info!("[vfs] {}:\n{}", path, String::from_utf8_lossy(&data)),
+                Err(e) => log::warn!("[vfs] cat {}: {:?}", path, e),
+            }
+        });
+    }
+
+    fn chattr(&mut self, change: Option<&str>, path: Option<&str>) {
+        let parse = |change: &str| {
+            let (set, letters) = match change.strip_prefix('+') {
+                Some(letters) => (true, letters),
+                None => (false, change.strip_prefix('-')?),
+            };
+            let flags = letters.chars().try_fold(vfs::FileFlags::empty(), |flags, letter| match letter {
+                'r' => Some(flags | vfs::FileFlags::READ_ONLY),
+                'h' => Some(flags | vfs::FileFlags::HIDDEN),
+                's' => Some(flags | vfs::FileFlags::SYSTEM),
+                _ => None,
+            })?;
+            Some((set, flags))
+        };
+        let (Some((set, flags)), Some(path)) = (change.and_then(parse), path.map(String::from)) else {
+            self.logger.write_str("usage: chattr <+|-><r|h|s> <path>\n").unwrap();
+            return;
+        };
+        self.blocking(move || {
+            let result = vfs::metadata(&path).and_then(|metadata| {
+                let mut new = metadata.flags;
+                new.set(flags, set);
+                vfs::set_flags(&path, new)
+            });
+            match result {
+                Ok(()) => log::info!("[vfs] {} flags changed", path),
+                Err(e) => log::warn!("[vfs] chattr {}: {:?}", path, e),
+            }
+        });
+    }
+
+    /// Creates the file if it doesn't exist, else sets its modification time to now
+    fn touch(&mut self, path: Option<&str>) {
+        let Some(path) = path.map(String::from) else {
+            self.logger.write_str("usage: touch <path>\n").unwrap();
+            return;
+        };
+        self.blocking(move || {
+            let result = match vfs::metadata(&path) {
+                Err(VfsError::NotFound) => vfs::create(&path),
+                Ok(_) => vfs::set_modified(&path, vfs::now()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                log::warn!("[vfs] touch {}: {:?}", path, e);
+            }
+        });
+    }
+
+    fn open(&mut self, path: Option<&str>, mode: Option<&str>) {
+        let flags = mode.unwrap_or("r").chars().try_fold(OpenFlags::empty(), |flags, c| match c {
+            'r' => Some(flags | OpenFlags::READ),
+            'w' => Some(flags | OpenFlags::WRITE),
+            'c' => Some(flags | OpenFlags::CREATE),
+            'a' => Some(flags | OpenFlags::APPEND),
+            _ => None,
+        });
+        let (Some(path), Some(flags)) = (path.map(String::from), flags) else {
+            self.logger.write_str("usage: open <path> [r][w][c][a]\n").unwrap();
+            return;
+        };
+        let files = self.files.clone();
+        self.blocking(move || {
+            match files.open(&path, flags) {
+                Ok(fd) => log::info!("[fs] {} open as {}", path, fd),
+                Err(e) => log::warn!("[fs] open {}: {:?}", path, e),
+            }
+        });
+    }
+
+    fn read(&mut self, fd: Option<&str>, len: Option<&str>) {
+        let fd = fd.and_then(|fd| fd.parse().ok()).map(fs::Fd);
+        let len = len.map_or(Some(4096), |len| len.parse::<usize>().ok());
+        let (Some(fd), Some(len)) = (fd, len) else {
+            self.logger.write_str("usage: read <fd> [len]\n").unwrap();
+            return;
+        };
+        let files = self.files.clone();
+        self.blocking(move || {
+            let mut buffer = alloc::vec![0u8; len];
+            match files.read(fd, &mut buffer) {
+                Ok(read) => log::info!("[fs] {} bytes from {}:\n{}", read, fd, String::from_utf8_lossy(&buffer[..read])),
+                Err(e) => log::warn!("[fs] read {}: {:?}", fd, e),
+            }
+        });
+    }
+
+    fn write(&mut self, fd: Option<&str>, text: String) {
+        let Some(fd) = fd.and_then(|fd| fd.parse().ok()).map(fs::Fd) else {
+            self.logger.write_str("usage: write <fd> <text>\n").unwrap();
+            return;
+        };
+        let files = self.files.clone();
+        self.blocking(move || {
+            if let Err(e) = files.write(fd, text.as_bytes()) {
+                log::warn!("[fs] write {}: {:?}", fd, e);
+            }
+        });
+    }
+
+    fn seek(&mut self, fd: Option<&str>, to: Option<&str>) {
+        let fd = fd.and_then(|fd| fd.parse().ok()).map(fs::Fd);
+        let to = to.and_then(|to| match to {
+            "end" => Some(SeekFrom::End(0)),
+            to if to.starts_with(['+', '-']) => to.parse().ok().map(SeekFrom::Current),
+            to => to.parse().ok().map(SeekFrom::Start),
+        });
+        let (Some(fd), Some(to)) = (fd, to) else {
+            self.logger.write_str("usage: seek <fd> <offset|+offset|-offset|end>\n").unwrap();
+            return;
+        };
+        let files = self.files.clone();
+        self.blocking(move || {
+            match files.seek(fd, to) {
+                Ok(position) => log::info!("[fs] {} at {}", fd, position),
+                Err(e) => log::warn!("[fs] seek {}: {:?}", fd, e),
+            }
+        });
+    }
+
+    fn close(&mut self, fd: Option<&str>) {
+        let Some(fd) = fd.and_then(|fd| fd.parse().ok()).map(fs::Fd) else {
+            self.logger.write_str("usage: close <fd>\n").unwrap();
+            return;
+        };
+        if let Err(e) = self.files.close(fd) {
+            writeln!(self.logger, "close: {:?}", e).unwrap();
+        }
+    }
+
+    fn hostfs(&mut self, path: Option<&str>) {
+        let Some(tag) = ninep::tag() else {
+            self.logger.write_str("no host share mounted\n").unwrap();
+            return;
+        };
+
+        let path = path.unwrap_or("");
+        match ninep::read_dir(path) {
+            Ok(names) => {
+                writeln!(self.logger, "{}:/{}", tag, path.trim_start_matches('/')).unwrap();
+                for name in names {
+                    writeln!(self.logger, "  {}", name).unwrap();
+                }
+            },
+            Err(e) => writeln!(self.logger, "hostfs: {:?}", e).unwrap(),
+        }
+    }
+}
+
+enum GptCommand {
+    List,
+    Init,
+    Add(u128, u64, u64, String),
+    Delete(usize),
+    Resize(usize, u64),
+}
+
+async fn gpt_command(disk: Arc<block::Disk>, command: GptCommand) -> Result<(), GptError> {
+    let mut editor = match command {
+        GptCommand::Init => GptEditor::create(disk.clone())?,
+        _ => GptEditor::open(disk.clone()).await?,
+    };
+    match command {
+        GptCommand::List => {
+            let (first, last) = editor.usable();
+            log::info!("[gpt] {}: usable LBAs {}-{}", disk.name(), first, last);
+            for (index, entry) in editor.partitions() {
+                log::info!("[gpt] {} {}: {} [{}-{}] {}", disk.name(), index, gpt::guid_to_str(entry.partition_type_guid),
+                    entry.starting_lba, entry.ending_lba, entry.name());
+            }
+            return Ok(());
+        },
+        GptCommand::Init => {},
+        GptCommand::Add(type_guid, first, last, name) => {
+            let index = editor.add(type_guid, first, last, &name)?;
+            log::info!("[gpt] {}: added partition {}", disk.name(), index);
+        },
+        GptCommand::Delete(index) => editor.delete(index)?,
+        GptCommand::Resize(index, last) => editor.resize(index, last)?,
+    }
+
+    editor.write().await?;
+    match block::partition::rescan(&disk).await {
+        Ok(count) => log::info!("[gpt] {}: table written, {} partitions", disk.name(), count),
+        Err(e) => log::warn!("[gpt] {}: table written but not found again: {:?}", disk.name(), e),
+    }
+    Ok(())
+}
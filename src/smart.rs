@@ -0,0 +1,148 @@
+// SMART drive health.
+//
+// Drivers which support it return the attribute table of SMART READ DATA and the verdict of
+// SMART RETURN STATUS as a `Health`. Attributes are kept as the drive reports them, the
+// well-known ones have accessors that decode the raw value.
+
+use alloc::vec::Vec;
+use core::fmt;
+use shared_lib::bytes::read_u16_le;
+use crate::ide::{AtaError, SECTOR_SIZE};
+
+const ATTRIBUTES_OFFSET: usize = 2;
+const ATTRIBUTE_SIZE: usize = 12;
+const MAX_ATTRIBUTES: usize = 30;
+
+pub const REALLOCATED_SECTORS: u8 = 5;
+pub const POWER_ON_HOURS: u8 = 9;
+pub const POWER_CYCLES: u8 = 12;
+pub const AIRFLOW_TEMPERATURE: u8 = 190;
+pub const TEMPERATURE: u8 = 194;
+pub const PENDING_SECTORS: u8 = 197;
+pub const UNCORRECTABLE_SECTORS: u8 = 198;
+
+pub struct Attribute {
+    pub id: u8,
+    pub flags: u16,
+    /// Normalized, higher is better
+    pub value: u8,
+    pub worst: u8,
+    /// 48 bits, the meaning is up to the vendor
+    pub raw: u64,
+}
+
+pub struct Health {
+    /// False if the drive predicts a failure
+    pub passed: bool,
+    pub attributes: Vec<Attribute>,
+}
+
+impl Health {
+    /// Parses the sector returned by SMART READ DATA
+    pub fn parse(data: &[u8], passed: bool) -> Result<Health, AtaError> {
+        if data.len() < SECTOR_SIZE {
+            return Err(AtaError::InvalidBufferSize);
+        }
+        // the last byte makes the sum zero
+        if data[..SECTOR_SIZE].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(AtaError::BadChecksum);
+        }
+
+        let attributes = data[ATTRIBUTES_OFFSET..ATTRIBUTES_OFFSET + MAX_ATTRIBUTES * ATTRIBUTE_SIZE]
+            .chunks_exact(ATTRIBUTE_SIZE)
+            .filter(|bytes| bytes[0] != 0)
+            .map(|bytes| {
+                let mut raw = [0u8; 8];
+                raw[..6].copy_from_slice(&bytes[5..11]);
+                Attribute { id: bytes[0], flags: read_u16_le(bytes, 1), value: bytes[3], worst: bytes[4], raw: u64::from_le_bytes(raw) }
+            })
+            .collect();
+        Ok(Health { passed, attributes })
+    }
+
+    pub fn attribute(&self, id: u8) -> Option<&Attribute> {
+        self.attributes.iter().find(|attribute| attribute.id == id)
+    }
+
+    pub fn reallocated_sectors(&self) -> Option<u64> {
+        self.attribute(REALLOCATED_SECTORS).map(|attribute| attribute.raw)
+    }
+
+    pub fn pending_sectors(&self) -> Option<u64> {
+        self.attribute(PENDING_SECTORS).map(|attribute| attribute.raw)
+    }
+
+    pub fn uncorrectable_sectors(&self) -> Option<u64> {
+        self.attribute(UNCORRECTABLE_SECTORS).map(|attribute| attribute.raw)
+    }
+
+    pub fn power_on_hours(&self) -> Option<u64> {
+        // some drives keep minutes or milliseconds in the upper bytes
+        self.attribute(POWER_ON_HOURS).map(|attribute| attribute.raw & 0xffff_ffff)
+    }
+
+    /// In °C, the low byte of the raw value, the others hold the minimum and maximum on some
+    /// drives
+    pub fn temperature(&self) -> Option<u8> {
+        self.attribute(TEMPERATURE).or_else(|| self.attribute(AIRFLOW_TEMPERATURE))
+            .map(|attribute| attribute.raw as u8)
+    }
+}
+
+pub fn attribute_name(id: u8) -> &'static str {
+    match id {
+        1 => "Raw_Read_Error_Rate",
+        3 => "Spin_Up_Time",
+        4 => "Start_Stop_Count",
+        REALLOCATED_SECTORS => "Reallocated_Sector_Ct",
+        7 => "Seek_Error_Rate",
+        POWER_ON_HOURS => "Power_On_Hours",
+        10 => "Spin_Retry_Count",
+        POWER_CYCLES => "Power_Cycle_Count",
+        AIRFLOW_TEMPERATURE => "Airflow_Temperature_Cel",
+        192 => "Power-Off_Retract_Count",
+        193 => "Load_Cycle_Count",
+        TEMPERATURE => "Temperature_Celsius",
+        196 => "Reallocated_Event_Count",
+        PENDING_SECTORS => "Current_Pending_Sector",
+        UNCORRECTABLE_SECTORS => "Offline_Uncorrectable",
+        199 => "UDMA_CRC_Error_Count",
+        _ => "Unknown_Attribute",
+    }
+}
+
+/// The `health` report of one drive
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "overall: {}", if self.passed { "PASSED" } else { "FAILING" })?;
+        writeln!(f, "ID  name                      value worst  raw")?;
+        for attribute in &self.attributes {
+            writeln!(f, "{:>3} {:<25} {:>5} {:>5}  {}", attribute.id, attribute_name(attribute.id), attribute.value,
+                attribute.worst, attribute.raw)?;
+        }
+        Ok(())
+    }
+}
+
+/// One line per drive for `health` without arguments, unknown values are left out
+pub struct Summary<'a>(pub &'a Health);
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let health = self.0;
+        f.write_str(if health.passed { "PASSED" } else { "FAILING" })?;
+        if let Some(temperature) = health.temperature() {
+            write!(f, ", {} C", temperature)?;
+        }
+        if let Some(hours) = health.power_on_hours() {
+            write!(f, ", {} hours on", hours)?;
+        }
+        if let Some(sectors) = health.reallocated_sectors() {
+            write!(f, ", {} reallocated", sectors)?;
+        }
+        if let Some(sectors) = health.pending_sectors() {
+            write!(f, ", {} pending", sectors)?;
+        }
+        Ok(())
+    }
+}
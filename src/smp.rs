@@ -0,0 +1,336 @@
+// Application processor bring-up.
+//
+// Every enabled Local APIC entry of the MADT other than the bootstrap processor's is started
+// with INIT-SIPI-SIPI. A startup IPI can only point a CPU at a page below 1 MiB, in real mode:
+// the loader keeps such a page (`ApTrampoline`), `init` copies `ap_trampoline` there and maps
+// it at its physical address. The trampoline switches to protected mode and to long mode with a
+// copy of the kernel's PML4 below 4 GiB (CR3 is only 32 bits wide until then), then loads the
+// real CR3 and CR4 of the bootstrap processor and calls `ap_main` on a stack of its own.
+//
+// The bootstrap processor allocates everything the CPU needs first, GDT, TSS, IST stacks, per-CPU
+// block and the kernel stack, since it holds the frame allocator, and starts one CPU at a time: the trampoline
+// data is shared. `ap_main` loads the tables, enables the local APIC and parks the CPU in a halt
+// loop with interrupts enabled, nothing runs on it yet.
+
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use shared_lib::addr::VirtAddr;
+use shared_lib::frame_allocator::{FrameAllocator, Zone};
+use shared_lib::get_tsc;
+use shared_lib::msr;
+use shared_lib::page_table::{map_address_with_offset, unmap_address_with_offset, PageTable, PageTableFlags};
+use shared_lib::phys_mapping_offset;
+use shared_lib::volatile::Mmio;
+use crate::apic::{self, LocalApicRegs};
+use crate::memory::active_level_4_table;
+use crate::task::timer;
+use crate::xsdt::LocalApic;
+use crate::{cmdline, cow, gdt, interrupts, percpu};
+
+const AP_STACK_PAGES: usize = 16;
+const EFER_LMA: u64 = 1 << 10;
+
+/// Used for the delays when the TSC isn't calibrated, faster than any CPU: the delays only get
+/// longer
+const FALLBACK_TSC_HZ: u64 = 5_000_000_000;
+const INIT_DELAY_US: u64 = 10_000;
+const STARTUP_DELAY_US: u64 = 200;
+const ONLINE_TIMEOUT_US: u64 = 100_000;
+
+/// Physical address of the trampoline page, 0 without one
+static TRAMPOLINE: AtomicU64 = AtomicU64::new(0);
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+/// Number of the last CPU which reached `ap_main`
+static CHECKED_IN: AtomicUsize = AtomicUsize::new(gdt::BSP);
+
+global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\"",
+    ".balign 16",
+    ".global ap_trampoline",
+    "ap_trampoline:",
+    ".code16",
+    "    cli",
+    "    cld",
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    xor ebx, ebx",
+    "    mov bx, ax",
+    "    shl ebx, 4",
+    // the far pointers and the GDT base depend on where the trampoline was copied
+    "    lea eax, [ebx + ap_protected_mode - ap_trampoline]",
+    "    mov [ap_protected_mode_target - ap_trampoline], eax",
+    "    lea eax, [ebx + ap_long_mode - ap_trampoline]",
+    "    mov [ap_long_mode_target - ap_trampoline], eax",
+    "    lea eax, [ebx + ap_gdt - ap_trampoline]",
+    "    mov [ap_gdtr - ap_trampoline + 2], eax",
+    "    lgdt [ap_gdtr - ap_trampoline]",
+    // PE and ET only: INIT leaves CD and NW set, caching stays off until they are cleared
+    "    mov eax, 0x11",
+    "    mov cr0, eax",
+    // jmp far dword [ap_protected_mode_target]
+    "    .byte 0x66, 0xFF, 0x2E",
+    "    .word ap_protected_mode_target - ap_trampoline",
+    ".code32",
+    "ap_protected_mode:",
+    "    mov ax, 0x10",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    // PAE, the low PML4, EFER with LME and paging
+    "    mov eax, cr4",
+    "    or eax, 1 << 5",
+    "    mov cr4, eax",
+    "    mov eax, [ebx + ap_low_cr3 - ap_trampoline]",
+    "    mov cr3, eax",
+    "    mov ecx, 0xC0000080",
+    "    mov eax, [ebx + ap_efer - ap_trampoline]",
+    "    mov edx, [ebx + ap_efer - ap_trampoline + 4]",
+    "    wrmsr",
+    // PG, WP, NE, ET, MP and PE, what the firmware left on the bootstrap processor
+    "    mov eax, 0x80010033",
+    "    mov cr0, eax",
+    // jmp far dword [ebx + ap_long_mode_target]
+    "    .byte 0xFF, 0xAB",
+    "    .long ap_long_mode_target - ap_trampoline",
+    ".code64",
+    "ap_long_mode:",
+    "    mov ebx, ebx",
+    "    mov rax, [rbx + ap_cr4 - ap_trampoline]",
+    "    mov cr4, rax",
+    "    mov rax, [rbx + ap_cr3 - ap_trampoline]",
+    "    mov cr3, rax",
+    "    mov rsp, [rbx + ap_stack - ap_trampoline]",
+    "    mov rdi, [rbx + ap_cpu - ap_trampoline]",
+    "    mov rax, [rbx + ap_entry - ap_trampoline]",
+    // the end of the frame pointer chain
+    "    xor ebp, ebp",
+    "    call rax",
+    "    ud2",
+    ".balign 8",
+    "ap_protected_mode_target:",
+    "    .long 0",
+    "    .word 0x08",
+    ".balign 8",
+    "ap_long_mode_target:",
+    "    .long 0",
+    "    .word 0x18",
+    ".balign 8",
+    // null, 32-bit code, data, 64-bit code
+    "ap_gdt:",
+    "    .quad 0",
+    "    .quad 0x00CF9A000000FFFF",
+    "    .quad 0x00CF92000000FFFF",
+    "    .quad 0x00AF9A000000FFFF",
+    "ap_gdtr:",
+    "    .word 31",
+    "    .long 0",
+    // `TrampolineData`
+    ".balign 8",
+    ".global ap_trampoline_data",
+    "ap_trampoline_data:",
+    "ap_low_cr3: .quad 0",
+    "ap_efer: .quad 0",
+    "ap_cr3: .quad 0",
+    "ap_cr4: .quad 0",
+    "ap_stack: .quad 0",
+    "ap_entry: .quad 0",
+    "ap_cpu: .quad 0",
+    ".global ap_trampoline_end",
+    "ap_trampoline_end:",
+    ".popsection",
+);
+
+extern "C" {
+    fn ap_trampoline();
+    fn ap_trampoline_data();
+    fn ap_trampoline_end();
+}
+
+/// Filled in by the bootstrap processor for each CPU, in the order of the labels at
+/// `ap_trampoline_data`
+#[repr(C)]
+struct TrampolineData {
+    /// Copy of the kernel's PML4 below 4 GiB
+    low_cr3: u64,
+    efer: u64,
+    cr3: u64,
+    cr4: u64,
+    /// Top of the kernel stack
+    stack: u64,
+    entry: u64,
+    cpu: u64,
+}
+
+fn symbol_addr(symbol: unsafe extern "C" fn()) -> u64 {
+    symbol as u64
+}
+
+/// Keeps the trampoline page the loader reserved, if it passed one
+pub fn set_trampoline(addr: Option<u64>) {
+    TRAMPOLINE.store(addr.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// CPUs running the kernel, the bootstrap processor included
+pub fn online_cpus() -> usize {
+    ONLINE.load(Ordering::Relaxed)
+}
+
+fn delay_us(us: u64) {
+    let tsc_hz = timer::tsc_frequency().unwrap_or(FALLBACK_TSC_HZ);
+    let end = get_tsc() + tsc_hz / 1_000_000 * us;
+    while get_tsc() < end {
+        core::hint::spin_loop();
+    }
+}
+
+/// Waits up to `us` microseconds for `cpu` to reach `ap_main`
+fn wait_online(cpu: usize, us: u64) -> bool {
+    let tsc_hz = timer::tsc_frequency().unwrap_or(FALLBACK_TSC_HZ);
+    let end = get_tsc() + tsc_hz / 1_000_000 * us;
+    while get_tsc() < end {
+        if CHECKED_IN.load(Ordering::Acquire) == cpu {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Starts the application processors of `processors` and waits until each is online or timed
+/// out. Called by `preinit` on the bootstrap processor, once its local APIC and timer run.
+pub fn init(frame_allocator: &mut FrameAllocator, local_apic: VirtAddr, processors: &[LocalApic]) {
+    let bsp_apic_id = (core::arch::x86_64::__cpuid(1).ebx >> 24) as u8;
+    let aps = processors.iter().filter(|cpu| cpu.is_enabled() && cpu.apic_id != bsp_apic_id).count();
+    if aps == 0 {
+        return;
+    }
+    if !cmdline::smp_enabled() {
+        log::info!("nosmp: not starting {} application processors", aps);
+        return;
+    }
+    let trampoline = TRAMPOLINE.load(Ordering::Relaxed);
+    if trampoline == 0 {
+        log::warn!("No AP trampoline page, not starting {} application processors", aps);
+        return;
+    }
+    LOCAL_APIC.store(local_apic.0, Ordering::Relaxed);
+
+    let low_pml4 = match install_trampoline(frame_allocator, trampoline) {
+        Ok(low_pml4) => low_pml4,
+        Err(e) => {
+            log::warn!("Failed to install the AP trampoline: {}", e);
+            return;
+        }
+    };
+
+    let regs = unsafe { Mmio::<LocalApicRegs>::new(local_apic) };
+    let mut cpu = gdt::BSP + 1;
+    for processor in processors.iter().filter(|cpu| cpu.is_enabled() && cpu.apic_id != bsp_apic_id) {
+        if cpu >= gdt::MAX_CPUS {
+            log::warn!("More than {} CPUs, the others stay offline", gdt::MAX_CPUS);
+            break;
+        }
+        match start_ap(frame_allocator, &regs, trampoline, low_pml4, processor.apic_id, cpu) {
+            Ok(()) => cpu += 1,
+            Err(e) => log::warn!("CPU with APIC ID {} did not start: {}", processor.apic_id, e),
+        }
+    }
+
+    unsafe {
+        let _ = unmap_address_with_offset(active_level_4_table(), VirtAddr::new(trampoline), phys_mapping_offset());
+        frame_allocator.deallocate_frame(low_pml4);
+    }
+    log::info!("SMP: {} of {} CPUs online", online_cpus(), aps + 1);
+}
+
+/// Copies the trampoline to its page, maps the page at its physical address and returns the
+/// low PML4
+fn install_trampoline(frame_allocator: &mut FrameAllocator, trampoline: u64) -> Result<u64, &'static str> {
+    let start = symbol_addr(ap_trampoline);
+    let len = (symbol_addr(ap_trampoline_end) - start) as usize;
+    unsafe {
+        core::ptr::copy_nonoverlapping(start as *const u8, (trampoline + phys_mapping_offset()) as *mut u8, len);
+        map_address_with_offset(active_level_4_table(), VirtAddr::new(trampoline), trampoline,
+            PageTableFlags::WRITABLE, frame_allocator, phys_mapping_offset())?;
+    }
+
+    // after the identity mapping: the copy shares its page tables
+    let low_pml4 = frame_allocator.allocate_frame_in_zone(Zone::Dma32).ok_or("no frame below 4 GiB for the PML4")?;
+    unsafe {
+        let kernel_pml4 = active_level_4_table() as *const PageTable;
+        core::ptr::copy_nonoverlapping(kernel_pml4, (low_pml4 + phys_mapping_offset()) as *mut PageTable, 1);
+    }
+    Ok(low_pml4)
+}
+
+fn start_ap(frame_allocator: &mut FrameAllocator, local_apic: &Mmio<LocalApicRegs>, trampoline: u64, low_pml4: u64,
+            apic_id: u8, cpu: usize) -> Result<(), &'static str> {
+    gdt::alloc_cpu(cpu, frame_allocator)?;
+    percpu::alloc_cpu(cpu, apic_id)?;
+    let stack = gdt::allocate_stack(AP_STACK_PAGES, "ap stack", frame_allocator)?;
+
+    let (cr3, cr4): (u64, u64);
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+    }
+    let data = TrampolineData {
+        low_cr3: low_pml4,
+        efer: unsafe { msr::read(msr::IA32_EFER) } & !EFER_LMA,
+        cr3,
+        cr4,
+        stack: stack.0,
+        entry: ap_main as extern "C" fn(u64) -> ! as u64,
+        cpu: cpu as u64,
+    };
+    let offset = symbol_addr(ap_trampoline_data) - symbol_addr(ap_trampoline);
+    unsafe {
+        core::ptr::write_volatile((trampoline + offset + phys_mapping_offset()) as *mut TrampolineData, data);
+    }
+
+    let page = (trampoline >> 12) as u8;
+    apic::send_init_ipi(local_apic, apic_id);
+    delay_us(INIT_DELAY_US);
+    apic::send_startup_ipi(local_apic, apic_id, page);
+    delay_us(STARTUP_DELAY_US);
+    // the second one is only needed if the CPU missed the first
+    if CHECKED_IN.load(Ordering::Acquire) != cpu {
+        apic::send_startup_ipi(local_apic, apic_id, page);
+    }
+
+    if !wait_online(cpu, ONLINE_TIMEOUT_US) {
+        // INIT parks it in wait-for-SIPI, so a late CPU can't run the trampoline after its page is
+        // unmapped or with the next CPU's data
+        apic::send_init_ipi(local_apic, apic_id);
+        delay_us(INIT_DELAY_US);
+        if CHECKED_IN.load(Ordering::Acquire) == cpu {
+            // it checked in after all, but INIT reset it and the next CPU gets its number
+            ONLINE.fetch_sub(1, Ordering::Relaxed);
+            CHECKED_IN.store(cpu - 1, Ordering::Release);
+        }
+        return Err("timed out");
+    }
+    Ok(())
+}
+
+extern "C" fn ap_main(cpu: u64) -> ! {
+    let cpu = cpu as usize;
+    if gdt::load_cpu(cpu).and_then(|()| percpu::load_cpu(cpu)).is_err() {
+        // nothing to report with, the bootstrap processor times out
+        crate::panic::halt();
+    }
+    interrupts::init_idt();
+    cow::enable_write_protect();
+    apic::init_ap(&unsafe { Mmio::<LocalApicRegs>::new(VirtAddr::new(LOCAL_APIC.load(Ordering::Relaxed))) });
+
+    ONLINE.fetch_add(1, Ordering::Relaxed);
+    CHECKED_IN.store(cpu, Ordering::Release);
+    log::info!("CPU {} online", cpu);
+
+    loop {
+        unsafe {
+            asm!("sti", "hlt", options(nomem, nostack));
+        }
+    }
+}
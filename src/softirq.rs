@@ -0,0 +1,71 @@
+// Deferred work of interrupt handlers (bottom halves).
+//
+// A handler does what can't wait, acknowledging the device and reading its data, and raises a
+// bottom half for the rest: a function pointer and one argument, pushed to a ring allocated by
+// `init`, so raising neither blocks nor allocates. The executor runs the pending bottom halves
+// before polling the next task, with interrupts enabled and in the order they were raised.
+//
+// Bottom halves run in the executor's context: they may take the locks tasks take and wake
+// tasks, but must not block.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+
+/// Bottom halves that can be pending at once
+const RING_SIZE: usize = 256;
+
+#[derive(Clone, Copy)]
+struct BottomHalf {
+    run: fn(u64),
+    arg: u64,
+}
+
+static RING: OnceCell<ArrayQueue<BottomHalf>> = OnceCell::uninit();
+
+static RAISED: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() {
+    RING.try_init_once(|| ArrayQueue::new(RING_SIZE))
+        .expect("softirq::init should only be called once");
+}
+
+/// Queues `run(arg)`, false if it was dropped because the ring is full or not set up yet
+///
+/// Must not block or allocate: called from interrupt handlers.
+pub fn raise(run: fn(u64), arg: u64) -> bool {
+    let Ok(ring) = RING.try_get() else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return false;
+    };
+    if ring.push(BottomHalf { run, arg }).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    RAISED.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+pub fn has_pending() -> bool {
+    RING.try_get().map_or(false, |ring| !ring.is_empty())
+}
+
+/// Runs the pending bottom halves, including those raised meanwhile, returns how many ran
+pub fn run_pending() -> usize {
+    let Ok(ring) = RING.try_get() else {
+        return 0;
+    };
+    let mut count = 0;
+    while let Some(bottom_half) = ring.pop() {
+        (bottom_half.run)(bottom_half.arg);
+        count += 1;
+    }
+    count
+}
+
+/// Part of the `irqstat` output
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    writeln!(out, "bottom halves: {} raised, {} dropped", RAISED.load(Ordering::Relaxed), DROPPED.load(Ordering::Relaxed))
+}
@@ -0,0 +1,144 @@
+// Kernel symbol table.
+//
+// The `symbols` file is generated from the kernel ELF by `disk_image` and loaded by the loader
+// next to the kernel. Layout (little endian):
+//   magic "FSYM", u32 count,
+//   count * { u64 addr, u32 size, u32 name offset } sorted by addr,
+//   NUL-terminated demangled names.
+
+use core::fmt;
+use conquer_once::spin::OnceCell;
+use shared_lib::bytes::{read_u32_le, read_u64_le};
+use shared_lib::stack_trace::{frame_pointer, walk_stack};
+
+const MAGIC: &[u8; 4] = b"FSYM";
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 16;
+const MAX_BACKTRACE_DEPTH: usize = 32;
+
+static SYMBOLS: OnceCell<SymbolTable> = OnceCell::uninit();
+
+#[derive(Debug)]
+pub enum SymbolsError {
+    BadMagic,
+    Truncated,
+}
+
+struct SymbolTable {
+    data: &'static [u8],
+    count: usize,
+    /// Added to the link-time addresses of the table, see `boot_info::KernelSlide`
+    slide: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub addr: u64,
+    pub offset: u64,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+impl SymbolTable {
+    fn new(data: &'static [u8], slide: u64) -> Result<Self, SymbolsError> {
+        if data.len() < HEADER_SIZE || &data[0..4] != MAGIC {
+            return Err(SymbolsError::BadMagic);
+        }
+
+        let count = read_u32_le(data, 4) as usize;
+        if data.len() < HEADER_SIZE + count * ENTRY_SIZE {
+            return Err(SymbolsError::Truncated);
+        }
+
+        Ok(SymbolTable { data, count, slide })
+    }
+
+    fn entry(&self, idx: usize) -> (u64, u64, usize) {
+        let offset = HEADER_SIZE + idx * ENTRY_SIZE;
+        (read_u64_le(self.data, offset), read_u32_le(self.data, offset + 8) as u64, read_u32_le(self.data, offset + 12) as usize)
+    }
+
+    fn name(&self, offset: usize) -> &'static str {
+        let names = &self.data[(HEADER_SIZE + self.count * ENTRY_SIZE).min(self.data.len())..];
+        let bytes = names.get(offset..).unwrap_or(&[]);
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        core::str::from_utf8(&bytes[..len]).unwrap_or("<bad name>")
+    }
+
+    fn resolve(&self, addr: u64) -> Option<Symbol> {
+        let addr = addr.checked_sub(self.slide)?;
+
+        // binary search for the first symbol starting after `addr`
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.entry(mid).0 <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        if low == 0 {
+            return None;
+        }
+
+        let (start, size, name_offset) = self.entry(low - 1);
+        if size != 0 && addr >= start + size {
+            return None;
+        }
+
+        Some(Symbol { name: self.name(name_offset), addr: start + self.slide, offset: addr - start })
+    }
+}
+
+/// Loads the symbol table passed by the loader. `addr` is a physical address, 0 means the loader
+/// didn't find a symbol file. `slide` is how far the kernel was moved from its link address.
+pub fn init(addr: u64, size: u64, slide: u64) -> Result<(), SymbolsError> {
+    if addr == 0 {
+        return Ok(());
+    }
+
+    let data = unsafe {
+        core::slice::from_raw_parts((addr + shared_lib::phys_mapping_offset()) as *const u8, size as usize)
+    };
+    let table = SymbolTable::new(data, slide)?;
+    log::info!("[symbols] loaded {} symbols, kernel slide {:#x}", table.count, slide);
+
+    SYMBOLS.init_once(move || table);
+    Ok(())
+}
+
+/// Finds the function containing `addr`.
+pub fn resolve(addr: u64) -> Option<Symbol> {
+    SYMBOLS.get()?.resolve(addr)
+}
+
+/// Formats `addr` as `name+offset` when it can be resolved.
+pub struct Resolved(pub u64);
+
+impl fmt::Display for Resolved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match resolve(self.0) {
+            Some(symbol) => write!(f, "{:#x} <{}>", self.0, symbol),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+/// Logs the call chain of the current function.
+pub fn print_backtrace() {
+    log::error!("Backtrace:");
+    let mut depth = 0;
+    unsafe {
+        walk_stack(frame_pointer(), |addr| {
+            log::error!("  #{}: {}", depth, Resolved(addr));
+            depth += 1;
+            depth < MAX_BACKTRACE_DEPTH
+        });
+    }
+}
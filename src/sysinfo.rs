@@ -0,0 +1,126 @@
+// System topology snapshot.
+//
+// A tree of what the kernel detected: CPUs, memory regions, PCI functions, block devices with
+// their partitions and network controllers. The code that detects something adds it to its
+// category as it goes and removes it when it goes away (PCI hot unplug), so this is the one place
+// to look instead of the boot log. `sysinfo` in the shell prints the tree, `find` looks a node up
+// by path, e.g. `block/disk0/part1`.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use shared_lib::spinlock::Spinlock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Cpus,
+    Memory,
+    Pci,
+    Block,
+    Network,
+}
+
+impl Category {
+    const ALL: [Category; 5] = [Category::Cpus, Category::Memory, Category::Pci, Category::Block, Category::Network];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Category::Cpus => "cpus",
+            Category::Memory => "memory",
+            Category::Pci => "pci",
+            Category::Block => "block",
+            Category::Network => "network",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub name: String,
+    pub properties: Vec<(&'static str, String)>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    pub fn new(name: impl Into<String>) -> Self {
+        Node { name: name.into(), properties: Vec::new(), children: Vec::new() }
+    }
+
+    pub fn with(mut self, key: &'static str, value: impl fmt::Display) -> Self {
+        self.properties.push((key, value.to_string()));
+        self
+    }
+
+    pub fn with_child(mut self, child: Node) -> Self {
+        self.children.push(child);
+        self
+    }
+
+    pub fn property(&self, key: &str) -> Option<&str> {
+        self.properties.iter().find(|(k, _)| *k == key).map(|(_, value)| value.as_str())
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    /// Node below this one at `path`, names separated by '/'
+    pub fn find(&self, path: &str) -> Option<&Node> {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(self, |node, name| node.child(name))
+    }
+
+    fn write(&self, out: &mut impl fmt::Write, depth: usize) -> fmt::Result {
+        write!(out, "{:indent$}{}", "", self.name, indent = depth * 2)?;
+        for (key, value) in &self.properties {
+            write!(out, " {}={}", key, value)?;
+        }
+        writeln!(out)?;
+        for child in &self.children {
+            child.write(out, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Nodes of each category, in the order of `Category::ALL`
+static SYSTEM_INFO: Spinlock<[Vec<Node>; 5]> = Spinlock::new([Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new()]);
+
+/// Adds `node` to `category`, replacing the node with the same name
+pub fn set(category: Category, node: Node) {
+    let mut info = SYSTEM_INFO.lock();
+    let nodes = &mut info[category as usize];
+    match nodes.iter_mut().find(|existing| existing.name == node.name) {
+        Some(existing) => *existing = node,
+        None => nodes.push(node),
+    }
+}
+
+pub fn remove(category: Category, name: &str) {
+    SYSTEM_INFO.lock()[category as usize].retain(|node| node.name != name);
+}
+
+/// Copy of the whole tree, the categories are the children of the root
+pub fn snapshot() -> Node {
+    let info = SYSTEM_INFO.lock();
+    let mut root = Node::new("system");
+    for category in Category::ALL {
+        root.children.push(Node { name: category.name().to_string(), properties: Vec::new(), children: info[category as usize].clone() });
+    }
+    root
+}
+
+/// Node at `path` from the root, e.g. `pci/00:1f.2`
+pub fn find(path: &str) -> Option<Node> {
+    snapshot().find(path).cloned()
+}
+
+/// Output of the `sysinfo` shell command, the subtree at `path` or everything
+pub fn dump(out: &mut impl fmt::Write, path: Option<&str>) -> fmt::Result {
+    let root = snapshot();
+    match root.find(path.unwrap_or("")) {
+        Some(node) => node.write(out, 0),
+        None => writeln!(out, "no such node"),
+    }
+}
@@ -0,0 +1,170 @@
+// Magic SysRq keys.
+//
+// Alt+SysRq+<key> triggers an emergency action, like Linux's magic SysRq. The combination is
+// recognized in the keyboard interrupt handler, before the scancodes reach the shell, so it works
+// even when the executor is wedged. The actions that only read atomics or can give up on a busy
+// lock run right there and print over serial; syncing blocks on the disks and is handed to
+// `sysrq_loop`, which runs the sync hooks on a kernel thread.
+//
+//   t: executor state and a backtrace of the interrupted context
+//   m: heap and frame allocator stats
+//   s: flush the registered filesystems
+//   b: reboot immediately
+//   any other key: list of the keys
+
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+use futures_util::task::AtomicWaker;
+use shared_lib::serial_println;
+use shared_lib::spinlock::Spinlock;
+use shared_lib::stack_trace::{frame_pointer, walk_stack};
+use crate::idt::InterruptStackFrame;
+use crate::symbols::Resolved;
+use crate::task::{executor, join, timer};
+
+// scancode set 1
+const ALT: u8 = 0x38;
+/// Sent by the Print Screen key while Alt is held
+const SYSRQ: u8 = 0x54;
+const RELEASED: u8 = 0x80;
+
+const KEY_T: u8 = 0x14;
+const KEY_M: u8 = 0x32;
+const KEY_S: u8 = 0x1F;
+const KEY_B: u8 = 0x30;
+
+const MAX_BACKTRACE_DEPTH: usize = 24;
+
+static ALT_DOWN: AtomicBool = AtomicBool::new(false);
+static SYSRQ_DOWN: AtomicBool = AtomicBool::new(false);
+
+static SYNC_REQUESTED: AtomicBool = AtomicBool::new(false);
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Flushes a filesystem or cache, called on Alt+SysRq+S from a kernel thread: it may block
+pub type SyncHook = fn() -> Result<(), &'static str>;
+
+static SYNC_HOOKS: Spinlock<Vec<(&'static str, SyncHook)>> = Spinlock::new(Vec::new());
+
+pub fn register_sync_hook(name: &'static str, hook: SyncHook) {
+    SYNC_HOOKS.lock().push((name, hook));
+}
+
+/// Called by the keyboard interrupt handler with every scancode. Returns true if the scancode
+/// belongs to a SysRq combination and must not reach the shell.
+///
+/// Must not block or allocate.
+pub(crate) fn handle_scancode(scancode: u8, stack_frame: &InterruptStackFrame) -> bool {
+    match scancode {
+        ALT => ALT_DOWN.store(true, Ordering::Relaxed),
+        code if code == ALT | RELEASED => {
+            ALT_DOWN.store(false, Ordering::Relaxed);
+            SYSRQ_DOWN.store(false, Ordering::Relaxed);
+        },
+        SYSRQ if ALT_DOWN.load(Ordering::Relaxed) => {
+            SYSRQ_DOWN.store(true, Ordering::Relaxed);
+            return true;
+        },
+        code if code == SYSRQ | RELEASED => {
+            return SYSRQ_DOWN.swap(false, Ordering::Relaxed);
+        },
+        code if SYSRQ_DOWN.load(Ordering::Relaxed) => {
+            if code & RELEASED == 0 {
+                run(code, stack_frame);
+            }
+            return true;
+        },
+        _ => {}
+    }
+    false
+}
+
+fn run(key: u8, stack_frame: &InterruptStackFrame) {
+    // whatever is wedged may be holding the serial port
+    unsafe { shared_lib::serial::SERIAL1.force_unlock() };
+
+    match key {
+        KEY_T => dump_tasks(stack_frame),
+        KEY_M => dump_memory(),
+        KEY_S => {
+            serial_println!("[sysrq] sync requested");
+            SYNC_REQUESTED.store(true, Ordering::Relaxed);
+            WAKER.wake();
+        },
+        KEY_B => {
+            serial_println!("[sysrq] rebooting");
+            crate::panic::reboot();
+        },
+        _ => serial_println!("[sysrq] keys: t (tasks), m (memory), s (sync), b (reboot)"),
+    }
+}
+
+fn dump_tasks(stack_frame: &InterruptStackFrame) {
+    let state = executor::state();
+    serial_println!("[sysrq] {} tasks, {} ready, tick {}", state.tasks, state.ready, timer::ticks());
+    match state.current {
+        Some((task, since)) => serial_println!("[sysrq] polling task {} since tick {}", task, since),
+        None => serial_println!("[sysrq] no task is being polled"),
+    }
+
+    serial_println!("[sysrq] interrupted at {}", Resolved(stack_frame.value.instruction_pointer.0));
+    serial_println!("[sysrq] backtrace:");
+    let mut depth = 0;
+    unsafe {
+        walk_stack(frame_pointer(), |addr| {
+            serial_println!("[sysrq]   #{}: {}", depth, Resolved(addr));
+            depth += 1;
+            depth < MAX_BACKTRACE_DEPTH
+        });
+    }
+}
+
+fn dump_memory() {
+    serial_println!("[sysrq] heap: {} KiB mapped, {} KiB max",
+        crate::allocator::heap_mapped_size() / 1024, crate::allocator::heap_max_size() / 1024);
+
+    // the interrupted code may be in the middle of an allocation
+    match crate::allocator::FRAME_ALLOCATOR.try_lock() {
+        Some(frame_allocator) => match frame_allocator.as_ref() {
+            Some(frame_allocator) => serial_println!("[sysrq] frames: {} free", frame_allocator.free_frames()),
+            None => serial_println!("[sysrq] frames: allocator not handed to the kernel yet"),
+        },
+        None => serial_println!("[sysrq] frames: allocator busy"),
+    }
+}
+
+/// Runs the sync requests of Alt+SysRq+S
+pub async fn sysrq_loop() {
+    loop {
+        poll_fn(|cx| {
+            if SYNC_REQUESTED.swap(false, Ordering::Relaxed) {
+                return Poll::Ready(());
+            }
+            WAKER.register(cx.waker());
+            if SYNC_REQUESTED.swap(false, Ordering::Relaxed) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }).await;
+
+        let hooks = SYNC_HOOKS.lock().clone();
+        if hooks.is_empty() {
+            log::info!("[sysrq] sync: no filesystems registered");
+            continue;
+        }
+        let spawned = join::spawn_blocking(move || {
+            for (name, hook) in hooks {
+                match hook() {
+                    Ok(()) => log::info!("[sysrq] sync: {} done", name),
+                    Err(e) => log::error!("[sysrq] sync: {} failed: {}", name, e),
+                }
+            }
+        });
+        if let Err(e) = spawned {
+            log::error!("[sysrq] sync: no thread to run it: {}", e);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+// Cooperative cancellation.
+//
+// A `CancellationToken` is shared by whoever may ask for a tear-down and the tasks doing the work.
+// The tasks check `is_cancelled` between steps or race `cancelled()` against what they wait for,
+// and clean up themselves. Child tokens are cancelled with their parent, so one token can stop a
+// task together with everything it started.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use shared_lib::interrupts::without_interrupts;
+use shared_lib::spinlock::Spinlock;
+
+struct Inner {
+    cancelled: AtomicBool,
+    /// Tasks waiting in `cancelled()`
+    wakers: Spinlock<Vec<Waker>>,
+    children: Spinlock<Vec<CancellationToken>>,
+}
+
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken {
+            inner: Arc::new(Inner {
+                cancelled: AtomicBool::new(false),
+                wakers: Spinlock::new(Vec::new()),
+                children: Spinlock::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// A token cancelled with this one, which can also be cancelled on its own
+    pub fn child_token(&self) -> CancellationToken {
+        let child = CancellationToken::new();
+        without_interrupts(|| {
+            let mut children = self.inner.children.lock();
+            // checked under the lock: `cancel` takes the children after setting the flag
+            if self.is_cancelled() {
+                child.inner.cancelled.store(true, Ordering::Release);
+            } else {
+                children.push(child.clone());
+            }
+        });
+        child
+    }
+
+    pub fn cancel(&self) {
+        if self.inner.cancelled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let (wakers, children) = without_interrupts(|| {
+            (core::mem::take(&mut *self.inner.wakers.lock()), core::mem::take(&mut *self.inner.children.lock()))
+        });
+        for waker in wakers {
+            waker.wake();
+        }
+        for child in children {
+            child.cancel();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Resolves once the token is cancelled
+    pub fn cancelled(&self) -> Cancelled {
+        Cancelled { token: self.clone() }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        CancellationToken::new()
+    }
+}
+
+pub struct Cancelled {
+    token: CancellationToken,
+}
+
+impl Future for Cancelled {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+
+        without_interrupts(|| {
+            let mut wakers = self.token.inner.wakers.lock();
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+        });
+
+        // `cancel` may have taken the wakers before ours was added
+        if self.token.is_cancelled() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
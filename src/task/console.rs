@@ -0,0 +1,43 @@
+use alloc::format;
+use chrono::DateTime;
+use shared_lib::logger::LOGGER;
+use crate::task::timer::{interval, now};
+
+/// Redraw period of the framebuffer log, ~60 fps
+const FRAME_MS: u64 = 16;
+
+/// Switches the framebuffer log to batched drawing and flushes it once per frame. The status bar
+/// is redrawn when the time page moves on to the next second.
+pub async fn console_flush_loop() {
+    let Ok(logger) = LOGGER.try_get() else {
+        // logging goes to serial
+        return;
+    };
+
+    logger.set_batching(true);
+    let mut shown_second = None;
+    let mut frames = interval(FRAME_MS);
+    loop {
+        frames.tick().await;
+
+        if let Some(time) = now() {
+            let second = time.uptime_ms / 1000;
+            if shown_second != Some(second) {
+                shown_second = Some(second);
+                logger.draw_status(&status_line(time.uptime_ms / 1000, time.wall_secs, time.ticks));
+            }
+        }
+
+        logger.flush();
+    }
+}
+
+fn status_line(uptime_secs: u64, wall_secs: u64, ticks: u64) -> alloc::string::String {
+    let uptime = format!("up {}d {:02}:{:02}:{:02}",
+        uptime_secs / 86400, uptime_secs / 3600 % 24, uptime_secs / 60 % 60, uptime_secs % 60);
+
+    match DateTime::from_timestamp(wall_secs as i64, 0).filter(|_| wall_secs != 0) {
+        Some(wall) => format!("{} | {} | ticks {}", uptime, wall, ticks),
+        None => format!("{} | ticks {}", uptime, ticks),
+    }
+}
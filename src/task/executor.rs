@@ -1,53 +1,278 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc};
+use super::{Priority, Task, TaskId};
+use alloc::{boxed::Box, collections::{BTreeMap, VecDeque}, sync::Arc, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
 use core::task::Waker;
 use crossbeam_queue::ArrayQueue;
 use core::task::{Context, Poll};
 use alloc::task::Wake;
 use core::arch::asm;
-use core::sync::atomic::AtomicBool;
-use core::sync::atomic::Ordering::Relaxed;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize};
+use core::sync::atomic::Ordering::{self, Relaxed};
+use shared_lib::get_tsc;
+use shared_lib::interrupts::without_interrupts;
+use shared_lib::spinlock::Spinlock;
+use crate::percpu::{self, NO_TASK};
+use crate::{config, idle, softirq, thread, watchdog};
+use super::timer;
 
 pub static STOP: AtomicBool = AtomicBool::new(false);
 
+// what the executor is doing is kept in the per-CPU block, readable from interrupt handlers
+// (see `sysrq`)
+static TASK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Snapshot of the executor state for debugging a wedged system
+pub struct ExecutorState {
+    pub tasks: usize,
+    /// Tasks in the ready queues of this CPU
+    pub ready: usize,
+    /// Task being polled right now on this CPU and the timer tick at which its poll started
+    pub current: Option<(u64, u64)>,
+}
+
+pub fn state() -> ExecutorState {
+    let cpu = percpu::current();
+    let current = cpu.current_task.load(Relaxed);
+    ExecutorState {
+        tasks: TASK_COUNT.load(Relaxed),
+        ready: cpu.run_queue().map_or(0, |queues| queues.len()),
+        current: (current != NO_TASK).then(|| (current, cpu.poll_started.load(Relaxed))),
+    }
+}
+
+/// Accounting of one task, see `tasks`
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: &'static str,
+    pub priority: Priority,
+    pub polls: u64,
+    /// TSC cycles spent polling the task, in total and in its longest poll
+    pub poll_cycles: u64,
+    pub max_poll_cycles: u64,
+    /// Timer tick of the last poll, None before the first one
+    pub last_polled: Option<u64>,
+}
+
+/// Updated by the executor after each poll, read by `tasks` from any task
+static TASK_TABLE: Spinlock<BTreeMap<TaskId, TaskInfo>> = Spinlock::new(BTreeMap::new());
+
+/// Accounting of every live task, by ID
+pub fn tasks() -> Vec<TaskInfo> {
+    without_interrupts(|| TASK_TABLE.lock().values().cloned().collect())
+}
+
+/// Output of the `ps` shell command
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    let tsc_khz = timer::tsc_frequency().map(|hz| hz / 1000);
+    writeln!(out, "  id name                     prio       polls    cpu ms  max us  last tick")?;
+    for task in tasks() {
+        write!(out, "{:>4} {:<24} {:<6} {:>11}", task.id.as_u64(), task.name, task.priority.name(), task.polls)?;
+        match tsc_khz {
+            Some(khz) if khz != 0 => write!(out, " {:>9} {:>7}", task.poll_cycles / khz, task.max_poll_cycles * 1000 / khz)?,
+            _ => write!(out, " {:>9} {:>7}", "?", "?")?,
+        }
+        match task.last_polled {
+            Some(tick) => writeln!(out, "  {}", tick)?,
+            None => writeln!(out, "  never")?,
+        }
+    }
+    Ok(())
+}
+
+/// Records a poll of `task_id` that took `cycles`, warns if it went over the poll budget
+fn account_poll(task_id: TaskId, cycles: u64) {
+    let name = without_interrupts(|| {
+        let mut table = TASK_TABLE.lock();
+        let info = table.get_mut(&task_id)?;
+        info.polls += 1;
+        info.poll_cycles += cycles;
+        info.max_poll_cycles = info.max_poll_cycles.max(cycles);
+        info.last_polled = Some(timer::ticks());
+        Some(info.name)
+    });
+
+    let Some(tsc_hz) = timer::tsc_frequency() else {
+        return;
+    };
+    let budget_ms = config::poll_budget_ms();
+    if budget_ms != 0 && cycles > budget_ms * (tsc_hz / 1000) {
+        // a task holding the CPU this long delays input and timers
+        log::warn!("[executor] task {} ({}) ran {} us in a single poll, budget is {} ms",
+            task_id.as_u64(), name.unwrap_or("?"), cycles / (tsc_hz / 1_000_000).max(1), budget_ms);
+    }
+}
+
+/// Task spawned through `join::spawn`, picked up by the executor from its loop
+struct Submitted {
+    task_id: TaskId,
+    name: &'static str,
+    priority: Priority,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+static SUBMITTED: Spinlock<VecDeque<Submitted>> = Spinlock::new(VecDeque::new());
+
+/// Hands a task to the executor, from a task, a thread or anywhere but an interrupt handler
+pub(super) fn submit(future: Pin<Box<dyn Future<Output = ()> + Send>>, name: &'static str, priority: Priority) -> TaskId {
+    let task_id = TaskId::new();
+    without_interrupts(|| SUBMITTED.lock().push_back(Submitted { task_id, name, priority, future }));
+    task_id
+}
+
+fn has_submitted() -> bool {
+    without_interrupts(|| !SUBMITTED.lock().is_empty())
+}
+
+/// Live tasks an executor can hold. A task is at most once in a ready queue, so the queues
+/// never overflow.
+pub const MAX_TASKS: usize = 16384;
+
+/// Polls of higher priority tasks a ready lower priority one waits at most for
+const STARVATION_LIMIT: usize = 16;
+
+/// One ready queue per priority.
+///
+/// `pop` takes from the highest priority queue that isn't empty, except that a waiting lower
+/// priority task is taken once higher priority ones were polled `STARVATION_LIMIT` times in a row
+/// ahead of it: a task that keeps waking itself up can't starve the background tasks.
+pub struct RunQueues {
+    queues: [ArrayQueue<TaskId>; Priority::COUNT],
+}
+
+impl RunQueues {
+    fn new() -> Self {
+        RunQueues {
+            queues: [ArrayQueue::new(MAX_TASKS), ArrayQueue::new(MAX_TASKS), ArrayQueue::new(MAX_TASKS)],
+        }
+    }
+
+    fn push(&self, task_id: TaskId, priority: Priority) -> Result<(), TaskId> {
+        self.queues[priority as usize].push(task_id)
+    }
+
+    /// `skipped` counts the polls each queue waited for, kept by the caller across calls
+    fn pop(&self, skipped: &mut [usize; Priority::COUNT]) -> Option<TaskId> {
+        let first = self.queues.iter().position(|queue| !queue.is_empty())?;
+        let starving = (first + 1..Priority::COUNT)
+            .find(|&lower| skipped[lower] >= STARVATION_LIMIT && !self.queues[lower].is_empty());
+        let taken = starving.unwrap_or(first);
+
+        for level in taken + 1..Priority::COUNT {
+            if self.queues[level].is_empty() {
+                skipped[level] = 0;
+            } else {
+                skipped[level] += 1;
+            }
+        }
+        skipped[taken] = 0;
+        self.queues[taken].pop()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+}
+
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    task_queue: Arc<RunQueues>,
+    /// The one waker of each task, created on spawn
+    waker_cache: BTreeMap<TaskId, (Arc<TaskWaker>, Waker)>,
+    skipped: [usize; Priority::COUNT],
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queue: Arc::new(RunQueues::new()),
             waker_cache: BTreeMap::new(),
+            skipped: [0; Priority::COUNT],
         }
     }
 
     pub fn spawn(&mut self, task: Task) {
         let task_id = task.id;
+        let priority = task.priority;
+        if self.tasks.len() >= MAX_TASKS {
+            panic!("too many tasks");
+        }
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        let name = self.tasks[&task_id].name;
+        without_interrupts(|| TASK_TABLE.lock().insert(task_id, TaskInfo {
+            id: task_id,
+            name,
+            priority,
+            polls: 0,
+            poll_cycles: 0,
+            max_poll_cycles: 0,
+            last_polled: None,
+        }));
+        crate::trace_task_spawn!(task_id.0);
+        let task_waker = TaskWaker::new(task_id, priority, self.task_queue.clone());
+        let waker = Waker::from(task_waker.clone());
+        self.waker_cache.insert(task_id, (task_waker.clone(), waker));
+        task_waker.wake_task();
+        TASK_COUNT.store(self.tasks.len(), Relaxed);
+    }
+
+    fn spawn_submitted(&mut self) {
+        while let Some(submitted) = without_interrupts(|| SUBMITTED.lock().pop_front()) {
+            self.spawn(Task {
+                id: submitted.task_id,
+                name: submitted.name,
+                priority: submitted.priority,
+                future: submitted.future,
+            });
+        }
     }
 
     fn run_ready_tasks(&mut self) {
-        while let Some(task_id) = self.task_queue.pop() {
-            let task = match self.tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue
+        loop {
+            // deferred interrupt work goes first, it may wake tasks
+            softirq::run_pending();
+            // tasks spawned by the last poll are queued behind the ready ones
+            self.spawn_submitted();
+            let Some(task_id) = self.task_queue.pop(&mut self.skipped) else {
+                break;
+            };
+            let (task, (task_waker, waker)) = match (self.tasks.get_mut(&task_id), self.waker_cache.get(&task_id)) {
+                (Some(task), Some(waker)) => (task, waker),
+                // woken through the waker of a finished task whose ID was reused
+                _ => continue
             };
-            let waker = self.waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, self.task_queue.clone()));
+            // a wakeup from here on queues the task again
+            task_waker.queued.store(false, Ordering::Release);
             let mut context = Context::from_waker(waker);
 
-            match task.poll(&mut context) {
+            crate::trace_task_poll!(task_id.0);
+            let cpu = percpu::current();
+            cpu.poll_started.store(timer::ticks(), Relaxed);
+            cpu.current_task.store(task_id.0, Relaxed);
+            let start = get_tsc();
+            let result = task.poll(&mut context);
+            let cycles = get_tsc().wrapping_sub(start);
+            cpu.current_task.store(NO_TASK, Relaxed);
+            cpu.stats.polls.fetch_add(1, Relaxed);
+            crate::trace_task_poll_end!(task_id.0, result.is_ready());
+            account_poll(task_id, cycles);
+
+            match result {
                 Poll::Ready(()) => {
+                    without_interrupts(|| TASK_TABLE.lock().remove(&task_id));
                     self.tasks.remove(&task_id);
                     self.waker_cache.remove(&task_id);
+                    task_id.release();
+                    TASK_COUNT.store(self.tasks.len(), Relaxed);
                 }
                 Poll::Pending => {}
             }
@@ -55,23 +280,43 @@ impl Executor {
     }
 
     pub fn run(&mut self) {
+        percpu::current().set_run_queue(self.task_queue.clone()).expect("Failed to register the run queue");
+        watchdog::arm();
         while !STOP.load(Relaxed) {
+            watchdog::heartbeat();
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
+        watchdog::disarm();
     }
 
+    /// Bottom halves to run or tasks to poll, ready or spawned through `join::spawn`
+    fn has_work(&self) -> bool {
+        softirq::has_pending() || !self.task_queue.is_empty() || has_submitted()
+    }
+
+    /// Halts the CPU until the next interrupt when no task and no other thread can run. Every
+    /// queue is checked again with interrupts disabled: a task woken by an interrupt handler after
+    /// that check leaves the interrupt pending, which ends the halt right away.
     fn sleep_if_idle(&self) {
+        if self.has_work() {
+            return;
+        }
+        // let the other threads run rather than halting the CPU under them
+        if thread::has_ready() {
+            thread::yield_now();
+            return;
+        }
+        let predicted_idle = timer::next_wakeup_ticks();
+
         // disable interrupts
         unsafe {
             asm!("cli", options(preserves_flags, nostack));
         }
 
-        if self.task_queue.is_empty() {
-            // enable and hlt
-            unsafe {
-                asm!("sti; hlt", options(nomem, nostack));
-            }
+        if !self.has_work() && !thread::has_ready() {
+            // enables interrupts
+            idle::enter(predicted_idle);
         } else {
             // enable interrupts
             unsafe {
@@ -83,19 +328,26 @@ impl Executor {
 
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    priority: Priority,
+    task_queue: Arc<RunQueues>,
+    /// Set while the task is in a ready queue, further wakeups don't queue it again
+    queued: AtomicBool,
 }
 
 impl TaskWaker {
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.task_queue.push(self.task_id, self.priority).expect("task_queue full");
+        }
     }
 
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
+    fn new(task_id: TaskId, priority: Priority, task_queue: Arc<RunQueues>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
             task_id,
+            priority,
             task_queue,
-        }))
+            queued: AtomicBool::new(false),
+        })
     }
 }
 
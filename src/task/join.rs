@@ -0,0 +1,133 @@
+// Tasks started from other tasks.
+//
+// `spawn` queues the task for the executor and returns a `JoinHandle`, a future resolving with
+// the task's output. Dropping the handle detaches the task. `JoinHandle::cancel` drops the task's
+// future the next time the executor would poll it, at one of its await points; tasks that must
+// clean up take a `CancellationToken` instead.
+//
+// `spawn_blocking` runs a closure that blocks the CPU for long, like a PIO transfer, on a kernel
+// thread of its own; the timer preempts it and the executor keeps polling the other tasks.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+use shared_lib::interrupts::without_interrupts;
+use shared_lib::spinlock::Spinlock;
+use crate::thread;
+use super::cancel::CancellationToken;
+use super::sync::channel;
+use super::{executor, task_name, Priority, TaskId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was cancelled through its handle before it finished
+    Cancelled,
+}
+
+struct JoinState<T> {
+    result: Spinlock<Option<Result<T, JoinError>>>,
+    /// Set once `result` was filled, also after the handle took it
+    finished: AtomicBool,
+    waker: AtomicWaker,
+    cancel: CancellationToken,
+}
+
+impl<T> JoinState<T> {
+    fn finish(&self, result: Result<T, JoinError>) {
+        without_interrupts(|| *self.result.lock() = Some(result));
+        self.finished.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+}
+
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Stops the task at its next await point, the handle then resolves with `JoinError::Cancelled`
+    pub fn cancel(&self) {
+        self.state.cancel.cancel();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.finished.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.state.waker.register(cx.waker());
+        if !self.state.finished.load(Ordering::Acquire) {
+            return Poll::Pending;
+        }
+        match without_interrupts(|| self.state.result.lock().take()) {
+            Some(result) => Poll::Ready(result),
+            None => panic!("JoinHandle polled after completion"),
+        }
+    }
+}
+
+/// Starts `future` as a task of normal priority
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn_with_priority(future, Priority::Normal)
+}
+
+pub fn spawn_with_priority<F>(future: F, priority: Priority) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let state = Arc::new(JoinState {
+        result: Spinlock::new(None),
+        finished: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+        cancel: CancellationToken::new(),
+    });
+
+    let task_state = state.clone();
+    let id = executor::submit(Box::pin(async move {
+        let mut future = core::pin::pin!(future);
+        let mut cancelled = core::pin::pin!(task_state.cancel.cancelled());
+        let result = core::future::poll_fn(|cx| {
+            if cancelled.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(JoinError::Cancelled));
+            }
+            future.as_mut().poll(cx).map(Ok)
+        }).await;
+        task_state.finish(result);
+    }), task_name::<F>(), priority);
+    JoinHandle { id, state }
+}
+
+/// Runs `f` on a new kernel thread, the handle resolves with its result. The thread can't be
+/// stopped: `JoinHandle::cancel` only drops the result.
+pub fn spawn_blocking<F, T>(f: F) -> Result<JoinHandle<T>, &'static str>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, mut receiver) = channel(1);
+    thread::spawn("blocking", move || {
+        // fails only if the handle was cancelled
+        let _ = sender.try_send(f());
+    })?;
+    Ok(spawn(async move {
+        receiver.recv().await.expect("blocking thread ended without a result")
+    }))
+}
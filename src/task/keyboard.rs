@@ -1,25 +1,25 @@
 use conquer_once::spin::OnceCell;
 use core::{pin::Pin, task::{Poll, Context}};
-use crossbeam_queue::ArrayQueue;
 use futures_util::stream::{Stream, StreamExt};
-use futures_util::task::AtomicWaker;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use shared_lib::out;
 use shared_lib::logger::LOGGER;
 use crate::shell::Shell;
+use crate::task::sync::{channel, Receiver, Sender, TrySendError};
 
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
-static WAKER: AtomicWaker = AtomicWaker::new();
+static SCANCODE_SENDER: OnceCell<Sender<u8>> = OnceCell::uninit();
 
-/// Called by the keyboard interrupt handler
-///
-/// Must not block or allocate.
-pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
-            log::warn!("scancode queue full; dropping keyboard input");
-        } else {
-            WAKER.wake();
+/// Bottom half of the keyboard interrupt, `arg` is the scancode
+pub(crate) fn scancode_bottom_half(arg: u64) {
+    add_scancode(arg as u8);
+}
+
+fn add_scancode(scancode: u8) {
+    if let Ok(sender) = SCANCODE_SENDER.try_get() {
+        match sender.try_send(scancode) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => log::warn!("scancode queue full; dropping keyboard input"),
+            Err(TrySendError::Closed(_)) => log::warn!("scancode reader is gone; dropping keyboard input"),
         }
     } else {
         log::warn!("scancode queue uninitialized");
@@ -27,37 +27,23 @@ pub(crate) fn add_scancode(scancode: u8) {
 }
 
 pub struct ScancodeStream {
-    _private: ()
+    receiver: Receiver<u8>,
 }
 
 impl ScancodeStream {
     pub fn new() -> Self {
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100))
+        let (sender, receiver) = channel(100);
+        SCANCODE_SENDER.try_init_once(|| sender)
             .expect("ScancodeStream::new should only be called once");
-        ScancodeStream{ _private: () }
+        ScancodeStream { receiver }
     }
 }
 
 impl Stream for ScancodeStream {
     type Item = u8;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        let queue = SCANCODE_QUEUE.try_get().expect("not initialized");
-
-        let scancode = queue.pop();
-        if scancode.is_some() {
-            return Poll::Ready(scancode)
-        }
-
-        WAKER.register(&cx.waker());
-
-        match queue.pop() {
-            Some(scancode) => {
-                WAKER.take();
-                Poll::Ready(Some(scancode))
-            },
-            None => Poll::Pending
-        }
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
     }
 }
 
@@ -1,36 +1,120 @@
 pub mod keyboard;
 pub mod executor;
 pub mod timer;
+pub mod console;
+pub mod cancel;
+pub mod join;
+pub mod sync;
 
 use core::{future::Future, pin::Pin};
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::task::{Context, Poll};
 use core::sync::atomic::{AtomicU64, Ordering};
+use shared_lib::interrupts::without_interrupts;
+use shared_lib::spinlock::Spinlock;
+
+/// Ready tasks of a higher priority are polled first, see `executor::RunQueues`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Latency sensitive: input, timers
+    High = 0,
+    Normal = 1,
+    /// Background work, runs when nothing else is ready
+    Idle = 2,
+}
+
+impl Priority {
+    pub const COUNT: usize = 3;
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Idle => "idle",
+        }
+    }
+}
 
 pub struct Task {
     id: TaskId,
+    /// For the `ps` table, see `task_name`
+    name: &'static str,
+    priority: Priority,
     future: Pin<Box<dyn Future<Output = ()>>>
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task::with_priority(future, Priority::Normal)
+    }
+
+    pub fn with_priority<F: Future<Output = ()> + 'static>(future: F, priority: Priority) -> Task {
         Task {
             id: TaskId::new(),
+            name: task_name::<F>(),
+            priority,
             future: Box::pin(future)
         }
     }
 
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
 }
 
+/// Name of the function whose future is `F`: `console_flush_loop` for the future returned by
+/// the async fn `ferr_os::task::console::console_flush_loop`
+fn task_name<F>() -> &'static str {
+    let name = core::any::type_name::<F>();
+    let name = name.strip_suffix("::{{closure}}").unwrap_or(name);
+    // generic parameters may contain paths as well
+    let path_end = name.find('<').unwrap_or(name.len());
+    match name[..path_end].rfind("::") {
+        Some(index) => &name[index + 2..],
+        None => name,
+    }
+}
+
+/// Lets the other ready tasks run before continuing
+pub async fn yield_now() {
+    let mut yielded = false;
+    core::future::poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }).await
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
+
+/// IDs of finished tasks, handed out again before new ones
+static FREE_IDS: Spinlock<Vec<u64>> = Spinlock::new(Vec::new());
 
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
-        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+        match without_interrupts(|| FREE_IDS.lock().pop()) {
+            Some(id) => TaskId(id),
+            None => TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+        }
+    }
+
+    /// Makes the ID of a finished task available again. A `JoinHandle` of the task may then
+    /// report the ID of another one.
+    fn release(self) {
+        without_interrupts(|| FREE_IDS.lock().push(self.0));
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
     }
 }
\ No newline at end of file
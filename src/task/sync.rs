@@ -0,0 +1,505 @@
+// Synchronization between async tasks.
+//
+// A task must not hold a `Spinlock` across an await point: the executor runs every task on the
+// same CPU, a task spinning on a lock held by a suspended one never lets the holder run again.
+// These primitives suspend the waiting task instead.
+//
+// Waiting tasks are kept in a `WaitQueue`, whose lock is only taken with interrupts disabled.
+// `Notify::notify_one` and `Sender::try_send` neither block nor allocate and may be used from
+// interrupt handlers; everything else is for task context.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use shared_lib::interrupts::without_interrupts;
+use shared_lib::spinlock::Spinlock;
+
+/// FIFO of suspended tasks. A waiter is identified by the ID it got when it first registered,
+/// `wake_one` and `wake_all` remove the waiters they wake.
+struct WaitQueue {
+    waiters: Spinlock<VecDeque<(u64, Waker)>>,
+    next_id: AtomicU64,
+}
+
+impl WaitQueue {
+    const fn new() -> Self {
+        WaitQueue {
+            waiters: Spinlock::new(VecDeque::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Queues the waiter `id`, or replaces its waker if it is still queued. Assigns the ID on the
+    /// first call.
+    fn register(&self, id: &mut Option<u64>, waker: &Waker) {
+        without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            if let Some(id) = *id {
+                if let Some((_, queued)) = waiters.iter_mut().find(|(waiter, _)| *waiter == id) {
+                    if !queued.will_wake(waker) {
+                        *queued = waker.clone();
+                    }
+                    return;
+                }
+            }
+            let new_id = *id.get_or_insert_with(|| self.next_id.fetch_add(1, Ordering::Relaxed));
+            waiters.push_back((new_id, waker.clone()));
+        });
+    }
+
+    /// Removes the waiter `id`, false if it was already woken
+    fn remove(&self, id: u64) -> bool {
+        without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            match waiters.iter().position(|(waiter, _)| *waiter == id) {
+                Some(index) => {
+                    waiters.remove(index);
+                    true
+                },
+                None => false,
+            }
+        })
+    }
+
+    fn contains(&self, id: u64) -> bool {
+        without_interrupts(|| self.waiters.lock().iter().any(|(waiter, _)| *waiter == id))
+    }
+
+    /// Wakes the oldest waiter, false if there was none
+    fn wake_one(&self) -> bool {
+        match without_interrupts(|| self.waiters.lock().pop_front()) {
+            Some((_, waker)) => {
+                waker.wake();
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn wake_all(&self) {
+        let waiters = without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+        for (_, waker) in waiters {
+            waker.wake();
+        }
+    }
+}
+
+/// Counting semaphore
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Acquire)
+    }
+
+    fn take_permit(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |permits| permits.checked_sub(1))
+            .is_ok()
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.take_permit().then_some(SemaphorePermit { semaphore: self })
+    }
+
+    /// Waits for a permit, it is given back when the `SemaphorePermit` is dropped
+    pub fn acquire(&self) -> Acquire<'_> {
+        Acquire { semaphore: self, waiter: None, done: false }
+    }
+
+    pub fn add_permits(&self, count: usize) {
+        self.permits.fetch_add(count, Ordering::AcqRel);
+        for _ in 0..count {
+            if !self.waiters.wake_one() {
+                break;
+            }
+        }
+    }
+}
+
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permit taken after the `SemaphorePermit` is gone
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
+
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    waiter: Option<u64>,
+    done: bool,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<SemaphorePermit<'a>> {
+        let semaphore = self.semaphore;
+        if !semaphore.take_permit() {
+            semaphore.waiters.register(&mut self.waiter, cx.waker());
+            // a permit given back before we were queued woke nobody
+            if !semaphore.take_permit() {
+                return Poll::Pending;
+            }
+        }
+
+        if let Some(id) = self.waiter.take() {
+            semaphore.waiters.remove(id);
+        }
+        self.done = true;
+        Poll::Ready(SemaphorePermit { semaphore })
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        // woken for a permit we won't take: the next waiter may
+        if let (Some(id), false) = (self.waiter, self.done) {
+            if !self.semaphore.waiters.remove(id) {
+                self.semaphore.waiters.wake_one();
+            }
+        }
+    }
+}
+
+/// Mutex whose `lock` suspends the task while another one holds it, the guard may be held
+/// across await points
+pub struct Mutex<T> {
+    semaphore: Semaphore,
+    data: UnsafeCell<T>,
+}
+
+// the semaphore gives out one guard at a time
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            semaphore: Semaphore::new(1),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        self.semaphore.acquire().await.forget();
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.semaphore.try_acquire().map(|permit| {
+            permit.forget();
+            MutexGuard { mutex: self }
+        })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the guard holds the only permit
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the guard holds the only permit
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.semaphore.add_permits(1);
+    }
+}
+
+/// Wakes up tasks waiting for an event. `notify_one` stores the notification if no task waits,
+/// the next `notified` completes right away; `notify_waiters` only wakes the tasks waiting now.
+pub struct Notify {
+    permit: AtomicBool,
+    waiters: WaitQueue,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            permit: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn notify_one(&self) {
+        if !self.waiters.wake_one() {
+            self.permit.store(true, Ordering::Release);
+        }
+    }
+
+    pub fn notify_waiters(&self) {
+        self.waiters.wake_all();
+    }
+
+    pub fn notified(&self) -> Notified<'_> {
+        Notified { notify: self, waiter: None, done: false }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    waiter: Option<u64>,
+    done: bool,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let notify = self.notify;
+        if notify.permit.swap(false, Ordering::AcqRel) {
+            if let Some(id) = self.waiter.take() {
+                notify.waiters.remove(id);
+            }
+            self.done = true;
+            return Poll::Ready(());
+        }
+
+        // queued before and no longer: woken by a notification
+        if let Some(id) = self.waiter {
+            if !notify.waiters.contains(id) {
+                self.waiter = None;
+                self.done = true;
+                return Poll::Ready(());
+            }
+        }
+
+        notify.waiters.register(&mut self.waiter, cx.waker());
+        // a `notify_one` before we were queued left a permit
+        if notify.permit.swap(false, Ordering::AcqRel) {
+            if let Some(id) = self.waiter.take() {
+                notify.waiters.remove(id);
+            }
+            self.done = true;
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        // a notification for a task that stopped waiting goes to the next one
+        if let (Some(id), false) = (self.waiter, self.done) {
+            if !self.notify.waiters.remove(id) {
+                self.notify.notify_one();
+            }
+        }
+    }
+}
+
+struct Channel<T> {
+    queue: ArrayQueue<T>,
+    receiver: AtomicWaker,
+    /// Senders waiting for room in the queue
+    senders: WaitQueue,
+    sender_count: AtomicUsize,
+    receiver_alive: AtomicBool,
+}
+
+/// Bounded multi-producer, single-consumer channel holding up to `capacity` values
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Channel {
+        queue: ArrayQueue::new(capacity),
+        receiver: AtomicWaker::new(),
+        senders: WaitQueue::new(),
+        sender_count: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+    });
+    (Sender { channel: channel.clone() }, Receiver { channel })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    /// The receiver is gone
+    Closed(T),
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Queues `value` without waiting, usable from interrupt handlers
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if !self.channel.receiver_alive.load(Ordering::Acquire) {
+            return Err(TrySendError::Closed(value));
+        }
+        self.channel.queue.push(value).map_err(TrySendError::Full)?;
+        self.channel.receiver.wake();
+        Ok(())
+    }
+
+    /// Waits for room in the queue, gives `value` back if the receiver is gone
+    pub fn send(&self, value: T) -> Sending<'_, T> {
+        Sending { sender: self, value: Some(value), waiter: None }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.sender_count.fetch_add(1, Ordering::Relaxed);
+        Sender { channel: self.channel.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.channel.sender_count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.channel.receiver.wake();
+        }
+    }
+}
+
+pub struct Sending<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+    waiter: Option<u64>,
+}
+
+// the value is moved out, never pinned
+impl<T> Unpin for Sending<'_, T> {}
+
+impl<T> Future for Sending<'_, T> {
+    type Output = Result<(), T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), T>> {
+        let this = &mut *self;
+        let channel = &this.sender.channel;
+        let value = this.value.take().expect("Sending polled after completion");
+
+        let value = match this.sender.try_send(value) {
+            Ok(()) => return Poll::Ready(Ok(())),
+            Err(TrySendError::Closed(value)) => return Poll::Ready(Err(value)),
+            Err(TrySendError::Full(value)) => value,
+        };
+
+        channel.senders.register(&mut this.waiter, cx.waker());
+        // the receiver may have made room before we were queued
+        match this.sender.try_send(value) {
+            Ok(()) => {
+                if let Some(id) = this.waiter.take() {
+                    channel.senders.remove(id);
+                }
+                Poll::Ready(Ok(()))
+            },
+            Err(TrySendError::Closed(value)) => Poll::Ready(Err(value)),
+            Err(TrySendError::Full(value)) => {
+                this.value = Some(value);
+                Poll::Pending
+            },
+        }
+    }
+}
+
+impl<T> Drop for Sending<'_, T> {
+    fn drop(&mut self) {
+        // woken for room we won't use: the next sender may
+        if let (Some(id), true) = (self.waiter, self.value.is_some()) {
+            if !self.sender.channel.senders.remove(id) {
+                self.sender.channel.senders.wake_one();
+            }
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Option<T> {
+        let value = self.channel.queue.pop()?;
+        self.channel.senders.wake_one();
+        Some(value)
+    }
+
+    /// Next value, None once the queue is empty and every sender is gone
+    pub async fn recv(&mut self) -> Option<T> {
+        core::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        if let Some(value) = self.try_recv() {
+            return Poll::Ready(Some(value));
+        }
+
+        self.channel.receiver.register(cx.waker());
+        match self.try_recv() {
+            Some(value) => {
+                self.channel.receiver.take();
+                Poll::Ready(Some(value))
+            },
+            None if self.channel.sender_count.load(Ordering::Acquire) == 0 => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.receiver_alive.store(false, Ordering::Release);
+        self.channel.senders.wake_all();
+    }
+}
@@ -1,155 +1,252 @@
 use alloc::collections::BTreeMap;
 use core::future::Future;
-use core::ops::{DerefMut};
 use core::pin::Pin;
 use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use core::task::{Context, Poll, Waker};
-use conquer_once::spin::OnceCell;
-use futures_util::stream::{Stream, StreamExt};
-use futures_util::task::AtomicWaker;
+use shared_lib::get_tsc;
+use shared_lib::interrupts::without_interrupts;
+use shared_lib::spinlock::Spinlock;
+use shared_lib::time_page::{TimeSnapshot, TIME_PAGE};
+use crate::softirq;
+
+/// Set while `expire_sleepers` is raised and didn't run yet, ticks in between share it
+static EXPIRE_PENDING: AtomicBool = AtomicBool::new(false);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Wall time at `WALL_CLOCK_BASE_TICKS`, set from the RTC by `set_wall_clock`
+static WALL_CLOCK_BASE_SECS: AtomicU64 = AtomicU64::new(0);
+static WALL_CLOCK_BASE_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Timer interrupts per second, the local APIC timer is programmed from it. 250 by default, the
+/// `tick_100hz` and `tick_1000hz` features select another rate at build time.
+#[cfg(feature = "tick_100hz")]
+pub const TIMER_FREQUENCY: u16 = 100;
+#[cfg(feature = "tick_1000hz")]
+pub const TIMER_FREQUENCY: u16 = 1000;
+#[cfg(not(any(feature = "tick_100hz", feature = "tick_1000hz")))]
+pub const TIMER_FREQUENCY: u16 = 250;
 
-static TIMER_FLAG: OnceCell<AtomicBool> = OnceCell::uninit();
-static WAKER: AtomicWaker = AtomicWaker::new();
+#[cfg(all(feature = "tick_100hz", feature = "tick_1000hz"))]
+compile_error!("the tick_100hz and tick_1000hz features are mutually exclusive");
 
-pub const TIMER_FREQUENCY: u16 = 250;
+pub const MS_PER_TICK: u64 = 1000 / TIMER_FREQUENCY as u64;
+
+// otherwise every conversion between ticks and milliseconds drifts
+const _: () = assert!(1000 % TIMER_FREQUENCY as u64 == 0, "TIMER_FREQUENCY must divide 1000");
+
+/// TSC cycles per tick, 0 if the TSC can't measure idle periods
+static TSC_PER_TICK: AtomicU64 = AtomicU64::new(0);
+/// TSC at the last counted tick
+static LAST_TICK_TSC: AtomicU64 = AtomicU64::new(0);
 
 /// Called by the timer interrupt handler
 ///
 /// Must not block or allocate.
 pub fn raise_timer() {
-    if let Ok(bool_flag) = TIMER_FLAG.try_get() {
-        bool_flag.store(true, Ordering::SeqCst);
-        if Ok(true) == bool_flag.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst) {
-            log::error!("[timer] raised timer flag hasn't been consumed last time!");
-        }
-        WAKER.wake();
+    LAST_TICK_TSC.store(get_tsc(), Ordering::Relaxed);
+    advance(1);
+}
+
+fn advance(count: u64) {
+    let ticks = TICKS.fetch_add(count, Ordering::Relaxed) + count;
+    publish_time(ticks);
+
+    if !EXPIRE_PENDING.swap(true, Ordering::AcqRel) && !softirq::raise(expire_sleepers, 0) {
+        // retried on the next tick
+        EXPIRE_PENDING.store(false, Ordering::Release);
     }
 }
 
-struct TimerStream {
-    _private: ()
+fn publish_time(ticks: u64) {
+    let base_secs = WALL_CLOCK_BASE_SECS.load(Ordering::Relaxed);
+    let wall_secs = if base_secs == 0 {
+        0
+    } else {
+        let base_ticks = WALL_CLOCK_BASE_TICKS.load(Ordering::Relaxed);
+        base_secs + ticks.saturating_sub(base_ticks) / TIMER_FREQUENCY as u64
+    };
+
+    TIME_PAGE.publish(TimeSnapshot {
+        ticks,
+        uptime_ms: ticks * 1000 / TIMER_FREQUENCY as u64,
+        wall_secs,
+    });
 }
 
-impl TimerStream {
-    pub fn new() -> Self {
-        TIMER_FLAG.try_init_once(|| AtomicBool::from(false))
-            .expect("TimerStream::new should only be called once");
-        TimerStream { _private: () }
-    }
+/// Sets the wall time in seconds since the Unix epoch, it then advances with the timer ticks.
+pub fn set_wall_clock(unix_secs: u64) {
+    WALL_CLOCK_BASE_TICKS.store(ticks(), Ordering::Relaxed);
+    WALL_CLOCK_BASE_SECS.store(unix_secs, Ordering::Relaxed);
 }
 
-impl Stream for TimerStream {
-    type Item = ();
+/// Latest (ticks, uptime, wall time) published by the timer interrupt, readable from any context
+pub fn now() -> Option<TimeSnapshot> {
+    TIME_PAGE.snapshot()
+}
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<()>> {
-        let timer_flag = crate::task::timer::TIMER_FLAG.try_get().expect("not initialized");
+/// Number of timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
 
-        if Ok(true) == timer_flag.compare_exchange(true, false, Ordering::Acquire, Ordering::Relaxed) {
-            return Poll::Ready(Some(()))
-        }
+/// Converts milliseconds to timer ticks, rounded up so sleeps are never short, at least one tick
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    u64::max(1, ms.div_ceil(MS_PER_TICK))
+}
 
-        WAKER.register(&cx.waker());
+/// Lets `account_idle` measure idle periods with the TSC. Called once the periodic timer runs,
+/// with the TSC frequency if it is invariant.
+pub fn set_tsc_frequency(tsc_hz: u64) {
+    LAST_TICK_TSC.store(get_tsc(), Ordering::Relaxed);
+    TSC_PER_TICK.store(tsc_hz / TIMER_FREQUENCY as u64, Ordering::Relaxed);
+}
 
-        match timer_flag.compare_exchange(true, false, Ordering::Acquire, Ordering::Relaxed) {
-            Ok(true) => {
-                WAKER.take();
-                Poll::Ready(Some(()))
-            },
-            Ok(false) => Poll::Pending,
-            Err(_) => Poll::Pending
-        }
+/// TSC frequency in Hz, None if the TSC isn't invariant or not calibrated yet
+pub fn tsc_frequency() -> Option<u64> {
+    let tsc_per_tick = TSC_PER_TICK.load(Ordering::Relaxed);
+    (tsc_per_tick != 0).then(|| tsc_per_tick * TIMER_FREQUENCY as u64)
+}
+
+/// Counts the ticks the timer missed while the CPU was idle: without ARAT the local APIC timer
+/// stops in deep C-states. Called after waking up.
+pub fn account_idle() {
+    let tsc_per_tick = TSC_PER_TICK.load(Ordering::Relaxed);
+    if tsc_per_tick == 0 {
+        return;
     }
+
+    without_interrupts(|| {
+        let elapsed = get_tsc().wrapping_sub(LAST_TICK_TSC.load(Ordering::Relaxed));
+        // the last one may still be on its way as an interrupt
+        let missed = (elapsed / tsc_per_tick).saturating_sub(1);
+        if missed > 0 {
+            LAST_TICK_TSC.fetch_add(missed * tsc_per_tick, Ordering::Relaxed);
+            advance(missed);
+        }
+    });
 }
 
+/// Sleeping tasks ordered by the tick they are due at: `expire_sleepers` only touches the
+/// expired ones, however many tasks sleep
 struct TimerTasksManager {
-    tasks: BTreeMap<u64, (u64, AtomicWaker)>, // task id -> (ticks counter, waker)
+    sleepers: BTreeMap<(u64, u64), Waker>, // (deadline tick, sleep id) -> waker
 }
 
-static TIMER_TASKS_MANAGER: spin::Mutex<TimerTasksManager> = spin::Mutex::new(TimerTasksManager{ tasks: BTreeMap::new() });
+static TIMER_TASKS_MANAGER: Spinlock<TimerTasksManager> = Spinlock::new(TimerTasksManager { sleepers: BTreeMap::new() });
 
 impl TimerTasksManager {
-    pub fn register_task(&mut self, id: u64, ticks: u64) -> Result<(), &'static str> {
-        return if self.tasks.contains_key(&id) {
-            Err("Task already registered")
-        } else {
-            self.tasks.insert(id, (ticks, AtomicWaker::new()));
-            Ok(())
+    /// Adds the sleep or replaces its waker
+    fn register(&mut self, deadline: u64, id: u64, waker: &Waker) {
+        match self.sleepers.get_mut(&(deadline, id)) {
+            Some(registered) if registered.will_wake(waker) => {},
+            Some(registered) => *registered = waker.clone(),
+            None => {
+                self.sleepers.insert((deadline, id), waker.clone());
+            },
         }
     }
 
-    pub fn decrement_all(&mut self) {
-        for mut item in self.tasks.iter_mut() {
-            let val = item.1.deref_mut();
-            val.0 = val.0.checked_sub(1).unwrap_or(0);
-
-            if val.0 == 0 {
-                val.1.wake();
-            }
-        }
+    fn remove(&mut self, deadline: u64, id: u64) {
+        self.sleepers.remove(&(deadline, id));
     }
 
-    pub fn check_task(&mut self, id: u64) -> Result<bool, &'static str> {
-        if self.tasks.get_mut(&id).expect("There is no such task").0.eq(&0) {
-            self.tasks.remove(&id).expect("Failed to remove task from map");
-            Ok(true)
-        } else {
-            Ok(false)
+    /// Wakes and removes the sleeps due at `now`
+    fn expire(&mut self, now: u64) {
+        while let Some(entry) = self.sleepers.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            entry.remove().wake();
         }
     }
 
-    pub fn register_waker(&mut self, id: u64, waker: &Waker) -> Result<(), &'static str> {
-        self.tasks.get_mut(&id).unwrap().1.register(waker);
-        Ok(())
+    fn next_deadline(&self) -> Option<u64> {
+        self.sleepers.first_key_value().map(|(&(deadline, _), _)| deadline)
     }
 }
 
-pub async fn timer_loop() {
-    let mut timer_stream = TimerStream::new();
+/// Ticks until the first sleeping task is due, None if nothing sleeps
+pub fn next_wakeup_ticks() -> Option<u64> {
+    let deadline = TIMER_TASKS_MANAGER.lock().next_deadline()?;
+    Some(deadline.saturating_sub(ticks()))
+}
 
-    while let Some(()) = timer_stream.next().await {
-        TIMER_TASKS_MANAGER.lock().decrement_all();
-    }
+/// Bottom half of the timer interrupt, wakes the tasks whose sleep is over. One run may cover
+/// several ticks, e.g. after an idle period.
+fn expire_sleepers(_: u64) {
+    EXPIRE_PENDING.store(false, Ordering::Release);
+    TIMER_TASKS_MANAGER.lock().expire(ticks());
 }
 
 struct Sleep {
-    task_id: u64
+    deadline: u64,
+    id: u64,
 }
 
 impl Sleep {
-    pub fn new(sleep_for_ms: u64) -> Sleep {
+    fn until(deadline: u64) -> Sleep {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
-        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-
-        let msec_freq = (1000 / TIMER_FREQUENCY) as u64; // every tick N msec passed
-
-        let timer_value = if sleep_for_ms < msec_freq {
-            1
-        } else {
-            sleep_for_ms / msec_freq
-        };
-
-        TIMER_TASKS_MANAGER
-            .lock()
-            .register_task(id, timer_value)
-            .expect("Failed to register task");
-
-        Sleep{ task_id: id }
+        Sleep { deadline, id: NEXT_ID.fetch_add(1, Ordering::Relaxed) }
     }
 }
 
 pub async fn sleep_for(sleep_for_ms: u64) {
-    Sleep::new(sleep_for_ms).await;
+    Sleep::until(ticks() + ms_to_ticks(sleep_for_ms)).await;
+}
+
+/// Sleeps until the tick count reaches `deadline`
+pub async fn sleep_until(deadline: u64) {
+    Sleep::until(deadline).await;
 }
 
 impl Future for Sleep {
     type Output = ();
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if TIMER_TASKS_MANAGER.lock().check_task(self.task_id).expect("Failed to check task") {
+        if ticks() >= self.deadline {
+            TIMER_TASKS_MANAGER.lock().remove(self.deadline, self.id);
+            return Poll::Ready(());
+        }
+
+        let mut manager = TIMER_TASKS_MANAGER.lock();
+        manager.register(self.deadline, self.id, cx.waker());
+        // `expire_sleepers` may have run for this deadline before we registered
+        if ticks() >= self.deadline {
+            manager.remove(self.deadline, self.id);
             Poll::Ready(())
         } else {
-            TIMER_TASKS_MANAGER.lock().register_waker(self.task_id, &cx.waker()).expect("Failed to register waker");
             Poll::Pending
         }
     }
 }
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        // a sleep dropped before it was due, e.g. by a cancelled task
+        TIMER_TASKS_MANAGER.lock().remove(self.deadline, self.id);
+    }
+}
+
+/// Completes every `period_ms` milliseconds, see `interval`
+pub struct Interval {
+    period: u64,
+    next: u64,
+}
+
+/// Periodic timer whose first tick is one period from now. The ticks don't drift with the time
+/// the task takes between them; periods missed while the task was busy are skipped.
+pub fn interval(period_ms: u64) -> Interval {
+    let period = ms_to_ticks(period_ms);
+    Interval { period, next: ticks() + period }
+}
+
+impl Interval {
+    pub async fn tick(&mut self) {
+        sleep_until(self.next).await;
+        let now = ticks();
+        self.next += self.period;
+        if self.next <= now {
+            self.next += (now - self.next) / self.period * self.period + self.period;
+        }
+    }
+}
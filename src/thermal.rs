@@ -0,0 +1,219 @@
+// CPU temperature.
+//
+// Read from the digital thermal sensors of Intel CPUs (CPUID leaf 6): IA32_THERM_STATUS for the
+// core we run on and IA32_PACKAGE_THERM_STATUS for the package. Both report how far below the
+// throttling temperature TjMax the CPU is. ACPI thermal zones would cover other machines, but
+// their temperatures come from AML methods (`_TMP`), which we can't evaluate.
+//
+// `thermal_monitor_loop` checks the sensors periodically and warns once when the temperature
+// reaches the `thermal_warn` setting or the CPU starts throttling. The sensor also raises the
+// thermal LVT interrupt of the local APIC when the CPU starts or stops throttling or gets
+// critically hot, `handle_interrupt` logs these events as they happen.
+
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use conquer_once::spin::OnceCell;
+use shared_lib::msr;
+use crate::apic::{Lvt, LvtEntry};
+use crate::config;
+use crate::interrupts::{InterruptIndex, APIC};
+use crate::task::timer::sleep_for;
+
+const CPUID_THERMAL_LEAF: u32 = 6;
+const CPUID_DIGITAL_SENSOR: u32 = 1 << 0;
+const CPUID_PACKAGE_SENSOR: u32 = 1 << 6;
+
+/// IA32_THERM_INTERRUPT: high and low temperature (throttling starts and stops), PROCHOT# and
+/// critical temperature
+const INTERRUPT_ENABLE: u64 = 1 << 0 | 1 << 1 | 1 << 2 | 1 << 4;
+
+const STATUS_THROTTLING: u64 = 1 << 0;
+/// Sticky bits, set when the event happened since they were last cleared
+const STATUS_THROTTLING_LOG: u64 = 1 << 1;
+const STATUS_PROCHOT_LOG: u64 = 1 << 3;
+const STATUS_CRITICAL_LOG: u64 = 1 << 5;
+const STATUS_LOG_BITS: u64 = STATUS_THROTTLING_LOG | STATUS_PROCHOT_LOG | STATUS_CRITICAL_LOG;
+const STATUS_READING_VALID: u64 = 1 << 31;
+const STATUS_READOUT_SHIFT: u64 = 16;
+const STATUS_READOUT_MASK: u64 = 0x7F;
+
+/// Used when the CPU doesn't report it
+const DEFAULT_TJ_MAX: u32 = 100;
+
+const CPUID_ECX_HYPERVISOR: u32 = 1 << 31;
+/// Nehalem, the first model with MSR_TEMPERATURE_TARGET
+const FIRST_TJ_MAX_MODEL: u32 = 0x1A;
+
+const CHECK_INTERVAL_MS: u64 = 5000;
+/// Degrees below the warning threshold before warning again
+const HYSTERESIS: u32 = 5;
+
+struct Sensors {
+    package: bool,
+    tj_max: u32,
+}
+
+static SENSORS: OnceCell<Option<Sensors>> = OnceCell::uninit();
+
+#[derive(Debug, Clone, Copy)]
+pub struct Reading {
+    pub celsius: u32,
+    /// The CPU is currently throttled because it is too hot
+    pub throttling: bool,
+}
+
+fn is_intel() -> bool {
+    let vendor = __cpuid(0);
+    [vendor.ebx, vendor.edx, vendor.ecx] == [
+        u32::from_le_bytes(*b"Genu"), u32::from_le_bytes(*b"ineI"), u32::from_le_bytes(*b"ntel")]
+}
+
+fn detect() -> Option<Sensors> {
+    if !is_intel() || __cpuid(0).eax < CPUID_THERMAL_LEAF {
+        return None;
+    }
+    let features = __cpuid(CPUID_THERMAL_LEAF).eax;
+    if features & CPUID_DIGITAL_SENSOR == 0 {
+        return None;
+    }
+
+    Some(Sensors { package: features & CPUID_PACKAGE_SENSOR != 0, tj_max: tj_max() })
+}
+
+/// TjMax from MSR_TEMPERATURE_TARGET where it is known to exist. The MSR isn't architectural:
+/// older models and most hypervisors raise #GP on it, and nothing catches that.
+fn tj_max() -> u32 {
+    let signature = __cpuid(1);
+    let family = signature.eax >> 8 & 0xF;
+    let model = (signature.eax >> 4 & 0xF) | (signature.eax >> 16 & 0xF) << 4;
+    if family != 6 || model < FIRST_TJ_MAX_MODEL || signature.ecx & CPUID_ECX_HYPERVISOR != 0 {
+        return DEFAULT_TJ_MAX;
+    }
+
+    // SAFETY: Intel family 6 from Nehalem on has the MSR, checked above
+    match unsafe { msr::read(msr::MSR_TEMPERATURE_TARGET) } >> 16 & 0xFF {
+        0 => DEFAULT_TJ_MAX,
+        tj_max => tj_max as u32,
+    }
+}
+
+pub fn init() {
+    match SENSORS.get_or_init(detect) {
+        Some(sensors) => log::info!("[thermal] digital thermal sensor, TjMax {} °C{}",
+            sensors.tj_max, if sensors.package { ", package sensor" } else { "" }),
+        None => {
+            log::info!("[thermal] no supported temperature sensor");
+            return;
+        },
+    }
+
+    let apic = APIC.lock();
+    if apic.max_lvt() < 5 {
+        return;
+    }
+    // SAFETY: the MSRs exist on CPUs with a digital thermal sensor
+    unsafe {
+        msr::write(msr::IA32_THERM_STATUS, msr::read(msr::IA32_THERM_STATUS) & !STATUS_LOG_BITS);
+        msr::write(msr::IA32_THERM_INTERRUPT, msr::read(msr::IA32_THERM_INTERRUPT) | INTERRUPT_ENABLE);
+    }
+    apic.set_lvt(Lvt::Thermal, LvtEntry::fixed(InterruptIndex::Thermal as u8));
+}
+
+/// Thermal LVT interrupt: logs and clears the events the core sensor recorded
+pub fn handle_interrupt() {
+    let Some(Some(sensors)) = SENSORS.get() else {
+        return;
+    };
+
+    // SAFETY: the interrupt is only enabled for the sensors found by `detect`
+    let status = unsafe { msr::read(msr::IA32_THERM_STATUS) };
+    let celsius = sensors.tj_max.saturating_sub((status >> STATUS_READOUT_SHIFT & STATUS_READOUT_MASK) as u32);
+    if status & STATUS_CRITICAL_LOG != 0 {
+        log::error!("[thermal] CPU reached its critical temperature");
+    }
+    if status & STATUS_PROCHOT_LOG != 0 {
+        log::warn!("[thermal] PROCHOT# asserted at {} °C", celsius);
+    }
+    if status & STATUS_THROTTLING_LOG != 0 {
+        if status & STATUS_THROTTLING != 0 {
+            log::warn!("[thermal] CPU started throttling at {} °C", celsius);
+        } else {
+            log::info!("[thermal] CPU stopped throttling at {} °C", celsius);
+        }
+    }
+
+    // the log bits are cleared by writing 0
+    unsafe {
+        msr::write(msr::IA32_THERM_STATUS, status & !STATUS_LOG_BITS);
+    }
+}
+
+fn read_status(sensors: &Sensors, status_msr: u32) -> Option<Reading> {
+    // SAFETY: only called for the sensors found by `detect`
+    let status = unsafe { msr::read(status_msr) };
+    if status & STATUS_READING_VALID == 0 {
+        return None;
+    }
+    let below_tj_max = (status >> STATUS_READOUT_SHIFT & STATUS_READOUT_MASK) as u32;
+    Some(Reading { celsius: sensors.tj_max.saturating_sub(below_tj_max), throttling: status & STATUS_THROTTLING != 0 })
+}
+
+/// Temperature of the core we run on
+pub fn core_temperature() -> Option<Reading> {
+    let sensors = SENSORS.get()?.as_ref()?;
+    read_status(sensors, msr::IA32_THERM_STATUS)
+}
+
+/// Temperature of the whole package, if the CPU has a package sensor
+pub fn package_temperature() -> Option<Reading> {
+    let sensors = SENSORS.get()?.as_ref()?;
+    if !sensors.package {
+        return None;
+    }
+    read_status(sensors, msr::IA32_PACKAGE_THERM_STATUS)
+}
+
+/// Output of the `sensors` shell command
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    let Some(Some(sensors)) = SENSORS.get() else {
+        return writeln!(out, "no temperature sensors");
+    };
+
+    for (name, reading) in [("core", core_temperature()), ("package", package_temperature())] {
+        match reading {
+            Some(reading) => writeln!(out, "{}: {} °C{}", name, reading.celsius,
+                if reading.throttling { ", throttling" } else { "" })?,
+            None if name == "package" && !sensors.package => {},
+            None => writeln!(out, "{}: no valid reading", name)?,
+        }
+    }
+    writeln!(out, "TjMax: {} °C, warning at {} °C", sensors.tj_max, config::thermal_warn_celsius())
+}
+
+pub async fn thermal_monitor_loop() {
+    if !matches!(SENSORS.get(), Some(Some(_))) {
+        return;
+    }
+
+    let mut warned = false;
+    let mut throttling = false;
+    loop {
+        let reading = package_temperature().or_else(core_temperature);
+        if let Some(reading) = reading {
+            let threshold = config::thermal_warn_celsius();
+            if reading.celsius >= threshold && !warned {
+                log::warn!("[thermal] CPU temperature {} °C reached the warning threshold of {} °C", reading.celsius, threshold);
+                warned = true;
+            } else if reading.celsius + HYSTERESIS < threshold {
+                warned = false;
+            }
+
+            if reading.throttling && !throttling {
+                log::warn!("[thermal] CPU is throttling at {} °C", reading.celsius);
+            }
+            throttling = reading.throttling;
+        }
+
+        sleep_for(CHECK_INTERVAL_MS).await;
+    }
+}
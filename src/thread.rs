@@ -0,0 +1,355 @@
+// Preemptive kernel threads.
+//
+// A thread is a kernel stack and the stack pointer saved while it is switched out.
+// `thread_switch` pushes the callee-saved registers on the old stack, stores RSP, loads the new
+// one and pops them; everything else was saved by its caller, the compiler or the entry of an
+// interrupt handler. A new thread's stack is set up to return into `thread_start`, which enables
+// interrupts and runs the thread's function.
+//
+// Scheduling is round-robin. `tick`, called at the end of the APIC timer interrupt, preempts the
+// running thread once it used up its time slice: the stacks are switched inside the handler and
+// the preempted thread returns from the interrupt once it is resumed. Threads also give up the
+// CPU with `yield_now` and `exit`. The thread `init` is called on, the boot stack running the
+// async executor, becomes the `main` thread; the idle thread runs when no other one is ready.
+// Only the bootstrap processor schedules threads.
+//
+// The scheduler lock is only taken with interrupts disabled, `tick` skips a slice if it is busy.
+// `tick` doesn't preempt while a spinlock is held either, it tries again on the next tick.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+use core::fmt;
+use core::future::Future;
+use core::pin::pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use shared_lib::addr::VirtAddr;
+use shared_lib::interrupts::without_interrupts;
+use shared_lib::spinlock::{self, Spinlock};
+use crate::allocator::FRAME_ALLOCATOR;
+use crate::gdt;
+use crate::task::timer;
+
+const THREAD_STACK_PAGES: usize = 8;
+const TIME_SLICE_MS: u64 = 10;
+
+pub type ThreadId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Exited,
+}
+
+impl ThreadState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThreadState::Ready => "ready",
+            ThreadState::Running => "running",
+            ThreadState::Exited => "exited",
+        }
+    }
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: ThreadState,
+    /// Saved stack pointer while switched out
+    rsp: u64,
+    /// Top of the stack, None for the main thread, which runs on the boot stack
+    stack: Option<VirtAddr>,
+    /// Taken by `thread_start`
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// Times the thread was switched to
+    runs: u64,
+}
+
+struct Scheduler {
+    current: Option<Box<Thread>>,
+    ready: VecDeque<Box<Thread>>,
+    /// Not in `ready`, picked when it is empty
+    idle: Option<Box<Thread>>,
+    idle_id: ThreadId,
+    /// Exited threads, their stacks are freed by the next `spawn`
+    dead: Vec<Box<Thread>>,
+    slice_left: u64,
+    switches: u64,
+}
+
+static SCHEDULER: Spinlock<Scheduler> = Spinlock::new(Scheduler {
+    current: None,
+    ready: VecDeque::new(),
+    idle: None,
+    idle_id: 0,
+    dead: Vec::new(),
+    slice_left: 0,
+    switches: 0,
+});
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+global_asm!(
+    ".global thread_switch",
+    "thread_switch:",
+    "    push rbp",
+    "    push rbx",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov [rdi], rsp",
+    "    mov rsp, rsi",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbx",
+    "    pop rbp",
+    "    ret",
+);
+
+extern "C" {
+    /// Saves the callee-saved registers and RSP to `old_rsp`, continues on the stack `new_rsp`
+    fn thread_switch(old_rsp: *mut u64, new_rsp: u64);
+}
+
+fn time_slice() -> u64 {
+    timer::ms_to_ticks(TIME_SLICE_MS).max(1)
+}
+
+/// Makes the running code the `main` thread and starts the idle thread. Called once the frame
+/// allocator is in `FRAME_ALLOCATOR`, before the executor runs.
+pub fn init() -> Result<(), &'static str> {
+    let main = Box::new(Thread {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        name: "main",
+        state: ThreadState::Running,
+        rsp: 0,
+        stack: None,
+        entry: None,
+        runs: 1,
+    });
+    let idle = new_thread("idle", Box::new(idle_loop))?;
+
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if scheduler.current.is_some() {
+            return Err("threads are already initialized");
+        }
+        scheduler.current = Some(main);
+        scheduler.idle_id = idle.id;
+        scheduler.idle = Some(idle);
+        scheduler.slice_left = time_slice();
+        Ok(())
+    })
+}
+
+fn new_thread(name: &'static str, entry: Box<dyn FnOnce() + Send>) -> Result<Box<Thread>, &'static str> {
+    let stack = without_interrupts(|| {
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().ok_or("no frame allocator")?;
+        gdt::allocate_stack(THREAD_STACK_PAGES, "thread stack", frame_allocator)
+    })?;
+
+    // what `thread_switch` pops: r15, r14, r13, r12, rbx, rbp, the return address into
+    // `thread_start` and a null return address for `thread_start` ending the frame chain
+    let top = stack.0 as *mut u64;
+    let initial = [0, 0, 0, 0, 0, 0, thread_start as extern "C" fn() -> ! as u64, 0];
+    let rsp = unsafe {
+        let rsp = top.sub(initial.len());
+        core::ptr::copy_nonoverlapping(initial.as_ptr(), rsp, initial.len());
+        rsp as u64
+    };
+
+    Ok(Box::new(Thread {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        name,
+        state: ThreadState::Ready,
+        rsp,
+        stack: Some(stack),
+        entry: Some(entry),
+        runs: 0,
+    }))
+}
+
+/// Starts a thread running `f`, it exits when `f` returns
+pub fn spawn(name: &'static str, f: impl FnOnce() + Send + 'static) -> Result<ThreadId, &'static str> {
+    reap();
+    let thread = new_thread(name, Box::new(f))?;
+    let id = thread.id;
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if scheduler.current.is_none() {
+            return Err("threads are not initialized");
+        }
+        scheduler.ready.push_back(thread);
+        Ok(())
+    })?;
+    Ok(id)
+}
+
+/// Frees the stacks of the exited threads
+fn reap() {
+    let dead = without_interrupts(|| core::mem::take(&mut SCHEDULER.lock().dead));
+    for thread in dead {
+        let Some(stack) = thread.stack else { continue };
+        without_interrupts(|| {
+            if let Some(frame_allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+                // SAFETY: the thread exited and was switched away from
+                if let Err(e) = unsafe { gdt::free_stack(stack, THREAD_STACK_PAGES, frame_allocator) } {
+                    log::warn!("Failed to free the stack of thread {}: {}", thread.id, e);
+                }
+            }
+        });
+    }
+}
+
+extern "C" fn thread_start() -> ! {
+    let entry = SCHEDULER.lock().current.as_mut().and_then(|thread| thread.entry.take());
+    // switched to with interrupts disabled
+    unsafe { asm!("sti", options(nomem, nostack)) };
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+fn idle_loop() {
+    loop {
+        if has_ready() {
+            yield_now();
+            continue;
+        }
+        unsafe {
+            asm!("cli", options(nomem, nostack));
+            if has_ready() {
+                asm!("sti", options(nomem, nostack));
+            } else {
+                // the STI shadow covers HLT, a wakeup in between isn't lost
+                asm!("sti; hlt", options(nomem, nostack));
+            }
+        }
+    }
+}
+
+/// Switches to the next ready thread, `state` is what the running one becomes. Without a ready
+/// thread a `Ready` one keeps running, an exiting one switches to the idle thread.
+fn schedule(state: ThreadState) {
+    without_interrupts(|| {
+        let (old_rsp, new_rsp) = {
+            let mut scheduler = SCHEDULER.lock();
+            let mut next = match scheduler.ready.pop_front() {
+                Some(next) => next,
+                None if state == ThreadState::Ready => {
+                    scheduler.slice_left = time_slice();
+                    return;
+                },
+                None => match scheduler.idle.take() {
+                    Some(idle) => idle,
+                    None => return,
+                },
+            };
+            let Some(mut previous) = scheduler.current.take() else {
+                scheduler.ready.push_front(next);
+                return;
+            };
+
+            previous.state = state;
+            let old_rsp = &mut previous.rsp as *mut u64;
+            next.state = ThreadState::Running;
+            next.runs += 1;
+            let new_rsp = next.rsp;
+            scheduler.current = Some(next);
+            scheduler.slice_left = time_slice();
+            scheduler.switches += 1;
+
+            // the boxes don't move, `old_rsp` stays valid
+            match state {
+                ThreadState::Exited => scheduler.dead.push(previous),
+                _ if previous.id == scheduler.idle_id => scheduler.idle = Some(previous),
+                _ => scheduler.ready.push_back(previous),
+            }
+            (old_rsp, new_rsp)
+        };
+
+        unsafe { thread_switch(old_rsp, new_rsp) };
+    });
+}
+
+/// Gives the CPU to the next ready thread, if there is one
+pub fn yield_now() {
+    schedule(ThreadState::Ready);
+}
+
+/// Runs `future` to completion on the running thread, polling it again after every yield. For
+/// blocking code on a kernel thread calling async APIs; on the main thread it would hold up the
+/// executor.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        yield_now();
+    }
+}
+
+/// Ends the running thread
+pub fn exit() -> ! {
+    schedule(ThreadState::Exited);
+    unreachable!("exited thread resumed");
+}
+
+/// Whether a thread other than the running one could run
+pub fn has_ready() -> bool {
+    without_interrupts(|| SCHEDULER.try_lock().map_or(false, |scheduler| !scheduler.ready.is_empty()))
+}
+
+pub fn current_id() -> Option<ThreadId> {
+    without_interrupts(|| SCHEDULER.lock().current.as_ref().map(|thread| thread.id))
+}
+
+/// Called at the end of the timer interrupt, preempts the running thread when its slice is over
+pub fn tick() {
+    let Some(mut scheduler) = SCHEDULER.try_lock() else {
+        return;
+    };
+    if scheduler.current.is_none() {
+        return;
+    }
+    scheduler.slice_left = scheduler.slice_left.saturating_sub(1);
+    if scheduler.slice_left > 0 || scheduler.ready.is_empty() {
+        return;
+    }
+    drop(scheduler);
+    // the interrupted code holds a lock, the slice stays used up until it drops it
+    if spinlock::any_held() {
+        return;
+    }
+    schedule(ThreadState::Ready);
+}
+
+/// Output of the `threads` shell command
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    // formatted outside of the lock, with interrupts enabled
+    let (switches, threads) = without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let threads: Vec<_> = scheduler.current.iter()
+            .chain(scheduler.ready.iter())
+            .chain(scheduler.idle.iter())
+            .map(|thread| (thread.id, thread.name, thread.state, thread.runs))
+            .collect();
+        (scheduler.switches, threads)
+    });
+
+    writeln!(out, "{} context switches", switches)?;
+    writeln!(out, "  id name             state    runs")?;
+    for (id, name, state, runs) in threads {
+        writeln!(out, "{:>4} {:<16} {:<8} {}", id, name, state.name(), runs)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,184 @@
+// Low-overhead event tracing.
+//
+// Events are stored as fixed-size binary records in a static ring buffer; recording an event is
+// an atomic increment plus a 32-byte store, so it is safe to use from interrupt handlers. The
+// ring is exported either as text lines over serial (`trace: <tsc> <event> <arg0> <arg1>`) or as
+// raw records to a block device. `trace_to_chrome.py` converts both into Chrome trace JSON.
+
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use shared_lib::{get_tsc, serial_println};
+use crate::block::Disk;
+use crate::ide::AtaError;
+
+pub const TRACE_RING_SIZE: usize = 2048;
+
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    Empty = 0,
+    IrqEnter = 1,
+    IrqExit = 2,
+    TaskPoll = 3,
+    TaskPollEnd = 4,
+    TaskSpawn = 5,
+    Mark = 6,
+}
+
+impl TraceEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            TraceEvent::Empty => "empty",
+            TraceEvent::IrqEnter => "irq_enter",
+            TraceEvent::IrqExit => "irq_exit",
+            TraceEvent::TaskPoll => "task_poll",
+            TraceEvent::TaskPollEnd => "task_poll_end",
+            TraceEvent::TaskSpawn => "task_spawn",
+            TraceEvent::Mark => "mark",
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TraceRecord {
+    pub tsc: u64,
+    pub event: TraceEvent,
+    reserved: [u16; 3],
+    pub args: [u64; 2],
+}
+
+const EMPTY_RECORD: TraceRecord = TraceRecord { tsc: 0, event: TraceEvent::Empty, reserved: [0; 3], args: [0; 2] };
+
+struct TraceRing(UnsafeCell<[TraceRecord; TRACE_RING_SIZE]>);
+
+// Every writer owns its slot through `NEXT_SLOT`
+unsafe impl Sync for TraceRing {}
+
+static RING: TraceRing = TraceRing(UnsafeCell::new([EMPTY_RECORD; TRACE_RING_SIZE]));
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Records an event. Use the `trace_*!` macros instead, they skip argument evaluation when
+/// tracing is off.
+#[inline]
+pub fn record(event: TraceEvent, arg0: u64, arg1: u64) {
+    let slot = NEXT_SLOT.fetch_add(1, Ordering::Relaxed) % TRACE_RING_SIZE;
+    let record = TraceRecord { tsc: get_tsc(), event, reserved: [0; 3], args: [arg0, arg1] };
+    unsafe {
+        core::ptr::write_volatile((RING.0.get() as *mut TraceRecord).add(slot), record);
+    }
+}
+
+/// Returns a copy of the recorded events, oldest first.
+pub fn snapshot() -> Vec<TraceRecord> {
+    let next = NEXT_SLOT.load(Ordering::Relaxed);
+    let count = next.min(TRACE_RING_SIZE);
+    let ring = unsafe { &*RING.0.get() };
+
+    (next - count..next)
+        .map(|i| ring[i % TRACE_RING_SIZE])
+        .collect()
+}
+
+/// Dumps the ring to serial, one event per line.
+pub fn export_to_serial() {
+    let records = snapshot();
+    serial_println!("trace: begin {}", records.len());
+    for record in records {
+        serial_println!("trace: {} {} {} {}", record.tsc, record.event.name(), record.args[0], record.args[1]);
+    }
+    serial_println!("trace: end");
+}
+
+/// Writes the ring as raw `TraceRecord`s to `disk`, starting at `lba`.
+///
+/// The first sector is a header: the `FERRTRACE` magic followed by the number of records as u64.
+pub async fn export_to_disk(disk: &Disk, lba: u64) -> Result<(), AtaError> {
+    let records = snapshot();
+
+    let mut bytes = Vec::with_capacity(512 + records.len() * core::mem::size_of::<TraceRecord>());
+    bytes.extend_from_slice(b"FERRTRACE");
+    bytes.resize(16, 0);
+    bytes.extend_from_slice(&(records.len() as u64).to_le_bytes());
+    bytes.resize(512, 0);
+
+    for record in &records {
+        bytes.extend_from_slice(&record.tsc.to_le_bytes());
+        bytes.extend_from_slice(&(record.event as u16).to_le_bytes());
+        bytes.extend_from_slice(&[0u8; 6]);
+        bytes.extend_from_slice(&record.args[0].to_le_bytes());
+        bytes.extend_from_slice(&record.args[1].to_le_bytes());
+    }
+    bytes.resize(bytes.len().next_multiple_of(512), 0);
+
+    disk.write(lba, &bytes).await
+}
+
+#[macro_export]
+macro_rules! trace_event {
+    ($event:expr, $arg0:expr, $arg1:expr) => {
+        if $crate::trace::is_enabled() {
+            $crate::trace::record($event, $arg0 as u64, $arg1 as u64);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! trace_irq_enter {
+    ($vector:expr) => {
+        $crate::trace_event!($crate::trace::TraceEvent::IrqEnter, $vector, 0)
+    };
+}
+
+#[macro_export]
+macro_rules! trace_irq_exit {
+    ($vector:expr) => {
+        $crate::trace_event!($crate::trace::TraceEvent::IrqExit, $vector, 0)
+    };
+}
+
+#[macro_export]
+macro_rules! trace_task_poll {
+    ($task_id:expr) => {
+        $crate::trace_event!($crate::trace::TraceEvent::TaskPoll, $task_id, 0)
+    };
+}
+
+#[macro_export]
+macro_rules! trace_task_poll_end {
+    ($task_id:expr, $ready:expr) => {
+        $crate::trace_event!($crate::trace::TraceEvent::TaskPollEnd, $task_id, $ready)
+    };
+}
+
+#[macro_export]
+macro_rules! trace_task_spawn {
+    ($task_id:expr) => {
+        $crate::trace_event!($crate::trace::TraceEvent::TaskSpawn, $task_id, 0)
+    };
+}
+
+#[macro_export]
+macro_rules! trace_mark {
+    ($id:expr) => {
+        $crate::trace_event!($crate::trace::TraceEvent::Mark, $id, 0)
+    };
+    ($id:expr, $value:expr) => {
+        $crate::trace_event!($crate::trace::TraceEvent::Mark, $id, $value)
+    };
+}
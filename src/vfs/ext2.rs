@@ -0,0 +1,51 @@
+// ext2 in the VFS, read-only.
+//
+// Symbolic links and device nodes are listed as files, reading them fails with `Unsupported`.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use shared_lib::ext2::{self, Ext2, Ext2Error};
+use crate::block::stream::BlockStream;
+use super::{io_error, DirEntry, FileFlags, FileSystem, FileType, Metadata, VfsError};
+
+pub(super) fn mount(stream: BlockStream) -> Result<Box<dyn FileSystem>, VfsError> {
+    Ok(Box::new(Ext2::mount(stream).map_err(error)?))
+}
+
+fn error(e: Ext2Error) -> VfsError {
+    match e {
+        Ext2Error::Io(e) => io_error(e),
+        Ext2Error::NotExt2 | Ext2Error::UnsupportedFeatures(_) | Ext2Error::Extents | Ext2Error::NotAFile => VfsError::Unsupported,
+        Ext2Error::Corrupted => VfsError::Corrupted,
+        Ext2Error::NotFound => VfsError::NotFound,
+        Ext2Error::NotADirectory => VfsError::NotADirectory,
+        Ext2Error::IsADirectory => VfsError::IsADirectory,
+    }
+}
+
+fn metadata(file_type: ext2::FileType, size: u64, modified: u32) -> Metadata {
+    let file_type = match file_type {
+        ext2::FileType::Directory => FileType::Directory,
+        _ => FileType::File,
+    };
+    Metadata { file_type, size, created: 0, modified: modified as u64, flags: FileFlags::empty() }
+}
+
+impl FileSystem for Ext2<BlockStream> {
+    fn kind(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn metadata(&mut self, path: &str) -> Result<Metadata, VfsError> {
+        Ext2::metadata(self, path).map(|info| metadata(info.file_type, info.size, info.modified)).map_err(error)
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, VfsError> {
+        let entries = Ext2::read_dir(self, path).map_err(error)?;
+        Ok(entries.into_iter().map(|entry| DirEntry { metadata: metadata(entry.file_type, entry.size, entry.modified), name: entry.name }).collect())
+    }
+
+    fn read(&mut self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Ext2::read(self, path, offset, buffer).map_err(error)
+    }
+}
@@ -0,0 +1,89 @@
+// FAT in the VFS.
+//
+// The driver writes every change through, `sync` only updates the free cluster count of FAT32
+// and flushes the cache. The read-only, hidden and system attributes are the same bits as
+// `FileFlags`; the FAT times aren't read.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use shared_lib::fat32::{self, Fat, FatError, FatType};
+use crate::block::stream::BlockStream;
+use super::{io_error, DirEntry, FileFlags, FileSystem, FileType, Metadata, VfsError};
+
+pub(super) fn mount(stream: BlockStream) -> Result<Box<dyn FileSystem>, VfsError> {
+    Ok(Box::new(Fat::mount(stream).map_err(error)?))
+}
+
+fn error(e: FatError) -> VfsError {
+    match e {
+        FatError::Io(e) => io_error(e),
+        FatError::NotFat => VfsError::Unsupported,
+        FatError::Corrupted => VfsError::Corrupted,
+        FatError::NotFound => VfsError::NotFound,
+        FatError::AlreadyExists => VfsError::AlreadyExists,
+        FatError::InvalidName => VfsError::InvalidPath,
+        FatError::NotADirectory => VfsError::NotADirectory,
+        FatError::IsADirectory => VfsError::IsADirectory,
+        FatError::DirectoryNotEmpty => VfsError::DirectoryNotEmpty,
+        FatError::NoSpace => VfsError::NoSpace,
+        FatError::FileTooLarge => VfsError::FileTooLarge,
+        FatError::ReadOnly => VfsError::ReadOnly,
+    }
+}
+
+fn metadata(entry: &fat32::DirEntry) -> Metadata {
+    let file_type = if entry.is_dir { FileType::Directory } else { FileType::File };
+    let flags = FileFlags::from_bits_truncate(entry.attributes as u32);
+    Metadata { file_type, size: entry.size, created: 0, modified: 0, flags }
+}
+
+impl FileSystem for Fat<BlockStream> {
+    fn kind(&self) -> &'static str {
+        match self.fat_type() {
+            FatType::Fat12 => "fat12",
+            FatType::Fat16 => "fat16",
+            FatType::Fat32 => "fat32",
+        }
+    }
+
+    fn metadata(&mut self, path: &str) -> Result<Metadata, VfsError> {
+        Fat::metadata(self, path).map(|entry| metadata(&entry)).map_err(error)
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, VfsError> {
+        let entries = Fat::read_dir(self, path).map_err(error)?;
+        Ok(entries.into_iter().map(|entry| DirEntry { metadata: metadata(&entry), name: entry.name }).collect())
+    }
+
+    fn read(&mut self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Fat::read(self, path, offset, buffer).map_err(error)
+    }
+
+    fn write(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        Fat::write(self, path, offset, data).map_err(error)
+    }
+
+    fn create(&mut self, path: &str) -> Result<(), VfsError> {
+        Fat::create(self, path).map_err(error)
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), VfsError> {
+        Fat::mkdir(self, path).map_err(error)
+    }
+
+    fn remove(&mut self, path: &str) -> Result<(), VfsError> {
+        Fat::remove(self, path).map_err(error)
+    }
+
+    fn rmdir(&mut self, path: &str) -> Result<(), VfsError> {
+        Fat::rmdir(self, path).map_err(error)
+    }
+
+    fn set_flags(&mut self, path: &str, flags: FileFlags) -> Result<(), VfsError> {
+        Fat::set_attributes(self, path, flags.bits() as u8).map_err(error)
+    }
+
+    fn sync(&mut self) -> Result<(), VfsError> {
+        self.flush().map_err(error)
+    }
+}
@@ -0,0 +1,112 @@
+// ferr_fs in the VFS.
+//
+// Changed inodes and the bitmap are written back by `sync`, the file data right away. Times come
+// from `vfs::now`.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use shared_lib::ferr_fs::{self, FerrFs, FileInfo, FsError};
+use shared_lib::io::{Read, Seek, SeekFrom, Write};
+use crate::block::stream::BlockStream;
+use super::{io_error, DirEntry, FileFlags, FileSystem, FileType, Metadata, VfsError};
+
+pub(super) fn mount(stream: BlockStream) -> Result<Box<dyn FileSystem>, VfsError> {
+    Ok(Box::new(FerrFs::mount(stream).map_err(error)?.with_clock(super::now)))
+}
+
+fn error(e: FsError) -> VfsError {
+    match e {
+        FsError::Io(e) => io_error(e),
+        FsError::NotFormatted => VfsError::Unsupported,
+        FsError::BadChecksum | FsError::Corrupted | FsError::DeviceTooSmall => VfsError::Corrupted,
+        FsError::NotFound => VfsError::NotFound,
+        FsError::AlreadyExists => VfsError::AlreadyExists,
+        FsError::InvalidName => VfsError::InvalidPath,
+        FsError::NotADirectory => VfsError::NotADirectory,
+        FsError::IsADirectory => VfsError::IsADirectory,
+        FsError::DirectoryNotEmpty => VfsError::DirectoryNotEmpty,
+        FsError::NoFreeInode | FsError::NoSpace => VfsError::NoSpace,
+        FsError::FileTooLarge => VfsError::FileTooLarge,
+        FsError::ReadOnly => VfsError::ReadOnly,
+    }
+}
+
+fn metadata(info: &FileInfo) -> Metadata {
+    let file_type = match info.file_type {
+        ferr_fs::FileType::File => FileType::File,
+        ferr_fs::FileType::Directory => FileType::Directory,
+    };
+    Metadata {
+        file_type,
+        size: info.size,
+        created: info.created,
+        modified: info.modified,
+        flags: FileFlags::from_bits_retain(info.flags.bits()),
+    }
+}
+
+impl FileSystem for FerrFs<BlockStream> {
+    fn kind(&self) -> &'static str {
+        "ferr_fs"
+    }
+
+    fn metadata(&mut self, path: &str) -> Result<Metadata, VfsError> {
+        FerrFs::metadata(self, path).map(|info| metadata(&info)).map_err(error)
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, VfsError> {
+        let entries = FerrFs::read_dir(self, path).map_err(error)?;
+        Ok(entries.into_iter().map(|info| DirEntry { metadata: metadata(&info), name: info.name }).collect())
+    }
+
+    fn read(&mut self, path: &str, offset: u64, mut buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let mut file = self.open(path).map_err(error)?;
+        file.seek(SeekFrom::Start(offset)).map_err(io_error)?;
+        let mut total = 0;
+        while !buffer.is_empty() {
+            match file.read(buffer).map_err(io_error)? {
+                0 => break,
+                n => {
+                    total += n;
+                    buffer = &mut buffer[n..];
+                },
+            }
+        }
+        Ok(total)
+    }
+
+    fn write(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        let mut file = self.open(path).map_err(error)?;
+        file.seek(SeekFrom::Start(offset)).map_err(io_error)?;
+        file.write_all(data).map_err(io_error)?;
+        Ok(data.len())
+    }
+
+    fn create(&mut self, path: &str) -> Result<(), VfsError> {
+        FerrFs::create(self, path).map(|_| ()).map_err(error)
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), VfsError> {
+        FerrFs::mkdir(self, path).map_err(error)
+    }
+
+    fn remove(&mut self, path: &str) -> Result<(), VfsError> {
+        self.delete(path).map_err(error)
+    }
+
+    fn rmdir(&mut self, path: &str) -> Result<(), VfsError> {
+        FerrFs::rmdir(self, path).map_err(error)
+    }
+
+    fn set_flags(&mut self, path: &str, flags: FileFlags) -> Result<(), VfsError> {
+        FerrFs::set_flags(self, path, ferr_fs::FileFlags::from_bits_retain(flags.bits())).map_err(error)
+    }
+
+    fn set_modified(&mut self, path: &str, modified: u64) -> Result<(), VfsError> {
+        FerrFs::set_modified(self, path, modified).map_err(error)
+    }
+
+    fn sync(&mut self) -> Result<(), VfsError> {
+        self.flush().map_err(error)
+    }
+}
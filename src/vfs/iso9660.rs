@@ -0,0 +1,51 @@
+// ISO 9660 in the VFS, read-only.
+//
+// A CD-ROM in an ATAPI drive is a disk like any other, as is an image attached as a hard disk;
+// either is mounted whole with `mount_disk`.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use shared_lib::iso9660::{self, Iso9660, IsoError};
+use crate::block::stream::BlockStream;
+use super::{io_error, DirEntry, FileFlags, FileSystem, FileType, Metadata, VfsError};
+
+pub(super) fn mount(stream: BlockStream) -> Result<Box<dyn FileSystem>, VfsError> {
+    let fs = Iso9660::mount(stream).map_err(error)?;
+    log::info!("[vfs] ISO 9660 volume {}{}", fs.volume_id(), if fs.has_rock_ridge() { " with Rock Ridge names" } else { "" });
+    Ok(Box::new(fs))
+}
+
+fn error(e: IsoError) -> VfsError {
+    match e {
+        IsoError::Io(e) => io_error(e),
+        IsoError::NotIso => VfsError::Unsupported,
+        IsoError::Corrupted => VfsError::Corrupted,
+        IsoError::NotFound => VfsError::NotFound,
+        IsoError::NotADirectory => VfsError::NotADirectory,
+        IsoError::IsADirectory => VfsError::IsADirectory,
+    }
+}
+
+fn metadata(entry: &iso9660::DirEntry) -> Metadata {
+    let file_type = if entry.is_dir { FileType::Directory } else { FileType::File };
+    Metadata { file_type, size: entry.size, created: 0, modified: 0, flags: FileFlags::empty() }
+}
+
+impl FileSystem for Iso9660<BlockStream> {
+    fn kind(&self) -> &'static str {
+        "iso9660"
+    }
+
+    fn metadata(&mut self, path: &str) -> Result<Metadata, VfsError> {
+        Iso9660::metadata(self, path).map(|entry| metadata(&entry)).map_err(error)
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, VfsError> {
+        let entries = Iso9660::read_dir(self, path).map_err(error)?;
+        Ok(entries.into_iter().map(|entry| DirEntry { metadata: metadata(&entry), name: entry.name }).collect())
+    }
+
+    fn read(&mut self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Iso9660::read(self, path, offset, buffer).map_err(error)
+    }
+}
@@ -0,0 +1,235 @@
+// Memory-mapped files.
+//
+// `mmap` gives a file a region of the kernel address space backed by the page cache: one frame
+// per page of the file, shared by every mapping of it. `mmap` reads the pages of the mapping into
+// the cache, unless an earlier mapping of the file did, and maps all of them right away: nothing
+// is paged in on fault, since the fault handler can neither wait for the disk nor for the locks.
+// The cached frames are freed with the last mapping of the file.
+//
+// Writable mappings write the pages the CPU marked dirty back to the file on `msync`, `munmap`
+// and `vfs::sync_all`. A mapping longer than the file grows it on write-back. `vfs::write`
+// doesn't go through the cache: a file written to while mapped keeps showing its old content in
+// the mapping, and a write-back of the same pages overwrites the new one.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use shared_lib::addr::VirtAddr;
+use shared_lib::page_table::{map_address_with_offset, take_dirty, unmap_range_with_offset, PageTableFlags, PAGE_SIZE};
+use shared_lib::phys_mapping_offset;
+use shared_lib::spinlock::Spinlock;
+use crate::allocator::FRAME_ALLOCATOR;
+use crate::memory::active_level_4_table;
+use crate::task::sync::Mutex;
+use crate::{thread, vm};
+use super::{FileType, VfsError};
+
+bitflags! {
+    /// Mappings are readable, these add to it
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MapFlags: u32 {
+        const WRITE = 1;
+        const EXEC = 1 << 1;
+    }
+}
+
+/// The cached pages of a mapped file
+struct CachedFile {
+    path: String,
+    /// Frames of the pages from the start of the file, 0 for the ones not read yet
+    frames: Vec<u64>,
+    mappings: usize,
+}
+
+struct Mapping {
+    start: u64,
+    /// Bytes of the file mapped, the region is rounded up to pages
+    len: u64,
+    path: String,
+    flags: PageTableFlags,
+    /// Of the pages from the start of the mapping
+    frames: Vec<u64>,
+}
+
+impl Mapping {
+    fn writable(&self) -> bool {
+        self.flags.contains(PageTableFlags::WRITABLE)
+    }
+}
+
+/// Held while pages are read, so a file is only read once
+static PAGE_CACHE: Mutex<Vec<CachedFile>> = Mutex::new(Vec::new());
+static MAPPINGS: Spinlock<Vec<Mapping>> = Spinlock::new(Vec::new());
+
+/// The frame through the physical memory window
+unsafe fn page_bytes<'a>(frame: u64) -> &'a mut [u8] {
+    core::slice::from_raw_parts_mut((frame + phys_mapping_offset()) as *mut u8, PAGE_SIZE as usize)
+}
+
+/// Reads the first `pages` pages of the file into the cache, returns their frames
+fn load(file: &mut CachedFile, pages: usize) -> Result<Vec<u64>, VfsError> {
+    if file.frames.len() < pages {
+        file.frames.resize(pages, 0);
+    }
+    for (index, frame) in file.frames[..pages].iter_mut().enumerate().filter(|(_, frame)| **frame == 0) {
+        *frame = FRAME_ALLOCATOR.lock().as_mut().and_then(|allocator| allocator.allocate_frame()).ok_or(VfsError::NoSpace)?;
+        let page = unsafe { page_bytes(*frame) };
+        page.fill(0);
+        super::read(&file.path, index as u64 * PAGE_SIZE, page)?;
+    }
+    Ok(file.frames[..pages].to_vec())
+}
+
+/// Maps `frames` from `start` on, unmapping them again if one fails
+fn map_pages(start: VirtAddr, frames: &[u64], flags: PageTableFlags) -> Result<(), VfsError> {
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().ok_or(VfsError::NoSpace)?;
+    for (index, &frame) in frames.iter().enumerate() {
+        let page = VirtAddr::new(start.0 + index as u64 * PAGE_SIZE);
+        let mapped = unsafe {
+            map_address_with_offset(active_level_4_table(), page, frame, flags, frame_allocator, phys_mapping_offset())
+        };
+        if mapped.is_err() {
+            unsafe {
+                let _ = unmap_range_with_offset(active_level_4_table(), start, index, phys_mapping_offset(), |_, _| {});
+            }
+            return Err(VfsError::NoSpace);
+        }
+    }
+    Ok(())
+}
+
+/// Drops a mapping of the file, its frames are freed with the last one
+fn release(cache: &mut Vec<CachedFile>, path: &str) {
+    let Some(index) = cache.iter().position(|file| file.path == path) else {
+        return;
+    };
+    cache[index].mappings -= 1;
+    if cache[index].mappings > 0 {
+        return;
+    }
+
+    let file = cache.swap_remove(index);
+    if let Some(frame_allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+        for &frame in file.frames.iter().filter(|frame| **frame != 0) {
+            unsafe {
+                frame_allocator.deallocate_frame(frame);
+            }
+        }
+    }
+}
+
+/// Maps the first `len` bytes of the file at `path`, returns where. Blocks until the pages are
+/// in the page cache.
+pub fn mmap(path: &str, len: usize, flags: MapFlags) -> Result<VirtAddr, VfsError> {
+    let path = super::normalize(path)?;
+    if super::metadata(&path)?.file_type == FileType::Directory {
+        return Err(VfsError::IsADirectory);
+    }
+
+    let pages = (len as u64).div_ceil(PAGE_SIZE).max(1) as usize;
+    let mut page_flags = PageTableFlags::NO_EXECUTE;
+    if flags.contains(MapFlags::WRITE) {
+        page_flags |= PageTableFlags::WRITABLE;
+    }
+    if flags.contains(MapFlags::EXEC) {
+        page_flags -= PageTableFlags::NO_EXECUTE;
+    }
+
+    let mut cache = thread::block_on(PAGE_CACHE.lock());
+    let index = match cache.iter().position(|file| file.path == path) {
+        Some(index) => index,
+        None => {
+            cache.push(CachedFile { path: path.clone(), frames: Vec::new(), mappings: 0 });
+            cache.len() - 1
+        },
+    };
+    cache[index].mappings += 1;
+    let mapped = load(&mut cache[index], pages).and_then(|frames| {
+        let start = vm::allocate_region(pages * PAGE_SIZE as usize, page_flags, "mmap").map_err(|_| VfsError::NoSpace)?;
+        if let Err(e) = map_pages(start, &frames, page_flags) {
+            let _ = vm::free_region(start);
+            return Err(e);
+        }
+        Ok((start, frames))
+    });
+    let (start, frames) = match mapped {
+        Ok(mapped) => mapped,
+        Err(e) => {
+            release(&mut cache, &path);
+            return Err(e);
+        },
+    };
+
+    log::debug!("[vfs] {} mapped at {}", path, start);
+    MAPPINGS.lock().push(Mapping { start: start.0, len: len as u64, path, flags: page_flags, frames });
+    Ok(start)
+}
+
+/// Writes the pages of a writable mapping that changed since the last write-back
+fn write_back(mapping: &Mapping) -> Result<(), VfsError> {
+    for (index, &frame) in mapping.frames.iter().enumerate() {
+        let offset = index as u64 * PAGE_SIZE;
+        let dirty = unsafe { take_dirty(active_level_4_table(), VirtAddr::new(mapping.start + offset), phys_mapping_offset()) };
+        if dirty != Ok(true) || offset >= mapping.len {
+            continue;
+        }
+        let end = (offset + PAGE_SIZE).min(mapping.len);
+        let page = unsafe { page_bytes(frame) };
+        super::write(&mapping.path, offset, &page[..(end - offset) as usize])?;
+    }
+    Ok(())
+}
+
+/// Writes the changes to the mapping starting at `addr` back to its file
+pub fn msync(addr: VirtAddr) -> Result<(), VfsError> {
+    let mapping = {
+        let mappings = MAPPINGS.lock();
+        let mapping = mappings.iter().find(|mapping| mapping.start == addr.0).ok_or(VfsError::NotFound)?;
+        if !mapping.writable() {
+            return Ok(());
+        }
+        Mapping { path: mapping.path.clone(), frames: mapping.frames.clone(), ..*mapping }
+    };
+    write_back(&mapping)
+}
+
+/// Writes back and removes the mapping starting at `addr`. The mapping is gone even if the
+/// write-back failed.
+pub fn munmap(addr: VirtAddr) -> Result<(), VfsError> {
+    let result = msync(addr);
+    let mapping = {
+        let mut mappings = MAPPINGS.lock();
+        let index = mappings.iter().position(|mapping| mapping.start == addr.0).ok_or(VfsError::NotFound)?;
+        mappings.swap_remove(index)
+    };
+
+    let unmapped = unsafe {
+        unmap_range_with_offset(active_level_4_table(), addr, mapping.frames.len(), phys_mapping_offset(), |_, _| {})
+    };
+    if let Err(e) = unmapped {
+        log::warn!("[vfs] unmapping {} at {}: {}", mapping.path, addr, e);
+    }
+    if let Err(e) = vm::free_region(addr) {
+        log::warn!("[vfs] no VMA for the mapping at {}: {:?}", addr, e);
+    }
+    release(&mut thread::block_on(PAGE_CACHE.lock()), &mapping.path);
+    result
+}
+
+/// Writes back every writable mapping, returns the first error
+pub(super) fn sync_all() -> Result<(), VfsError> {
+    let starts: Vec<VirtAddr> = MAPPINGS.lock().iter()
+        .filter(|mapping| mapping.writable())
+        .map(|mapping| VirtAddr::new(mapping.start))
+        .collect();
+    let mut result = Ok(());
+    for start in starts {
+        match msync(start) {
+            // unmapped meanwhile, which wrote it back
+            Ok(()) | Err(VfsError::NotFound) => {},
+            Err(e) => result = result.and(Err(e)),
+        }
+    }
+    result
+}
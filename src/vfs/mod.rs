@@ -0,0 +1,447 @@
+// Virtual filesystem.
+//
+// Filesystems are mounted on absolute paths and share a single tree: a path belongs to the
+// mount with the longest matching path, which gets the rest of it from its own root. Mount
+// points show up as directories in the listing of their parent, even if the filesystem below
+// has no such directory.
+//
+// The filesystems on partitions are synchronous and reach their disk through a
+// `block::stream::BlockStream`, which waits on the block cache with `thread::block_on`. All of
+// the functions here can block for a long time and must run on a kernel thread, e.g. under
+// `join::spawn_blocking`. Each filesystem is behind its own mutex, calls to different mounts run
+// concurrently.
+//
+// `mount_partition` probes the partition for the filesystems it knows, in turn: ferr_fs, FAT,
+// then the read-only ext2 and ISO 9660. `mount_disk` does the same for a disk without a
+// partition table. `mount_tmpfs` mounts a filesystem held on the heap, there is one on /tmp.
+//
+// Files can also be mapped in memory with `mmap`, see `mmap.rs`.
+//
+// Times in `Metadata` are seconds since the Unix epoch, from `now`, and 0 where the filesystem
+// doesn't keep them. `FileFlags` are the DOS attributes, on FAT, or their ferr_fs equivalent.
+
+mod ext2;
+mod fat32;
+mod ferr_fs;
+mod iso9660;
+mod mmap;
+mod tmpfs;
+
+pub use mmap::{mmap, msync, munmap, MapFlags};
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use chrono::{DateTime, Datelike, Timelike};
+use core::fmt;
+use shared_lib::io::IoError;
+use shared_lib::spinlock::Spinlock;
+use crate::block::Disk;
+use crate::block::partition::Partition;
+use crate::block::stream::BlockStream;
+use crate::task::sync::Mutex;
+use crate::task::timer;
+use crate::{chrono as rtc, thread};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    /// Not absolute, or a name the filesystem doesn't allow
+    InvalidPath,
+    ReadOnly,
+    NoSpace,
+    FileTooLarge,
+    /// The filesystem is inconsistent
+    Corrupted,
+    /// The device failed
+    Io,
+    /// A path is already mounted, or nothing is mounted there
+    Busy,
+    /// No known filesystem on the partition, or an unknown type asked for
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct FileFlags: u32 {
+        const READ_ONLY = 1;
+        const HIDDEN = 1 << 1;
+        const SYSTEM = 1 << 2;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub size: u64,
+    /// Seconds since the Unix epoch, 0 if unknown
+    pub created: u64,
+    pub modified: u64,
+    pub flags: FileFlags,
+}
+
+impl Metadata {
+    /// A directory that only exists as a mount point
+    fn mount_point() -> Metadata {
+        Metadata { file_type: FileType::Directory, size: 0, created: 0, modified: 0, flags: FileFlags::empty() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+/// A mounted filesystem. Paths are absolute from its root, like `/` or `/EFI/BOOT`. The
+/// modifying operations fail with `ReadOnly` unless implemented.
+pub trait FileSystem: Send {
+    /// Short name of the filesystem, like `fat16`
+    fn kind(&self) -> &'static str;
+
+    fn metadata(&mut self, path: &str) -> Result<Metadata, VfsError>;
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, VfsError>;
+
+    /// Reads from `offset` until the buffer is full or the file ends, returns how many bytes
+    fn read(&mut self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError>;
+
+    /// Writes all of `data` at `offset`, growing the file as needed
+    fn write(&mut self, _path: &str, _offset: u64, _data: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Creates an empty file
+    fn create(&mut self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn mkdir(&mut self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn remove(&mut self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn rmdir(&mut self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn set_flags(&mut self, _path: &str, _flags: FileFlags) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    /// Seconds since the Unix epoch
+    fn set_modified(&mut self, _path: &str, _modified: u64) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    /// Makes the changes durable
+    fn sync(&mut self) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
+
+struct Mount {
+    path: String,
+    /// What is mounted, like a partition id
+    source: String,
+    kind: &'static str,
+    fs: Mutex<Box<dyn FileSystem>>,
+}
+
+static MOUNTS: Spinlock<Vec<Arc<Mount>>> = Spinlock::new(Vec::new());
+
+/// A mount point as listed by `mounts`
+pub struct MountInfo {
+    pub path: String,
+    pub source: String,
+    pub kind: &'static str,
+}
+
+impl fmt::Display for MountInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on {} type {}", self.source, self.path, self.kind)
+    }
+}
+
+/// `path` without `.`, `..` and repeated slashes
+pub fn normalize(path: &str) -> Result<String, VfsError> {
+    if !path.starts_with('/') {
+        return Err(VfsError::InvalidPath);
+    }
+    let mut components: Vec<&str> = Vec::new();
+    for name in path.split('/') {
+        match name {
+            "" | "." => {},
+            ".." => {
+                components.pop();
+            },
+            name => components.push(name),
+        }
+    }
+    let mut normalized = String::new();
+    for name in components {
+        normalized.push('/');
+        normalized.push_str(name);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// The part of `path` below `mount_path`, if it is below
+fn strip_mount<'p>(path: &'p str, mount_path: &str) -> Option<&'p str> {
+    if mount_path == "/" {
+        return Some(path);
+    }
+    match path.strip_prefix(mount_path)? {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+/// The mount `path` belongs to and the path in it
+fn resolve(path: &str) -> Result<(Arc<Mount>, String), VfsError> {
+    let path = normalize(path)?;
+    let mounts = MOUNTS.lock();
+    let (mount, rest) = mounts.iter()
+        .filter_map(|mount| strip_mount(&path, &mount.path).map(|rest| (mount, rest)))
+        .max_by_key(|(mount, _)| mount.path.len())
+        .ok_or(VfsError::NotFound)?;
+    Ok((mount.clone(), String::from(rest)))
+}
+
+/// Runs `f` on the filesystem `path` belongs to, with the path in it
+fn with_fs<T>(path: &str, f: impl FnOnce(&mut dyn FileSystem, &str) -> Result<T, VfsError>) -> Result<T, VfsError> {
+    let (mount, rest) = resolve(path)?;
+    let mut fs = thread::block_on(mount.fs.lock());
+    f(&mut **fs, &rest)
+}
+
+/// Whether a filesystem is mounted at `path` or above it
+pub fn is_mounted(path: &str) -> bool {
+    resolve(path).is_ok()
+}
+
+pub fn mount(path: &str, source: &str, fs: Box<dyn FileSystem>) -> Result<(), VfsError> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(VfsError::Busy);
+    }
+    log::info!("[vfs] {} mounted on {} ({})", source, path, fs.kind());
+    mounts.push(Arc::new(Mount { path, source: String::from(source), kind: fs.kind(), fs: Mutex::new(fs) }));
+    Ok(())
+}
+
+/// Syncs the filesystem and removes it from the tree. Calls already running on it complete.
+pub fn unmount(path: &str) -> Result<(), VfsError> {
+    let path = normalize(path)?;
+    let mount = {
+        let mut mounts = MOUNTS.lock();
+        let index = mounts.iter().position(|mount| mount.path == path).ok_or(VfsError::Busy)?;
+        mounts.remove(index)
+    };
+    let result = thread::block_on(mount.fs.lock()).sync();
+    log::info!("[vfs] {} unmounted from {}", mount.source, path);
+    result
+}
+
+/// Doesn't block, unlike the rest
+pub fn mounts() -> Vec<MountInfo> {
+    MOUNTS.lock().iter().map(|mount| MountInfo {
+        path: mount.path.clone(),
+        source: mount.source.clone(),
+        kind: mount.kind,
+    }).collect()
+}
+
+type Probe = fn(BlockStream) -> Result<Box<dyn FileSystem>, VfsError>;
+
+/// The filesystems `mount_partition` knows, by the kind it takes, in probing order
+const KINDS: [(&str, Probe); 4] = [
+    ("ferr_fs", ferr_fs::mount),
+    ("fat", fat32::mount),
+    ("ext2", ext2::mount),
+    ("iso9660", iso9660::mount),
+];
+
+/// The filesystem of `kind` on the stream, or the first one found if None
+fn probe(stream: impl Fn() -> BlockStream, kind: Option<&str>) -> Result<Box<dyn FileSystem>, VfsError> {
+    match kind {
+        Some(kind) => {
+            let (_, mount) = KINDS.iter().find(|(name, _)| *name == kind).ok_or(VfsError::Unsupported)?;
+            mount(stream())
+        },
+        None => KINDS.iter().find_map(|(_, mount)| mount(stream()).ok()).ok_or(VfsError::Unsupported),
+    }
+}
+
+/// Mounts the filesystem on `partition`. `kind` is one of `KINDS`, any of them if None.
+pub fn mount_partition(partition: &Partition, path: &str, kind: Option<&str>) -> Result<(), VfsError> {
+    let fs = probe(|| BlockStream::new(partition), kind)?;
+    mount(path, &partition.id(), fs)
+}
+
+/// Mounts a filesystem spanning a whole disk, like an ISO image attached as one
+pub fn mount_disk(disk: &Arc<Disk>, path: &str, kind: Option<&str>) -> Result<(), VfsError> {
+    let fs = probe(|| BlockStream::whole_disk(disk.clone()), kind)?;
+    mount(path, disk.name(), fs)
+}
+
+/// Mounts an empty tmpfs
+pub fn mount_tmpfs(path: &str) -> Result<(), VfsError> {
+    mount(path, "tmpfs", tmpfs::mount())
+}
+
+pub fn metadata(path: &str) -> Result<Metadata, VfsError> {
+    with_fs(path, |fs, path| fs.metadata(path))
+}
+
+/// The entries of a directory, mount points below it included
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, VfsError> {
+    let path = normalize(path)?;
+    let mut entries = with_fs(&path, |fs, path| fs.read_dir(path))?;
+    let mount_points: Vec<String> = MOUNTS.lock().iter()
+        .filter(|mount| mount.path != "/")
+        .filter_map(|mount| {
+            let (parent, name) = mount.path.rsplit_once('/')?;
+            let parent = if parent.is_empty() { "/" } else { parent };
+            (parent == path).then(|| String::from(name))
+        })
+        .collect();
+    for name in mount_points {
+        entries.retain(|entry| entry.name != name);
+        entries.push(DirEntry { name, metadata: Metadata::mount_point() });
+    }
+    Ok(entries)
+}
+
+pub fn read(path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+    with_fs(path, |fs, path| fs.read(path, offset, buffer))
+}
+
+/// The whole content of a file
+pub fn read_to_end(path: &str) -> Result<Vec<u8>, VfsError> {
+    with_fs(path, |fs, path| {
+        let metadata = fs.metadata(path)?;
+        if metadata.file_type == FileType::Directory {
+            return Err(VfsError::IsADirectory);
+        }
+        let mut data = alloc::vec![0u8; metadata.size as usize];
+        let len = fs.read(path, 0, &mut data)?;
+        data.truncate(len);
+        Ok(data)
+    })
+}
+
+pub fn write(path: &str, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+    with_fs(path, |fs, path| fs.write(path, offset, data))
+}
+
+pub fn create(path: &str) -> Result<(), VfsError> {
+    with_fs(path, |fs, path| fs.create(path))
+}
+
+pub fn mkdir(path: &str) -> Result<(), VfsError> {
+    with_fs(path, |fs, path| fs.mkdir(path))
+}
+
+pub fn remove(path: &str) -> Result<(), VfsError> {
+    with_fs(path, |fs, path| fs.remove(path))
+}
+
+pub fn rmdir(path: &str) -> Result<(), VfsError> {
+    if MOUNTS.lock().iter().any(|mount| Ok(&mount.path) == normalize(path).as_ref()) {
+        return Err(VfsError::Busy);
+    }
+    with_fs(path, |fs, path| fs.rmdir(path))
+}
+
+pub fn set_flags(path: &str, flags: FileFlags) -> Result<(), VfsError> {
+    with_fs(path, |fs, path| fs.set_flags(path, flags))
+}
+
+pub fn set_modified(path: &str, modified: u64) -> Result<(), VfsError> {
+    with_fs(path, |fs, path| fs.set_modified(path, modified))
+}
+
+/// Seconds since the Unix epoch, from the timer once the wall clock is set, else the RTC
+pub fn now() -> u64 {
+    match timer::now() {
+        Some(snapshot) if snapshot.wall_secs != 0 => snapshot.wall_secs,
+        _ => rtc::read_rtc().timestamp() as u64,
+    }
+}
+
+/// `sync_all` as a SysRq sync hook
+pub fn sync_hook() -> Result<(), &'static str> {
+    sync_all().map_err(|_| "a filesystem failed to sync")
+}
+
+/// Writes back the mapped files and syncs every mounted filesystem, returns the first error
+pub fn sync_all() -> Result<(), VfsError> {
+    let mut result = mmap::sync_all();
+    let mounts: Vec<Arc<Mount>> = MOUNTS.lock().clone();
+    for mount in mounts {
+        if let Err(e) = thread::block_on(mount.fs.lock()).sync() {
+            log::warn!("[vfs] sync of {} failed: {:?}", mount.path, e);
+            result = result.and(Err(e));
+        }
+    }
+    result
+}
+
+fn io_error(e: IoError) -> VfsError {
+    match e {
+        IoError::StorageFull | IoError::WriteZero => VfsError::NoSpace,
+        IoError::InvalidData | IoError::UnexpectedEof => VfsError::Corrupted,
+        IoError::Device | IoError::InvalidSeek => VfsError::Io,
+        IoError::ReadOnly => VfsError::ReadOnly,
+    }
+}
+
+/// Writes the listing of `path` to `w`, like `ls -l`
+pub fn dump(w: &mut impl fmt::Write, path: &str) -> Result<(), VfsError> {
+    let mut entries = read_dir(path)?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    for DirEntry { name, metadata } in entries {
+        let flag = |flag, c| if metadata.flags.contains(flag) { c } else { '-' };
+        let kind = if metadata.file_type == FileType::Directory { 'd' } else { '-' };
+        let modified = match DateTime::from_timestamp(metadata.modified as i64, 0) {
+            Some(time) if metadata.modified != 0 => alloc::format!(
+                "{:04}-{:02}-{:02} {:02}:{:02}",
+                time.year(), time.month(), time.day(), time.hour(), time.minute(),
+            ),
+            _ => String::from("-"),
+        };
+        writeln!(
+            w,
+            "{}{}{}{} {:>10} {:>16} {}",
+            kind,
+            flag(FileFlags::READ_ONLY, 'r'),
+            flag(FileFlags::HIDDEN, 'h'),
+            flag(FileFlags::SYSTEM, 's'),
+            metadata.size,
+            modified,
+            name,
+        ).map_err(|_| VfsError::Io)?;
+    }
+    Ok(())
+}
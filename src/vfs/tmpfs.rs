@@ -0,0 +1,195 @@
+// tmpfs, a filesystem on the heap.
+//
+// A tree of nodes without a device behind it: file data is a `Vec` per file, directories map
+// names to their children in order. Nothing survives an unmount. The data of all the files
+// together is capped at `CAPACITY`, and a file the heap can't grow fails with `NoSpace` instead
+// of bringing the kernel down.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::{DirEntry, FileFlags, FileSystem, FileType, Metadata, VfsError};
+
+/// Bytes of file data a tmpfs holds at most
+pub const CAPACITY: u64 = 64 * 1024 * 1024;
+
+/// Longest name of an entry, as on the other filesystems
+const MAX_NAME_LEN: usize = 255;
+
+enum Content {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Node>),
+}
+
+struct Node {
+    created: u64,
+    modified: u64,
+    flags: FileFlags,
+    content: Content,
+}
+
+impl Node {
+    fn new(content: Content) -> Node {
+        let now = super::now();
+        Node { created: now, modified: now, flags: FileFlags::empty(), content }
+    }
+
+    fn metadata(&self) -> Metadata {
+        let (file_type, size) = match &self.content {
+            Content::File(data) => (FileType::File, data.len() as u64),
+            Content::Directory(entries) => (FileType::Directory, entries.len() as u64),
+        };
+        Metadata { file_type, size, created: self.created, modified: self.modified, flags: self.flags }
+    }
+
+    fn entries(&mut self) -> Result<&mut BTreeMap<String, Node>, VfsError> {
+        match &mut self.content {
+            Content::Directory(entries) => Ok(entries),
+            Content::File(_) => Err(VfsError::NotADirectory),
+        }
+    }
+}
+
+struct TmpFs {
+    root: Node,
+    /// Bytes of file data, against `CAPACITY`
+    used: u64,
+}
+
+pub(super) fn mount() -> Box<dyn FileSystem> {
+    Box::new(TmpFs { root: Node::new(Content::Directory(BTreeMap::new())), used: 0 })
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|name| !name.is_empty())
+}
+
+impl TmpFs {
+    fn node(&mut self, path: &str) -> Result<&mut Node, VfsError> {
+        let mut node = &mut self.root;
+        for name in components(path) {
+            node = node.entries()?.get_mut(name).ok_or(VfsError::NotFound)?;
+        }
+        Ok(node)
+    }
+
+    /// The directory holding the last component of `path`, and that component
+    fn parent<'p>(&mut self, path: &'p str) -> Result<(&mut Node, &'p str), VfsError> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() || name == "." || name == ".." || name.len() > MAX_NAME_LEN {
+            return Err(VfsError::InvalidPath);
+        }
+        let parent = self.node(parent)?;
+        parent.entries()?;
+        Ok((parent, name))
+    }
+
+    fn add(&mut self, path: &str, content: Content) -> Result<(), VfsError> {
+        let (parent, name) = self.parent(path)?;
+        let entries = parent.entries()?;
+        if entries.contains_key(name) {
+            return Err(VfsError::AlreadyExists);
+        }
+        entries.insert(String::from(name), Node::new(content));
+        parent.modified = super::now();
+        Ok(())
+    }
+
+    fn delete(&mut self, path: &str, file_type: FileType) -> Result<(), VfsError> {
+        let (parent, name) = self.parent(path)?;
+        let entries = parent.entries()?;
+        let node = entries.get_mut(name).ok_or(VfsError::NotFound)?;
+        match (&node.content, file_type) {
+            (Content::Directory(_), FileType::File) => return Err(VfsError::IsADirectory),
+            (Content::File(_), FileType::Directory) => return Err(VfsError::NotADirectory),
+            (Content::Directory(children), _) if !children.is_empty() => return Err(VfsError::DirectoryNotEmpty),
+            _ if node.flags.contains(FileFlags::READ_ONLY) => return Err(VfsError::ReadOnly),
+            _ => {},
+        }
+        let freed = match entries.remove(name) {
+            Some(Node { content: Content::File(data), .. }) => data.len() as u64,
+            _ => 0,
+        };
+        parent.modified = super::now();
+        self.used -= freed;
+        Ok(())
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn kind(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn metadata(&mut self, path: &str) -> Result<Metadata, VfsError> {
+        Ok(self.node(path)?.metadata())
+    }
+
+    fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, VfsError> {
+        let entries = self.node(path)?.entries()?;
+        Ok(entries.iter().map(|(name, node)| DirEntry { name: name.clone(), metadata: node.metadata() }).collect())
+    }
+
+    fn read(&mut self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let Content::File(data) = &self.node(path)?.content else {
+            return Err(VfsError::IsADirectory);
+        };
+        let start = (offset as usize).min(data.len());
+        let len = buffer.len().min(data.len() - start);
+        buffer[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write(&mut self, path: &str, offset: u64, bytes: &[u8]) -> Result<usize, VfsError> {
+        let used = self.used;
+        let node = self.node(path)?;
+        if node.flags.contains(FileFlags::READ_ONLY) {
+            return Err(VfsError::ReadOnly);
+        }
+        let Content::File(data) = &mut node.content else {
+            return Err(VfsError::IsADirectory);
+        };
+
+        let end = offset.checked_add(bytes.len() as u64).ok_or(VfsError::FileTooLarge)?;
+        let grown = end.saturating_sub(data.len() as u64);
+        if used.checked_add(grown).map_or(true, |total| total > CAPACITY) {
+            return Err(VfsError::NoSpace);
+        }
+        if grown > 0 {
+            data.try_reserve(grown as usize).map_err(|_| VfsError::NoSpace)?;
+            data.resize(end as usize, 0);
+        }
+        data[offset as usize..end as usize].copy_from_slice(bytes);
+        node.modified = super::now();
+        self.used += grown;
+        Ok(bytes.len())
+    }
+
+    fn create(&mut self, path: &str) -> Result<(), VfsError> {
+        self.add(path, Content::File(Vec::new()))
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), VfsError> {
+        self.add(path, Content::Directory(BTreeMap::new()))
+    }
+
+    fn remove(&mut self, path: &str) -> Result<(), VfsError> {
+        self.delete(path, FileType::File)
+    }
+
+    fn rmdir(&mut self, path: &str) -> Result<(), VfsError> {
+        self.delete(path, FileType::Directory)
+    }
+
+    fn set_flags(&mut self, path: &str, flags: FileFlags) -> Result<(), VfsError> {
+        self.node(path)?.flags = flags;
+        Ok(())
+    }
+
+    fn set_modified(&mut self, path: &str, modified: u64) -> Result<(), VfsError> {
+        self.node(path)?.modified = modified;
+        Ok(())
+    }
+}
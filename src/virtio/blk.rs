@@ -0,0 +1,418 @@
+// Disks over virtio-blk.
+//
+// The disks QEMU attaches with `-drive if=virtio`. A request is a chain of three buffers in the
+// DMA area of the disk: a header with the request type and the first sector, the data, and a
+// status byte the device fills in. The device copies the data itself, so a request costs one
+// notification and one interrupt however many sectors it moves. Requests go one at a time over
+// the single queue. The DMA area belongs to the device until the chain comes back on the used
+// ring: a task dropped while waiting for it leaves the request in flight, and the next request
+// waits for it to complete before reusing the area.
+//
+// The tasks wait for the completion interrupt, delivered on the INTx line the firmware assigned.
+// Only the identification during the probe spins on the used ring.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+use futures_util::future::BoxFuture;
+use futures_util::task::AtomicWaker;
+use shared_lib::interrupts::without_interrupts;
+use shared_lib::phys_mapping_offset;
+use shared_lib::spinlock::Spinlock;
+use crate::allocator::{alloc_contiguous, free_contiguous};
+use crate::ide::{AtaError, BlockDevice, SECTOR_SIZE};
+use crate::interrupts::{self, Vector};
+use crate::ioapic;
+use crate::pci::{PciAddress, PciDevice, PciMatch, ProbeResult, Probed};
+use crate::percpu;
+use crate::task::sync::Mutex;
+use super::{QueueBuffer, VirtioError, VirtioPci, Virtqueue, VIRTIO_VENDOR_ID};
+
+const FEATURE_RO: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+// request types
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+const REQUEST_GET_ID: u32 = 8;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// Length of the serial number returned by GET_ID, not terminated if it has all 20 bytes
+const ID_LEN: usize = 20;
+
+/// Only one request is in flight, a few descriptors are enough
+const QUEUE_SIZE: u16 = 16;
+/// The header and the status byte share the first frame of the DMA area, the data follows
+const HEADER_LEN: u32 = 16;
+const DATA_OFFSET: u64 = 4096;
+const DATA_FRAMES: usize = 16;
+/// Bytes moved by the largest request
+const MAX_REQUEST_LEN: usize = DATA_FRAMES * 4096;
+
+/// Header, status byte and data of the request in flight
+struct DmaArea {
+    phys: u64,
+}
+
+impl DmaArea {
+    fn new() -> Result<DmaArea, VirtioError> {
+        alloc_contiguous(1 + DATA_FRAMES, 4096)
+            .map(|phys| DmaArea { phys })
+            .ok_or(VirtioError::OutOfMemory)
+    }
+
+    fn ptr(&self, offset: u64) -> *mut u8 {
+        (self.phys + offset + phys_mapping_offset()) as *mut u8
+    }
+
+    fn data(&mut self, len: usize) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr(DATA_OFFSET), len) }
+    }
+
+    fn status(&self) -> u8 {
+        unsafe { read_volatile(self.ptr(u64::from(HEADER_LEN))) }
+    }
+}
+
+impl Drop for DmaArea {
+    fn drop(&mut self) {
+        unsafe { free_contiguous(self.phys, 1 + DATA_FRAMES) };
+    }
+}
+
+/// The queue and the DMA area, held for the duration of a request
+struct Requests {
+    queue: Virtqueue,
+    dma: DmaArea,
+    /// Chain head of the request the device works on, kept when the task waiting for it is
+    /// dropped
+    in_flight: Option<u16>,
+}
+
+impl Requests {
+    /// Places a request moving `len` bytes of the DMA area and notifies the device. No other
+    /// request may be in flight.
+    fn submit(&mut self, transport: &VirtioPci, ty: u32, sector: u64, len: usize) -> Result<(), AtaError> {
+        unsafe {
+            let header = self.dma.ptr(0);
+            write_volatile(header as *mut u32, ty);
+            write_volatile(header.add(4) as *mut u32, 0);
+            write_volatile(header.add(8) as *mut u64, sector);
+            // not a valid status, so a request the device skipped doesn't look successful
+            write_volatile(self.dma.ptr(u64::from(HEADER_LEN)), 0xFF);
+        }
+
+        let header = QueueBuffer { phys: self.dma.phys, len: HEADER_LEN, device_writable: false };
+        let status = QueueBuffer { phys: self.dma.phys + u64::from(HEADER_LEN), len: 1, device_writable: true };
+        let data = QueueBuffer { phys: self.dma.phys + DATA_OFFSET, len: len as u32, device_writable: ty != REQUEST_OUT };
+        let with_data = [header, data, status];
+        let buffers: &[QueueBuffer] = if len == 0 { &[header, status] } else { &with_data };
+
+        // SAFETY: the DMA area is only touched with the request lock held and nothing is in
+        // flight, it isn't reused before the chain is back
+        let head = unsafe { self.queue.add(buffers) }.map_err(|_| AtaError::ChannelBusy)?;
+        self.in_flight = Some(head);
+        if self.queue.should_notify() {
+            transport.notify(&self.queue);
+        }
+        Ok(())
+    }
+
+    /// Result of the request in flight, None while the device still works on it
+    fn completion(&mut self) -> Option<Result<(), AtaError>> {
+        let head = self.in_flight?;
+        let (used, _) = self.queue.pop_used()?;
+        // one request at a time, the chain is the one in flight
+        debug_assert_eq!(used, head);
+        self.in_flight = None;
+        Some(match self.dma.status() {
+            STATUS_OK => Ok(()),
+            STATUS_UNSUPPORTED => Err(AtaError::CommandAborted),
+            _ => Err(AtaError::DeviceFault),
+        })
+    }
+
+    fn run_blocking(&mut self, transport: &VirtioPci, ty: u32, sector: u64, len: usize) -> Result<(), AtaError> {
+        self.submit(transport, ty, sector, len)?;
+        loop {
+            if let Some(result) = self.completion() {
+                return result;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+struct Disk {
+    transport: VirtioPci,
+    requests: Mutex<Requests>,
+    /// Task waiting for the completion interrupt
+    waker: AtomicWaker,
+    /// The completion interrupt is routed, otherwise `run` polls the used ring
+    interrupts: AtomicBool,
+    /// The function was hot-removed, requests fail
+    removed: AtomicBool,
+    /// In sectors
+    capacity: u64,
+    features: u64,
+    model: [u8; 41],
+}
+
+impl Drop for Disk {
+    /// The device must stop using the queue before it is freed
+    fn drop(&mut self) {
+        self.transport.reset();
+    }
+}
+
+impl Disk {
+    fn check_request(&self, lba: u64, len: usize) -> Result<(), AtaError> {
+        if self.removed.load(Ordering::Relaxed) {
+            return Err(AtaError::DeviceFault);
+        }
+        if len % SECTOR_SIZE != 0 {
+            return Err(AtaError::InvalidBufferSize);
+        }
+        match lba.checked_add((len / SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.capacity => Ok(()),
+            _ => Err(AtaError::OutOfRange),
+        }
+    }
+
+    fn check_write(&self, lba: u64, len: usize) -> Result<(), AtaError> {
+        if self.features & FEATURE_RO != 0 {
+            return Err(AtaError::WriteProtected);
+        }
+        self.check_request(lba, len)
+    }
+
+    /// Whether writes sit in a volatile cache until flushed
+    fn has_write_cache(&self) -> bool {
+        self.features & FEATURE_FLUSH != 0
+    }
+
+    async fn run(&self, requests: &mut Requests, ty: u32, sector: u64, len: usize) -> Result<(), AtaError> {
+        if requests.in_flight.is_some() {
+            // left by a dropped task, the device may still write to the DMA area; its result
+            // is of no use to anyone
+            let _ = self.wait(requests).await;
+            if requests.in_flight.is_some() {
+                return Err(AtaError::DeviceFault);
+            }
+        }
+        requests.submit(&self.transport, ty, sector, len)?;
+        self.wait(requests).await
+    }
+
+    /// Waits for the request in flight to complete. If the future is dropped the request stays
+    /// in flight.
+    async fn wait(&self, requests: &mut Requests) -> Result<(), AtaError> {
+        poll_fn(|cx| {
+            let interrupts = self.interrupts.load(Ordering::Relaxed);
+            if interrupts {
+                self.waker.register(cx.waker());
+            }
+            if let Some(result) = requests.completion() {
+                return Poll::Ready(result);
+            }
+            if self.removed.load(Ordering::Relaxed) {
+                return Poll::Ready(Err(AtaError::DeviceFault));
+            }
+            if !interrupts {
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        }).await
+    }
+}
+
+/// Disks checked when a virtio-blk interrupt arrives, the INTx lines may be shared
+static DISKS: Spinlock<Vec<Arc<Disk>>> = Spinlock::new(Vec::new());
+
+/// INTx line, vector and GSI of each routed line
+static ROUTED_LINES: Spinlock<Vec<(u8, Vector, u32)>> = Spinlock::new(Vec::new());
+
+fn interrupt() -> bool {
+    let mut handled = false;
+    for disk in DISKS.lock().iter() {
+        // reading the ISR deasserts the line
+        if disk.transport.read_isr() != 0 {
+            disk.waker.wake();
+            handled = true;
+        }
+    }
+    handled
+}
+
+/// Routes the INTx line of `disk`, unless a disk on the same line did already
+fn route_interrupt(disk: &Disk) -> Result<(), &'static str> {
+    let line = disk.transport.interrupt_line();
+    if line == 0 || line >= 0xF0 {
+        return Err("no interrupt line assigned");
+    }
+    let mut routed_lines = ROUTED_LINES.lock();
+    if !routed_lines.iter().any(|&(routed, _, _)| routed == line) {
+        let Vector(vector) = interrupts::register_irq(None, interrupt)?;
+        let gsi = ioapic::route_pci_irq(line, vector, percpu::current().apic_id)
+            .inspect_err(|_| { let _ = interrupts::unregister_irq(Vector(vector), interrupt); })?;
+        routed_lines.push((line, Vector(vector), gsi));
+        log::info!("[virtio-blk] {} interrupts on GSI {}", disk.transport.address(), gsi);
+    }
+    disk.interrupts.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+fn init(transport: VirtioPci) -> Result<Disk, VirtioError> {
+    let dma = DmaArea::new()?;
+
+    transport.begin_init();
+    let features = transport.negotiate_features(FEATURE_RO | FEATURE_FLUSH)?;
+    let queue = transport.setup_queue(0, QUEUE_SIZE)?;
+    transport.driver_ok();
+
+    let mut requests = Requests { queue, dma, in_flight: None };
+    let mut model = [0; 41];
+    let prefix = b"VirtIO disk";
+    model[..prefix.len()].copy_from_slice(prefix);
+    // the serial number is optional
+    if requests.run_blocking(&transport, REQUEST_GET_ID, 0, ID_LEN).is_ok() {
+        let serial = requests.dma.data(ID_LEN);
+        let len = serial.iter().position(|&b| b == 0).unwrap_or(ID_LEN);
+        if len > 0 {
+            model[prefix.len()] = b' ';
+            model[prefix.len() + 1..][..len].copy_from_slice(&serial[..len]);
+        }
+    }
+
+    Ok(Disk {
+        capacity: transport.read_config_u64(0),
+        transport,
+        requests: Mutex::new(requests),
+        waker: AtomicWaker::new(),
+        interrupts: AtomicBool::new(false),
+        removed: AtomicBool::new(false),
+        features,
+        model,
+    })
+}
+
+/// A virtio-blk disk
+pub struct VirtioBlk {
+    disk: Arc<Disk>,
+}
+
+/// Transitional and modern virtio-blk functions
+pub const PCI_MATCHES: &[PciMatch] = &[PciMatch::Id(VIRTIO_VENDOR_ID, 0x1001), PciMatch::Id(VIRTIO_VENDOR_ID, 0x1042)];
+
+/// Sets up the virtio-blk function as a drive
+pub async fn probe(device: PciDevice) -> ProbeResult {
+    let address = device.address();
+    let disk = match VirtioPci::new(&device).and_then(init) {
+        Ok(disk) => Arc::new(disk),
+        Err(e) => {
+            log::warn!("[virtio-blk] {}: {:?}", address, e);
+            return Err("device setup failed");
+        },
+    };
+
+    if let Err(e) = route_interrupt(&disk) {
+        log::warn!("[virtio-blk] {}: polling for completions, {}", address, e);
+    }
+    without_interrupts(|| DISKS.lock().push(disk.clone()));
+    Ok(alloc::vec![Probed::Drive(Box::new(VirtioBlk { disk }))])
+}
+
+/// Fails the requests of the removed function at `address` and drops its interrupt line once no
+/// other disk shares it. The disk itself goes when its last user lets go of it.
+pub fn remove(address: PciAddress) {
+    let removed = without_interrupts(|| {
+        let mut disks = DISKS.lock();
+        let position = disks.iter().position(|disk| disk.transport.address() == address)?;
+        let removed = disks.remove(position);
+        let line = removed.transport.interrupt_line();
+        let shared = disks.iter().any(|other| other.transport.interrupt_line() == line);
+        Some((removed, line, shared))
+    });
+    let Some((disk, line, shared)) = removed else {
+        return;
+    };
+
+    disk.removed.store(true, Ordering::Relaxed);
+    disk.waker.wake();
+    if shared {
+        return;
+    }
+    let mut routed_lines = ROUTED_LINES.lock();
+    if let Some(index) = routed_lines.iter().position(|&(routed, _, _)| routed == line) {
+        let (_, vector, gsi) = routed_lines.swap_remove(index);
+        if let Err(e) = ioapic::mask(gsi).and_then(|()| interrupts::unregister_irq(vector, interrupt)) {
+            log::warn!("[virtio-blk] GSI {} not released: {}", gsi, e);
+        }
+    }
+}
+
+impl VirtioBlk {
+    pub fn is_read_only(&self) -> bool {
+        self.disk.features & FEATURE_RO != 0
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    /// The task sleeps until the device is done
+    fn read<'a>(&'a self, lba: u64, buffer: &'a mut [u8]) -> BoxFuture<'a, Result<(), AtaError>> {
+        Box::pin(async move {
+            let disk = &*self.disk;
+            disk.check_request(lba, buffer.len())?;
+            let mut requests = disk.requests.lock().await;
+            for (i, chunk) in buffer.chunks_mut(MAX_REQUEST_LEN).enumerate() {
+                let sector = lba + (i * MAX_REQUEST_LEN / SECTOR_SIZE) as u64;
+                disk.run(&mut requests, REQUEST_IN, sector, chunk.len()).await?;
+                chunk.copy_from_slice(requests.dma.data(chunk.len()));
+            }
+            Ok(())
+        })
+    }
+
+    /// Resolves once the data is on the disk, flushed from its cache
+    fn write<'a>(&'a self, lba: u64, data: &'a [u8]) -> BoxFuture<'a, Result<(), AtaError>> {
+        Box::pin(async move {
+            let disk = &*self.disk;
+            disk.check_write(lba, data.len())?;
+            let mut requests = disk.requests.lock().await;
+            for (i, chunk) in data.chunks(MAX_REQUEST_LEN).enumerate() {
+                let sector = lba + (i * MAX_REQUEST_LEN / SECTOR_SIZE) as u64;
+                requests.dma.data(chunk.len()).copy_from_slice(chunk);
+                disk.run(&mut requests, REQUEST_OUT, sector, chunk.len()).await?;
+            }
+            if disk.has_write_cache() {
+                disk.run(&mut requests, REQUEST_FLUSH, 0, 0).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn size(&self) -> u64 {
+        self.disk.capacity
+    }
+
+    fn model(&self) -> [u8; 41] {
+        self.disk.model
+    }
+
+    fn location(&self) -> String {
+        format!("virtio {}", self.disk.transport.address())
+    }
+
+    fn pci_address(&self) -> Option<PciAddress> {
+        Some(self.disk.transport.address())
+    }
+}
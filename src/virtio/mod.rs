@@ -0,0 +1,106 @@
+// Virtio devices over PCI.
+//
+// This module holds what all virtio drivers share: finding the registers of a function with
+// either the legacy (I/O BAR) or the modern (vendor capabilities) interface, the status and
+// feature negotiation handshake, split virtqueues in DMA memory, notifications and the ISR.
+// A device driver only deals with its configuration space and the buffers it puts on its queues.
+
+pub mod blk;
+pub mod ninep;
+mod queue;
+mod transport;
+
+pub use queue::{Virtqueue, QueueBuffer};
+pub use transport::VirtioPci;
+
+use crate::pci::PciFunctionId;
+
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
+/// Device status bits
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_DEVICE_NEEDS_RESET: u8 = 64;
+pub const STATUS_FAILED: u8 = 128;
+
+/// Device independent feature bits
+pub const FEATURE_RING_INDIRECT_DESC: u64 = 1 << 28;
+pub const FEATURE_RING_EVENT_IDX: u64 = 1 << 29;
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// ISR status bits, reading the ISR acknowledges the interrupt
+pub const ISR_QUEUE: u8 = 1;
+pub const ISR_CONFIG_CHANGE: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Network,
+    Block,
+    Console,
+    Entropy,
+    Balloon,
+    Scsi,
+    Gpu,
+    Input,
+    Socket,
+    /// 9P transport, used for host shared folders
+    NineP,
+    Other(u16),
+}
+
+impl DeviceType {
+    fn from_id(id: u16) -> Self {
+        match id {
+            1 => DeviceType::Network,
+            2 => DeviceType::Block,
+            3 => DeviceType::Console,
+            4 => DeviceType::Entropy,
+            5 => DeviceType::Balloon,
+            8 => DeviceType::Scsi,
+            9 => DeviceType::NineP,
+            16 => DeviceType::Gpu,
+            18 => DeviceType::Input,
+            19 => DeviceType::Socket,
+            id => DeviceType::Other(id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// Neither a legacy I/O BAR nor the modern capabilities were found
+    NoTransport,
+    /// A BAR or capability points to memory that could not be mapped
+    MappingFailed,
+    /// The device didn't accept the negotiated features
+    FeaturesRejected,
+    /// The queue doesn't exist or is already in use
+    QueueUnavailable,
+    /// No physically contiguous memory for a queue
+    OutOfMemory,
+    /// Not enough free descriptors for the buffers
+    QueueFull,
+}
+
+/// Device type of a virtio function, None for other vendors or unknown device ids.
+pub fn device_type(id: &PciFunctionId) -> Option<DeviceType> {
+    if id.vendor_id != VIRTIO_VENDOR_ID {
+        return None;
+    }
+
+    match id.device_id {
+        // transitional devices
+        0x1000 => Some(DeviceType::Network),
+        0x1001 => Some(DeviceType::Block),
+        0x1002 => Some(DeviceType::Balloon),
+        0x1003 => Some(DeviceType::Console),
+        0x1004 => Some(DeviceType::Scsi),
+        0x1005 => Some(DeviceType::Entropy),
+        0x1009 => Some(DeviceType::NineP),
+        // modern devices: 0x1040 + device type
+        0x1041..=0x107F => Some(DeviceType::from_id(id.device_id - 0x1040)),
+        _ => None,
+    }
+}
@@ -0,0 +1,551 @@
+// Host shared folder over virtio-9p.
+//
+// A 9P2000.L client for the folder QEMU exports with
+// `-virtfs local,path=<dir>,mount_tag=host,security_model=none`, so logs, traces and screenshots
+// written by the OS show up on the host right away. The share isn't mounted in the VFS: it is
+// reached through the functions below with paths relative to its root, and the first device
+// found is the one used. When it is hot-removed the next one found takes over.
+//
+// Requests go one at a time over the single queue, each as a chain of a request buffer and a
+// response buffer in DMA memory. The driver polls the used ring instead of taking interrupts,
+// the host answers within microseconds.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use shared_lib::phys_mapping_offset;
+use shared_lib::spinlock::{MappedSpinlockGuard, Spinlock, SpinlockGuard};
+use crate::allocator::{alloc_contiguous, free_contiguous};
+use crate::pci::{PciAddress, PciDevice, PciMatch, ProbeResult};
+use super::{QueueBuffer, VirtioError, VirtioPci, Virtqueue, VIRTIO_VENDOR_ID};
+
+/// The device config has the mount tag
+const FEATURE_MOUNT_TAG: u64 = 1 << 0;
+
+const PROTOCOL_VERSION: &str = "9P2000.L";
+const BUFFER_FRAMES: usize = 4;
+/// Largest message in either direction, the request and the response buffer each have this size
+const MAX_MESSAGE_SIZE: u32 = (BUFFER_FRAMES * 4096) as u32;
+/// size, type and tag
+const HEADER_SIZE: usize = 7;
+/// Header plus the fid, offset and count fields of Tread/Twrite
+const IO_HEADER_SIZE: u32 = HEADER_SIZE as u32 + 4 + 8 + 4;
+const NO_TAG: u16 = 0xFFFF;
+const NO_FID: u32 = 0xFFFF_FFFF;
+const ROOT_FID: u32 = 0;
+
+// message types, the R-message is always T + 1
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TREADDIR: u8 = 40;
+const TMKDIR: u8 = 72;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+// Linux open flags used by Tlopen/Tlcreate
+const O_RDONLY: u32 = 0;
+const O_WRONLY: u32 = 1;
+const O_CREAT: u32 = 0x40;
+const O_TRUNC: u32 = 0x200;
+const O_DIRECTORY: u32 = 0x10000;
+
+/// Linux errno of a name that isn't there
+const ENOENT: u32 = 2;
+
+const FILE_MODE: u32 = 0o644;
+const DIR_MODE: u32 = 0o755;
+/// Twalk takes at most this many names
+const MAX_WALK_NAMES: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NinePError {
+    /// No virtio-9p device was found
+    NotMounted,
+    Virtio(VirtioError),
+    /// The server failed the request with this Linux errno
+    Server(u32),
+    /// The response was cut short or of the wrong type
+    Protocol,
+    /// The path has more components than a walk allows, or names something that isn't there
+    InvalidPath,
+}
+
+impl From<VirtioError> for NinePError {
+    fn from(e: VirtioError) -> Self {
+        NinePError::Virtio(e)
+    }
+}
+
+/// A physically contiguous buffer for one message
+struct DmaBuffer {
+    phys: u64,
+}
+
+impl DmaBuffer {
+    fn new() -> Result<DmaBuffer, VirtioError> {
+        alloc_contiguous(BUFFER_FRAMES, 4096)
+            .map(|phys| DmaBuffer { phys })
+            .ok_or(VirtioError::OutOfMemory)
+    }
+
+    fn bytes(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut((self.phys + phys_mapping_offset()) as *mut u8, MAX_MESSAGE_SIZE as usize) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unsafe { free_contiguous(self.phys, BUFFER_FRAMES) };
+    }
+}
+
+/// A T-message being built, the size is filled in by `finish`
+struct Message(Vec<u8>);
+
+impl Message {
+    fn new(ty: u8) -> Self {
+        let mut bytes = Vec::with_capacity(64);
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.push(ty);
+        // requests are serialized, every one can use the same tag
+        bytes.extend_from_slice(&(if ty == TVERSION { NO_TAG } else { 1 }).to_le_bytes());
+        Message(bytes)
+    }
+
+    fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn str(self, value: &str) -> Self {
+        let mut message = self.u16(value.len() as u16);
+        message.0.extend_from_slice(value.as_bytes());
+        message
+    }
+
+    fn data(mut self, value: &[u8]) -> Self {
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.0.len() as u32;
+        self.0[..4].copy_from_slice(&size.to_le_bytes());
+        self.0
+    }
+}
+
+/// Fields of an R-message, after the header
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], NinePError> {
+        if self.0.len() < n {
+            return Err(NinePError::Protocol);
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, NinePError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, NinePError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, NinePError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, NinePError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a str, NinePError> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.take(len)?).map_err(|_| NinePError::Protocol)
+    }
+
+    /// Skips a qid: type, version and path
+    fn qid(&mut self) -> Result<u8, NinePError> {
+        let ty = self.u8()?;
+        self.take(4 + 8)?;
+        Ok(ty)
+    }
+}
+
+struct Share {
+    transport: VirtioPci,
+    queue: Virtqueue,
+    request: DmaBuffer,
+    response: DmaBuffer,
+    tag: String,
+    msize: u32,
+    /// Highest fid handed out, the root is fid 0
+    next_fid: u32,
+    free_fids: Vec<u32>,
+    /// Tells the share apart from the ones before and after it
+    generation: u64,
+}
+
+static SHARE: Spinlock<Option<Share>> = Spinlock::new(None);
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+impl Drop for Share {
+    /// When the setup failed or the function was removed: the device must stop using the queue
+    /// before it is freed
+    fn drop(&mut self) {
+        self.transport.reset();
+    }
+}
+
+impl Share {
+    /// Sends `message` and waits for the response, returns its body. Rlerror becomes an error.
+    fn transact(&mut self, message: Vec<u8>) -> Result<Vec<u8>, NinePError> {
+        let expected = message[4] + 1;
+        self.request.bytes()[..message.len()].copy_from_slice(&message);
+
+        let buffers = [
+            QueueBuffer { phys: self.request.phys, len: message.len() as u32, device_writable: false },
+            QueueBuffer { phys: self.response.phys, len: self.msize, device_writable: true },
+        ];
+        // SAFETY: the buffers are owned by the share and we wait for the chain below
+        let head = unsafe { self.queue.add(&buffers)? };
+        if self.queue.should_notify() {
+            self.transport.notify(&self.queue);
+        }
+        loop {
+            match self.queue.pop_used() {
+                Some((used, _)) if used == head => break,
+                Some(_) => {},
+                None => core::hint::spin_loop(),
+            }
+        }
+
+        let response = self.response.bytes();
+        let mut reader = Reader(response);
+        let size = reader.u32()? as usize;
+        let ty = reader.u8()?;
+        reader.u16()?;
+        let body = response.get(HEADER_SIZE..size).ok_or(NinePError::Protocol)?;
+        match ty {
+            RLERROR => Err(NinePError::Server(Reader(body).u32()?)),
+            ty if ty == expected => Ok(body.to_vec()),
+            _ => Err(NinePError::Protocol),
+        }
+    }
+
+    fn alloc_fid(&mut self) -> u32 {
+        self.free_fids.pop().unwrap_or_else(|| {
+            self.next_fid += 1;
+            self.next_fid
+        })
+    }
+
+    fn clunk(&mut self, fid: u32) {
+        // the fid is released even if the server complains
+        let _ = self.transact(Message::new(TCLUNK).u32(fid).finish());
+        self.free_fids.push(fid);
+    }
+
+    /// New fid for the file at `path`, relative to the root
+    fn walk(&mut self, path: &str) -> Result<u32, NinePError> {
+        let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+        if names.len() > MAX_WALK_NAMES {
+            return Err(NinePError::InvalidPath);
+        }
+
+        let fid = self.alloc_fid();
+        let mut message = Message::new(TWALK).u32(ROOT_FID).u32(fid).u16(names.len() as u16);
+        for name in &names {
+            message = message.str(name);
+        }
+        let walked = match self.transact(message.finish()) {
+            Ok(body) => Reader(&body).u16()? as usize,
+            Err(e) => {
+                self.free_fids.push(fid);
+                return Err(e);
+            },
+        };
+        // a partial walk doesn't create the fid
+        if walked != names.len() {
+            self.free_fids.push(fid);
+            return Err(NinePError::InvalidPath);
+        }
+        Ok(fid)
+    }
+
+    fn open(&mut self, path: &str, flags: u32) -> Result<u32, NinePError> {
+        let fid = self.walk(path)?;
+        if let Err(e) = self.transact(Message::new(TLOPEN).u32(fid).u32(flags).finish()) {
+            self.clunk(fid);
+            return Err(e);
+        }
+        Ok(fid)
+    }
+
+    /// Opens `path` for writing, truncated, and creates it if it doesn't exist
+    fn create(&mut self, path: &str) -> Result<u32, NinePError> {
+        match self.open(path, O_WRONLY | O_TRUNC) {
+            // a walk to a missing name fails on the server or stops short
+            Err(NinePError::InvalidPath | NinePError::Server(ENOENT)) => {},
+            result => return result,
+        }
+
+        let (parent, name) = path.trim_end_matches('/').rsplit_once('/').unwrap_or(("", path));
+        // Tlcreate turns the directory fid into the new file's fid
+        let fid = self.walk(parent)?;
+        let message = Message::new(TLCREATE).u32(fid).str(name).u32(O_WRONLY | O_CREAT | O_TRUNC).u32(FILE_MODE).u32(0);
+        if let Err(e) = self.transact(message.finish()) {
+            self.clunk(fid);
+            return Err(e);
+        }
+        Ok(fid)
+    }
+
+    /// Largest data payload of a single Tread/Twrite
+    fn io_size(&self) -> u32 {
+        self.msize - IO_HEADER_SIZE
+    }
+
+    fn read(&mut self, fid: u32, offset: u64, count: u32) -> Result<Vec<u8>, NinePError> {
+        let body = self.transact(Message::new(TREAD).u32(fid).u64(offset).u32(count.min(self.io_size())).finish())?;
+        let mut reader = Reader(&body);
+        let count = reader.u32()? as usize;
+        Ok(reader.take(count)?.to_vec())
+    }
+
+    fn write(&mut self, fid: u32, offset: u64, data: &[u8]) -> Result<usize, NinePError> {
+        let data = &data[..data.len().min(self.io_size() as usize)];
+        let body = self.transact(Message::new(TWRITE).u32(fid).u64(offset).u32(data.len() as u32).data(data).finish())?;
+        Ok(Reader(&body).u32()? as usize)
+    }
+}
+
+fn init(transport: VirtioPci) -> Result<Share, NinePError> {
+    let request = DmaBuffer::new()?;
+    let response = DmaBuffer::new()?;
+
+    transport.begin_init();
+    let features = transport.negotiate_features(FEATURE_MOUNT_TAG)?;
+    let queue = transport.setup_queue(0, 128)?;
+    transport.driver_ok();
+
+    let tag = if features & FEATURE_MOUNT_TAG != 0 {
+        let len = transport.read_config_u16(0);
+        (0..len).map(|i| transport.read_config_u8(2 + i) as char).collect()
+    } else {
+        String::new()
+    };
+
+    let mut share = Share {
+        transport,
+        queue,
+        request,
+        response,
+        tag,
+        msize: MAX_MESSAGE_SIZE,
+        next_fid: ROOT_FID,
+        free_fids: Vec::new(),
+        generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+    };
+
+    let body = share.transact(Message::new(TVERSION).u32(MAX_MESSAGE_SIZE).str(PROTOCOL_VERSION).finish())?;
+    let mut reader = Reader(&body);
+    share.msize = reader.u32()?.min(MAX_MESSAGE_SIZE);
+    if reader.str()? != PROTOCOL_VERSION || share.msize <= IO_HEADER_SIZE {
+        return Err(NinePError::Protocol);
+    }
+
+    // n_uname 0 is root, which `security_model=none` maps to the user running QEMU
+    share.transact(Message::new(TATTACH).u32(ROOT_FID).u32(NO_FID).str("root").str("").u32(0).finish())?;
+    Ok(share)
+}
+
+/// Transitional and modern virtio-9p functions
+pub const PCI_MATCHES: &[PciMatch] = &[PciMatch::Id(VIRTIO_VENDOR_ID, 0x1009), PciMatch::Id(VIRTIO_VENDOR_ID, 0x1049)];
+
+/// Sets up the virtio-9p function as the host share, unless one was found before.
+pub async fn probe(device: PciDevice) -> ProbeResult {
+    let address = device.address();
+    if is_mounted() {
+        return Err("a share is already mounted");
+    }
+
+    match VirtioPci::new(&device).map_err(NinePError::from).and_then(init) {
+        Ok(share) => {
+            log::info!("[9p] host share \"{}\" at {}, msize {}", share.tag, address, share.msize);
+            let mut mounted = SHARE.lock();
+            if mounted.is_some() {
+                return Err("a share is already mounted");
+            }
+            *mounted = Some(share);
+            Ok(Vec::new())
+        },
+        Err(e) => {
+            log::warn!("[9p] {}: {:?}", address, e);
+            Err("device setup failed")
+        },
+    }
+}
+
+/// Drops the share if it was the removed function at `address`, its files fail from now on
+pub fn remove(address: PciAddress) {
+    let removed = {
+        let mut share = SHARE.lock();
+        match share.as_ref() {
+            Some(mounted) if mounted.transport.address() == address => share.take(),
+            _ => None,
+        }
+    };
+    if let Some(share) = removed {
+        log::info!("[9p] host share \"{}\" removed", share.tag);
+    }
+}
+
+fn share() -> Result<MappedSpinlockGuard<'static, Share>, NinePError> {
+    SpinlockGuard::try_map(SHARE.lock(), Option::as_mut).map_err(|_| NinePError::NotMounted)
+}
+
+pub fn is_mounted() -> bool {
+    SHARE.lock().is_some()
+}
+
+/// Mount tag of the share
+pub fn tag() -> Option<String> {
+    Some(SHARE.lock().as_ref()?.tag.clone())
+}
+
+/// A file of the share opened for writing, closed when dropped
+pub struct HostFile {
+    fid: u32,
+    offset: u64,
+    /// Of the share the fid belongs to
+    generation: u64,
+}
+
+impl HostFile {
+    /// Creates `path`, or truncates it if it exists. The parent directory must exist.
+    pub fn create(path: &str) -> Result<HostFile, NinePError> {
+        let mut share = share()?;
+        let fid = share.create(path)?;
+        Ok(HostFile { fid, offset: 0, generation: share.generation })
+    }
+
+    /// The share the file was opened on, unless it was removed
+    fn share(&self) -> Result<MappedSpinlockGuard<'static, Share>, NinePError> {
+        share().and_then(|share| if share.generation == self.generation { Ok(share) } else { Err(NinePError::NotMounted) })
+    }
+
+    /// Appends `data` to the file
+    pub fn write_all(&mut self, mut data: &[u8]) -> Result<(), NinePError> {
+        let mut share = self.share()?;
+        while !data.is_empty() {
+            let written = share.write(self.fid, self.offset, data)?;
+            if written == 0 {
+                return Err(NinePError::Protocol);
+            }
+            self.offset += written as u64;
+            data = &data[written..];
+        }
+        Ok(())
+    }
+}
+
+impl Drop for HostFile {
+    fn drop(&mut self) {
+        if let Ok(mut share) = self.share() {
+            share.clunk(self.fid);
+        }
+    }
+}
+
+/// Creates or replaces the file at `path` with `data`
+pub fn write_file(path: &str, data: &[u8]) -> Result<(), NinePError> {
+    HostFile::create(path)?.write_all(data)
+}
+
+pub fn read_file(path: &str) -> Result<Vec<u8>, NinePError> {
+    let mut share = share()?;
+    let fid = share.open(path, O_RDONLY)?;
+    let mut data = Vec::new();
+    let result = loop {
+        match share.read(fid, data.len() as u64, u32::MAX) {
+            Ok(chunk) if chunk.is_empty() => break Ok(()),
+            Ok(chunk) => data.extend_from_slice(&chunk),
+            Err(e) => break Err(e),
+        }
+    };
+    share.clunk(fid);
+    result.map(|_| data)
+}
+
+/// Names in the directory at `path`, with a '/' appended to subdirectories
+pub fn read_dir(path: &str) -> Result<Vec<String>, NinePError> {
+    const QID_TYPE_DIR: u8 = 0x80;
+
+    let mut share = share()?;
+    let fid = share.open(path, O_RDONLY | O_DIRECTORY)?;
+    let mut names = Vec::new();
+    let mut offset = 0;
+    let result = loop {
+        let count = share.io_size();
+        let body = match share.transact(Message::new(TREADDIR).u32(fid).u64(offset).u32(count).finish()) {
+            Ok(body) => body,
+            Err(e) => break Err(e),
+        };
+
+        // entries: qid, offset of the next entry, type, name
+        let mut reader = Reader(&body);
+        let Ok(count) = reader.u32() else { break Err(NinePError::Protocol) };
+        let mut entries = match reader.take(count as usize) {
+            Ok(entries) => Reader(entries),
+            Err(e) => break Err(e),
+        };
+        if entries.0.is_empty() {
+            break Ok(());
+        }
+        while !entries.0.is_empty() {
+            let entry = (|| Ok::<_, NinePError>((entries.qid()?, entries.u64()?, entries.u8()?, entries.str()?)))();
+            let Ok((qid_type, next, _, name)) = entry else { break };
+            offset = next;
+            if name != "." && name != ".." {
+                let mut name = String::from(name);
+                if qid_type & QID_TYPE_DIR != 0 {
+                    name.push('/');
+                }
+                names.push(name);
+            }
+        }
+    };
+    share.clunk(fid);
+    result.map(|_| names)
+}
+
+/// Creates the directory `path`, its parent must exist
+pub fn create_dir(path: &str) -> Result<(), NinePError> {
+    let (parent, name) = path.trim_end_matches('/').rsplit_once('/').unwrap_or(("", path));
+    let mut share = share()?;
+    let fid = share.walk(parent)?;
+    let result = share.transact(Message::new(TMKDIR).u32(fid).str(name).u32(DIR_MODE).u32(0).finish());
+    share.clunk(fid);
+    result.map(|_| ())
+}
@@ -0,0 +1,215 @@
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use shared_lib::phys_mapping_offset;
+use crate::allocator::{alloc_contiguous, free_contiguous};
+use super::VirtioError;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const USED_F_NO_NOTIFY: u16 = 1;
+
+const FRAME_SIZE: usize = 4096;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// A physically contiguous buffer handed to the device.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueBuffer {
+    pub phys: u64,
+    pub len: u32,
+    /// Filled by the device, e.g. data read from a disk. Otherwise the device only reads it.
+    pub device_writable: bool,
+}
+
+/// A split virtqueue. The descriptor table, the available ring and the used ring live in one
+/// physically contiguous allocation in the legacy layout, which modern devices accept as well.
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    phys: u64,
+    frames: usize,
+    pub(super) notify_offset: u16,
+
+    desc: *mut Descriptor,
+    avail: *mut u16,
+    used: *mut u16,
+
+    free_head: u16,
+    num_free: u16,
+    avail_idx: u16,
+    last_used_idx: u16,
+}
+
+// the rings are only touched through `&mut self`
+unsafe impl Send for Virtqueue {}
+
+fn used_ring_offset(size: u16) -> usize {
+    let desc_and_avail = 16 * size as usize + 6 + 2 * size as usize;
+    desc_and_avail.next_multiple_of(FRAME_SIZE)
+}
+
+impl Virtqueue {
+    pub(super) fn new(index: u16, size: u16) -> Result<Virtqueue, VirtioError> {
+        let bytes = used_ring_offset(size) + 6 + 8 * size as usize;
+        let frames = bytes.div_ceil(FRAME_SIZE);
+        let phys = alloc_contiguous(frames, FRAME_SIZE).ok_or(VirtioError::OutOfMemory)?;
+
+        let base = (phys + phys_mapping_offset()) as *mut u8;
+        unsafe {
+            core::ptr::write_bytes(base, 0, frames * FRAME_SIZE);
+        }
+
+        let queue = Virtqueue {
+            index,
+            size,
+            phys,
+            frames,
+            notify_offset: 0,
+            desc: base as *mut Descriptor,
+            avail: unsafe { base.add(16 * size as usize) as *mut u16 },
+            used: unsafe { base.add(used_ring_offset(size)) as *mut u16 },
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used_idx: 0,
+        };
+
+        // all descriptors form the free list
+        for i in 0..size {
+            unsafe { write_volatile(&mut (*queue.desc.add(i as usize)).next, i.wrapping_add(1) % size) };
+        }
+        Ok(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    pub(super) fn desc_phys(&self) -> u64 {
+        self.phys
+    }
+
+    pub(super) fn avail_phys(&self) -> u64 {
+        self.phys + 16 * self.size as u64
+    }
+
+    pub(super) fn used_phys(&self) -> u64 {
+        self.phys + used_ring_offset(self.size) as u64
+    }
+
+    /// Places `buffers` as one descriptor chain on the available ring and returns the chain
+    /// head, which `pop_used` reports once the device is done with it. The device has to be
+    /// notified afterwards.
+    ///
+    /// # Safety
+    /// The buffers must stay valid and untouched until the chain is returned by `pop_used`.
+    pub unsafe fn add(&mut self, buffers: &[QueueBuffer]) -> Result<u16, VirtioError> {
+        if buffers.is_empty() || buffers.len() > self.num_free as usize {
+            return Err(VirtioError::QueueFull);
+        }
+
+        // the chain follows the free list, so the `next` links are already in place
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let desc = self.desc.add(index as usize);
+            let mut flags = if buffer.device_writable { DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+
+            write_volatile(&mut (*desc).addr, buffer.phys);
+            write_volatile(&mut (*desc).len, buffer.len);
+            write_volatile(&mut (*desc).flags, flags);
+            index = read_volatile(&(*desc).next);
+        }
+        self.free_head = index;
+        self.num_free -= buffers.len() as u16;
+
+        // avail ring: flags, idx, ring[size], used_event
+        let slot = self.avail.add(2 + (self.avail_idx % self.size) as usize);
+        write_volatile(slot, head);
+
+        // the device must see the ring entry before the new index
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        write_volatile(self.avail.add(1), self.avail_idx);
+        fence(Ordering::SeqCst);
+
+        Ok(head)
+    }
+
+    /// Whether the device wants a notification for new buffers
+    pub fn should_notify(&self) -> bool {
+        unsafe { read_volatile(self.used) & USED_F_NO_NOTIFY == 0 }
+    }
+
+    /// Whether the device has returned chains that weren't popped yet
+    pub fn has_used(&self) -> bool {
+        unsafe { read_volatile(self.used.add(1)) != self.last_used_idx }
+    }
+
+    /// Takes the next chain returned by the device and frees its descriptors. Returns the chain
+    /// head and the number of bytes the device wrote into it.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+
+        // used ring: flags, idx, ring[size] of (id, len)
+        let (head, len) = unsafe {
+            let elem = (self.used.add(2) as *mut UsedElem).add((self.last_used_idx % self.size) as usize);
+            (read_volatile(&(*elem).id) as u16, read_volatile(&(*elem).len))
+        };
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        // put the chain back on the free list
+        let mut index = head;
+        let mut count = 1;
+        unsafe {
+            loop {
+                let desc = self.desc.add(index as usize);
+                if read_volatile(&(*desc).flags) & DESC_F_NEXT == 0 {
+                    write_volatile(&mut (*desc).next, self.free_head);
+                    break;
+                }
+                index = read_volatile(&(*desc).next);
+                count += 1;
+            }
+        }
+        self.free_head = head;
+        self.num_free += count;
+
+        Some((head, len))
+    }
+}
+
+impl Drop for Virtqueue {
+    /// The device must be reset (or the queue disabled) before, so it no longer touches the rings.
+    fn drop(&mut self) {
+        unsafe {
+            free_contiguous(self.phys, self.frames);
+        }
+    }
+}
@@ -0,0 +1,375 @@
+use core::ptr::{read_volatile, write_volatile};
+use shared_lib::addr::{PhysAddr, VirtAddr};
+use shared_lib::register_block;
+use shared_lib::volatile::Mmio;
+use crate::memory::map_mmio;
+use crate::pci::{self, pci_config_read_dword, PciAddress, PciDevice, Resource};
+use crate::port::{Port, PortReadOnly};
+use super::*;
+
+const PCI_CAP_ID_VENDOR: u8 = 0x09;
+
+// virtio_pci_cap cfg_type
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// legacy registers, relative to BAR0
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_PFN: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+/// Start of the device configuration while MSI-X is disabled
+const LEGACY_DEVICE_CFG: u16 = 0x14;
+
+register_block! {
+    /// virtio_pci_common_cfg
+    struct CommonCfg[0x38] {
+        DEVICE_FEATURE_SELECT: u32 = 0x00,
+        DEVICE_FEATURE: u32 = 0x04,
+        DRIVER_FEATURE_SELECT: u32 = 0x08,
+        DRIVER_FEATURE: u32 = 0x0C,
+        MSIX_CONFIG: u16 = 0x10,
+        NUM_QUEUES: u16 = 0x12,
+        DEVICE_STATUS: u8 = 0x14,
+        CONFIG_GENERATION: u8 = 0x15,
+        QUEUE_SELECT: u16 = 0x16,
+        QUEUE_SIZE: u16 = 0x18,
+        QUEUE_MSIX_VECTOR: u16 = 0x1A,
+        QUEUE_ENABLE: u16 = 0x1C,
+        QUEUE_NOTIFY_OFF: u16 = 0x1E,
+        QUEUE_DESC_LO: u32 = 0x20,
+        QUEUE_DESC_HI: u32 = 0x24,
+        QUEUE_DRIVER_LO: u32 = 0x28,
+        QUEUE_DRIVER_HI: u32 = 0x2C,
+        QUEUE_DEVICE_LO: u32 = 0x30,
+        QUEUE_DEVICE_HI: u32 = 0x34,
+    }
+}
+
+enum Interface {
+    Legacy {
+        io_base: u16,
+    },
+    Modern {
+        common: Mmio<CommonCfg>,
+        notify_base: VirtAddr,
+        notify_multiplier: u32,
+        isr: VirtAddr,
+        device: Option<VirtAddr>,
+    },
+}
+
+/// The registers of a virtio PCI function, with the legacy or the modern interface.
+pub struct VirtioPci {
+    address: PciAddress,
+    interrupt_line: u8,
+    interface: Interface,
+}
+
+/// (BAR, offset, length) of a virtio capability
+type CapRegion = (u8, u32, u32);
+
+fn read_config(address: PciAddress, offset: u8) -> u32 {
+    unsafe { pci_config_read_dword(address, offset as u16) }
+}
+
+fn map_region(device: &PciDevice, (bar, offset, length): CapRegion) -> Result<VirtAddr, VirtioError> {
+    let Some(Resource::Memory { base, size, .. }) = device.bar(bar as usize) else {
+        return Err(VirtioError::MappingFailed);
+    };
+    if base == 0 || offset as u64 + length as u64 > size {
+        return Err(VirtioError::MappingFailed);
+    }
+    map_mmio(PhysAddr(base + offset as u64), length as usize).map_err(|_| VirtioError::MappingFailed)
+}
+
+fn find_modern_interface(pci_device: &PciDevice) -> Result<Option<Interface>, VirtioError> {
+    let address = pci_device.address();
+    let mut common = None;
+    let mut notify = None;
+    let mut isr = None;
+    let mut device = None;
+
+    for &(id, offset) in pci_device.capabilities() {
+        if id != PCI_CAP_ID_VENDOR {
+            continue;
+        }
+
+        let [_vndr, _next, _len, cfg_type] = read_config(address, offset).to_le_bytes();
+        let bar = read_config(address, offset + 4) as u8;
+        let region = (bar, read_config(address, offset + 8), read_config(address, offset + 12));
+
+        // the first capability of each type is the preferred one
+        match cfg_type {
+            CAP_COMMON_CFG => { common.get_or_insert(region); },
+            CAP_NOTIFY_CFG => { notify.get_or_insert((region, read_config(address, offset + 16))); },
+            CAP_ISR_CFG => { isr.get_or_insert(region); },
+            CAP_DEVICE_CFG => { device.get_or_insert(region); },
+            _ => {}
+        }
+    }
+
+    let (Some(common), Some((notify, notify_multiplier)), Some(isr)) = (common, notify, isr) else {
+        return Ok(None);
+    };
+
+    Ok(Some(Interface::Modern {
+        common: unsafe { Mmio::new(map_region(pci_device, common)?) },
+        notify_base: map_region(pci_device, notify)?,
+        notify_multiplier,
+        isr: map_region(pci_device, isr)?,
+        device: device.map(|device| map_region(pci_device, device)).transpose()?,
+    }))
+}
+
+impl VirtioPci {
+    /// Finds the registers of the virtio function, preferring the modern interface, and enables
+    /// its BARs and bus mastering.
+    pub fn new(device: &PciDevice) -> Result<VirtioPci, VirtioError> {
+        device.enable(pci::PCI_COMMAND_IO_SPACE | pci::PCI_COMMAND_MEMORY_SPACE | pci::PCI_COMMAND_BUS_MASTER);
+
+        let interface = match find_modern_interface(device)? {
+            Some(interface) => interface,
+            // only transitional devices have the legacy interface
+            None if (0x1000..0x1040).contains(&device.id().device_id) => match device.bar(0) {
+                Some(Resource::Io { base, .. }) => Interface::Legacy { io_base: base as u16 },
+                _ => return Err(VirtioError::NoTransport),
+            },
+            None => return Err(VirtioError::NoTransport),
+        };
+
+        Ok(VirtioPci { address: device.address(), interrupt_line: device.interrupt_line(), interface })
+    }
+
+    pub fn address(&self) -> PciAddress {
+        self.address
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self.interface, Interface::Modern { .. })
+    }
+
+    /// Legacy INTx line from the configuration space
+    pub fn interrupt_line(&self) -> u8 {
+        self.interrupt_line
+    }
+
+    pub fn status(&self) -> u8 {
+        match &self.interface {
+            Interface::Legacy { io_base } => unsafe { Port::<u8>::new(io_base + LEGACY_DEVICE_STATUS).read() },
+            Interface::Modern { common, .. } => common.read(CommonCfg::DEVICE_STATUS),
+        }
+    }
+
+    pub fn set_status(&self, status: u8) {
+        match &self.interface {
+            Interface::Legacy { io_base } => unsafe { Port::<u8>::new(io_base + LEGACY_DEVICE_STATUS).write(status) },
+            Interface::Modern { common, .. } => common.write(CommonCfg::DEVICE_STATUS, status),
+        }
+    }
+
+    pub fn add_status(&self, bits: u8) {
+        self.set_status(self.status() | bits);
+    }
+
+    /// Resets the device: it stops using its queues, which can be dropped afterwards.
+    pub fn reset(&self) {
+        self.set_status(0);
+        if self.is_modern() {
+            // the reset is complete once the status reads back as 0
+            while self.status() != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Resets the device and tells it that a driver was found.
+    pub fn begin_init(&self) {
+        self.reset();
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+    }
+
+    pub fn device_features(&self) -> u64 {
+        match &self.interface {
+            Interface::Legacy { io_base } => unsafe { Port::<u32>::new(io_base + LEGACY_DEVICE_FEATURES).read() as u64 },
+            Interface::Modern { common, .. } => {
+                common.write(CommonCfg::DEVICE_FEATURE_SELECT, 0);
+                let low = common.read(CommonCfg::DEVICE_FEATURE);
+                common.write(CommonCfg::DEVICE_FEATURE_SELECT, 1);
+                let high = common.read(CommonCfg::DEVICE_FEATURE);
+                (high as u64) << 32 | low as u64
+            },
+        }
+    }
+
+    /// Accepts the features in `supported` which the device offers, `FEATURE_VERSION_1` is added
+    /// for modern devices. Returns the negotiated features; if the device rejects them it is
+    /// marked as failed.
+    pub fn negotiate_features(&self, supported: u64) -> Result<u64, VirtioError> {
+        let features = match &self.interface {
+            Interface::Legacy { io_base } => {
+                let features = self.device_features() & supported & 0xFFFF_FFFF;
+                unsafe { Port::<u32>::new(io_base + LEGACY_DRIVER_FEATURES).write(features as u32) };
+                return Ok(features);
+            },
+            Interface::Modern { common, .. } => {
+                let features = self.device_features() & (supported | FEATURE_VERSION_1);
+                common.write(CommonCfg::DRIVER_FEATURE_SELECT, 0);
+                common.write(CommonCfg::DRIVER_FEATURE, features as u32);
+                common.write(CommonCfg::DRIVER_FEATURE_SELECT, 1);
+                common.write(CommonCfg::DRIVER_FEATURE, (features >> 32) as u32);
+                features
+            },
+        };
+
+        self.add_status(STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+        Ok(features)
+    }
+
+    /// Number of queues, None for legacy devices which don't report it
+    pub fn num_queues(&self) -> Option<u16> {
+        match &self.interface {
+            Interface::Legacy { .. } => None,
+            Interface::Modern { common, .. } => Some(common.read(CommonCfg::NUM_QUEUES)),
+        }
+    }
+
+    /// Allocates queue `index` with at most `max_size` entries and hands it to the device.
+    /// Legacy devices dictate the size.
+    pub fn setup_queue(&self, index: u16, max_size: u16) -> Result<Virtqueue, VirtioError> {
+        match &self.interface {
+            Interface::Legacy { io_base } => unsafe {
+                Port::<u16>::new(io_base + LEGACY_QUEUE_SELECT).write(index);
+                let size = PortReadOnly::<u16>::new(io_base + LEGACY_QUEUE_SIZE).read();
+                let mut pfn = Port::<u32>::new(io_base + LEGACY_QUEUE_PFN);
+                if size == 0 || pfn.read() != 0 {
+                    return Err(VirtioError::QueueUnavailable);
+                }
+
+                let queue = Virtqueue::new(index, size)?;
+                pfn.write((queue.desc_phys() >> 12) as u32);
+                Ok(queue)
+            },
+            Interface::Modern { common, .. } => {
+                common.write(CommonCfg::QUEUE_SELECT, index);
+                let device_size = common.read(CommonCfg::QUEUE_SIZE);
+                if device_size == 0 || common.read(CommonCfg::QUEUE_ENABLE) != 0 {
+                    return Err(VirtioError::QueueUnavailable);
+                }
+
+                // split queues must have a power of two size
+                let size = 1 << (device_size.min(max_size.max(1))).ilog2();
+                common.write(CommonCfg::QUEUE_SIZE, size);
+
+                let mut queue = Virtqueue::new(index, size)?;
+                let split = |addr: u64| (addr as u32, (addr >> 32) as u32);
+                let (desc_lo, desc_hi) = split(queue.desc_phys());
+                let (driver_lo, driver_hi) = split(queue.avail_phys());
+                let (device_lo, device_hi) = split(queue.used_phys());
+                common.write(CommonCfg::QUEUE_DESC_LO, desc_lo);
+                common.write(CommonCfg::QUEUE_DESC_HI, desc_hi);
+                common.write(CommonCfg::QUEUE_DRIVER_LO, driver_lo);
+                common.write(CommonCfg::QUEUE_DRIVER_HI, driver_hi);
+                common.write(CommonCfg::QUEUE_DEVICE_LO, device_lo);
+                common.write(CommonCfg::QUEUE_DEVICE_HI, device_hi);
+
+                queue.notify_offset = common.read(CommonCfg::QUEUE_NOTIFY_OFF);
+                common.write(CommonCfg::QUEUE_ENABLE, 1);
+                Ok(queue)
+            },
+        }
+    }
+
+    /// Tells the device that new buffers are available on `queue`.
+    pub fn notify(&self, queue: &Virtqueue) {
+        match &self.interface {
+            Interface::Legacy { io_base } => unsafe { Port::<u16>::new(io_base + LEGACY_QUEUE_NOTIFY).write(queue.index()) },
+            Interface::Modern { notify_base, notify_multiplier, .. } => unsafe {
+                let addr = notify_base.0 + queue.notify_offset as u64 * *notify_multiplier as u64;
+                write_volatile(addr as *mut u16, queue.index());
+            },
+        }
+    }
+
+    /// Reads and thereby acknowledges the interrupt status, see `ISR_QUEUE` and `ISR_CONFIG_CHANGE`.
+    pub fn read_isr(&self) -> u8 {
+        match &self.interface {
+            Interface::Legacy { io_base } => unsafe { PortReadOnly::<u8>::new(io_base + LEGACY_ISR).read() },
+            Interface::Modern { isr, .. } => unsafe { read_volatile(isr.0 as *const u8) },
+        }
+    }
+
+    /// Finishes the initialization, the device may be used from now on.
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    fn config_generation(&self) -> u8 {
+        match &self.interface {
+            Interface::Legacy { .. } => 0,
+            Interface::Modern { common, .. } => common.read(CommonCfg::CONFIG_GENERATION),
+        }
+    }
+
+    pub fn read_config_u8(&self, offset: u16) -> u8 {
+        match &self.interface {
+            Interface::Legacy { io_base } => unsafe { Port::<u8>::new(io_base + LEGACY_DEVICE_CFG + offset).read() },
+            Interface::Modern { device, .. } => device.map_or(0, |device| unsafe { read_volatile((device.0 + offset as u64) as *const u8) }),
+        }
+    }
+
+    pub fn read_config_u16(&self, offset: u16) -> u16 {
+        match &self.interface {
+            Interface::Legacy { io_base } => unsafe { Port::<u16>::new(io_base + LEGACY_DEVICE_CFG + offset).read() },
+            Interface::Modern { device, .. } => device.map_or(0, |device| unsafe { read_volatile((device.0 + offset as u64) as *const u16) }),
+        }
+    }
+
+    pub fn read_config_u32(&self, offset: u16) -> u32 {
+        match &self.interface {
+            Interface::Legacy { io_base } => unsafe { Port::<u32>::new(io_base + LEGACY_DEVICE_CFG + offset).read() },
+            Interface::Modern { device, .. } => device.map_or(0, |device| unsafe { read_volatile((device.0 + offset as u64) as *const u32) }),
+        }
+    }
+
+    /// Reads a 64 bit field with two 32 bit accesses, retried if the device changed its
+    /// configuration in between.
+    pub fn read_config_u64(&self, offset: u16) -> u64 {
+        loop {
+            let generation = self.config_generation();
+            let value = (self.read_config_u32(offset + 4) as u64) << 32 | self.read_config_u32(offset) as u64;
+            if self.config_generation() == generation {
+                return value;
+            }
+        }
+    }
+
+    pub fn write_config_u8(&self, offset: u16, value: u8) {
+        match &self.interface {
+            Interface::Legacy { io_base } => unsafe { Port::<u8>::new(io_base + LEGACY_DEVICE_CFG + offset).write(value) },
+            Interface::Modern { device, .. } => if let Some(device) = device {
+                unsafe { write_volatile((device.0 + offset as u64) as *mut u8, value) };
+            },
+        }
+    }
+
+    pub fn write_config_u32(&self, offset: u16, value: u32) {
+        match &self.interface {
+            Interface::Legacy { io_base } => unsafe { Port::<u32>::new(io_base + LEGACY_DEVICE_CFG + offset).write(value) },
+            Interface::Modern { device, .. } => if let Some(device) = device {
+                unsafe { write_volatile((device.0 + offset as u64) as *mut u32, value) };
+            },
+        }
+    }
+}
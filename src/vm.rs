@@ -0,0 +1,127 @@
+// Kernel virtual address space.
+//
+// Every range the kernel uses is recorded here as a VMA (virtual memory area): the physical
+// memory window, the heap and the regions handed out by `allocate_region`, e.g. MMIO windows.
+// The regions placed by the loader are reserved at their fixed addresses with `reserve`, dynamic
+// regions are taken first-fit from `DYNAMIC_START..DYNAMIC_END`, with an unmapped guard page
+// after each so an overrun faults instead of running into the next region. A VMA only records
+// the range, mapping its pages is up to its owner.
+
+use alloc::vec::Vec;
+use core::fmt;
+use shared_lib::addr::VirtAddr;
+use shared_lib::page_table::{PageTableFlags, PAGE_SIZE};
+use shared_lib::spinlock::Spinlock;
+
+/// Dynamic regions, away from the kernel, its stack, the physical memory window and the EFI
+/// runtime mappings
+pub const DYNAMIC_START: u64 = 0x400_0000_0000;
+pub const DYNAMIC_END: u64 = 0x500_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    /// The range overlaps the VMA with this name
+    Overlap(&'static str),
+    OutOfSpace,
+    NotFound,
+    Unaligned,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: u64,
+    pub len: u64,
+    /// Flags the owner maps the pages with
+    pub flags: PageTableFlags,
+    pub name: &'static str,
+}
+
+impl Vma {
+    pub fn end(&self) -> u64 {
+        self.start + self.len
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        (self.start..self.end()).contains(&addr)
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end()
+    }
+}
+
+/// Sorted by start address, never overlapping
+static VMAS: Spinlock<Vec<Vma>> = Spinlock::new(Vec::new());
+
+fn insert(vmas: &mut Vec<Vma>, vma: Vma) -> Result<(), VmError> {
+    if let Some(existing) = vmas.iter().find(|existing| existing.overlaps(vma.start, vma.end())) {
+        return Err(VmError::Overlap(existing.name));
+    }
+
+    let index = vmas.partition_point(|existing| existing.start < vma.start);
+    vmas.insert(index, vma);
+    Ok(())
+}
+
+/// Records the fixed range `start..start + len`, an error if it overlaps a recorded one
+pub fn reserve(start: VirtAddr, len: u64, flags: PageTableFlags, name: &'static str) -> Result<(), VmError> {
+    if start.0 % PAGE_SIZE != 0 {
+        return Err(VmError::Unaligned);
+    }
+
+    let len = len.next_multiple_of(PAGE_SIZE);
+    insert(&mut VMAS.lock(), Vma { start: start.0, len, flags, name })
+}
+
+/// Takes a free range of `len` bytes, rounded up to pages, from the dynamic regions
+pub fn allocate_region(len: usize, flags: PageTableFlags, name: &'static str) -> Result<VirtAddr, VmError> {
+    let len = (len.max(1) as u64).next_multiple_of(PAGE_SIZE);
+    let mut vmas = VMAS.lock();
+
+    let mut start = DYNAMIC_START;
+    for vma in vmas.iter().filter(|vma| vma.end() > DYNAMIC_START && vma.start < DYNAMIC_END) {
+        if vma.start >= start + len + PAGE_SIZE {
+            break;
+        }
+        start = start.max(vma.end() + PAGE_SIZE);
+    }
+    if start + len > DYNAMIC_END {
+        return Err(VmError::OutOfSpace);
+    }
+
+    insert(&mut vmas, Vma { start, len, flags, name })?;
+    Ok(VirtAddr::new(start))
+}
+
+/// Changes the length of the VMA starting at `start`, e.g. when the heap is allowed to grow
+pub fn resize(start: VirtAddr, len: u64) -> Result<(), VmError> {
+    let len = len.next_multiple_of(PAGE_SIZE);
+    let mut vmas = VMAS.lock();
+    let index = vmas.iter().position(|vma| vma.start == start.0).ok_or(VmError::NotFound)?;
+
+    if let Some(next) = vmas.get(index + 1).filter(|next| next.start < start.0 + len) {
+        return Err(VmError::Overlap(next.name));
+    }
+    vmas[index].len = len;
+    Ok(())
+}
+
+/// Forgets the VMA starting at `start` and returns it. Its pages must be unmapped already.
+pub fn free_region(start: VirtAddr) -> Result<Vma, VmError> {
+    let mut vmas = VMAS.lock();
+    let index = vmas.iter().position(|vma| vma.start == start.0).ok_or(VmError::NotFound)?;
+    Ok(vmas.remove(index))
+}
+
+/// The VMA containing `addr`
+pub fn find(addr: VirtAddr) -> Option<Vma> {
+    VMAS.lock().iter().find(|vma| vma.contains(addr.0)).copied()
+}
+
+/// Output of the `vm` shell command
+pub fn dump(out: &mut impl fmt::Write) -> fmt::Result {
+    for vma in VMAS.lock().iter() {
+        writeln!(out, "{:#014x}-{:#014x} {:>10} KiB {:<12} {:?}", vma.start, vma.end(), vma.len / 1024, vma.name, vma.flags)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,254 @@
+// Soft- and hard-lockup detector.
+//
+// Soft lockup: interrupts work, but the executor loop hasn't advanced (e.g. a task polling a
+// device forever). Checked from the APIC timer interrupt.
+// Hard lockup: the APIC timer interrupt itself isn't serviced (interrupts disabled, stuck in a
+// handler). Checked from a periodic NMI, which fires even with interrupts disabled. It comes from
+// performance counter 0 counting unhalted core cycles, delivered through the performance counter
+// LVT of the local APIC, if the CPU has architectural performance monitoring. Otherwise the PIT
+// is routed through the IOAPIC with NMI delivery mode. Core cycles only advance while the CPU
+// runs, and may run faster than the TSC they are scaled with: the NMI rate is an estimate, but
+// nothing is stuck while the CPU is halted anyway.
+//
+// Either way the stuck context is reported once over serial: the interrupted frame, the top of
+// its stack and a symbolized backtrace.
+
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use shared_lib::addr::VirtAddr;
+use shared_lib::bits::get_bits;
+use shared_lib::msr;
+use shared_lib::serial_println;
+use shared_lib::stack_trace::{frame_pointer, walk_stack};
+use shared_lib::volatile::Mmio;
+use crate::apic::{write_lvt, LocalApicRegs, Lvt, LvtEntry};
+use crate::idt::InterruptStackFrame;
+use crate::memory::translate_addr;
+use crate::port::PortWriteOnly;
+use crate::symbols::Resolved;
+use crate::task::timer::{self, TIMER_FREQUENCY};
+
+/// Seconds without progress before a lockup is reported.
+pub const LOCKUP_THRESHOLD_SECS: u64 = 5;
+
+const PIT_TICK_RATE: u64 = 1193182;
+/// Slowest possible PIT rate, ~18.2 NMIs per second
+const PIT_DIVISOR: u16 = 0xFFFF;
+const PIT_NMI_PER_SEC: u64 = PIT_TICK_RATE / PIT_DIVISOR as u64;
+
+const CPUID_PERFMON_LEAF: u32 = 0xA;
+/// IA32_PERFEVTSEL: UnHalted Core Cycles, counted in ring 0 and 3, interrupt on overflow
+const EVENT_CORE_CYCLES: u64 = 0x3C;
+const PERFEVTSEL_USR: u64 = 1 << 16;
+const PERFEVTSEL_OS: u64 = 1 << 17;
+const PERFEVTSEL_INT: u64 = 1 << 20;
+const PERFEVTSEL_EN: u64 = 1 << 22;
+const PERF_NMI_PER_SEC: u64 = 4;
+/// Writes to IA32_PMC0 sign-extend bit 31, longer periods need the full-width alias
+const MAX_PERF_PERIOD: u64 = (1 << 31) - 1;
+
+const MAX_BACKTRACE_DEPTH: usize = 24;
+const STACK_DUMP_WORDS: u64 = 16;
+
+static ARMED: AtomicBool = AtomicBool::new(false);
+
+static HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+
+// state of the soft-lockup check, only touched by the timer interrupt
+static SEEN_HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+static TICKS_WITHOUT_HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+static SOFT_REPORTED: AtomicBool = AtomicBool::new(false);
+
+// state of the hard-lockup check, only touched by the NMI
+static SEEN_TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+static NMIS_WITHOUT_TICK: AtomicU64 = AtomicU64::new(0);
+static HARD_REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Rate of the lockup NMI, 0 without one
+static NMI_PER_SEC: AtomicU64 = AtomicU64::new(0);
+// performance counter source, PERF_PERIOD is 0 if unused
+static PERF_PERIOD: AtomicU64 = AtomicU64::new(0);
+static PERF_COUNTER_WIDTH: AtomicU64 = AtomicU64::new(0);
+static PERF_GLOBAL_CTRL: AtomicBool = AtomicBool::new(false);
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+/// Starts checking. Called by the executor once it is running.
+pub fn arm() {
+    SEEN_HEARTBEAT.store(HEARTBEAT.load(Ordering::Relaxed), Ordering::Relaxed);
+    SEEN_TIMER_TICKS.store(TIMER_TICKS.load(Ordering::Relaxed), Ordering::Relaxed);
+    ARMED.store(true, Ordering::Relaxed);
+}
+
+pub fn disarm() {
+    ARMED.store(false, Ordering::Relaxed);
+}
+
+/// Called on every iteration of the executor loop.
+#[inline]
+pub fn heartbeat() {
+    HEARTBEAT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Programs PIT channel 0 as a rate generator. Its IRQ is routed as NMI by `initialize_apic`.
+pub fn start_pit() {
+    let mut command = PortWriteOnly::<u8>::new(0x43);
+    let mut channel0 = PortWriteOnly::<u8>::new(0x40);
+    unsafe {
+        // channel 0, lobyte/hibyte, mode 2 (rate generator), binary
+        command.write(0x34);
+        channel0.write((PIT_DIVISOR & 0xff) as u8);
+        channel0.write((PIT_DIVISOR >> 8) as u8);
+    }
+    NMI_PER_SEC.store(PIT_NMI_PER_SEC, Ordering::Relaxed);
+}
+
+/// Raises the lockup NMI from performance counter 0 through the performance counter LVT of the
+/// local APIC at `local_apic`. False if the CPU can't count core cycles or the TSC isn't
+/// calibrated, the PIT has to do then.
+pub fn start_perf_counter(local_apic: VirtAddr) -> bool {
+    if __cpuid(0).eax < CPUID_PERFMON_LEAF {
+        return false;
+    }
+    let perfmon = __cpuid(CPUID_PERFMON_LEAF);
+    let version = get_bits(perfmon.eax, 0..8);
+    let counters = get_bits(perfmon.eax, 8..16);
+    let width = get_bits(perfmon.eax, 16..24);
+    let events = get_bits(perfmon.eax, 24..32);
+    // EBX has a bit set for each of the first `events` events that is not available
+    if version == 0 || counters == 0 || events == 0 || perfmon.ebx & 1 != 0 || width < 32 {
+        return false;
+    }
+    let Some(tsc_hz) = timer::tsc_frequency() else {
+        return false;
+    };
+
+    let period = (tsc_hz / PERF_NMI_PER_SEC).min(MAX_PERF_PERIOD);
+    NMI_PER_SEC.store(tsc_hz.div_ceil(period), Ordering::Relaxed);
+    PERF_PERIOD.store(period, Ordering::Relaxed);
+    PERF_COUNTER_WIDTH.store(u64::from(width), Ordering::Relaxed);
+    PERF_GLOBAL_CTRL.store(version >= 2, Ordering::Relaxed);
+    LOCAL_APIC.store(local_apic.0, Ordering::Relaxed);
+
+    let regs = unsafe { Mmio::<LocalApicRegs>::new(local_apic) };
+    write_lvt(&regs, Lvt::PerformanceCounter, LvtEntry::nmi());
+    unsafe {
+        msr::write(msr::IA32_PERFEVTSEL0, 0);
+        msr::write(msr::IA32_PMC0, period.wrapping_neg());
+        msr::write(msr::IA32_PERFEVTSEL0,
+            EVENT_CORE_CYCLES | PERFEVTSEL_USR | PERFEVTSEL_OS | PERFEVTSEL_INT | PERFEVTSEL_EN);
+        if version >= 2 {
+            msr::write(msr::IA32_PERF_GLOBAL_CTRL, msr::read(msr::IA32_PERF_GLOBAL_CTRL) | 1);
+        }
+    }
+    log::info!("NMI watchdog: performance counter, {} NMIs per second", NMI_PER_SEC.load(Ordering::Relaxed));
+    true
+}
+
+/// Restarts the performance counter if it overflowed, i.e. raised the current NMI
+fn perf_counter_overflowed() -> bool {
+    let period = PERF_PERIOD.load(Ordering::Relaxed);
+    let width = PERF_COUNTER_WIDTH.load(Ordering::Relaxed);
+    // counting up from -period, the top bit clears on overflow
+    let counter = unsafe { msr::read(msr::IA32_PMC0) };
+    if counter & (1 << (width - 1)) != 0 {
+        return false;
+    }
+
+    unsafe {
+        msr::write(msr::IA32_PMC0, period.wrapping_neg());
+        if PERF_GLOBAL_CTRL.load(Ordering::Relaxed) {
+            msr::write(msr::IA32_PERF_GLOBAL_OVF_CTRL, 1);
+        }
+    }
+    // delivering the NMI masked the LVT
+    let regs = unsafe { Mmio::<LocalApicRegs>::new(VirtAddr::new(LOCAL_APIC.load(Ordering::Relaxed))) };
+    write_lvt(&regs, Lvt::PerformanceCounter, LvtEntry::nmi());
+    true
+}
+
+/// Called from the APIC timer interrupt.
+pub fn timer_tick(stack_frame: &InterruptStackFrame) {
+    TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+    if !ARMED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let heartbeat = HEARTBEAT.load(Ordering::Relaxed);
+    if heartbeat != SEEN_HEARTBEAT.swap(heartbeat, Ordering::Relaxed) {
+        TICKS_WITHOUT_HEARTBEAT.store(0, Ordering::Relaxed);
+        SOFT_REPORTED.store(false, Ordering::Relaxed);
+        return;
+    }
+
+    let stalled = TICKS_WITHOUT_HEARTBEAT.fetch_add(1, Ordering::Relaxed) + 1;
+    if stalled >= LOCKUP_THRESHOLD_SECS * TIMER_FREQUENCY as u64 && !SOFT_REPORTED.swap(true, Ordering::Relaxed) {
+        report("soft lockup: executor made no progress", stalled / TIMER_FREQUENCY as u64, stack_frame);
+    }
+}
+
+/// Called from the NMI handler.
+pub fn nmi_tick(stack_frame: &InterruptStackFrame) {
+    if PERF_PERIOD.load(Ordering::Relaxed) != 0 && !perf_counter_overflowed() {
+        return;
+    }
+    if !ARMED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let ticks = TIMER_TICKS.load(Ordering::Relaxed);
+    if ticks != SEEN_TIMER_TICKS.swap(ticks, Ordering::Relaxed) {
+        NMIS_WITHOUT_TICK.store(0, Ordering::Relaxed);
+        HARD_REPORTED.store(false, Ordering::Relaxed);
+        return;
+    }
+
+    let nmi_per_sec = NMI_PER_SEC.load(Ordering::Relaxed).max(1);
+    let stalled = NMIS_WITHOUT_TICK.fetch_add(1, Ordering::Relaxed) + 1;
+    if stalled >= LOCKUP_THRESHOLD_SECS * nmi_per_sec && !HARD_REPORTED.swap(true, Ordering::Relaxed) {
+        report("hard lockup: timer interrupt not serviced", stalled / nmi_per_sec, stack_frame);
+    }
+}
+
+fn report(what: &str, seconds: u64, stack_frame: &InterruptStackFrame) {
+    // the stuck context may be holding the serial port
+    unsafe { shared_lib::serial::SERIAL1.force_unlock() };
+
+    serial_println!("[watchdog] {} for {} s", what, seconds);
+    serial_println!("[watchdog] rip: {}", Resolved(stack_frame.value.instruction_pointer.0));
+    serial_println!("[watchdog] rsp: {:#x}, rflags: {:#x}, cs: {:#x}, ss: {:#x}",
+        stack_frame.value.stack_pointer.0, stack_frame.value.cpu_flags,
+        stack_frame.value.code_segment, stack_frame.value.stack_segment);
+    dump_stack(stack_frame.value.stack_pointer.0);
+
+    // the chain goes through the interrupt handler into the interrupted code
+    serial_println!("[watchdog] backtrace:");
+    let mut depth = 0;
+    unsafe {
+        walk_stack(frame_pointer(), |addr| {
+            serial_println!("[watchdog]   #{}: {}", depth, Resolved(addr));
+            depth += 1;
+            depth < MAX_BACKTRACE_DEPTH
+        });
+    }
+}
+
+/// The top `STACK_DUMP_WORDS` words of the interrupted stack, up to the first unmapped page
+fn dump_stack(rsp: u64) {
+    serial_println!("[watchdog] stack:");
+    for word in 0..STACK_DUMP_WORDS {
+        let addr = rsp + word * 8;
+        if word == 0 || addr % 4096 == 0 {
+            let mapped = VirtAddr::new_checked(addr).ok()
+                .and_then(|page| unsafe { translate_addr(page) })
+                .is_some();
+            if !mapped {
+                serial_println!("[watchdog]   {:#018x}: not mapped", addr);
+                return;
+            }
+        }
+
+        let value = unsafe { core::ptr::read_volatile(addr as *const u64) };
+        serial_println!("[watchdog]   {:#018x}: {:#018x}", addr, value);
+    }
+}
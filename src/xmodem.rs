@@ -0,0 +1,199 @@
+// XMODEM receiver over COM1.
+//
+// Supports XMODEM-CRC, XMODEM-1K and falls back to the original checksum variant when the
+// sender doesn't answer the CRC handshake. The transfer polls the UART directly, so the
+// executor is blocked for its duration: logging is silenced (the log shares the port with the
+// transfer) and the lockup watchdog is disarmed.
+//
+// A received file is held on the heap until the transfer is done, then `store` writes it through
+// the VFS.
+
+use alloc::vec::Vec;
+use shared_lib::crc::calculate_crc16_xmodem;
+use shared_lib::serial::SERIAL1;
+use crate::task::timer::{ms_to_ticks, ticks};
+use crate::vfs::{self, VfsError};
+use crate::watchdog;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1A;
+const CRC_MODE: u8 = b'C';
+
+const MAX_RETRIES: u32 = 10;
+const CRC_HANDSHAKE_ATTEMPTS: u32 = 3;
+const BYTE_TIMEOUT_MS: u64 = 1000;
+const HANDSHAKE_TIMEOUT_MS: u64 = 3000;
+
+/// Received files are held on the kernel heap
+pub const MAX_FILE_SIZE: usize = 128 * 1024;
+
+#[derive(Debug)]
+pub enum XmodemError {
+    Timeout,
+    Cancelled,
+    TooManyErrors,
+    TooLarge,
+}
+
+fn send(byte: u8) {
+    SERIAL1.lock().send(byte);
+}
+
+fn receive(timeout_ms: u64) -> Result<u8, XmodemError> {
+    let deadline = ticks() + ms_to_ticks(timeout_ms);
+    loop {
+        if let Some(byte) = SERIAL1.lock().try_receive() {
+            return Ok(byte);
+        }
+        if ticks() >= deadline {
+            return Err(XmodemError::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Drains the line until the sender goes quiet, used before a NAK.
+fn purge() {
+    while receive(BYTE_TIMEOUT_MS).is_ok() {}
+}
+
+fn cancel() {
+    for _ in 0..3 {
+        send(CAN);
+    }
+}
+
+enum Packet {
+    Data(u8, Vec<u8>),
+    End,
+}
+
+fn receive_packet(header: u8, crc_mode: bool) -> Result<Option<Packet>, XmodemError> {
+    let size = match header {
+        SOH => 128,
+        STX => 1024,
+        EOT => return Ok(Some(Packet::End)),
+        CAN => {
+            return match receive(BYTE_TIMEOUT_MS)? {
+                CAN => Err(XmodemError::Cancelled),
+                _ => Ok(None),
+            }
+        },
+        _ => return Ok(None),
+    };
+
+    let block = receive(BYTE_TIMEOUT_MS)?;
+    let block_complement = receive(BYTE_TIMEOUT_MS)?;
+
+    let mut data = Vec::with_capacity(size);
+    for _ in 0..size {
+        data.push(receive(BYTE_TIMEOUT_MS)?);
+    }
+
+    let valid = if crc_mode {
+        let crc = (receive(BYTE_TIMEOUT_MS)? as u16) << 8 | receive(BYTE_TIMEOUT_MS)? as u16;
+        crc == calculate_crc16_xmodem(&data)
+    } else {
+        let checksum = receive(BYTE_TIMEOUT_MS)?;
+        checksum == data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+    };
+
+    if !valid || block != !block_complement {
+        return Ok(None);
+    }
+    Ok(Some(Packet::Data(block, data)))
+}
+
+fn receive_file() -> Result<Vec<u8>, XmodemError> {
+    // ask for CRC mode first, fall back to checksums
+    let mut crc_mode = true;
+    let mut header = None;
+    for attempt in 0..CRC_HANDSHAKE_ATTEMPTS + MAX_RETRIES {
+        crc_mode = attempt < CRC_HANDSHAKE_ATTEMPTS;
+        send(if crc_mode { CRC_MODE } else { NAK });
+        if let Ok(byte) = receive(HANDSHAKE_TIMEOUT_MS) {
+            header = Some(byte);
+            break;
+        }
+    }
+    let mut header = header.ok_or(XmodemError::Timeout)?;
+
+    let mut file = Vec::new();
+    let mut expected_block: u8 = 1;
+    let mut errors = 0;
+
+    loop {
+        match receive_packet(header, crc_mode) {
+            Ok(Some(Packet::End)) => {
+                send(ACK);
+                break;
+            },
+            Ok(Some(Packet::Data(block, data))) if block == expected_block => {
+                if file.len() + data.len() > MAX_FILE_SIZE {
+                    cancel();
+                    return Err(XmodemError::TooLarge);
+                }
+                file.extend_from_slice(&data);
+                expected_block = expected_block.wrapping_add(1);
+                errors = 0;
+                send(ACK);
+            },
+            // our ACK got lost and the sender repeated the previous block
+            Ok(Some(Packet::Data(block, _))) if block == expected_block.wrapping_sub(1) => send(ACK),
+            Ok(_) | Err(XmodemError::Timeout) => {
+                errors += 1;
+                if errors > MAX_RETRIES {
+                    cancel();
+                    return Err(XmodemError::TooManyErrors);
+                }
+                purge();
+                send(NAK);
+            },
+            Err(e) => return Err(e),
+        }
+
+        header = match receive(HANDSHAKE_TIMEOUT_MS) {
+            Ok(byte) => byte,
+            Err(_) => {
+                cancel();
+                return Err(XmodemError::Timeout);
+            }
+        };
+    }
+
+    // the last block is padded with SUB
+    while file.last() == Some(&SUB) {
+        file.pop();
+    }
+    Ok(file)
+}
+
+/// Receives a file over serial, with the log silenced meanwhile
+pub fn receive_file_quietly() -> Result<Vec<u8>, XmodemError> {
+    let log_level = log::max_level();
+    log::set_max_level(log::LevelFilter::Off);
+    watchdog::disarm();
+
+    let result = receive_file();
+
+    watchdog::arm();
+    log::set_max_level(log_level);
+
+    result
+}
+
+/// Writes a received file to `path`, replacing the file there. Blocks, like the VFS.
+pub fn store(path: &str, data: &[u8]) -> Result<(), VfsError> {
+    match vfs::remove(path) {
+        Ok(()) | Err(VfsError::NotFound) => {},
+        Err(e) => return Err(e),
+    }
+    vfs::create(path)?;
+    vfs::write(path, 0, data)?;
+    Ok(())
+}
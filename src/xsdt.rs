@@ -1,256 +1,325 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
 use core::slice::from_raw_parts;
 use shared_lib::addr::{PhysAddr, VirtAddr};
+use shared_lib::bytes::{read_u16_le, read_u32_le, read_u64_le};
 use shared_lib::frame_allocator::FrameAllocator;
-use shared_lib::page_table::{align_down, align_down_u64, map_address_with_offset};
-use shared_lib::VIRT_MAPPING_OFFSET;
-use crate::memory::active_level_4_table;
-
-#[repr(C)]
-struct RsdpV2 {
-    pub signature: [u8; 8],
-    pub checksum: u8,
-    pub oemid: [u8; 6],
-    pub revision: u8,
-    pub rsdt_address: u32, // deprecated
+use shared_lib::page_table::PAGE_SIZE;
+use shared_lib::phys_mapping_offset;
+use crate::memory::map_mmio_with;
+use crate::sysinfo::{self, Category, Node};
 
-    pub length: u32,
-    pub xsdt_address: u64,
-    pub extended_checksum: u8,
-    pub reserved: [u8; 3],
-}
+// ACPI tables are parsed from byte slices at the offsets given by the spec: firmware doesn't
+// align them (e.g. the XSDT pointer array starts at offset 36).
+
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+const SDT_HEADER_SIZE: usize = 36;
 
 fn wrapping_sum(arr: &[u8]) -> u8 {
     arr.iter().fold(0u8, |a, b| a.wrapping_add(*b))
 }
 
-fn get_xsdt_address(rsdp_addr: PhysAddr) -> VirtAddr {
-    let rsdp_virt_addr = {
-        log::info!("RSDP: {:#x}", rsdp_addr.0);
-        let rsdp_virt_addr = VirtAddr::new_checked(rsdp_addr.0 + VIRT_MAPPING_OFFSET).unwrap();
-
-        log::info!("RSDP virt - {:#x}", rsdp_virt_addr.0);
-        rsdp_virt_addr
-    };
-
-    let acpi_revision_ptr = rsdp_virt_addr.offset(8 + 1 + 6).unwrap().0 as *const u8;
-    let acpi_revision = unsafe { *acpi_revision_ptr };
+/// Validates an ACPI 2.0 RSDP and returns the physical address of the XSDT.
+pub fn parse_rsdp(rsdp: &[u8]) -> Result<u64, &'static str> {
+    if rsdp.len() < RSDP_V2_SIZE || &rsdp[0..8] != b"RSD PTR " {
+        return Err("Invalid RSDP signature");
+    }
 
+    let acpi_revision = rsdp[15];
     log::info!("ACPI revision: {}", acpi_revision);
     if acpi_revision != 2 {
-        panic!("ACPI1 is not supported!");
+        return Err("ACPI1 is not supported!");
     }
 
-    let rsdp_ptr = rsdp_virt_addr.0 as *mut RsdpV2;
-    let rsdp = unsafe { rsdp_ptr.as_mut().unwrap() };
-
-    let v1_bytes_sum = wrapping_sum(&rsdp.signature)
-        + rsdp.checksum
-        + wrapping_sum(&rsdp.oemid)
-        + rsdp.revision
-        + wrapping_sum(&rsdp.rsdt_address.to_ne_bytes());
-    let v2_bytes_sum = wrapping_sum(&rsdp.length.to_ne_bytes())
-        + wrapping_sum(&rsdp.xsdt_address.to_ne_bytes())
-        + rsdp.extended_checksum
-        + wrapping_sum(&rsdp.reserved);
-
-    log::info!("v1_bytes_sum: {:#x}", v1_bytes_sum);
-    log::info!("v2_bytes_sum: {:#x}", v2_bytes_sum);
-
-    if v1_bytes_sum != 0 {
-        panic!("ACPI1 checksum failed");
+    if wrapping_sum(&rsdp[..RSDP_V1_SIZE]) != 0 {
+        return Err("ACPI1 checksum failed");
     }
 
-    if v2_bytes_sum != 0 {
-        panic!("ACPI2 checksum failed");
+    if wrapping_sum(&rsdp[..RSDP_V2_SIZE]) != 0 {
+        return Err("ACPI2 checksum failed");
     }
-    VirtAddr::new_checked(rsdp.xsdt_address + VIRT_MAPPING_OFFSET).unwrap()
+
+    Ok(read_u64_le(rsdp, 24))
 }
 
-#[repr(C)]
-struct AcpiSdtHeader {
+pub struct SdtHeader {
     pub signature: [u8; 4],
     pub length: u32,
     pub revision: u8,
-    pub checksum: u8,
     pub oemid: [u8; 6],
-    pub oem_table_id: [u8; 8],
-    pub oem_revision: u32,
-    pub creator_id: u32,
-    pub creator_revision: u32
+    pub creator_revision: u32,
 }
 
-impl AcpiSdtHeader {
-    pub fn get_bytes_sum(&self) -> u8 {
-        wrapping_sum(&self.signature)
-            .wrapping_add(wrapping_sum(&self.creator_id.to_ne_bytes()))
-            .wrapping_add(wrapping_sum(&self.length.to_ne_bytes()))
-            .wrapping_add(wrapping_sum(&self.oemid))
-            .wrapping_add(self.revision)
-            .wrapping_add(wrapping_sum(&self.creator_revision.to_ne_bytes()))
-            .wrapping_add(wrapping_sum(&self.oem_revision.to_ne_bytes()))
-            .wrapping_add(self.checksum)
-            .wrapping_add(wrapping_sum(&self.oem_table_id))
+impl SdtHeader {
+    pub fn parse(table: &[u8]) -> Result<Self, &'static str> {
+        if table.len() < SDT_HEADER_SIZE {
+            return Err("SDT is too short");
+        }
+
+        Ok(SdtHeader {
+            signature: table[0..4].try_into().unwrap(),
+            length: read_u32_le(table, 4),
+            revision: table[8],
+            oemid: table[10..16].try_into().unwrap(),
+            creator_revision: read_u32_le(table, 32),
+        })
     }
 }
 
-#[repr(C)]
-struct MadtHeader {
-    pub local_apic_addr: u32,
-    pub apic_flags: u32,
+/// Checks the length and the checksum of a whole table, header included.
+pub fn validate_sdt(table: &[u8], signature: &[u8; 4]) -> Result<SdtHeader, &'static str> {
+    let header = SdtHeader::parse(table)?;
+    if &header.signature != signature {
+        return Err("Unexpected SDT signature");
+    }
+    if header.length as usize != table.len() {
+        return Err("SDT length mismatch");
+    }
+    if wrapping_sum(table) != 0 {
+        return Err("SDT checksum failed");
+    }
+    Ok(header)
 }
 
-#[repr(C)]
-struct MadtEntryHeader {
-    pub entry_type: u8,
-    pub record_length: u8
-}
+/// Returns physical addresses of the tables listed in the XSDT.
+pub fn parse_xsdt(table: &[u8]) -> Result<impl Iterator<Item = u64> + '_, &'static str> {
+    validate_sdt(table, b"XSDT")?;
 
-#[repr(C)]
-struct MadtEntryIOApic {
-    pub io_apic_id: u8,
-    pub reserved: u8,
-    pub io_apic_addr: u32,
-    pub global_system_interrupt_base: u32
+    Ok(table[SDT_HEADER_SIZE..]
+        .chunks_exact(8)
+        .map(|ptr| read_u64_le(ptr, 0)))
 }
 
-#[repr(C)]
-struct MadtEntryIOApicInterruptSource {
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
     pub bus_source: u8,
     pub irq_source: u8,
     pub global_system_interrupt: u32,
-    pub flags: u16
+    pub flags: u16,
 }
 
-struct ApicPhysAddrs {
-    pub local_apic_addr: PhysAddr,
-    pub io_apic_addr: PhysAddr
+/// Processor Local APIC entry of the MADT
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub flags: u32,
 }
 
-fn handle_madt(header: &AcpiSdtHeader, data_addr: VirtAddr) -> Result<ApicPhysAddrs, &'static str> {
-    log::info!("MADT handling. Len: {}", header.length);
-
-    let mut check_sum = header.get_bytes_sum();
-    for i in 0..(header.length - 36) {
-        unsafe {
-            check_sum = check_sum.wrapping_add(*((data_addr.0 + i as u64) as *const u8));
-        }
+impl LocalApic {
+    pub fn is_enabled(&self) -> bool {
+        self.flags & 1 != 0
     }
-    if check_sum != 0 {
-        return Err("MADT checksum failed");
+
+    /// Disabled, but the firmware allows bringing it online
+    pub fn is_online_capable(&self) -> bool {
+        self.flags & 2 != 0
     }
+}
 
-    let madt_header = unsafe {
-        (data_addr.0 as *const MadtHeader).as_ref().unwrap()
-    };
+pub struct Madt {
+    pub local_apic_addr: u64,
+    pub apic_flags: u32,
+    pub io_apic_id: u8,
+    pub io_apic_addr: Option<u64>,
+    pub global_system_interrupt_base: u32,
+    pub overrides: Vec<InterruptSourceOverride>,
+    pub processors: Vec<LocalApic>,
+}
 
-    log::info!("local apic phys: {:#x} flags: {}", madt_header.local_apic_addr, madt_header.apic_flags);
+/// Parses the MADT ("APIC" table), header included.
+pub fn parse_madt(table: &[u8]) -> Result<Madt, &'static str> {
+    let header = validate_sdt(table, b"APIC")?;
+    log::info!("MADT handling. Len: {}", header.length);
 
-    let mut result: Result<ApicPhysAddrs, &'static str> = Err("Invalid MADT");
-    let mut offset: u64 = 8;
-    while offset < (header.length - 36) as u64 {
-        let entry_header = unsafe {
-            ((data_addr.0 + offset) as *const MadtEntryHeader).as_ref().unwrap()
-        };
+    if table.len() < SDT_HEADER_SIZE + 8 {
+        return Err("Invalid MADT");
+    }
 
-        log::info!("MADT entry: type: {}, len: {}", entry_header.entry_type, entry_header.record_length);
+    let mut madt = Madt {
+        local_apic_addr: read_u32_le(table, SDT_HEADER_SIZE) as u64,
+        apic_flags: read_u32_le(table, SDT_HEADER_SIZE + 4),
+        io_apic_id: 0,
+        io_apic_addr: None,
+        global_system_interrupt_base: 0,
+        overrides: Vec::new(),
+        processors: Vec::new(),
+    };
 
-        if entry_header.entry_type == 1 {
-            let io_apic_entry = unsafe {
-                ((data_addr.0 + offset + 2) as *const MadtEntryIOApic).as_ref().unwrap()
-            };
+    log::info!("local apic phys: {:#x} flags: {}", madt.local_apic_addr, madt.apic_flags);
 
-            log::info!("io apic: addr: {:#x}, global system int base: {:#x}. id: {}", io_apic_entry.io_apic_addr, io_apic_entry.global_system_interrupt_base, io_apic_entry.io_apic_id);
+    let mut offset = SDT_HEADER_SIZE + 8;
+    while offset + 2 <= table.len() {
+        let entry_type = table[offset];
+        let record_length = table[offset + 1] as usize;
+        if record_length < 2 || offset + record_length > table.len() {
+            return Err("Invalid MADT entry length");
+        }
+        let entry = &table[offset..offset + record_length];
 
-            result = Ok(ApicPhysAddrs {
-                local_apic_addr: PhysAddr(madt_header.local_apic_addr as u64),
-                io_apic_addr: PhysAddr(io_apic_entry.io_apic_addr as u64)
+        log::info!("MADT entry: type: {}, len: {}", entry_type, record_length);
+
+        if entry_type == 0 && record_length >= 8 {
+            madt.processors.push(LocalApic {
+                processor_id: entry[2],
+                apic_id: entry[3],
+                flags: read_u32_le(entry, 4),
             });
-        } else if entry_header.entry_type == 2 {
-            let io_apic_source_interrupt_entry = unsafe {
-                ((data_addr.0 + offset + 2) as *const MadtEntryIOApicInterruptSource).as_ref().unwrap()
+        } else if entry_type == 1 && record_length >= 12 {
+            madt.io_apic_id = entry[2];
+            madt.io_apic_addr = Some(read_u32_le(entry, 4) as u64);
+            madt.global_system_interrupt_base = read_u32_le(entry, 8);
+
+            log::info!("io apic: addr: {:#x}, global system int base: {:#x}. id: {}", read_u32_le(entry, 4), madt.global_system_interrupt_base, madt.io_apic_id);
+        } else if entry_type == 2 && record_length >= 10 {
+            let interrupt_override = InterruptSourceOverride {
+                bus_source: entry[2],
+                irq_source: entry[3],
+                global_system_interrupt: read_u32_le(entry, 4),
+                flags: read_u16_le(entry, 8),
             };
 
-            log::info!("Entry Type 2: I/O APIC Interrupt Source Override. {:#x} {:#x} {:#x} {:#x}", io_apic_source_interrupt_entry.bus_source, io_apic_source_interrupt_entry.irq_source, io_apic_source_interrupt_entry.global_system_interrupt, io_apic_source_interrupt_entry.flags);
+            log::info!("Entry Type 2: I/O APIC Interrupt Source Override. {:#x} {:#x} {:#x} {:#x}", interrupt_override.bus_source, interrupt_override.irq_source, interrupt_override.global_system_interrupt, interrupt_override.flags);
+            madt.overrides.push(interrupt_override);
         }
 
-        offset += entry_header.record_length as u64;
+        offset += record_length;
     }
-    result
-}
 
-pub struct ApicAddresses {
-    pub local_apic_addr: VirtAddr,
-    pub io_apic_addr: VirtAddr
+    if madt.io_apic_addr.is_none() {
+        return Err("Invalid MADT");
+    }
+    Ok(madt)
 }
 
-pub fn read_xsdt(allocator: &mut FrameAllocator, rsdp_addr: u64) -> ApicAddresses {
-    let xsdt_addr = get_xsdt_address(PhysAddr(rsdp_addr));
-    log::info!("XSDT addr: {:#x}", xsdt_addr.0);
-
-    let xsdt_header_ptr = xsdt_addr.0 as *mut AcpiSdtHeader;
-    let xsdt_header = unsafe { xsdt_header_ptr.as_mut().unwrap() };
-    log::info!("XSDT header: s:{:?}, len:{:#x}, rev:{}, ch: {}, oemid: {:?}, cr_rev: {:#x}", xsdt_header.signature, xsdt_header.length, xsdt_header.revision, xsdt_header.checksum,
-    xsdt_header.oemid, xsdt_header.creator_revision);
-
-    let mut check_sum = xsdt_header.get_bytes_sum();
+/// ECAM window of the buses `start_bus..=end_bus` of a PCI segment, an entry of the MCFG
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    /// Where the configuration space of bus 0 would be, even if the window starts later
+    pub base: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
 
-    let pointers_to_other_sdts = unsafe { from_raw_parts((xsdt_addr.0 + 36) as *const u64, ((xsdt_header.length - 36) / 8) as usize) };
+/// Parses the MCFG, header included.
+pub fn parse_mcfg(table: &[u8]) -> Result<Vec<McfgEntry>, &'static str> {
+    validate_sdt(table, b"MCFG")?;
+
+    // 8 reserved bytes, then 16 per entry
+    let entries = table.get(SDT_HEADER_SIZE + 8..).ok_or("Invalid MCFG")?;
+    entries.chunks_exact(16)
+        .map(|entry| {
+            let (start_bus, end_bus) = (entry[10], entry[11]);
+            if end_bus < start_bus {
+                return Err("Invalid MCFG bus range");
+            }
+            Ok(McfgEntry { base: read_u64_le(entry, 0), segment: read_u16_le(entry, 8), start_bus, end_bus })
+        })
+        .collect()
+}
 
-    for ptr in pointers_to_other_sdts {
-        check_sum += wrapping_sum(&ptr.to_ne_bytes());
-    }
+/// Returns a table in the physical memory mapping. The length is taken from its header.
+unsafe fn sdt_bytes(phys_addr: u64) -> &'static [u8] {
+    let virt_addr = phys_addr + phys_mapping_offset();
+    let length = core::ptr::read_unaligned((virt_addr + 4) as *const u32);
+    from_raw_parts(virt_addr as *const u8, length as usize)
+}
 
-    if check_sum != 0 {
-        panic!("XSDT checksum failed");
+/// Processor brand string from CPUID, e.g. "QEMU Virtual CPU version 2.5+"
+fn cpu_model() -> String {
+    if __cpuid(0x8000_0000).eax < 0x8000_0004 {
+        return String::from("unknown");
     }
+    let bytes: Vec<u8> = (0x8000_0002..=0x8000_0004)
+        .map(|leaf| __cpuid(leaf))
+        .flat_map(|regs| [regs.eax, regs.ebx, regs.ecx, regs.edx])
+        .flat_map(u32::to_le_bytes)
+        .collect();
+    String::from_utf8_lossy(&bytes).trim_matches(|c: char| c == '\0' || c == ' ').into()
+}
 
-    let mut apic_addrs = ApicPhysAddrs { local_apic_addr:PhysAddr(0), io_apic_addr:PhysAddr(0)};
-    for sdt_ptr in pointers_to_other_sdts {
-        let header_ptr = (sdt_ptr + VIRT_MAPPING_OFFSET) as *const AcpiSdtHeader;
-        let header = unsafe { header_ptr.as_ref().unwrap() };
-        let s = match core::str::from_utf8(&header.signature) {
-            Ok(v) => v,
-            Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
+fn register_cpus(madt: &Madt) {
+    let bsp_apic_id = (__cpuid(1).ebx >> 24) as u8;
+    for (index, cpu) in madt.processors.iter().enumerate() {
+        let state = if cpu.is_enabled() {
+            "enabled"
+        } else if cpu.is_online_capable() {
+            "online capable"
+        } else {
+            "disabled"
         };
-        log::info!("Found SDT {}", s);
-        if s == "APIC" {
-            apic_addrs = handle_madt(header, VirtAddr::new_checked(sdt_ptr + VIRT_MAPPING_OFFSET + 36).unwrap()).unwrap();
+        let mut node = Node::new(format!("cpu{}", index))
+            .with("apic_id", cpu.apic_id)
+            .with("acpi_id", cpu.processor_id)
+            .with("state", state);
+        if cpu.apic_id == bsp_apic_id {
+            node = node.with("bsp", "yes").with("model", cpu_model());
         }
+        sysinfo::set(Category::Cpus, node);
     }
+}
 
-    if apic_addrs.local_apic_addr.0 == 0 {
-        panic!("Failed to find local APIC");
-    }
-
-    let mut apic_phys = apic_addrs.local_apic_addr.0;
-    let mut apic_virt = VirtAddr::new(apic_addrs.local_apic_addr.0 + VIRT_MAPPING_OFFSET);
-    let apic_virt_end = apic_virt.offset(0x10_0000)
-        .expect("Failed to offset virtual address");
+pub struct ApicAddresses {
+    pub local_apic_addr: VirtAddr,
+    pub io_apic_addr: VirtAddr,
+    pub io_apic_gsi_base: u32,
+    pub overrides: Vec<InterruptSourceOverride>,
+    /// Local APICs of the MADT, the bootstrap processor's included
+    pub processors: Vec<LocalApic>,
+}
 
-    let l4_table = unsafe {
-        active_level_4_table()
+pub fn read_xsdt(allocator: &mut FrameAllocator, rsdp_addr: u64) -> ApicAddresses {
+    log::info!("RSDP: {:#x}", rsdp_addr);
+    let rsdp = unsafe {
+        from_raw_parts((rsdp_addr + phys_mapping_offset()) as *const u8, RSDP_V2_SIZE)
     };
+    let xsdt_addr = parse_rsdp(rsdp).unwrap();
+    log::info!("XSDT addr: {:#x}", xsdt_addr);
 
-    while apic_virt < apic_virt_end {
-        unsafe {
-            map_address_with_offset(l4_table, apic_virt, apic_phys, allocator, VIRT_MAPPING_OFFSET)
-                .expect("Failed to map new frame");
-        }
+    let xsdt = unsafe { sdt_bytes(xsdt_addr) };
+    let xsdt_header = SdtHeader::parse(xsdt).unwrap();
+    log::info!("XSDT header: s:{:?}, len:{:#x}, rev:{}, oemid: {:?}, cr_rev: {:#x}", xsdt_header.signature, xsdt_header.length, xsdt_header.revision,
+    xsdt_header.oemid, xsdt_header.creator_revision);
 
-        apic_virt = apic_virt.offset(4096).unwrap();
-        apic_phys += 4096;
+    let mut madt = None;
+    let mut mcfg = Vec::new();
+    for sdt_ptr in parse_xsdt(xsdt).expect("XSDT checksum failed") {
+        let table = unsafe { sdt_bytes(sdt_ptr) };
+        let s = match core::str::from_utf8(&table[0..4]) {
+            Ok(v) => v,
+            Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
+        };
+        log::info!("Found SDT {}", s);
+        if s == "APIC" {
+            madt = Some(parse_madt(table).unwrap());
+        } else if s == "MCFG" {
+            // the ports still reach segment 0
+            mcfg = parse_mcfg(table).unwrap_or_else(|e| {
+                log::warn!("Ignoring the MCFG: {}", e);
+                Vec::new()
+            });
+        }
     }
+    crate::pci::init_ecam(allocator, &mcfg);
 
-    let io_apic_phys = apic_addrs.io_apic_addr.0 << 16; // hack. For some reason on qemu we need it
-    let io_apic_virt = VirtAddr::new(io_apic_phys + VIRT_MAPPING_OFFSET);
+    let madt = madt.expect("Failed to find local APIC");
+    register_cpus(&madt);
 
-    unsafe {
-        map_address_with_offset(l4_table, align_down(io_apic_virt), align_down_u64(io_apic_phys), allocator, VIRT_MAPPING_OFFSET)
-            .expect("Failed to map new frame");
-    }
+    // the register page of each
+    let local_apic_addr = map_mmio_with(allocator, PhysAddr(madt.local_apic_addr), PAGE_SIZE as usize)
+        .expect("Failed to map the local APIC");
+    let io_apic_addr = map_mmio_with(allocator, PhysAddr(madt.io_apic_addr.unwrap()), PAGE_SIZE as usize)
+        .expect("Failed to map the IO-APIC");
 
     ApicAddresses {
-        local_apic_addr: VirtAddr::new_checked(apic_addrs.local_apic_addr.0 + VIRT_MAPPING_OFFSET).unwrap(),
-        io_apic_addr: io_apic_virt
+        local_apic_addr,
+        io_apic_addr,
+        io_apic_gsi_base: madt.global_system_interrupt_base,
+        overrides: madt.overrides,
+        processors: madt.processors,
     }
 }
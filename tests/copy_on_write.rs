@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(shared_lib::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use shared_lib::{entry_point, phys_mapping_offset, BootInfo};
+use shared_lib::boot_info::{NextFreeFrame, Rsdp};
+use shared_lib::frame_allocator::MemoryMap;
+use shared_lib::page_table::{unmap_address_with_offset, PageTableFlags, PAGE_SIZE};
+use core::panic::PanicInfo;
+use ferr_os::allocator::{HEAP_SIZE, FRAME_ALLOCATOR, enable_heap_growth, init_heap};
+use ferr_os::memory::{active_level_4_table, translate_addr};
+use ferr_os::{cow, vm};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use shared_lib::frame_allocator::FrameAllocator;
+
+    let l4_table = unsafe {
+        active_level_4_table()
+    };
+
+    let mut allocator = unsafe {
+        FrameAllocator::new(boot_info.get::<MemoryMap>().unwrap(), phys_mapping_offset(),
+            boot_info.get::<NextFreeFrame>().unwrap().0)
+    };
+
+    init_heap(l4_table, &mut allocator)
+        .expect("Failed to init heap");
+
+    ferr_os::preinit(&mut allocator, boot_info.get::<Rsdp>().map_or(0, |rsdp| rsdp.0));
+
+    enable_heap_growth(allocator, 4 * HEAP_SIZE);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ferr_os::test_panic_handler(info)
+}
+
+fn allocate_frame() -> u64 {
+    FRAME_ALLOCATOR.lock().as_mut().and_then(|allocator| allocator.allocate_frame()).expect("Out of frames")
+}
+
+fn deallocate_frame(frame: u64) {
+    unsafe { FRAME_ALLOCATOR.lock().as_mut().unwrap().deallocate_frame(frame) };
+}
+
+#[test_case]
+fn kernel_write_gets_private_copy() {
+    let frame = allocate_frame();
+    let original = (frame + phys_mapping_offset()) as *mut u64;
+    unsafe { original.write_volatile(0x1234) };
+
+    let page = vm::allocate_region(PAGE_SIZE as usize, PageTableFlags::WRITABLE, "cow test").unwrap();
+    cow::map_shared(page, frame, PageTableFlags::WRITABLE).unwrap();
+
+    let shared = page.0 as *mut u64;
+    assert_eq!(unsafe { shared.read_volatile() }, 0x1234);
+    // faults with CR0.WP set, without it the write would land in `frame`
+    unsafe { shared.write_volatile(0x5678) };
+
+    assert_eq!(unsafe { shared.read_volatile() }, 0x5678);
+    assert_eq!(unsafe { original.read_volatile() }, 0x1234);
+    let private = unsafe { translate_addr(page) }.unwrap();
+    assert_ne!(private, frame);
+
+    // the page is a plain mapping of its copy now
+    unsafe { unmap_address_with_offset(active_level_4_table(), page, phys_mapping_offset()) }.unwrap();
+    deallocate_frame(private);
+    deallocate_frame(frame);
+    vm::free_region(page).unwrap();
+}
@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use shared_lib::{entry_point, get_tsc, serial_print, serial_println, BootInfo};
+use shared_lib::{exit_qemu, QemuExitCode};
+use shared_lib::boot_info::{NextFreeFrame, Rsdp};
+use shared_lib::frame_allocator::MemoryMap;
+use ferr_os::allocator::{HEAP_MAX_SIZE, enable_heap_growth, init_heap};
+use ferr_os::memory::active_level_4_table;
+use ferr_os::percpu;
+use ferr_os::task::executor::{self, Executor};
+use ferr_os::task::timer::{self, sleep_for};
+use ferr_os::task::Task;
+
+const SLEEPERS: usize = 10_000;
+
+static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use shared_lib::frame_allocator::FrameAllocator;
+
+    let l4_table = unsafe {
+        active_level_4_table()
+    };
+
+    let mut allocator = unsafe {
+        FrameAllocator::new(boot_info.get::<MemoryMap>().unwrap(), shared_lib::phys_mapping_offset(),
+            boot_info.get::<NextFreeFrame>().unwrap().0)
+    };
+
+    init_heap(l4_table, &mut allocator)
+        .expect("Failed to init heap");
+
+    ferr_os::preinit(&mut allocator, boot_info.get::<Rsdp>().map_or(0, |rsdp| rsdp.0));
+
+    enable_heap_growth(allocator, HEAP_MAX_SIZE);
+
+    serial_print!("executor_stress::many_timers...\t");
+
+    let mut executor = Executor::new();
+    for i in 0..SLEEPERS {
+        executor.spawn(Task::new(sleeper(i)));
+    }
+    executor.spawn(Task::new(supervisor(get_tsc())));
+    executor.run();
+
+    panic!("Executor stopped");
+}
+
+/// Sleeps between 4 ms and 200 ms, so the deadlines are spread over many ticks
+async fn sleeper(i: usize) {
+    sleep_for(4 + (i as u64 * 7) % 197).await;
+    WOKEN.fetch_add(1, Ordering::Relaxed);
+}
+
+async fn supervisor(start: u64) {
+    let timeout = timer::ticks() + timer::ms_to_ticks(10_000);
+    while WOKEN.load(Ordering::Relaxed) < SLEEPERS {
+        assert!(timer::ticks() < timeout, "only {} of {} sleepers woke up", WOKEN.load(Ordering::Relaxed), SLEEPERS);
+        sleep_for(10).await;
+    }
+
+    // only this task is left
+    assert!(executor::tasks().len() <= 1);
+
+    let cycles = get_tsc().wrapping_sub(start);
+    let polls = percpu::current().stats.polls.load(Ordering::Relaxed);
+    match timer::tsc_frequency() {
+        Some(hz) => serial_print!("{} polls in {} ms ", polls, cycles / (hz / 1000)),
+        None => serial_print!("{} polls in {} TSC cycles ", polls, cycles),
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ferr_os::test_panic_handler(info)
+}
@@ -8,9 +8,11 @@ extern crate alloc;
 
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-use shared_lib::{entry_point, BootInfo, VIRT_MAPPING_OFFSET};
+use shared_lib::{entry_point, BootInfo};
+use shared_lib::boot_info::{NextFreeFrame, Rsdp};
+use shared_lib::frame_allocator::MemoryMap;
 use core::panic::PanicInfo;
-use ferr_os::allocator::{HEAP_SIZE, init_heap};
+use ferr_os::allocator::{HEAP_SIZE, enable_heap_growth, heap_mapped_size, init_heap};
 use ferr_os::memory::active_level_4_table;
 
 entry_point!(main);
@@ -22,12 +24,17 @@ fn main(boot_info: &'static BootInfo) -> ! {
         active_level_4_table()
     };
 
-    let mut allocator = FrameAllocator::new(&boot_info.memory_map, VIRT_MAPPING_OFFSET, boot_info.memory_map_next_free_frame);
+    let mut allocator = unsafe {
+        FrameAllocator::new(boot_info.get::<MemoryMap>().unwrap(), shared_lib::phys_mapping_offset(),
+            boot_info.get::<NextFreeFrame>().unwrap().0)
+    };
 
     init_heap(l4_table, &mut allocator)
         .expect("Failed to init heap");
 
-    ferr_os::preinit(&mut allocator, boot_info.rsdp_addr);
+    ferr_os::preinit(&mut allocator, boot_info.get::<Rsdp>().map_or(0, |rsdp| rsdp.0));
+
+    enable_heap_growth(allocator, 4 * HEAP_SIZE);
 
     test_main();
     loop {}
@@ -72,4 +79,50 @@ fn many_boxes_long_lived() {
         assert_eq!(*x, i);
     }
     assert_eq!(*long_lived, 1);
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn heap_grows_on_demand() {
+    // doesn't fit into the eagerly mapped part of the heap
+    let n = 2 * HEAP_SIZE / 8;
+    let vec: Vec<u64> = (0..n as u64).collect();
+    assert_eq!(vec.iter().sum::<u64>(), (n as u64 - 1) * n as u64 / 2);
+    assert!(heap_mapped_size() > HEAP_SIZE);
+}
+
+#[test_case]
+fn vm_regions_do_not_overlap() {
+    use ferr_os::allocator::HEAP_START;
+    use ferr_os::vm::{allocate_region, find, free_region, reserve, VmError};
+    use shared_lib::addr::VirtAddr;
+    use shared_lib::page_table::PageTableFlags;
+
+    assert_eq!(Some("heap"), find(VirtAddr::new(HEAP_START as u64 + 4 * HEAP_SIZE as u64 - 1)).map(|vma| vma.name));
+    assert_eq!(Err(VmError::Overlap("heap")), reserve(VirtAddr::new(HEAP_START as u64), 4096, PageTableFlags::empty(), "test"));
+
+    let first = allocate_region(4096, PageTableFlags::WRITABLE, "test").unwrap();
+    let second = allocate_region(3 * 4096, PageTableFlags::WRITABLE, "test").unwrap();
+    // a guard page between the two
+    assert!(second.0 >= first.0 + 2 * 4096);
+    assert_eq!(3 * 4096, find(second).unwrap().len);
+
+    assert_eq!(4096, free_region(first).unwrap().len);
+    assert_eq!(Err(VmError::NotFound), free_region(first).map(|vma| vma.len));
+    free_region(second).unwrap();
+}
+
+#[cfg(feature = "heap_redzones")]
+#[test_case]
+fn redzone_overrun_is_detected() {
+    use shared_lib::allocator::redzone::{check_all, REDZONE_PATTERN};
+
+    let mut buffer = Box::new([0u8; 24]);
+    assert_eq!(check_all(), 0);
+
+    // one byte past the end, restored before the free would panic on it
+    let past_end = unsafe { buffer.as_mut_ptr().add(24) };
+    unsafe { past_end.write_volatile(0x42) };
+    assert_eq!(check_all(), 1);
+    unsafe { past_end.write_volatile(REDZONE_PATTERN) };
+    assert_eq!(check_all(), 0);
+}
@@ -0,0 +1,577 @@
+// Integration tests driven by the ramdisk.
+//
+// `package_kernel_and_run.py` packs `tests/integration/initrd` into a cpio archive and boots this
+// kernel with it. Every line of its `manifest` names a case and its arguments. The cases run
+// against the fixtures next to the manifest, each result is printed to serial like the unit
+// tests, and QEMU exits with success only if no case failed. Cases for subsystems the kernel
+// doesn't have are skipped rather than failed.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::future::{ready, Future};
+use core::panic::PanicInfo;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+use shared_lib::{entry_point, exit_qemu, serial_print, serial_println, BootInfo, QemuExitCode};
+use shared_lib::boot_info::{Initrd, NextFreeFrame};
+use shared_lib::frame_allocator::MemoryMap;
+use shared_lib::spinlock::Spinlock;
+use shared_lib::ferr_fs::{FerrFs, FileType};
+use shared_lib::io::{Read, SeekFrom, Write};
+use futures_util::future::BoxFuture;
+use ferr_os::allocator::init_heap;
+use ferr_os::block::{cache, Disk};
+use ferr_os::block::partition::{self, Partition, PartitionType};
+use ferr_os::block::stream::BlockStream;
+use ferr_os::gpt::{self, parse_gpt, GptEditor};
+use ferr_os::fs::{FdTable, FileError, OpenFlags, STDIN, STDOUT};
+use ferr_os::ide::{AtaError, BlockDevice, SECTOR_SIZE};
+use ferr_os::initrd;
+use ferr_os::memory::active_level_4_table;
+use ferr_os::vfs::{self, FileFlags as VfsFileFlags, FileType as VfsFileType, VfsError};
+
+enum Outcome {
+    Ok,
+    Failed(String),
+    Skipped(&'static str),
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use shared_lib::frame_allocator::FrameAllocator;
+
+    let l4_table = unsafe {
+        active_level_4_table()
+    };
+
+    let mut allocator = unsafe {
+        FrameAllocator::new(boot_info.get::<MemoryMap>().unwrap(), shared_lib::phys_mapping_offset(),
+            boot_info.get::<NextFreeFrame>().unwrap().0)
+    };
+
+    init_heap(l4_table, &mut allocator)
+        .expect("Failed to init heap");
+
+    let ramdisk = boot_info.get::<Initrd>().copied().unwrap_or(Initrd { addr: 0, size: 0 });
+    initrd::init(ramdisk.addr, ramdisk.size);
+
+    let manifest = initrd::find("manifest")
+        .and_then(|manifest| core::str::from_utf8(manifest).ok())
+        .expect("No manifest in the ramdisk");
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for line in manifest.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let mut args = line.split_whitespace();
+        let case = args.next().unwrap();
+        let args: Vec<&str> = args.collect();
+
+        serial_print!("integration::{}...\t", line);
+        match run_case(case, &args) {
+            Outcome::Ok => {
+                serial_println!("[ok]");
+                passed += 1;
+            },
+            Outcome::Failed(reason) => {
+                serial_println!("[failed] {}", reason);
+                failed += 1;
+            },
+            Outcome::Skipped(reason) => {
+                serial_println!("[skipped] {}", reason);
+                skipped += 1;
+            },
+        }
+    }
+
+    serial_println!("integration: {} passed, {} failed, {} skipped", passed, failed, skipped);
+    exit_qemu(if failed == 0 { QemuExitCode::Success } else { QemuExitCode::Failed });
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ferr_os::test_panic_handler(info)
+}
+
+fn run_case(case: &str, args: &[&str]) -> Outcome {
+    match case {
+        "gpt" => gpt(args),
+        "fs" => ferr_fs(),
+        "vfs" => vfs(),
+        "fd" => fd(),
+        "tmpfs" => tmpfs(),
+        "ext2" => ext2(args),
+        "cache" => cache_writeback(),
+        "mbr" => mbr(),
+        "gpt_edit" => gpt_edit(),
+        "udp" | "tcp" => Outcome::Skipped("no network stack in this kernel"),
+        _ => Outcome::Failed(format!("unknown case {}", case)),
+    }
+}
+
+/// Runs `future` to completion in place: the cases have no executor, and their futures only wait
+/// for the ramdisk, which completes every request right away
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+fn fixture(name: &str) -> Result<&'static [u8], String> {
+    initrd::find(name).ok_or_else(|| format!("fixture {} missing from the ramdisk", name))
+}
+
+/// A disk image from the ramdisk, writes are rejected
+struct RamDisk {
+    image: &'static [u8],
+}
+
+impl BlockDevice for RamDisk {
+    fn read<'a>(&'a self, lba: u64, buffer: &'a mut [u8]) -> BoxFuture<'a, Result<(), AtaError>> {
+        let start = lba as usize * SECTOR_SIZE;
+        let result = self.image.get(start..start + buffer.len())
+            .map(|sectors| buffer.copy_from_slice(sectors))
+            .ok_or(AtaError::IdMarkNotFound);
+        Box::pin(ready(result))
+    }
+
+    fn write<'a>(&'a self, _lba: u64, _data: &'a [u8]) -> BoxFuture<'a, Result<(), AtaError>> {
+        Box::pin(ready(Err(AtaError::CommandAborted)))
+    }
+
+    fn size(&self) -> u64 {
+        (self.image.len() / SECTOR_SIZE) as u64
+    }
+
+    fn model(&self) -> [u8; 41] {
+        let mut model = [b' '; 41];
+        model[..7].copy_from_slice(b"ramdisk");
+        model
+    }
+
+    fn location(&self) -> String {
+        String::from("initrd")
+    }
+}
+
+/// `gpt <disk image> <partition count> [<partition name>...]`
+fn gpt(args: &[&str]) -> Outcome {
+    let (Some(image), Some(Ok(expected_count))) = (args.first(), args.get(1).map(|count| count.parse::<usize>())) else {
+        return Outcome::Failed(String::from("usage: gpt <disk image> <partition count> [<partition name>...]"));
+    };
+    let image = match fixture(image) {
+        Ok(image) => image,
+        Err(e) => return Outcome::Failed(e),
+    };
+
+    let disk = Arc::new(Disk::new(String::from("ramdisk"), Box::new(RamDisk { image })));
+    let partitions = match block_on(parse_gpt(&disk)) {
+        Ok(partitions) => partitions,
+        Err(e) => return Outcome::Failed(format!("{:?}", e)),
+    };
+    if partitions.len() != expected_count {
+        return Outcome::Failed(format!("{} partitions, expected {}", partitions.len(), expected_count));
+    }
+    for (partition, expected_name) in partitions.iter().zip(&args[2..]) {
+        if partition.name() != *expected_name {
+            return Outcome::Failed(format!("partition {}, expected {}", partition.name(), expected_name));
+        }
+    }
+
+    // partition LBAs are relative to its start and stop at its end
+    for partition in &partitions {
+        let (mut sector, mut expected) = ([0; SECTOR_SIZE], [0; SECTOR_SIZE]);
+        if block_on(partition.read(0, &mut sector)).is_err()
+            || block_on(disk.read(partition.start(), &mut expected)).is_err()
+            || sector != expected {
+            return Outcome::Failed(format!("partition {} doesn't start at LBA {}", partition.id(), partition.start()));
+        }
+        if block_on(partition.read(partition.size(), &mut sector)).is_ok() {
+            return Outcome::Failed(format!("partition {} read past its end", partition.id()));
+        }
+    }
+    Outcome::Ok
+}
+
+/// A writable disk in memory
+struct MemDisk {
+    sectors: Spinlock<Vec<u8>>,
+}
+
+impl BlockDevice for MemDisk {
+    fn read<'a>(&'a self, lba: u64, buffer: &'a mut [u8]) -> BoxFuture<'a, Result<(), AtaError>> {
+        let start = lba as usize * SECTOR_SIZE;
+        let result = self.sectors.lock().get(start..start + buffer.len())
+            .map(|sectors| buffer.copy_from_slice(sectors))
+            .ok_or(AtaError::IdMarkNotFound);
+        Box::pin(ready(result))
+    }
+
+    fn write<'a>(&'a self, lba: u64, data: &'a [u8]) -> BoxFuture<'a, Result<(), AtaError>> {
+        let start = lba as usize * SECTOR_SIZE;
+        let result = self.sectors.lock().get_mut(start..start + data.len())
+            .map(|sectors| sectors.copy_from_slice(data))
+            .ok_or(AtaError::IdMarkNotFound);
+        Box::pin(ready(result))
+    }
+
+    fn size(&self) -> u64 {
+        (self.sectors.lock().len() / SECTOR_SIZE) as u64
+    }
+
+    fn model(&self) -> [u8; 41] {
+        let mut model = [b' '; 41];
+        model[..6].copy_from_slice(b"memory");
+        model
+    }
+
+    fn location(&self) -> String {
+        String::from("memory")
+    }
+}
+
+/// Writes an MBR or EBR entry into `sector`
+fn mbr_entry(sector: &mut [u8], slot: usize, partition_type: u8, start: u32, sectors: u32) {
+    let entry = &mut sector[446 + slot * 16..446 + (slot + 1) * 16];
+    entry[4] = partition_type;
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    sector[510..512].copy_from_slice(&[0x55, 0xaa]);
+}
+
+/// `mbr`: a primary partition and two logical ones in an extended partition
+fn mbr() -> Outcome {
+    let mut image = vec![0; 64 * SECTOR_SIZE];
+    mbr_entry(&mut image[..SECTOR_SIZE], 0, 0x0c, 2, 4);
+    mbr_entry(&mut image[..SECTOR_SIZE], 1, 0x0f, 8, 32);
+    // EBRs: logical partitions are relative to their EBR, links to the extended partition
+    mbr_entry(&mut image[8 * SECTOR_SIZE..9 * SECTOR_SIZE], 0, 0x83, 1, 4);
+    mbr_entry(&mut image[8 * SECTOR_SIZE..9 * SECTOR_SIZE], 1, 0x05, 16, 8);
+    mbr_entry(&mut image[24 * SECTOR_SIZE..25 * SECTOR_SIZE], 0, 0x83, 1, 4);
+
+    let disk = Arc::new(Disk::new(String::from("mbrdisk"), Box::new(MemDisk { sectors: Spinlock::new(image) })));
+    let partitions = match block_on(partition::scan(&disk)) {
+        Ok(partitions) => partitions,
+        Err(e) => return Outcome::Failed(format!("{:?}", e)),
+    };
+    let found: Vec<(usize, u64, PartitionType)> = partitions.iter()
+        .map(|partition| (partition.index(), partition.start(), partition.partition_type()))
+        .collect();
+    let expected = [(1, 2, PartitionType::Mbr(0x0c)), (5, 9, PartitionType::Mbr(0x83)), (6, 25, PartitionType::Mbr(0x83))];
+    if found != expected {
+        return Outcome::Failed(format!("partitions {:?}, expected {:?}", found, expected));
+    }
+    Outcome::Ok
+}
+
+/// `gpt_edit`: a table made by `GptEditor` is found again, changes to it too
+fn gpt_edit() -> Outcome {
+    const SECTORS: usize = 128;
+    let disk = Arc::new(Disk::new(String::from("gptdisk"), Box::new(MemDisk { sectors: Spinlock::new(vec![0; SECTORS * SECTOR_SIZE]) })));
+    let linux = gpt::type_guid("linux").unwrap();
+
+    let mut editor = match GptEditor::create(disk.clone()) {
+        Ok(editor) => editor,
+        Err(e) => return Outcome::Failed(format!("create: {:?}", e)),
+    };
+    let (first, last) = editor.usable();
+    if editor.add(linux, first, first + 15, "root").is_err() || editor.add(linux, first + 16, last, "data").is_err() {
+        return Outcome::Failed(String::from("add failed"));
+    }
+    if editor.add(linux, first + 10, first + 20, "overlap").is_ok() {
+        return Outcome::Failed(String::from("overlapping partition added"));
+    }
+    if let Err(e) = block_on(editor.write()) {
+        return Outcome::Failed(format!("write: {:?}", e));
+    }
+
+    let names = |disk: &Arc<Disk>| block_on(partition::scan(disk))
+        .map(|partitions| partitions.iter().map(|partition| (String::from(partition.name()), partition.end())).collect::<Vec<_>>());
+    match names(&disk) {
+        Ok(found) if found == [(String::from("root"), first + 15), (String::from("data"), last)] => {},
+        found => return Outcome::Failed(format!("created table: {:?}", found)),
+    }
+
+    let mut backup = [0; SECTOR_SIZE];
+    if block_on(disk.read(SECTORS as u64 - 1, &mut backup)).is_err() || &backup[..8] != b"EFI PART" {
+        return Outcome::Failed(String::from("no backup header"));
+    }
+
+    let mut editor = match block_on(GptEditor::open(disk.clone())) {
+        Ok(editor) => editor,
+        Err(e) => return Outcome::Failed(format!("open: {:?}", e)),
+    };
+    if editor.delete(2).is_err() || editor.resize(1, last).is_err() {
+        return Outcome::Failed(String::from("delete or resize failed"));
+    }
+    if let Err(e) = block_on(editor.write()) {
+        return Outcome::Failed(format!("write: {:?}", e));
+    }
+    match names(&disk) {
+        Ok(found) if found == [(String::from("root"), last)] => Outcome::Ok,
+        found => Outcome::Failed(format!("changed table: {:?}", found)),
+    }
+}
+
+/// `cache`: writes stay in the cache until a sync, which writes adjacent sectors with one request
+fn cache_writeback() -> Outcome {
+    const SECTORS: usize = 64;
+    let disk = Arc::new(Disk::new(String::from("memdisk"), Box::new(MemDisk { sectors: Spinlock::new(vec![0; SECTORS * SECTOR_SIZE]) })));
+    let on_disk = |lba: usize| {
+        let mut sector = [0; SECTOR_SIZE];
+        block_on(disk.device().read(lba as u64, &mut sector)).ok().map(|_| sector[0])
+    };
+
+    let data: Vec<u8> = (0..4 * SECTOR_SIZE).map(|i| (i / SECTOR_SIZE) as u8 + 1).collect();
+    if let Err(e) = block_on(cache::write(&disk, 10, &data)) {
+        return Outcome::Failed(format!("write: {:?}", e));
+    }
+    if on_disk(10) != Some(0) {
+        return Outcome::Failed(String::from("written through before the sync"));
+    }
+
+    let mut read_back = vec![0; data.len()];
+    if block_on(cache::read(&disk, 10, &mut read_back)).is_err() || read_back != data {
+        return Outcome::Failed(String::from("cached data doesn't match"));
+    }
+
+    if let Err(e) = block_on(cache::sync()) {
+        return Outcome::Failed(format!("sync: {:?}", e));
+    }
+    for lba in 10..14 {
+        if on_disk(lba) != Some(lba as u8 - 9) {
+            return Outcome::Failed(format!("LBA {} not written back", lba));
+        }
+    }
+    let writes = disk.stats().writes;
+    if writes != 1 {
+        return Outcome::Failed(format!("{} writes for one run of sectors", writes));
+    }
+    Outcome::Ok
+}
+
+/// `fs`: ferr_fs on a partition, through a `BlockStream`, keeps its files across a remount and
+/// stays inside the partition
+fn ferr_fs() -> Outcome {
+    const SECTORS: usize = 1024;
+    const START: u64 = 64;
+    let disk = Arc::new(Disk::new(String::from("fsdisk"), Box::new(MemDisk { sectors: Spinlock::new(vec![0; SECTORS * SECTOR_SIZE]) })));
+    let partition = match Partition::new(disk.clone(), 1, START, 512) {
+        Ok(partition) => partition,
+        Err(e) => return Outcome::Failed(format!("partition: {:?}", e)),
+    };
+
+    let data: Vec<u8> = (0..20000).map(|i| (i % 253) as u8).collect();
+    let written = FerrFs::format(BlockStream::new(&partition)).and_then(|mut fs| {
+        fs.mkdir("/logs")?;
+        fs.create("/logs/boot.log")?.write_all(&data)?;
+        fs.create("/empty")?;
+        fs.flush()
+    });
+    if let Err(e) = written {
+        return Outcome::Failed(format!("write: {:?}", e));
+    }
+
+    let mut fs = match FerrFs::mount(BlockStream::new(&partition)) {
+        Ok(fs) => fs,
+        Err(e) => return Outcome::Failed(format!("mount: {:?}", e)),
+    };
+    let expected = [
+        (String::from("logs"), FileType::Directory, 64),
+        (String::from("logs/boot.log"), FileType::File, data.len() as u64),
+        (String::from("empty"), FileType::File, 0),
+    ];
+    let files = fs.walk("/").map(|files| files.into_iter().map(|info| (info.name, info.file_type, info.size)).collect::<Vec<_>>());
+    match files {
+        Ok(files) if files == expected => {},
+        files => return Outcome::Failed(format!("files {:?}", files)),
+    }
+    let mut read_back = vec![0; data.len()];
+    match fs.open("/logs/boot.log").map(|mut file| file.read_exact(&mut read_back)) {
+        Ok(Ok(())) if read_back == data => {},
+        result => return Outcome::Failed(format!("read back: {:?}", result)),
+    }
+
+    let outside = |lba: u64| {
+        let mut sector = [0; SECTOR_SIZE];
+        block_on(disk.device().read(lba, &mut sector)).map_or(true, |_| sector != [0; SECTOR_SIZE])
+    };
+    if outside(START - 1) || outside(START + 512) {
+        return Outcome::Failed(String::from("wrote outside the partition"));
+    }
+    Outcome::Ok
+}
+
+/// An image made by Linux `mke2fs -t ext2 -d`, with `big.bin` holding `i % 251` at byte `i`
+fn ext2(args: &[&str]) -> Outcome {
+    let Some(image) = args.first() else {
+        return Outcome::Failed(String::from("usage: ext2 <disk image>"));
+    };
+    let image = match fixture(image) {
+        Ok(image) => image,
+        Err(e) => return Outcome::Failed(e),
+    };
+    let sectors = (image.len() / SECTOR_SIZE) as u64;
+    let disk = Arc::new(Disk::new(String::from("ext2disk"), Box::new(RamDisk { image })));
+    let partition = match Partition::new(disk, 1, 0, sectors) {
+        Ok(partition) => partition,
+        Err(e) => return Outcome::Failed(format!("partition: {:?}", e)),
+    };
+
+    let result = (|| {
+        vfs::mount_partition(&partition, "/linux", None)?;
+        let mut names: Vec<String> = vfs::read_dir("/linux")?.into_iter().map(|entry| entry.name).collect();
+        names.sort();
+        let big = vfs::read_to_end("/linux/big.bin")?;
+        let readme = vfs::read_to_end("/linux/docs/readme.txt")?;
+        let read_only = vfs::create("/linux/new");
+        vfs::unmount("/linux")?;
+        Ok::<_, VfsError>((names, big, readme, read_only))
+    })();
+    match result {
+        Ok((names, big, readme, read_only)) => {
+            if names != ["big.bin", "docs", "link", "lost+found"] {
+                return Outcome::Failed(format!("names {:?}", names));
+            }
+            if big.len() != 20000 || big.iter().enumerate().any(|(i, &byte)| byte != (i % 251) as u8) {
+                return Outcome::Failed(String::from("big.bin read back wrong"));
+            }
+            if readme != b"made by mke2fs\n" || read_only != Err(VfsError::ReadOnly) {
+                return Outcome::Failed(format!("{:?} {:?}", readme, read_only));
+            }
+            Outcome::Ok
+        },
+        Err(e) => Outcome::Failed(format!("{:?}", e)),
+    }
+}
+
+/// Two ferr_fs partitions, one mounted on the root and one below it
+fn vfs() -> Outcome {
+    let disk = Arc::new(Disk::new(String::from("vfsdisk"), Box::new(MemDisk { sectors: Spinlock::new(vec![0; 1024 * SECTOR_SIZE]) })));
+    let partitions = (Partition::new(disk.clone(), 1, 0, 512), Partition::new(disk, 2, 512, 512));
+    let (Ok(root), Ok(data)) = partitions else {
+        return Outcome::Failed(String::from("partitions"));
+    };
+    for partition in [&root, &data] {
+        if let Err(e) = FerrFs::format(BlockStream::new(partition)) {
+            return Outcome::Failed(format!("format: {:?}", e));
+        }
+    }
+
+    let result = (|| {
+        vfs::mount_partition(&root, "/", None)?;
+        vfs::mount_partition(&data, "/data", Some("ferr_fs"))?;
+        vfs::mkdir("/etc")?;
+        vfs::create("/data/./x/../notes")?;
+        vfs::write("/data/notes", 3, b"abc")?;
+        let names: Vec<(String, VfsFileType)> = vfs::read_dir("/")?.into_iter()
+            .map(|entry| (entry.name, entry.metadata.file_type))
+            .collect();
+        let contents = vfs::read_to_end("/data/notes")?;
+        let busy = vfs::rmdir("/data");
+        vfs::unmount("/data")?;
+        let hidden = vfs::metadata("/data/notes");
+        vfs::unmount("/")?;
+        Ok::<_, VfsError>((names, contents, busy, hidden))
+    })();
+    let expected = (
+        vec![(String::from("etc"), VfsFileType::Directory), (String::from("data"), VfsFileType::Directory)],
+        Vec::from(*b"\0\0\0abc"),
+        Err(VfsError::Busy),
+        Err(VfsError::NotFound),
+    );
+    match result {
+        Ok(result) if result == expected => Outcome::Ok,
+        result => Outcome::Failed(format!("{:?}", result)),
+    }
+}
+
+fn tmpfs() -> Outcome {
+    let result = (|| {
+        vfs::mount_tmpfs("/scratch")?;
+        vfs::mkdir("/scratch/dir")?;
+        vfs::create("/scratch/dir/file")?;
+        vfs::write("/scratch/dir/file", 2, b"tmp")?;
+        vfs::create("/scratch/a")?;
+        let names: Vec<(String, VfsFileType, u64)> = vfs::read_dir("/scratch")?.into_iter()
+            .map(|entry| (entry.name, entry.metadata.file_type, entry.metadata.size))
+            .collect();
+        let contents = vfs::read_to_end("/scratch/dir/file")?;
+        let not_empty = vfs::rmdir("/scratch/dir");
+        vfs::set_flags("/scratch/dir/file", VfsFileFlags::READ_ONLY)?;
+        let read_only = (vfs::write("/scratch/dir/file", 0, b"x"), vfs::remove("/scratch/dir/file"));
+        vfs::set_flags("/scratch/dir/file", VfsFileFlags::empty())?;
+        vfs::remove("/scratch/dir/file")?;
+        vfs::rmdir("/scratch/dir")?;
+        let gone = vfs::metadata("/scratch/dir");
+        vfs::unmount("/scratch")?;
+        Ok::<_, VfsError>((names, contents, not_empty, read_only, gone))
+    })();
+    let expected = (
+        vec![(String::from("a"), VfsFileType::File, 0), (String::from("dir"), VfsFileType::Directory, 1)],
+        Vec::from(*b"\0\0tmp"),
+        Err(VfsError::DirectoryNotEmpty),
+        (Err(VfsError::ReadOnly), Err(VfsError::ReadOnly)),
+        Err(VfsError::NotFound),
+    );
+    match result {
+        Ok(result) if result == expected => Outcome::Ok,
+        result => Outcome::Failed(format!("{:?}", result)),
+    }
+}
+
+fn fd() -> Outcome {
+    let disk = Arc::new(Disk::new(String::from("fddisk"), Box::new(MemDisk { sectors: Spinlock::new(vec![0; 512 * SECTOR_SIZE]) })));
+    let Ok(partition) = Partition::new(disk, 1, 0, 512) else {
+        return Outcome::Failed(String::from("partition"));
+    };
+    if let Err(e) = FerrFs::format(BlockStream::new(&partition)) {
+        return Outcome::Failed(format!("format: {:?}", e));
+    }
+    if let Err(e) = vfs::mount_partition(&partition, "/", None) {
+        return Outcome::Failed(format!("mount: {:?}", e));
+    }
+
+    let files = FdTable::new();
+    let result = (|| {
+        let missing = files.open("/log", OpenFlags::READ);
+        let log = files.open("/log", OpenFlags::WRITE | OpenFlags::CREATE)?;
+        files.write(log, b"hello ")?;
+        files.write(log, b"world")?;
+        let reader = files.open("/log", OpenFlags::READ)?;
+        files.seek(reader, SeekFrom::End(-5))?;
+        let mut word = [0u8; 8];
+        let len = files.read(reader, &mut word)?;
+        let end = files.read(reader, &mut word)?;
+        let not_writable = files.write(reader, b"x");
+        files.close(log)?;
+        let closed = files.write(log, b"x");
+        // the lowest free descriptor again
+        let again = files.open("/log", OpenFlags::READ | OpenFlags::WRITE | OpenFlags::APPEND)?;
+        files.write(again, b"!")?;
+        files.seek(again, SeekFrom::Start(0))?;
+        let mut all = [0u8; 16];
+        let all_len = files.read(again, &mut all)?;
+        let stdio = (files.read(STDIN, &mut all)?, files.write(STDOUT, b"fd case on stdout\n")?);
+        Ok::<_, FileError>((missing, (log, reader, again), word[..len].to_vec(), end, not_writable, closed, all[..all_len].to_vec(), stdio))
+    })();
+    let _ = vfs::unmount("/");
+    match result {
+        Ok((Err(FileError::Vfs(VfsError::NotFound)), (log, reader, again), word, 0, Err(FileError::NotWritable), Err(FileError::BadDescriptor), all, (0, 18)))
+            if (log.0, reader.0, again.0) == (3, 4, 3) && word == b"world" && all == b"hello world!" => Outcome::Ok,
+        result => Outcome::Failed(format!("{:?}", result)),
+    }
+}
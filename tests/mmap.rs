@@ -0,0 +1,154 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(shared_lib::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use shared_lib::{entry_point, BootInfo};
+use shared_lib::addr::VirtAddr;
+use shared_lib::boot_info::{NextFreeFrame, Rsdp};
+use shared_lib::frame_allocator::MemoryMap;
+use core::panic::PanicInfo;
+use ferr_os::allocator::{HEAP_SIZE, enable_heap_growth, init_heap};
+use ferr_os::memory::{active_level_4_table, translate_addr};
+use ferr_os::vfs::{self, MapFlags};
+use ferr_os::vm;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use shared_lib::frame_allocator::FrameAllocator;
+
+    let l4_table = unsafe {
+        active_level_4_table()
+    };
+
+    let mut allocator = unsafe {
+        FrameAllocator::new(boot_info.get::<MemoryMap>().unwrap(), shared_lib::phys_mapping_offset(),
+            boot_info.get::<NextFreeFrame>().unwrap().0)
+    };
+
+    init_heap(l4_table, &mut allocator)
+        .expect("Failed to init heap");
+
+    ferr_os::preinit(&mut allocator, boot_info.get::<Rsdp>().map_or(0, |rsdp| rsdp.0));
+
+    enable_heap_growth(allocator, 4 * HEAP_SIZE);
+
+    vfs::mount_tmpfs("/tmp").expect("Failed to mount /tmp");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ferr_os::test_panic_handler(info)
+}
+
+/// A file of `len` bytes counting up from 0
+fn create_file(path: &str, len: usize) -> Vec<u8> {
+    let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+    vfs::create(path).unwrap();
+    vfs::write(path, 0, &data).unwrap();
+    data
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    vfs::read_to_end(path).unwrap()
+}
+
+fn mapped<'a>(addr: VirtAddr, len: usize) -> &'a mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(addr.0 as *mut u8, len) }
+}
+
+#[test_case]
+fn mmap_maps_every_page() {
+    let data = create_file("/tmp/read", 3 * 4096 + 100);
+    let addr = vfs::mmap("/tmp/read", data.len(), MapFlags::empty()).unwrap();
+
+    // mapped up front, nothing is paged in on access
+    for page in 0..4 {
+        assert!(unsafe { translate_addr(VirtAddr::new(addr.0 + page * 4096)) }.is_some());
+    }
+    assert_eq!(mapped(addr, data.len()), &data[..]);
+    // past the end of the file is zeroed
+    assert!(mapped(VirtAddr::new(addr.0 + data.len() as u64), 4096 - 100).iter().all(|&b| b == 0));
+
+    vfs::munmap(addr).unwrap();
+    vfs::remove("/tmp/read").unwrap();
+}
+
+#[test_case]
+fn mmap_shares_the_page_cache() {
+    let data = create_file("/tmp/shared", 4096);
+    let first = vfs::mmap("/tmp/shared", data.len(), MapFlags::WRITE).unwrap();
+    let second = vfs::mmap("/tmp/shared", data.len(), MapFlags::empty()).unwrap();
+    assert_ne!(first, second);
+
+    mapped(first, 1)[0] = 0xAA;
+    assert_eq!(mapped(second, 1)[0], 0xAA);
+    assert_eq!(unsafe { translate_addr(first) }, unsafe { translate_addr(second) });
+
+    vfs::munmap(second).unwrap();
+    vfs::munmap(first).unwrap();
+    vfs::remove("/tmp/shared").unwrap();
+}
+
+#[test_case]
+fn msync_writes_dirty_pages_back() {
+    let mut data = create_file("/tmp/msync", 2 * 4096);
+    let addr = vfs::mmap("/tmp/msync", data.len(), MapFlags::WRITE).unwrap();
+
+    mapped(addr, data.len())[4096 + 7] = 0x55;
+    data[4096 + 7] = 0x55;
+    vfs::msync(addr).unwrap();
+    assert_eq!(read_file("/tmp/msync"), data);
+
+    // nothing changed since, the file is left alone
+    vfs::write("/tmp/msync", 0, b"new").unwrap();
+    vfs::msync(addr).unwrap();
+    assert_eq!(&read_file("/tmp/msync")[..3], b"new");
+
+    vfs::munmap(addr).unwrap();
+    vfs::remove("/tmp/msync").unwrap();
+}
+
+#[test_case]
+fn msync_grows_the_file() {
+    create_file("/tmp/grow", 100);
+    let addr = vfs::mmap("/tmp/grow", 2 * 4096, MapFlags::WRITE).unwrap();
+
+    mapped(VirtAddr::new(addr.0 + 4096), 1)[0] = 1;
+    vfs::msync(addr).unwrap();
+    assert_eq!(vfs::metadata("/tmp/grow").unwrap().size, 2 * 4096);
+
+    vfs::munmap(addr).unwrap();
+    vfs::remove("/tmp/grow").unwrap();
+}
+
+#[test_case]
+fn munmap_writes_back_and_unmaps() {
+    create_file("/tmp/munmap", 4096);
+    let addr = vfs::mmap("/tmp/munmap", 4096, MapFlags::WRITE).unwrap();
+
+    mapped(addr, 4096).fill(0x11);
+    vfs::munmap(addr).unwrap();
+    assert_eq!(read_file("/tmp/munmap"), vec![0x11; 4096]);
+    assert!(unsafe { translate_addr(addr) }.is_none());
+    assert!(vm::find(addr).is_none());
+    assert!(vfs::munmap(addr).is_err());
+
+    vfs::remove("/tmp/munmap").unwrap();
+}
+
+#[test_case]
+fn mmap_rejects_directories() {
+    vfs::mkdir("/tmp/dir").unwrap();
+    assert!(vfs::mmap("/tmp/dir", 4096, MapFlags::empty()).is_err());
+    vfs::rmdir("/tmp/dir").unwrap();
+}
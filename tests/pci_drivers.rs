@@ -0,0 +1,124 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(shared_lib::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use shared_lib::{entry_point, BootInfo};
+use shared_lib::boot_info::NextFreeFrame;
+use shared_lib::frame_allocator::MemoryMap;
+use ferr_os::allocator::init_heap;
+use ferr_os::memory::active_level_4_table;
+use ferr_os::pci::{self, PciAddress, PciDevice, PciFunctionId, PciMatch, ProbeResult, Probed};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use shared_lib::frame_allocator::FrameAllocator;
+
+    let l4_table = unsafe {
+        active_level_4_table()
+    };
+
+    let mut allocator = unsafe {
+        FrameAllocator::new(boot_info.get::<MemoryMap>().unwrap(), shared_lib::phys_mapping_offset(),
+            boot_info.get::<NextFreeFrame>().unwrap().0)
+    };
+
+    init_heap(l4_table, &mut allocator)
+        .expect("Failed to init heap");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ferr_os::test_panic_handler(info)
+}
+
+/// The probes complete right away
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+fn function_id(vendor_id: u16, device_id: u16, class_code: u8, subclass: u8, prog_if: u8) -> PciFunctionId {
+    PciFunctionId { vendor_id, device_id, class_code, subclass, prog_if, header_type: 0 }
+}
+
+fn address(device: u8) -> PciAddress {
+    PciAddress { segment: 0, bus: 0xFF, device, function: 0 }
+}
+
+static REJECTED: AtomicUsize = AtomicUsize::new(0);
+
+async fn reject(_device: PciDevice) -> ProbeResult {
+    REJECTED.fetch_add(1, Ordering::Relaxed);
+    Err("not this one")
+}
+
+async fn accept(_device: PciDevice) -> ProbeResult {
+    Ok(Vec::new())
+}
+
+fn remove(_address: PciAddress) {}
+
+#[test_case]
+fn class_match() {
+    let pattern = PciMatch::Class(0x1, 0x6, None);
+    assert!(pattern.matches(&function_id(0x8086, 0x2922, 0x1, 0x6, 0x1)));
+    assert!(pattern.matches(&function_id(0x1B36, 0x0010, 0x1, 0x6, 0x2)));
+    assert!(!pattern.matches(&function_id(0x8086, 0x2922, 0x1, 0x8, 0x1)));
+}
+
+#[test_case]
+fn class_match_with_prog_if() {
+    let pattern = PciMatch::Class(0x1, 0x1, Some(0x80));
+    assert!(pattern.matches(&function_id(0x8086, 0x7010, 0x1, 0x1, 0x80)));
+    assert!(!pattern.matches(&function_id(0x8086, 0x7010, 0x1, 0x1, 0x8A)));
+}
+
+#[test_case]
+fn id_match() {
+    let pattern = PciMatch::Id(0x1AF4, 0x1042);
+    assert!(pattern.matches(&function_id(0x1AF4, 0x1042, 0x1, 0x0, 0x0)));
+    assert!(!pattern.matches(&function_id(0x1AF4, 0x1001, 0x1, 0x0, 0x0)));
+    // the class doesn't matter
+    assert!(pattern.matches(&function_id(0x1AF4, 0x1042, 0xFF, 0xFF, 0xFF)));
+}
+
+#[test_case]
+fn bind_falls_through_to_next_driver() {
+    const REJECTING: &[PciMatch] = &[PciMatch::Id(0xFFF0, 0x0001)];
+    const ACCEPTING: &[PciMatch] = &[PciMatch::Class(0xFF, 0x01, None)];
+    pci::register_driver("test-reject", REJECTING, reject, remove).unwrap();
+    pci::register_driver("test-accept", ACCEPTING, accept, remove).unwrap();
+
+    let device = PciDevice::from_id(address(1), function_id(0xFFF0, 0x0001, 0xFF, 0x01, 0x0));
+    let (driver, probed) = block_on(pci::bind(device));
+    assert_eq!(driver, Some("test-accept"));
+    assert!(probed.is_empty());
+    assert_eq!(REJECTED.load(Ordering::Relaxed), 1);
+    assert_eq!(pci::bound_driver(address(1)), Some("test-accept"));
+
+    // nothing matches
+    let device = PciDevice::from_id(address(2), function_id(0xFFF0, 0x0002, 0xFF, 0x02, 0x0));
+    let (driver, probed) = block_on(pci::bind(device));
+    assert_eq!(driver, None);
+    assert!(matches!(probed.as_slice(), [Probed::Generic(_)]));
+    assert_eq!(pci::bound_driver(address(2)), None);
+}
@@ -0,0 +1,165 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(shared_lib::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use shared_lib::{entry_point, BootInfo};
+use shared_lib::boot_info::NextFreeFrame;
+use shared_lib::frame_allocator::MemoryMap;
+use core::panic::PanicInfo;
+use ferr_os::allocator::init_heap;
+use ferr_os::gpt::{check_protective_mbr, parse_partition_entries, GptError, PartitionTableHeader};
+use ferr_os::memory::active_level_4_table;
+use ferr_os::smart::Health;
+use ferr_os::xsdt::{parse_madt, parse_mcfg, parse_rsdp, parse_xsdt, McfgEntry};
+
+// Tables as found in QEMU (q35, 2 CPUs) and sectors of a disk made by `disk_image`
+static RSDP: &[u8] = include_bytes!("data/rsdp.bin");
+static XSDT: &[u8] = include_bytes!("data/xsdt.bin");
+static MADT: &[u8] = include_bytes!("data/madt.bin");
+static MCFG: &[u8] = include_bytes!("data/mcfg.bin");
+static GPT_LBA0: &[u8] = include_bytes!("data/gpt_lba0.bin");
+static GPT_LBA1: &[u8] = include_bytes!("data/gpt_lba1.bin");
+static GPT_ENTRIES: &[u8] = include_bytes!("data/gpt_entries.bin");
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use shared_lib::frame_allocator::FrameAllocator;
+
+    let l4_table = unsafe {
+        active_level_4_table()
+    };
+
+    let mut allocator = unsafe {
+        FrameAllocator::new(boot_info.get::<MemoryMap>().unwrap(), shared_lib::phys_mapping_offset(),
+            boot_info.get::<NextFreeFrame>().unwrap().0)
+    };
+
+    init_heap(l4_table, &mut allocator)
+        .expect("Failed to init heap");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ferr_os::test_panic_handler(info)
+}
+
+/// Copies `blob` to an odd address, so every multi-byte field is misaligned.
+fn misaligned(blob: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(blob.len() + 1);
+    buffer.push(0);
+    buffer.extend_from_slice(blob);
+    buffer
+}
+
+#[test_case]
+fn rsdp() {
+    assert_eq!(0x7FFE22A0, parse_rsdp(RSDP).unwrap());
+    assert_eq!(0x7FFE22A0, parse_rsdp(&misaligned(RSDP)[1..]).unwrap());
+
+    let mut broken = misaligned(RSDP);
+    broken[25] ^= 1;
+    assert!(parse_rsdp(&broken[1..]).is_err());
+}
+
+#[test_case]
+fn xsdt_entries() {
+    let buffer = misaligned(XSDT);
+    let entries: Vec<u64> = parse_xsdt(&buffer[1..]).unwrap().collect();
+    assert_eq!(&[0x7FFE2000, 0x7FFE20F4, 0x7FFE2184, 0x7FFE21BC], entries.as_slice());
+}
+
+#[test_case]
+fn madt() {
+    let buffer = misaligned(MADT);
+    let madt = parse_madt(&buffer[1..]).unwrap();
+
+    assert_eq!(0xFEE00000, madt.local_apic_addr);
+    assert_eq!(Some(0xFEC00000), madt.io_apic_addr);
+    assert_eq!(0, madt.global_system_interrupt_base);
+    assert_eq!(5, madt.overrides.len());
+    assert_eq!(0, madt.overrides[0].irq_source);
+    assert_eq!(2, madt.overrides[0].global_system_interrupt);
+    assert_eq!(0xd, madt.overrides[1].flags);
+    assert_eq!(2, madt.processors.len());
+    assert_eq!(1, madt.processors[1].apic_id);
+    assert!(madt.processors.iter().all(|cpu| cpu.is_enabled()));
+}
+
+#[test_case]
+fn madt_bad_checksum() {
+    let mut buffer = misaligned(MADT);
+    buffer[40] ^= 0xff;
+    assert!(parse_madt(&buffer[1..]).is_err());
+}
+
+#[test_case]
+fn mcfg() {
+    let buffer = misaligned(MCFG);
+    let entries = parse_mcfg(&buffer[1..]).unwrap();
+    assert_eq!(&[McfgEntry { base: 0xB0000000, segment: 0, start_bus: 0, end_bus: 0xFF }], entries.as_slice());
+
+    let mut broken = misaligned(MCFG);
+    broken[1 + 44] ^= 1;
+    assert!(parse_mcfg(&broken[1..]).is_err());
+}
+
+#[test_case]
+fn gpt() {
+    check_protective_mbr(&misaligned(GPT_LBA0)[1..]).unwrap();
+
+    let header = PartitionTableHeader::parse(&misaligned(GPT_LBA1)[1..]).unwrap();
+    assert_eq!(0x10000, header.gpt_revision);
+    assert_eq!(2, header.starting_lba_of_array);
+    assert_eq!(128, header.entries_num);
+    assert_eq!(128, header.entry_size);
+
+    let array = misaligned(GPT_ENTRIES);
+    let entries: Vec<_> = parse_partition_entries(&header, &array[1..]).unwrap().collect();
+    assert_eq!(1, entries.len());
+
+    let (idx, entry) = &entries[0];
+    assert_eq!(0, *idx);
+    assert_eq!("C12A7328-F81F-11D2-BA4B-00A0C93EC93B", ferr_os::gpt::guid_to_str(entry.partition_type_guid));
+    assert_eq!(34, entry.starting_lba);
+    assert_eq!(4129, entry.ending_lba);
+    assert_eq!("boot", entry.name());
+}
+
+#[test_case]
+fn gpt_bad_checksums() {
+    let mut lba1 = misaligned(GPT_LBA1);
+    lba1[1 + 40] ^= 1;
+    assert!(matches!(PartitionTableHeader::parse(&lba1[1..]), Err(GptError::InvalidTableHeaderChecksum)));
+
+    let header = PartitionTableHeader::parse(GPT_LBA1).unwrap();
+    let mut array = misaligned(GPT_ENTRIES);
+    array[1 + 200] ^= 1;
+    assert!(matches!(parse_partition_entries(&header, &array[1..]), Err(GptError::InvalidEntriesArrayChecksum)));
+}
+
+#[test_case]
+fn smart_attributes() {
+    let mut data = [0u8; 512];
+    // temperature 35 C, 1234 hours
+    data[2..14].copy_from_slice(&[194, 0x22, 0, 100, 100, 35, 0, 20, 0, 45, 0, 0]);
+    data[14..26].copy_from_slice(&[9, 0x32, 0, 99, 99, 0xd2, 0x04, 0, 0, 0, 0, 0]);
+    data[511] = 0u8.wrapping_sub(data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)));
+
+    let health = Health::parse(&misaligned(&data)[1..], true).unwrap();
+    assert_eq!(2, health.attributes.len());
+    assert_eq!(Some(35), health.temperature());
+    assert_eq!(Some(1234), health.power_on_hours());
+    assert_eq!(None, health.reallocated_sectors());
+
+    data[100] ^= 1;
+    assert!(Health::parse(&data, true).is_err());
+}
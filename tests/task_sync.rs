@@ -0,0 +1,187 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(shared_lib::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::{pin, Pin};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use shared_lib::{entry_point, BootInfo};
+use shared_lib::boot_info::NextFreeFrame;
+use shared_lib::frame_allocator::MemoryMap;
+use ferr_os::allocator::init_heap;
+use ferr_os::memory::active_level_4_table;
+use ferr_os::task::sync::{Mutex, Notify};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use shared_lib::frame_allocator::FrameAllocator;
+
+    let l4_table = unsafe {
+        active_level_4_table()
+    };
+
+    let mut allocator = unsafe {
+        FrameAllocator::new(boot_info.get::<MemoryMap>().unwrap(), shared_lib::phys_mapping_offset(),
+            boot_info.get::<NextFreeFrame>().unwrap().0)
+    };
+
+    init_heap(l4_table, &mut allocator)
+        .expect("Failed to init heap");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ferr_os::test_panic_handler(info)
+}
+
+/// Stands in for a task, counts how often it was woken
+struct Task {
+    wakes: AtomicUsize,
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.wakes.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Task {
+    fn new() -> Arc<Task> {
+        Arc::new(Task { wakes: AtomicUsize::new(0) })
+    }
+
+    fn wakes(&self) -> usize {
+        self.wakes.load(Ordering::Relaxed)
+    }
+
+    fn poll<F: Future>(self: &Arc<Self>, future: Pin<&mut F>) -> Poll<F::Output> {
+        let waker = Waker::from(self.clone());
+        future.poll(&mut Context::from_waker(&waker))
+    }
+}
+
+#[test_case]
+fn notify_one_without_waiter_is_kept() {
+    let notify = Notify::new();
+    let task = Task::new();
+    notify.notify_one();
+
+    assert!(task.poll(pin!(notify.notified())).is_ready());
+    // the permit is used up
+    assert!(task.poll(pin!(notify.notified())).is_pending());
+}
+
+#[test_case]
+fn notify_one_wakes_the_oldest_waiter() {
+    let notify = Notify::new();
+    let (first, second) = (Task::new(), Task::new());
+    let mut first_notified = pin!(notify.notified());
+    let mut second_notified = pin!(notify.notified());
+    assert!(first.poll(first_notified.as_mut()).is_pending());
+    assert!(second.poll(second_notified.as_mut()).is_pending());
+
+    notify.notify_one();
+    assert_eq!((first.wakes(), second.wakes()), (1, 0));
+    assert!(first.poll(first_notified.as_mut()).is_ready());
+    assert!(second.poll(second_notified.as_mut()).is_pending());
+}
+
+#[test_case]
+fn notify_waiters_wakes_everyone_waiting() {
+    let notify = Notify::new();
+    let (first, second) = (Task::new(), Task::new());
+    let mut first_notified = pin!(notify.notified());
+    let mut second_notified = pin!(notify.notified());
+    assert!(first.poll(first_notified.as_mut()).is_pending());
+    assert!(second.poll(second_notified.as_mut()).is_pending());
+
+    notify.notify_waiters();
+    assert_eq!((first.wakes(), second.wakes()), (1, 1));
+    assert!(first.poll(first_notified.as_mut()).is_ready());
+    assert!(second.poll(second_notified.as_mut()).is_ready());
+    // no permit is left for later waiters
+    assert!(Task::new().poll(pin!(notify.notified())).is_pending());
+}
+
+#[test_case]
+fn dropped_waiter_passes_the_notification_on() {
+    let notify = Notify::new();
+    let (first, second) = (Task::new(), Task::new());
+    let mut first_notified = Box::pin(notify.notified());
+    let mut second_notified = pin!(notify.notified());
+    assert!(first.poll(first_notified.as_mut()).is_pending());
+    assert!(second.poll(second_notified.as_mut()).is_pending());
+
+    notify.notify_one();
+    drop(first_notified);
+    assert_eq!(second.wakes(), 1);
+    assert!(second.poll(second_notified.as_mut()).is_ready());
+}
+
+#[test_case]
+fn mutex_try_lock_fails_while_locked() {
+    let mutex = Mutex::new(0);
+    let guard = mutex.try_lock().unwrap();
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    assert!(mutex.try_lock().is_some());
+}
+
+#[test_case]
+fn mutex_hands_the_lock_to_waiters_in_order() {
+    let mutex = Mutex::new(0);
+    let (first, second) = (Task::new(), Task::new());
+    let mut guard = mutex.try_lock().unwrap();
+    let mut first_lock = pin!(mutex.lock());
+    let mut second_lock = pin!(mutex.lock());
+    assert!(first.poll(first_lock.as_mut()).is_pending());
+    assert!(second.poll(second_lock.as_mut()).is_pending());
+
+    *guard += 1;
+    drop(guard);
+    assert_eq!((first.wakes(), second.wakes()), (1, 0));
+    let Poll::Ready(mut guard) = first.poll(first_lock.as_mut()) else {
+        panic!("first waiter didn't get the lock");
+    };
+    assert_eq!(*guard, 1);
+    assert!(second.poll(second_lock.as_mut()).is_pending());
+
+    *guard += 1;
+    drop(guard);
+    assert_eq!(second.wakes(), 1);
+    let Poll::Ready(guard) = second.poll(second_lock.as_mut()) else {
+        panic!("second waiter didn't get the lock");
+    };
+    assert_eq!(*guard, 2);
+}
+
+#[test_case]
+fn dropped_lock_waiter_passes_the_lock_on() {
+    let mutex = Mutex::new(());
+    let (first, second) = (Task::new(), Task::new());
+    let guard = mutex.try_lock().unwrap();
+    let mut first_lock = Box::pin(mutex.lock());
+    let mut second_lock = pin!(mutex.lock());
+    assert!(first.poll(first_lock.as_mut()).is_pending());
+    assert!(second.poll(second_lock.as_mut()).is_pending());
+
+    drop(guard);
+    assert_eq!(first.wakes(), 1);
+    // woken, but gone before it took the lock
+    drop(first_lock);
+    assert_eq!(second.wakes(), 1);
+    assert!(second.poll(second_lock.as_mut()).is_ready());
+}
@@ -0,0 +1,75 @@
+#!/usr/bin/env python3
+
+# Converts a kernel trace into Chrome trace JSON (open it in chrome://tracing or Perfetto).
+#
+# usage: trace_to_chrome.py <serial log | raw disk dump> <output.json> [tsc MHz]
+#
+# The input is either a serial log containing the output of the `trace dump` shell command,
+# or sectors written by `trace::export_to_disk`.
+
+import json
+import struct
+import sys
+
+RECORD_SIZE = 32
+EVENT_NAMES = ["empty", "irq_enter", "irq_exit", "task_poll", "task_poll_end", "task_spawn", "mark"]
+
+
+def read_serial_log(path):
+    records = []
+    with open(path, "r", errors="replace") as f:
+        for line in f:
+            parts = line.split()
+            if len(parts) != 5 or parts[0] != "trace:":
+                continue
+            records.append((int(parts[1]), parts[2], int(parts[3]), int(parts[4])))
+    return records
+
+
+def read_disk_dump(data):
+    count = struct.unpack_from("<Q", data, 16)[0]
+    records = []
+    for i in range(count):
+        tsc, event, arg0, arg1 = struct.unpack_from("<QH6xQQ", data, 512 + i * RECORD_SIZE)
+        records.append((tsc, EVENT_NAMES[event], arg0, arg1))
+    return records
+
+
+def to_chrome_events(records, tsc_mhz):
+    if not records:
+        return []
+
+    start = records[0][0]
+    events = []
+    for tsc, name, arg0, arg1 in records:
+        event = {"pid": 0, "ts": (tsc - start) / tsc_mhz}
+        if name == "irq_enter" or name == "irq_exit":
+            event.update(name="irq {}".format(arg0), tid="irq", ph="B" if name == "irq_enter" else "E")
+        elif name == "task_poll" or name == "task_poll_end":
+            event.update(name="task {}".format(arg0), tid="executor", ph="B" if name == "task_poll" else "E")
+            if name == "task_poll_end":
+                event["args"] = {"ready": bool(arg1)}
+        else:
+            event.update(name="{} {}".format(name, arg0), tid="events", ph="i", s="g", args={"value": arg1})
+        events.append(event)
+    return events
+
+
+if len(sys.argv) < 3:
+    print("usage: trace_to_chrome.py <serial log | raw disk dump> <output.json> [tsc MHz]")
+    sys.exit(1)
+
+with open(sys.argv[1], "rb") as f:
+    data = f.read()
+
+if data.startswith(b"FERRTRACE"):
+    records = read_disk_dump(data)
+else:
+    records = read_serial_log(sys.argv[1])
+
+tsc_mhz = float(sys.argv[3]) if len(sys.argv) > 3 else 1000.0
+
+with open(sys.argv[2], "w") as f:
+    json.dump({"traceEvents": to_chrome_events(records, tsc_mhz), "displayTimeUnit": "ns"}, f)
+
+print("converted {} events".format(len(records)))